@@ -0,0 +1,174 @@
+//! Minimal Solidity ABI helpers for the calls made by the payout service
+
+use super::hash::keccak256;
+
+/// 4-byte function selector for a canonical signature like `hasRole(bytes32,address)`
+pub fn selector(signature: &str) -> [u8; 4] {
+    let hash = keccak256(signature.as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// EIP-55 mixed-case checksum encoding of a 20-byte address
+pub fn to_checksum_address(address: &[u8; 20]) -> String {
+    let lower = hex::encode(address);
+    let hash = keccak256(lower.as_bytes());
+    let checksummed: String = lower
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+/// Left-pad a 20-byte address given as 0x-prefixed hex into a 32-byte word
+pub fn encode_address(address: &str) -> Option<[u8; 32]> {
+    let bytes = hex::decode(address.strip_prefix("0x")?).ok()?;
+    if bytes.len() != 20 {
+        return None;
+    }
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(&bytes);
+    Some(word)
+}
+
+/// Hex digits of the word [`encode_address`] makes of `address`, keeping the
+/// case of its digits, `None` unless it is 0x-prefixed hex of exactly 20 bytes
+pub fn address_word_hex(address: &str) -> Option<String> {
+    encode_address(address)?;
+    Some(format!("{:0>64}", &address[2..]))
+}
+
+/// Encode an unsigned integer as a big-endian 32-byte word
+pub fn encode_uint(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+/// Build 0x-prefixed calldata from a selector and already-encoded static words
+pub fn encode_call(selector: [u8; 4], words: &[[u8; 32]]) -> String {
+    let mut data = selector.to_vec();
+    for word in words {
+        data.extend_from_slice(word);
+    }
+    format!("0x{}", hex::encode(data))
+}
+
+/// Split 0x-prefixed return data into 32-byte words
+pub fn decode_words(data: &str) -> Option<Vec<[u8; 32]>> {
+    let bytes = hex::decode(data.strip_prefix("0x").unwrap_or(data)).ok()?;
+    if bytes.len() % 32 != 0 {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks(32)
+            .map(|chunk| {
+                let mut word = [0u8; 32];
+                word.copy_from_slice(chunk);
+                word
+            })
+            .collect(),
+    )
+}
+
+/// Decode a single ABI-encoded `bool` return value
+pub fn decode_bool(data: &str) -> Option<bool> {
+    let words = decode_words(data)?;
+    let word = words.first()?;
+    if word[..31].iter().any(|b| *b != 0) {
+        return None;
+    }
+    match word[31] {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+/// Decimal digits of a `uint256` word, which may not fit any native integer
+pub fn uint_to_decimal(word: &[u8; 32]) -> String {
+    let mut remaining = *word;
+    let mut digits = Vec::new();
+    while remaining.iter().any(|b| *b != 0) {
+        let mut carry = 0u16;
+        for byte in remaining.iter_mut() {
+            let value = (carry << 8) | u16::from(*byte);
+            *byte = (value / 10) as u8;
+            carry = value % 10;
+        }
+        digits.push(b'0' + carry as u8);
+    }
+    if digits.is_empty() {
+        return "0".to_string();
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn hashes_with_keccak() {
+        assert_eq!(
+            hex::encode(keccak256(b"")),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+    }
+
+    #[test]
+    fn computes_known_selectors() {
+        assert_eq!(
+            hex::encode(selector("payoutToUser(bytes32,address,uint256)")),
+            "b77276d8"
+        );
+        assert_eq!(
+            hex::encode(selector("hasRole(bytes32,address)")),
+            "91d14854"
+        );
+        assert_eq!(
+            hex::encode(selector("transfer(address,uint256)")),
+            "a9059cbb"
+        );
+    }
+
+    #[test]
+    fn encodes_and_decodes_words() {
+        let word = encode_address("0x70997970C51812dc3A010C7d01b50e0d17dc79C8").unwrap();
+        assert_eq!(
+            hex::encode(word),
+            "00000000000000000000000070997970c51812dc3a010c7d01b50e0d17dc79c8"
+        );
+        assert!(encode_address("0x1234").is_none());
+        assert_eq!(
+            to_checksum_address(&word[12..].try_into().unwrap()),
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+        );
+        assert_eq!(encode_uint(0x0102)[30..], [1, 2]);
+
+        assert_eq!(decode_bool(&format!("0x{:064x}", 1)), Some(true));
+        assert_eq!(decode_bool(&format!("0x{}", "00".repeat(32))), Some(false));
+        assert_eq!(decode_bool("0x"), None);
+        assert_eq!(decode_bool(&format!("0x{}", "02".repeat(32))), None);
+
+        assert_eq!(uint_to_decimal(&[0; 32]), "0");
+        assert_eq!(
+            uint_to_decimal(&encode_uint(u128::MAX)),
+            u128::MAX.to_string()
+        );
+        assert_eq!(
+            uint_to_decimal(&[0xff; 32]),
+            "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+        );
+    }
+}
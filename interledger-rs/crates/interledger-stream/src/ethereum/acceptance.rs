@@ -0,0 +1,372 @@
+//! Deciding whether to fulfil a packet whose payout is certain to fail
+//!
+//! The STREAM receiver fulfils a packet before its payout runs, so a payout
+//! that fails takes the sender's money without paying the recipient.
+//! [`EthereumPayoutService::would_accept`] runs the checks of execution that
+//! need no RPC: destination, recipient and memo checks, zero amounts, the
+//! operator's role, the kill switch, the pause and, for payouts needing no
+//! conversion, daily limits. A payout failing one of them is rejected before
+//! fulfilment if its [`RejectKind`] is in `reject_before_fulfil`, and is
+//! otherwise accepted and held or refused by execution as before. The
+//! [`RejectReason`] carries the ILP error code to reject with, and its JSON is
+//! sent as the reject's message.
+
+use super::{payload::plan_request, standby, EthereumPayoutService, PayoutError, PayoutRequest};
+use interledger_packet::ErrorCode;
+use serde_json::{json, Value};
+
+/// Kind of reason a payout would fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectKind {
+    /// The destination does not parse
+    InvalidDestination,
+    /// The asset segment names a token address that does not resolve
+    UnsupportedAsset,
+    /// The recipient is invalid or on the deny list
+    BlockedRecipient,
+    /// The recipient is held after failing repeatedly
+    RecipientHeld,
+    /// The amount is zero and zero amounts are rejected
+    ZeroAmount,
+    /// The Treasury is paused, so the payout would be deferred
+    Paused,
+    /// Payouts are stopped by the kill switch or a lost operator role
+    Stopped,
+    /// The payout would exceed a daily cap
+    LimitExceeded,
+    /// Execution would refuse the payout for another reason
+    Refused,
+}
+
+impl RejectKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RejectKind::InvalidDestination => "invalid_destination",
+            RejectKind::UnsupportedAsset => "unsupported_asset",
+            RejectKind::BlockedRecipient => "blocked_recipient",
+            RejectKind::RecipientHeld => "recipient_held",
+            RejectKind::ZeroAmount => "zero_amount",
+            RejectKind::Paused => "paused",
+            RejectKind::Stopped => "stopped",
+            RejectKind::LimitExceeded => "limit_exceeded",
+            RejectKind::Refused => "refused",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "invalid_destination" => Some(RejectKind::InvalidDestination),
+            "unsupported_asset" => Some(RejectKind::UnsupportedAsset),
+            "blocked_recipient" => Some(RejectKind::BlockedRecipient),
+            "recipient_held" => Some(RejectKind::RecipientHeld),
+            "zero_amount" => Some(RejectKind::ZeroAmount),
+            "paused" => Some(RejectKind::Paused),
+            "stopped" => Some(RejectKind::Stopped),
+            "limit_exceeded" => Some(RejectKind::LimitExceeded),
+            "refused" => Some(RejectKind::Refused),
+            _ => None,
+        }
+    }
+
+    /// Kinds in a comma-separated list such as "blocked_recipient,paused"
+    pub fn parse_list(spec: &str) -> Option<Vec<Self>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(RejectKind::parse)
+            .collect()
+    }
+
+    /// Code to reject the packet with: final for payouts that can never
+    /// succeed, temporary for those that may once the service recovers
+    pub fn ilp_error_code(self) -> ErrorCode {
+        match self {
+            RejectKind::InvalidDestination => ErrorCode::F02_UNREACHABLE,
+            RejectKind::ZeroAmount => ErrorCode::F00_BAD_REQUEST,
+            RejectKind::UnsupportedAsset | RejectKind::BlockedRecipient | RejectKind::Refused => {
+                ErrorCode::F99_APPLICATION_ERROR
+            }
+            RejectKind::LimitExceeded => ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+            RejectKind::RecipientHeld | RejectKind::Paused | RejectKind::Stopped => {
+                ErrorCode::T99_APPLICATION_ERROR
+            }
+        }
+    }
+}
+
+/// Why a payout would fail, as reported to the sender
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectReason {
+    pub kind: RejectKind,
+    pub message: String,
+}
+
+impl RejectReason {
+    fn new(kind: RejectKind, message: impl Into<String>) -> Self {
+        RejectReason {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    fn from_error(err: PayoutError) -> Self {
+        let kind = match &err {
+            PayoutError::InvalidDestination(_) | PayoutError::InvalidStreamToken { .. } => {
+                RejectKind::InvalidDestination
+            }
+            PayoutError::UnknownTokenAddress { .. } | PayoutError::AmbiguousTokenAddress { .. } => {
+                RejectKind::UnsupportedAsset
+            }
+            PayoutError::RecipientInvalid { .. } | PayoutError::RecipientBlocked { .. } => {
+                RejectKind::BlockedRecipient
+            }
+            PayoutError::RecipientHeld { .. } => RejectKind::RecipientHeld,
+            PayoutError::ZeroAmount { .. } => RejectKind::ZeroAmount,
+            PayoutError::KillSwitchEngaged { .. } | PayoutError::NotAuthorized { .. } => {
+                RejectKind::Stopped
+            }
+            PayoutError::LimitExceeded { .. } => RejectKind::LimitExceeded,
+            _ => RejectKind::Refused,
+        };
+        RejectReason::new(kind, err.to_string())
+    }
+
+    pub fn ilp_error_code(&self) -> ErrorCode {
+        self.kind.ilp_error_code()
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "reason": self.kind.as_str(),
+            "message": self.message,
+            "ilp_code": self.ilp_error_code().to_string(),
+        })
+    }
+}
+
+impl EthereumPayoutService {
+    /// Fail with the reason paying `amount` to `destination` would fail, if
+    /// it is one the configuration rejects before fulfilment
+    pub fn would_accept(&self, destination: &str, amount: u64) -> Result<(), RejectReason> {
+        self.would_accept_request(&PayoutRequest::new(destination, amount, 0))
+    }
+
+    /// As [`EthereumPayoutService::would_accept`], for a complete request
+    pub fn would_accept_request(&self, request: &PayoutRequest) -> Result<(), RejectReason> {
+        match self.check_acceptance(request) {
+            Err(reason) if self.config.reject_before_fulfil.contains(&reason.kind) => Err(reason),
+            _ => Ok(()),
+        }
+    }
+
+    /// The reason `request` would fail the checks of execution needing no
+    /// RPC, whether or not it is rejected before fulfilment
+    pub fn check_acceptance(&self, request: &PayoutRequest) -> Result<(), RejectReason> {
+        let eth_dest = self
+            .check_destination(request)
+            .map_err(RejectReason::from_error)?;
+        if request.amount == 0 {
+            if self.config.reject_zero_amounts {
+                return Err(RejectReason::from_error(PayoutError::ZeroAmount {
+                    destination: request.destination.clone(),
+                }));
+            }
+            return Ok(());
+        }
+        self.check_operational().map_err(RejectReason::from_error)?;
+        if self.is_kill_switch_engaged() {
+            return Err(RejectReason::new(
+                RejectKind::Stopped,
+                "Payouts are deferred by the kill switch",
+            ));
+        }
+        if self.is_paused() {
+            return Err(RejectReason::new(
+                RejectKind::Paused,
+                "Payouts are deferred while the Treasury is paused",
+            ));
+        }
+
+        // Converted amounts need a rate, which may have to be fetched
+        let converts = request
+            .source_asset
+            .as_ref()
+            .is_some_and(|asset| *asset != eth_dest.asset_code);
+        if !converts {
+            let amount = self
+                .rescale(request, &eth_dest)
+                .map_err(RejectReason::from_error)?
+                .in_asset(&eth_dest.asset_code);
+            if !amount.is_zero() {
+                let plans = plan_request(&self.config, request, &amount, None)
+                    .map_err(RejectReason::from_error)?;
+                self.check_daily_limits(&plans)
+                    .map_err(RejectReason::from_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the STREAM receiver should fulfil a packet of `packet_amount` in
+/// `source_asset` to `destination`, by the service that would pay it out
+pub fn would_accept_stream_payout(
+    source_asset: &str,
+    asset_scale: u8,
+    destination: &str,
+    packet_amount: u64,
+    sequence: u64,
+) -> Result<(), RejectReason> {
+    if !destination.contains(".eth.") {
+        return Ok(());
+    }
+    let service = match standby::payout_router() {
+        Some(router) => router
+            .route(destination)
+            .map(|tenant| tenant.service.clone()),
+        None => standby::payout_service(),
+    };
+    match service {
+        Some(service) => service.would_accept_request(
+            &PayoutRequest::from_stream(destination, packet_amount, sequence, asset_scale)
+                .with_source_asset(source_asset),
+        ),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{FailureStreak, FailureStreakPolicy, PayoutOutcome};
+    use super::*;
+    use chrono::Utc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    const RECIPIENT: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+
+    fn rejecting_all() -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.reject_before_fulfil = RejectKind::parse_list(
+            "invalid_destination,unsupported_asset,blocked_recipient,recipient_held,\
+             zero_amount,paused,stopped,limit_exceeded,refused",
+        )
+        .unwrap();
+        config
+    }
+
+    fn rejected(service: &EthereumPayoutService, destination: &str, amount: u64) -> RejectKind {
+        service.would_accept(destination, amount).unwrap_err().kind
+    }
+
+    #[tokio::test]
+    async fn rejects_each_reason_with_its_ilp_code() {
+        let mut config = rejecting_all();
+        config.reject_zero_amounts = true;
+        config.assets.apply_daily_caps("EURC:150").unwrap();
+        config.failure_streaks = Some(FailureStreakPolicy {
+            threshold: 3,
+            summary_interval: Duration::from_secs(3600),
+            hold: true,
+        });
+        let transport = mock_chain();
+        let service = test_service(config, transport.clone());
+        assert_eq!(service.would_accept(TEST_DESTINATION, 100), Ok(()));
+
+        assert_eq!(
+            rejected(&service, "test.receiver.eth.31337.EURC", 100),
+            RejectKind::InvalidDestination
+        );
+        let unknown_token =
+            TEST_DESTINATION.replace("EURC", "0x3333333333333333333333333333333333333333");
+        assert_eq!(
+            rejected(&service, &unknown_token, 100),
+            RejectKind::UnsupportedAsset
+        );
+        let burn = "test.receiver.eth.31337.EURC.0x000000000000000000000000000000000000dEaD.x";
+        let reason = service.would_accept(burn, 100).unwrap_err();
+        assert_eq!(reason.kind, RejectKind::BlockedRecipient);
+        assert_eq!(
+            reason.to_json(),
+            json!({
+                "reason": "blocked_recipient",
+                "message": service.execute_payout(burn, 100, 0).await.unwrap_err().to_string(),
+                "ilp_code": "F99",
+            })
+        );
+        assert_eq!(
+            rejected(&service, TEST_DESTINATION, 0),
+            RejectKind::ZeroAmount
+        );
+
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let reason = service.would_accept(TEST_DESTINATION, 100).unwrap_err();
+        assert_eq!(reason.kind, RejectKind::LimitExceeded);
+        assert_eq!(
+            reason.ilp_error_code(),
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY
+        );
+        assert_eq!(service.would_accept(TEST_DESTINATION, 50), Ok(()));
+
+        let streak = FailureStreak {
+            failures: 3,
+            last_error: "execution reverted".to_string(),
+            last_failure: Utc::now(),
+            summarized_at: None,
+            unsummarized: 0,
+            held: true,
+        };
+        service
+            .failure_streaks
+            .with_written(&*service.store, RECIPIENT, |state| state.0 = Some(streak));
+        assert_eq!(
+            rejected(&service, TEST_DESTINATION, 10),
+            RejectKind::RecipientHeld
+        );
+        service.release_recipient(RECIPIENT);
+
+        service.kill_switch.store(true, Ordering::SeqCst);
+        let reason = service.would_accept(TEST_DESTINATION, 10).unwrap_err();
+        assert_eq!(reason.kind, RejectKind::Stopped);
+        assert_eq!(reason.ilp_error_code(), ErrorCode::T99_APPLICATION_ERROR);
+        service.kill_switch.store(false, Ordering::SeqCst);
+
+        service.mark_paused();
+        assert_eq!(rejected(&service, TEST_DESTINATION, 10), RejectKind::Paused);
+        // None of the checks reached the node
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn reasons_not_listed_are_accepted_and_held() {
+        let mut config = test_config();
+        config.reject_before_fulfil = RejectKind::parse_list("blocked_recipient").unwrap();
+        let service = test_service(config, mock_chain());
+        service.mark_paused();
+
+        let burn = "test.receiver.eth.31337.EURC.0x000000000000000000000000000000000000dEaD.x";
+        assert_eq!(rejected(&service, burn, 100), RejectKind::BlockedRecipient);
+        // Paused is not listed, so the packet is fulfilled and its payout deferred
+        assert_eq!(service.would_accept(TEST_DESTINATION, 100), Ok(()));
+        assert_eq!(
+            service.check_acceptance(&PayoutRequest::new(TEST_DESTINATION, 100, 1)),
+            Err(RejectReason::new(
+                RejectKind::Paused,
+                "Payouts are deferred while the Treasury is paused"
+            ))
+        );
+        assert_eq!(
+            service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .unwrap(),
+            PayoutOutcome::Deferred
+        );
+
+        assert_eq!(RejectKind::parse_list("paused,nonsense"), None);
+    }
+}
@@ -0,0 +1,236 @@
+//! Verification that the operator is still allowed to call the Treasury
+//!
+//! If the operator loses its role every payout reverts, so the service checks
+//! `hasRole(bytes32,address)` at startup and periodically, and refuses to
+//! submit payouts while the check fails.
+
+use super::abi::{decode_bool, encode_address, encode_call, selector};
+use super::shutdown::unless_cancelled;
+use super::{EthereumPayoutService, PayoutError};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Outcome of the most recent operator role check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorizationState {
+    /// Role verification is disabled in the config
+    Disabled,
+    /// No check has completed yet
+    Unchecked,
+    Authorized,
+    /// The operator lacks the role; payouts are short-circuited
+    Unauthorized,
+}
+
+/// Calldata for `hasRole(bytes32 role, address account)`
+pub fn has_role_calldata(role: &[u8; 32], account: &str) -> Option<String> {
+    Some(encode_call(
+        selector("hasRole(bytes32,address)"),
+        &[*role, encode_address(account)?],
+    ))
+}
+
+impl EthereumPayoutService {
+    /// Current operator authorization state
+    pub fn authorization(&self) -> AuthorizationState {
+        *self.authorization.lock().unwrap()
+    }
+
+    /// Whether the service is degraded because the operator lacks its role
+    pub fn is_degraded(&self) -> bool {
+        self.authorization() == AuthorizationState::Unauthorized
+    }
+
+    /// Query the Treasury for the operator's role and update the authorization state.
+    ///
+    /// A failed RPC call leaves the previous state untouched.
+    pub async fn check_operator_role(&self) -> Result<AuthorizationState, PayoutError> {
+        let role = match &self.config.role_check {
+            Some(check) => check.role,
+            None => return Ok(AuthorizationState::Disabled),
+        };
+        let operator = self.operator_address();
+        let has_role = self.has_role(&role, &operator).await?;
+
+        let state = if has_role {
+            AuthorizationState::Authorized
+        } else {
+            AuthorizationState::Unauthorized
+        };
+        let previous = std::mem::replace(&mut *self.authorization.lock().unwrap(), state);
+        if previous != state {
+            match state {
+                AuthorizationState::Unauthorized => error!(
+                    "ALERT: operator {} lacks role 0x{} on Treasury {}; payouts are suspended",
+                    operator,
+                    hex::encode(role),
+                    self.config.treasury_address
+                ),
+                _ if previous == AuthorizationState::Unauthorized => info!(
+                    "Operator {} regained its role on Treasury {}; resuming payouts",
+                    operator, self.config.treasury_address
+                ),
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    /// Whether `account` holds `role` on the Treasury
+    pub(super) async fn has_role(
+        &self,
+        role: &[u8; 32],
+        account: &str,
+    ) -> Result<bool, PayoutError> {
+        let data = has_role_calldata(role, account)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid operator address {}", account)))?;
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({ "to": &self.config.treasury_address, "data": data })],
+            )
+            .await?;
+        result
+            .as_str()
+            .and_then(decode_bool)
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("hasRole returned {}", result)))
+    }
+
+    /// Spawn a task re-checking the operator role on the configured interval.
+    ///
+    /// Returns `None` when the role check is disabled. The task stops on shutdown.
+    pub fn spawn_role_monitor(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval = self.config.role_check.as_ref()?.interval;
+        let service = Arc::clone(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                if let Err(err) = service.check_operator_role().await {
+                    warn!("Operator role check failed: {}", err);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_OPERATOR,
+    };
+    use super::super::RoleCheckConfig;
+    use super::*;
+    use serde_json::Value;
+
+    fn word(value: bool) -> Value {
+        json!(format!("0x{:064x}", value as u8))
+    }
+
+    fn role_checked_config() -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.role_check = Some(RoleCheckConfig::default());
+        config
+    }
+
+    #[test]
+    fn encodes_has_role_call() {
+        let data = has_role_calldata(&[0x11; 32], TEST_OPERATOR).unwrap();
+        assert_eq!(
+            data,
+            format!(
+                "0x91d14854{}000000000000000000000000f39fd6e51aad88f6f4ce6ab8827279cfffb92266",
+                "11".repeat(32)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn authorized_operator_can_pay_out() {
+        let transport = mock_chain();
+        transport.on_result("eth_call", word(true));
+        let service = test_service(role_checked_config(), transport.clone());
+
+        assert_eq!(
+            service.check_operator_role().await.unwrap(),
+            AuthorizationState::Authorized
+        );
+        assert!(!service.is_degraded());
+        let call = &transport.calls("eth_call")[0]["params"][0];
+        assert_eq!(call["to"], super::super::testing::TEST_TREASURY);
+        assert!(call["data"].as_str().unwrap().starts_with("0x91d14854"));
+
+        let outcome = service
+            .execute_payout(super::super::testing::TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+    }
+
+    #[tokio::test]
+    async fn unauthorized_operator_short_circuits_until_recheck_passes() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", word(false));
+        let service = test_service(role_checked_config(), transport.clone());
+
+        assert_eq!(
+            service.check_operator_role().await.unwrap(),
+            AuthorizationState::Unauthorized
+        );
+        assert!(service.is_degraded());
+        let err = service
+            .execute_payout(super::super::testing::TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::NotAuthorized { .. }));
+        // Nothing was sent while unauthorized
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 0);
+
+        // A failing check keeps the degraded state
+        transport.on_error("eth_call", -32000, "header not found");
+        assert!(service.check_operator_role().await.is_err());
+        assert!(service.is_degraded());
+
+        transport.on_result("eth_call", word(true));
+        assert_eq!(
+            service.check_operator_role().await.unwrap(),
+            AuthorizationState::Authorized
+        );
+        assert!(!service.is_degraded());
+    }
+
+    #[tokio::test]
+    async fn disabled_check_makes_no_calls() {
+        let transport = MockTransport::new();
+        let service = Arc::new(test_service(test_config(), transport.clone()));
+
+        assert_eq!(
+            service.check_operator_role().await.unwrap(),
+            AuthorizationState::Disabled
+        );
+        assert!(service.spawn_role_monitor().is_none());
+        assert_eq!(service.authorization(), AuthorizationState::Disabled);
+        assert_eq!(transport.call_count("eth_call"), 0);
+    }
+
+    #[tokio::test]
+    async fn monitor_rechecks_periodically() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", word(false));
+        let mut config = role_checked_config();
+        config.role_check.as_mut().unwrap().interval = std::time::Duration::from_millis(10);
+        let service = Arc::new(test_service(config, transport.clone()));
+
+        let monitor = service.spawn_role_monitor().unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(35)).await;
+        monitor.abort();
+        assert!(transport.call_count("eth_call") >= 2);
+        assert!(service.is_degraded());
+    }
+}
@@ -0,0 +1,325 @@
+//! Recipient address formats per chain
+//!
+//! Destinations name their chain before the recipient, so the recipient is
+//! validated against the format registered for that chain. Chains without a
+//! registered format use [`AddressFormat::evm`]. The stream token ending the
+//! destination is validated against one [`StreamTokenFormat`] for all chains.
+//!
+//! The chain segment is a chain ID or an alias the operator configured with
+//! [`AddressFormats::with_chain_alias`], e.g. `base` for 8453. A segment
+//! that is all digits is always read as a chain ID, so an alias that is also
+//! a number never applies and is warned about when configured.
+
+use super::abi::to_checksum_address;
+use super::{EthereumDestination, EthereumPayoutConfig, PayoutError};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tracing::warn;
+
+type Checksum = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// What a valid recipient address of a chain looks like
+#[derive(Clone)]
+pub struct AddressFormat {
+    prefix: String,
+    /// Characters after the prefix
+    length: usize,
+    charset: fn(char) -> bool,
+    checksum: Option<Checksum>,
+}
+
+impl AddressFormat {
+    /// Addresses of `length` characters from `charset` after `prefix`
+    pub fn new(prefix: &str, length: usize, charset: fn(char) -> bool) -> Self {
+        AddressFormat {
+            prefix: prefix.to_string(),
+            length,
+            charset,
+            checksum: None,
+        }
+    }
+
+    /// `0x` and 40 hex digits, which must match their EIP-55 checksum if
+    /// they mix upper and lower case
+    pub fn evm() -> Self {
+        AddressFormat::new("0x", 40, |c| c.is_ascii_hexdigit())
+            .with_checksum(eip55_checksum_matches)
+    }
+
+    /// Also require `checksum` to accept the whole address
+    pub fn with_checksum<F>(mut self, checksum: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.checksum = Some(Arc::new(checksum));
+        self
+    }
+
+    /// Why `address` is not in this format, if it is not
+    pub fn validate(&self, address: &str) -> Result<(), &'static str> {
+        let body = address
+            .strip_prefix(self.prefix.as_str())
+            .ok_or("wrong prefix")?;
+        if body.chars().count() != self.length {
+            return Err("wrong length");
+        }
+        if !body.chars().all(self.charset) {
+            return Err("invalid character");
+        }
+        match &self.checksum {
+            Some(checksum) if !checksum(address) => Err("checksum mismatch"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for AddressFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AddressFormat")
+            .field("prefix", &self.prefix)
+            .field("length", &self.length)
+            .field("checksum", &self.checksum.is_some())
+            .finish()
+    }
+}
+
+/// Whether a `0x`-prefixed hex address is all one case or EIP-55 checksummed
+fn eip55_checksum_matches(address: &str) -> bool {
+    let hex = &address[2..];
+    if !hex.bytes().any(|b| b.is_ascii_lowercase()) || !hex.bytes().any(|b| b.is_ascii_uppercase())
+    {
+        return true;
+    }
+    let mut bytes = [0u8; 20];
+    hex::decode_to_slice(hex, &mut bytes).is_ok() && to_checksum_address(&bytes) == address
+}
+
+/// Characters a stream token may consist of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TokenAlphabet {
+    /// URL-safe base64 without padding, as STREAM receivers generate
+    #[default]
+    Base64Url,
+    /// Hex digits of either case, in pairs
+    Hex,
+}
+
+impl TokenAlphabet {
+    pub fn parse(alphabet: &str) -> Option<Self> {
+        match alphabet {
+            "base64url" => Some(TokenAlphabet::Base64Url),
+            "hex" => Some(TokenAlphabet::Hex),
+            _ => None,
+        }
+    }
+}
+
+/// What a valid stream token looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamTokenFormat {
+    pub alphabet: TokenAlphabet,
+    pub min_len: usize,
+    pub max_len: usize,
+}
+
+impl Default for StreamTokenFormat {
+    fn default() -> Self {
+        StreamTokenFormat {
+            alphabet: TokenAlphabet::default(),
+            min_len: 1,
+            max_len: 128,
+        }
+    }
+}
+
+impl StreamTokenFormat {
+    /// Why `token` is not in this format, if it is not
+    pub fn validate(&self, token: &str) -> Result<(), &'static str> {
+        if token.len() < self.min_len {
+            return Err("too short");
+        }
+        if token.len() > self.max_len {
+            return Err("too long");
+        }
+        match self.alphabet {
+            TokenAlphabet::Base64Url if token.ends_with('=') => Err("padding not allowed"),
+            TokenAlphabet::Base64Url
+                if !token
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') =>
+            {
+                Err("invalid character")
+            }
+            TokenAlphabet::Hex if !token.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                Err("invalid character")
+            }
+            TokenAlphabet::Hex if !token.len().is_multiple_of(2) => Err("odd length"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Address formats of the chains payouts are configured for
+#[derive(Debug, Clone, Default)]
+pub struct AddressFormats {
+    chains: HashMap<u64, AddressFormat>,
+    token: StreamTokenFormat,
+    aliases: HashMap<String, u64>,
+}
+
+impl AddressFormats {
+    /// Validate recipients on `chain_id` against `format` instead of the EVM one
+    pub fn with_chain(mut self, chain_id: u64, format: AddressFormat) -> Self {
+        self.chains.insert(chain_id, format);
+        self
+    }
+
+    /// Accept `alias` as the chain segment of destinations on `chain_id`
+    pub fn with_chain_alias(mut self, alias: &str, chain_id: u64) -> Self {
+        if parse_chain_id(alias).is_some() {
+            warn!(
+                "Chain alias {:?} for chain {} is a number and is read as a chain ID instead",
+                alias, chain_id
+            );
+        }
+        self.aliases.insert(alias.to_string(), chain_id);
+        self
+    }
+
+    /// Parse chain aliases from a comma-separated list of `alias:chainId`
+    /// entries, e.g. `base:8453,arbitrum:42161`
+    pub fn parse_chain_aliases(spec: &str) -> Option<Vec<(String, u64)>> {
+        spec.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (alias, chain_id) = entry.split_once(':')?;
+                let alias = alias.trim();
+                if alias.is_empty() || alias.contains('.') {
+                    return None;
+                }
+                Some((alias.to_string(), chain_id.trim().parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// Chain ID a destination's chain segment names, a number or an alias
+    pub fn chain_id(&self, segment: &str) -> Option<u64> {
+        parse_chain_id(segment).or_else(|| self.aliases.get(segment).copied())
+    }
+
+    /// Validate stream tokens against `format` instead of the default
+    pub fn with_token(mut self, format: StreamTokenFormat) -> Self {
+        self.token = format;
+        self
+    }
+
+    pub fn token_format(&self) -> StreamTokenFormat {
+        self.token
+    }
+
+    /// Validate `token` as the stream token of a destination
+    pub fn validate_token(&self, token: &str) -> Result<(), &'static str> {
+        self.token.validate(token)
+    }
+
+    /// Validate `address` as a recipient on `chain_id`
+    pub fn validate(&self, chain_id: u64, address: &str) -> Result<(), &'static str> {
+        match self.chains.get(&chain_id) {
+            Some(format) => format.validate(address),
+            None => AddressFormat::evm().validate(address),
+        }
+    }
+}
+
+/// `segment` as a chain ID if it is all digits; `u64::from_str` would also
+/// accept a leading '+'
+fn parse_chain_id(segment: &str) -> Option<u64> {
+    Some(segment)
+        .filter(|chain| !chain.is_empty() && chain.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|chain| chain.parse().ok())
+}
+
+impl EthereumPayoutConfig {
+    /// Parse `destination` with the configured address formats
+    /// and its asset resolved if named by token address
+    pub fn parse_destination(&self, destination: &str) -> Option<EthereumDestination> {
+        EthereumDestination::parse_with(destination, &self.address_formats)
+            .and_then(|eth_dest| self.resolve_asset(eth_dest, destination).ok())
+    }
+
+    /// Error for a `destination` that does not parse, `InvalidStreamToken` if
+    /// only its token is at fault, or whose token address does not resolve
+    pub(super) fn destination_error(&self, destination: &str) -> PayoutError {
+        let token = destination.rsplit('.').next().unwrap_or_default();
+        match EthereumDestination::try_parse_with(destination, &self.address_formats) {
+            Err(reason) if reason.starts_with("Invalid stream token") => {
+                PayoutError::InvalidStreamToken {
+                    destination: destination.to_string(),
+                    reason: self
+                        .address_formats
+                        .validate_token(token)
+                        .err()
+                        .unwrap_or_default(),
+                }
+            }
+            Ok(eth_dest) => match self.resolve_asset(eth_dest, destination) {
+                Err(err) => err,
+                Ok(_) => PayoutError::InvalidDestination(destination.to_string()),
+            },
+            _ => PayoutError::InvalidDestination(destination.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evm_addresses_follow_eip55_when_mixed_case() {
+        let evm = AddressFormat::evm();
+        let checksummed = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+        assert_eq!(evm.validate(checksummed), Ok(()));
+        assert_eq!(evm.validate(&checksummed.to_lowercase()), Ok(()));
+        assert_eq!(
+            evm.validate(&checksummed.to_uppercase().replace("0X", "0x")),
+            Ok(())
+        );
+        assert_eq!(
+            evm.validate("0x70997970c51812dc3A010C7d01b50e0d17dc79C8"),
+            Err("checksum mismatch")
+        );
+        assert_eq!(evm.validate(&checksummed[2..]), Err("wrong prefix"));
+        assert_eq!(evm.validate(&checksummed[..41]), Err("wrong length"));
+        assert_eq!(
+            evm.validate("0xZZ997970C51812dc3A010C7d01b50e0d17dc79C8"),
+            Err("invalid character")
+        );
+    }
+
+    #[test]
+    fn validates_stream_tokens_per_alphabet() {
+        let base64url = StreamTokenFormat::default();
+        assert_eq!(base64url.validate("3q2-7_Vz9KLm"), Ok(()));
+        assert_eq!(base64url.validate("3q2+7/Vz"), Err("invalid character"));
+        assert_eq!(base64url.validate("3q2-7w=="), Err("padding not allowed"));
+        assert_eq!(base64url.validate(""), Err("too short"));
+        assert_eq!(base64url.validate(&"A".repeat(129)), Err("too long"));
+
+        let hex = StreamTokenFormat {
+            alphabet: TokenAlphabet::Hex,
+            min_len: 32,
+            max_len: 64,
+        };
+        assert_eq!(hex.validate(&"deadBEEF".repeat(4)), Ok(()));
+        assert_eq!(hex.validate(&"ab".repeat(8)), Err("too short"));
+        assert_eq!(
+            hex.validate(&format!("{}g", "a".repeat(32))),
+            Err("invalid character")
+        );
+        assert_eq!(hex.validate(&"a".repeat(33)), Err("odd length"));
+        assert_eq!(hex.validate(&"ab".repeat(33)), Err("too long"));
+    }
+}
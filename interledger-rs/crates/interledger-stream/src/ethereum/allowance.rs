@@ -0,0 +1,455 @@
+//! Keeping the Treasury's token allowance topped up
+//!
+//! A Treasury that pulls payouts from the operator's own balance with
+//! `transferFrom` can only pay out what the operator approved it to spend.
+//! With `treasury_allowances` set, `allowance(operator, treasury)` of each
+//! listed asset's token is read when the monitor starts and every `interval`
+//! after. Once it is below the asset's `threshold`, an `approve` of the
+//! Treasury for the asset's `cap` is sent, under the same signing lock and
+//! nonce store as payouts. An unlimited approval is only ever sent for an
+//! asset whose cap is explicitly `unlimited`.
+//!
+//! Approvals are not payouts: they are kept in the store as
+//! [`AllowanceApproval`]s beside the payout records and counted under
+//! `payouts.ethereum.allowance_approvals`.
+
+use super::abi::{decode_words, encode_address, encode_call, encode_uint, selector};
+use super::payload::{TxParams, DEFAULT_GAS_LIMIT};
+use super::shutdown::unless_cancelled;
+use super::signer::is_node_signer_error;
+use super::store::Timestamp;
+use super::{metrics, EthereumPayoutService, PayoutError};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+/// How much the Treasury is approved for once its allowance runs low
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowanceCap {
+    /// An allowance of this many base units
+    Amount(u128),
+    /// The largest `uint256`, which most tokens never decrease
+    Unlimited,
+}
+
+impl AllowanceCap {
+    fn word(self) -> [u8; 32] {
+        match self {
+            AllowanceCap::Amount(amount) => encode_uint(amount),
+            AllowanceCap::Unlimited => [0xff; 32],
+        }
+    }
+
+    fn to_json(self) -> Value {
+        match self {
+            AllowanceCap::Amount(amount) => json!(amount.to_string()),
+            AllowanceCap::Unlimited => json!("unlimited"),
+        }
+    }
+
+    fn parse(cap: &str) -> Option<Self> {
+        match cap {
+            "unlimited" => Some(AllowanceCap::Unlimited),
+            amount => amount.parse().ok().map(AllowanceCap::Amount),
+        }
+    }
+}
+
+/// Allowance kept for one asset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenAllowance {
+    /// Address of the asset's ERC-20 token
+    pub token: String,
+    /// Allowance below which the Treasury is approved again, in base units
+    pub threshold: u128,
+    /// Allowance the approval sets
+    pub cap: AllowanceCap,
+}
+
+/// Which allowances are kept and how often they are checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowancePolicy {
+    /// Allowance kept per asset code
+    pub assets: BTreeMap<String, TokenAllowance>,
+    /// Delay between checks
+    pub interval: Duration,
+}
+
+impl AllowancePolicy {
+    /// Parse a comma-separated list of `CODE:token:threshold:cap` entries, the
+    /// cap being a number of base units above the threshold or `unlimited`.
+    /// Example: `EURC:0x1aBa...:1000000000:5000000000,USDC:0xA0b8...:0:unlimited`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut assets = BTreeMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let fields: Vec<&str> = entry.split(':').map(str::trim).collect();
+            let (code, allowance) = match fields.as_slice() {
+                [code, token, threshold, cap] => (
+                    code.to_string(),
+                    TokenAllowance {
+                        token: token.to_string(),
+                        threshold: threshold.parse().ok()?,
+                        cap: AllowanceCap::parse(cap)?,
+                    },
+                ),
+                _ => return None,
+            };
+            // A cap at or below the threshold would be approved again on every check
+            if let AllowanceCap::Amount(cap) = allowance.cap {
+                if cap <= allowance.threshold {
+                    return None;
+                }
+            }
+            assets.insert(code, allowance);
+        }
+        if assets.is_empty() {
+            return None;
+        }
+        Some(AllowancePolicy {
+            assets,
+            interval: Duration::from_secs(300),
+        })
+    }
+}
+
+/// An `approve` sent to top up the Treasury's allowance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowanceApproval {
+    pub asset_code: String,
+    pub token: String,
+    /// Allowance the check found, saturated at `u128::MAX`
+    pub previous: u128,
+    pub cap: AllowanceCap,
+    pub tx_hash: String,
+    pub approved_at: Timestamp,
+}
+
+impl AllowanceApproval {
+    pub(super) fn to_json(&self) -> Value {
+        json!({
+            "asset_code": self.asset_code,
+            "token": self.token,
+            "previous": self.previous.to_string(),
+            "cap": self.cap.to_json(),
+            "tx_hash": self.tx_hash,
+            "approved_at": self.approved_at.to_rfc3339(),
+        })
+    }
+
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        Some(AllowanceApproval {
+            asset_code: value["asset_code"].as_str()?.to_string(),
+            token: value["token"].as_str()?.to_string(),
+            previous: value["previous"].as_str()?.parse().ok()?,
+            cap: AllowanceCap::parse(value["cap"].as_str()?)?,
+            tx_hash: value["tx_hash"].as_str()?.to_string(),
+            approved_at: DateTime::parse_from_rfc3339(value["approved_at"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+        })
+    }
+}
+
+/// Calldata of `allowance(owner, spender)`, `None` unless both are addresses
+pub fn allowance_calldata(owner: &str, spender: &str) -> Option<String> {
+    Some(encode_call(
+        selector("allowance(address,address)"),
+        &[encode_address(owner)?, encode_address(spender)?],
+    ))
+}
+
+/// Calldata of `approve(spender, cap)`, `None` unless `spender` is an address
+pub fn approve_calldata(spender: &str, cap: AllowanceCap) -> Option<String> {
+    Some(encode_call(
+        selector("approve(address,uint256)"),
+        &[encode_address(spender)?, cap.word()],
+    ))
+}
+
+impl EthereumPayoutService {
+    /// Check the Treasury's allowance on every asset of `treasury_allowances`
+    /// and approve it again where it ran low, returning the approvals sent.
+    /// An asset whose check fails is logged and left for the next check.
+    pub async fn check_allowances(&self) -> Vec<AllowanceApproval> {
+        let policy = match &self.config.treasury_allowances {
+            Some(policy) => policy,
+            None => return Vec::new(),
+        };
+        let mut approvals = Vec::new();
+        for (asset_code, allowance) in &policy.assets {
+            match self.top_up_allowance(asset_code, allowance).await {
+                Ok(Some(approval)) => approvals.push(approval),
+                Ok(None) => {}
+                Err(err) => {
+                    metrics::allowance_approval(
+                        self.config.tenant.as_deref(),
+                        asset_code,
+                        "failed",
+                    );
+                    error!(
+                        "ALERT: Keeping the Treasury's {} allowance failed: {}",
+                        asset_code, err
+                    );
+                }
+            }
+        }
+        approvals
+    }
+
+    async fn top_up_allowance(
+        &self,
+        asset_code: &str,
+        allowance: &TokenAllowance,
+    ) -> Result<Option<AllowanceApproval>, PayoutError> {
+        let treasury = &self.config.treasury_address;
+        let previous = self.allowance_of(&allowance.token, treasury).await?;
+        if previous >= allowance.threshold {
+            debug!(
+                "Treasury's {} allowance of {} is above {}",
+                asset_code, previous, allowance.threshold
+            );
+            return Ok(None);
+        }
+        let data = approve_calldata(treasury, allowance.cap)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid Treasury address {}", treasury)))?;
+        let tx_hash = self.send_approval(&allowance.token, &data).await?;
+        info!(
+            "Treasury's {} allowance of {} is below {}, approved {:?} in {}",
+            asset_code, previous, allowance.threshold, allowance.cap, tx_hash
+        );
+        metrics::allowance_approval(self.config.tenant.as_deref(), asset_code, "approved");
+        let approval = AllowanceApproval {
+            asset_code: asset_code.to_string(),
+            token: allowance.token.clone(),
+            previous,
+            cap: allowance.cap,
+            tx_hash,
+            approved_at: self.clock.now(),
+        };
+        self.store.save_allowance_approval(approval.clone());
+        Ok(Some(approval))
+    }
+
+    /// What the operator allows `spender` to take of `token`, saturated at `u128::MAX`
+    async fn allowance_of(&self, token: &str, spender: &str) -> Result<u128, PayoutError> {
+        let operator = self.operator_address();
+        let data = allowance_calldata(&operator, spender)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid spender address {}", spender)))?;
+        let result = self
+            .read_at_block("eth_call", vec![json!({ "to": token, "data": data })])
+            .await?;
+        let word = result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .ok_or_else(|| {
+                PayoutError::InvalidResponse(format!("allowance returned {}", result))
+            })?;
+        if word[..16].iter().any(|byte| *byte != 0) {
+            return Ok(u128::MAX);
+        }
+        let mut low = [0; 16];
+        low.copy_from_slice(&word[16..]);
+        Ok(u128::from_be_bytes(low))
+    }
+
+    /// Send `data` to `token` with the next operator nonce, as payouts are sent
+    async fn send_approval(&self, token: &str, data: &str) -> Result<String, PayoutError> {
+        let _signing = self.signing.read().await;
+        let lease = self.lease_nonce().await?;
+        let result = self
+            .send_approval_with_nonce(token, data, lease.nonce)
+            .await;
+        self.settle_nonce(&lease, result.as_ref().err()).await;
+        result
+    }
+
+    async fn send_approval_with_nonce(
+        &self,
+        token: &str,
+        data: &str,
+        nonce: u64,
+    ) -> Result<String, PayoutError> {
+        let params = TxParams {
+            nonce,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            gas_price: self.get_gas_price().await?,
+        };
+        let submission = self.sign_transaction(token, data, 0, params).await?;
+        match self.rpc(submission.request).await {
+            Ok(result) => {
+                self.note_broadcast(nonce);
+                result.as_str().map(str::to_string).ok_or_else(|| {
+                    PayoutError::InvalidResponse("No transaction hash in response".to_string())
+                })
+            }
+            Err(PayoutError::Rpc { message, .. }) if is_node_signer_error(&message) => {
+                Err(PayoutError::SignerUnavailable(message))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Spawn a task checking allowances now and every `interval` of
+    /// `treasury_allowances`, `None` when it is not set. The task stops on
+    /// shutdown.
+    pub fn spawn_allowance_monitor(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval = self.config.treasury_allowances.as_ref()?.interval;
+        let service = Arc::clone(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                service.check_allowances().await;
+            }
+        }))
+    }
+}
+
+/// Whether a send that failed with `err` was refused by the node, leaving its
+/// nonce free for the next transaction
+pub(super) fn leaves_nonce_unused(err: &PayoutError) -> bool {
+    matches!(
+        err,
+        PayoutError::Rpc { .. }
+            | PayoutError::Reverted(_)
+            | PayoutError::GasCostTooHigh { .. }
+            | PayoutError::SignerUnavailable(_)
+            | PayoutError::Cancelled
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, MockTransport, TEST_OPERATOR, TEST_TREASURY,
+    };
+    use super::*;
+
+    const TOKEN: &str = "0x3333333333333333333333333333333333333333";
+
+    /// Node reporting an allowance of `allowance`, as a 0x-prefixed word
+    fn node(allowance: &str) -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", json!(allowance));
+        transport.on_result("eth_getTransactionCount", json!("0x7"));
+        transport.on_result("eth_gasPrice", json!("0x1"));
+        transport.on_result("eth_sendRawTransaction", json!("0xapprove"));
+        transport
+    }
+
+    fn keeping(cap: AllowanceCap) -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        let spec = match cap {
+            AllowanceCap::Amount(cap) => format!("EURC:{}:100:{}", TOKEN, cap),
+            AllowanceCap::Unlimited => format!("EURC:{}:100:unlimited", TOKEN),
+        };
+        config.treasury_allowances = AllowancePolicy::parse(&spec);
+        config
+    }
+
+    #[test]
+    fn parses_allowance_policies() {
+        let policy = AllowancePolicy::parse(&format!("EURC:{}:100:1000", TOKEN)).unwrap();
+        assert_eq!(
+            policy.assets["EURC"],
+            TokenAllowance {
+                token: TOKEN.to_string(),
+                threshold: 100,
+                cap: AllowanceCap::Amount(1000),
+            }
+        );
+        assert!(AllowancePolicy::parse(&format!("EURC:{}:100:100", TOKEN)).is_none());
+        assert!(AllowancePolicy::parse(&format!("EURC:{}:100:max", TOKEN)).is_none());
+        assert!(AllowancePolicy::parse("EURC:100:1000").is_none());
+        assert!(AllowancePolicy::parse("").is_none());
+    }
+
+    #[tokio::test]
+    async fn approves_the_treasury_once_its_allowance_runs_low() {
+        let transport = node(&format!("0x{:064x}", 99));
+        let service = test_service(keeping(AllowanceCap::Amount(1000)), transport.clone());
+        let approvals = service.check_allowances().await;
+        assert_eq!(approvals.len(), 1);
+        assert_eq!(approvals[0].previous, 99);
+        assert_eq!(approvals[0].tx_hash, "0xapprove");
+        assert_eq!(service.store().allowance_approvals(), approvals);
+
+        let read = &transport.calls("eth_call")[0]["params"][0];
+        assert_eq!(read["to"], TOKEN);
+        assert_eq!(
+            read["data"].as_str(),
+            allowance_calldata(TEST_OPERATOR, TEST_TREASURY).as_deref()
+        );
+        assert!(read["data"].as_str().unwrap().starts_with("0xdd62ed3e"));
+
+        let sent = &transport.sent_transactions()[0];
+        assert_eq!(sent["to"], TOKEN);
+        assert_eq!(sent["nonce"], "0x7");
+        assert_eq!(
+            sent["data"],
+            format!(
+                "0x095ea7b3{:0>64}{:064x}",
+                TEST_TREASURY[2..].to_lowercase(),
+                1000
+            )
+        );
+    }
+
+    #[test]
+    fn file_stores_keep_approvals() {
+        use super::super::{FilePayoutStore, PayoutStore};
+        use chrono::TimeZone;
+
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let approval = AllowanceApproval {
+            asset_code: "EURC".to_string(),
+            token: TOKEN.to_string(),
+            previous: u128::MAX - 1,
+            cap: AllowanceCap::Unlimited,
+            tx_hash: "0xapprove".to_string(),
+            approved_at: Utc.timestamp_opt(10, 0).unwrap(),
+        };
+        FilePayoutStore::open(&path)
+            .unwrap()
+            .save_allowance_approval(approval.clone());
+        let store = FilePayoutStore::open(&path).unwrap();
+        assert_eq!(store.allowance_approvals(), vec![approval]);
+        assert_eq!(store.highest_serial(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn approves_unlimited_only_when_configured() {
+        let transport = node(&format!("0x{:064x}", 0));
+        let service = test_service(keeping(AllowanceCap::Unlimited), transport.clone());
+        assert_eq!(service.check_allowances().await.len(), 1);
+        let sent = &transport.sent_transactions()[0];
+        assert!(sent["data"].as_str().unwrap().ends_with(&"f".repeat(64)));
+    }
+
+    #[tokio::test]
+    async fn leaves_a_sufficient_allowance_alone() {
+        for allowance in [format!("0x{:064x}", 100), format!("0x{}", "f".repeat(64))] {
+            let transport = node(&allowance);
+            let service = test_service(keeping(AllowanceCap::Amount(1000)), transport.clone());
+            assert!(service.check_allowances().await.is_empty());
+            assert_eq!(transport.call_count("eth_call"), 1);
+            assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+            assert!(service.store().allowance_approvals().is_empty());
+        }
+
+        // Nothing is read without a policy
+        let transport = node("0x");
+        let service = test_service(test_config(), transport.clone());
+        assert!(service.check_allowances().await.is_empty());
+        assert_eq!(transport.request_count(), 0);
+    }
+}
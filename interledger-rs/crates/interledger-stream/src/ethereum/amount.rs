@@ -0,0 +1,209 @@
+//! Amounts tagged with their unit
+//!
+//! Amounts received over ILP, token base units paid out and wei spent on gas
+//! are all integers, and using one for another pays the wrong amount. Each
+//! has its own type here: arithmetic is only defined within a unit, and going
+//! from one unit to another takes a named conversion. Public entry points
+//! still take integers and wrap them right away.
+
+use super::{ExchangeRate, RoundingMode};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Amount received over ILP, in the base units of the account's asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct IlpAmount(u64);
+
+impl IlpAmount {
+    pub const fn new(amount: u64) -> Self {
+        IlpAmount(amount)
+    }
+
+    pub const fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The amount as base units of `asset`, when the ILP account is
+    /// denominated in the payout asset itself
+    pub fn in_asset(self, asset: &str) -> TokenAmount {
+        TokenAmount::new(asset, self.0)
+    }
+
+    /// The amount converted at `rate` into its target asset, along with the
+    /// residue rounding by `mode` left, see [`ExchangeRate::convert_rounded`].
+    /// `None` if the result overflows.
+    pub fn convert(self, rate: &ExchangeRate, mode: RoundingMode) -> Option<(TokenAmount, i128)> {
+        let (converted, residue) = rate.convert_rounded(self.0, mode)?;
+        Some((TokenAmount::new(rate.to_asset.clone(), converted), residue))
+    }
+
+    /// The amount at asset scale `to` instead of `from`, rounding down, e.g.
+    /// 1500 at scale 3 is 1 at scale 0. `None` if the result overflows.
+    pub fn rescale(self, from: u8, to: u8) -> Option<IlpAmount> {
+        let amount = u128::from(self.0);
+        let rescaled = if to >= from {
+            amount.checked_mul(10u128.checked_pow(u32::from(to - from))?)?
+        } else {
+            // Dividing by more than u128::MAX leaves nothing
+            10u128
+                .checked_pow(u32::from(from - to))
+                .map_or(0, |divisor| amount / divisor)
+        };
+        u64::try_from(rescaled).ok().map(IlpAmount)
+    }
+}
+
+/// Amount of a token, in its smallest unit
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TokenAmount {
+    pub asset: String,
+    pub base_units: u64,
+}
+
+impl TokenAmount {
+    pub fn new(asset: impl Into<String>, base_units: u64) -> Self {
+        TokenAmount {
+            asset: asset.into(),
+            base_units,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.base_units == 0
+    }
+
+    /// `None` if the amounts are of different assets or their sum overflows
+    pub fn checked_add(&self, other: &TokenAmount) -> Option<TokenAmount> {
+        if self.asset != other.asset {
+            return None;
+        }
+        Some(TokenAmount::new(
+            self.asset.clone(),
+            self.base_units.checked_add(other.base_units)?,
+        ))
+    }
+
+    /// `None` if the amounts are of different assets or `other` is larger
+    pub fn checked_sub(&self, other: &TokenAmount) -> Option<TokenAmount> {
+        if self.asset != other.asset {
+            return None;
+        }
+        Some(TokenAmount::new(
+            self.asset.clone(),
+            self.base_units.checked_sub(other.base_units)?,
+        ))
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.base_units, self.asset)
+    }
+}
+
+/// Amount of the chain's native currency spent on gas, in wei
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Wei(u128);
+
+impl Wei {
+    pub const fn new(wei: u128) -> Self {
+        Wei(wei)
+    }
+
+    pub const fn value(self) -> u128 {
+        self.0
+    }
+
+    /// Cost of `gas` units at `price` per unit, `None` if it overflows
+    pub fn for_gas(gas: u64, price: Wei) -> Option<Wei> {
+        u128::from(gas).checked_mul(price.0).map(Wei)
+    }
+
+    pub fn checked_add(self, other: Wei) -> Option<Wei> {
+        self.0.checked_add(other.0).map(Wei)
+    }
+
+    pub fn saturating_add(self, other: Wei) -> Wei {
+        Wei(self.0.saturating_add(other.0))
+    }
+}
+
+impl fmt::Display for Wei {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} wei", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn rate(rate: u128, scale: u8) -> ExchangeRate {
+        ExchangeRate {
+            from_asset: "XRP".to_string(),
+            to_asset: "EURC".to_string(),
+            rate,
+            scale,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn ilp_amounts_convert_into_the_rates_target_asset() {
+        let amount = IlpAmount::new(2_500_000);
+        assert_eq!(amount.in_asset("EURC"), TokenAmount::new("EURC", 2_500_000));
+        let (converted, residue) = amount
+            .convert(&rate(512, 6), RoundingMode::HalfEven)
+            .unwrap();
+        assert_eq!(converted, TokenAmount::new("EURC", 1280));
+        assert_eq!(residue, 0);
+        assert!(IlpAmount::new(u64::MAX)
+            .convert(&rate(2, 0), RoundingMode::Down)
+            .is_none());
+    }
+
+    #[test]
+    fn ilp_amounts_rescale_between_asset_scales() {
+        let amount = IlpAmount::new(1_999);
+        assert_eq!(amount.rescale(3, 3), Some(amount));
+        assert_eq!(amount.rescale(3, 0), Some(IlpAmount::new(1)));
+        assert_eq!(amount.rescale(9, 6), Some(IlpAmount::new(1)));
+        assert_eq!(amount.rescale(9, 5), Some(IlpAmount::new(0)));
+        assert_eq!(amount.rescale(2, 6), Some(IlpAmount::new(19_990_000)));
+        assert_eq!(amount.rescale(255, 0), Some(IlpAmount::new(0)));
+        assert_eq!(IlpAmount::new(u64::MAX).rescale(0, 1), None);
+        assert_eq!(amount.rescale(0, 40), None);
+    }
+
+    #[test]
+    fn token_arithmetic_stays_within_an_asset() {
+        let eurc = TokenAmount::new("EURC", 700);
+        assert_eq!(
+            eurc.checked_add(&TokenAmount::new("EURC", 300)),
+            Some(TokenAmount::new("EURC", 1000))
+        );
+        assert_eq!(eurc.checked_add(&TokenAmount::new("USDC", 300)), None);
+        assert_eq!(eurc.checked_sub(&TokenAmount::new("EURC", 701)), None);
+        assert_eq!(
+            TokenAmount::new("EURC", u64::MAX).checked_add(&TokenAmount::new("EURC", 1)),
+            None
+        );
+        assert_eq!(eurc.to_string(), "700 EURC");
+    }
+
+    #[test]
+    fn gas_costs_are_checked() {
+        let price = Wei::new(2_000_000_000);
+        assert_eq!(
+            Wei::for_gas(21_000, price),
+            Some(Wei::new(42_000_000_000_000))
+        );
+        assert_eq!(Wei::for_gas(2, Wei::new(u128::MAX)), None);
+        assert_eq!(Wei::new(u128::MAX).checked_add(Wei::new(1)), None);
+        assert_eq!(
+            Wei::new(u128::MAX).saturating_add(Wei::new(1)),
+            Wei::new(u128::MAX)
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//! Human approval of payouts above an asset's approval threshold
+//!
+//! A held payout is recorded as `PendingApproval` under the payment ID of its
+//! request, without a Safe transaction hash, and is not broadcast. Approving
+//! it marks it `Approved` and sends it; rejecting it, or letting it outlive
+//! `approval_ttl`, marks it `Rejected` for good.
+
+use super::{
+    plan_payouts, EthereumPayoutService, PayoutError, PayoutOutcome, PayoutPlan, PayoutRecord,
+    PayoutRequest, PayoutStatus, TokenAmount,
+};
+use chrono::TimeZone;
+use tracing::info;
+
+/// Number of records read per page while listing held payouts
+const APPROVAL_PAGE_SIZE: usize = 500;
+
+/// Notified as payouts enter and leave the approval queue, e.g. to drive an approval UI
+pub trait ApprovalObserver: Send + Sync {
+    /// A payout was held for approval
+    fn approval_requested(&self, record: &PayoutRecord);
+
+    /// A held payout was approved, rejected or expired; `record` has its new status
+    fn approval_resolved(&self, record: &PayoutRecord);
+}
+
+/// Whether a request above its asset's approval threshold may be sent
+#[derive(Debug)]
+pub(super) enum Approval {
+    /// Below the threshold, or approved already
+    NotNeeded,
+    /// Held by an earlier attempt that is still waiting
+    Held(Box<PayoutRecord>),
+    /// To be held before it is sent
+    Required,
+}
+
+impl PayoutRecord {
+    /// Whether the payout is held for approval by this service rather than by Safe owners
+    fn is_held(&self) -> bool {
+        self.status == PayoutStatus::PendingApproval && self.safe_tx_hash.is_none()
+    }
+}
+
+impl EthereumPayoutService {
+    /// Whether the request must be approved before it is sent, refusing it if
+    /// it was rejected
+    pub(super) fn approval(
+        &self,
+        request: &PayoutRequest,
+        plans: &[PayoutPlan],
+        amount: &TokenAmount,
+    ) -> Result<Approval, PayoutError> {
+        let threshold = self
+            .config
+            .assets
+            .get(&plans[0].destination.asset_code)
+            .and_then(|asset| asset.approval_threshold);
+        match threshold {
+            Some(threshold) if amount.base_units > threshold => {}
+            _ => return Ok(Approval::NotNeeded),
+        }
+
+        let payment_id = self.config.payment_id_of(request);
+        if let Some(existing) = self.lookup(&payment_id) {
+            let existing = self.expire_if_due(existing);
+            match existing.status {
+                _ if existing.is_held() => return Ok(Approval::Held(Box::new(existing))),
+                PayoutStatus::Rejected => return Err(rejected(&existing)),
+                PayoutStatus::Approved => return Ok(Approval::NotNeeded),
+                // A failed attempt needs a fresh approval
+                _ if existing.is_retryable() => {}
+                _ => return Ok(Approval::NotNeeded),
+            }
+        }
+        Ok(Approval::Required)
+    }
+
+    /// Hold the request as decided by [`Self::approval`]
+    pub(super) fn hold_for_approval(
+        &self,
+        request: &PayoutRequest,
+        plans: &[PayoutPlan],
+        amount: &TokenAmount,
+        approval: Approval,
+    ) -> Option<PayoutOutcome> {
+        match approval {
+            Approval::NotNeeded => return None,
+            Approval::Held(existing) => {
+                return Some(PayoutOutcome::HeldForApproval {
+                    payment_id: existing.payment_id_hex(),
+                })
+            }
+            Approval::Required => {}
+        }
+
+        let payment_id = self.config.payment_id_of(request);
+        let mut record =
+            self.payout_record(request, &plans[0], None, PayoutStatus::PendingApproval);
+        record.payment_id = payment_id;
+        record.amount = amount.base_units;
+        record.deadline = self
+            .config
+            .approval_ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| self.clock.now() + ttl);
+        info!(
+            "Holding payout {} of {} {} to {} for approval",
+            record.payment_id_hex(),
+            amount,
+            record.asset_code,
+            self.display_address(&record.recipient)
+        );
+        self.store.save(record.clone());
+        if let Some(observer) = &self.approval_observer {
+            observer.approval_requested(&record);
+        }
+        Some(PayoutOutcome::HeldForApproval {
+            payment_id: record.payment_id_hex(),
+        })
+    }
+
+    /// Payouts waiting for approval, oldest first
+    pub fn list_pending_approval(&self) -> Vec<PayoutRecord> {
+        // Records are timestamped with the service clock, so this covers all of them
+        let from = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let to = self.clock.now() + chrono::Duration::days(1);
+        let mut pending = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.store.list_range(from, to, cursor, APPROVAL_PAGE_SIZE);
+            pending.extend(
+                page.iter()
+                    .filter(|record| record.is_held())
+                    .map(|record| self.expire_if_due(record.clone()))
+                    .filter(PayoutRecord::is_held),
+            );
+            if page.len() < APPROVAL_PAGE_SIZE {
+                return pending;
+            }
+            cursor = page.last().map(Into::into);
+        }
+    }
+
+    /// Approve a held payout and send it
+    pub async fn approve(&self, payment_id: &[u8; 32]) -> Result<PayoutOutcome, PayoutError> {
+        let mut record = self.held_record(payment_id)?;
+        if self.is_degraded() {
+            return Err(PayoutError::NotAuthorized {
+                operator: self.operator_address(),
+            });
+        }
+        let mut plans = plan_payouts(
+            &self.config,
+            &record.destination,
+            record.amount,
+            record.sequence,
+        )?;
+        for plan in &mut plans {
+            plan.conversion = record.conversion.clone();
+            if let Some(memo) = &record.memo {
+                plan.attach_memo(memo)?;
+            }
+        }
+
+        record.status = PayoutStatus::Approved;
+        self.resolve(&record);
+        info!("Payout {} approved", record.payment_id_hex());
+
+        let request = PayoutRequest::for_record(&record);
+        if self.is_paused() {
+            return Ok(self.defer(request));
+        }
+        self.execute_plans(&request, &plans).await
+    }
+
+    /// Reject a held payout; it is never sent
+    pub fn reject(
+        &self,
+        payment_id: &[u8; 32],
+        reason: impl Into<String>,
+    ) -> Result<PayoutRecord, PayoutError> {
+        let mut record = self.held_record(payment_id)?;
+        record.status = PayoutStatus::Rejected;
+        record.last_error = Some(reason.into());
+        self.resolve(&record);
+        info!(
+            "Payout {} rejected: {}",
+            record.payment_id_hex(),
+            record.last_error.as_deref().unwrap_or_default()
+        );
+        Ok(record)
+    }
+
+    fn held_record(&self, payment_id: &[u8; 32]) -> Result<PayoutRecord, PayoutError> {
+        let record = self.lookup(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        let record = self.expire_if_due(record);
+        match record.status {
+            _ if record.is_held() => Ok(record),
+            PayoutStatus::Rejected => Err(rejected(&record)),
+            status => Err(PayoutError::Config(format!(
+                "Payout {} is {:?}, not awaiting approval",
+                record.payment_id_hex(),
+                status
+            ))),
+        }
+    }
+
+    /// Reject a held payout whose approval deadline has passed
+    fn expire_if_due(&self, mut record: PayoutRecord) -> PayoutRecord {
+        let expired = record.is_held()
+            && record
+                .deadline
+                .is_some_and(|deadline| deadline <= self.clock.now());
+        if expired {
+            record.status = PayoutStatus::Rejected;
+            record.last_error = Some("Approval expired".to_string());
+            self.resolve(&record);
+            info!("Approval of payout {} expired", record.payment_id_hex());
+        }
+        record
+    }
+
+    fn resolve(&self, record: &PayoutRecord) {
+        self.store.save(record.clone());
+        if let Some(observer) = &self.approval_observer {
+            observer.approval_resolved(record);
+        }
+    }
+}
+
+fn rejected(record: &PayoutRecord) -> PayoutError {
+    PayoutError::Rejected {
+        payment_id: record.payment_id_hex(),
+        reason: record.last_error.clone().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, TEST_DESTINATION,
+    };
+    use super::super::EthereumPayoutConfig;
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: Mutex<Vec<(&'static str, PayoutStatus)>>,
+    }
+
+    impl ApprovalObserver for RecordingObserver {
+        fn approval_requested(&self, record: &PayoutRecord) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(("requested", record.status));
+        }
+
+        fn approval_resolved(&self, record: &PayoutRecord) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(("resolved", record.status));
+        }
+    }
+
+    fn approval_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config
+            .assets
+            .apply_approval_thresholds("EURC:1000")
+            .unwrap();
+        config.approval_ttl = Some(Duration::from_secs(3600));
+        config
+    }
+
+    fn held_id(sequence: u64) -> [u8; 32] {
+        EthereumPayoutService::generate_payment_id(TEST_DESTINATION, sequence)
+    }
+
+    #[tokio::test]
+    async fn holds_payouts_above_the_threshold() {
+        let transport = mock_chain();
+        let observer = Arc::new(RecordingObserver::default());
+        let service = test_service(approval_config(), transport.clone())
+            .with_approval_observer(observer.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 1000, 1)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, PayoutOutcome::Submitted { .. }));
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 1001, 2)
+            .await
+            .unwrap();
+        let expected = PayoutOutcome::HeldForApproval {
+            payment_id: format!("0x{}", hex::encode(held_id(2))),
+        };
+        assert_eq!(outcome, expected);
+        // Replays report the same hold without notifying again
+        assert_eq!(
+            service
+                .execute_payout(TEST_DESTINATION, 1001, 2)
+                .await
+                .unwrap(),
+            expected
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        let pending = service.list_pending_approval();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].amount, 1001);
+        assert_eq!(
+            *observer.events.lock().unwrap(),
+            vec![("requested", PayoutStatus::PendingApproval)]
+        );
+    }
+
+    #[tokio::test]
+    async fn approval_sends_the_payout() {
+        let transport = mock_chain();
+        let observer = Arc::new(RecordingObserver::default());
+        let service = test_service(approval_config(), transport.clone())
+            .with_approval_observer(observer.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 5000, 1)
+            .await
+            .unwrap();
+
+        let outcome = service.approve(&held_id(1)).await.unwrap();
+        assert_eq!(
+            outcome,
+            PayoutOutcome::Submitted {
+                tx_hash: "0xabc".to_string()
+            }
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+        let record = service.store.get(&held_id(1)).unwrap();
+        assert_eq!(record.status, PayoutStatus::Submitted);
+        assert!(service.list_pending_approval().is_empty());
+        assert_eq!(
+            observer.events.lock().unwrap().last(),
+            Some(&("resolved", PayoutStatus::Approved))
+        );
+        assert!(matches!(
+            service.approve(&held_id(1)).await,
+            Err(PayoutError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejection_is_final() {
+        let transport = mock_chain();
+        let service = test_service(approval_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 5000, 1)
+            .await
+            .unwrap();
+
+        let record = service.reject(&held_id(1), "unknown recipient").unwrap();
+        assert_eq!(record.status, PayoutStatus::Rejected);
+        assert!(service.list_pending_approval().is_empty());
+        assert!(matches!(
+            service.execute_payout(TEST_DESTINATION, 5000, 1).await,
+            Err(PayoutError::Rejected { reason, .. }) if reason == "unknown recipient"
+        ));
+        assert!(service.approve(&held_id(1)).await.is_err());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[tokio::test]
+    async fn approvals_expire() {
+        let clock = FakeClock::new();
+        let observer = Arc::new(RecordingObserver::default());
+        let service = test_service(approval_config(), mock_chain())
+            .with_clock(clock.clone())
+            .with_approval_observer(observer.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 5000, 1)
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(3600));
+        assert!(service.list_pending_approval().is_empty());
+        let record = service.store.get(&held_id(1)).unwrap();
+        assert_eq!(record.status, PayoutStatus::Rejected);
+        assert_eq!(record.last_error.as_deref(), Some("Approval expired"));
+        assert_eq!(
+            observer.events.lock().unwrap().last(),
+            Some(&("resolved", PayoutStatus::Rejected))
+        );
+        assert!(matches!(
+            service.approve(&held_id(1)).await,
+            Err(PayoutError::Rejected { .. })
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+//! Destinations naming their asset by token address
+//!
+//! Some integrators put the ERC-20 contract's address in the asset segment
+//! instead of an asset code, e.g. `g.eth.1.0xA0b8...eB48.0x7099...79C8.token`.
+//! A segment is read as a token address exactly when it is `0x` followed by
+//! 40 hex digits, and as an asset code otherwise, so a code is never looked
+//! up by address nor an address by code. The address is matched against the
+//! registry's token addresses ignoring case, and the destination then names
+//! the one asset it matched while `asset_segment` keeps it as written; records
+//! store both. An address registered for several assets is refused as
+//! ambiguous. One that is not registered is refused too, unless
+//! `accept_token_addresses` is set: it is then paid by direct transfer of that
+//! token under the address as asset code, in base units since its decimals
+//! are unknown.
+
+use super::destination::is_hex_address;
+use super::{AssetInfo, AssetRegistry, EthereumDestination, EthereumPayoutConfig, PayoutError};
+
+impl AssetRegistry {
+    /// Assets whose token is at `token`, ignoring case, by code
+    pub fn by_token_address(&self, token: &str) -> Vec<&AssetInfo> {
+        let mut assets: Vec<&AssetInfo> = self
+            .codes()
+            .filter_map(|code| self.get(code))
+            .filter(|asset| {
+                asset
+                    .token_address
+                    .as_deref()
+                    .is_some_and(|address| address.eq_ignore_ascii_case(token))
+            })
+            .collect();
+        assets.sort_by(|a, b| a.code.cmp(&b.code));
+        assets
+    }
+}
+
+impl EthereumDestination {
+    /// The asset segment as written, if it is not the asset code
+    pub(super) fn written_asset(&self) -> Option<String> {
+        Some(self.asset_segment.clone()).filter(|segment| *segment != self.asset_code)
+    }
+}
+
+impl EthereumPayoutConfig {
+    /// `eth_dest` with a token address in its asset segment resolved to the
+    /// registered asset
+    pub(super) fn resolve_asset(
+        &self,
+        mut eth_dest: EthereumDestination,
+        destination: &str,
+    ) -> Result<EthereumDestination, PayoutError> {
+        if !is_hex_address(&eth_dest.asset_segment) {
+            return Ok(eth_dest);
+        }
+        let token = &eth_dest.asset_segment;
+        match self.assets.by_token_address(token).as_slice() {
+            [asset] => eth_dest.asset_code = asset.code.clone(),
+            [] if self.accept_token_addresses => {}
+            [] => {
+                return Err(PayoutError::UnknownTokenAddress {
+                    destination: destination.to_string(),
+                    token: token.clone(),
+                })
+            }
+            assets => {
+                return Err(PayoutError::AmbiguousTokenAddress {
+                    token: token.clone(),
+                    codes: assets.iter().map(|asset| asset.code.clone()).collect(),
+                })
+            }
+        }
+        Ok(eth_dest)
+    }
+
+    /// Token paid directly for an asset code that is an accepted but
+    /// unregistered token address
+    pub(super) fn unregistered_token<'a>(&self, asset_code: &'a str) -> Option<&'a str> {
+        Some(asset_code).filter(|code| {
+            self.accept_token_addresses && is_hex_address(code) && self.assets.get(code).is_none()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service};
+    use super::super::{plan_payout, FilePayoutStore, PayoutMode};
+    use super::*;
+    use std::sync::Arc;
+
+    const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn destination(asset: &str) -> String {
+        format!("test.receiver.eth.31337.{}.{}.abc123", asset, RECIPIENT)
+    }
+
+    fn usdc_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config
+            .assets
+            .insert(AssetInfo::direct_transfer("USDC", 6, USDC));
+        config
+    }
+
+    #[test]
+    fn symbols_and_addresses_name_the_same_asset() {
+        let config = usdc_config();
+        let by_symbol = config.parse_destination(&destination("USDC")).unwrap();
+        assert_eq!(
+            (
+                by_symbol.asset_code.as_str(),
+                by_symbol.asset_segment.as_str()
+            ),
+            ("USDC", "USDC")
+        );
+        let lowercase = USDC.to_ascii_lowercase();
+        let by_address = config.parse_destination(&destination(&lowercase)).unwrap();
+        assert_eq!(by_address.asset_code, "USDC");
+        assert_eq!(by_address.asset_segment, lowercase);
+        let plan = plan_payout(&config, &destination(&lowercase), 100, 1).unwrap();
+        assert_eq!(
+            (plan.mode, plan.to.as_str()),
+            (PayoutMode::DirectTransfer, USDC)
+        );
+
+        // A code shaped like an address is only ever matched as an address
+        let mut config = usdc_config();
+        config.assets.insert(AssetInfo::new(USDC, 6));
+        let shadowed = config.parse_destination(&destination(USDC)).unwrap();
+        assert_eq!(shadowed.asset_code, "USDC");
+
+        // Nor is an address shared by two assets resolved to either
+        let mut config = usdc_config();
+        config
+            .assets
+            .insert(AssetInfo::direct_transfer("USDC.e", 6, lowercase));
+        assert!(config.parse_destination(&destination(USDC)).is_none());
+        assert!(matches!(
+            config.destination_error(&destination(USDC)),
+            PayoutError::AmbiguousTokenAddress { codes, .. } if codes == ["USDC", "USDC.e"]
+        ));
+    }
+
+    #[tokio::test]
+    async fn unregistered_addresses_are_refused_unless_accepted() {
+        let unknown = "0x3333333333333333333333333333333333333333";
+        let transport = mock_chain();
+        let service = test_service(usdc_config(), transport.clone());
+        assert!(matches!(
+            service.execute_payout(&destination(unknown), 100, 1).await,
+            Err(PayoutError::UnknownTokenAddress { token, .. }) if token == unknown
+        ));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        // Direct transfers need a store that survives restarts
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut config = usdc_config();
+        config.accept_token_addresses = true;
+        let service = test_service(config, transport.clone())
+            .with_store(Arc::new(FilePayoutStore::open(&path).unwrap()));
+        service
+            .execute_payout(&destination(unknown), 100, 1)
+            .await
+            .unwrap();
+        let sent = &transport.sent_transactions()[0];
+        assert_eq!(sent["to"], unknown);
+        let payment_id = service.config().payment_id(&destination(unknown), 1);
+        let record = service.store().get(&payment_id).unwrap();
+        // The segment is the code, so is not noted twice
+        assert_eq!(record.asset_code, unknown);
+        assert_eq!(record.asset_segment, None);
+        assert_eq!(record.decimals, 0);
+
+        // Records of registered addresses note the segment beside the code
+        service
+            .execute_payout(&destination(&USDC.to_ascii_lowercase()), 100, 2)
+            .await
+            .unwrap();
+        let payment_id = service
+            .config()
+            .payment_id(&destination(&USDC.to_ascii_lowercase()), 2);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.asset_code, "USDC");
+        assert_eq!(record.asset_segment, Some(USDC.to_ascii_lowercase()));
+    }
+}
@@ -0,0 +1,337 @@
+//! Registry of assets that can be paid out
+
+use std::collections::HashMap;
+
+/// How payouts of an asset reach the recipient
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutMode {
+    /// `payoutToUser` on the Treasury, which deduplicates payment IDs itself
+    Treasury,
+    /// `transfer` on the token from the operator account.
+    ///
+    /// There is no contract-side deduplication, so idempotency relies entirely
+    /// on the payout store, which must be persistent.
+    DirectTransfer,
+    /// Plain value transfer of the chain's native asset from the operator
+    /// account, deduplicated through the payout store like `DirectTransfer`
+    Native,
+    /// EIP-3009 `transferWithAuthorization` signed by the operator and
+    /// submitted by a relayer, which pays the gas. The token rejects reused
+    /// authorization nonces, so the payment ID doubles as the nonce.
+    Authorization,
+}
+
+impl PayoutMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "treasury" => Some(PayoutMode::Treasury),
+            "direct_transfer" => Some(PayoutMode::DirectTransfer),
+            "native" => Some(PayoutMode::Native),
+            "authorization" => Some(PayoutMode::Authorization),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PayoutMode::Treasury => "treasury",
+            PayoutMode::DirectTransfer => "direct_transfer",
+            PayoutMode::Native => "native",
+            PayoutMode::Authorization => "authorization",
+        }
+    }
+
+    /// Whether nothing on-chain rejects a replayed payout, leaving idempotency to the store
+    pub fn relies_on_store(self) -> bool {
+        matches!(self, PayoutMode::DirectTransfer | PayoutMode::Native)
+    }
+}
+
+/// Name and version of a token's EIP-712 domain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenDomain {
+    pub name: String,
+    pub version: String,
+}
+
+/// Static information about a payout asset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetInfo {
+    pub code: String,
+    pub decimals: u8,
+    pub mode: PayoutMode,
+    /// ERC-20 contract address, required unless paid out through the Treasury
+    pub token_address: Option<String>,
+    /// Largest amount sent in one transaction; larger payouts are split
+    pub max_payout_per_tx: Option<u64>,
+    /// Largest amount paid out per UTC day across all recipients
+    pub daily_cap: Option<u64>,
+    /// Largest amount paid out per UTC day to any one recipient
+    pub recipient_daily_cap: Option<u64>,
+    /// Payouts above this amount are held until approved
+    pub approval_threshold: Option<u64>,
+    /// Payouts below this amount are accumulated and flushed on a schedule
+    pub min_payout: Option<u64>,
+    /// EIP-712 domain of the token; fetched from `DOMAIN_SEPARATOR()` when unset
+    pub domain: Option<TokenDomain>,
+    /// The token deducts a fee on transfer, so recipients are expected to
+    /// receive less than was sent
+    pub fee_on_transfer: bool,
+}
+
+impl AssetInfo {
+    /// An asset paid out through the Treasury
+    pub fn new(code: impl Into<String>, decimals: u8) -> Self {
+        AssetInfo {
+            code: code.into(),
+            decimals,
+            mode: PayoutMode::Treasury,
+            token_address: None,
+            max_payout_per_tx: None,
+            daily_cap: None,
+            recipient_daily_cap: None,
+            approval_threshold: None,
+            min_payout: None,
+            domain: None,
+            fee_on_transfer: false,
+        }
+    }
+
+    /// An asset transferred directly from the operator account
+    pub fn direct_transfer(
+        code: impl Into<String>,
+        decimals: u8,
+        token_address: impl Into<String>,
+    ) -> Self {
+        AssetInfo {
+            mode: PayoutMode::DirectTransfer,
+            token_address: Some(token_address.into()),
+            ..AssetInfo::new(code, decimals)
+        }
+    }
+
+    /// A token paid out through signed EIP-3009 authorizations
+    pub fn authorization(
+        code: impl Into<String>,
+        decimals: u8,
+        token_address: impl Into<String>,
+    ) -> Self {
+        AssetInfo {
+            mode: PayoutMode::Authorization,
+            ..AssetInfo::direct_transfer(code, decimals, token_address)
+        }
+    }
+
+    /// The chain's native asset, which always has 18 decimals
+    pub fn native(code: impl Into<String>) -> Self {
+        AssetInfo {
+            mode: PayoutMode::Native,
+            ..AssetInfo::new(code, 18)
+        }
+    }
+}
+
+/// Assets known to the payout service, keyed by asset code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetRegistry {
+    assets: HashMap<String, AssetInfo>,
+}
+
+impl Default for AssetRegistry {
+    /// The POC deployment pays out MockEURC, which has 6 decimals
+    fn default() -> Self {
+        let mut registry = AssetRegistry::empty();
+        registry.insert(AssetInfo::new("EURC", 6));
+        registry
+    }
+}
+
+impl AssetRegistry {
+    pub fn empty() -> Self {
+        AssetRegistry {
+            assets: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, asset: AssetInfo) {
+        self.assets.insert(asset.code.clone(), asset);
+    }
+
+    pub fn get(&self, code: &str) -> Option<&AssetInfo> {
+        self.assets.get(code)
+    }
+
+    pub fn get_mut(&mut self, code: &str) -> Option<&mut AssetInfo> {
+        self.assets.get_mut(code)
+    }
+
+    /// Codes of the registered assets, in no particular order
+    pub fn codes(&self) -> impl Iterator<Item = &str> {
+        self.assets.keys().map(String::as_str)
+    }
+
+    /// Apply per-transaction caps from a comma-separated list of `CODE:amount`
+    /// entries, e.g. `EURC:1000000000`. Every code must already be registered.
+    pub fn apply_caps(&mut self, spec: &str) -> Option<()> {
+        self.apply_amounts(spec, |asset, cap| asset.max_payout_per_tx = Some(cap))
+    }
+
+    /// Apply daily caps across all recipients, in the same format as [`Self::apply_caps`]
+    pub fn apply_daily_caps(&mut self, spec: &str) -> Option<()> {
+        self.apply_amounts(spec, |asset, cap| asset.daily_cap = Some(cap))
+    }
+
+    /// Apply daily caps per recipient, in the same format as [`Self::apply_caps`]
+    pub fn apply_recipient_daily_caps(&mut self, spec: &str) -> Option<()> {
+        self.apply_amounts(spec, |asset, cap| asset.recipient_daily_cap = Some(cap))
+    }
+
+    /// Apply approval thresholds, in the same format as [`Self::apply_caps`]
+    pub fn apply_approval_thresholds(&mut self, spec: &str) -> Option<()> {
+        self.apply_amounts(spec, |asset, threshold| {
+            asset.approval_threshold = Some(threshold)
+        })
+    }
+
+    /// Apply minimum payouts, in the same format as [`Self::apply_caps`]
+    pub fn apply_min_payouts(&mut self, spec: &str) -> Option<()> {
+        self.apply_amounts(spec, |asset, min| asset.min_payout = Some(min))
+    }
+
+    fn apply_amounts(&mut self, spec: &str, apply: impl Fn(&mut AssetInfo, u64)) -> Option<()> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (code, cap) = entry.split_once(':')?;
+            let cap: u64 = cap.trim().parse().ok()?;
+            if cap == 0 {
+                return None;
+            }
+            apply(self.get_mut(code.trim())?, cap);
+        }
+        Some(())
+    }
+
+    /// Configure EIP-712 domains from a comma-separated list of
+    /// `CODE:name:version` entries, e.g. `USDC:USD Coin:2`
+    pub fn apply_domains(&mut self, spec: &str) -> Option<()> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut fields = entry.splitn(3, ':').map(str::trim);
+            let (code, name, version) = (fields.next()?, fields.next()?, fields.next()?);
+            self.get_mut(code)?.domain = Some(TokenDomain {
+                name: name.to_string(),
+                version: version.to_string(),
+            });
+        }
+        Some(())
+    }
+
+    /// Mark the assets in a comma-separated list of codes, e.g. `PAXG,STA`,
+    /// as deducting a fee on transfer
+    pub fn apply_fee_on_transfer(&mut self, spec: &str) -> Option<()> {
+        for code in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            self.get_mut(code)?.fee_on_transfer = true;
+        }
+        Some(())
+    }
+
+    /// Whether any asset relies on the payout store for idempotency
+    pub fn has_direct_transfers(&self) -> bool {
+        self.assets
+            .values()
+            .any(|asset| asset.mode.relies_on_store())
+    }
+
+    /// Parse a registry from a comma-separated list of `CODE:decimals` entries,
+    /// optionally followed by `:direct:<token address>` for direct transfers or
+    /// `:authorization:<token address>` for EIP-3009 authorizations,
+    /// and `CODE:native` entries for the chain's native asset.
+    /// Example: `EURC:6,USDC:6:direct:0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48,ETH:native`
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut registry = AssetRegistry::empty();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let fields: Vec<&str> = entry.split(':').map(str::trim).collect();
+            let asset = match fields.as_slice() {
+                [code, "native"] => AssetInfo::native(*code),
+                [code, decimals] => AssetInfo::new(*code, decimals.parse().ok()?),
+                [code, decimals, "direct", token] => {
+                    AssetInfo::direct_transfer(*code, decimals.parse().ok()?, *token)
+                }
+                [code, decimals, "authorization", token] => {
+                    AssetInfo::authorization(*code, decimals.parse().ok()?, *token)
+                }
+                _ => return None,
+            };
+            registry.insert(asset);
+        }
+        Some(registry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_asset_list() {
+        let registry = AssetRegistry::parse("EURC:6, WETH:18").unwrap();
+        assert_eq!(registry.get("EURC").unwrap().decimals, 6);
+        assert_eq!(registry.get("WETH").unwrap().decimals, 18);
+        assert!(registry.get("USDC").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_asset_list() {
+        assert!(AssetRegistry::parse("EURC").is_none());
+        assert!(AssetRegistry::parse("EURC:six").is_none());
+        assert!(AssetRegistry::parse("USDC:6:direct").is_none());
+        assert!(AssetRegistry::parse("USDC:6:sideways:0x00").is_none());
+    }
+
+    #[test]
+    fn parses_direct_transfer_assets() {
+        let registry = AssetRegistry::parse("EURC:6,USDC:6:direct:0xA0b8").unwrap();
+        let usdc = registry.get("USDC").unwrap();
+        assert_eq!(usdc.mode, PayoutMode::DirectTransfer);
+        assert_eq!(usdc.token_address.as_deref(), Some("0xA0b8"));
+        assert_eq!(registry.get("EURC").unwrap().mode, PayoutMode::Treasury);
+        assert!(registry.has_direct_transfers());
+        assert!(!AssetRegistry::default().has_direct_transfers());
+    }
+
+    #[test]
+    fn applies_per_transaction_caps() {
+        let mut registry = AssetRegistry::default();
+        registry.apply_caps("EURC:1000").unwrap();
+        assert_eq!(registry.get("EURC").unwrap().max_payout_per_tx, Some(1000));
+        assert!(registry.apply_caps("USDC:1000").is_none());
+        assert!(registry.apply_caps("EURC:0").is_none());
+    }
+
+    #[test]
+    fn parses_authorization_assets_and_domains() {
+        let mut registry = AssetRegistry::parse("USDC:6:authorization:0xA0b8").unwrap();
+        assert_eq!(
+            registry.get("USDC").unwrap().mode,
+            PayoutMode::Authorization
+        );
+        assert!(!registry.has_direct_transfers());
+
+        registry.apply_domains("USDC:USD Coin:2").unwrap();
+        assert_eq!(
+            registry.get("USDC").unwrap().domain,
+            Some(TokenDomain {
+                name: "USD Coin".to_string(),
+                version: "2".to_string()
+            })
+        );
+        assert!(registry.apply_domains("USDC:USD Coin").is_none());
+        assert!(registry.apply_domains("EURC:Euro Coin:2").is_none());
+    }
+
+    #[test]
+    fn parses_native_assets() {
+        let registry = AssetRegistry::parse("ETH:native").unwrap();
+        assert_eq!(registry.get("ETH"), Some(&AssetInfo::native("ETH")));
+        assert_eq!(registry.get("ETH").unwrap().decimals, 18);
+        assert!(registry.has_direct_transfers());
+    }
+}
@@ -0,0 +1,406 @@
+//! Attribution of payout gas costs to the senders behind them
+//!
+//! A request may carry opaque tags, such as the STREAM sender's account or
+//! connection ID, added with [`PayoutRequest::with_attribution`]. Records
+//! keep them as an [`Attribution`], each tag with a weight: one for a tag of
+//! an ordinary payout, the amount accumulated under it for a dust payout
+//! paying many requests in one transaction, and the request's weights again
+//! for each chunk of a split payout. A payout's gas cost is split over its
+//! tags in proportion to their weights.
+//!
+//! [`EthereumPayoutService::attributed_costs`] sums the split costs over a
+//! time range, and `payouts.ethereum.attributed_gas_cost_wei` counts them as
+//! receipts come in. Both name the `attribution_top_n` costliest tags and
+//! fold the others into [`OTHER_TAG`], so their cardinality stays bounded
+//! however many senders there are.
+
+use super::store::{PageCursor, PayoutRecord, Timestamp};
+use super::{metrics, EthereumPayoutService, PayoutRequest, EXTERNAL_PAYMENT_ID};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// Label of the tags outside the top N
+pub const OTHER_TAG: &str = "other";
+
+/// Label of payouts made without a tag
+pub const UNTAGGED: &str = "untagged";
+
+/// Records read per page while aggregating
+const AGGREGATION_PAGE_SIZE: usize = 500;
+
+/// Tags a payout's cost is attributed to, with the weight of each
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attribution(BTreeMap<String, u64>);
+
+impl Attribution {
+    /// `weight` more for `tag`
+    pub fn add(&mut self, tag: &str, weight: u64) {
+        let entry = self.0.entry(tag.to_string()).or_default();
+        *entry = entry.saturating_add(weight);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn weights(&self) -> &BTreeMap<String, u64> {
+        &self.0
+    }
+
+    /// `total` split over the tags in proportion to their weights, rounded
+    /// down, with what rounding leaves going to the heaviest tag
+    pub fn split(&self, total: u128) -> Vec<(String, u128)> {
+        let sum: u128 = self.0.values().map(|weight| u128::from(*weight)).sum();
+        // Tags of nothing but zero weights share equally
+        let equal = sum == 0;
+        let sum = if equal { self.0.len() as u128 } else { sum };
+        let mut shares: Vec<(String, u128)> = self
+            .0
+            .iter()
+            .map(|(tag, tag_weight)| {
+                let weight = if equal { 1 } else { u128::from(*tag_weight) };
+                let share = match total.checked_mul(weight) {
+                    Some(product) => product / sum,
+                    None => total / sum * weight,
+                };
+                (tag.clone(), share)
+            })
+            .collect();
+        let remainder = total - shares.iter().map(|(_, share)| share).sum::<u128>();
+        let heaviest = self
+            .0
+            .iter()
+            .enumerate()
+            .max_by(|(i, (_, a)), (j, (_, b))| a.cmp(b).then(j.cmp(i)))
+            .map(|(i, _)| i);
+        if let Some(heaviest) = heaviest {
+            shares[heaviest].1 += remainder;
+        }
+        shares
+    }
+
+    pub(super) fn to_json(&self) -> Value {
+        json!(self.0)
+    }
+
+    /// Missing in records from before attribution, which have no tags
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        let tags = match value {
+            Value::Null => return Some(Attribution::default()),
+            Value::Object(tags) => tags,
+            _ => return None,
+        };
+        let mut attribution = Attribution::default();
+        for (tag, weight) in tags {
+            attribution.add(tag, weight.as_u64()?);
+        }
+        Some(attribution)
+    }
+
+    /// Tags with their shares of `gas_cost` as exported, e.g. `a=700;b=300`,
+    /// or the tags alone if the cost is unknown
+    pub(super) fn render(&self, gas_cost: Option<u128>) -> String {
+        let parts: Vec<String> = match gas_cost {
+            Some(cost) => self
+                .split(cost)
+                .into_iter()
+                .map(|(tag, share)| format!("{}={}", tag, share))
+                .collect(),
+            None => self.0.keys().cloned().collect(),
+        };
+        parts.join(";")
+    }
+}
+
+impl PayoutRequest {
+    /// Attribute the payout's cost to `tag`, e.g. the sender's account ID;
+    /// costs of a request with several tags are split evenly
+    pub fn with_attribution(mut self, tag: &str) -> Self {
+        self.attribution.add(tag, 1);
+        self
+    }
+
+    /// The request `record` was made for, as far as resuming it needs: with
+    /// its tags, and its payment ID if that was supplied rather than derived
+    pub(super) fn for_record(record: &PayoutRecord) -> Self {
+        let mut request = PayoutRequest::new(&record.destination, record.amount, record.sequence);
+        request.attribution = record.attribution.clone();
+        if record.payment_id_strategy.as_deref() == Some(EXTERNAL_PAYMENT_ID) {
+            request.payment_id = Some(record.payment_id);
+        }
+        request
+    }
+}
+
+/// Gas cost attributed to one tag
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributedCost {
+    pub tag: String,
+    /// Payouts with a share of the cost
+    pub payouts: u64,
+    pub gas_cost_wei: u128,
+}
+
+/// Every tag's cost so far, ranking tags for metric labels
+#[derive(Debug, Default)]
+pub(super) struct AttributionTotals {
+    totals: HashMap<String, u128>,
+}
+
+impl AttributionTotals {
+    /// Add `cost` to `tag`, returning the label it is counted under: the tag
+    /// while among the `top_n` costliest, `other` otherwise
+    fn label(&mut self, tag: &str, cost: u128, top_n: usize) -> String {
+        let total = self.totals.entry(tag.to_string()).or_default();
+        *total = total.saturating_add(cost);
+        let total = *total;
+        let ahead = self
+            .totals
+            .iter()
+            .filter(|(other, other_total)| {
+                **other_total > total || (**other_total == total && other.as_str() < tag)
+            })
+            .count();
+        if ahead < top_n {
+            tag.to_string()
+        } else {
+            OTHER_TAG.to_string()
+        }
+    }
+}
+
+/// `costs` with those outside the `top_n` costliest folded into `other`
+fn fold_top(costs: HashMap<String, AttributedCost>, top_n: usize) -> Vec<AttributedCost> {
+    let mut costs: Vec<AttributedCost> = costs.into_values().collect();
+    costs.sort_by(|a, b| {
+        b.gas_cost_wei
+            .cmp(&a.gas_cost_wei)
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
+    if costs.len() <= top_n {
+        return costs;
+    }
+    let rest = costs.split_off(top_n);
+    costs.push(AttributedCost {
+        tag: OTHER_TAG.to_string(),
+        payouts: rest.iter().map(|cost| cost.payouts).sum(),
+        gas_cost_wei: rest.iter().map(|cost| cost.gas_cost_wei).sum(),
+    });
+    costs
+}
+
+impl EthereumPayoutService {
+    /// Gas costs of the payouts with `from <= timestamp < to` by tag,
+    /// costliest first, beyond `attribution_top_n` tags summed as `other`
+    pub fn attributed_costs(&self, from: Timestamp, to: Timestamp) -> Vec<AttributedCost> {
+        let mut costs: HashMap<String, AttributedCost> = HashMap::new();
+        let mut cursor: Option<PageCursor> = None;
+        loop {
+            let page = self
+                .store
+                .list_range(from, to, cursor, AGGREGATION_PAGE_SIZE);
+            for record in &page {
+                for (tag, share) in cost_shares(record) {
+                    let cost = costs.entry(tag.clone()).or_insert(AttributedCost {
+                        tag,
+                        payouts: 0,
+                        gas_cost_wei: 0,
+                    });
+                    cost.payouts += 1;
+                    cost.gas_cost_wei = cost.gas_cost_wei.saturating_add(share);
+                }
+            }
+            if page.len() < AGGREGATION_PAGE_SIZE {
+                break;
+            }
+            cursor = page.last().map(PageCursor::from);
+        }
+        fold_top(costs, self.config.attribution_top_n)
+    }
+
+    /// Count the mined payout's gas cost under its tags
+    pub(super) fn attribute_gas_cost(&self, record: &PayoutRecord) {
+        let top_n = self.config.attribution_top_n;
+        let mut totals = self.attribution_totals.lock().unwrap();
+        for (tag, share) in cost_shares(record) {
+            let label = totals.label(&tag, share, top_n);
+            metrics::attributed_gas_cost(self.config.tenant.as_deref(), &label, share);
+        }
+    }
+}
+
+/// The record's gas cost split over its tags, none before it is known
+fn cost_shares(record: &PayoutRecord) -> Vec<(String, u128)> {
+    match record.gas_cost {
+        Some(cost) if record.attribution.is_empty() => vec![(UNTAGGED.to_string(), cost)],
+        Some(cost) => record.attribution.split(cost),
+        None => Vec::new(),
+    }
+}
+
+/// Tags with the given weights, for building records by hand
+#[cfg(test)]
+pub(super) fn attribution_of(tags: &[(&str, u64)]) -> Attribution {
+    let mut attribution = Attribution::default();
+    for (tag, weight) in tags {
+        attribution.add(tag, *weight);
+    }
+    attribution
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, FakeClock, MockTransport};
+    use super::super::{Clock, FlushSchedule, PayoutOutcome};
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn mined_node() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        // 21000 gas at 1 gwei
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({
+                "blockNumber": "0x7",
+                "gasUsed": "0x5208",
+                "effectiveGasPrice": "0x3b9aca00",
+                "status": "0x1",
+            }),
+        );
+        transport
+    }
+
+    #[test]
+    fn splits_in_proportion_to_weights() {
+        let attribution = attribution_of(&[("alice", 700), ("bob", 200), ("carol", 100)]);
+        assert_eq!(
+            attribution.split(1_000),
+            [
+                ("alice".to_string(), 700),
+                ("bob".to_string(), 200),
+                ("carol".to_string(), 100)
+            ]
+        );
+        // What rounding leaves goes to the heaviest tag
+        let split = attribution.split(1_001);
+        assert_eq!(split[0], ("alice".to_string(), 701));
+        assert_eq!(split.iter().map(|(_, share)| share).sum::<u128>(), 1_001);
+        assert_eq!(
+            attribution_of(&[("a", 0), ("b", 0)]).split(10),
+            [("a".to_string(), 5), ("b".to_string(), 5)]
+        );
+        assert_eq!(
+            attribution.render(Some(1_000)),
+            "alice=700;bob=200;carol=100"
+        );
+        assert_eq!(attribution.render(None), "alice;bob;carol");
+        assert_eq!(
+            Attribution::from_json(&attribution.to_json()),
+            Some(attribution)
+        );
+    }
+
+    #[tokio::test]
+    async fn dust_batches_split_their_gas_cost_by_amount() {
+        let mut config = test_config();
+        config.assets.get_mut("EURC").unwrap().min_payout = Some(1_000);
+        config.dust_flush = Some(FlushSchedule {
+            interval: Duration::from_secs(3600),
+            offset: Duration::ZERO,
+        });
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let service = test_service(config, mined_node()).with_clock(clock.clone());
+        let destination =
+            |token: &str| format!("test.receiver.eth.31337.EURC.{}.{}", RECIPIENT, token);
+        for (sequence, (sender, amount)) in [("alice", 600), ("bob", 300), ("alice", 100)]
+            .iter()
+            .enumerate()
+        {
+            let request = PayoutRequest::new(destination(sender), *amount, sequence as u64)
+                .with_attribution(sender);
+            assert!(matches!(
+                service.execute(&request).await.unwrap(),
+                PayoutOutcome::Accumulated { .. }
+            ));
+        }
+        let flushed = service.flush_dust().await;
+        assert_eq!(flushed.len(), 1);
+        let record = service
+            .refresh_receipt(&flushed[0].payment_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.gas_cost, Some(21_000_000_000_000));
+        assert_eq!(
+            record.attribution,
+            attribution_of(&[("alice", 700), ("bob", 300)])
+        );
+
+        let costs = service.attributed_costs(start, clock.now() + chrono::Duration::seconds(1));
+        assert_eq!(
+            costs,
+            [
+                AttributedCost {
+                    tag: "alice".to_string(),
+                    payouts: 1,
+                    gas_cost_wei: 14_700_000_000_000,
+                },
+                AttributedCost {
+                    tag: "bob".to_string(),
+                    payouts: 1,
+                    gas_cost_wei: 6_300_000_000_000,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_top_tags_are_named() {
+        let mut config = test_config();
+        config.attribution_top_n = 2;
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let service = test_service(config, mined_node()).with_clock(clock.clone());
+        let senders = ["alice", "alice", "alice", "bob", "bob", "carol", "dave"];
+        for (sequence, sender) in senders.iter().enumerate() {
+            let destination = format!("test.receiver.eth.31337.EURC.{}.{}", RECIPIENT, sender);
+            let request =
+                PayoutRequest::new(&destination, 100, sequence as u64).with_attribution(sender);
+            service.execute(&request).await.unwrap();
+            let payment_id = service.config().payment_id(&destination, sequence as u64);
+            service.refresh_receipt(&payment_id).await.unwrap();
+        }
+        service
+            .execute_payout(
+                &format!("test.receiver.eth.31337.EURC.{}.anon", RECIPIENT),
+                100,
+                99,
+            )
+            .await
+            .unwrap();
+
+        let costs = service.attributed_costs(start, clock.now() + chrono::Duration::seconds(1));
+        let tags: Vec<(&str, u64)> = costs
+            .iter()
+            .map(|cost| (cost.tag.as_str(), cost.payouts))
+            .collect();
+        // The untagged payout has no receipt yet and so no cost
+        assert_eq!(tags, [("alice", 3), ("bob", 2), ("other", 2)]);
+        assert_eq!(
+            costs.iter().map(|cost| cost.gas_cost_wei).sum::<u128>(),
+            7 * 21_000_000_000_000
+        );
+
+        let mut totals = AttributionTotals::default();
+        assert_eq!(totals.label("alice", 30, 2), "alice");
+        assert_eq!(totals.label("bob", 20, 2), "bob");
+        assert_eq!(totals.label("carol", 10, 2), "other");
+        // Carol overtakes bob, who is then counted as other
+        assert_eq!(totals.label("carol", 20, 2), "carol");
+        assert_eq!(totals.label("bob", 1, 2), "other");
+    }
+}
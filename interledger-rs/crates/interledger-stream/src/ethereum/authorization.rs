@@ -0,0 +1,278 @@
+//! EIP-3009 `transferWithAuthorization` payouts
+//!
+//! The operator signs a transfer authorization for the recipient and hands it
+//! to a relayer, which submits it to the token and pays the gas. The
+//! authorization nonce is the payment ID, so a replayed payout is rejected by
+//! the token itself.
+
+use super::abi::{decode_words, encode_address, encode_uint, selector};
+use super::eip712::{hash_struct, typed_data_hash, Eip712Domain};
+use super::hash::keccak256;
+use super::{
+    EthereumPayoutService, PayoutError, PayoutOutcome, PayoutPlan, PayoutRequest, PayoutStatus,
+    Signature,
+};
+use chrono::Utc;
+use serde_json::{json, Value};
+use tracing::info;
+
+/// EIP-712 type of an EIP-3009 transfer authorization
+pub const TRANSFER_WITH_AUTHORIZATION_TYPE: &str = "TransferWithAuthorization(address from,address to,uint256 value,uint256 validAfter,uint256 validBefore,bytes32 nonce)";
+
+/// An EIP-3009 authorization to move `value` tokens from `from` to `to`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferAuthorization {
+    pub from: String,
+    pub to: String,
+    pub value: u64,
+    /// Unix time after which the authorization is valid
+    pub valid_after: u64,
+    /// Unix time before which the authorization must be used
+    pub valid_before: u64,
+    pub nonce: [u8; 32],
+}
+
+impl TransferAuthorization {
+    pub fn struct_hash(&self) -> Result<[u8; 32], PayoutError> {
+        let address = |address: &str| {
+            encode_address(address)
+                .ok_or_else(|| PayoutError::Config(format!("Invalid address {}", address)))
+        };
+        Ok(hash_struct(
+            &keccak256(TRANSFER_WITH_AUTHORIZATION_TYPE.as_bytes()),
+            &[
+                address(&self.from)?,
+                address(&self.to)?,
+                encode_uint(self.value.into()),
+                encode_uint(self.valid_after.into()),
+                encode_uint(self.valid_before.into()),
+                self.nonce,
+            ],
+        ))
+    }
+
+    /// Digest signed by `from` under the token's domain separator
+    pub fn signing_hash(&self, domain_separator: &[u8; 32]) -> Result<[u8; 32], PayoutError> {
+        Ok(typed_data_hash(domain_separator, &self.struct_hash()?))
+    }
+
+    /// Request body posted to the relayer
+    pub fn relay_request(&self, token: &str, signature: &Signature) -> Value {
+        json!({
+            "token": token,
+            "from": self.from,
+            "to": self.to,
+            "value": self.value.to_string(),
+            "validAfter": self.valid_after.to_string(),
+            "validBefore": self.valid_before.to_string(),
+            "nonce": format!("0x{}", hex::encode(self.nonce)),
+            "v": signature.v,
+            "r": format!("0x{}", hex::encode(signature.r)),
+            "s": format!("0x{}", hex::encode(signature.s)),
+        })
+    }
+}
+
+impl EthereumPayoutService {
+    /// Sign an authorization for `plan` and submit it through the relayer
+    pub(super) async fn relay_authorization(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let relayer = self.config.relayer_url.as_ref().ok_or_else(|| {
+            PayoutError::Config("Authorization payouts require a relayer URL".to_string())
+        })?;
+        let separator = self.token_domain_separator(plan).await?;
+        let signer = self.operator_signer()?;
+        let valid_after = 0;
+        let valid_before =
+            Utc::now().timestamp() as u64 + self.config.authorization_validity.as_secs();
+        let authorization = TransferAuthorization {
+            from: signer.address(),
+            to: plan.destination.recipient.clone(),
+            value: plan.amount,
+            valid_after,
+            valid_before,
+            nonce: plan.payment_id,
+        };
+        let signature = signer
+            .sign_normalized(&authorization.signing_hash(&separator)?)
+            .await?;
+
+        let response = self
+            .http
+            .post(relayer)
+            .json(&authorization.relay_request(&plan.to, &signature))
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(PayoutError::InvalidResponse(format!(
+                "Relayer returned {}: {}",
+                status, text
+            )));
+        }
+        let body: Value = response.json().await?;
+        let tx_hash = body["txHash"]
+            .as_str()
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("Relayer returned {}", body)))?
+            .to_string();
+
+        info!("Authorization relayed: {}", tx_hash);
+        self.store.save(self.payout_record(
+            request,
+            plan,
+            Some(tx_hash.clone()),
+            PayoutStatus::Submitted,
+        ));
+        Ok(PayoutOutcome::Submitted { tx_hash })
+    }
+
+    /// The token's domain separator, computed from the configured name and
+    /// version or read from the token's `DOMAIN_SEPARATOR()`
+    async fn token_domain_separator(&self, plan: &PayoutPlan) -> Result<[u8; 32], PayoutError> {
+        let domain = self
+            .config
+            .assets
+            .get(&plan.destination.asset_code)
+            .and_then(|asset| asset.domain.as_ref());
+        if let Some(domain) = domain {
+            return Eip712Domain {
+                name: Some(domain.name.clone()),
+                version: Some(domain.version.clone()),
+                chain_id: Some(self.config.expected_chain_id),
+                verifying_contract: Some(plan.to.clone()),
+            }
+            .separator();
+        }
+
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({
+                    "to": &plan.to,
+                    "data": format!("0x{}", hex::encode(selector("DOMAIN_SEPARATOR()"))),
+                })],
+            )
+            .await?;
+        result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .ok_or_else(|| {
+                PayoutError::InvalidResponse(format!("DOMAIN_SEPARATOR returned {}", result))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, MockTransport, TEST_DESTINATION, TEST_OPERATOR,
+        TEST_OPERATOR_KEY,
+    };
+    use super::super::{AssetInfo, LocalSigner};
+    use super::*;
+    use k256::ecdsa::{RecoveryId, Signature as EcdsaSignature, VerifyingKey};
+    use mockito::{mock, Matcher};
+
+    const USDC: &str = "0x5FC8d32690cc91D4c39d9d3abcBD16989F875707";
+
+    fn authorization() -> TransferAuthorization {
+        TransferAuthorization {
+            from: TEST_OPERATOR.to_string(),
+            to: "0x70997970C51812dc3A010C7d01b50e0d17dc79C8".to_string(),
+            value: 1_000_000,
+            valid_after: 0,
+            valid_before: 1_700_000_000,
+            nonce: [0x42; 32],
+        }
+    }
+
+    fn recover(hash: &[u8; 32], signature: &Signature) -> String {
+        let sig = EcdsaSignature::from_scalars(signature.r, signature.s).unwrap();
+        let id = RecoveryId::from_byte(signature.v - 27).unwrap();
+        let key = VerifyingKey::recover_from_prehash(hash, &sig, id).unwrap();
+        let point = key.to_encoded_point(false);
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&keccak256(&point.as_bytes()[1..])[12..]);
+        super::super::abi::to_checksum_address(&address)
+    }
+
+    #[test]
+    fn type_hash_matches_fiat_token() {
+        // TRANSFER_WITH_AUTHORIZATION_TYPEHASH from FiatTokenV2 (USDC)
+        assert_eq!(
+            hex::encode(keccak256(TRANSFER_WITH_AUTHORIZATION_TYPE.as_bytes())),
+            "7c7c6cdb67a18743f49ec6fa9b35f50d52ed05cbed4cc592e13b44501c1a2267"
+        );
+    }
+
+    #[test]
+    fn signature_recovers_to_the_operator() {
+        let domain = Eip712Domain {
+            name: Some("USD Coin".to_string()),
+            version: Some("2".to_string()),
+            chain_id: Some(1),
+            verifying_contract: Some(USDC.to_string()),
+        };
+        let hash = authorization()
+            .signing_hash(&domain.separator().unwrap())
+            .unwrap();
+        let signature = LocalSigner::from_hex(TEST_OPERATOR_KEY)
+            .unwrap()
+            .sign_hash(&hash)
+            .unwrap();
+        assert_eq!(recover(&hash, &signature), TEST_OPERATOR);
+
+        let other = TransferAuthorization {
+            nonce: [0x43; 32],
+            ..authorization()
+        };
+        assert_ne!(
+            other.signing_hash(&domain.separator().unwrap()).unwrap(),
+            hash
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_signed_authorization() {
+        let relay = mock("POST", "/relay/transfer-with-authorization")
+            .match_body(Matcher::PartialJson(json!({
+                "token": USDC,
+                "from": TEST_OPERATOR,
+                "to": "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+                "value": "2500",
+                "validAfter": "0",
+            })))
+            .with_status(200)
+            .with_body(r#"{"txHash": "0xfeed"}"#)
+            .create();
+        let separator = [0x99; 32];
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", json!(format!("0x{}", hex::encode(separator))));
+        let mut config = test_config();
+        config
+            .assets
+            .insert(AssetInfo::authorization("EURC", 6, USDC));
+        config.relayer_url = Some(format!(
+            "{}/relay/transfer-with-authorization",
+            mockito::server_url()
+        ));
+        let service = test_service(config, transport.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 2500, 1)
+            .await
+            .unwrap();
+        relay.assert();
+        assert_eq!(outcome.tx_hash(), Some("0xfeed"));
+        // The domain separator was read from the token; nothing was sent by the operator
+        let call = &transport.calls("eth_call")[0]["params"][0];
+        assert_eq!(call["to"], USDC);
+        assert_eq!(call["data"], "0x3644e515");
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+}
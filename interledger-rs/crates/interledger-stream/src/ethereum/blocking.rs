@@ -0,0 +1,205 @@
+//! Blocking wrapper for hosts without a tokio runtime
+//!
+//! [`BlockingPayoutService`] owns a current-thread runtime and drives the
+//! async service on it for the duration of each call. Background tasks, such
+//! as monitors or queued payouts, only make progress while a call is running.
+//! Calls that need the runtime fail with [`PayoutError::InAsyncContext`] when
+//! made from within another runtime, where blocking would panic.
+
+use super::{
+    EthereumPayoutConfig, EthereumPayoutService, HealthReport, PageCursor, PayoutError,
+    PayoutOutcome, PayoutPreview, PayoutRecord, RecipientStatement, SequenceStats, Timestamp,
+};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Handle, Runtime};
+
+/// [`EthereumPayoutService`] with blocking methods
+pub struct BlockingPayoutService {
+    service: Arc<EthereumPayoutService>,
+    /// Only `None` while dropping
+    runtime: Option<Runtime>,
+}
+
+impl BlockingPayoutService {
+    /// Create a payout service from `config`, see [`EthereumPayoutService::new`]
+    pub fn new(config: EthereumPayoutConfig) -> Result<Self, PayoutError> {
+        BlockingPayoutService::from_service(EthereumPayoutService::new(config)?)
+    }
+
+    /// Wrap a configured service; its background tasks are spawned on the
+    /// wrapper's runtime
+    pub fn from_service(service: EthereumPayoutService) -> Result<Self, PayoutError> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| PayoutError::Config(format!("Cannot start a runtime: {}", err)))?;
+        let service = service.with_runtime(runtime.handle().clone());
+        Ok(BlockingPayoutService {
+            service: Arc::new(service),
+            runtime: Some(runtime),
+        })
+    }
+
+    /// The wrapped service, for methods that do not block
+    pub fn service(&self) -> &Arc<EthereumPayoutService> {
+        &self.service
+    }
+
+    /// Run `future` to completion on the wrapper's runtime
+    fn block_on<F: Future>(&self, future: F) -> Result<F::Output, PayoutError> {
+        if Handle::try_current().is_ok() {
+            return Err(PayoutError::InAsyncContext);
+        }
+        let runtime = self
+            .runtime
+            .as_ref()
+            .expect("runtime is only taken on drop");
+        Ok(runtime.block_on(future))
+    }
+
+    /// See [`EthereumPayoutService::execute_payout`]
+    pub fn execute_payout(
+        &self,
+        destination: &str,
+        amount: u64,
+        sequence: u64,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        self.block_on(self.service.execute_payout(destination, amount, sequence))?
+    }
+
+    /// See [`EthereumPayoutService::execute_payout_from`]
+    pub fn execute_payout_from(
+        &self,
+        source_asset: &str,
+        destination: &str,
+        amount: u64,
+        sequence: u64,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        self.block_on(self.service.execute_payout_from(
+            source_asset,
+            destination,
+            amount,
+            sequence,
+        ))?
+    }
+
+    /// See [`EthereumPayoutService::preview_payout`]
+    pub fn preview_payout(
+        &self,
+        destination: &str,
+        amount: u64,
+    ) -> Result<PayoutPreview, PayoutError> {
+        self.block_on(self.service.preview_payout(destination, amount))?
+    }
+
+    /// See [`EthereumPayoutService::health`]
+    pub fn health(&self) -> Result<HealthReport, PayoutError> {
+        self.block_on(self.service.health())
+    }
+
+    /// Record of the payout with `payment_id`, if there is one
+    pub fn payout(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        self.service.store().get(payment_id)
+    }
+
+    /// See [`EthereumPayoutService::sequence_stats`]
+    pub fn sequence_stats(&self, destination: &str) -> SequenceStats {
+        self.service.sequence_stats(destination)
+    }
+
+    /// See [`EthereumPayoutService::dust_balance`]
+    pub fn dust_balance(&self, asset_code: &str, recipient: &str) -> u64 {
+        self.service.dust_balance(asset_code, recipient)
+    }
+
+    /// See [`EthereumPayoutService::list_pending_approval`]
+    pub fn list_pending_approval(&self) -> Vec<PayoutRecord> {
+        self.service.list_pending_approval()
+    }
+
+    /// See [`EthereumPayoutService::statement`]
+    pub fn statement(
+        &self,
+        recipient: &str,
+        from: Timestamp,
+        to: Timestamp,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<RecipientStatement, PayoutError> {
+        self.service.statement(recipient, from, to, after, limit)
+    }
+}
+
+impl Drop for BlockingPayoutService {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks on its tasks, which panics inside another runtime
+        if let Some(runtime) = self.runtime.take() {
+            if Handle::try_current().is_ok() {
+                runtime.shutdown_background();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    fn blocking_service() -> (BlockingPayoutService, Arc<MockTransport>) {
+        let transport = mock_chain();
+        let service = test_service(test_config(), transport.clone());
+        (
+            BlockingPayoutService::from_service(service).unwrap(),
+            transport,
+        )
+    }
+
+    #[test]
+    fn pays_out_without_a_runtime() {
+        let (service, transport) = blocking_service();
+        let preview = service.preview_payout(TEST_DESTINATION, 100).unwrap();
+        assert_eq!(preview.amount, Some(100));
+
+        let outcome = service.execute_payout(TEST_DESTINATION, 100, 0).unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        let payment_id = service.service().config().payment_id(TEST_DESTINATION, 0);
+        assert_eq!(service.payout(&payment_id).unwrap().amount, 100);
+        assert_eq!(service.sequence_stats(TEST_DESTINATION).highest, Some(0));
+        let statement = service
+            .statement(
+                "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+                Utc.timestamp_opt(0, 0).unwrap(),
+                Utc::now() + chrono::Duration::hours(1),
+                None,
+                10,
+            )
+            .unwrap();
+        assert_eq!(statement.payouts.len(), 1);
+        assert!(service.health().is_ok());
+    }
+
+    #[test]
+    fn refuses_to_block_inside_a_runtime() {
+        let (service, transport) = blocking_service();
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async move {
+            assert!(matches!(
+                service.execute_payout(TEST_DESTINATION, 100, 0),
+                Err(PayoutError::InAsyncContext)
+            ));
+            assert!(matches!(service.health(), Err(PayoutError::InAsyncContext)));
+            // Queries that do not need the runtime still answer
+            assert!(service.list_pending_approval().is_empty());
+            // Dropping the wrapper here must not panic either
+            drop(service);
+        });
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+}
@@ -0,0 +1,274 @@
+//! Treasuries whose payout function takes other arguments than `payoutToUser`
+//!
+//! By default Treasury payouts call `payoutToUser(bytes32,address,uint256)`.
+//! A [`PayoutCall`] describes another function instead: its canonical
+//! signature, from which the selector is computed, and what to pass for each
+//! of its parameters in order, e.g. a partner's
+//! `payout(address,uint256,bytes32,uint16)` with a constant source tag last.
+//! Every layout passes the payment ID, recipient and amount exactly once, and
+//! is checked against the signature when it is built, so a mismatch fails at
+//! startup rather than on the first payout.
+
+use super::abi::{address_word_hex, selector};
+use super::PayoutError;
+
+/// What is passed for one parameter of the payout function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayoutArgument {
+    /// The payout's payment ID, as `bytes32`
+    PaymentId,
+    /// The recipient's address
+    Recipient,
+    /// The amount in the asset's base units, as a `uint` of at least 64 bits
+    Amount,
+    /// A constant `uint` of any width it fits in
+    ConstUint(u128),
+    /// A constant `bytes32`
+    ConstBytes32([u8; 32]),
+}
+
+impl PayoutArgument {
+    /// Parse `payment_id`, `recipient`, `amount`, `uint:<decimal>` or
+    /// `bytes32:<0x-prefixed hex>`
+    pub fn parse(argument: &str) -> Option<Self> {
+        match argument.split_once(':') {
+            None => match argument {
+                "payment_id" => Some(PayoutArgument::PaymentId),
+                "recipient" => Some(PayoutArgument::Recipient),
+                "amount" => Some(PayoutArgument::Amount),
+                _ => None,
+            },
+            Some(("uint", value)) => value.parse().ok().map(PayoutArgument::ConstUint),
+            Some(("bytes32", value)) => {
+                let bytes = hex::decode(value.strip_prefix("0x")?).ok()?;
+                let mut word = [0; 32];
+                if bytes.len() != word.len() {
+                    return None;
+                }
+                word.copy_from_slice(&bytes);
+                Some(PayoutArgument::ConstBytes32(word))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// The form [`Self::parse`] reads
+    pub fn as_spec(&self) -> String {
+        match self {
+            PayoutArgument::PaymentId => "payment_id".to_string(),
+            PayoutArgument::Recipient => "recipient".to_string(),
+            PayoutArgument::Amount => "amount".to_string(),
+            PayoutArgument::ConstUint(value) => format!("uint:{}", value),
+            PayoutArgument::ConstBytes32(word) => format!("bytes32:0x{}", hex::encode(word)),
+        }
+    }
+
+    /// Whether the argument can be passed for a parameter of type `kind`
+    fn fits(&self, kind: &str) -> bool {
+        let bits = uint_bits(kind);
+        match self {
+            PayoutArgument::PaymentId | PayoutArgument::ConstBytes32(_) => kind == "bytes32",
+            PayoutArgument::Recipient => kind == "address",
+            PayoutArgument::Amount => bits.is_some_and(|bits| bits >= 64),
+            PayoutArgument::ConstUint(value) => {
+                bits.is_some_and(|bits| bits >= 128 || *value >> bits == 0)
+            }
+        }
+    }
+}
+
+/// Width of an unsigned integer type, `None` if `kind` is not one
+fn uint_bits(kind: &str) -> Option<u32> {
+    let bits = match kind.strip_prefix("uint")? {
+        "" => return None,
+        bits => bits.parse().ok()?,
+    };
+    if bits == 0 || bits > 256 || bits % 8 != 0 {
+        return None;
+    }
+    Some(bits)
+}
+
+/// Parameter types of a canonical signature like `payout(address,uint256)`
+fn parameter_types(signature: &str) -> Option<Vec<&str>> {
+    let (name, rest) = signature.split_once('(')?;
+    let parameters = rest.strip_suffix(')')?;
+    let is_identifier = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_identifier || parameters.contains(|c: char| c.is_whitespace() || c == '(') {
+        return None;
+    }
+    if parameters.is_empty() {
+        return Some(Vec::new());
+    }
+    Some(parameters.split(',').collect())
+}
+
+/// Function a Treasury payout calls and the arguments passed to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutCall {
+    signature: String,
+    arguments: Vec<PayoutArgument>,
+}
+
+impl PayoutCall {
+    /// A layout for `signature`, failing unless it has one parameter per
+    /// argument, of a type the argument fits, and passes each of the payment
+    /// ID, recipient and amount exactly once
+    pub fn new(
+        signature: impl Into<String>,
+        arguments: Vec<PayoutArgument>,
+    ) -> Result<Self, PayoutError> {
+        let signature = signature.into();
+        let invalid = |reason: String| {
+            PayoutError::Config(format!("Invalid payout call {}: {}", signature, reason))
+        };
+        let types = parameter_types(&signature)
+            .ok_or_else(|| invalid("not a canonical function signature".to_string()))?;
+        if types.len() != arguments.len() {
+            return Err(invalid(format!(
+                "{} parameters but {} arguments",
+                types.len(),
+                arguments.len()
+            )));
+        }
+        for (index, (kind, argument)) in types.iter().zip(&arguments).enumerate() {
+            if !argument.fits(kind) {
+                return Err(invalid(format!(
+                    "argument {} ({}) cannot be passed as {}",
+                    index,
+                    argument.as_spec(),
+                    kind
+                )));
+            }
+        }
+        for required in [
+            PayoutArgument::PaymentId,
+            PayoutArgument::Recipient,
+            PayoutArgument::Amount,
+        ] {
+            let count = arguments.iter().filter(|arg| **arg == required).count();
+            if count != 1 {
+                return Err(invalid(format!(
+                    "{} is passed {} times instead of once",
+                    required.as_spec(),
+                    count
+                )));
+            }
+        }
+        Ok(PayoutCall {
+            signature,
+            arguments,
+        })
+    }
+
+    /// A layout for `signature` with the comma-separated arguments `spec`,
+    /// each in the form [`PayoutArgument::parse`] reads
+    pub fn parse(signature: &str, spec: &str) -> Result<Self, PayoutError> {
+        let arguments = spec
+            .split(',')
+            .map(str::trim)
+            .map(|argument| {
+                PayoutArgument::parse(argument).ok_or_else(|| {
+                    PayoutError::Config(format!("Invalid payout call argument {}", argument))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        PayoutCall::new(signature, arguments)
+    }
+
+    pub fn signature(&self) -> &str {
+        &self.signature
+    }
+
+    pub fn arguments(&self) -> &[PayoutArgument] {
+        &self.arguments
+    }
+
+    /// The arguments in the form [`Self::parse`] reads
+    pub fn arguments_spec(&self) -> String {
+        let arguments: Vec<String> = self.arguments.iter().map(PayoutArgument::as_spec).collect();
+        arguments.join(",")
+    }
+
+    /// ABI-encode the call paying `amount` to `recipient` under `payment_id`
+    /// as 0x-prefixed hex, `None` if `recipient` is not a 20-byte hex address
+    pub fn calldata(&self, payment_id: &[u8; 32], recipient: &str, amount: u64) -> Option<String> {
+        let mut data = format!("0x{}", hex::encode(selector(&self.signature)));
+        for argument in &self.arguments {
+            let word = match argument {
+                PayoutArgument::PaymentId => hex::encode(payment_id),
+                PayoutArgument::Recipient => address_word_hex(recipient)?,
+                PayoutArgument::Amount => format!("{:0>64x}", amount),
+                PayoutArgument::ConstUint(value) => format!("{:064x}", value),
+                PayoutArgument::ConstBytes32(word) => hex::encode(word),
+            };
+            data.push_str(&word);
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::payload::payout_calldata;
+    use super::*;
+
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn rejection(signature: &str, spec: &str) -> String {
+        PayoutCall::parse(signature, spec).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn the_default_layout_encodes_as_payout_to_user() {
+        let call = PayoutCall::parse(
+            "payoutToUser(bytes32,address,uint256)",
+            "payment_id,recipient,amount",
+        )
+        .unwrap();
+        assert_eq!(
+            call.calldata(&[0xab; 32], RECIPIENT, 1_000_000),
+            payout_calldata(&[0xab; 32], RECIPIENT, 1_000_000)
+        );
+        assert_eq!(call.calldata(&[0xab; 32], &RECIPIENT[..41], 1), None);
+    }
+
+    #[test]
+    fn checks_arguments_against_the_signature() {
+        let signature = "payout(address,uint256,bytes32,uint16)";
+        assert!(PayoutCall::parse(signature, "recipient,amount,payment_id,uint:65535").is_ok());
+        assert!(rejection(signature, "recipient,amount,payment_id").contains("4 parameters"));
+        assert!(
+            rejection(signature, "recipient,amount,payment_id,uint:65536")
+                .contains("cannot be passed as uint16")
+        );
+        assert!(rejection(signature, "amount,recipient,payment_id,uint:7")
+            .contains("argument 0 (amount) cannot be passed as address"));
+        assert!(rejection(
+            "payout(address,uint32,bytes32)",
+            "recipient,amount,payment_id"
+        )
+        .contains("cannot be passed as uint32"));
+        assert!(rejection(
+            "payout(bytes32,address,uint256,bytes32)",
+            "payment_id,recipient,amount,payment_id"
+        )
+        .contains("payment_id is passed 2 times"));
+        assert!(rejection("payout(address, uint256)", "recipient,amount")
+            .contains("not a canonical function signature"));
+        assert!(rejection(signature, "recipient,amount,payment_id,int:7")
+            .contains("Invalid payout call argument int:7"));
+
+        let call = PayoutCall::parse(
+            "payout(bytes32,address,uint256,bytes32)",
+            &format!("payment_id,recipient,amount,bytes32:0x{}", "11".repeat(32)),
+        )
+        .unwrap();
+        assert_eq!(
+            PayoutCall::parse(call.signature(), &call.arguments_spec()).unwrap(),
+            call
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Cancellation of broadcast payouts that have not been mined yet
+//!
+//! A pending transaction is replaced by a zero-value transfer from the
+//! operator to itself with the same nonce and a higher fee. Whichever of the
+//! two is mined decides whether the payout was cancelled or came too late.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::{
+    EthereumPayoutService, PayoutError, PayoutRecord, PayoutStatus, TransactionReceipt, TxParams,
+    NATIVE_TRANSFER_GAS_LIMIT,
+};
+use serde_json::json;
+use tracing::{info, warn};
+
+/// Fee increase of a replacement over the original, in percent.
+/// Geth and most other clients require at least 10%.
+const REPLACEMENT_FEE_BUMP_PERCENT: u64 = 25;
+
+/// How a cancellation attempt ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CancelOutcome {
+    /// The self-transfer was mined; the payout will never happen
+    Cancelled { cancel_tx_hash: String },
+    /// The payout was mined first and is now confirmed (or failed on-chain)
+    TooLate { record: Box<PayoutRecord> },
+}
+
+impl EthereumPayoutService {
+    /// Replace the payout's pending transaction with a zero-value self-transfer
+    /// and wait for one of the two to be mined.
+    ///
+    /// Refuses payouts that were never broadcast or already have a receipt.
+    pub async fn cancel_inflight(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<CancelOutcome, PayoutError> {
+        let record = self.store.get(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        let tx_hash = match (&record.tx_hash, record.status) {
+            (Some(tx_hash), PayoutStatus::Submitted) | (Some(tx_hash), PayoutStatus::Abandoned)
+                if tx_hash.starts_with("0x") =>
+            {
+                tx_hash.clone()
+            }
+            _ => {
+                return Err(PayoutError::Config(format!(
+                    "Payout {} is {:?} without a pending transaction",
+                    record.payment_id_hex(),
+                    record.status
+                )))
+            }
+        };
+        if let Some(record) = self.refresh_receipt(payment_id).await? {
+            return Err(PayoutError::Config(format!(
+                "Payout {} was already mined in block {}",
+                record.payment_id_hex(),
+                record.block_number.unwrap_or_default()
+            )));
+        }
+
+        let pending = self
+            .rpc(rpc_request("eth_getTransactionByHash", json!([&tx_hash])))
+            .await?;
+        if pending.is_null() {
+            return Err(PayoutError::InvalidResponse(format!(
+                "Transaction {} is unknown to the node",
+                tx_hash
+            )));
+        }
+        let signing = self.signing.read().await;
+        let operator = self.operator_address();
+        let sender = pending["from"].as_str().unwrap_or(&operator);
+        if !sender.eq_ignore_ascii_case(&operator) {
+            // Replacing it needs the nonce of an account the service no longer sends from
+            return Err(PayoutError::Config(format!(
+                "Payout {} was sent by retired operator {}",
+                record.payment_id_hex(),
+                sender
+            )));
+        }
+        let nonce = parse_quantity(&pending["nonce"])?;
+        let original_price = parse_quantity(&pending["gasPrice"])?;
+        let gas_price = (original_price.saturating_mul(100 + REPLACEMENT_FEE_BUMP_PERCENT) / 100)
+            .max(original_price + 1)
+            .max(self.get_gas_price().await?);
+
+        let params = TxParams {
+            nonce,
+            gas_limit: NATIVE_TRANSFER_GAS_LIMIT,
+            gas_price,
+        };
+        let cancel_tx_hash = self
+            .send_raw_transaction(&operator, "0x", 0, params)
+            .await?;
+        drop(signing);
+        info!(
+            "Cancelling payout {}: replaced {} with {} at nonce {}",
+            record.payment_id_hex(),
+            tx_hash,
+            cancel_tx_hash,
+            nonce
+        );
+        let mut record = record;
+        record.cancel_tx_hash = Some(cancel_tx_hash.clone());
+        self.store.save(record.clone());
+
+        loop {
+            if let Some(record) = self.refresh_receipt(payment_id).await? {
+                warn!(
+                    "Payout {} was mined before its cancellation",
+                    record.payment_id_hex()
+                );
+                return Ok(CancelOutcome::TooLate {
+                    record: Box::new(record),
+                });
+            }
+            let receipt = self
+                .rpc(rpc_request(
+                    "eth_getTransactionReceipt",
+                    json!([&cancel_tx_hash]),
+                ))
+                .await?;
+            if !receipt.is_null() {
+                let receipt = TransactionReceipt::parse(&receipt)?;
+                record.status = PayoutStatus::Cancelled;
+                record.block_number = Some(receipt.block_number);
+                record.gas_used = Some(receipt.gas_used);
+                record.effective_gas_price = receipt.effective_gas_price.or(Some(gas_price.into()));
+                record.gas_cost = record
+                    .effective_gas_price
+                    .map(|price| price * u128::from(receipt.gas_used));
+                self.store.save(record);
+                self.settle_split(payment_id);
+                info!("Payout 0x{} cancelled", hex::encode(payment_id));
+                return Ok(CancelOutcome::Cancelled { cancel_tx_hash });
+            }
+            if let Some(deadline) = record.deadline {
+                if self.clock.now() >= deadline {
+                    return Err(self.abandon(
+                        record,
+                        format!(
+                            "neither {} nor {} mined before the deadline",
+                            tx_hash, cancel_tx_hash
+                        ),
+                    ));
+                }
+            }
+            if !self
+                .sleep_unless_cancelled(self.config.receipt_poll_interval)
+                .await
+            {
+                return Err(PayoutError::Cancelled);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION, TEST_OPERATOR,
+    };
+    use super::super::EthereumPayoutConfig;
+    use super::*;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const MINED: &str = r#"{"blockNumber": "0x7", "gasUsed": "0x5208", "status": "0x1"}"#;
+
+    /// A payout broadcast as 0xabc at nonce 5, with receipts mined after
+    /// `original_after` and `cancel_after` lookups respectively
+    async fn inflight(
+        original_after: usize,
+        cancel_after: usize,
+    ) -> (EthereumPayoutService, Arc<MockTransport>, [u8; 32]) {
+        inflight_with(test_config(), original_after, cancel_after).await
+    }
+
+    async fn inflight_with(
+        config: EthereumPayoutConfig,
+        original_after: usize,
+        cancel_after: usize,
+    ) -> (EthereumPayoutService, Arc<MockTransport>, [u8; 32]) {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x5"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        transport.on_result(
+            "eth_getTransactionByHash",
+            json!({"hash": "0xabc", "nonce": "0x5", "gasPrice": "0x3b9aca00"}),
+        );
+        let lookups = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        transport.on("eth_getTransactionReceipt", move |params| {
+            let (counter, after) = match params[0].as_str() {
+                Some("0xabc") => (&lookups.0, original_after),
+                _ => (&lookups.1, cancel_after),
+            };
+            if counter.fetch_add(1, Ordering::SeqCst) < after {
+                Ok(Value::Null)
+            } else {
+                Ok(serde_json::from_str(MINED).unwrap())
+            }
+        });
+        let service = test_service(config, transport.clone()).with_clock(FakeClock::new());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        transport.on_result("eth_sendRawTransaction", json!("0xcancel"));
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        (service, transport, payment_id)
+    }
+
+    #[tokio::test]
+    async fn cancellation_wins_the_race() {
+        let (service, transport, payment_id) = inflight(usize::MAX, 2).await;
+        let outcome = service.cancel_inflight(&payment_id).await.unwrap();
+        assert_eq!(
+            outcome,
+            CancelOutcome::Cancelled {
+                cancel_tx_hash: "0xcancel".to_string()
+            }
+        );
+
+        let replacement = &transport.sent_transactions()[1];
+        assert_eq!(replacement["from"], TEST_OPERATOR);
+        assert_eq!(replacement["to"], TEST_OPERATOR);
+        assert_eq!(replacement["nonce"], "0x5");
+        assert_eq!(replacement["gasPrice"], format!("0x{:x}", 1_250_000_000u64));
+        assert!(replacement.get("value").is_none());
+
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Cancelled);
+        assert_eq!(record.cancel_tx_hash.as_deref(), Some("0xcancel"));
+        assert_eq!(record.block_number, Some(7));
+    }
+
+    #[tokio::test]
+    async fn original_wins_the_race() {
+        let (service, _, payment_id) = inflight(2, usize::MAX).await;
+        match service.cancel_inflight(&payment_id).await.unwrap() {
+            CancelOutcome::TooLate { record } => {
+                assert_eq!(record.status, PayoutStatus::Confirmed);
+                assert_eq!(record.cancel_tx_hash.as_deref(), Some("0xcancel"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn mined_payouts_are_not_cancelled() {
+        let (service, transport, payment_id) = inflight(0, 0).await;
+        assert!(service.cancel_inflight(&payment_id).await.is_err());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().status,
+            PayoutStatus::Confirmed
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_on_the_replacement_stops_at_the_deadline() {
+        let mut config = test_config();
+        config.payout_deadline = Some(Duration::from_secs(60));
+        let (service, _, payment_id) = inflight_with(config, usize::MAX, usize::MAX).await;
+        assert!(matches!(
+            service.cancel_inflight(&payment_id).await,
+            Err(PayoutError::Abandoned { .. })
+        ));
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Abandoned);
+        assert_eq!(record.cancel_tx_hash.as_deref(), Some("0xcancel"));
+    }
+}
@@ -0,0 +1,354 @@
+//! Detection of the Treasury's version and optional functions
+//!
+//! Treasury deployments differ between chains: v1 has neither the memo
+//! overload of `payoutToUser` nor batch payouts, later versions add them. At
+//! startup the service calls the configured version view, `version()` by
+//! default, and probes each optional function with an `eth_call` of
+//! harmless arguments from the operator. A function that answers, or reverts
+//! with data such as a reason string, is there; a call that reverts without
+//! data or hits an invalid opcode fell through the contract's dispatcher, so
+//! the function is not. Probes that fail otherwise leave the function
+//! unknown.
+//!
+//! The result is in [`status`](super::status()). Requests needing a function
+//! the Treasury lacks are refused before anything is sent, rather than
+//! reverting on-chain: payouts with memos, which are never paid without
+//! them, when the memo overload is missing.
+
+use super::abi::selector;
+use super::rpc::rpc_request;
+use super::{
+    payout_memo_calldata, EthereumPayoutService, PayoutError, PayoutRequest,
+    PAYOUT_WITH_MEMO_SIGNATURE,
+};
+use serde_json::json;
+use std::convert::TryFrom;
+use tracing::{info, warn};
+
+/// View returning the Treasury's version unless configured otherwise
+pub const DEFAULT_VERSION_VIEW: &str = "version()";
+
+/// Batch payout function of Treasury v2 and later
+pub const BATCH_PAYOUT_SIGNATURE: &str = "payoutBatch(bytes32[],address[],uint256[])";
+
+/// View telling whether a payment ID was already paid
+pub const IS_PROCESSED_SIGNATURE: &str = "isProcessed(bytes32)";
+
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+/// What the Treasury was found to support; `None` where a probe failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreasuryCapabilities {
+    pub treasury: String,
+    pub chain_id: u64,
+    /// What the version view returned, `None` without one
+    pub version: Option<String>,
+    /// `payoutToUser` with a memo, see [`PAYOUT_WITH_MEMO_SIGNATURE`]
+    pub memo: Option<bool>,
+    /// See [`BATCH_PAYOUT_SIGNATURE`]
+    pub batch: Option<bool>,
+    /// See [`IS_PROCESSED_SIGNATURE`]
+    pub is_processed: Option<bool>,
+}
+
+/// How the answer to a probe reads
+fn probe_result(result: &Result<serde_json::Value, PayoutError>) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(PayoutError::Rpc { message, data, .. }) => {
+            let data = data.as_deref().map(|data| data.trim_start_matches("0x"));
+            if message.to_ascii_lowercase().contains("invalid opcode") {
+                Some(false)
+            } else if data.is_some_and(|data| !data.is_empty()) {
+                Some(true)
+            } else if message.to_ascii_lowercase().contains("revert") {
+                Some(false)
+            } else {
+                None
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+/// A version view's return value: a number, a string, or its hex otherwise
+fn decode_version(result: &str) -> Option<String> {
+    let bytes = hex::decode(result.trim_start_matches("0x")).ok()?;
+    match bytes.len() {
+        0 => None,
+        32 if bytes[..16].iter().all(|b| *b == 0) => {
+            let mut low = [0u8; 16];
+            low.copy_from_slice(&bytes[16..]);
+            Some(u128::from_be_bytes(low).to_string())
+        }
+        len if len >= 64 => {
+            let word = |at: usize| -> Option<usize> {
+                let word = bytes.get(at..at + 32)?;
+                if word[..24].iter().any(|b| *b != 0) {
+                    return None;
+                }
+                let mut low = [0u8; 8];
+                low.copy_from_slice(&word[24..]);
+                usize::try_from(u64::from_be_bytes(low)).ok()
+            };
+            let offset = word(0)?;
+            let len = word(offset)?;
+            let start = offset.checked_add(32)?;
+            let text = bytes.get(start..start.checked_add(len)?)?;
+            Some(String::from_utf8_lossy(text).into_owned())
+        }
+        _ => Some(format!("0x{}", hex::encode(bytes))),
+    }
+}
+
+impl EthereumPayoutService {
+    /// Call the Treasury's version view and probe its optional functions,
+    /// recording what was found
+    pub async fn probe_treasury(&self) -> TreasuryCapabilities {
+        let version = match &self.config.treasury_version_view {
+            Some(view) => self.read_version(view).await,
+            None => None,
+        };
+        let memo =
+            payout_memo_calldata(&[0; 32], ZERO_ADDRESS, 0, &[]).expect("the zero address encodes");
+        // Three empty arrays, each an offset to its zero length
+        let batch = format!(
+            "0x{}{:064x}{:064x}{:064x}{:064x}{:064x}{:064x}",
+            hex::encode(selector(BATCH_PAYOUT_SIGNATURE)),
+            0x60,
+            0x80,
+            0xa0,
+            0,
+            0,
+            0
+        );
+        let is_processed = format!(
+            "0x{}{}",
+            hex::encode(selector(IS_PROCESSED_SIGNATURE)),
+            "0".repeat(64)
+        );
+        let capabilities = TreasuryCapabilities {
+            treasury: self.config.treasury_address.clone(),
+            chain_id: self.config.expected_chain_id,
+            version,
+            memo: self.probe_function(&memo).await,
+            batch: self.probe_function(&batch).await,
+            is_processed: self.probe_function(&is_processed).await,
+        };
+        info!(
+            "Treasury {} is version {}: memo {:?}, batch {:?}, isProcessed {:?}",
+            capabilities.treasury,
+            capabilities.version.as_deref().unwrap_or("unknown"),
+            capabilities.memo,
+            capabilities.batch,
+            capabilities.is_processed
+        );
+        if capabilities.memo == Some(false) {
+            warn!(
+                "Treasury {} has no {}; payouts with memos are refused",
+                capabilities.treasury, PAYOUT_WITH_MEMO_SIGNATURE
+            );
+        }
+        *self.treasury_capabilities.lock().unwrap() = Some(capabilities.clone());
+        capabilities
+    }
+
+    /// What the last [`Self::probe_treasury`] found, `None` before it ran
+    pub fn treasury_capabilities(&self) -> Option<TreasuryCapabilities> {
+        self.treasury_capabilities.lock().unwrap().clone()
+    }
+
+    /// Refuse a memo the Treasury was found unable to record
+    pub(super) fn check_memo_support(&self, request: &PayoutRequest) -> Result<(), PayoutError> {
+        let unsupported = self
+            .treasury_capabilities
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|capabilities| capabilities.memo == Some(false));
+        if request.memo.is_some() && unsupported && self.config.payout_call.is_none() {
+            return Err(PayoutError::Config(format!(
+                "Treasury {} has no {} to carry the memo",
+                self.config.treasury_address, PAYOUT_WITH_MEMO_SIGNATURE
+            )));
+        }
+        Ok(())
+    }
+
+    async fn read_version(&self, view: &str) -> Option<String> {
+        let call = json!({
+            "to": self.config.treasury_address,
+            "data": format!("0x{}", hex::encode(selector(view))),
+        });
+        let result = self
+            .rpc(rpc_request("eth_call", json!([call, "latest"])))
+            .await;
+        match &result {
+            Ok(value) => value.as_str().and_then(decode_version),
+            Err(err) => {
+                if probe_result(&result).is_none() {
+                    warn!("Could not read the Treasury's {}: {}", view, err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether the Treasury has the function `data` calls
+    async fn probe_function(&self, data: &str) -> Option<bool> {
+        let call = json!({
+            "from": self.operator_address(),
+            "to": self.config.treasury_address,
+            "data": data,
+        });
+        let result = self
+            .rpc(rpc_request("eth_call", json!([call, "latest"])))
+            .await;
+        let found = probe_result(&result);
+        if let (Err(err), None) = (&result, found) {
+            warn!("Could not probe the Treasury: {}", err);
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{Dispatched, PayoutOutcome, PAYOUT_TO_USER_SELECTOR};
+    use super::*;
+    use std::sync::Arc;
+
+    /// Treasury answering `payoutToUser` and the functions whose selectors
+    /// are in `present`, reverting without data on any other
+    fn treasury(version: Option<String>, present: &[&str]) -> Arc<MockTransport> {
+        let transport = mock_chain();
+        let present: Vec<String> = present
+            .iter()
+            .map(|signature| hex::encode(selector(signature)))
+            .chain([PAYOUT_TO_USER_SELECTOR.to_string()])
+            .collect();
+        let version_selector = hex::encode(selector(DEFAULT_VERSION_VIEW));
+        transport.on("eth_call", move |params| {
+            let data = params[0]["data"].as_str().unwrap();
+            let called = &data[2..10];
+            match &version {
+                Some(version) if called == version_selector => Ok(json!(version)),
+                _ if !present.iter().any(|selector| selector == called) => {
+                    Err(json!({ "code": 3, "message": "execution reverted" }))
+                }
+                // Present, and refusing the probe's zero arguments
+                _ if data[10..].bytes().all(|digit| digit == b'0') => Err(json!({
+                    "code": 3,
+                    "message": "execution reverted: zero amount",
+                    "data": "0x08c379a0",
+                })),
+                _ => Ok(json!("0x")),
+            }
+        });
+        transport
+    }
+
+    #[tokio::test]
+    async fn detects_a_v1_treasury() {
+        let transport = treasury(Some(format!("0x{:064x}", 1)), &[]);
+        let service = Arc::new(test_service(test_config(), transport.clone()));
+        let capabilities = service.probe_treasury().await;
+        assert_eq!(capabilities.version.as_deref(), Some("1"));
+        assert_eq!(
+            (
+                capabilities.memo,
+                capabilities.batch,
+                capabilities.is_processed
+            ),
+            (Some(false), Some(false), Some(false))
+        );
+        assert_eq!(service.treasury_capabilities(), Some(capabilities));
+
+        // Memos are refused before sending, memo-less payouts still go out
+        let sent = transport.call_count("eth_sendRawTransaction");
+        let request = PayoutRequest::new(TEST_DESTINATION, 100, 1).with_memo(b"INV-1".to_vec());
+        match service.dispatch_payout(request).await {
+            Dispatched::Completed(Err(PayoutError::Config(message))) => {
+                assert!(message.contains("to carry the memo"))
+            }
+            other => panic!("expected the memo to be refused, got {:?}", other),
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), sent);
+        assert!(matches!(
+            service
+                .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 2))
+                .await,
+            Dispatched::Completed(Ok(PayoutOutcome::Submitted { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_a_v2_treasury() {
+        // version() returning the string "2.1.0"
+        let v2 = format!("0x{:064x}{:064x}{:0<64}", 0x20, 5, hex::encode("2.1.0"));
+        let transport = treasury(
+            Some(v2),
+            &[
+                PAYOUT_WITH_MEMO_SIGNATURE,
+                BATCH_PAYOUT_SIGNATURE,
+                IS_PROCESSED_SIGNATURE,
+            ],
+        );
+        let service = Arc::new(test_service(test_config(), transport.clone()));
+        let capabilities = service.probe_treasury().await;
+        assert_eq!(capabilities.version.as_deref(), Some("2.1.0"));
+        assert_eq!(
+            (
+                capabilities.memo,
+                capabilities.batch,
+                capabilities.is_processed
+            ),
+            (Some(true), Some(true), Some(true))
+        );
+        let probe = &transport.calls("eth_call")[1]["params"][0];
+        assert_eq!(probe["from"], json!(service.operator_address()));
+
+        let request = PayoutRequest::new(TEST_DESTINATION, 100, 1).with_memo(b"INV-1".to_vec());
+        assert!(matches!(
+            service.dispatch_payout(request).await,
+            Dispatched::Completed(Ok(PayoutOutcome::Submitted { .. }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn treasuries_without_a_version_view_are_still_probed() {
+        let transport = treasury(None, &[IS_PROCESSED_SIGNATURE]);
+        let service = test_service(test_config(), transport.clone());
+        let capabilities = service.probe_treasury().await;
+        assert_eq!(capabilities.version, None);
+        assert_eq!(
+            (
+                capabilities.memo,
+                capabilities.batch,
+                capabilities.is_processed
+            ),
+            (Some(false), Some(false), Some(true))
+        );
+
+        // Contracts without a dispatcher fallback hit an invalid opcode instead
+        transport.on_error("eth_call", -32000, "invalid opcode: INVALID");
+        assert_eq!(service.probe_treasury().await.is_processed, Some(false));
+
+        // A node that cannot answer leaves everything unknown
+        transport.on_error("eth_call", -32603, "internal error");
+        let capabilities = service.probe_treasury().await;
+        assert_eq!(
+            (
+                capabilities.version,
+                capabilities.memo,
+                capabilities.batch,
+                capabilities.is_processed
+            ),
+            (None, None, None, None)
+        );
+        assert_eq!(decode_version("0x"), None);
+    }
+}
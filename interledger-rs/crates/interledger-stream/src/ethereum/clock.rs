@@ -0,0 +1,54 @@
+//! Source of time for deadlines, rate ages and record timestamps
+//!
+//! The wall clock can step back, e.g. when NTP corrects it, so records and
+//! events also carry a serial from a per-service counter that only increases.
+//! Listings are ordered by serial rather than timestamp. The counter starts
+//! above the highest serial in the store, so serials keep increasing across
+//! restarts with a persistent store.
+
+use super::{EthereumPayoutService, Timestamp};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Wall clock and timer used by the service, replaceable in tests
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Timestamp;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The system clock and tokio timers
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Utc::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+impl EthereumPayoutService {
+    /// Next serial for a record or event
+    pub(super) fn next_serial(&self) -> u64 {
+        self.serials
+            .fetch_max(self.store.highest_serial(), Ordering::SeqCst);
+        self.serials.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Serial of the record for `payment_id`: that of its existing record, so
+    /// it keeps its place when rewritten, or else a new one
+    pub(super) fn record_serial(&self, payment_id: &[u8; 32]) -> u64 {
+        match self.store.get(payment_id) {
+            Some(record) if record.serial > 0 => record.serial,
+            _ => self.next_serial(),
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Outcome of a split payout as a whole
+//!
+//! A payout above the asset's `max_payout_per_tx` is sent as chunks, each
+//! recorded and reported under its own payment ID. Chunks left unsent
+//! because an earlier one failed are recorded as `Skipped`. The request's
+//! payment ID names the parent: [`EthereumPayoutService::composite_payout`]
+//! returns it with its chunk records, while looking a chunk up returns that
+//! chunk alone.
+//!
+//! Once every chunk is terminal the service publishes a
+//! [`PayoutEvent::Composite`] for the parent, summarising its chunks: settled
+//! if all were confirmed, failed if none was, partially settled otherwise.
+//! Resuming a split payout can later end it again, settled. Splits in flight
+//! are tracked in memory, so chunks ending after a restart publish no parent
+//! event; the parent can still be queried once all its chunks are recorded.
+
+use super::payload::chunk_payment_id;
+use super::{EthereumPayoutService, PayoutEvent, PayoutRecord, PayoutStatus};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// How far a split payout got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompositeStatus {
+    /// Some chunks are not terminal yet
+    Pending,
+    /// Every chunk was confirmed
+    Settled,
+    /// Some chunks were confirmed and the others ended without paying
+    PartiallySettled,
+    /// No chunk was confirmed
+    Failed,
+}
+
+impl CompositeStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompositeStatus::Pending => "pending",
+            CompositeStatus::Settled => "settled",
+            CompositeStatus::PartiallySettled => "partially_settled",
+            CompositeStatus::Failed => "failed",
+        }
+    }
+}
+
+/// One chunk of a split payout as its parent event reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildSummary {
+    pub payment_id: String,
+    pub status: PayoutStatus,
+    pub amount: u64,
+    pub tx_hash: Option<String>,
+}
+
+impl ChildSummary {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "payment_id": self.payment_id,
+            "status": self.status.as_str(),
+            "amount": self.amount,
+            "tx_hash": self.tx_hash,
+        })
+    }
+}
+
+/// A split payout with the records of its chunks, in chunk order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompositePayout {
+    pub payment_id: [u8; 32],
+    /// Number of chunks, including any not recorded yet
+    pub total: usize,
+    pub children: Vec<PayoutRecord>,
+}
+
+/// Whether a chunk in `status` will not change without an operator
+fn is_terminal(status: PayoutStatus) -> bool {
+    matches!(
+        status,
+        PayoutStatus::Confirmed
+            | PayoutStatus::Failed
+            | PayoutStatus::Abandoned
+            | PayoutStatus::Cancelled
+            | PayoutStatus::Rejected
+            | PayoutStatus::Skipped
+    )
+}
+
+impl CompositePayout {
+    pub fn status(&self) -> CompositeStatus {
+        let pending = self.children.len() < self.total
+            || self.children.iter().any(|child| !is_terminal(child.status));
+        let settled = self
+            .children
+            .iter()
+            .filter(|child| child.status == PayoutStatus::Confirmed)
+            .count();
+        match settled {
+            _ if pending => CompositeStatus::Pending,
+            0 => CompositeStatus::Failed,
+            settled if settled == self.total => CompositeStatus::Settled,
+            _ => CompositeStatus::PartiallySettled,
+        }
+    }
+
+    pub fn summaries(&self) -> Vec<ChildSummary> {
+        self.children
+            .iter()
+            .map(|child| ChildSummary {
+                payment_id: child.payment_id_hex(),
+                status: child.status,
+                amount: child.amount,
+                tx_hash: child.tx_hash.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Split payouts in flight: chunk count per parent, parent per chunk
+#[derive(Debug, Default)]
+pub(super) struct Splits {
+    totals: HashMap<[u8; 32], usize>,
+    parents: HashMap<[u8; 32], [u8; 32]>,
+}
+
+impl EthereumPayoutService {
+    /// The split payout `payment_id` names, `None` unless it was split
+    pub fn composite_payout(&self, payment_id: &[u8; 32]) -> Option<CompositePayout> {
+        let chunk = |index: usize| self.lookup(&chunk_payment_id(payment_id, index as u32));
+        let tracked = self.splits.lock().unwrap().totals.get(payment_id).copied();
+        let children: Vec<PayoutRecord> = match tracked {
+            Some(total) => (0..total).filter_map(chunk).collect(),
+            None => (0..)
+                .map(chunk)
+                .take_while(Option::is_some)
+                .flatten()
+                .collect(),
+        };
+        if tracked.is_none() && children.is_empty() {
+            return None;
+        }
+        Some(CompositePayout {
+            payment_id: *payment_id,
+            total: tracked.unwrap_or(children.len()),
+            children,
+        })
+    }
+
+    /// Track the split of `parent` into `chunks` until it ends
+    pub(super) fn track_split(&self, parent: [u8; 32], chunks: &[[u8; 32]]) {
+        let mut splits = self.splits.lock().unwrap();
+        splits.totals.insert(parent, chunks.len());
+        for chunk in chunks {
+            splits.parents.insert(*chunk, parent);
+        }
+    }
+
+    /// Publish the parent's outcome if `chunk` belongs to a split payout whose
+    /// chunks are now all terminal
+    pub(super) fn settle_split(&self, chunk: &[u8; 32]) {
+        let parent = match self.splits.lock().unwrap().parents.get(chunk) {
+            Some(parent) => *parent,
+            None => return,
+        };
+        let composite = match self.composite_payout(&parent) {
+            Some(composite) => composite,
+            None => return,
+        };
+        let status = composite.status();
+        if status == CompositeStatus::Pending {
+            return;
+        }
+        let mut splits = self.splits.lock().unwrap();
+        // Another chunk ending at the same time may have settled it already
+        if splits.totals.remove(&parent).is_none() {
+            return;
+        }
+        for child in &composite.children {
+            splits.parents.remove(&child.payment_id);
+        }
+        drop(splits);
+        let first = &composite.children[0];
+        self.publish_payout(
+            &first.destination,
+            first.sequence,
+            PayoutEvent::Composite {
+                payment_id: format!("0x{}", hex::encode(parent)),
+                status,
+                children: composite.summaries(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn parent_settles_once_every_chunk_is_terminal() {
+        let mut config = test_config();
+        config.assets.apply_caps("EURC:100").unwrap();
+        let transport = mock_chain();
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x7", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        let sent = Arc::new(Mutex::new(0));
+        let counter = sent.clone();
+        transport.on("eth_sendRawTransaction", move |_| {
+            let mut sent = counter.lock().unwrap();
+            *sent += 1;
+            match *sent {
+                2 => Err(json!({"code": -32000, "message": "insufficient funds"})),
+                n => Ok(json!(format!("0x{:02x}", n))),
+            }
+        });
+        let service = test_service(config, transport);
+        let mut events = service.subscribe();
+        let parent = service.config().payment_id(TEST_DESTINATION, 1);
+        let chunks: Vec<[u8; 32]> = (0..3).map(|i| chunk_payment_id(&parent, i)).collect();
+
+        service
+            .execute_payout(TEST_DESTINATION, 250, 1)
+            .await
+            .unwrap_err();
+        // The first chunk is not mined yet, so the parent has not ended
+        let composite = service.composite_payout(&parent).unwrap();
+        assert_eq!(composite.status(), CompositeStatus::Pending);
+        assert!(service.composite_payout(&chunks[0]).is_none());
+        assert_eq!(
+            service.lookup(&chunks[2]).unwrap().status,
+            PayoutStatus::Skipped
+        );
+
+        service.refresh_receipt(&chunks[0]).await.unwrap();
+        let composite = service.composite_payout(&parent).unwrap();
+        assert_eq!(composite.status(), CompositeStatus::PartiallySettled);
+
+        let mut received = Vec::new();
+        while let Ok(stamped) = events.try_recv() {
+            received.push(stamped.event);
+        }
+        let hex = |id: &[u8; 32]| format!("0x{}", hex::encode(id));
+        let names: Vec<Value> = received
+            .iter()
+            .map(|event| event.to_json()["event"].clone())
+            .collect();
+        assert_eq!(
+            names,
+            ["submitted", "failed", "skipped", "confirmed", "composite"]
+        );
+        assert_eq!(received[1].payment_id(), Some(hex(&chunks[1]).as_str()));
+        let composite_event = received.last().unwrap();
+        assert_eq!(
+            *composite_event,
+            PayoutEvent::Composite {
+                payment_id: hex(&parent),
+                status: CompositeStatus::PartiallySettled,
+                children: vec![
+                    ChildSummary {
+                        payment_id: hex(&chunks[0]),
+                        status: PayoutStatus::Confirmed,
+                        amount: 100,
+                        tx_hash: Some("0x01".to_string()),
+                    },
+                    ChildSummary {
+                        payment_id: hex(&chunks[1]),
+                        status: PayoutStatus::Failed,
+                        amount: 100,
+                        tx_hash: None,
+                    },
+                    ChildSummary {
+                        payment_id: hex(&chunks[2]),
+                        status: PayoutStatus::Skipped,
+                        amount: 50,
+                        tx_hash: None,
+                    },
+                ],
+            }
+        );
+        let json = composite_event.to_json();
+        assert_eq!(json["status"], "partially_settled");
+        assert_eq!(
+            (json["settled"].clone(), json["total"].clone()),
+            (json!(1), json!(3))
+        );
+        assert_eq!(json["children"][1]["status"], "failed");
+
+        // Resuming ends it again, settled
+        service
+            .execute_payout(TEST_DESTINATION, 250, 1)
+            .await
+            .unwrap();
+        for chunk in &chunks[1..] {
+            service.refresh_receipt(chunk).await.unwrap();
+        }
+        let mut last = None;
+        while let Ok(stamped) = events.try_recv() {
+            last = Some(stamped.event);
+        }
+        match last {
+            Some(PayoutEvent::Composite { status, .. }) => {
+                assert_eq!(status, CompositeStatus::Settled)
+            }
+            other => panic!("expected the parent event, got {:?}", other),
+        }
+    }
+}
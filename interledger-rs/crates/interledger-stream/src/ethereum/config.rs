@@ -0,0 +1,715 @@
+//! Configuration of the Ethereum payout service
+
+use super::address::AddressFormats;
+use super::hash::keccak256;
+use super::{
+    AllowancePolicy, AssetRegistry, BlockPinning, CanaryPayout, ConnectionPolicy,
+    DestinationPolicy, ExecutionMode, FailureStreakPolicy, FlushSchedule, GasFallbacks,
+    KillSwitchConfig, KillSwitchMode, L2FeeModel, LogMask, PaymentIdHash, PaymentIdStrategy,
+    PayoutCall, RecipientDenyList, RecipientOrdering, RejectKind, RetryPolicy, RevertDecoder,
+    RoundingMode, SafeConfig, SelfTestOptions, StaticRateProvider, StoreMigration, TokenAlphabet,
+    DEFAULT_RECIPIENT_STATE_CAPACITY, DEFAULT_VERSION_VIEW,
+};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Role checked by default, as defined by OpenZeppelin AccessControl deployments
+pub const DEFAULT_OPERATOR_ROLE: &str = "OPERATOR_ROLE";
+
+/// Verification that the operator holds its role on the Treasury
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoleCheckConfig {
+    /// 32-byte role identifier passed to `hasRole(bytes32,address)`
+    pub role: [u8; 32],
+    /// How often the role is re-checked after startup
+    pub interval: Duration,
+}
+
+impl Default for RoleCheckConfig {
+    fn default() -> Self {
+        RoleCheckConfig {
+            role: keccak256(DEFAULT_OPERATOR_ROLE.as_bytes()),
+            interval: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RoleCheckConfig {
+    /// Parse a role given either as a 0x-prefixed 32-byte hash or as a role name to hash
+    pub fn parse_role(role: &str) -> Option<[u8; 32]> {
+        match role.strip_prefix("0x") {
+            Some(hash) => {
+                let bytes = hex::decode(hash).ok()?;
+                let mut role = [0u8; 32];
+                if bytes.len() != role.len() {
+                    return None;
+                }
+                role.copy_from_slice(&bytes);
+                Some(role)
+            }
+            None => Some(keccak256(role.as_bytes())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EthereumPayoutConfig {
+    pub rpc_url: String,
+    /// Further endpoints of the same chain; requests are spread over all of them by performance
+    pub fallback_rpc_urls: Vec<String>,
+    pub treasury_address: String,
+    pub operator_private_key: String,
+    pub expected_chain_id: u64,
+    /// Domain separating payment IDs of this Treasury deployment from others,
+    /// `None` for IDs derived from the destination and sequence alone
+    pub payment_id_domain: Option<String>,
+    /// Hash payment IDs are derived with
+    pub payment_id_hash: PaymentIdHash,
+    /// Derives payment IDs instead of `payment_id_hash`, see
+    /// [`EthereumPayoutService::with_payment_id_strategy`](super::EthereumPayoutService::with_payment_id_strategy)
+    pub payment_id_strategy: Option<Arc<dyn PaymentIdStrategy>>,
+    pub assets: AssetRegistry,
+    /// Recipients that are never paid; the zero address is always refused
+    pub denied_recipients: RecipientDenyList,
+    /// Refuse sequences this far behind the highest one paid to the same destination
+    pub sequence_max_lag: Option<u64>,
+    /// Operator role verification, `None` for Treasury contracts without AccessControl
+    pub role_check: Option<RoleCheckConfig>,
+    /// Interval of the periodic `paused()` check, `None` to rely on revert detection only
+    pub pause_check_interval: Option<Duration>,
+    /// Sentinel file stopping payouts on every instance while it exists
+    pub kill_switch: Option<KillSwitchConfig>,
+    /// How often an unavailable signer is probed
+    pub signer_probe_interval: Duration,
+    /// Custom errors decoded from revert data
+    pub revert_errors: RevertDecoder,
+    /// File backing the payout store, `None` to keep records in memory only.
+    /// Required when any asset uses direct transfers.
+    pub store_path: Option<PathBuf>,
+    /// Whether a store file from an older release is backed up and migrated
+    /// or refused
+    pub store_migration: StoreMigration,
+    /// Safe that payouts above its threshold are proposed to instead of sent
+    pub safe: Option<SafeConfig>,
+    /// Endpoint accepting signed EIP-3009 authorizations for submission
+    pub relayer_url: Option<String>,
+    /// How long a signed authorization stays valid
+    pub authorization_validity: Duration,
+    /// When accumulated dust is paid out, `None` to only flush on demand
+    pub dust_flush: Option<FlushSchedule>,
+    /// How long a payout may wait for approval before it is rejected, `None` to wait indefinitely
+    pub approval_ttl: Option<Duration>,
+    /// Fixed rates from the ILP asset to payout assets, if no other provider is set
+    pub static_rates: Option<StaticRateProvider>,
+    /// How converted amounts are rounded to the payout asset's base units
+    pub rounding: RoundingMode,
+    /// Add the residue left by rounding to the recipient's later payouts
+    pub carry_rounding_residue: bool,
+    /// Longest memo accepted for the Treasury's memo overload, in bytes
+    pub max_memo_len: usize,
+    /// Fail zero-amount payouts instead of skipping them, to surface upstream bugs
+    pub reject_zero_amounts: bool,
+    /// Keep payments `maybe_execute_payout` skips in the store as `Skipped` records
+    pub record_skips: bool,
+    /// Fall back to the default Anvil account if the operator key is unusable.
+    /// Only for local development; never enable it against a real chain.
+    pub dev_mode: bool,
+    /// Have the node sign transactions with its own unlocked account
+    /// (`eth_sendTransaction`) instead of the operator's signer
+    pub node_signing: bool,
+    /// Tenant the service pays out for, labelling its metrics
+    pub tenant: Option<String>,
+    /// Whether `maybe_execute_payout` waits for the payout, spawns it or queues it
+    pub execution_mode: ExecutionMode,
+    /// Whether payouts to the same recipient run one at a time and in order
+    pub recipient_ordering: RecipientOrdering,
+    /// Payout events kept for subscribers; slower subscribers miss the oldest
+    pub event_channel_capacity: usize,
+    /// Entries of each kind of per-recipient state, such as dust balances,
+    /// kept in memory; the least recently used beyond it go to the store
+    pub recipient_state_capacity: usize,
+    /// Recipient address formats of chains that are not plain EVM
+    pub address_formats: AddressFormats,
+    /// How destinations' stream tokens are shown in logs and events
+    pub destination_policy: DestinationPolicy,
+    /// Masking of recipient addresses in logs and events, `None` to show them
+    pub log_mask: Option<LogMask>,
+    /// Attribution tags named in cost metrics and aggregates, the others
+    /// being counted as `other`
+    pub attribution_top_n: usize,
+    /// Pay unregistered token addresses in a destination's asset segment by
+    /// direct transfer instead of refusing them
+    pub accept_token_addresses: bool,
+    /// Self-test a service must pass before `init_payout_service` publishes
+    /// it, `None` to publish it untested
+    pub self_test: Option<SelfTestOptions>,
+    /// Reasons a payout would fail that reject the ILP packet before it is
+    /// fulfilled; payouts failing for other reasons are accepted and held
+    pub reject_before_fulfil: Vec<RejectKind>,
+    /// Time a payout may take, including retries and waiting for its receipt,
+    /// before it is abandoned. `None` sends once and waits indefinitely.
+    pub payout_deadline: Option<Duration>,
+    /// Delay between attempts to send a payout that failed transiently
+    pub retry_interval: Duration,
+    /// Most of each retry delay cut off at random, in percent, so payouts
+    /// failing together are not all retried at once
+    pub retry_jitter_percent: u32,
+    /// Queue payouts without a deadline that fail transiently for later
+    /// attempts, `None` to fail them right away
+    pub retry_queue: Option<RetryPolicy>,
+    /// Collapse the alerts of recipients whose payouts keep failing, `None`
+    /// to alert every failure
+    pub failure_streaks: Option<FailureStreakPolicy>,
+    /// Times a request throttled by the RPC provider is retried after backing off
+    pub throttle_retries: u32,
+    /// How long a nonce reserved from a shared nonce store stays leased
+    pub nonce_lease_ttl: Duration,
+    /// Redis server whose nonce store is shared with the other senders of
+    /// the operator key
+    #[cfg(feature = "redis-nonce-store")]
+    pub nonce_store_url: Option<String>,
+    /// Delay between receipt lookups while waiting for a payout to be mined
+    pub receipt_poll_interval: Duration,
+    /// How far the amount a token payout delivered may differ from the amount
+    /// sent before it is flagged, in basis points of the amount sent
+    pub amount_mismatch_tolerance_bps: u32,
+    /// Alert on flagged payouts of assets not marked as fee-on-transfer,
+    /// rather than only warning
+    pub alert_amount_mismatch: bool,
+    /// Interval of the background gas price sampler, `None` to look it up per payout
+    pub gas_sample_interval: Option<Duration>,
+    /// Fee the chain charges beyond gas used times gas price
+    pub l2_fee_model: L2FeeModel,
+    /// Most a payout's transaction may cost in wei, L2 gas at its limit plus
+    /// any L1 fee, `None` for no limit
+    pub max_gas_cost: Option<u128>,
+    /// Block the chain reads made before sending a payout are pinned to
+    pub block_pinning: BlockPinning,
+    /// Whether `payoutToUser` returns the Treasury's `uint256 payoutId`, as
+    /// Treasury v3 does; it is captured when the payout is simulated
+    pub payout_returns_id: bool,
+    /// View the Treasury's version is read from at startup, `None` to not read it
+    pub treasury_version_view: Option<String>,
+    /// Function Treasury payouts call in place of `payoutToUser`
+    pub payout_call: Option<PayoutCall>,
+    /// Token allowances of the Treasury kept topped up by the operator, for
+    /// Treasuries paying out with `transferFrom`
+    pub treasury_allowances: Option<AllowancePolicy>,
+    /// Sampled gas prices older than this are ignored in favour of a live lookup
+    pub gas_quote_ttl: Duration,
+    /// How long a gas estimate is reused for Treasury and direct transfer
+    /// payouts of the same shape, `None` to send a fixed gas limit
+    pub gas_estimate_ttl: Option<Duration>,
+    /// Gas limits sent when estimating fails with a transient error
+    pub gas_fallbacks: GasFallbacks,
+    /// Payouts taking longer than this to send and mine are logged with their slowest phase
+    pub slow_payout_threshold: Duration,
+    /// Operator balance in wei below which the service reports itself degraded
+    pub balance_floor: Option<u128>,
+    /// How long health check results from the node are reused
+    pub health_cache_ttl: Duration,
+    /// When pooled connections to the node are replaced by new ones
+    pub connection_policy: ConnectionPolicy,
+    /// Time allowed for a request to the node that does not submit a transaction
+    pub read_timeout: Duration,
+    /// Time allowed for submitting a transaction; a send that times out is
+    /// verified on-chain rather than retried
+    pub submit_timeout: Duration,
+    /// Time allowed for each node request made by a health check
+    pub health_timeout: Duration,
+    /// Payouts fail rather than use a rate older than this
+    pub rate_max_age: Duration,
+    /// Submit Treasury payouts as UserOperations from a smart account
+    #[cfg(feature = "erc4337")]
+    pub user_op: Option<super::UserOpConfig>,
+}
+
+impl EthereumPayoutConfig {
+    /// Create a config with the required settings and defaults for everything else
+    pub fn new(
+        rpc_url: impl Into<String>,
+        treasury_address: impl Into<String>,
+        operator_private_key: impl Into<String>,
+        expected_chain_id: u64,
+    ) -> Self {
+        EthereumPayoutConfig {
+            rpc_url: rpc_url.into(),
+            fallback_rpc_urls: Vec::new(),
+            treasury_address: treasury_address.into(),
+            operator_private_key: operator_private_key.into(),
+            expected_chain_id,
+            payment_id_domain: None,
+            payment_id_hash: PaymentIdHash::default(),
+            payment_id_strategy: None,
+            assets: AssetRegistry::default(),
+            denied_recipients: RecipientDenyList::default(),
+            sequence_max_lag: None,
+            role_check: None,
+            pause_check_interval: None,
+            kill_switch: None,
+            signer_probe_interval: Duration::from_secs(10),
+            revert_errors: RevertDecoder::default(),
+            store_path: None,
+            store_migration: StoreMigration::default(),
+            safe: None,
+            relayer_url: None,
+            authorization_validity: Duration::from_secs(3600),
+            approval_ttl: None,
+            dust_flush: None,
+            execution_mode: ExecutionMode::default(),
+            recipient_ordering: RecipientOrdering::default(),
+            event_channel_capacity: 256,
+            recipient_state_capacity: DEFAULT_RECIPIENT_STATE_CAPACITY,
+            reject_zero_amounts: false,
+            record_skips: false,
+            dev_mode: false,
+            node_signing: false,
+            tenant: None,
+            max_memo_len: 256,
+            address_formats: AddressFormats::default(),
+            destination_policy: DestinationPolicy::default(),
+            log_mask: None,
+            attribution_top_n: 10,
+            accept_token_addresses: false,
+            self_test: None,
+            reject_before_fulfil: Vec::new(),
+            payout_deadline: None,
+            retry_interval: Duration::from_secs(5),
+            retry_jitter_percent: 0,
+            retry_queue: None,
+            failure_streaks: None,
+            receipt_poll_interval: Duration::from_secs(2),
+            amount_mismatch_tolerance_bps: 0,
+            alert_amount_mismatch: false,
+            throttle_retries: 3,
+            nonce_lease_ttl: Duration::from_secs(60),
+            gas_sample_interval: None,
+            gas_quote_ttl: Duration::from_secs(15),
+            gas_estimate_ttl: None,
+            gas_fallbacks: GasFallbacks::default(),
+            l2_fee_model: L2FeeModel::default(),
+            max_gas_cost: None,
+            block_pinning: BlockPinning::default(),
+            payout_returns_id: false,
+            treasury_version_view: Some(DEFAULT_VERSION_VIEW.to_string()),
+            payout_call: None,
+            treasury_allowances: None,
+            slow_payout_threshold: Duration::from_secs(5),
+            balance_floor: None,
+            health_cache_ttl: Duration::from_secs(10),
+            health_timeout: Duration::from_secs(2),
+            read_timeout: Duration::from_secs(5),
+            connection_policy: ConnectionPolicy::default(),
+            submit_timeout: Duration::from_secs(30),
+            static_rates: None,
+            rounding: RoundingMode::default(),
+            carry_rounding_residue: false,
+            rate_max_age: Duration::from_secs(300),
+            #[cfg(feature = "erc4337")]
+            user_op: None,
+            #[cfg(feature = "redis-nonce-store")]
+            nonce_store_url: None,
+        }
+    }
+
+    /// Create config from environment variables
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Create config from variables named like the environment variables
+    /// [`Self::from_env`] reads, looked up with `var`
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let flag = |name: &str| var(name).is_some_and(|value| is_truthy(&value));
+        let rpc_url = var("ETHEREUM_RPC_URL")?;
+        let treasury_address = var("TREASURY_ADDRESS")?;
+        let operator_private_key = var("OPERATOR_PRIVATE_KEY")?;
+        let chain_id: u64 = var("CHAIN_ID")?.parse().ok()?;
+        let mut config =
+            EthereumPayoutConfig::new(rpc_url, treasury_address, operator_private_key, chain_id);
+
+        // Optional comma-separated endpoints used alongside ETHEREUM_RPC_URL
+        if let Some(urls) = var("ETHEREUM_RPC_FALLBACK_URLS") {
+            config.fallback_rpc_urls = urls
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(String::from)
+                .collect();
+        }
+
+        // Namespace payment IDs per Treasury; migrate existing records with `migrate_payment_ids`
+        config.payment_id_domain = var("PAYMENT_ID_DOMAIN");
+        // "sha256" (default) or "keccak256"
+        if let Some(hash) = var("PAYMENT_ID_HASH") {
+            config.payment_id_hash = PaymentIdHash::parse(&hash)?;
+        }
+
+        // Optional list of payout assets, e.g. "EURC:6,USDC:6"
+        if let Some(spec) = var("PAYOUT_ASSETS") {
+            config.assets = AssetRegistry::parse(&spec)?;
+        }
+        // Optional EIP-712 domains of authorization tokens, e.g. "USDC:USD Coin:2"
+        if let Some(spec) = var("PAYOUT_TOKEN_DOMAINS") {
+            config.assets.apply_domains(&spec)?;
+        }
+        // Optional per-transaction caps, e.g. "EURC:1000000000"
+        if let Some(spec) = var("PAYOUT_MAX_PER_TX") {
+            config.assets.apply_caps(&spec)?;
+        }
+        // Optional daily caps in total and per recipient, e.g. "EURC:50000000000"
+        if let Some(spec) = var("PAYOUT_DAILY_CAPS") {
+            config.assets.apply_daily_caps(&spec)?;
+        }
+        if let Some(spec) = var("PAYOUT_RECIPIENT_DAILY_CAPS") {
+            config.assets.apply_recipient_daily_caps(&spec)?;
+        }
+        // Optional amounts above which payouts wait for approval, e.g. "EURC:10000000000"
+        if let Some(spec) = var("PAYOUT_APPROVAL_THRESHOLDS") {
+            config.assets.apply_approval_thresholds(&spec)?;
+        }
+        if let Some(secs) = var("APPROVAL_TTL_SECS") {
+            config.approval_ttl = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        // Optional minimum amounts below which payouts accumulate, e.g. "EURC:1000000"
+        if let Some(spec) = var("PAYOUT_MIN_AMOUNTS") {
+            config.assets.apply_min_payouts(&spec)?;
+        }
+        // Tokens deducting a fee on transfer, e.g. "PAXG"
+        if let Some(spec) = var("PAYOUT_FEE_ON_TRANSFER_ASSETS") {
+            config.assets.apply_fee_on_transfer(&spec)?;
+        }
+        if let Some(bps) = var("PAYOUT_AMOUNT_MISMATCH_TOLERANCE_BPS") {
+            config.amount_mismatch_tolerance_bps = bps.parse().ok()?;
+        }
+        config.alert_amount_mismatch = flag("PAYOUT_ALERT_AMOUNT_MISMATCH");
+        // Flush accumulated dust every interval, e.g. 86400 with an offset of 7200 for 02:00 UTC
+        if let Some(secs) = var("DUST_FLUSH_INTERVAL_SECS") {
+            let offset = match var("DUST_FLUSH_OFFSET_SECS") {
+                Some(offset) => offset.parse().ok()?,
+                None => 0,
+            };
+            config.dust_flush = Some(FlushSchedule {
+                interval: Duration::from_secs(secs.parse().ok()?),
+                offset: Duration::from_secs(offset),
+            });
+        }
+
+        // Addresses refused on top of the built-in burn addresses
+        if let Some(spec) = var("PAYOUT_DENIED_RECIPIENTS") {
+            config.denied_recipients.extend_from_spec(&spec)?;
+        }
+
+        if let Some(lag) = var("SEQUENCE_MAX_LAG") {
+            config.sequence_max_lag = Some(lag.parse().ok()?);
+        }
+
+        if let Some(len) = var("PAYOUT_MAX_MEMO_BYTES") {
+            config.max_memo_len = len.parse().ok()?;
+        }
+        config.reject_zero_amounts = flag("PAYOUT_REJECT_ZERO_AMOUNTS");
+        config.record_skips = flag("PAYOUT_RECORD_SKIPS");
+        config.dev_mode = flag("PAYOUT_DEV_MODE");
+        config.node_signing = flag("PAYOUT_NODE_SIGNING");
+        config.accept_token_addresses = flag("PAYOUT_ACCEPT_TOKEN_ADDRESSES");
+
+        config.store_path = var("PAYOUT_STORE_PATH").map(PathBuf::from);
+        // "backup" (default) or "refuse"
+        if let Some(migration) = var("PAYOUT_STORE_MIGRATION") {
+            config.store_migration = StoreMigration::parse(&migration)?;
+        }
+
+        // The bundled Treasury uses an operator mapping rather than AccessControl,
+        // so the role check is opt-in
+        if flag("OPERATOR_ROLE_CHECK") {
+            let mut role_check = RoleCheckConfig::default();
+            if let Some(role) = var("OPERATOR_ROLE") {
+                role_check.role = RoleCheckConfig::parse_role(&role)?;
+            }
+            if let Some(secs) = var("OPERATOR_ROLE_CHECK_INTERVAL_SECS") {
+                role_check.interval = Duration::from_secs(secs.parse().ok()?);
+            }
+            config.role_check = Some(role_check);
+        }
+
+        if let Some(secs) = var("PAUSE_CHECK_INTERVAL_SECS") {
+            config.pause_check_interval = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        if let Some(secs) = var("SIGNER_PROBE_INTERVAL_SECS") {
+            config.signer_probe_interval = Duration::from_secs(secs.parse().ok()?);
+        }
+        if let Some(path) = var("PAYOUT_KILL_SWITCH_PATH") {
+            let mut kill_switch = KillSwitchConfig::new(path);
+            if let Some(secs) = var("PAYOUT_KILL_SWITCH_INTERVAL_SECS") {
+                kill_switch.interval = Duration::from_secs(secs.parse().ok()?);
+            }
+            // "defer" (default) or "reject"
+            if let Some(mode) = var("PAYOUT_KILL_SWITCH_MODE") {
+                kill_switch.mode = KillSwitchMode::parse(&mode)?;
+            }
+            config.kill_switch = Some(kill_switch);
+        }
+
+        // Extra custom errors on top of the built-in ones, e.g. "AlreadyPaid(bytes32)"
+        if let Some(spec) = var("REVERT_ERRORS") {
+            config.revert_errors.extend_from_spec(&spec)?;
+        }
+
+        config.relayer_url = var("PAYOUT_RELAYER_URL");
+        if let Some(secs) = var("AUTHORIZATION_VALIDITY_SECS") {
+            config.authorization_validity = Duration::from_secs(secs.parse().ok()?);
+        }
+
+        // "inline" (default), "spawned" or "queued"
+        if let Some(mode) = var("PAYOUT_EXECUTION_MODE") {
+            config.execution_mode = ExecutionMode::parse(&mode)?;
+        }
+        // "base64url" (default) or "hex"
+        let mut token = config.address_formats.token_format();
+        if let Some(alphabet) = var("PAYOUT_STREAM_TOKEN_ALPHABET") {
+            token.alphabet = TokenAlphabet::parse(&alphabet)?;
+        }
+        if let Some(len) = var("PAYOUT_STREAM_TOKEN_MIN_LEN") {
+            token.min_len = len.parse().ok()?;
+        }
+        if let Some(len) = var("PAYOUT_STREAM_TOKEN_MAX_LEN") {
+            token.max_len = len.parse().ok()?;
+        }
+        config.address_formats = config.address_formats.with_token(token);
+        // Optional chain segment aliases, e.g. "base:8453,arbitrum:42161"
+        if let Some(spec) = var("PAYOUT_CHAIN_ALIASES") {
+            for (alias, chain_id) in AddressFormats::parse_chain_aliases(&spec)? {
+                config.address_formats = config.address_formats.with_chain_alias(&alias, chain_id);
+            }
+        }
+        // "truncated" (default), "hashed" or "full"
+        if let Some(policy) = var("PAYOUT_DESTINATION_POLICY") {
+            config.destination_policy = DestinationPolicy::parse(&policy)?;
+        }
+        if let Some(key) = var("PAYOUT_LOG_MASK_KEY") {
+            let mask = LogMask::new(key);
+            config.log_mask = Some(if flag("PAYOUT_LOG_MASK_DESTINATIONS") {
+                mask.with_destinations()
+            } else {
+                mask
+            });
+        }
+        if let Some(top_n) = var("PAYOUT_ATTRIBUTION_TOP_N") {
+            config.attribution_top_n = top_n.parse().ok()?;
+        }
+        if flag("PAYOUT_SELF_TEST") {
+            let mut self_test = SelfTestOptions::default();
+            if let Some(secs) = var("PAYOUT_SELF_TEST_TIMEOUT_SECS") {
+                self_test.timeout = Duration::from_secs(secs.parse().ok()?);
+            }
+            // Only paid on dev chains
+            if let Some(destination) = var("PAYOUT_SELF_TEST_CANARY") {
+                let amount = match var("PAYOUT_SELF_TEST_CANARY_AMOUNT") {
+                    Some(amount) => amount.parse().ok()?,
+                    None => 1,
+                };
+                self_test.canary = Some(CanaryPayout {
+                    destination,
+                    amount,
+                });
+            }
+            config.self_test = Some(self_test);
+        }
+        // Optional reasons to reject before fulfilling, e.g. "blocked_recipient,paused"
+        if let Some(spec) = var("PAYOUT_REJECT_BEFORE_FULFIL") {
+            config.reject_before_fulfil = RejectKind::parse_list(&spec)?;
+        }
+        if let Some(ordering) = var("PAYOUT_RECIPIENT_ORDERING") {
+            config.recipient_ordering = RecipientOrdering::parse(&ordering)?;
+        }
+        if let Some(capacity) = var("PAYOUT_EVENT_CHANNEL_CAPACITY") {
+            config.event_channel_capacity = capacity.parse().ok()?;
+        }
+        if let Some(capacity) = var("PAYOUT_RECIPIENT_STATE_CAPACITY") {
+            config.recipient_state_capacity = capacity.parse().ok()?;
+        }
+        if let Some(secs) = var("PAYOUT_DEADLINE_SECS") {
+            config.payout_deadline = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        if let Some(secs) = var("PAYOUT_RETRY_INTERVAL_SECS") {
+            config.retry_interval = Duration::from_secs(secs.parse().ok()?);
+        }
+        if let Some(percent) = var("PAYOUT_RETRY_JITTER_PERCENT") {
+            config.retry_jitter_percent = Some(percent.parse().ok()?).filter(|p| *p <= 100)?;
+        }
+        if flag("PAYOUT_RETRY_QUEUE") {
+            let mut policy = RetryPolicy::default();
+            if let Some(secs) = var("PAYOUT_RETRY_INITIAL_DELAY_SECS") {
+                policy.initial_delay = Duration::from_secs(secs.parse().ok()?);
+            }
+            if let Some(secs) = var("PAYOUT_RETRY_MAX_DELAY_SECS") {
+                policy.max_delay = Duration::from_secs(secs.parse().ok()?);
+            }
+            if let Some(attempts) = var("PAYOUT_RETRY_MAX_ATTEMPTS") {
+                policy.max_attempts = attempts.parse().ok()?;
+            }
+            if let Some(capacity) = var("PAYOUT_RETRY_QUEUE_CAPACITY") {
+                policy.capacity = capacity.parse().ok()?;
+            }
+            config.retry_queue = Some(policy);
+        }
+        if let Some(threshold) = var("PAYOUT_FAILURE_STREAK_THRESHOLD") {
+            let mut policy = FailureStreakPolicy {
+                threshold: threshold.parse().ok()?,
+                hold: flag("PAYOUT_HOLD_FAILING_RECIPIENTS"),
+                ..FailureStreakPolicy::default()
+            };
+            if let Some(secs) = var("PAYOUT_FAILURE_SUMMARY_INTERVAL_SECS") {
+                policy.summary_interval = Duration::from_secs(secs.parse().ok()?);
+            }
+            config.failure_streaks = Some(policy);
+        }
+        if let Some(retries) = var("RPC_THROTTLE_RETRIES") {
+            config.throttle_retries = retries.parse().ok()?;
+        }
+        if let Some(secs) = var("RPC_CONNECTION_MAX_AGE_SECS") {
+            config.connection_policy.max_age = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        // 0 to only replace connections found stale
+        if let Some(failures) = var("RPC_RESET_CONNECTIONS_AFTER_FAILURES") {
+            config.connection_policy.reset_after_failures =
+                Some(failures.parse().ok()?).filter(|failures| *failures > 0);
+        }
+        if let Some(secs) = var("RPC_READ_TIMEOUT_SECS") {
+            config.read_timeout = Duration::from_secs(secs.parse().ok()?);
+        }
+        if let Some(secs) = var("RPC_SUBMIT_TIMEOUT_SECS") {
+            config.submit_timeout = Duration::from_secs(secs.parse().ok()?);
+        }
+        if let Some(secs) = var("NONCE_LEASE_TTL_SECS") {
+            config.nonce_lease_ttl = Duration::from_secs(secs.parse().ok()?);
+        }
+        #[cfg(feature = "redis-nonce-store")]
+        {
+            config.nonce_store_url = var("NONCE_STORE_URL");
+        }
+        if let Some(secs) = var("RECEIPT_POLL_INTERVAL_SECS") {
+            config.receipt_poll_interval = Duration::from_secs(secs.parse().ok()?);
+        }
+
+        if let Some(secs) = var("GAS_SAMPLE_INTERVAL_SECS") {
+            config.gas_sample_interval = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        if let Some(secs) = var("GAS_QUOTE_TTL_SECS") {
+            config.gas_quote_ttl = Duration::from_secs(secs.parse().ok()?);
+        }
+        if let Some(secs) = var("GAS_ESTIMATE_TTL_SECS") {
+            config.gas_estimate_ttl = Some(Duration::from_secs(secs.parse().ok()?));
+        }
+        if let Some(gas) = var("GAS_FALLBACK_EOA") {
+            config.gas_fallbacks.default.eoa = gas.parse().ok()?;
+        }
+        if let Some(gas) = var("GAS_FALLBACK_CONTRACT") {
+            config.gas_fallbacks.default.contract = gas.parse().ok()?;
+        }
+        // e.g. "USDT:120000:300000", limits for EOA and contract recipients
+        if let Some(overrides) = var("GAS_FALLBACK_ASSETS") {
+            config.gas_fallbacks.assets = GasFallbacks::parse_overrides(&overrides)?;
+        }
+        if let Some(secs) = var("RECIPIENT_CODE_TTL_SECS") {
+            config.gas_fallbacks.code_ttl = Duration::from_secs(secs.parse().ok()?);
+        }
+        // "op-stack" for Optimism, Base and other OP-stack chains, "arbitrum" for Arbitrum
+        if let Some(model) = var("PAYOUT_L2_FEE_MODEL") {
+            config.l2_fee_model = L2FeeModel::parse(&model)?;
+        }
+        if let Some(wei) = var("PAYOUT_MAX_GAS_COST_WEI") {
+            config.max_gas_cost = Some(wei.parse().ok()?);
+        }
+        // "number" pins to the head's number, "hash" to its hash (EIP-1898)
+        if let Some(pinning) = var("PAYOUT_BLOCK_PINNING") {
+            config.block_pinning = BlockPinning::parse(&pinning)?;
+        }
+        if let Some(returns_id) = var("TREASURY_RETURNS_PAYOUT_ID") {
+            config.payout_returns_id = returns_id.parse().ok()?;
+        }
+        // e.g. "VERSION()", or empty to not read a version
+        if let Some(view) = var("TREASURY_VERSION_VIEW") {
+            config.treasury_version_view = Some(view).filter(|view| !view.is_empty());
+        }
+        // e.g. "payout(address,uint256,bytes32,uint16)" with
+        // "recipient,amount,payment_id,uint:7", see `PayoutArgument::parse`
+        if let Some(signature) = var("TREASURY_PAYOUT_SIGNATURE") {
+            let arguments = var("TREASURY_PAYOUT_ARGUMENTS")?;
+            config.payout_call = Some(PayoutCall::parse(&signature, &arguments).ok()?);
+        }
+        if let Some(allowances) = var("TREASURY_ALLOWANCES") {
+            let mut policy = AllowancePolicy::parse(&allowances)?;
+            if let Some(secs) = var("TREASURY_ALLOWANCE_CHECK_INTERVAL_SECS") {
+                policy.interval = Duration::from_secs(secs.parse().ok()?);
+            }
+            config.treasury_allowances = Some(policy);
+        }
+
+        if let Some(millis) = var("SLOW_PAYOUT_THRESHOLD_MS") {
+            config.slow_payout_threshold = Duration::from_millis(millis.parse().ok()?);
+        }
+
+        if let Some(wei) = var("OPERATOR_BALANCE_FLOOR_WEI") {
+            config.balance_floor = Some(wei.parse().ok()?);
+        }
+
+        // Fixed rates between base units, e.g. "XRP:EURC:512:6" for 0.000512
+        if let Some(spec) = var("PAYOUT_STATIC_RATES") {
+            config.static_rates = Some(StaticRateProvider::parse(&spec)?);
+        }
+        if let Some(secs) = var("RATE_MAX_AGE_SECS") {
+            config.rate_max_age = Duration::from_secs(secs.parse().ok()?);
+        }
+        // "down", "up" or "half_even"
+        if let Some(mode) = var("PAYOUT_ROUNDING") {
+            config.rounding = RoundingMode::parse(&mode)?;
+        }
+        config.carry_rounding_residue = flag("PAYOUT_CARRY_ROUNDING_RESIDUE");
+
+        #[cfg(feature = "erc4337")]
+        if let Some(account) = var("SMART_ACCOUNT_ADDRESS") {
+            let mut user_op = super::UserOpConfig::new(account, var("BUNDLER_URL")?);
+            if let Some(entry_point) = var("ENTRY_POINT_ADDRESS") {
+                user_op.entry_point = entry_point;
+            }
+            config.user_op = Some(user_op);
+        }
+
+        if let Some(address) = var("SAFE_ADDRESS") {
+            config.safe = Some(SafeConfig {
+                address,
+                service_url: var("SAFE_TX_SERVICE_URL")?,
+                threshold: var("SAFE_PROPOSAL_THRESHOLD")?.parse().ok()?,
+            });
+        }
+
+        Some(config)
+    }
+}
+
+/// Whether a flag's value is truthy ("1", "true", "yes")
+fn is_truthy(value: &str) -> bool {
+    matches!(value.to_ascii_lowercase().as_str(), "1" | "true" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_role_names_and_hashes() {
+        let hashed = RoleCheckConfig::parse_role("OPERATOR_ROLE").unwrap();
+        assert_eq!(
+            hex::encode(hashed),
+            "97667070c54ef182b0f5858b034beac1b6f3089aa2d3188bb1e8929f4fa9b929"
+        );
+        let explicit = RoleCheckConfig::parse_role(
+            "0x97667070c54ef182b0f5858b034beac1b6f3089aa2d3188bb1e8929f4fa9b929",
+        )
+        .unwrap();
+        assert_eq!(hashed, explicit);
+        assert!(RoleCheckConfig::parse_role("0x1234").is_none());
+    }
+}
@@ -0,0 +1,341 @@
+//! Recycling of long-lived connections to the node
+//!
+//! Pooled HTTP connections outlive the address their host resolved to, so a
+//! provider moving its hostname to new IPs leaves the pool writing to dead
+//! sockets. Each endpoint's transport is reset, dropping its connections so
+//! the next request resolves the host again and connects afresh:
+//!
+//! - once its connections are older than `max_age`
+//! - right away when a request fails on a stale pooled connection
+//! - after `reset_after_failures` transport failures in a row
+//!
+//! Stale connection failures are the client's, not the endpoint's, so they do
+//! not count against the endpoint in an [`EndpointPool`](super::EndpointPool).
+//! An HTTP client injected with `with_http_client` is shared with the host and
+//! is kept as it is; inject a builder with `with_http_client_builder` to let
+//! the service replace it.
+
+use super::rpc::HttpTransport;
+use super::throttle::endpoint_label;
+use super::{
+    is_ipc_endpoint, metrics, Clock, EthereumPayoutConfig, IpcTransport, PayoutError, RpcTransport,
+    SystemClock, Timestamp,
+};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Builds the HTTP client of a transport, again each time it is reset
+pub type HttpClientFactory = Arc<dyn Fn() -> reqwest::Client + Send + Sync>;
+
+/// When an endpoint's connections are dropped for new ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionPolicy {
+    /// Age after which connections are replaced, `None` to keep them while they work
+    pub max_age: Option<Duration>,
+    /// Transport failures in a row after which connections are replaced,
+    /// `None` to only replace stale ones
+    pub reset_after_failures: Option<u32>,
+}
+
+impl Default for ConnectionPolicy {
+    fn default() -> Self {
+        ConnectionPolicy {
+            max_age: None,
+            reset_after_failures: Some(3),
+        }
+    }
+}
+
+/// Whether `err` came from writing to or reading from a connection the other
+/// end had already closed
+pub fn is_stale_connection(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            if matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        // hyper's error for a pooled connection closed while in use
+        if err
+            .to_string()
+            .contains("connection closed before message completed")
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[derive(Debug)]
+struct ConnectionState {
+    opened: Timestamp,
+    failures: u32,
+}
+
+/// Transport resetting the connections of another when they get old or keep failing
+pub struct RecyclingTransport {
+    inner: Arc<dyn RpcTransport>,
+    policy: ConnectionPolicy,
+    endpoint: String,
+    clock: Arc<dyn Clock>,
+    tenant: Option<String>,
+    state: Mutex<ConnectionState>,
+}
+
+impl RecyclingTransport {
+    pub fn new(inner: Arc<dyn RpcTransport>, endpoint: &str, policy: ConnectionPolicy) -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        RecyclingTransport {
+            inner,
+            policy,
+            endpoint: endpoint_label(endpoint),
+            state: Mutex::new(ConnectionState {
+                opened: clock.now(),
+                failures: 0,
+            }),
+            clock,
+            tenant: None,
+        }
+    }
+
+    /// Age connections on the given clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.state.get_mut().unwrap().opened = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Label the transport's metrics with `tenant`
+    pub fn with_tenant(mut self, tenant: Option<String>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// Transport failures in a row since the last success or reset
+    pub fn consecutive_failures(&self) -> u32 {
+        self.state.lock().unwrap().failures
+    }
+
+    fn recycle(&self, state: &mut ConnectionState, reason: &'static str) {
+        info!(
+            "Resetting connections to {} ({})",
+            self.endpoint,
+            reason.replace('_', " ")
+        );
+        self.inner.reset();
+        state.opened = self.clock.now();
+        state.failures = 0;
+        metrics::connection_reset(self.tenant.as_deref(), &self.endpoint, reason);
+    }
+}
+
+#[async_trait]
+impl RpcTransport for RecyclingTransport {
+    async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+        if let Some(max_age) = self.policy.max_age {
+            let mut state = self.state.lock().unwrap();
+            let age = (self.clock.now() - state.opened)
+                .to_std()
+                .unwrap_or_default();
+            if age >= max_age {
+                self.recycle(&mut state, "max_age");
+            }
+        }
+
+        let result = self.inner.send(request).await;
+        let mut state = self.state.lock().unwrap();
+        match &result {
+            Err(PayoutError::StaleConnection { .. }) => self.recycle(&mut state, "stale"),
+            Err(PayoutError::Transport(_)) | Err(PayoutError::TimedOut { .. }) => {
+                state.failures += 1;
+                if self
+                    .policy
+                    .reset_after_failures
+                    .is_some_and(|limit| state.failures >= limit)
+                {
+                    self.recycle(&mut state, "repeated_failures");
+                }
+            }
+            _ => state.failures = 0,
+        }
+        result
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.recycle(&mut state, "requested");
+    }
+}
+
+/// Transport for `endpoint`, over IPC for socket paths and HTTP otherwise,
+/// recycling its connections by the config's policy. HTTP clients are
+/// rebuilt with `build` if it is given.
+pub(super) fn endpoint_transport(
+    http: &reqwest::Client,
+    build: Option<&HttpClientFactory>,
+    endpoint: &str,
+    config: &EthereumPayoutConfig,
+) -> Arc<dyn RpcTransport> {
+    let inner: Arc<dyn RpcTransport> = if is_ipc_endpoint(endpoint) {
+        Arc::new(IpcTransport::new(endpoint))
+    } else {
+        match build {
+            Some(build) => Arc::new(HttpTransport::rebuilding(build.clone(), endpoint)),
+            None => Arc::new(HttpTransport::new(http.clone(), endpoint)),
+        }
+    };
+    Arc::new(
+        RecyclingTransport::new(inner, endpoint, config.connection_policy)
+            .with_tenant(config.tenant.clone()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::FakeClock;
+    use super::super::EndpointPool;
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Node behind a pool holding connections to an address it moved away from,
+    /// failing until the pool is reset
+    struct MovedNode {
+        stale: AtomicBool,
+        error: fn() -> PayoutError,
+        resets: AtomicUsize,
+    }
+
+    impl MovedNode {
+        fn new(error: fn() -> PayoutError) -> Arc<Self> {
+            Arc::new(MovedNode {
+                stale: AtomicBool::new(true),
+                error,
+                resets: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl RpcTransport for MovedNode {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            if self.stale.load(Ordering::SeqCst) {
+                return Err((self.error)());
+            }
+            Ok(json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"}))
+        }
+
+        fn reset(&self) {
+            self.stale.store(false, Ordering::SeqCst);
+            self.resets.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn stale() -> PayoutError {
+        PayoutError::StaleConnection {
+            endpoint: "node".to_string(),
+            message: "connection closed before message completed".to_string(),
+        }
+    }
+
+    fn unreachable() -> PayoutError {
+        PayoutError::Transport("error sending request".to_string())
+    }
+
+    fn request() -> Value {
+        json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": [], "id": 1})
+    }
+
+    #[test]
+    fn recognizes_closed_connections() {
+        let reset = io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer");
+        assert!(is_stale_connection(&reset));
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!is_stale_connection(&refused));
+        assert!(stale().is_transient());
+    }
+
+    #[tokio::test]
+    async fn recovers_from_a_stale_pool_without_marking_the_endpoint_down() {
+        let node = MovedNode::new(stale);
+        let recycling = Arc::new(RecyclingTransport::new(
+            node.clone(),
+            "http://node:8545",
+            ConnectionPolicy::default(),
+        ));
+        let pool = EndpointPool::new(vec![(
+            "node".to_string(),
+            recycling as Arc<dyn RpcTransport>,
+        )]);
+
+        assert!(matches!(
+            pool.send(request()).await,
+            Err(PayoutError::StaleConnection { .. })
+        ));
+        assert_eq!(node.resets.load(Ordering::SeqCst), 1);
+        for _ in 0..20 {
+            assert!(pool.send(request()).await.is_ok());
+        }
+        let stats = &pool.stats()[0];
+        assert_eq!(stats.requests, 20);
+        assert_eq!(stats.successes, 20);
+    }
+
+    #[tokio::test]
+    async fn resets_after_repeated_transport_failures() {
+        let node = MovedNode::new(unreachable);
+        let recycling = RecyclingTransport::new(
+            node.clone(),
+            "http://node:8545",
+            ConnectionPolicy {
+                max_age: None,
+                reset_after_failures: Some(3),
+            },
+        );
+        for failures in 1..=2 {
+            assert!(recycling.send(request()).await.is_err());
+            assert_eq!(recycling.consecutive_failures(), failures);
+        }
+        assert_eq!(node.resets.load(Ordering::SeqCst), 0);
+        assert!(recycling.send(request()).await.is_err());
+        assert_eq!(node.resets.load(Ordering::SeqCst), 1);
+        assert_eq!(recycling.consecutive_failures(), 0);
+        assert!(recycling.send(request()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replaces_connections_past_their_max_age() {
+        let node = MovedNode::new(stale);
+        node.stale.store(false, Ordering::SeqCst);
+        let clock = FakeClock::new();
+        let recycling = RecyclingTransport::new(
+            node.clone(),
+            "http://node:8545",
+            ConnectionPolicy {
+                max_age: Some(Duration::from_secs(300)),
+                reset_after_failures: None,
+            },
+        )
+        .with_clock(clock.clone());
+
+        recycling.send(request()).await.unwrap();
+        clock.advance(Duration::from_secs(299));
+        recycling.send(request()).await.unwrap();
+        assert_eq!(node.resets.load(Ordering::SeqCst), 0);
+        clock.advance(Duration::from_secs(1));
+        recycling.send(request()).await.unwrap();
+        assert_eq!(node.resets.load(Ordering::SeqCst), 1);
+    }
+}
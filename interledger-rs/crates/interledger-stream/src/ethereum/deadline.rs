@@ -0,0 +1,277 @@
+//! Deadlines after which unfinished payouts are abandoned
+//!
+//! A payout that cannot be sent, or is not mined, by its deadline is marked
+//! `Abandoned` with the last error and left for an operator to look at and
+//! resubmit through [`EthereumPayoutService::retry_failed`].
+
+use super::payload::plan_call;
+use super::{
+    metrics, EthereumPayoutService, PayoutError, PayoutOutcome, PayoutRecord, PayoutRequest,
+    PayoutStatus, Timestamp,
+};
+use tracing::{error, info, warn};
+
+impl EthereumPayoutService {
+    /// Deadline of a payout starting now, from the request or the config
+    pub(super) fn deadline_for(&self, request: &PayoutRequest) -> Option<Timestamp> {
+        let deadline = request.deadline.or(self.config.payout_deadline)?;
+        Some(self.clock.now() + chrono::Duration::from_std(deadline).ok()?)
+    }
+
+    /// Mark the payout abandoned and alert, returning the error to report
+    pub(super) fn abandon(&self, mut record: PayoutRecord, last_error: String) -> PayoutError {
+        error!(
+            "ALERT: payout {} of {} {} to {} abandoned at its deadline, needs manual handling: {}",
+            record.payment_id_hex(),
+            record.amount,
+            record.asset_code,
+            self.display_address(&record.recipient),
+            self.scrub_address(&last_error, &record.recipient)
+        );
+        metrics::abandoned(self.config.tenant.as_deref(), &record.asset_code);
+        let payment_id = record.payment_id_hex();
+        let id = record.payment_id;
+        record.status = PayoutStatus::Abandoned;
+        record.last_error = Some(last_error.clone());
+        self.store.save(record);
+        self.settle_split(&id);
+        PayoutError::Abandoned {
+            payment_id,
+            last_error,
+        }
+    }
+
+    /// Poll for the payout's receipt until it is mined or its deadline passes.
+    ///
+    /// Without a deadline on the record this waits until the receipt appears.
+    pub async fn wait_for_receipt(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<PayoutRecord, PayoutError> {
+        let mut last_error = "no receipt before the deadline".to_string();
+        loop {
+            match self.refresh_receipt(payment_id).await {
+                Ok(Some(record)) => return Ok(record),
+                Ok(None) => {}
+                Err(err) if err.is_transient() => {
+                    warn!("Receipt lookup failed, retrying: {}", err);
+                    last_error = err.to_string();
+                }
+                Err(err) => return Err(err),
+            }
+            let record = self.store.get(payment_id).ok_or_else(|| {
+                PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+            })?;
+            if record.tx_hash.is_none() || record.status != PayoutStatus::Submitted {
+                return Err(PayoutError::Config(format!(
+                    "Payout {} has no transaction to wait for",
+                    record.payment_id_hex()
+                )));
+            }
+            if let Some(deadline) = record.deadline {
+                if self.clock.now() >= deadline {
+                    return Err(self.abandon(record, last_error));
+                }
+            }
+            // The record stays submitted, so waiting can resume after a restart
+            if !self
+                .sleep_unless_cancelled(self.config.receipt_poll_interval)
+                .await
+            {
+                return Err(PayoutError::Cancelled);
+            }
+        }
+    }
+
+    /// Submit a failed, abandoned or retry-scheduled payout again, with a fresh deadline
+    pub async fn retry_failed(&self, payment_id: &[u8; 32]) -> Result<PayoutOutcome, PayoutError> {
+        let record = self.lookup(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        if !matches!(
+            record.status,
+            PayoutStatus::Failed | PayoutStatus::Abandoned | PayoutStatus::RetryScheduled
+        ) {
+            return Err(PayoutError::Config(format!(
+                "Payout {} is {:?}, not failed or abandoned",
+                record.payment_id_hex(),
+                record.status
+            )));
+        }
+        if self.is_degraded() {
+            return Err(PayoutError::NotAuthorized {
+                operator: self.operator_address(),
+            });
+        }
+
+        let eth_dest = self
+            .config
+            .parse_destination(&record.destination)
+            .ok_or_else(|| PayoutError::InvalidDestination(record.destination.clone()))?;
+        let mut plan = plan_call(
+            &self.config,
+            eth_dest,
+            record.payment_id,
+            record.amount,
+            &record.destination,
+        )?;
+        if plan.mode.relies_on_store() && !record.is_retryable() {
+            // Nothing on-chain would stop the earlier transaction from also landing
+            return Err(PayoutError::Config(format!(
+                "Payout {} was broadcast as {} and may still be mined; cancel it before retrying",
+                record.payment_id_hex(),
+                record.tx_hash.as_deref().unwrap_or_default()
+            )));
+        }
+        plan.conversion = record.conversion.clone();
+        if let Some(memo) = &record.memo {
+            plan.attach_memo(memo)?;
+        }
+
+        info!(
+            "Retrying {:?} payout {}",
+            record.status,
+            record.payment_id_hex()
+        );
+        let request = PayoutRequest::for_record(&record);
+        let deadline = self.deadline_for(&request);
+        self.execute_plan(&request, &plan, deadline).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::Clock;
+    use super::*;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn deadline_service(transport: Arc<MockTransport>) -> (EthereumPayoutService, Arc<FakeClock>) {
+        let mut config = test_config();
+        config.payout_deadline = Some(Duration::from_secs(600));
+        config.retry_interval = Duration::from_secs(60);
+        config.receipt_poll_interval = Duration::from_secs(30);
+        let clock = FakeClock::new();
+        let service = test_service(config, transport).with_clock(clock.clone());
+        (service, clock)
+    }
+
+    fn payment_id() -> [u8; 32] {
+        EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1)
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_until_they_clear() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_gasPrice", json!("0x1"));
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        transport.on("eth_getTransactionCount", move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 3 {
+                Err(json!({"code": -32005, "message": "limit exceeded"}))
+            } else {
+                Ok(json!("0x0"))
+            }
+        });
+        let (service, clock) = deadline_service(transport.clone());
+        let started = clock.now();
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        assert_eq!(clock.now() - started, chrono::Duration::seconds(180));
+        let record = service.store().get(&payment_id()).unwrap();
+        assert_eq!(
+            record.deadline,
+            Some(started + chrono::Duration::seconds(600))
+        );
+    }
+
+    #[tokio::test]
+    async fn deadline_expiry_mid_retry_abandons_and_retry_resurrects() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_gasPrice", json!("0x1"));
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        transport.on_error("eth_getTransactionCount", -32603, "internal error");
+        let (service, clock) = deadline_service(transport.clone());
+        let started = clock.now();
+
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::Abandoned { .. }));
+        // Attempts at 0, 60, ..., 540s; the next one would be past the deadline
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 10);
+        assert!(clock.now() - started < chrono::Duration::seconds(600));
+        let record = service.store().get(&payment_id()).unwrap();
+        assert_eq!(record.status, PayoutStatus::Abandoned);
+        assert!(record.last_error.unwrap().contains("internal error"));
+
+        // Once the node recovers, the abandoned payout can be resubmitted
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        let outcome = service.retry_failed(&payment_id()).await.unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        assert_eq!(
+            service.store().get(&payment_id()).unwrap().status,
+            PayoutStatus::Submitted
+        );
+        assert!(service.retry_failed(&payment_id()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn deadline_expiry_mid_receipt_wait_abandons() {
+        let transport = mock_chain();
+        transport.on_result("eth_getTransactionReceipt", Value::Null);
+        let (service, clock) = deadline_service(transport.clone());
+        let started = clock.now();
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        clock.advance(Duration::from_secs(400));
+        let err = service.wait_for_receipt(&payment_id()).await.unwrap_err();
+        assert!(matches!(err, PayoutError::Abandoned { .. }));
+        assert!(clock.now() - started >= chrono::Duration::seconds(600));
+        // Lookups at 400, 430, ..., 610s
+        assert_eq!(transport.call_count("eth_getTransactionReceipt"), 8);
+        let record = service.store().get(&payment_id()).unwrap();
+        assert_eq!(record.status, PayoutStatus::Abandoned);
+        assert_eq!(record.tx_hash.as_deref(), Some("0xabc"));
+
+        // The Treasury rejects a replayed payment ID, so resubmitting is safe
+        service.retry_failed(&payment_id()).await.unwrap();
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+    }
+
+    #[tokio::test]
+    async fn receipt_before_deadline_confirms() {
+        let transport = mock_chain();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        transport.on("eth_getTransactionReceipt", move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                Ok(Value::Null)
+            } else {
+                Ok(json!({"blockNumber": "0x1", "gasUsed": "0x5208", "status": "0x1"}))
+            }
+        });
+        let (service, _) = deadline_service(transport);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let record = service.wait_for_receipt(&payment_id()).await.unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+    }
+}
@@ -0,0 +1,242 @@
+//! Detection of tokens delivering another amount than was sent
+//!
+//! Fee-on-transfer tokens deduct a fee from each transfer, so the recipient
+//! receives less than the Treasury sent and reconciliation never balances.
+//! Once a token payout is mined, the ERC-20 `Transfer` logs of its receipt
+//! crediting the recipient are added up and compared with the amount paid. A
+//! difference beyond `amount_mismatch_tolerance_bps` is recorded on the payout
+//! as an [`AmountMismatch`] and counted. It is only logged for assets marked
+//! `fee_on_transfer`, where it is expected, and alerted for others if
+//! `alert_amount_mismatch` is set.
+
+use super::abi::{decode_words, encode_address};
+use super::hash::keccak256;
+use super::{metrics, EthereumPayoutService, PayoutMode, PayoutRecord};
+use serde_json::{json, Value};
+use tracing::{error, info, warn};
+
+/// Event every ERC-20 transfer emits
+pub const TRANSFER_EVENT_SIGNATURE: &str = "Transfer(address,address,uint256)";
+
+/// Amounts sent and received by a payout whose recipient was credited another amount
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AmountMismatch {
+    pub sent: u64,
+    pub received: u128,
+}
+
+impl AmountMismatch {
+    pub(super) fn to_json(self) -> Value {
+        // Decimal strings, as `received` may not fit a JSON number
+        json!({"sent": self.sent, "received": self.received.to_string()})
+    }
+
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        Some(AmountMismatch {
+            sent: value["sent"].as_u64()?,
+            received: value["received"].as_str()?.parse().ok()?,
+        })
+    }
+}
+
+/// Total credited to `recipient` by the `Transfer` events among a receipt's
+/// `logs`, counting only those emitted by `token` if it is given.
+///
+/// `None` if the receipt carries no logs to look at.
+pub fn received_amount(logs: &Value, token: Option<&str>, recipient: &str) -> Option<u128> {
+    let logs = logs.as_array()?;
+    let topic = format!(
+        "0x{}",
+        hex::encode(keccak256(TRANSFER_EVENT_SIGNATURE.as_bytes()))
+    );
+    let to = encode_address(recipient)?;
+    let received = logs
+        .iter()
+        .filter(|log| {
+            token.is_none_or(|token| {
+                log["address"]
+                    .as_str()
+                    .is_some_and(|address| address.eq_ignore_ascii_case(token))
+            })
+        })
+        .filter(|log| {
+            let topics = &log["topics"];
+            topics[0]
+                .as_str()
+                .is_some_and(|first| first.eq_ignore_ascii_case(&topic))
+                && topics[2]
+                    .as_str()
+                    .and_then(decode_words)
+                    .is_some_and(|words| words == [to])
+        })
+        .filter_map(|log| {
+            let word = *decode_words(log["data"].as_str()?)?.first()?;
+            // Amounts beyond u128 are no payout's and are counted as the most possible
+            if word[..16].iter().any(|byte| *byte != 0) {
+                return Some(u128::MAX);
+            }
+            let mut low = [0u8; 16];
+            low.copy_from_slice(&word[16..]);
+            Some(u128::from_be_bytes(low))
+        })
+        .fold(0u128, u128::saturating_add);
+    Some(received)
+}
+
+impl EthereumPayoutService {
+    /// Flag the mined token payout if its recipient was credited another amount than was sent
+    pub(super) fn check_delivery(&self, record: &mut PayoutRecord, logs: &Value) {
+        let asset = match self.config.assets.get(&record.asset_code) {
+            Some(asset) if asset.mode != PayoutMode::Native => asset,
+            _ => return,
+        };
+        let received =
+            match received_amount(logs, asset.token_address.as_deref(), &record.recipient) {
+                Some(received) => received,
+                None => return,
+            };
+        let sent = u128::from(record.amount);
+        let difference = sent.abs_diff(received);
+        if difference * 10_000 <= sent * u128::from(self.config.amount_mismatch_tolerance_bps) {
+            return;
+        }
+
+        record.amount_mismatch = Some(AmountMismatch {
+            sent: record.amount,
+            received,
+        });
+        metrics::amount_mismatch(
+            self.config.tenant.as_deref(),
+            &record.asset_code,
+            asset.fee_on_transfer,
+        );
+        let message = format!(
+            "Payout {} sent {} {} to {} but it received {}",
+            record.payment_id_hex(),
+            record.amount,
+            record.asset_code,
+            record.recipient,
+            received
+        );
+        if asset.fee_on_transfer {
+            info!("{} (fee-on-transfer asset)", message);
+        } else if self.config.alert_amount_mismatch {
+            error!("ALERT: {}", message);
+        } else {
+            warn!("{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{AssetInfo, EthereumPayoutConfig, PayoutStatus};
+    use super::*;
+
+    const TOKEN: &str = "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512";
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn transfer_log(address: &str, to: &str, amount: u64) -> Value {
+        json!({
+            "address": address,
+            "topics": [
+                format!("0x{}", hex::encode(keccak256(TRANSFER_EVENT_SIGNATURE.as_bytes()))),
+                format!("0x{:0>64}", "5fbdb2315678afecb367f032d93f642f64180aa3"),
+                format!("0x{:0>64}", to.trim_start_matches("0x")),
+            ],
+            "data": format!("0x{:064x}", amount),
+        })
+    }
+
+    fn token_config(fee_on_transfer: bool) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.insert(AssetInfo {
+            token_address: Some(TOKEN.to_string()),
+            fee_on_transfer,
+            ..AssetInfo::new("EURC", 6)
+        });
+        config.amount_mismatch_tolerance_bps = 10;
+        config
+    }
+
+    /// Record of a 1 EURC payout mined with the given receipt logs
+    async fn mined(config: EthereumPayoutConfig, logs: Value) -> PayoutRecord {
+        let transport = mock_chain();
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x5", "gasUsed": "0x5208", "status": "0x1", "logs": logs}),
+        );
+        let service = test_service(config, transport);
+        service
+            .execute_payout(TEST_DESTINATION, 1_000_000, 1)
+            .await
+            .unwrap();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        service.refresh_receipt(&payment_id).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn sums_transfers_to_the_recipient() {
+        let logs = json!([
+            transfer_log(TOKEN, RECIPIENT, 600),
+            transfer_log(TOKEN, RECIPIENT, 300),
+            // Fee sent elsewhere and a transfer of another token
+            transfer_log(TOKEN, "0x000000000000000000000000000000000000fee5", 100),
+            transfer_log("0x0000000000000000000000000000000000000001", RECIPIENT, 5),
+        ]);
+        assert_eq!(received_amount(&logs, Some(TOKEN), RECIPIENT), Some(900));
+        assert_eq!(received_amount(&logs, None, RECIPIENT), Some(905));
+        assert_eq!(received_amount(&Value::Null, None, RECIPIENT), None);
+    }
+
+    #[tokio::test]
+    async fn flags_short_deliveries_beyond_the_tolerance() {
+        let exact = mined(
+            token_config(false),
+            json!([transfer_log(TOKEN, RECIPIENT, 1_000_000)]),
+        )
+        .await;
+        assert_eq!(exact.status, PayoutStatus::Confirmed);
+        assert_eq!(exact.amount_mismatch, None);
+
+        // 0.05% short, within the 0.1% tolerance
+        let slightly_short = mined(
+            token_config(false),
+            json!([transfer_log(TOKEN, RECIPIENT, 999_500)]),
+        )
+        .await;
+        assert_eq!(slightly_short.amount_mismatch, None);
+
+        let grossly_short = mined(
+            token_config(false),
+            json!([transfer_log(TOKEN, RECIPIENT, 900_000)]),
+        )
+        .await;
+        assert_eq!(grossly_short.status, PayoutStatus::Confirmed);
+        let mismatch = AmountMismatch {
+            sent: 1_000_000,
+            received: 900_000,
+        };
+        assert_eq!(grossly_short.amount_mismatch, Some(mismatch));
+        assert_eq!(
+            AmountMismatch::from_json(&mismatch.to_json()),
+            Some(mismatch)
+        );
+
+        // Still recorded for a fee-on-transfer asset, just not alerted
+        let mut config = token_config(true);
+        config.alert_amount_mismatch = true;
+        let expected = mined(config, json!([transfer_log(TOKEN, RECIPIENT, 990_000)])).await;
+        assert_eq!(
+            expected.amount_mismatch.map(|mismatch| mismatch.received),
+            Some(990_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn receipts_without_logs_are_not_checked() {
+        let record = mined(token_config(false), Value::Null).await;
+        assert_eq!(record.amount_mismatch, None);
+    }
+}
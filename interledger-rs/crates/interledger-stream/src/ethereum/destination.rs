@@ -0,0 +1,335 @@
+//! Parsing of ILP destination addresses into Ethereum payout targets
+
+use super::address::AddressFormats;
+use super::DestinationPolicy;
+use tracing::debug;
+
+/// Parsed destination address for Ethereum payouts
+/// Format: {prefix}.eth.{chainId}.{asset}.{recipient}.{streamToken}
+///
+/// Built by parsing or with [`EthereumDestination::new`], which check the
+/// recipient and token the same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct EthereumDestination {
+    /// Chain the segment after `eth` names
+    pub chain_id: u64,
+    /// That segment as written, the chain ID or an alias of it
+    pub chain_segment: String,
+    /// Registered asset the asset segment names
+    pub asset_code: String,
+    /// That segment as written, the asset code or a token address
+    pub asset_segment: String,
+    pub recipient: String,
+    /// Stream token ending the destination, checked against the configured
+    /// [`StreamTokenFormat`](super::StreamTokenFormat)
+    pub token: String,
+}
+
+impl EthereumDestination {
+    /// A destination from its parts, checked as [`Self::try_parse`] checks them
+    pub fn new(
+        chain_id: u64,
+        asset_code: &str,
+        recipient: &str,
+        token: &str,
+    ) -> Result<Self, String> {
+        Self::new_with(
+            chain_id,
+            asset_code,
+            recipient,
+            token,
+            &AddressFormats::default(),
+        )
+    }
+
+    /// A destination from its parts, checked as [`Self::try_parse_with`] checks them
+    pub fn new_with(
+        chain_id: u64,
+        asset_code: &str,
+        recipient: &str,
+        token: &str,
+        formats: &AddressFormats,
+    ) -> Result<Self, String> {
+        // The chain decides what its addresses look like
+        formats
+            .validate(chain_id, recipient)
+            .map_err(|reason| format!("Invalid recipient address {:?}: {}", recipient, reason))?;
+
+        formats
+            .validate_token(token)
+            .map_err(|reason| format!("Invalid stream token {:?}: {}", token, reason))?;
+
+        Ok(EthereumDestination {
+            chain_id,
+            chain_segment: chain_id.to_string(),
+            asset_code: asset_code.to_string(),
+            asset_segment: asset_code.to_string(),
+            recipient: recipient.to_string(),
+            token: token.to_string(),
+        })
+    }
+
+    /// Parse an ILP destination address to extract Ethereum payout info
+    /// Expected format: test.receiver.eth.31337.EURC.0x1234...abcd.streamToken
+    ///
+    /// Destinations arrive from the network, so this must never panic on any input.
+    pub fn parse(destination: &str) -> Option<Self> {
+        Self::try_parse(destination)
+            .map_err(|reason| {
+                debug!(
+                    "{}: {}",
+                    reason,
+                    DestinationPolicy::default().render(destination)
+                )
+            })
+            .ok()
+    }
+
+    /// Like [`Self::parse`], with the reason a destination was not understood
+    pub fn try_parse(destination: &str) -> Result<Self, String> {
+        Self::try_parse_with(destination, &AddressFormats::default())
+    }
+
+    /// Parse with the recipient validated against its chain's address format
+    /// and the stream token against the token format
+    pub fn parse_with(destination: &str, formats: &AddressFormats) -> Option<Self> {
+        Self::try_parse_with(destination, formats)
+            .map_err(|reason| {
+                debug!(
+                    "{}: {}",
+                    reason,
+                    DestinationPolicy::default().render(destination)
+                )
+            })
+            .ok()
+    }
+
+    /// Like [`Self::parse_with`], with the reason a destination was not understood
+    pub fn try_parse_with(destination: &str, formats: &AddressFormats) -> Result<Self, String> {
+        let parts: Vec<&str> = destination.split('.').collect();
+
+        // Need at least: prefix.connector.eth.chainId.asset.recipient.token
+        if parts.len() < 7 {
+            return Err("Destination too short for Ethereum payout".to_string());
+        }
+
+        // Find "eth" marker
+        let eth_idx = parts
+            .iter()
+            .position(|&p| p == "eth")
+            .ok_or_else(|| "Destination has no eth segment".to_string())?;
+        let (chain_segment, asset_segment, recipient_str) =
+            match parts.get(eth_idx + 1..eth_idx + 4) {
+                Some(&[chain, asset, recipient]) => (chain, asset, recipient),
+                _ => return Err("Invalid Ethereum destination format".to_string()),
+            };
+        let token = match &parts[eth_idx + 4..] {
+            [token] => *token,
+            [] => return Err("Destination has no stream token".to_string()),
+            _ => return Err("Destination has segments after the stream token".to_string()),
+        };
+
+        let chain_id = formats
+            .chain_id(chain_segment)
+            .ok_or_else(|| format!("Invalid chain ID {:?}", chain_segment))?;
+
+        let mut parsed = Self::new_with(chain_id, asset_segment, recipient_str, token, formats)?;
+        parsed.chain_segment = chain_segment.to_string();
+        Ok(parsed)
+    }
+}
+
+/// Whether `s` is `0x` followed by exactly 40 ASCII hex digits
+pub(crate) fn is_hex_address(s: &str) -> bool {
+    match s.strip_prefix("0x") {
+        Some(hex) => hex.len() == 40 && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::strategies::{arbitrary_destination, valid_destination};
+    use super::super::testing::{test_config, TEST_DESTINATION};
+    use super::super::{plan_payout, AddressFormat, PayoutError, StreamTokenFormat, TokenAlphabet};
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse_destination() {
+        let dest = "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.abc123";
+        let parsed = EthereumDestination::parse(dest).unwrap();
+
+        assert_eq!(parsed.chain_id, 31337);
+        assert_eq!(parsed.asset_code, "EURC");
+        assert_eq!(
+            parsed.recipient,
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+        );
+        assert_eq!(parsed.token, "abc123");
+    }
+
+    #[test]
+    fn rejects_invalid_stream_tokens() {
+        let parse = |token: &str, formats: &AddressFormats| {
+            EthereumDestination::try_parse_with(
+                &format!(
+                    "test.receiver.eth.1.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8{}",
+                    token
+                ),
+                formats,
+            )
+        };
+        let base64url = AddressFormats::default();
+        assert!(parse(".c2VjcmV0LXRva2Vu", &base64url).is_ok());
+        assert!(parse(".c2VjcmV0LXRva2Vu==", &base64url)
+            .unwrap_err()
+            .contains("padding not allowed"));
+        assert!(parse(&format!(".{}", "A".repeat(200)), &base64url)
+            .unwrap_err()
+            .contains("too long"));
+        assert!(parse("", &base64url).is_err());
+        assert!(parse(".abc.def", &base64url)
+            .unwrap_err()
+            .contains("after the stream token"));
+
+        let hex = AddressFormats::default().with_token(StreamTokenFormat {
+            alphabet: TokenAlphabet::Hex,
+            ..StreamTokenFormat::default()
+        });
+        assert_eq!(parse(".00ff7a", &hex).unwrap().token, "00ff7a");
+        assert!(parse(".c2VjcmV0", &hex)
+            .unwrap_err()
+            .contains("invalid character"));
+
+        let mut config = test_config();
+        config.address_formats = hex;
+        assert!(matches!(
+            plan_payout(&config, &TEST_DESTINATION.replace("abc123", "xyz"), 100, 1),
+            Err(PayoutError::InvalidStreamToken {
+                reason: "invalid character",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn resolves_configured_chain_aliases() {
+        let formats = AddressFormats::default()
+            .with_chain_alias("base", 8453)
+            .with_chain_alias("10", 8453);
+        let parse = |chain: &str| {
+            EthereumDestination::try_parse_with(
+                &format!(
+                    "test.receiver.eth.{}.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.abc123",
+                    chain
+                ),
+                &formats,
+            )
+        };
+        let base = parse("base").unwrap();
+        assert_eq!((base.chain_id, base.chain_segment.as_str()), (8453, "base"));
+        let numeric = parse("8453").unwrap();
+        assert_eq!(
+            (numeric.chain_id, numeric.chain_segment.as_str()),
+            (8453, "8453")
+        );
+
+        // A number is a chain ID even when configured as an alias
+        assert_eq!(parse("10").unwrap().chain_id, 10);
+        assert_eq!(
+            parse("optimism").unwrap_err(),
+            "Invalid chain ID \"optimism\""
+        );
+        assert!(
+            EthereumDestination::try_parse(&TEST_DESTINATION.replace("31337", "base")).is_err()
+        );
+
+        assert_eq!(
+            AddressFormats::parse_chain_aliases("base:8453, arbitrum:42161"),
+            Some(vec![
+                ("base".to_string(), 8453),
+                ("arbitrum".to_string(), 42161)
+            ])
+        );
+        assert_eq!(AddressFormats::parse_chain_aliases("base"), None);
+        assert_eq!(AddressFormats::parse_chain_aliases("ba.se:8453"), None);
+    }
+
+    #[test]
+    fn test_parse_invalid_destination() {
+        assert!(EthereumDestination::parse("test.sender.user").is_none());
+        assert!(EthereumDestination::parse("too.short").is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_segments() {
+        // Marker too close to the end to hold chain, asset and recipient
+        assert!(EthereumDestination::parse("a.b.c.d.e.eth.1").is_none());
+        // Non-hex recipient of the right length, including multi-byte characters
+        assert!(EthereumDestination::parse(
+            "test.receiver.eth.1.EURC.0xZZ997970C51812dc3A010C7d01b50e0d17dc79C8.t"
+        )
+        .is_none());
+        assert!(EthereumDestination::parse(
+            "test.receiver.eth.1.EURC.0x\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}.t"
+        )
+        .is_none());
+        // Signed or overflowing chain IDs
+        assert!(EthereumDestination::parse(
+            "test.receiver.eth.+1.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.t"
+        )
+        .is_none());
+        assert!(EthereumDestination::parse(
+            "test.receiver.eth.99999999999999999999999.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.t"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn custom_formats_apply_only_to_their_chain() {
+        let base58 = |c: char| c.is_ascii_alphanumeric() && !"0OIl".contains(c);
+        let formats = AddressFormats::default().with_chain(
+            424242,
+            AddressFormat::new("ak_", 12, base58).with_checksum(|address| !address.ends_with('z')),
+        );
+        let parse = |chain: u64, recipient: &str| {
+            let destination = format!("test.receiver.eth.{}.EURC.{}.t", chain, recipient);
+            EthereumDestination::try_parse_with(&destination, &formats)
+        };
+        let evm = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+        assert_eq!(
+            parse(424242, "ak_2pQmJrN8dW4x").unwrap().recipient,
+            "ak_2pQmJrN8dW4x"
+        );
+        assert!(parse(424242, evm).unwrap_err().contains("wrong prefix"));
+        assert!(parse(424242, "ak_2pQmJrN8dW40")
+            .unwrap_err()
+            .contains("invalid character"));
+        assert!(parse(424242, "ak_2pQmJrN8dW4z")
+            .unwrap_err()
+            .contains("checksum mismatch"));
+        // Other chains keep the EVM format
+        assert!(parse(31337, evm).is_ok());
+        assert!(parse(31337, "ak_2pQmJrN8dW4x").is_err());
+        assert!(parse(31337, "0x70997970c51812dc3A010C7d01b50e0d17dc79C8")
+            .unwrap_err()
+            .contains("checksum mismatch"));
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(destination in arbitrary_destination()) {
+            if let Some(parsed) = EthereumDestination::parse(&destination) {
+                prop_assert!(is_hex_address(&parsed.recipient));
+            }
+        }
+
+        #[test]
+        fn valid_destinations_round_trip((destination, expected) in valid_destination()) {
+            prop_assert_eq!(EthereumDestination::parse(&destination), Some(expected));
+        }
+    }
+}
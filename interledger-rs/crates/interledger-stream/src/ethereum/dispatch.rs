@@ -0,0 +1,308 @@
+//! How payouts run relative to the STREAM fulfill that triggered them
+//!
+//! [`ExecutionMode::Inline`] awaits the payout before the packet is fulfilled,
+//! `Spawned` runs each payout on its own task and `Queued` hands it to a single
+//! worker executing payouts in arrival order. Payouts still queued at shutdown
+//! are only kept in memory and are lost.
+
+use super::ordering::Turn;
+use super::shutdown::unless_cancelled;
+use super::{
+    log_outcome, EthereumPayoutService, PayoutError, PayoutEvent, PayoutOutcome, PayoutRequest,
+};
+use futures::FutureExt;
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+/// Whether `maybe_execute_payout` waits for the payout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExecutionMode {
+    /// Execute before returning
+    #[default]
+    Inline,
+    /// Execute on a new task and return immediately
+    Spawned,
+    /// Push to the background queue and return immediately
+    Queued,
+}
+
+impl ExecutionMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "inline" => Some(ExecutionMode::Inline),
+            "spawned" => Some(ExecutionMode::Spawned),
+            "queued" => Some(ExecutionMode::Queued),
+            _ => None,
+        }
+    }
+}
+
+/// Notified of payouts executed in the background
+pub trait PayoutObserver: Send + Sync {
+    /// Called once a spawned or queued payout finished, including when it panicked
+    fn payout_finished(&self, request: &PayoutRequest, result: &Result<PayoutOutcome, PayoutError>);
+
+    /// Called once later payouts are sent from `new_address` instead of `old_address`
+    fn operator_rotated(&self, _old_address: &str, _new_address: &str) {}
+}
+
+/// What became of a dispatched payout
+#[derive(Debug)]
+pub enum Dispatched {
+    /// Executed inline
+    Completed(Result<PayoutOutcome, PayoutError>),
+    /// Running on the task behind `handle`
+    Spawned {
+        payment_id: String,
+        handle: JoinHandle<()>,
+    },
+    /// Waiting in the background queue, `position` 1 being next
+    Queued { payment_id: String, position: usize },
+}
+
+impl EthereumPayoutService {
+    /// Execute the payout according to the configured execution mode
+    pub async fn dispatch_payout(self: &Arc<Self>, request: PayoutRequest) -> Dispatched {
+        let payment_id = self.request_payment_id(&request);
+        self.publish_payout(
+            &request.destination,
+            request.sequence,
+            PayoutEvent::Enqueued {
+                payment_id: payment_id.clone(),
+            },
+        );
+        match self.config.execution_mode {
+            ExecutionMode::Inline => Dispatched::Completed(self.execute(&request).await),
+            ExecutionMode::Spawned => {
+                let service = Arc::clone(self);
+                // Taken now, so the recipient's payouts run in the order they were dispatched
+                let turn = self.take_turn(&request);
+                let handle = self.spawn(async move { service.run_reported(request, turn).await });
+                Dispatched::Spawned { payment_id, handle }
+            }
+            ExecutionMode::Queued => {
+                let position = {
+                    let mut queue = self.queue.lock().unwrap();
+                    queue.push_back(request);
+                    queue.len()
+                };
+                self.queue_ready.notify_one();
+                Dispatched::Queued {
+                    payment_id,
+                    position,
+                }
+            }
+        }
+    }
+
+    /// Payouts waiting for the queue worker
+    pub fn queued_count(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Spawn the worker executing queued payouts one at a time.
+    ///
+    /// Returns `None` unless the execution mode is `Queued`. The worker stops
+    /// on shutdown, or once the service has been dropped and the queue is empty.
+    pub fn spawn_payout_worker(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        if self.config.execution_mode != ExecutionMode::Queued {
+            return None;
+        }
+        let service = Arc::downgrade(self);
+        let ready = Arc::clone(&self.queue_ready);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            while !cancellation.is_cancelled() {
+                let next = match service.upgrade() {
+                    Some(service) => {
+                        let next = service.queue.lock().unwrap().pop_front();
+                        next.map(|request| (service, request))
+                    }
+                    None => break,
+                };
+                match next {
+                    Some((service, request)) => {
+                        let turn = service.take_turn(&request);
+                        service.run_reported(request, turn).await
+                    }
+                    None => {
+                        if unless_cancelled(&cancellation, ready.notified())
+                            .await
+                            .is_none()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Execute a background payout, turning a panic into an error, and report it
+    async fn run_reported(&self, request: PayoutRequest, turn: Option<Turn>) {
+        let result = match AssertUnwindSafe(self.execute_in_turn(&request, turn))
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => result,
+            Err(panic) => Err(PayoutError::Panicked(panic_message(panic))),
+        };
+        log_outcome(self, &request.destination, &result);
+        if let Some(observer) = &self.payout_observer {
+            observer.payout_finished(&request, &result);
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or_else(
+            || "unknown panic".to_string(),
+            |message| message.to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        finished: Mutex<Vec<(u64, String)>>,
+    }
+
+    impl PayoutObserver for RecordingObserver {
+        fn payout_finished(
+            &self,
+            request: &PayoutRequest,
+            result: &Result<PayoutOutcome, PayoutError>,
+        ) {
+            let summary = match result {
+                Ok(outcome) => format!("{:?}", outcome),
+                Err(err) => err.to_string(),
+            };
+            self.finished
+                .lock()
+                .unwrap()
+                .push((request.sequence, summary));
+        }
+    }
+
+    fn service_in(
+        mode: ExecutionMode,
+        transport: Arc<MockTransport>,
+    ) -> (Arc<EthereumPayoutService>, Arc<RecordingObserver>) {
+        let mut config = test_config();
+        config.execution_mode = mode;
+        let observer = Arc::new(RecordingObserver::default());
+        let service = test_service(config, transport).with_payout_observer(observer.clone());
+        (Arc::new(service), observer)
+    }
+
+    #[tokio::test]
+    async fn inline_payouts_complete_before_returning() {
+        let transport = mock_chain();
+        let (service, observer) = service_in(ExecutionMode::Inline, transport.clone());
+        let dispatched = service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 1))
+            .await;
+        match dispatched {
+            Dispatched::Completed(Ok(outcome)) => assert_eq!(outcome.tx_hash(), Some("0xabc")),
+            other => panic!("expected a completed payout, got {:?}", other),
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+        // The caller saw the result, so the observer is not told again
+        assert!(observer.finished.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn spawned_payouts_return_immediately_and_report_panics() {
+        let transport = mock_chain();
+        let (service, observer) = service_in(ExecutionMode::Spawned, transport.clone());
+        let handle = match service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 1))
+            .await
+        {
+            Dispatched::Spawned { handle, .. } => handle,
+            other => panic!("expected a spawned payout, got {:?}", other),
+        };
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        handle.await.unwrap();
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        transport.on("eth_gasPrice", |_| panic!("node client bug"));
+        let handle = match service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 2))
+            .await
+        {
+            Dispatched::Spawned { handle, .. } => handle,
+            other => panic!("expected a spawned payout, got {:?}", other),
+        };
+        // The panic is caught inside the task rather than aborting it
+        handle.await.unwrap();
+
+        let finished = observer.finished.lock().unwrap();
+        assert_eq!(finished.len(), 2);
+        assert!(finished[0].1.contains("0xabc"));
+        assert_eq!(
+            finished[1],
+            (2, "Payout task panicked: node client bug".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn queued_payouts_run_in_order_on_the_worker() {
+        let transport = mock_chain();
+        let (service, observer) = service_in(ExecutionMode::Queued, transport.clone());
+        for sequence in 1..=3 {
+            match service
+                .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, sequence))
+                .await
+            {
+                Dispatched::Queued { position, .. } => assert_eq!(position, sequence as usize),
+                other => panic!("expected a queued payout, got {:?}", other),
+            }
+        }
+        assert_eq!(service.queued_count(), 3);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        let worker = service.spawn_payout_worker().unwrap();
+        for _ in 0..100 {
+            if observer.finished.lock().unwrap().len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let sequences: Vec<u64> = observer
+            .finished
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert_eq!(service.queued_count(), 0);
+
+        // Payouts queued later wake the idle worker
+        service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 4))
+            .await;
+        for _ in 0..100 {
+            if observer.finished.lock().unwrap().len() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 4);
+        worker.abort();
+    }
+}
@@ -0,0 +1,366 @@
+//! Dry runs of payouts over recorded traffic
+//!
+//! Answers what would have been paid had payouts been on: a window of
+//! traffic, either the skips kept in the store with `record_skips` or
+//! `destination,amount,sequence` lines read with [`parse_traffic`], is
+//! [`replay`]ed against the service's config and its transactions priced at
+//! the current gas price. The report totals what would have been paid per
+//! asset and per recipient, and lists every payment that would have been
+//! blocked with the rule blocking it. As in a replay, rules depending on
+//! service state are not evaluated, and the node is only read.
+
+use super::{
+    replay, EthereumPayoutService, PayoutBlocker, PayoutPlan, PayoutRequest, PayoutStatus,
+    Timestamp, Wei,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Records read from the store per page
+const TRAFFIC_PAGE_SIZE: usize = 500;
+
+/// Payouts of one asset, or of one asset to one recipient
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PayoutTotals {
+    pub payouts: u64,
+    pub transactions: u64,
+    /// Sum of the payouts in the asset's base units
+    pub amount: u128,
+    /// Gas of all transactions at the quoted price, in wei, `None` if any of
+    /// them could not be estimated
+    pub estimated_gas_cost: Option<u128>,
+}
+
+impl PayoutTotals {
+    fn add(&mut self, amount: u64, transactions: usize, gas_cost: Option<u128>) {
+        self.estimated_gas_cost = match (self.payouts, self.estimated_gas_cost, gas_cost) {
+            (0, _, gas_cost) => gas_cost,
+            (_, Some(total), Some(gas_cost)) => Some(total.saturating_add(gas_cost)),
+            _ => None,
+        };
+        self.payouts += 1;
+        self.transactions += transactions as u64;
+        self.amount += u128::from(amount);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "payouts": self.payouts,
+            "transactions": self.transactions,
+            "amount": self.amount.to_string(),
+            "estimated_gas_cost": self.estimated_gas_cost.map(|wei| wei.to_string()),
+        })
+    }
+}
+
+/// A payment that would not have been paid right away
+#[derive(Debug)]
+pub struct BlockedPayout {
+    pub request: PayoutRequest,
+    pub blocker: PayoutBlocker,
+}
+
+/// What paying out a window of traffic would have done
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    /// Payments in the traffic
+    pub requests: u64,
+    /// Gas price transactions were priced at, `None` if it could not be fetched
+    pub gas_price: Option<u64>,
+    /// Payouts that would have been sent, per asset code
+    pub assets: BTreeMap<String, PayoutTotals>,
+    /// Payouts that would have been sent, per recipient and asset code
+    pub recipients: BTreeMap<(String, String), PayoutTotals>,
+    /// Payments that would have been blocked, in traffic order
+    pub blocked: Vec<BlockedPayout>,
+}
+
+impl DryRunReport {
+    /// Gas of every payout that would have been sent, in wei
+    pub fn estimated_gas_cost(&self) -> Option<u128> {
+        self.assets
+            .values()
+            .map(|totals| totals.estimated_gas_cost)
+            .try_fold(0u128, |total, gas_cost| {
+                Some(total.saturating_add(gas_cost?))
+            })
+    }
+
+    /// Number of blocked payments per rule, see [`PayoutBlocker::rule`]
+    pub fn blocked_by_rule(&self) -> BTreeMap<&'static str, u64> {
+        let mut rules = BTreeMap::new();
+        for blocked in &self.blocked {
+            *rules.entry(blocked.blocker.rule()).or_default() += 1;
+        }
+        rules
+    }
+
+    pub fn to_json(&self) -> Value {
+        let assets: serde_json::Map<String, Value> = self
+            .assets
+            .iter()
+            .map(|(asset_code, totals)| (asset_code.clone(), totals.to_json()))
+            .collect();
+        let recipients: Vec<Value> = self
+            .recipients
+            .iter()
+            .map(|((recipient, asset_code), totals)| {
+                let mut entry = totals.to_json();
+                entry["recipient"] = json!(recipient);
+                entry["asset_code"] = json!(asset_code);
+                entry
+            })
+            .collect();
+        let blocked: Vec<Value> = self
+            .blocked
+            .iter()
+            .map(|blocked| {
+                json!({
+                    "destination": blocked.request.destination,
+                    "sequence": blocked.request.sequence,
+                    "amount": blocked.request.amount,
+                    "blocked": blocked.blocker.to_json(),
+                })
+            })
+            .collect();
+        json!({
+            "requests": self.requests,
+            "gas_price": self.gas_price,
+            "estimated_gas_cost": self.estimated_gas_cost().map(|wei| wei.to_string()),
+            "assets": assets,
+            "recipients": recipients,
+            "blocked_by_rule": self.blocked_by_rule(),
+            "blocked": blocked,
+        })
+    }
+}
+
+/// Parse traffic from lines of `destination,amount,sequence`, skipping blank
+/// lines and lines starting with `#`
+pub fn parse_traffic(input: &str) -> Result<Vec<PayoutRequest>, String> {
+    input
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            parse_traffic_line(line)
+                .ok_or_else(|| format!("Line {}: expected destination,amount,sequence", number))
+        })
+        .collect()
+}
+
+fn parse_traffic_line(line: &str) -> Option<PayoutRequest> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    match fields[..] {
+        [destination, amount, sequence] => Some(PayoutRequest::new(
+            destination,
+            amount.parse().ok()?,
+            sequence.parse().ok()?,
+        )),
+        _ => None,
+    }
+}
+
+impl EthereumPayoutService {
+    /// Requests of the payments skipped with `from <= timestamp < to`, as
+    /// kept in the store with `record_skips`
+    pub fn skipped_traffic(&self, from: Timestamp, to: Timestamp) -> Vec<PayoutRequest> {
+        let mut traffic = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.store.list_range(from, to, cursor, TRAFFIC_PAGE_SIZE);
+            for record in page.iter().filter(|r| r.status == PayoutStatus::Skipped) {
+                let mut request = PayoutRequest::for_record(record);
+                request.memo = record.memo.clone();
+                // Skips are kept in the asset their amount was received in
+                let payout_asset = self
+                    .config
+                    .parse_destination(&record.destination)
+                    .map(|destination| destination.asset_code);
+                if payout_asset.is_some_and(|asset_code| asset_code != record.asset_code) {
+                    request.source_asset = Some(record.asset_code.clone());
+                }
+                traffic.push(request);
+            }
+            if page.len() < TRAFFIC_PAGE_SIZE {
+                return traffic;
+            }
+            cursor = page.last().map(Into::into);
+        }
+    }
+
+    /// Report what paying out `traffic` would have done, sending nothing
+    pub async fn dry_run(&self, traffic: impl IntoIterator<Item = PayoutRequest>) -> DryRunReport {
+        let mut report = DryRunReport {
+            gas_price: self.get_gas_price().await.ok(),
+            ..DryRunReport::default()
+        };
+        for result in replay(traffic.into_iter(), &self.config) {
+            report.requests += 1;
+            let amount = match (result.blocked, result.amount) {
+                (Some(blocker), _) => {
+                    report.blocked.push(BlockedPayout {
+                        request: result.request,
+                        blocker,
+                    });
+                    continue;
+                }
+                (None, Some(amount)) => amount,
+                (None, None) => continue,
+            };
+            let gas_cost = match report.gas_price {
+                Some(gas_price) => self.plans_gas_cost(&result.plans, gas_price).await,
+                None => None,
+            };
+            let asset_code = &result.plans[0].destination.asset_code;
+            let recipient = &result.plans[0].destination.recipient;
+            report.assets.entry(asset_code.clone()).or_default().add(
+                amount,
+                result.plans.len(),
+                gas_cost,
+            );
+            report
+                .recipients
+                .entry((recipient.clone(), asset_code.clone()))
+                .or_default()
+                .add(amount, result.plans.len(), gas_cost);
+        }
+        report
+    }
+
+    async fn plans_gas_cost(&self, plans: &[PayoutPlan], gas_price: u64) -> Option<u128> {
+        let mut cost = Wei::default();
+        for plan in plans {
+            let gas = self.gas_limit(plan).await.ok()?;
+            cost = cost.saturating_add(Wei::for_gas(gas, Wei::new(u128::from(gas_price)))?);
+        }
+        Some(cost.value())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::skip::SkipReason;
+    use super::super::testing::{
+        assert_golden, test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{Clock, DEFAULT_GAS_LIMIT};
+    use super::*;
+    use std::sync::Arc;
+
+    const RECIPIENTS: [&str; 3] = [
+        "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+        "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC",
+        "0x90F79bf6EB2c4f870365E785982E1f101E93b906",
+    ];
+
+    fn destination(recipient: &str) -> String {
+        format!("test.receiver.eth.31337.EURC.{}.abc123", recipient)
+    }
+
+    /// A week of traffic: every recipient paid each day, with a large payment
+    /// on Wednesdays and a zero amount, a dust amount and a malformed
+    /// destination on Sundays
+    fn week() -> String {
+        let mut lines = vec!["# destination,amount,sequence".to_string()];
+        for day in 0..7u64 {
+            for (index, recipient) in RECIPIENTS.iter().enumerate() {
+                let amount = 100 * (index as u64 + 1) + day;
+                lines.push(format!("{},{},{}", destination(recipient), amount, day));
+            }
+            if day == 2 {
+                lines.push(format!("{},2500,100", destination(RECIPIENTS[0])));
+            }
+        }
+        lines.push(String::new());
+        lines.push(format!("{},0,200", destination(RECIPIENTS[1])));
+        lines.push(format!("{},5,201", destination(RECIPIENTS[2])));
+        lines.push("test.receiver.eth.31337.EURC.0x1234.abc123,100,202".to_string());
+        lines.join("\n")
+    }
+
+    fn dry_run_service() -> (EthereumPayoutService, Arc<MockTransport>) {
+        let transport = MockTransport::new();
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        let mut config = test_config();
+        config.assets.apply_caps("EURC:1000").unwrap();
+        config.assets.apply_min_payouts("EURC:10").unwrap();
+        config
+            .assets
+            .apply_approval_thresholds("EURC:2000")
+            .unwrap();
+        (test_service(config, transport.clone()), transport)
+    }
+
+    #[tokio::test]
+    async fn reports_a_week_of_traffic() {
+        let (service, transport) = dry_run_service();
+        let report = service.dry_run(parse_traffic(&week()).unwrap()).await;
+
+        assert_eq!(report.requests, 25);
+        let eurc = &report.assets["EURC"];
+        assert_eq!(eurc.payouts, 21);
+        assert_eq!(eurc.transactions, 21);
+        assert_eq!(eurc.amount, 7 * 600 + 3 * 21);
+        let gas_cost = 21 * u128::from(DEFAULT_GAS_LIMIT) * 1_000_000_000;
+        assert_eq!(report.estimated_gas_cost(), Some(gas_cost));
+        let rules: Vec<(&str, u64)> = report.blocked_by_rule().into_iter().collect();
+        assert_eq!(
+            rules,
+            [
+                ("awaiting_approval", 1),
+                ("below_minimum", 1),
+                ("nothing_to_pay", 1),
+                ("refused", 1)
+            ]
+        );
+        assert_golden("dry_run_week", &report.to_json());
+
+        assert_eq!(transport.call_count("eth_gasPrice"), 1);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 0);
+    }
+
+    #[tokio::test]
+    async fn reads_skips_of_a_window_from_the_store() {
+        let clock = FakeClock::new();
+        let mut config = test_config();
+        config.record_skips = true;
+        let service = test_service(config, MockTransport::new()).with_clock(clock.clone());
+        let start = clock.now();
+        let skip = |request: PayoutRequest| service.record_skip(&request, &SkipReason::Blocked);
+        skip(PayoutRequest::new(TEST_DESTINATION, 100, 1));
+        clock.advance(std::time::Duration::from_secs(3_600));
+        skip(PayoutRequest::new(TEST_DESTINATION, 200, 2).with_source_asset("XRP"));
+        skip(PayoutRequest::new(
+            "test.receiver.eth.31337.EURC.0x1234.abc123",
+            300,
+            3,
+        ));
+        let end = clock.now() + chrono::Duration::seconds(1);
+
+        let traffic = service.skipped_traffic(start, end);
+        assert_eq!(traffic.len(), 3);
+        assert_eq!(traffic[1].source_asset.as_deref(), Some("XRP"));
+        assert_eq!(traffic[2].source_asset, None);
+        let later = start + chrono::Duration::seconds(60);
+        assert_eq!(service.skipped_traffic(later, end).len(), 2);
+
+        // Without a rate the converted skip is refused, as in a replay
+        let report = service.dry_run(traffic).await;
+        assert_eq!(report.assets["EURC"].payouts, 1);
+        assert_eq!(report.gas_price, None);
+        assert_eq!(report.assets["EURC"].estimated_gas_cost, None);
+        assert_eq!(report.blocked.len(), 2);
+    }
+
+    #[test]
+    fn rejects_malformed_traffic() {
+        assert_eq!(
+            parse_traffic("a,1,2\n\na,1").unwrap_err(),
+            "Line 3: expected destination,amount,sequence"
+        );
+        assert!(parse_traffic("a,-1,2").is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Accumulation of payouts below an asset's minimum
+//!
+//! Amounts under the asset's `min_payout` are added to a balance per asset
+//! and recipient instead of being sent. Every non-zero balance is paid out in
+//! one transaction when the flush schedule comes round, whether or not it
+//! reached the minimum, so recipients are never owed dust indefinitely.
+//! Balances are kept in memory, spilling to the store beyond its capacity,
+//! and only those spilled survive a restart.
+
+use super::hash::sha256;
+use super::payload::plan_call;
+use super::recipient_state::SpilledState;
+use super::shutdown::unless_cancelled;
+use super::{
+    Attribution, EthereumDestination, EthereumPayoutConfig, EthereumPayoutService, PayoutError,
+    PayoutOutcome, PayoutRequest, Timestamp, TokenAmount,
+};
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Flush times every `interval`, shifted by `offset` from the Unix epoch,
+/// e.g. an interval of one day with an offset of two hours flushes at 02:00 UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushSchedule {
+    pub interval: Duration,
+    pub offset: Duration,
+}
+
+impl FlushSchedule {
+    /// Most recent flush time at or before `now`
+    pub fn last_slot(&self, now: Timestamp) -> Timestamp {
+        let interval = self.interval.as_secs().max(1) as i64;
+        let offset = self.offset.as_secs() as i64;
+        let slot = (now.timestamp() - offset).div_euclid(interval) * interval + offset;
+        Utc.timestamp_opt(slot, 0).unwrap()
+    }
+
+    /// First flush time after `now`
+    pub fn next_slot(&self, now: Timestamp) -> Timestamp {
+        self.last_slot(now) + chrono::Duration::seconds(self.interval.as_secs().max(1) as i64)
+    }
+}
+
+/// Dust owed to one recipient in one asset
+#[derive(Debug, Default)]
+pub(super) struct DustBalance {
+    /// Latest ILP destination paid, used for the aggregate payout's record
+    destination: String,
+    sequence: u64,
+    amount: u64,
+    /// Tags of the requests added, weighted by the amount each added
+    attribution: Attribution,
+    /// Requests already added, so replays are not counted twice
+    accumulated: HashSet<[u8; 32]>,
+}
+
+impl DustBalance {
+    fn merge(&mut self, other: DustBalance) {
+        if self.destination.is_empty() {
+            self.destination = other.destination;
+            self.sequence = other.sequence;
+        }
+        self.amount = self.amount.saturating_add(other.amount);
+        for (tag, weight) in other.attribution.weights() {
+            self.attribution.add(tag, *weight);
+        }
+        self.accumulated.extend(other.accumulated);
+    }
+}
+
+impl SpilledState for DustBalance {
+    fn to_json(&self) -> Value {
+        let mut accumulated: Vec<String> = self.accumulated.iter().map(hex::encode).collect();
+        accumulated.sort();
+        json!({
+            "destination": self.destination,
+            "sequence": self.sequence,
+            "amount": self.amount,
+            "attribution": self.attribution.to_json(),
+            "accumulated": accumulated,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let mut accumulated = HashSet::new();
+        for id in value["accumulated"].as_array()? {
+            let mut payment_id = [0; 32];
+            hex::decode_to_slice(id.as_str()?, &mut payment_id).ok()?;
+            accumulated.insert(payment_id);
+        }
+        Some(DustBalance {
+            destination: value["destination"].as_str()?.to_string(),
+            sequence: value["sequence"].as_u64()?,
+            amount: value["amount"].as_u64()?,
+            attribution: Attribution::from_json(&value["attribution"])?,
+            accumulated,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.amount == 0 && self.accumulated.is_empty()
+    }
+}
+
+/// Key of a balance: the asset code and lowercase recipient address
+fn ledger_key(asset_code: &str, recipient: &str) -> String {
+    format!("{}/{}", asset_code, recipient.to_ascii_lowercase())
+}
+
+/// Asset code and recipient of a balance's key
+fn split_key(key: &str) -> (String, String) {
+    let (asset_code, recipient) = key.rsplit_once('/').unwrap_or((key, ""));
+    (asset_code.to_string(), recipient.to_string())
+}
+
+/// Result of paying out one balance during a flush
+#[derive(Debug)]
+pub struct DustPayout {
+    pub asset_code: String,
+    pub recipient: String,
+    pub amount: u64,
+    pub payment_id: [u8; 32],
+    /// On failure the amount is kept for the next flush
+    pub result: Result<PayoutOutcome, PayoutError>,
+}
+
+/// Payment ID of the aggregate payout of a balance at the given flush time
+pub fn dust_payment_id(asset_code: &str, recipient: &str, slot: Timestamp) -> [u8; 32] {
+    let data = format!(
+        "dust:{}:{}:{}",
+        asset_code,
+        recipient.to_ascii_lowercase(),
+        slot.to_rfc3339()
+    );
+    sha256(data.as_bytes())
+}
+
+/// The asset's minimum payout, if `amount` is below it
+pub(super) fn dust_minimum(
+    config: &EthereumPayoutConfig,
+    eth_dest: &EthereumDestination,
+    amount: &TokenAmount,
+) -> Option<u64> {
+    let min_payout = config.assets.get(&eth_dest.asset_code)?.min_payout?;
+    Some(min_payout).filter(|min_payout| amount.base_units < *min_payout)
+}
+
+impl EthereumPayoutService {
+    /// Add the request to its recipient's balance
+    pub(super) fn accumulate_dust(
+        &self,
+        request: &PayoutRequest,
+        eth_dest: &EthereumDestination,
+        amount: u64,
+    ) -> PayoutOutcome {
+        let payment_id = self.config.payment_id_of(request);
+        let key = ledger_key(&eth_dest.asset_code, &eth_dest.recipient);
+        let balance = self.dust.with(&*self.store, &key, |balance| {
+            if balance.accumulated.insert(payment_id) {
+                balance.amount = balance.amount.saturating_add(amount);
+                for (tag, share) in request.attribution.split(u128::from(amount)) {
+                    balance.attribution.add(&tag, share as u64);
+                }
+                balance.destination = request.destination.clone();
+                balance.sequence = balance.sequence.max(request.sequence);
+            }
+            balance.amount
+        });
+        debug!(
+            "Accumulated {} {} for {}, balance {}",
+            amount,
+            eth_dest.asset_code,
+            self.display_address(&eth_dest.recipient),
+            balance
+        );
+        PayoutOutcome::Accumulated { balance }
+    }
+
+    /// Dust currently owed to `recipient` in `asset_code`
+    pub fn dust_balance(&self, asset_code: &str, recipient: &str) -> u64 {
+        self.dust.with(
+            &*self.store,
+            &ledger_key(asset_code, recipient),
+            |balance| balance.amount,
+        )
+    }
+
+    /// Dust currently owed to `recipient`, per asset
+    pub(super) fn dust_balances(&self, recipient: &str) -> Vec<(String, u64)> {
+        let recipient = recipient.to_ascii_lowercase();
+        self.dust
+            .keys(&*self.store)
+            .into_iter()
+            .filter(|key| split_key(key).1 == recipient)
+            .map(|key| {
+                let amount = self.dust.with(&*self.store, &key, |balance| balance.amount);
+                (split_key(&key).0, amount)
+            })
+            .filter(|(_, amount)| *amount > 0)
+            .collect()
+    }
+
+    /// Pay out every non-zero balance, each under a payment ID derived from
+    /// the current flush slot. A failed payout keeps its balance for the next
+    /// flush and does not stop the others.
+    pub async fn flush_dust(&self) -> Vec<DustPayout> {
+        let now = self.clock.now();
+        let slot = self
+            .config
+            .dust_flush
+            .map_or(now, |schedule| schedule.last_slot(now));
+        let balances: Vec<_> = self
+            .dust
+            .keys(&*self.store)
+            .into_iter()
+            .map(|key| (split_key(&key), self.dust.take(&*self.store, &key)))
+            .collect();
+
+        let mut payouts = Vec::new();
+        for ((asset_code, recipient), balance) in balances {
+            if balance.amount == 0 {
+                continue;
+            }
+            let payment_id = self
+                .config
+                .namespaced(dust_payment_id(&asset_code, &recipient, slot));
+            let result = self.pay_dust(&balance, payment_id).await;
+            if let Err(err) = &result {
+                warn!(
+                    "Dust payout of {} {} to {} failed, keeping it for the next flush: {}",
+                    balance.amount,
+                    asset_code,
+                    self.display_address(&recipient),
+                    self.scrub_address(&err.to_string(), &recipient)
+                );
+            }
+            let amount = balance.amount;
+            if result.is_err() {
+                let key = ledger_key(&asset_code, &recipient);
+                self.dust
+                    .with(&*self.store, &key, |owed| owed.merge(balance));
+            }
+            payouts.push(DustPayout {
+                asset_code,
+                recipient,
+                amount,
+                payment_id,
+                result,
+            });
+        }
+        payouts
+    }
+
+    async fn pay_dust(
+        &self,
+        balance: &DustBalance,
+        payment_id: [u8; 32],
+    ) -> Result<PayoutOutcome, PayoutError> {
+        if let Some(existing) = self.lookup(&payment_id) {
+            if !existing.is_retryable() {
+                return Err(PayoutError::Config(format!(
+                    "Dust payout {} was already sent in this flush slot",
+                    existing.payment_id_hex()
+                )));
+            }
+        }
+        let eth_dest = self
+            .config
+            .parse_destination(&balance.destination)
+            .ok_or_else(|| PayoutError::InvalidDestination(balance.destination.clone()))?;
+        let plan = plan_call(
+            &self.config,
+            eth_dest,
+            payment_id,
+            balance.amount,
+            &balance.destination,
+        )?;
+        let mut request =
+            PayoutRequest::new(&balance.destination, balance.amount, balance.sequence);
+        request.payment_id = Some(payment_id);
+        request.attribution = balance.attribution.clone();
+        let deadline = self.deadline_for(&request);
+        self.execute_plan(&request, &plan, deadline).await
+    }
+
+    /// Spawn a task flushing dust on the configured schedule.
+    ///
+    /// Returns `None` when no schedule is configured. The task only holds a
+    /// weak reference and stops once the service has been dropped or shut down.
+    pub fn spawn_dust_flusher(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let schedule = self.config.dust_flush?;
+        let service = Arc::downgrade(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            loop {
+                let wait = match service.upgrade() {
+                    Some(service) => {
+                        let now = service.clock.now();
+                        (schedule.next_slot(now) - now).to_std().unwrap_or_default()
+                    }
+                    None => break,
+                };
+                if unless_cancelled(&cancellation, tokio::time::sleep(wait))
+                    .await
+                    .is_none()
+                {
+                    break;
+                }
+                let service = match service.upgrade() {
+                    Some(service) => service,
+                    None => break,
+                };
+                let payouts = service.flush_dust().await;
+                let failed = payouts.iter().filter(|p| p.result.is_err()).count();
+                info!(
+                    "Flushed dust to {} recipients, {} failed",
+                    payouts.len(),
+                    failed
+                );
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        decode_raw_transaction, mock_chain, test_config, test_service, FakeClock, MockTransport,
+        TEST_DESTINATION,
+    };
+    use super::super::{Clock, EthereumPayoutConfig};
+    use super::*;
+    use serde_json::json;
+
+    const OTHER_DESTINATION: &str =
+        "test.receiver.eth.31337.EURC.0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC.abc123";
+    const RECIPIENT: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+    const OTHER_RECIPIENT: &str = "0x3c44cdddb6a900fa2b585dd299e03d12fa4293bc";
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn dust_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.get_mut("EURC").unwrap().min_payout = Some(1000);
+        config.dust_flush = Some(FlushSchedule {
+            interval: DAY,
+            offset: Duration::from_secs(2 * 60 * 60),
+        });
+        config
+    }
+
+    /// Node that refuses transfers to `refused`
+    fn refusing(refused: &'static str) -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on("eth_sendRawTransaction", move |params| {
+            let tx = decode_raw_transaction(params[0].as_str().unwrap()).unwrap();
+            let data = tx["data"].as_str().unwrap_or_default().to_ascii_lowercase();
+            if data.contains(&refused[2..]) {
+                Err(json!({"code": -32000, "message": "insufficient funds"}))
+            } else {
+                Ok(json!("0xabc"))
+            }
+        });
+        transport
+    }
+
+    #[test]
+    fn schedule_slots_align_to_the_offset() {
+        // 2024-01-02T01:00Z falls between the 02:00Z flushes of the 1st and 2nd
+        let schedule = dust_config().dust_flush.unwrap();
+        let now = Utc.timestamp_opt(1_704_157_200, 0).unwrap();
+        assert_eq!(
+            schedule.last_slot(now),
+            Utc.timestamp_opt(1_704_074_400, 0).unwrap()
+        );
+        assert_eq!(
+            schedule.next_slot(now),
+            Utc.timestamp_opt(1_704_160_800, 0).unwrap()
+        );
+        let slot = Utc.timestamp_opt(1_704_160_800, 0).unwrap();
+        assert_eq!(schedule.last_slot(slot), slot);
+    }
+
+    #[tokio::test]
+    async fn flush_pays_aggregates_per_recipient() {
+        let transport = mock_chain();
+        let clock = FakeClock::new();
+        let service = test_service(dust_config(), transport.clone()).with_clock(clock.clone());
+
+        for (destination, amount, sequence) in [
+            (TEST_DESTINATION, 300, 1),
+            (TEST_DESTINATION, 200, 2),
+            // Replays are not counted twice
+            (TEST_DESTINATION, 200, 2),
+            (OTHER_DESTINATION, 400, 1),
+        ] {
+            assert!(matches!(
+                service
+                    .execute_payout(destination, amount, sequence)
+                    .await
+                    .unwrap(),
+                PayoutOutcome::Accumulated { .. }
+            ));
+        }
+        // Amounts at the minimum are paid right away
+        service
+            .execute_payout(TEST_DESTINATION, 1000, 3)
+            .await
+            .unwrap();
+        assert_eq!(service.dust_balance("EURC", RECIPIENT), 500);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        let payouts = service.flush_dust().await;
+        let slot = service.config.dust_flush.unwrap().last_slot(clock.now());
+        let summary: Vec<_> = payouts
+            .iter()
+            .map(|p| (p.recipient.as_str(), p.amount, p.payment_id))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    OTHER_RECIPIENT,
+                    400,
+                    dust_payment_id("EURC", OTHER_RECIPIENT, slot)
+                ),
+                (RECIPIENT, 500, dust_payment_id("EURC", RECIPIENT, slot)),
+            ]
+        );
+        assert!(payouts.iter().all(|p| p.result.is_ok()));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 3);
+        assert_eq!(service.dust_balance("EURC", RECIPIENT), 0);
+        assert_eq!(service.store.get(&summary[1].2).unwrap().amount, 500);
+
+        // Nothing is owed, so the next flush sends nothing
+        clock.advance(DAY);
+        assert!(service.flush_dust().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_recipient_does_not_block_the_batch() {
+        let transport = refusing(RECIPIENT);
+        let clock = FakeClock::new();
+        let service = test_service(dust_config(), transport.clone()).with_clock(clock.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 300, 1)
+            .await
+            .unwrap();
+        service
+            .execute_payout(OTHER_DESTINATION, 400, 1)
+            .await
+            .unwrap();
+
+        let payouts = service.flush_dust().await;
+        assert!(payouts[0].result.is_ok());
+        assert!(payouts[1].result.is_err());
+        assert_eq!(service.dust_balance("EURC", OTHER_RECIPIENT), 0);
+        assert_eq!(service.dust_balance("EURC", RECIPIENT), 300);
+
+        // Paid at the next flush under a new payment ID
+        transport.on_result("eth_sendRawTransaction", json!("0xdef"));
+        clock.advance(DAY);
+        let retried = service.flush_dust().await;
+        assert_eq!(retried.len(), 1);
+        assert!(retried[0].result.is_ok());
+        assert_ne!(retried[0].payment_id, payouts[1].payment_id);
+        assert_eq!(service.dust_balance("EURC", RECIPIENT), 0);
+    }
+
+    #[tokio::test]
+    async fn balances_spilled_to_the_store_are_still_paid() {
+        let transport = mock_chain();
+        let mut config = dust_config();
+        config.recipient_state_capacity = 1;
+        let service = test_service(config, transport.clone());
+        for (destination, amount, sequence) in [
+            (TEST_DESTINATION, 300, 1),
+            (OTHER_DESTINATION, 400, 1),
+            // Replayed after its balance was spilled, still counted once
+            (TEST_DESTINATION, 300, 1),
+            (TEST_DESTINATION, 200, 2),
+        ] {
+            service
+                .execute_payout(destination, amount, sequence)
+                .await
+                .unwrap();
+        }
+        assert_eq!(service.dust.resident(), 1);
+        let spilled = ledger_key("EURC", OTHER_RECIPIENT);
+        assert_eq!(
+            service.store.recipient_state("dust", &spilled).unwrap()["amount"],
+            400
+        );
+        assert_eq!(service.dust_balance("EURC", RECIPIENT), 500);
+
+        let payouts = service.flush_dust().await;
+        let amounts: Vec<_> = payouts
+            .iter()
+            .map(|p| (p.recipient.as_str(), p.amount))
+            .collect();
+        assert_eq!(amounts, [(OTHER_RECIPIENT, 400), (RECIPIENT, 500)]);
+        assert!(payouts.iter().all(|p| p.result.is_ok()));
+        assert_eq!(service.store.recipient_state("dust", &spilled), None);
+        assert!(service.dust.keys(&*service.store).is_empty());
+    }
+}
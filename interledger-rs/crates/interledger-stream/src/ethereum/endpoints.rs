@@ -0,0 +1,447 @@
+//! Selection among several RPC endpoints by observed performance
+//!
+//! Each endpoint keeps a rolling window of its recent requests. Requests are
+//! spread by smooth weighted round-robin, with weights proportional to the
+//! success rate over the p95 latency. Failures count at [`FAILURE_LATENCY`]
+//! in the latency percentile, so an endpoint failing quickly is not mistaken
+//! for a fast one. Every endpoint keeps at least [`WEIGHT_FLOOR`] so a
+//! recovering one is still probed, and weights only move once they drift
+//! past [`WEIGHT_HYSTERESIS`] to avoid flapping between similar endpoints.
+//! Requests failing on a stale pooled connection are not counted.
+
+use super::exposition::EndpointCounters;
+use super::nonce::{pending_nonce_read, PendingNonces};
+use super::rpc::{rpc_result, RpcTransport};
+use super::throttle::endpoint_label;
+use super::{metrics, Clock, PayoutError, SystemClock};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Requests remembered per endpoint
+const WINDOW: usize = 100;
+/// Requests an endpoint must have served before its weight departs from the best
+const MIN_SAMPLES: usize = 10;
+/// Weight of the best performing endpoint
+const MAX_WEIGHT: u32 = 1000;
+/// Least weight of any endpoint, so about 5% of traffic probes a poor one
+pub const WEIGHT_FLOOR: u32 = 50;
+/// Relative change needed before an endpoint's weight is updated
+pub const WEIGHT_HYSTERESIS: f64 = 0.25;
+/// Latency a failed request counts with
+pub const FAILURE_LATENCY: Duration = Duration::from_secs(5);
+
+/// Observed performance of one endpoint over its recent requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointStats {
+    /// Host of the endpoint, without credentials or path
+    pub endpoint: String,
+    pub requests: usize,
+    pub successes: usize,
+    /// 95th percentile latency, counting failures at `FAILURE_LATENCY`
+    pub p95_latency: Option<Duration>,
+    /// Share of traffic relative to the other endpoints' weights
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    success: bool,
+    latency: Duration,
+}
+
+#[derive(Debug)]
+struct EndpointState {
+    samples: VecDeque<Sample>,
+    weight: u32,
+    /// Running credit of the weighted round-robin
+    current: i64,
+}
+
+impl EndpointState {
+    fn stats(&self, endpoint: &str) -> EndpointStats {
+        EndpointStats {
+            endpoint: endpoint.to_string(),
+            requests: self.samples.len(),
+            successes: self.samples.iter().filter(|s| s.success).count(),
+            p95_latency: self.p95_latency(),
+            weight: self.weight,
+        }
+    }
+
+    fn p95_latency(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut latencies: Vec<Duration> = self
+            .samples
+            .iter()
+            .map(|sample| {
+                if sample.success {
+                    sample.latency
+                } else {
+                    sample.latency.max(FAILURE_LATENCY)
+                }
+            })
+            .collect();
+        latencies.sort();
+        let rank = (latencies.len() * 95).div_ceil(100);
+        Some(latencies[rank.max(1) - 1])
+    }
+
+    /// Success rate over p95 latency, `None` until enough requests were seen
+    fn score(&self) -> Option<f64> {
+        if self.samples.len() < MIN_SAMPLES {
+            return None;
+        }
+        let successes = self.samples.iter().filter(|sample| sample.success).count();
+        let success_rate = successes as f64 / self.samples.len() as f64;
+        let p95 = self.p95_latency()?.as_secs_f64().max(0.001);
+        Some(success_rate / p95)
+    }
+}
+
+/// Transport spreading requests over several endpoints by their performance
+pub struct EndpointPool {
+    endpoints: Vec<(String, Arc<dyn RpcTransport>)>,
+    state: Mutex<Vec<EndpointState>>,
+    /// Requests sent to each endpoint, for `metrics_text`
+    counters: Vec<EndpointCounters>,
+    clock: Arc<dyn Clock>,
+    /// Tenant labelling the pool's metrics
+    tenant: Option<String>,
+    /// Highest pending nonce any endpoint answered, per address
+    pending_nonces: PendingNonces,
+}
+
+impl EndpointPool {
+    /// Pool over labelled transports, all starting with equal weight
+    pub fn new(endpoints: Vec<(String, Arc<dyn RpcTransport>)>) -> Self {
+        let state = endpoints
+            .iter()
+            .map(|_| EndpointState {
+                samples: VecDeque::with_capacity(WINDOW),
+                weight: MAX_WEIGHT,
+                current: 0,
+            })
+            .collect();
+        let counters = endpoints.iter().map(|_| Default::default()).collect();
+        EndpointPool {
+            endpoints,
+            state: Mutex::new(state),
+            counters,
+            clock: Arc::new(SystemClock),
+            tenant: None,
+            pending_nonces: PendingNonces::default(),
+        }
+    }
+
+    /// Pool over HTTP or IPC endpoints, labelled by host
+    pub fn http(client: &reqwest::Client, urls: &[String]) -> Self {
+        EndpointPool::new(
+            urls.iter()
+                .map(|url| (endpoint_label(url), super::ipc::transport_for(client, url)))
+                .collect(),
+        )
+    }
+
+    /// Measure latencies on the given clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Label the pool's metrics with `tenant`
+    pub fn with_tenant(mut self, tenant: Option<String>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    pub fn stats(&self) -> Vec<EndpointStats> {
+        let state = self.state.lock().unwrap();
+        self.endpoints
+            .iter()
+            .zip(state.iter())
+            .map(|((label, _), endpoint)| endpoint.stats(label))
+            .collect()
+    }
+
+    /// Requests sent to each endpoint, by label
+    pub(super) fn counters(&self) -> impl Iterator<Item = (&str, &EndpointCounters)> {
+        self.endpoints
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .zip(self.counters.iter())
+    }
+
+    /// Trust the next pending nonce any endpoint answers for `address`
+    pub(super) fn forget_pending_nonce(&self, address: &str) {
+        self.pending_nonces.forget(address);
+    }
+
+    /// Whether the endpoint answered a pending nonce lower than one read
+    /// before, counting it if so
+    fn nonce_regressed(&self, index: usize, request: &Value, response: &Value) -> bool {
+        let (address, pending) = match pending_nonce_read(request, response) {
+            Some(read) => read,
+            None => return false,
+        };
+        if self.pending_nonces.observe(&address, pending).is_none() {
+            return false;
+        }
+        let label = &self.endpoints[index].0;
+        warn!(
+            "Endpoint {} answered a pending nonce of {} for {} lower than read before",
+            label, pending, address
+        );
+        self.counters[index].nonce_regression();
+        metrics::nonce_regression(self.tenant.as_deref(), label);
+        true
+    }
+
+    /// Index of the endpoint to send the next request to
+    fn pick(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let total: i64 = state
+            .iter()
+            .map(|endpoint| i64::from(endpoint.weight))
+            .sum();
+        for endpoint in state.iter_mut() {
+            endpoint.current += i64::from(endpoint.weight);
+        }
+        let (index, _) = state
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, endpoint)| (endpoint.current, std::cmp::Reverse(*index)))
+            .expect("pool has endpoints");
+        state[index].current -= total;
+        index
+    }
+
+    fn record(&self, index: usize, sample: Sample) {
+        let mut state = self.state.lock().unwrap();
+        let samples = &mut state[index].samples;
+        if samples.len() == WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+
+        // Endpoints without enough samples yet are assumed to be as good as the best
+        let scores: Vec<Option<f64>> = state.iter().map(EndpointState::score).collect();
+        let best = scores.iter().flatten().cloned().fold(0.0, f64::max);
+        if best <= 0.0 {
+            return;
+        }
+        for (endpoint, score) in state.iter_mut().zip(scores) {
+            let target = score.map_or(MAX_WEIGHT, |score| {
+                ((score / best * f64::from(MAX_WEIGHT)).round() as u32).max(WEIGHT_FLOOR)
+            });
+            let change = (f64::from(target) - f64::from(endpoint.weight)).abs();
+            if change > f64::from(endpoint.weight) * WEIGHT_HYSTERESIS {
+                endpoint.weight = target;
+            }
+        }
+        for ((label, _), endpoint) in self.endpoints.iter().zip(state.iter()) {
+            metrics::endpoint_stats(self.tenant.as_deref(), &endpoint.stats(label));
+        }
+    }
+}
+
+#[async_trait]
+impl RpcTransport for EndpointPool {
+    async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+        let index = self.pick();
+        let started = self.clock.now();
+        let result = self.endpoints[index].1.send(request.clone()).await;
+        let latency = (self.clock.now() - started).to_std().unwrap_or_default();
+        let failed = !matches!(&result, Ok(response) if response.get("error").is_none());
+        self.counters[index].record(latency, failed);
+        // The client's pool went stale; the endpoint may be fine
+        if let Err(PayoutError::StaleConnection { .. }) = &result {
+            return result;
+        }
+        // Errors the endpoint itself is responsible for, not the request,
+        // and nonces behind what other endpoints already answered
+        let success = match &result {
+            Ok(response) => match rpc_result(response.clone()) {
+                Err(err) => !err.is_transient(),
+                Ok(_) => !self.nonce_regressed(index, &request, response),
+            },
+            Err(_) => false,
+        };
+        self.record(index, Sample { success, latency });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{FakeClock, MockTransport, TEST_OPERATOR};
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Endpoint answering after a fixed latency, failing every `fail_every`th request
+    struct ProfiledEndpoint {
+        clock: Arc<FakeClock>,
+        latency: Mutex<Duration>,
+        fail_every: Mutex<Option<usize>>,
+        served: AtomicUsize,
+    }
+
+    impl ProfiledEndpoint {
+        fn new(clock: &Arc<FakeClock>, latency_ms: u64, fail_every: Option<usize>) -> Arc<Self> {
+            Arc::new(ProfiledEndpoint {
+                clock: clock.clone(),
+                latency: Mutex::new(Duration::from_millis(latency_ms)),
+                fail_every: Mutex::new(fail_every),
+                served: AtomicUsize::new(0),
+            })
+        }
+
+        fn set_profile(&self, latency_ms: u64, fail_every: Option<usize>) {
+            *self.latency.lock().unwrap() = Duration::from_millis(latency_ms);
+            *self.fail_every.lock().unwrap() = fail_every;
+        }
+    }
+
+    #[async_trait]
+    impl RpcTransport for ProfiledEndpoint {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            let served = self.served.fetch_add(1, Ordering::SeqCst) + 1;
+            self.clock.advance(*self.latency.lock().unwrap());
+            match *self.fail_every.lock().unwrap() {
+                Some(every) if served.is_multiple_of(every) => Ok(json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": {"code": -32603, "message": "internal error"}
+                })),
+                _ => Ok(json!({"jsonrpc": "2.0", "id": request["id"], "result": "0x1"})),
+            }
+        }
+    }
+
+    fn pool(
+        clock: &Arc<FakeClock>,
+        a: &Arc<ProfiledEndpoint>,
+        b: &Arc<ProfiledEndpoint>,
+    ) -> EndpointPool {
+        EndpointPool::new(vec![
+            ("a".to_string(), a.clone() as Arc<dyn RpcTransport>),
+            ("b".to_string(), b.clone() as Arc<dyn RpcTransport>),
+        ])
+        .with_clock(clock.clone())
+    }
+
+    /// Requests served by each endpoint while sending `count` more
+    async fn split(pool: &EndpointPool, a: &ProfiledEndpoint, count: usize) -> (usize, usize) {
+        let before = a.served.load(Ordering::SeqCst);
+        for _ in 0..count {
+            let _ = pool
+                .send(json!({"id": 1, "method": "eth_blockNumber"}))
+                .await;
+        }
+        let to_a = a.served.load(Ordering::SeqCst) - before;
+        (to_a, count - to_a)
+    }
+
+    #[tokio::test]
+    async fn traffic_shifts_to_the_healthier_endpoint_and_back() {
+        let clock = FakeClock::new();
+        let a = ProfiledEndpoint::new(&clock, 40, None);
+        // Answers quickly, but every other request fails
+        let b = ProfiledEndpoint::new(&clock, 5, Some(2));
+        let pool = pool(&clock, &a, &b);
+
+        split(&pool, &a, 200).await;
+        let (to_a, to_b) = split(&pool, &a, 200).await;
+        assert!(to_a > 180, "a served {} of 200", to_a);
+        // The failing endpoint is still probed at the floor weight
+        assert!(to_b > 0);
+        let stats = pool.stats();
+        assert_eq!(stats[1].weight, WEIGHT_FLOOR);
+        assert_eq!(stats[1].p95_latency, Some(FAILURE_LATENCY));
+        assert_eq!(stats[0].p95_latency, Some(Duration::from_millis(40)));
+
+        // Once it recovers and is faster, the probes pull traffic back to it
+        b.set_profile(5, None);
+        let mut shifted = false;
+        for _ in 0..50 {
+            let (_, to_b) = split(&pool, &a, 100).await;
+            if to_b > 80 {
+                shifted = true;
+                break;
+            }
+        }
+        assert!(shifted, "stats: {:?}", pool.stats());
+    }
+
+    #[tokio::test]
+    async fn similar_endpoints_keep_equal_weights() {
+        let clock = FakeClock::new();
+        let a = ProfiledEndpoint::new(&clock, 10, None);
+        let b = ProfiledEndpoint::new(&clock, 12, None);
+        let pool = pool(&clock, &a, &b);
+
+        let (to_a, to_b) = split(&pool, &a, 400).await;
+        assert_eq!((to_a, to_b), (200, 200));
+        let weights: Vec<u32> = pool.stats().iter().map(|s| s.weight).collect();
+        assert_eq!(weights, vec![MAX_WEIGHT, MAX_WEIGHT]);
+    }
+
+    #[tokio::test]
+    async fn lagging_nonces_count_against_the_endpoint() {
+        let clock = FakeClock::new();
+        let ahead = MockTransport::new();
+        ahead.on_result("eth_getTransactionCount", json!("0x8"));
+        let behind = MockTransport::new();
+        behind.on_result("eth_getTransactionCount", json!("0x5"));
+        let pool = EndpointPool::new(vec![
+            ("ahead".to_string(), ahead as Arc<dyn RpcTransport>),
+            ("behind".to_string(), behind as Arc<dyn RpcTransport>),
+        ])
+        .with_clock(clock);
+        let read = json!({
+            "id": 1,
+            "method": "eth_getTransactionCount",
+            "params": [TEST_OPERATOR, "pending"],
+        });
+        for _ in 0..4 {
+            pool.send(read.clone()).await.unwrap();
+        }
+        let successes: Vec<usize> = pool.stats().iter().map(|s| s.successes).collect();
+        assert_eq!(successes, [2, 0]);
+
+        // Once forgotten, the lagging endpoint's next reading is trusted;
+        // one of the latest block, sent to the other, is never compared
+        pool.forget_pending_nonce(TEST_OPERATOR);
+        let mut latest = read.clone();
+        latest["params"][1] = json!("latest");
+        pool.send(latest).await.unwrap();
+        pool.send(read).await.unwrap();
+        assert_eq!(pool.stats()[1].successes, 1);
+    }
+
+    #[test]
+    fn p95_counts_failures_at_the_penalty_latency() {
+        let mut state = EndpointState {
+            samples: VecDeque::new(),
+            weight: MAX_WEIGHT,
+            current: 0,
+        };
+        assert_eq!(state.p95_latency(), None);
+        for i in 0..100 {
+            state.samples.push_back(Sample {
+                success: i >= 5,
+                latency: Duration::from_millis(if i < 5 { 1 } else { i }),
+            });
+        }
+        // Five failures in a hundred sit right at the 95th percentile boundary
+        assert_eq!(state.p95_latency(), Some(Duration::from_millis(99)));
+        state.samples[10].success = false;
+        assert_eq!(state.p95_latency(), Some(FAILURE_LATENCY));
+    }
+}
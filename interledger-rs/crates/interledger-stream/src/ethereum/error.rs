@@ -0,0 +1,204 @@
+//! Errors returned by the Ethereum payout service
+
+use super::DecodedRevert;
+
+/// Reasons a payout could not be executed
+#[derive(Debug, thiserror::Error)]
+pub enum PayoutError {
+    #[error("Invalid Ethereum destination: {0}")]
+    InvalidDestination(String),
+    #[error("Invalid stream token in {destination}: {reason}")]
+    InvalidStreamToken {
+        destination: String,
+        reason: &'static str,
+    },
+    #[error("Token address {token} in {destination} is not in the asset registry")]
+    UnknownTokenAddress { destination: String, token: String },
+    #[error("Token address {token} is registered for several assets: {codes:?}")]
+    AmbiguousTokenAddress { token: String, codes: Vec<String> },
+    #[error("Signer unavailable: {0}")]
+    SignerUnavailable(String),
+    #[error("Payouts are stopped by the kill switch at {path}")]
+    KillSwitchEngaged { path: String },
+    #[error("Recipient {recipient} is not allowed: {reason}")]
+    RecipientInvalid {
+        recipient: String,
+        reason: &'static str,
+    },
+    #[error("Sequence {sequence} for {destination} is more than {max_lag} behind {highest}")]
+    SequenceOutOfWindow {
+        destination: String,
+        sequence: u64,
+        highest: u64,
+        max_lag: u64,
+    },
+    #[error("Payout of {amount} {asset_code} to {recipient} would exceed the daily {scope} cap of {cap} (paid today: {asset_total} in total, {recipient_total} to this recipient)")]
+    LimitExceeded {
+        asset_code: String,
+        recipient: String,
+        amount: u64,
+        /// "asset" for the cap across all recipients, "recipient" for the per-recipient one
+        scope: &'static str,
+        cap: u64,
+        asset_total: u128,
+        recipient_total: u128,
+    },
+    #[error("Payout {payment_id} was rejected: {reason}")]
+    Rejected { payment_id: String, reason: String },
+    #[error("External payment ID must be 32 bytes, not {len}")]
+    InvalidPaymentId { len: usize },
+    #[error("Memo of {len} bytes exceeds the maximum of {max}")]
+    MemoTooLong { len: usize, max: usize },
+    #[error("Payout to {destination} has a zero amount")]
+    ZeroAmount { destination: String },
+    #[error("{source_amount} {source_asset} rounds to zero {asset_code}; check the asset scales and rate")]
+    RoundedToZero {
+        source_asset: String,
+        source_amount: u64,
+        asset_code: String,
+    },
+    #[error("Payout would cost up to {cost} wei in gas, above the maximum of {max}")]
+    GasCostTooHigh { cost: u128, max: u128 },
+    #[error("Connection to {endpoint} was closed before it answered: {message}")]
+    StaleConnection { endpoint: String, message: String },
+    #[error("Payouts to {recipient} are held back until payout {payment_id} is resolved")]
+    RecipientBlocked {
+        recipient: String,
+        payment_id: String,
+    },
+    #[error("Payouts to {recipient} are held for review after {failures} failed in a row")]
+    RecipientHeld { recipient: String, failures: u64 },
+    #[error("Operator {operator} is not authorized on the Treasury")]
+    NotAuthorized { operator: String },
+    #[error("RPC error {code}: {message}")]
+    Rpc {
+        code: i64,
+        message: String,
+        /// Revert data returned alongside execution errors, if any
+        data: Option<String>,
+    },
+    #[error("Transaction reverted: {0}")]
+    Reverted(DecodedRevert),
+    #[error("Split payout stopped after {} of {total} transactions: {source}", completed.len())]
+    PartialPayout {
+        /// Hashes of the chunks sent so far; these are skipped on retry
+        completed: Vec<String>,
+        total: usize,
+        source: Box<PayoutError>,
+    },
+    #[error("Rate from {from_asset} to {to_asset} is {age_secs}s old, more than the allowed {max_age_secs}s")]
+    StaleRate {
+        from_asset: String,
+        to_asset: String,
+        age_secs: i64,
+        max_age_secs: u64,
+    },
+    #[error("Payout {payment_id} abandoned at its deadline: {last_error}")]
+    Abandoned {
+        payment_id: String,
+        last_error: String,
+    },
+    #[error("Rate limited by {endpoint}")]
+    Throttled {
+        /// Host of the endpoint that refused the request
+        endpoint: String,
+        /// Delay requested through `Retry-After`
+        retry_after: Option<std::time::Duration>,
+    },
+    #[error("Payout task panicked: {0}")]
+    Panicked(String),
+    #[error("Interrupted by shutdown")]
+    Cancelled,
+    #[error("{method} timed out after {after:?}")]
+    TimedOut {
+        method: String,
+        after: std::time::Duration,
+    },
+    #[error("Submission with nonce {nonce} timed out and could not be verified; check the operator's transactions before retrying")]
+    SubmissionUnverified { nonce: u64 },
+    #[error("RPC transport error: {0}")]
+    Transport(String),
+    #[error("Invalid RPC response: {0}")]
+    InvalidResponse(String),
+    #[error("Payout store could not be written: {0}")]
+    StoreWrite(String),
+    #[error("Nonce store unavailable: {0}")]
+    NonceStore(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error("Invalid operator private key: {0}")]
+    InvalidOperatorKey(String),
+    #[error(
+        "Blocking payout API called from within an async runtime; use the async service instead"
+    )]
+    InAsyncContext,
+}
+
+impl PayoutError {
+    /// Whether the same request may succeed if tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PayoutError::Transport(_)
+            | PayoutError::Throttled { .. }
+            | PayoutError::TimedOut { .. }
+            | PayoutError::StaleConnection { .. }
+            | PayoutError::GasCostTooHigh { .. }
+            | PayoutError::RecipientBlocked { .. }
+            | PayoutError::KillSwitchEngaged { .. }
+            | PayoutError::SignerUnavailable(_)
+            | PayoutError::NonceStore(_) => true,
+            // Internal error and request limit exceeded, as returned by
+            // overloaded or rate-limiting nodes
+            PayoutError::Rpc { code, .. } => matches!(code, -32603 | -32005),
+            _ => false,
+        }
+    }
+
+    /// Short name of the kind of error, as recorded in a payout's attempts
+    pub fn class(&self) -> &'static str {
+        match self {
+            PayoutError::InvalidDestination(_) => "invalid_destination",
+            PayoutError::InvalidStreamToken { .. } => "invalid_stream_token",
+            PayoutError::UnknownTokenAddress { .. } => "unknown_token_address",
+            PayoutError::AmbiguousTokenAddress { .. } => "ambiguous_token_address",
+            PayoutError::SignerUnavailable(_) => "signer_unavailable",
+            PayoutError::KillSwitchEngaged { .. } => "kill_switch_engaged",
+            PayoutError::RecipientInvalid { .. } => "recipient_invalid",
+            PayoutError::SequenceOutOfWindow { .. } => "sequence_out_of_window",
+            PayoutError::LimitExceeded { .. } => "limit_exceeded",
+            PayoutError::Rejected { .. } => "rejected",
+            PayoutError::InvalidPaymentId { .. } => "invalid_payment_id",
+            PayoutError::MemoTooLong { .. } => "memo_too_long",
+            PayoutError::ZeroAmount { .. } => "zero_amount",
+            PayoutError::RoundedToZero { .. } => "rounded_to_zero",
+            PayoutError::GasCostTooHigh { .. } => "gas_cost_too_high",
+            PayoutError::StaleConnection { .. } => "stale_connection",
+            PayoutError::RecipientBlocked { .. } => "recipient_blocked",
+            PayoutError::RecipientHeld { .. } => "recipient_held",
+            PayoutError::NotAuthorized { .. } => "not_authorized",
+            PayoutError::Rpc { .. } => "rpc",
+            PayoutError::Reverted(_) => "reverted",
+            PayoutError::PartialPayout { .. } => "partial_payout",
+            PayoutError::StaleRate { .. } => "stale_rate",
+            PayoutError::Abandoned { .. } => "abandoned",
+            PayoutError::Throttled { .. } => "throttled",
+            PayoutError::Panicked(_) => "panicked",
+            PayoutError::Cancelled => "cancelled",
+            PayoutError::TimedOut { .. } => "timed_out",
+            PayoutError::SubmissionUnverified { .. } => "submission_unverified",
+            PayoutError::Transport(_) => "transport",
+            PayoutError::InvalidResponse(_) => "invalid_response",
+            PayoutError::StoreWrite(_) => "store_write",
+            PayoutError::NonceStore(_) => "nonce_store",
+            PayoutError::Config(_) => "config",
+            PayoutError::InvalidOperatorKey(_) => "invalid_operator_key",
+            PayoutError::InAsyncContext => "in_async_context",
+        }
+    }
+}
+
+impl From<reqwest::Error> for PayoutError {
+    fn from(err: reqwest::Error) -> Self {
+        PayoutError::Transport(err.to_string())
+    }
+}
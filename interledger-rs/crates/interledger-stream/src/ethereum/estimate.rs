@@ -0,0 +1,233 @@
+//! Gas limits of contract payouts, estimated once per calldata shape
+//!
+//! Payouts of one asset through the same contract function cost about the
+//! same gas, so with `gas_estimate_ttl` set, Treasury and direct transfer
+//! payouts share an estimate per shape until it expires. Whether the
+//! recipient is a contract is part of the shape, since a token may call into
+//! it. A mined payout that used more gas than its shape's estimate drops the
+//! estimate, so the next payout of that shape estimates again.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::store::Timestamp;
+use super::{metrics, EthereumPayoutService, PayoutError, PayoutPlan};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// Percentage added to an estimate for the differences between payouts of a shape
+pub(super) const GAS_ESTIMATE_HEADROOM_PERCENT: u64 = 20;
+
+/// What a payout's gas depends on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct EstimateKey {
+    chain_id: u64,
+    /// Lowercase address of the called contract
+    to: String,
+    /// First four bytes of the calldata
+    selector: String,
+    recipient_is_contract: bool,
+    asset_code: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CachedEstimate {
+    gas: u64,
+    estimated_at: Timestamp,
+}
+
+/// How often payouts found a cached estimate
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GasEstimateStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Estimates dropped because a payout used more gas
+    pub invalidations: u64,
+}
+
+impl GasEstimateStats {
+    /// Share of lookups answered from the cache, `None` before the first one
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct GasEstimateCache {
+    entries: HashMap<EstimateKey, CachedEstimate>,
+    stats: GasEstimateStats,
+}
+
+impl EthereumPayoutService {
+    /// Gas limit of a contract payout, from its shape's estimate while fresh
+    pub(super) async fn estimated_gas_limit(&self, plan: &PayoutPlan) -> Result<u64, PayoutError> {
+        let tenant = self.config.tenant.as_deref();
+        let key = EstimateKey {
+            chain_id: self.config.expected_chain_id,
+            to: plan.to.to_lowercase(),
+            selector: plan.data.get(..10).unwrap_or(&plan.data).to_lowercase(),
+            recipient_is_contract: self
+                .recipient_is_contract(&plan.destination.recipient)
+                .await?,
+            asset_code: plan.destination.asset_code.clone(),
+        };
+        if let Some(gas) = self.cached_estimate(&key) {
+            metrics::gas_estimate(tenant, "hit");
+            return Ok(with_headroom(gas));
+        }
+        metrics::gas_estimate(tenant, "miss");
+        let gas = self.estimate_gas(plan).await?;
+        let mut cache = self.gas_estimates.lock().unwrap();
+        cache.entries.insert(
+            key,
+            CachedEstimate {
+                gas,
+                estimated_at: self.clock.now(),
+            },
+        );
+        Ok(with_headroom(gas))
+    }
+
+    fn cached_estimate(&self, key: &EstimateKey) -> Option<u64> {
+        let ttl = chrono::Duration::from_std(self.config.gas_estimate_ttl?).ok()?;
+        let mut cache = self.gas_estimates.lock().unwrap();
+        let fresh = cache
+            .entries
+            .get(key)
+            .filter(|cached| self.clock.now() - cached.estimated_at < ttl)
+            .map(|cached| cached.gas);
+        if fresh.is_some() {
+            cache.stats.hits += 1;
+        } else {
+            cache.stats.misses += 1;
+        }
+        fresh
+    }
+
+    pub(super) async fn estimate_gas(&self, plan: &PayoutPlan) -> Result<u64, PayoutError> {
+        let estimate = self
+            .rpc(rpc_request(
+                "eth_estimateGas",
+                json!([{
+                    "from": self.operator_address(),
+                    "to": &plan.to,
+                    "value": format!("0x{:x}", plan.value),
+                    "data": &plan.data,
+                }]),
+            ))
+            .await?;
+        parse_quantity(&estimate)
+    }
+
+    /// Drop the estimates of `asset_code` that a mined payout exceeded
+    pub(super) fn observe_gas_used(&self, asset_code: &str, gas_used: u64) {
+        let chain_id = self.config.expected_chain_id;
+        let mut cache = self.gas_estimates.lock().unwrap();
+        let before = cache.entries.len();
+        cache.entries.retain(|key, cached| {
+            key.chain_id != chain_id || key.asset_code != asset_code || cached.gas >= gas_used
+        });
+        let dropped = (before - cache.entries.len()) as u64;
+        if dropped > 0 {
+            cache.stats.invalidations += dropped;
+            metrics::gas_estimate(self.config.tenant.as_deref(), "invalidated");
+        }
+    }
+
+    /// Hits and misses of the gas estimate cache since the service started
+    pub fn gas_estimate_stats(&self) -> GasEstimateStats {
+        self.gas_estimates.lock().unwrap().stats
+    }
+}
+
+fn with_headroom(gas: u64) -> u64 {
+    gas.saturating_add(gas.saturating_mul(GAS_ESTIMATE_HEADROOM_PERCENT) / 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn estimating_service() -> (EthereumPayoutService, Arc<MockTransport>) {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_result("eth_getCode", json!("0x"));
+        transport.on_result("eth_estimateGas", json!("0xc350"));
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        let mut config = test_config();
+        config.gas_estimate_ttl = Some(Duration::from_secs(300));
+        (test_service(config, transport.clone()), transport)
+    }
+
+    #[tokio::test]
+    async fn payouts_of_one_shape_share_an_estimate() {
+        let (service, transport) = estimating_service();
+        for sequence in 1..=5 {
+            service
+                .execute_payout(TEST_DESTINATION, 100 + sequence, sequence)
+                .await
+                .unwrap();
+        }
+        assert_eq!(transport.call_count("eth_estimateGas"), 1);
+        let sent = transport.sent_transactions();
+        // 50000 estimated plus 20%
+        assert!(sent.iter().all(|tx| tx["gas"] == "0xea60"));
+        let stats = service.gas_estimate_stats();
+        assert_eq!((stats.hits, stats.misses), (4, 1));
+        assert_eq!(stats.hit_rate(), Some(0.8));
+    }
+
+    #[tokio::test]
+    async fn receipt_above_the_estimate_busts_it() {
+        let chain = deployed_chain();
+        let mut config = test_config();
+        config.gas_estimate_ttl = Some(Duration::from_secs(300));
+        let service = test_service(config, chain.clone());
+        let settle = |sequence| {
+            chain.advance_block();
+            let payment_id = service.config().payment_id(TEST_DESTINATION, sequence);
+            let service = &service;
+            async move { service.refresh_receipt(&payment_id).await.unwrap().unwrap() }
+        };
+
+        // Within the estimate nothing changes
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(settle(1).await.gas_used, Some(50_000));
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(chain.call_count("eth_estimateGas"), 1);
+
+        chain.set_gas_used(50_001);
+        settle(2).await;
+        assert_eq!(service.gas_estimate_stats().invalidations, 1);
+
+        chain.set_gas_used(70_000);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 3)
+            .await
+            .unwrap();
+        assert_eq!(chain.call_count("eth_estimateGas"), 2);
+        let sent = chain.sent_transactions();
+        assert_eq!(sent[2]["gas"], "0x14820");
+    }
+
+    #[test]
+    fn headroom_saturates_on_absurd_estimates() {
+        assert_eq!(with_headroom(50_000), 60_000);
+        assert_eq!(with_headroom(u64::MAX), u64::MAX);
+    }
+}
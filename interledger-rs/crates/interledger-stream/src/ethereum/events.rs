@@ -0,0 +1,326 @@
+//! Payout lifecycle events for subscribers
+//!
+//! [`EthereumPayoutService::subscribe`] returns a receiver of a broadcast
+//! channel keeping the last `event_channel_capacity` events. A receiver that
+//! falls further behind loses the oldest events and is told how many with
+//! `RecvError::Lagged`; publishing never waits for receivers, so a slow
+//! subscriber cannot hold up payouts. Every event is stamped with the time and
+//! a serial it was published at, the serial ordering events even when the
+//! wall clock steps back.
+
+use super::{
+    ChildSummary, CompositeStatus, EthereumPayoutService, PayoutStatus, RetryAttempt, Timestamp,
+};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+/// Something that happened to a payout, identified by its hex payment ID,
+/// or to the service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayoutEvent {
+    /// Accepted by `dispatch_payout`, before it runs
+    Enqueued {
+        payment_id: String,
+    },
+    Submitted {
+        payment_id: String,
+        tx_hash: String,
+        /// ID the Treasury assigned to the payout, see `payout_returns_id`
+        treasury_payout_id: Option<String>,
+    },
+    Confirmed {
+        payment_id: String,
+        block_number: u64,
+    },
+    /// Not paid, or reverted on-chain
+    Failed {
+        payment_id: String,
+        reason: String,
+    },
+    Skipped {
+        payment_id: String,
+        reason: String,
+    },
+    /// An attempt failed; the payout is tried again after the attempt's
+    /// delay, or was given up on without one
+    AttemptFailed {
+        payment_id: String,
+        attempt: RetryAttempt,
+    },
+    /// Every chunk of a split payout is terminal; never `Pending`
+    Composite {
+        payment_id: String,
+        status: CompositeStatus,
+        children: Vec<ChildSummary>,
+    },
+    /// Later payouts are sent from `new_address`
+    OperatorRotated {
+        old_address: String,
+        new_address: String,
+    },
+}
+
+impl PayoutEvent {
+    /// Payment ID of the payout the event is about, `None` for service events
+    pub fn payment_id(&self) -> Option<&str> {
+        match self {
+            PayoutEvent::Enqueued { payment_id }
+            | PayoutEvent::Submitted { payment_id, .. }
+            | PayoutEvent::Confirmed { payment_id, .. }
+            | PayoutEvent::Failed { payment_id, .. }
+            | PayoutEvent::AttemptFailed { payment_id, .. }
+            | PayoutEvent::Composite { payment_id, .. }
+            | PayoutEvent::Skipped { payment_id, .. } => Some(payment_id),
+            PayoutEvent::OperatorRotated { .. } => None,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            PayoutEvent::Enqueued { payment_id } => {
+                json!({"event": "enqueued", "payment_id": payment_id})
+            }
+            PayoutEvent::Submitted {
+                payment_id,
+                tx_hash,
+                treasury_payout_id,
+            } => {
+                let mut json =
+                    json!({"event": "submitted", "payment_id": payment_id, "tx_hash": tx_hash});
+                if let Some(treasury_payout_id) = treasury_payout_id {
+                    json["treasury_payout_id"] = json!(treasury_payout_id);
+                }
+                json
+            }
+            PayoutEvent::Confirmed {
+                payment_id,
+                block_number,
+            } => json!({
+                "event": "confirmed",
+                "payment_id": payment_id,
+                "block_number": block_number,
+            }),
+            PayoutEvent::Failed { payment_id, reason } => {
+                json!({"event": "failed", "payment_id": payment_id, "reason": reason})
+            }
+            PayoutEvent::Skipped { payment_id, reason } => {
+                json!({"event": "skipped", "payment_id": payment_id, "reason": reason})
+            }
+            PayoutEvent::AttemptFailed {
+                payment_id,
+                attempt,
+            } => {
+                let mut json = attempt.to_json();
+                json["event"] = json!("attempt_failed");
+                json["payment_id"] = json!(payment_id);
+                json
+            }
+            PayoutEvent::Composite {
+                payment_id,
+                status,
+                children,
+            } => json!({
+                "event": "composite",
+                "payment_id": payment_id,
+                "status": status.as_str(),
+                "settled": children
+                    .iter()
+                    .filter(|child| child.status == PayoutStatus::Confirmed)
+                    .count(),
+                "total": children.len(),
+                "children": children.iter().map(ChildSummary::to_json).collect::<Vec<_>>(),
+            }),
+            PayoutEvent::OperatorRotated {
+                old_address,
+                new_address,
+            } => json!({
+                "event": "operator_rotated",
+                "old_address": old_address,
+                "new_address": new_address,
+            }),
+        }
+    }
+}
+
+/// An event with when it was published
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StampedEvent {
+    pub event: PayoutEvent,
+    pub timestamp: Timestamp,
+    /// Position among the service's records and events
+    pub serial: u64,
+    /// ILP destination of the payout the event is about as the
+    /// [`DestinationPolicy`](super::DestinationPolicy) shows it, `None` for
+    /// service events
+    pub destination: Option<String>,
+    /// ILP sequence of the payout the event is about
+    pub sequence: Option<u64>,
+}
+
+impl StampedEvent {
+    pub fn to_json(&self) -> Value {
+        let mut json = self.event.to_json();
+        json["timestamp"] = json!(self.timestamp.to_rfc3339());
+        json["serial"] = json!(self.serial);
+        if let (Some(destination), Some(sequence)) = (&self.destination, self.sequence) {
+            json["destination"] = json!(destination);
+            json["sequence"] = json!(sequence);
+        }
+        json
+    }
+}
+
+impl EthereumPayoutService {
+    /// Receive the events of payouts from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<StampedEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish an event about the service rather than a payout
+    pub(super) fn publish(&self, event: PayoutEvent) {
+        self.send_event(event, None);
+    }
+
+    /// Publish an event about the payout of `sequence` to `destination`
+    pub(super) fn publish_payout(&self, destination: &str, sequence: u64, event: PayoutEvent) {
+        self.send_event(event, Some((destination.to_string(), sequence)));
+    }
+
+    fn send_event(&self, event: PayoutEvent, payout: Option<(String, u64)>) {
+        let (event, payout) = match payout {
+            Some((destination, sequence)) => {
+                let display = self.display();
+                let event = event.scrubbed(display, &destination);
+                (event, Some((display.render(&destination), sequence)))
+            }
+            None => (event, None),
+        };
+        let (destination, sequence) = payout.unzip();
+        let event = StampedEvent {
+            event,
+            timestamp: self.clock.now(),
+            serial: self.next_serial(),
+            destination,
+            sequence,
+        };
+        // Only fails while nobody is subscribed
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::PayoutRequest;
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+    fn chain() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x7", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        transport
+    }
+
+    #[tokio::test]
+    async fn events_follow_each_payout() {
+        let service = Arc::new(test_service(test_config(), chain()));
+        let mut events = service.subscribe();
+        let id = |sequence| {
+            format!(
+                "0x{}",
+                hex::encode(service.config().payment_id(TEST_DESTINATION, sequence))
+            )
+        };
+
+        service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 1))
+            .await;
+        service
+            .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 0, 2))
+            .await;
+        service
+            .dispatch_payout(PayoutRequest::new("example.eth.USDC.0x123", 100, 3))
+            .await;
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        service.refresh_receipt(&payment_id).await.unwrap();
+
+        let mut received = Vec::new();
+        let mut stamps = Vec::new();
+        while let Ok(stamped) = events.try_recv() {
+            received.push(stamped.event.clone());
+            stamps.push(stamped);
+        }
+        assert!(stamps
+            .windows(2)
+            .all(|pair| pair[0].serial < pair[1].serial));
+        let shown = service.display_destination(TEST_DESTINATION);
+        assert_eq!(stamps[0].destination.as_deref(), Some(shown.as_str()));
+        assert_eq!(stamps[3].to_json()["sequence"], 2);
+        assert_eq!(received.len(), 7, "{:?}", received);
+        assert_eq!(
+            received[..4],
+            [
+                PayoutEvent::Enqueued { payment_id: id(1) },
+                PayoutEvent::Submitted {
+                    payment_id: id(1),
+                    tx_hash: "0xabc".to_string(),
+                    treasury_payout_id: None,
+                },
+                PayoutEvent::Enqueued { payment_id: id(2) },
+                PayoutEvent::Skipped {
+                    payment_id: id(2),
+                    reason: "zero amount".to_string()
+                },
+            ]
+        );
+        assert!(matches!(received[5], PayoutEvent::Failed { .. }));
+        assert_eq!(
+            received[6],
+            PayoutEvent::Confirmed {
+                payment_id: id(1),
+                block_number: 7
+            }
+        );
+        assert_eq!(received[6].to_json()["event"], "confirmed");
+    }
+
+    #[tokio::test]
+    async fn lagging_receivers_lose_the_oldest_events() {
+        let mut config = test_config();
+        config.event_channel_capacity = 2;
+        let transport = chain();
+        let service = Arc::new(test_service(config, transport.clone()));
+        let mut events = service.subscribe();
+
+        for sequence in 1..=3 {
+            service
+                .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, sequence))
+                .await;
+        }
+        // Payouts went ahead regardless
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 3);
+
+        assert!(matches!(events.recv().await, Err(RecvError::Lagged(4))));
+        let id = format!(
+            "0x{}",
+            hex::encode(service.config().payment_id(TEST_DESTINATION, 3))
+        );
+        assert_eq!(
+            events.recv().await.unwrap().event,
+            PayoutEvent::Enqueued {
+                payment_id: id.clone()
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap().event.payment_id(),
+            Some(id.as_str())
+        );
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
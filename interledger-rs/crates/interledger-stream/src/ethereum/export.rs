@@ -0,0 +1,280 @@
+//! CSV export of payout history for accounting
+
+use super::store::{PageCursor, PayoutStatus, PayoutStore, Timestamp};
+use std::io::{self, Write};
+
+/// Number of records fetched from the store per page while exporting
+const EXPORT_PAGE_SIZE: usize = 500;
+
+const CSV_HEADER: [&str; 9] = [
+    "payment_id",
+    "recipient",
+    "asset",
+    "amount",
+    "tx_hash",
+    "block_number",
+    "timestamp",
+    "gas_cost_wei",
+    "gas_cost_by_tag",
+];
+
+/// Write all confirmed payouts with `from <= timestamp < to` as RFC 4180 CSV.
+///
+/// Records are read from the store page by page so arbitrarily large ranges
+/// can be exported without holding them all in memory. Returns the number of
+/// data rows written (excluding the header).
+pub fn export_csv<W: Write>(
+    store: &dyn PayoutStore,
+    writer: &mut W,
+    from: Timestamp,
+    to: Timestamp,
+) -> io::Result<usize> {
+    write_row(writer, &CSV_HEADER)?;
+
+    let mut rows = 0;
+    let mut cursor: Option<PageCursor> = None;
+    loop {
+        let page = store.list_range(from, to, cursor, EXPORT_PAGE_SIZE);
+        for record in page.iter().filter(|r| r.status == PayoutStatus::Confirmed) {
+            let amount = format_amount(record.amount, record.decimals);
+            let block = record.block_number.map(|b| b.to_string());
+            let gas = record.gas_cost.map(|g| g.to_string());
+            let by_tag = record.attribution.render(record.gas_cost);
+            write_row(
+                writer,
+                &[
+                    &record.payment_id_hex(),
+                    &record.recipient,
+                    &record.asset_code,
+                    &amount,
+                    record.tx_hash.as_deref().unwrap_or(""),
+                    block.as_deref().unwrap_or(""),
+                    &record.timestamp.to_rfc3339(),
+                    gas.as_deref().unwrap_or(""),
+                    &by_tag,
+                ],
+            )?;
+            rows += 1;
+        }
+        if page.len() < EXPORT_PAGE_SIZE {
+            break;
+        }
+        cursor = page.last().map(PageCursor::from);
+    }
+    writer.flush()?;
+    Ok(rows)
+}
+
+/// Render a base-unit amount as a decimal string, e.g. 1500000 with 6 decimals is "1.500000"
+pub fn format_amount(amount: u64, decimals: u8) -> String {
+    if decimals == 0 {
+        return amount.to_string();
+    }
+    let digits = format!("{:0>width$}", amount, width = decimals as usize + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals as usize);
+    format!("{}.{}", whole, fraction)
+}
+
+fn write_row<W: Write>(writer: &mut W, fields: &[&str]) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write_field(writer, field)?;
+    }
+    writer.write_all(b"\r\n")
+}
+
+/// Quote a field if it contains a delimiter, quote or line break, doubling embedded quotes
+fn write_field<W: Write>(writer: &mut W, field: &str) -> io::Result<()> {
+    if field.contains([',', '"', '\r', '\n']) {
+        writer.write_all(b"\"")?;
+        writer.write_all(field.replace('"', "\"\"").as_bytes())?;
+        writer.write_all(b"\"")
+    } else {
+        writer.write_all(field.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::attribution::attribution_of;
+    use super::super::store::{InMemoryPayoutStore, PayoutRecord};
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, TEST_DESTINATION,
+    };
+    use super::super::{Clock, PhaseTimings};
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+    use std::time::Duration;
+
+    fn synthetic_record(i: u32) -> PayoutRecord {
+        let mut payment_id = [0u8; 32];
+        payment_id[..4].copy_from_slice(&i.to_be_bytes());
+        PayoutRecord {
+            payment_id,
+            namespace: None,
+            payment_id_strategy: None,
+            attribution: match i % 3 {
+                0 => Default::default(),
+                _ => attribution_of(&[("sender, a", 1), ("sender b", u64::from(i % 3))]),
+            },
+            destination: format!("test.receiver.eth.31337.EURC.0x{:040x}.token{}", i, i),
+            sequence: i as u64,
+            recipient: format!("0x{:040x}", i),
+            // Exercise quoting with awkward asset codes
+            asset_code: match i % 4 {
+                0 => "EURC".to_string(),
+                1 => "EU,RC".to_string(),
+                2 => "say \"hi\"".to_string(),
+                _ => "multi\r\nline".to_string(),
+            },
+            asset_segment: None,
+            amount: i as u64 * 1_234_567,
+            decimals: (i % 19) as u8,
+            tx_hash: Some(format!("0x{:064x}", i)),
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: if i.is_multiple_of(7) {
+                None
+            } else {
+                Some(i as u64 + 100)
+            },
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: Some(i as u128 * 21_000_000_000_000),
+            l1_fee: None,
+            conversion: None,
+            memo: None,
+            status: if i % 10 == 9 {
+                PayoutStatus::Failed
+            } else {
+                PayoutStatus::Confirmed
+            },
+            deadline: None,
+            last_error: None,
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: Utc.timestamp_opt(1_700_000_000 + i as i64 * 60, 0).unwrap(),
+            serial: u64::from(i) + 1,
+            pinned_block: None,
+            treasury_payout_id: None,
+        }
+    }
+
+    #[test]
+    fn formats_amounts_with_decimals() {
+        assert_eq!(format_amount(1_500_000, 6), "1.500000");
+        assert_eq!(format_amount(9, 2), "0.09");
+        assert_eq!(format_amount(0, 3), "0.000");
+        assert_eq!(format_amount(42, 0), "42");
+        assert_eq!(format_amount(u64::MAX, 18), "18.446744073709551615");
+    }
+
+    #[test]
+    fn quotes_fields_per_rfc4180() {
+        let mut out = Vec::new();
+        write_row(&mut out, &["plain", "a,b", "say \"hi\"", "x\ny"]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "plain,\"a,b\",\"say \"\"hi\"\"\",\"x\ny\"\r\n"
+        );
+    }
+
+    #[test]
+    fn export_round_trips_through_csv_parser() {
+        let store = InMemoryPayoutStore::new();
+        let records: Vec<PayoutRecord> = (0..3000).map(synthetic_record).collect();
+        for record in &records {
+            store.save(record.clone());
+        }
+        // Export a window that excludes the first and last 100 records
+        let from = records[100].timestamp;
+        let to = records[2900].timestamp;
+
+        let mut out = Vec::new();
+        let written = export_csv(&store, &mut out, from, to).unwrap();
+
+        let expected: Vec<Vec<String>> = records[100..2900]
+            .iter()
+            .filter(|r| r.status == PayoutStatus::Confirmed)
+            .map(|r| {
+                vec![
+                    r.payment_id_hex(),
+                    r.recipient.clone(),
+                    r.asset_code.clone(),
+                    format_amount(r.amount, r.decimals),
+                    r.tx_hash.clone().unwrap_or_default(),
+                    r.block_number.map(|b| b.to_string()).unwrap_or_default(),
+                    r.timestamp.to_rfc3339(),
+                    r.gas_cost.map(|g| g.to_string()).unwrap_or_default(),
+                    r.attribution.render(r.gas_cost),
+                ]
+            })
+            .collect();
+        assert_eq!(written, expected.len());
+
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .from_reader(out.as_slice());
+        let headers: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        assert_eq!(headers, CSV_HEADER);
+        let parsed: Vec<Vec<String>> = reader
+            .records()
+            .map(|row| row.unwrap().iter().map(String::from).collect())
+            .collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[tokio::test]
+    async fn export_keeps_creation_order_when_the_clock_steps_back() {
+        let transport = mock_chain();
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x7", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let service = test_service(test_config(), transport).with_clock(clock.clone());
+        for sequence in 1..=3 {
+            service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .unwrap();
+            let payment_id = service.config().payment_id(TEST_DESTINATION, sequence);
+            service.refresh_receipt(&payment_id).await.unwrap();
+            // NTP steps the wall clock back between payouts
+            clock.rewind(Duration::from_secs(60));
+        }
+
+        let records: Vec<PayoutRecord> = (1..=3)
+            .map(|sequence| {
+                let payment_id = service.config().payment_id(TEST_DESTINATION, sequence);
+                service.store().get(&payment_id).unwrap()
+            })
+            .collect();
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].serial < pair[1].serial));
+        assert!(records[2].timestamp < records[0].timestamp);
+        let ids: Vec<String> = records.iter().map(PayoutRecord::payment_id_hex).collect();
+        let mut out = Vec::new();
+        let written = service
+            .export_csv(
+                &mut out,
+                start - chrono::Duration::hours(1),
+                start + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(written, 3);
+        let exported: Vec<String> = csv::Reader::from_reader(out.as_slice())
+            .records()
+            .map(|row| row.unwrap()[0].to_string())
+            .collect();
+        assert_eq!(exported, ids);
+    }
+}
@@ -0,0 +1,466 @@
+//! Payout metrics in the OpenMetrics text format
+//!
+//! Embedders that do not install a `metrics` recorder can still scrape the
+//! service: alongside the recorder it counts into its own atomics, rendered by
+//! [`EthereumPayoutService::metrics_text`]. Counters are allocated up front
+//! for every registered asset, so the payout path only increments atomics; an
+//! asset missing from the registry, whose payouts can only fail, is counted in
+//! a map taken under a lock. RPC requests are counted per endpoint, by the
+//! endpoint pool when there is one. Gauges are read when rendering.
+//!
+//! Metric names and the `asset`, `chain_id` and `endpoint` labels are stable;
+//! a service set up for a tenant adds a `tenant` label to every sample.
+
+use super::throttle::endpoint_label;
+use super::{AssetRegistry, EthereumPayoutService};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the histograms' buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Observations per bucket, the last one above every bound
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(
+            duration.as_micros().min(u128::from(u64::MAX)) as u64,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[derive(Default)]
+struct AssetCounters {
+    submitted: AtomicU64,
+    /// Base units of the asset in submitted payouts
+    submitted_amount: AtomicU64,
+    failed: AtomicU64,
+    confirmed: AtomicU64,
+    reverted: AtomicU64,
+    gas_cost_wei: AtomicU64,
+    /// Time from starting a payout to broadcasting it
+    submission: Histogram,
+}
+
+/// Name, help and field of a counter kept per asset
+type AssetCounter = (&'static str, &'static str, fn(&AssetCounters) -> &AtomicU64);
+
+/// Requests sent to one RPC endpoint
+#[derive(Default)]
+pub(super) struct EndpointCounters {
+    requests: AtomicU64,
+    /// Requests that failed or were answered with a JSON-RPC error
+    errors: AtomicU64,
+    latency: Histogram,
+    /// Pending nonces read lower than one read before
+    nonce_regressions: AtomicU64,
+}
+
+impl EndpointCounters {
+    pub(super) fn record(&self, latency: Duration, failed: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(latency);
+    }
+
+    pub(super) fn nonce_regression(&self) {
+        self.nonce_regressions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counters of a service, see the module documentation
+pub(super) struct PayoutCounters {
+    assets: HashMap<String, AssetCounters>,
+    /// Assets missing from the registry
+    unregistered: Mutex<HashMap<String, Arc<AssetCounters>>>,
+    /// Requests sent without an endpoint pool
+    pub(super) endpoint: EndpointCounters,
+}
+
+impl PayoutCounters {
+    pub(super) fn new(assets: &AssetRegistry) -> Self {
+        PayoutCounters {
+            assets: assets
+                .codes()
+                .map(|code| (code.to_string(), AssetCounters::default()))
+                .collect(),
+            unregistered: Mutex::default(),
+            endpoint: EndpointCounters::default(),
+        }
+    }
+
+    fn asset(&self, code: &str, count: impl FnOnce(&AssetCounters)) {
+        match self.assets.get(code) {
+            Some(counters) => count(counters),
+            None => {
+                let counters = self
+                    .unregistered
+                    .lock()
+                    .unwrap()
+                    .entry(code.to_string())
+                    .or_default()
+                    .clone();
+                count(&counters)
+            }
+        }
+    }
+
+    pub(super) fn submitted(&self, asset_code: &str, amount: u64, took: Duration) {
+        self.asset(asset_code, |counters| {
+            counters.submitted.fetch_add(1, Ordering::Relaxed);
+            counters
+                .submitted_amount
+                .fetch_add(amount, Ordering::Relaxed);
+            counters.submission.observe(took);
+        })
+    }
+
+    pub(super) fn failed(&self, asset_code: &str) {
+        self.asset(asset_code, |counters| {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    /// A payout's receipt was seen, `success` unless it reverted
+    pub(super) fn mined(&self, asset_code: &str, success: bool) {
+        self.asset(asset_code, |counters| {
+            let count = if success {
+                &counters.confirmed
+            } else {
+                &counters.reverted
+            };
+            count.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+
+    pub(super) fn gas_cost(&self, asset_code: &str, wei: u64) {
+        self.asset(asset_code, |counters| {
+            counters.gas_cost_wei.fetch_add(wei, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Escape a label value: backslash, double quote and line feed
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Text being rendered, with the labels every sample carries
+struct Exposition {
+    text: String,
+    common: Vec<(&'static str, String)>,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        self.text.push_str(name);
+        let common = self
+            .common
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()));
+        let mut labels = labels.iter().cloned().chain(common).peekable();
+        if labels.peek().is_some() {
+            let labels: Vec<String> = labels
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let mut count = 0;
+        for (bucket, observed) in histogram.buckets.iter().enumerate() {
+            count += observed.load(Ordering::Relaxed);
+            let bound = BUCKETS
+                .get(bucket)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let mut labels = labels.to_vec();
+            labels.push(("le", &bound));
+            self.sample(&format!("{}_bucket", name), &labels, count);
+        }
+        self.sample(&format!("{}_count", name), labels, count);
+        let micros = histogram.sum_micros.load(Ordering::Relaxed);
+        self.sample(
+            &format!("{}_sum", name),
+            labels,
+            micros as f64 / 1_000_000.0,
+        );
+    }
+}
+
+impl EthereumPayoutService {
+    /// The service's counters, gauges and histograms in the OpenMetrics text
+    /// exposition format, which Prometheus also reads
+    pub fn metrics_text(&self) -> String {
+        let mut out = Exposition {
+            text: String::new(),
+            common: vec![("chain_id", self.config.expected_chain_id.to_string())],
+        };
+        if let Some(tenant) = &self.config.tenant {
+            out.common.push(("tenant", tenant.clone()));
+        }
+
+        let unregistered = self.counters.unregistered.lock().unwrap().clone();
+        let mut assets: BTreeMap<&str, &AssetCounters> = self
+            .counters
+            .assets
+            .iter()
+            .map(|(code, counters)| (code.as_str(), counters))
+            .collect();
+        assets.extend(
+            unregistered
+                .iter()
+                .map(|(code, counters)| (code.as_str(), counters.as_ref())),
+        );
+        let counters: [AssetCounter; 6] = [
+            ("submitted", "Payout transactions broadcast", |c| {
+                &c.submitted
+            }),
+            (
+                "submitted_amount",
+                "Base units of the asset in broadcast payouts",
+                |c| &c.submitted_amount,
+            ),
+            ("failed", "Payouts that failed", |c| &c.failed),
+            ("confirmed", "Payouts mined successfully", |c| &c.confirmed),
+            ("reverted", "Payouts mined but reverted", |c| &c.reverted),
+            ("gas_cost_wei", "Wei paid in gas by mined payouts", |c| {
+                &c.gas_cost_wei
+            }),
+        ];
+        for (name, help, counter) in counters.iter() {
+            let name = format!("ethereum_payouts_{}", name);
+            out.family(&name, "counter", help);
+            for (asset, counters) in &assets {
+                let value = counter(counters).load(Ordering::Relaxed);
+                out.sample(&format!("{}_total", name), &[("asset", asset)], value);
+            }
+        }
+        let name = "ethereum_payout_submission_seconds";
+        out.family(
+            name,
+            "histogram",
+            "Time from starting a payout to broadcasting it",
+        );
+        for (asset, counters) in &assets {
+            out.histogram(name, &[("asset", asset)], &counters.submission);
+        }
+
+        let label = endpoint_label(&self.config.rpc_url);
+        let pooled: Vec<(&str, &EndpointCounters)> = match &self.endpoints {
+            Some(pool) => pool.counters().collect(),
+            None => vec![(&label, &self.counters.endpoint)],
+        };
+        out.family("ethereum_rpc_requests", "counter", "JSON-RPC requests sent");
+        for (endpoint, counters) in &pooled {
+            let requests = counters.requests.load(Ordering::Relaxed);
+            out.sample(
+                "ethereum_rpc_requests_total",
+                &[("endpoint", endpoint)],
+                requests,
+            );
+        }
+        out.family(
+            "ethereum_rpc_errors",
+            "counter",
+            "JSON-RPC requests that failed or were answered with an error",
+        );
+        for (endpoint, counters) in &pooled {
+            let errors = counters.errors.load(Ordering::Relaxed);
+            out.sample(
+                "ethereum_rpc_errors_total",
+                &[("endpoint", endpoint)],
+                errors,
+            );
+        }
+        out.family(
+            "ethereum_rpc_nonce_regressions",
+            "counter",
+            "Pending nonces read lower than one read before",
+        );
+        for (endpoint, counters) in &pooled {
+            let regressions = counters.nonce_regressions.load(Ordering::Relaxed);
+            out.sample(
+                "ethereum_rpc_nonce_regressions_total",
+                &[("endpoint", endpoint)],
+                regressions,
+            );
+        }
+        let name = "ethereum_rpc_request_seconds";
+        out.family(
+            name,
+            "histogram",
+            "Time until a JSON-RPC request was answered",
+        );
+        for (endpoint, counters) in &pooled {
+            out.histogram(name, &[("endpoint", endpoint)], &counters.latency);
+        }
+
+        let gauges = [
+            (
+                "deferred",
+                "Payouts deferred until the Treasury is unpaused or the signer responds",
+                self.deferred_count() as u64,
+            ),
+            (
+                "retry_queue",
+                "Payouts waiting to be retried",
+                self.retry_queue_len() as u64,
+            ),
+            (
+                "paused",
+                "Whether the Treasury was paused when last checked",
+                u64::from(self.is_paused()),
+            ),
+            (
+                "read_only",
+                "Whether payouts are held because the signer is unavailable",
+                u64::from(self.is_read_only()),
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let name = format!("ethereum_payouts_{}", name);
+            out.family(&name, "gauge", help);
+            out.sample(&name, &[], value);
+        }
+        out.text.push_str("# EOF\n");
+        out.text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{EndpointPool, PayoutOutcome, RpcTransport};
+    use super::*;
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.lines().collect()
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label_value("plain"), "plain");
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[tokio::test]
+    async fn counts_payouts_per_asset() {
+        let transport = mock_chain();
+        let service = test_service(test_config(), transport.clone());
+        for sequence in 1..=2 {
+            assert!(matches!(
+                service
+                    .execute_payout(TEST_DESTINATION, 100, sequence)
+                    .await
+                    .unwrap(),
+                PayoutOutcome::Submitted { .. }
+            ));
+        }
+        transport.on_error("eth_sendRawTransaction", -32000, "insufficient funds");
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 3)
+            .await
+            .is_err());
+
+        let text = service.metrics_text();
+        let lines = lines(&text);
+        let chain_id = service.config().expected_chain_id;
+        let sample = |name: &str, value: &str| {
+            format!(
+                "ethereum_payouts_{}_total{{asset=\"EURC\",chain_id=\"{}\"}} {}",
+                name, chain_id, value
+            )
+        };
+        assert!(lines.contains(&"# TYPE ethereum_payouts_submitted counter"));
+        assert!(lines.contains(&sample("submitted", "2").as_str()));
+        assert!(lines.contains(&sample("submitted_amount", "200").as_str()));
+        assert!(lines.contains(&sample("failed", "1").as_str()));
+        assert!(lines.contains(&sample("confirmed", "0").as_str()));
+        let bucket = format!(
+            "ethereum_payout_submission_seconds_bucket{{asset=\"EURC\",le=\"+Inf\",chain_id=\"{}\"}} 2",
+            chain_id
+        );
+        assert!(lines.contains(&bucket.as_str()), "{}", text);
+
+        // Every request went to the single configured endpoint
+        let endpoint = format!(
+            "ethereum_rpc_requests_total{{endpoint=\"{}\",chain_id=\"{}\"}} {}",
+            endpoint_label(&service.config().rpc_url),
+            chain_id,
+            transport.request_count()
+        );
+        assert!(lines.contains(&endpoint.as_str()), "{}", text);
+        let errors = format!(
+            "ethereum_rpc_errors_total{{endpoint=\"{}\",chain_id=\"{}\"}} 1",
+            endpoint_label(&service.config().rpc_url),
+            chain_id
+        );
+        assert!(lines.contains(&errors.as_str()), "{}", text);
+        assert!(lines.contains(
+            &format!("ethereum_payouts_deferred{{chain_id=\"{}\"}} 0", chain_id).as_str()
+        ));
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+
+    #[tokio::test]
+    async fn labels_pooled_endpoints_verbatim() {
+        let transport = mock_chain();
+        let pool = EndpointPool::new(vec![(
+            "node \"a\"\\1\n".to_string(),
+            transport.clone() as Arc<dyn RpcTransport>,
+        )]);
+        let mut config = test_config();
+        config.tenant = Some("shop\"1".to_string());
+        let service = test_service(config, transport.clone()).with_endpoint_pool(Arc::new(pool));
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        let text = service.metrics_text();
+        let chain_id = service.config().expected_chain_id;
+        let requests = format!(
+            "ethereum_rpc_requests_total{{endpoint=\"node \\\"a\\\"\\\\1\\n\",chain_id=\"{}\",tenant=\"shop\\\"1\"}} {}",
+            chain_id,
+            transport.request_count()
+        );
+        assert!(lines(&text).contains(&requests.as_str()), "{}", text);
+        // Requests through the pool are not counted twice
+        assert_eq!(text.matches("ethereum_rpc_requests_total{").count(), 1);
+    }
+}
@@ -0,0 +1,909 @@
+//! Programmable chain state for payout lifecycle tests
+//!
+//! [`FakeChain`] answers the RPC methods the payout service uses from a small
+//! model of a chain: account nonces and balances, a pending pool and mined
+//! blocks. Sent transactions stay pending until [`FakeChain::advance_block`]
+//! mines them, so tests decide how long mining takes. Reverts, dropped
+//! transactions and reorgs are set up per test, and receipts, blocks and logs
+//! always agree with the resulting state. Unreliable nodes are modelled by
+//! failing methods with [`FakeChain::fail`] and [`FakeChain::fail_next`], and
+//! a node refusing one payment with [`FakeChain::reject_payment`].
+//!
+//! Signed EIP-155 legacy transactions from `eth_sendRawTransaction` are
+//! decoded, their sender recovered and their real hash used; unsigned
+//! `eth_sendTransaction` submissions, as under `node_signing`, get a hash of
+//! their fields. Calls succeed without return data unless they revert.
+//! Methods outside the model can be answered with [`FakeChain::on`].
+
+use super::hash::keccak256;
+use super::rpc::parse_quantity;
+use super::testing::{decode_raw_transaction, sent_transactions};
+use super::{PayoutError, RpcTransport};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Balance of accounts not given one with [`FakeChain::set_balance`], 10000 ETH as on Anvil
+pub const FAKE_CHAIN_BALANCE: u128 = 10_000 * 1_000_000_000_000_000_000;
+
+/// Gas used by a plain value transfer
+const TRANSFER_GAS: u64 = 21_000;
+
+type Handler = Box<dyn Fn(&Value) -> Result<Value, Value> + Send + Sync>;
+
+/// Error answered to requests for a method, `remaining` times or until healed
+struct Fault {
+    remaining: Option<usize>,
+    error: Value,
+}
+
+#[derive(Debug, Clone)]
+struct FakeTx {
+    hash: String,
+    from: String,
+    to: String,
+    nonce: u64,
+    value: u128,
+    data: String,
+    gas: u64,
+    gas_price: u64,
+}
+
+#[derive(Debug, Clone)]
+struct MinedTx {
+    tx: FakeTx,
+    gas_used: u64,
+    success: bool,
+    /// Successful contract calls log one event, with the called selector as
+    /// its topic and the arguments as its data
+    logged: bool,
+}
+
+#[derive(Debug)]
+struct Block {
+    number: u64,
+    hash: String,
+    transactions: Vec<MinedTx>,
+}
+
+struct ChainState {
+    gas_price: u64,
+    gas_used: u64,
+    /// Nonces and balances before the first mined block, by lowercase address
+    base_nonces: HashMap<String, u64>,
+    base_balances: HashMap<String, u128>,
+    code: HashMap<String, String>,
+    pending: Vec<FakeTx>,
+    /// Mined blocks after genesis, oldest first
+    blocks: Vec<Block>,
+    /// Reorgs so far, so replacement blocks get new hashes
+    forks: u64,
+    /// Revert data of payment ids, in lowercase hex, whose calls and
+    /// transactions revert
+    reverting: HashMap<String, String>,
+    /// Payment ids, in lowercase hex, whose next submission is refused
+    rejecting: HashMap<String, Value>,
+    /// Whether receipts leave out `effectiveGasPrice`, as before London
+    legacy_receipts: bool,
+}
+
+/// In-memory chain implementing [`RpcTransport`]
+pub struct FakeChain {
+    chain_id: u64,
+    state: Mutex<ChainState>,
+    handlers: Mutex<HashMap<String, Handler>>,
+    faults: Mutex<HashMap<String, Fault>>,
+    delays: Mutex<HashMap<String, Duration>>,
+    calls: Mutex<Vec<Value>>,
+}
+
+impl FakeChain {
+    /// Chain at its genesis block with a gas price of 1 gwei
+    pub fn new(chain_id: u64) -> Arc<Self> {
+        Arc::new(FakeChain {
+            chain_id,
+            state: Mutex::new(ChainState {
+                gas_price: 1_000_000_000,
+                gas_used: 50_000,
+                base_nonces: HashMap::new(),
+                base_balances: HashMap::new(),
+                code: HashMap::new(),
+                pending: Vec::new(),
+                blocks: Vec::new(),
+                forks: 0,
+                reverting: HashMap::new(),
+                rejecting: HashMap::new(),
+                legacy_receipts: false,
+            }),
+            handlers: Mutex::new(HashMap::new()),
+            faults: Mutex::new(HashMap::new()),
+            delays: Mutex::new(HashMap::new()),
+            calls: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Answer `method` using `handler` instead of the chain state
+    pub fn on<F>(&self, method: &str, handler: F)
+    where
+        F: Fn(&Value) -> Result<Value, Value> + Send + Sync + 'static,
+    {
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), Box::new(handler));
+    }
+
+    /// Answer every request for `method` with `error` until [`FakeChain::heal`]
+    pub fn fail(&self, method: &str, error: Value) {
+        let fault = Fault {
+            remaining: None,
+            error,
+        };
+        self.faults
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), fault);
+    }
+
+    /// Answer the next `count` requests for `method` with `error`
+    pub fn fail_next(&self, method: &str, count: usize, error: Value) {
+        let mut faults = self.faults.lock().unwrap();
+        if count == 0 {
+            faults.remove(method);
+        } else {
+            let fault = Fault {
+                remaining: Some(count),
+                error,
+            };
+            faults.insert(method.to_string(), fault);
+        }
+    }
+
+    /// Wait `delay` before answering `method`, as a slow node would
+    pub fn delay(&self, method: &str, delay: Duration) {
+        self.delays
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), delay);
+    }
+
+    /// Stop failing `method`
+    pub fn heal(&self, method: &str) {
+        self.faults.lock().unwrap().remove(method);
+    }
+
+    /// Transactions `address` sent before the first mined block
+    pub fn set_nonce(&self, address: &str, nonce: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.base_nonces.insert(address.to_lowercase(), nonce);
+    }
+
+    /// Balance of `address` before the first mined block
+    pub fn set_balance(&self, address: &str, wei: u128) {
+        let mut state = self.state.lock().unwrap();
+        state.base_balances.insert(address.to_lowercase(), wei);
+    }
+
+    /// Deploy `code` at `address`, making it a contract
+    pub fn set_code(&self, address: &str, code: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.code.insert(address.to_lowercase(), code.to_string());
+    }
+
+    pub fn set_gas_price(&self, wei: u64) {
+        self.state.lock().unwrap().gas_price = wei;
+    }
+
+    /// Gas used by contract calls mined from now on, and what they estimate at
+    pub fn set_gas_used(&self, gas: u64) {
+        self.state.lock().unwrap().gas_used = gas;
+    }
+
+    /// Make calls and transactions carrying `payment_id` in their calldata
+    /// revert, without revert data. Transactions already pending revert when
+    /// mined.
+    pub fn revert_payment(&self, payment_id: &[u8; 32]) {
+        self.revert_payment_with(payment_id, "0x");
+    }
+
+    /// Make calls and transactions carrying `payment_id` revert with `data`,
+    /// e.g. a custom error
+    pub fn revert_payment_with(&self, payment_id: &[u8; 32], data: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .reverting
+            .insert(hex::encode(payment_id), data.to_string());
+    }
+
+    /// Refuse the next submission carrying `payment_id` in its calldata with
+    /// `error`, as a node checking it before accepting it would
+    pub fn reject_payment(&self, payment_id: &[u8; 32], error: Value) {
+        let mut state = self.state.lock().unwrap();
+        state.rejecting.insert(hex::encode(payment_id), error);
+    }
+
+    /// Serve receipts without `effectiveGasPrice`, as nodes did before London
+    pub fn set_legacy_receipts(&self, legacy: bool) {
+        self.state.lock().unwrap().legacy_receipts = legacy;
+    }
+
+    /// Evict a pending transaction from the pool, returning whether it was there
+    pub fn drop_transaction(&self, hash: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.pending.len();
+        state
+            .pending
+            .retain(|tx| !tx.hash.eq_ignore_ascii_case(hash));
+        state.pending.len() < before
+    }
+
+    /// Mine a block with every pending transaction, returning their hashes
+    pub fn advance_block(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut pending = std::mem::take(&mut state.pending);
+        pending.sort_by(|a, b| (&a.from, a.nonce).cmp(&(&b.from, b.nonce)));
+        let transactions: Vec<MinedTx> = pending
+            .into_iter()
+            .map(|tx| {
+                let success = state.revert_data(&tx.data).is_none();
+                let is_contract = state.is_contract(&tx.to);
+                let gas_used = if is_contract {
+                    state.gas_used
+                } else {
+                    TRANSFER_GAS
+                };
+                MinedTx {
+                    gas_used: gas_used.min(tx.gas),
+                    tx,
+                    success,
+                    logged: success && is_contract,
+                }
+            })
+            .collect();
+        let number = state.head() + 1;
+        let hash = self.block_hash(number, state.forks);
+        let hashes = transactions
+            .iter()
+            .map(|mined| mined.tx.hash.clone())
+            .collect();
+        state.blocks.push(Block {
+            number,
+            hash,
+            transactions,
+        });
+        hashes
+    }
+
+    /// Undo the last `depth` blocks, returning their transactions to the
+    /// pending pool to be mined again
+    pub fn reorg(&self, depth: u64) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..depth {
+            match state.blocks.pop() {
+                Some(block) => {
+                    let txs = block.transactions.into_iter().map(|mined| mined.tx);
+                    state.pending.extend(txs);
+                }
+                None => break,
+            }
+        }
+        state.forks += 1;
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.state.lock().unwrap().head()
+    }
+
+    /// Hashes of the transactions waiting to be mined
+    pub fn pending(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.pending.iter().map(|tx| tx.hash.clone()).collect()
+    }
+
+    /// Transactions of `address` mined so far, including those before genesis
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.state
+            .lock()
+            .unwrap()
+            .mined_nonce(&address.to_lowercase())
+    }
+
+    pub fn balance(&self, address: &str) -> u128 {
+        self.state.lock().unwrap().balance(&address.to_lowercase())
+    }
+
+    /// All request bodies received for `method`, in order
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call["method"] == method)
+            .cloned()
+            .collect()
+    }
+
+    pub fn call_count(&self, method: &str) -> usize {
+        self.calls(method).len()
+    }
+
+    /// Transactions received with `eth_sendRawTransaction`, in order
+    pub fn sent_transactions(&self) -> Vec<Value> {
+        sent_transactions(&self.calls("eth_sendRawTransaction"))
+    }
+
+    fn block_hash(&self, number: u64, fork: u64) -> String {
+        let mut preimage = b"fake-chain".to_vec();
+        preimage.extend_from_slice(&self.chain_id.to_be_bytes());
+        preimage.extend_from_slice(&number.to_be_bytes());
+        preimage.extend_from_slice(&fork.to_be_bytes());
+        format!("0x{}", hex::encode(keccak256(&preimage)))
+    }
+
+    fn answer(&self, request: &Value) -> Value {
+        self.calls.lock().unwrap().push(request.clone());
+        let method = request["method"].as_str().unwrap_or_default();
+        let params = &request["params"];
+        let outcome = match self.fault(method) {
+            Some(error) => Err(error),
+            None => match self.handlers.lock().unwrap().get(method) {
+                Some(handler) => handler(params),
+                None => self.call(method, params),
+            },
+        };
+        match outcome {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+        }
+    }
+
+    /// Error to answer a request for `method` with, if it is failing
+    fn fault(&self, method: &str) -> Option<Value> {
+        let mut faults = self.faults.lock().unwrap();
+        let fault = faults.get_mut(method)?;
+        let error = fault.error.clone();
+        match fault.remaining {
+            Some(1) => {
+                faults.remove(method);
+            }
+            Some(ref mut remaining) => *remaining -= 1,
+            None => {}
+        }
+        Some(error)
+    }
+
+    fn call(&self, method: &str, params: &Value) -> Result<Value, Value> {
+        let mut state = self.state.lock().unwrap();
+        let address = || params[0].as_str().unwrap_or_default().to_lowercase();
+        Ok(match method {
+            "eth_chainId" => quantity(self.chain_id),
+            "net_version" => json!(self.chain_id.to_string()),
+            "eth_blockNumber" => quantity(state.head()),
+            "eth_gasPrice" => quantity(state.gas_price),
+            "eth_getTransactionCount" => {
+                let address = address();
+                let mut nonce = state.mined_nonce(&address);
+                if params[1] == "pending" {
+                    nonce += state.pending.iter().filter(|tx| tx.from == address).count() as u64;
+                }
+                quantity(nonce)
+            }
+            "eth_getBalance" => json!(format!("0x{:x}", state.balance(&address()))),
+            "eth_getCode" => json!(state.code.get(&address()).map_or("0x", String::as_str)),
+            "eth_call" | "eth_estimateGas" => {
+                let data = params[0]["data"].as_str().unwrap_or_default();
+                if let Some(revert) = state.revert_data(data) {
+                    return Err(
+                        json!({"code": 3, "message": "execution reverted", "data": revert}),
+                    );
+                }
+                match method {
+                    "eth_call" => json!("0x"),
+                    _ => {
+                        let to = params[0]["to"].as_str().unwrap_or_default();
+                        quantity(if state.is_contract(to) {
+                            state.gas_used
+                        } else {
+                            TRANSFER_GAS
+                        })
+                    }
+                }
+            }
+            "eth_sendTransaction" => json!(state.submit(&params[0], None)?),
+            "eth_sendRawTransaction" => {
+                let raw = params[0].as_str().unwrap_or_default();
+                let tx = decode_raw_transaction(raw)
+                    .ok_or_else(|| invalid_params("invalid raw transaction"))?;
+                let hash = hex::decode(&raw[2..]).map_err(invalid_params)?;
+                let hash = format!("0x{}", hex::encode(keccak256(&hash)));
+                json!(state.submit(&tx, Some(hash))?)
+            }
+            "eth_getTransactionByHash" => {
+                let hash = params[0].as_str().unwrap_or_default();
+                match state
+                    .pending
+                    .iter()
+                    .find(|tx| tx.hash.eq_ignore_ascii_case(hash))
+                {
+                    Some(tx) => tx.to_json(None),
+                    None => state
+                        .find_mined(hash)
+                        .map_or(Value::Null, |(block, index)| {
+                            block.transactions[index].tx.to_json(Some((block, index)))
+                        }),
+                }
+            }
+            "eth_getTransactionReceipt" => state
+                .find_mined(params[0].as_str().unwrap_or_default())
+                .map_or(Value::Null, |(block, index)| {
+                    receipt(block, index, state.legacy_receipts)
+                }),
+            "eth_getBlockByNumber" => {
+                let number = match &params[0] {
+                    tag if tag == "latest" || tag == "pending" => state.head(),
+                    tag if tag == "earliest" => 0,
+                    number => parse_quantity(number).map_err(invalid_params)?,
+                };
+                let full = params[1].as_bool().unwrap_or(false);
+                self.block_json(&state, number, full)
+            }
+            "eth_getLogs" => json!(state.logs(&params[0]).map_err(invalid_params)?),
+            _ => return Err(json!({ "code": -32601, "message": "Method not found" })),
+        })
+    }
+
+    fn block_json(&self, state: &ChainState, number: u64, full: bool) -> Value {
+        if number > state.head() {
+            return Value::Null;
+        }
+        let parent_hash = match number {
+            0 => format!("0x{:064x}", 0),
+            n => state
+                .block_hash(n - 1)
+                .unwrap_or_else(|| self.block_hash(0, 0)),
+        };
+        let (hash, transactions) = match state.blocks.iter().find(|b| b.number == number) {
+            Some(block) => {
+                let transactions = (0..block.transactions.len())
+                    .map(|index| match full {
+                        true => block.transactions[index].tx.to_json(Some((block, index))),
+                        false => json!(block.transactions[index].tx.hash),
+                    })
+                    .collect();
+                (block.hash.clone(), transactions)
+            }
+            None => (self.block_hash(0, 0), Vec::new()),
+        };
+        json!({
+            "number": format!("0x{:x}", number),
+            "hash": hash,
+            "parentHash": parent_hash,
+            "transactions": transactions,
+        })
+    }
+}
+
+#[async_trait]
+impl RpcTransport for FakeChain {
+    async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+        let delay = match &request {
+            Value::Array(batch) => batch.iter().collect(),
+            request => vec![request],
+        }
+        .iter()
+        .filter_map(|request| request["method"].as_str())
+        .filter_map(|method| self.delays.lock().unwrap().get(method).copied())
+        .max();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        match request {
+            Value::Array(batch) => Ok(batch.iter().map(|member| self.answer(member)).collect()),
+            request => Ok(self.answer(&request)),
+        }
+    }
+}
+
+impl ChainState {
+    fn head(&self) -> u64 {
+        self.blocks.last().map_or(0, |block| block.number)
+    }
+
+    fn block_hash(&self, number: u64) -> Option<String> {
+        self.blocks
+            .iter()
+            .find(|block| block.number == number)
+            .map(|block| block.hash.clone())
+    }
+
+    /// Revert data of a call or transaction with calldata `data`, if it reverts
+    fn revert_data(&self, data: &str) -> Option<String> {
+        let data = data.to_lowercase();
+        self.reverting
+            .iter()
+            .find(|(id, _)| data.contains(id.as_str()))
+            .map(|(_, revert)| revert.clone())
+    }
+
+    fn mined(&self) -> impl Iterator<Item = &MinedTx> {
+        self.blocks.iter().flat_map(|block| &block.transactions)
+    }
+
+    fn mined_nonce(&self, address: &str) -> u64 {
+        let base = self.base_nonces.get(address).copied().unwrap_or(0);
+        base + self
+            .mined()
+            .filter(|mined| mined.tx.from == address)
+            .count() as u64
+    }
+
+    fn balance(&self, address: &str) -> u128 {
+        let base = self
+            .base_balances
+            .get(address)
+            .copied()
+            .unwrap_or(FAKE_CHAIN_BALANCE);
+        self.mined().fold(base, |balance, mined| {
+            let tx = &mined.tx;
+            let value = if mined.success { tx.value } else { 0 };
+            let mut balance = balance;
+            if tx.from == address {
+                let fee = u128::from(mined.gas_used) * u128::from(tx.gas_price);
+                balance = balance.saturating_sub(fee + value);
+            }
+            if tx.to == address {
+                balance += value;
+            }
+            balance
+        })
+    }
+
+    fn is_contract(&self, address: &str) -> bool {
+        self.code
+            .get(&address.to_lowercase())
+            .is_some_and(|code| code != "0x" && !code.is_empty())
+    }
+
+    fn find_mined(&self, hash: &str) -> Option<(&Block, usize)> {
+        self.blocks.iter().find_map(|block| {
+            block
+                .transactions
+                .iter()
+                .position(|mined| mined.tx.hash.eq_ignore_ascii_case(hash))
+                .map(|index| (block, index))
+        })
+    }
+
+    /// Add a transaction to the pool as a node would, returning its hash,
+    /// `hash` if it was signed
+    fn submit(&mut self, tx: &Value, hash: Option<String>) -> Result<String, Value> {
+        let field = |name: &str| tx[name].as_str().unwrap_or_default().to_lowercase();
+        let from = field("from");
+        if from.is_empty() {
+            return Err(invalid_params("missing from"));
+        }
+        let quantity_or = |name: &str, default: u64| match &tx[name] {
+            Value::Null => Ok(default),
+            value => parse_quantity(value).map_err(invalid_params),
+        };
+        let next_nonce = self.mined_nonce(&from)
+            + self.pending.iter().filter(|tx| tx.from == from).count() as u64;
+        let mut tx = FakeTx {
+            hash: String::new(),
+            to: field("to"),
+            nonce: quantity_or("nonce", next_nonce)?,
+            value: u128::from(quantity_or("value", 0)?),
+            data: match field("data") {
+                data if data.is_empty() => "0x".to_string(),
+                data => data,
+            },
+            gas: quantity_or("gas", 100_000)?,
+            gas_price: quantity_or("gasPrice", self.gas_price)?,
+            from,
+        };
+        tx.hash = hash.unwrap_or_else(|| {
+            let mut preimage = Vec::new();
+            for part in [&tx.from, &tx.to, &tx.data] {
+                preimage.extend_from_slice(part.as_bytes());
+            }
+            preimage.extend_from_slice(&tx.nonce.to_be_bytes());
+            preimage.extend_from_slice(&tx.value.to_be_bytes());
+            preimage.extend_from_slice(&tx.gas.to_be_bytes());
+            preimage.extend_from_slice(&tx.gas_price.to_be_bytes());
+            format!("0x{}", hex::encode(keccak256(&preimage)))
+        });
+
+        if self.find_mined(&tx.hash).is_some() || self.pending.iter().any(|p| p.hash == tx.hash) {
+            return Err(node_error("already known"));
+        }
+        let rejected = self
+            .rejecting
+            .keys()
+            .find(|id| tx.data.contains(id.as_str()))
+            .cloned();
+        if let Some(error) = rejected.and_then(|id| self.rejecting.remove(&id)) {
+            return Err(error);
+        }
+        if tx.nonce < self.mined_nonce(&tx.from) {
+            return Err(node_error("nonce too low"));
+        }
+        if let Some(replaced) = self
+            .pending
+            .iter()
+            .position(|p| p.from == tx.from && p.nonce == tx.nonce)
+        {
+            if tx.gas_price <= self.pending[replaced].gas_price {
+                return Err(node_error("replacement transaction underpriced"));
+            }
+            self.pending.remove(replaced);
+        } else if tx.nonce > next_nonce {
+            return Err(node_error("nonce too high"));
+        }
+        let hash = tx.hash.clone();
+        self.pending.push(tx);
+        Ok(hash)
+    }
+
+    /// Logs matching an `eth_getLogs` filter
+    fn logs(&self, filter: &Value) -> Result<Vec<Value>, PayoutError> {
+        let bound = |name: &str, default: u64| match &filter[name] {
+            Value::Null => Ok(default),
+            tag if tag == "latest" || tag == "pending" => Ok(self.head()),
+            tag if tag == "earliest" => Ok(0),
+            number => parse_quantity(number),
+        };
+        let from = bound("fromBlock", self.head())?;
+        let to = bound("toBlock", self.head())?;
+        let addresses: Vec<String> = match &filter["address"] {
+            Value::String(address) => vec![address.to_lowercase()],
+            Value::Array(addresses) => addresses
+                .iter()
+                .filter_map(Value::as_str)
+                .map(str::to_lowercase)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(self
+            .blocks
+            .iter()
+            .filter(|b| from <= b.number && b.number <= to)
+            .flat_map(|block| (0..block.transactions.len()).filter_map(move |i| log(block, i)))
+            .filter(|log| {
+                addresses.is_empty() || addresses.iter().any(|address| log["address"] == *address)
+            })
+            .collect())
+    }
+}
+
+impl FakeTx {
+    fn to_json(&self, mined: Option<(&Block, usize)>) -> Value {
+        json!({
+            "hash": self.hash,
+            "from": self.from,
+            "to": self.to,
+            "nonce": format!("0x{:x}", self.nonce),
+            "value": format!("0x{:x}", self.value),
+            "input": self.data,
+            "gas": format!("0x{:x}", self.gas),
+            "gasPrice": format!("0x{:x}", self.gas_price),
+            "blockNumber": mined.map(|(block, _)| format!("0x{:x}", block.number)),
+            "blockHash": mined.map(|(block, _)| block.hash.clone()),
+            "transactionIndex": mined.map(|(_, index)| format!("0x{:x}", index)),
+        })
+    }
+}
+
+fn receipt(block: &Block, index: usize, legacy: bool) -> Value {
+    let mined = &block.transactions[index];
+    let cumulative: u64 = block.transactions[..=index]
+        .iter()
+        .map(|mined| mined.gas_used)
+        .sum();
+    let logs: Vec<Value> = log(block, index).into_iter().collect();
+    let mut receipt = json!({
+        "transactionHash": mined.tx.hash,
+        "transactionIndex": format!("0x{:x}", index),
+        "blockHash": block.hash,
+        "blockNumber": format!("0x{:x}", block.number),
+        "from": mined.tx.from,
+        "to": mined.tx.to,
+        "gasUsed": format!("0x{:x}", mined.gas_used),
+        "cumulativeGasUsed": format!("0x{:x}", cumulative),
+        "effectiveGasPrice": format!("0x{:x}", mined.tx.gas_price),
+        "status": if mined.success { "0x1" } else { "0x0" },
+        "logs": logs,
+    });
+    if legacy {
+        receipt.as_object_mut().unwrap().remove("effectiveGasPrice");
+    }
+    receipt
+}
+
+/// The event logged by the transaction at `index`, if any
+fn log(block: &Block, index: usize) -> Option<Value> {
+    let mined = &block.transactions[index];
+    if !mined.logged {
+        return None;
+    }
+    let log_index = block.transactions[..index]
+        .iter()
+        .filter(|mined| mined.logged)
+        .count();
+    let tx = &mined.tx;
+    let data = tx.data.trim_start_matches("0x");
+    let (selector, arguments) = data.split_at(8.min(data.len()));
+    Some(json!({
+        "address": tx.to,
+        "topics": [format!("0x{:0<64}", selector)],
+        "data": format!("0x{}", arguments),
+        "blockNumber": format!("0x{:x}", block.number),
+        "blockHash": block.hash,
+        "transactionHash": tx.hash,
+        "transactionIndex": format!("0x{:x}", index),
+        "logIndex": format!("0x{:x}", log_index),
+        "removed": false,
+    }))
+}
+
+fn quantity(value: u64) -> Value {
+    json!(format!("0x{:x}", value))
+}
+
+fn node_error(message: &str) -> Value {
+    json!({ "code": -32000, "message": message })
+}
+
+fn invalid_params(err: impl ToString) -> Value {
+    json!({ "code": -32602, "message": err.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rpc::rpc_request;
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, TEST_DESTINATION, TEST_OPERATOR, TEST_TREASURY,
+    };
+    use super::super::{EthereumPayoutService, PayoutStatus};
+    use super::*;
+
+    fn deployed() -> (EthereumPayoutService, Arc<FakeChain>) {
+        let chain = deployed_chain();
+        (test_service(test_config(), chain.clone()), chain)
+    }
+
+    async fn rpc(chain: &FakeChain, method: &str, params: Value) -> Value {
+        chain.send(rpc_request(method, params)).await.unwrap()["result"].clone()
+    }
+
+    #[tokio::test]
+    async fn payouts_stay_pending_until_mined() {
+        let (service, chain) = deployed();
+        let tx_hash = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap()
+            .tx_hash()
+            .unwrap()
+            .to_string();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        assert_eq!(chain.pending(), vec![tx_hash.clone()]);
+        assert_eq!(service.refresh_receipt(&payment_id).await.unwrap(), None);
+        assert_eq!(chain.nonce(TEST_OPERATOR), 0);
+
+        // The pending payout already counts towards the next nonce
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        let sent = chain.sent_transactions();
+        assert_eq!(sent[1]["nonce"], "0x1");
+        assert_eq!(sent[1]["from"], TEST_OPERATOR);
+        // Signed transactions keep their real hash
+        let raw = &chain.calls("eth_sendRawTransaction")[0]["params"][0];
+        let raw = hex::decode(&raw.as_str().unwrap()[2..]).unwrap();
+        assert_eq!(tx_hash, format!("0x{}", hex::encode(keccak256(&raw))));
+
+        assert_eq!(chain.advance_block().len(), 2);
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!(record.block_number, Some(1));
+        assert_eq!(record.gas_cost, Some(50_000 * 1_000_000_000));
+        assert_eq!(chain.nonce(TEST_OPERATOR), 2);
+        assert_eq!(
+            chain.balance(TEST_OPERATOR),
+            FAKE_CHAIN_BALANCE - 2 * 50_000 * 1_000_000_000
+        );
+
+        let receipt = rpc(&chain, "eth_getTransactionReceipt", json!([tx_hash])).await;
+        let logs = rpc(&chain, "eth_getLogs", json!([{"address": TEST_TREASURY}])).await;
+        assert_eq!(logs.as_array().unwrap().len(), 2);
+        assert_eq!(receipt["logs"][0], logs[0]);
+        let block = rpc(&chain, "eth_getBlockByNumber", json!(["latest", false])).await;
+        assert_eq!(block["hash"], receipt["blockHash"]);
+        assert_eq!(block["transactions"][0], json!(tx_hash));
+    }
+
+    #[tokio::test]
+    async fn replays_reverts_drops_and_reorgs() {
+        let (service, chain) = deployed();
+        let payment_id = |sequence| service.config().payment_id(TEST_DESTINATION, sequence);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        // The chain changes between the simulation and mining
+        chain.revert_payment(&payment_id(1));
+        chain.advance_block();
+        let record = service
+            .refresh_receipt(&payment_id(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.status, PayoutStatus::Failed);
+
+        // A dropped payout frees its nonce, once the node refuses the next
+        // one above it as too high
+        let dropped = service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert!(chain.drop_transaction(dropped.tx_hash().unwrap()));
+        assert!(chain.advance_block().is_empty());
+        assert_eq!(service.refresh_receipt(&payment_id(2)).await.unwrap(), None);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 3)
+            .await
+            .unwrap_err();
+        service
+            .execute_payout(TEST_DESTINATION, 100, 3)
+            .await
+            .unwrap();
+        let sent = chain.sent_transactions();
+        assert_eq!(sent[2]["nonce"], "0x2");
+        assert_eq!(sent[3]["nonce"], "0x1");
+
+        // Replacing a pending transaction takes a higher gas price
+        let mut replacement = sent[3].clone();
+        replacement["data"] = json!("0x");
+        let response = chain
+            .send(rpc_request("eth_sendTransaction", json!([replacement])))
+            .await
+            .unwrap();
+        assert_eq!(
+            response["error"]["message"],
+            "replacement transaction underpriced"
+        );
+
+        chain.advance_block();
+        let hash_before =
+            rpc(&chain, "eth_getBlockByNumber", json!(["0x3", false])).await["hash"].clone();
+        assert_eq!(
+            service
+                .refresh_receipt(&payment_id(3))
+                .await
+                .unwrap()
+                .unwrap()
+                .block_number,
+            Some(3)
+        );
+
+        // Reorged out, the payout is pending again until mined anew
+        chain.reorg(1);
+        assert_eq!(chain.block_number(), 2);
+        assert_eq!(chain.pending().len(), 1);
+        assert_eq!(service.refresh_receipt(&payment_id(3)).await.unwrap(), None);
+        chain.advance_block();
+        let hash_after =
+            rpc(&chain, "eth_getBlockByNumber", json!(["0x3", false])).await["hash"].clone();
+        assert_ne!(hash_before, hash_after);
+        let record = service
+            .refresh_receipt(&payment_id(3))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!(chain.nonce(TEST_OPERATOR), 2);
+    }
+}
@@ -0,0 +1,156 @@
+//! Background sampling of the node's gas price
+//!
+//! Under load every payout asking for `eth_gasPrice` means hundreds of
+//! identical calls per minute. The sampler refreshes a shared quote instead,
+//! and payouts only fall back to a live lookup when the quote is stale.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::shutdown::unless_cancelled;
+use super::{EthereumPayoutService, PayoutError, Timestamp};
+use serde_json::json;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Gas price in wei and when it was sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct GasQuote {
+    pub gas_price: u64,
+    pub sampled_at: Timestamp,
+}
+
+impl EthereumPayoutService {
+    /// Gas price for a new transaction, from the sampled quote if it is fresh
+    pub(super) async fn get_gas_price(&self) -> Result<u64, PayoutError> {
+        match self.cached_gas_price() {
+            Some(gas_price) => Ok(gas_price),
+            None => self.fetch_gas_price().await,
+        }
+    }
+
+    fn cached_gas_price(&self) -> Option<u64> {
+        let quote = (*self.gas_quote.lock().unwrap())?;
+        let ttl = chrono::Duration::from_std(self.config.gas_quote_ttl).ok()?;
+        if self.clock.now() - quote.sampled_at < ttl {
+            Some(quote.gas_price)
+        } else {
+            None
+        }
+    }
+
+    async fn fetch_gas_price(&self) -> Result<u64, PayoutError> {
+        let result = self.rpc(rpc_request("eth_gasPrice", json!([]))).await?;
+        parse_quantity(&result)
+    }
+
+    /// Fetch the gas price and store it as the current quote
+    pub async fn sample_gas_price(&self) -> Result<u64, PayoutError> {
+        let gas_price = self.fetch_gas_price().await?;
+        *self.gas_quote.lock().unwrap() = Some(GasQuote {
+            gas_price,
+            sampled_at: self.clock.now(),
+        });
+        Ok(gas_price)
+    }
+
+    /// Spawn a task sampling the gas price on the configured interval.
+    ///
+    /// Returns `None` when sampling is disabled. The task only holds a weak
+    /// reference and stops once the service has been dropped or shut down.
+    pub fn spawn_gas_sampler(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval = self.config.gas_sample_interval?;
+        let service = Arc::downgrade(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                let service = match service.upgrade() {
+                    Some(service) => service,
+                    None => break,
+                };
+                if let Err(err) = service.sample_gas_price().await {
+                    warn!("Gas price sampling failed: {}", err);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::time::Duration;
+
+    fn payout_transport() -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_result("eth_sendRawTransaction", json!(format!("0x{:064x}", 1)));
+        transport
+    }
+
+    #[tokio::test]
+    async fn fresh_quote_avoids_live_lookup() {
+        let transport = payout_transport();
+        let clock = FakeClock::new();
+        let service = test_service(test_config(), transport.clone()).with_clock(clock.clone());
+        service.sample_gas_price().await.unwrap();
+
+        transport.on_result("eth_gasPrice", json!("0x1"));
+        for sequence in 1..=3 {
+            service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .unwrap();
+        }
+        assert_eq!(transport.call_count("eth_gasPrice"), 1);
+        let sent = transport.sent_transactions();
+        assert_eq!(sent[2]["gasPrice"], "0x3b9aca00");
+
+        // Past the TTL the payout fetches the price itself
+        clock.advance(service.config.gas_quote_ttl);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 4)
+            .await
+            .unwrap();
+        assert_eq!(transport.call_count("eth_gasPrice"), 2);
+        let sent = transport.sent_transactions();
+        assert_eq!(sent[3]["gasPrice"], "0x1");
+    }
+
+    #[tokio::test]
+    async fn sampler_survives_errors_and_stops_with_the_service() {
+        let transport = MockTransport::new();
+        transport.on_error("eth_gasPrice", -32603, "internal error");
+        let mut config = test_config();
+        config.gas_sample_interval = Some(Duration::from_millis(10));
+        let service = Arc::new(test_service(config, transport.clone()));
+
+        let sampler = service.spawn_gas_sampler().unwrap();
+        tokio::time::sleep(Duration::from_millis(35)).await;
+        assert!(transport.call_count("eth_gasPrice") >= 2);
+        assert_eq!(service.cached_gas_price(), None);
+
+        transport.on_result("eth_gasPrice", json!("0x7"));
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(service.cached_gas_price(), Some(7));
+
+        drop(service);
+        tokio::time::timeout(Duration::from_secs(1), sampler)
+            .await
+            .expect("sampler stops once the service is dropped")
+            .unwrap();
+    }
+
+    #[test]
+    fn sampling_is_opt_in() {
+        let service = Arc::new(test_service(test_config(), MockTransport::new()));
+        assert!(service.spawn_gas_sampler().is_none());
+    }
+}
@@ -0,0 +1,192 @@
+//! Gas limits sent when estimating a payout's gas fails
+//!
+//! Nodes under load fail `eth_estimateGas` with transient errors, and a
+//! payout should not wait on them when a fixed limit would do. The limit is
+//! picked from [`GasFallbacks`] instead: `eoa` for recipients without code,
+//! the higher `contract` for contract wallets, which run code when paid,
+//! and per-asset overrides of both for tokens costing more to transfer.
+//! Whether a recipient has code comes from
+//! [`classify`](EthereumPayoutService::classify), which remembers it for
+//! `code_ttl`. A recipient whose code cannot be read gets the contract limit. Errors that are not transient, like a reverting
+//! estimate, still fail the payout.
+
+use super::{metrics, EthereumPayoutService, PayoutPlan, RecipientKind, DEFAULT_GAS_LIMIT};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Gas limits of one asset's payouts by recipient type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GasFallback {
+    /// For recipients without code
+    pub eoa: u64,
+    /// For recipients with code
+    pub contract: u64,
+}
+
+/// Gas limits sent when estimation fails
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GasFallbacks {
+    /// Limits of assets without an override
+    pub default: GasFallback,
+    /// Limits per asset code
+    pub assets: BTreeMap<String, GasFallback>,
+    /// How long whether a recipient has code is remembered, by every
+    /// feature asking [`classify`](EthereumPayoutService::classify)
+    pub code_ttl: Duration,
+}
+
+impl Default for GasFallbacks {
+    fn default() -> Self {
+        GasFallbacks {
+            default: GasFallback {
+                eoa: DEFAULT_GAS_LIMIT,
+                contract: 250_000,
+            },
+            assets: BTreeMap::new(),
+            code_ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+impl GasFallbacks {
+    /// Parse per-asset overrides from a comma-separated list of
+    /// `CODE:eoa:contract` entries, e.g. `USDT:120000:300000`
+    pub fn parse_overrides(spec: &str) -> Option<BTreeMap<String, GasFallback>> {
+        let mut assets = BTreeMap::new();
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let fields: Vec<&str> = entry.split(':').map(str::trim).collect();
+            match fields.as_slice() {
+                [code, eoa, contract] => assets.insert(
+                    code.to_string(),
+                    GasFallback {
+                        eoa: eoa.parse().ok()?,
+                        contract: contract.parse().ok()?,
+                    },
+                ),
+                _ => return None,
+            };
+        }
+        Some(assets)
+    }
+
+    /// Limit for a payout of `asset_code` to a recipient with or without code
+    pub fn gas_limit(&self, asset_code: &str, recipient_is_contract: bool) -> u64 {
+        let fallback = self.assets.get(asset_code).unwrap_or(&self.default);
+        if recipient_is_contract {
+            fallback.contract
+        } else {
+            fallback.eoa
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Gas limit of `plan` from the fallback table
+    pub(super) async fn fallback_gas_limit(&self, plan: &PayoutPlan) -> u64 {
+        let recipient = &plan.destination.recipient;
+        let is_contract = self.classify(recipient).await != RecipientKind::Eoa;
+        metrics::gas_estimate(self.config.tenant.as_deref(), "fallback");
+        self.config
+            .gas_fallbacks
+            .gas_limit(&plan.destination.asset_code, is_contract)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport, TEST_DESTINATION};
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Service whose estimates fail as on an overloaded node, and whose
+    /// recipient has code while `is_contract` is set
+    fn overloaded(
+        config: super::super::EthereumPayoutConfig,
+    ) -> (EthereumPayoutService, Arc<MockTransport>, Arc<AtomicBool>) {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_error("eth_estimateGas", -32603, "internal error");
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        let is_contract = Arc::new(AtomicBool::new(false));
+        let flag = is_contract.clone();
+        transport.on("eth_getCode", move |_| {
+            let code = if flag.load(Ordering::SeqCst) {
+                "0x6080604052"
+            } else {
+                "0x"
+            };
+            Ok(json!(code))
+        });
+        let mut config = config;
+        config.gas_estimate_ttl = Some(Duration::from_secs(300));
+        (
+            test_service(config, transport.clone()),
+            transport,
+            is_contract,
+        )
+    }
+
+    fn sent_gas(transport: &MockTransport) -> Vec<String> {
+        transport
+            .sent_transactions()
+            .iter()
+            .map(|call| call["gas"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn eoa_recipients_get_the_eoa_limit() {
+        let (service, transport, _) = overloaded(test_config());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        // 100000
+        assert_eq!(sent_gas(&transport), ["0x186a0"]);
+    }
+
+    #[tokio::test]
+    async fn contract_recipients_get_the_contract_limit() {
+        let (service, transport, is_contract) = overloaded(test_config());
+        is_contract.store(true, Ordering::SeqCst);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        // 250000
+        assert_eq!(sent_gas(&transport), ["0x3d090"]);
+
+        // Reverting estimates are not papered over
+        transport.on_error("eth_estimateGas", 3, "execution reverted");
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .is_err());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn asset_overrides_take_precedence() {
+        let mut config = test_config();
+        config.gas_fallbacks.assets = GasFallbacks::parse_overrides("EURC:120000:300000").unwrap();
+        // Without caching, the recipient's new code is seen right away
+        config.gas_fallbacks.code_ttl = Duration::ZERO;
+        let (service, transport, is_contract) = overloaded(config);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        is_contract.store(true, Ordering::SeqCst);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(sent_gas(&transport), ["0x1d4c0", "0x493e0"]);
+
+        assert_eq!(GasFallbacks::parse_overrides("EURC:120000"), None);
+        assert_eq!(GasFallbacks::parse_overrides("EURC:lots:300000"), None);
+    }
+}
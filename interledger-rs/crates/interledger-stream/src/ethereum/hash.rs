@@ -0,0 +1,124 @@
+//! Hash functions used by the payout service
+//!
+//! Keccak-256, SHA-256 and HMAC-SHA256 come from the RustCrypto `sha3`,
+//! `sha2` and `hmac` crates, so the payout service does not depend on `ring`
+//! for hashing.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use sha3::{Digest, Keccak256};
+
+/// Keccak-256 hash as used throughout Ethereum
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// SHA-256 hash
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// HMAC-SHA256 of `data` under `key`, per RFC 2104
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Hash payment IDs are derived with
+///
+/// The preimage of a payment ID is the UTF-8 bytes of the ILP destination
+/// followed by the sequence as 8 big-endian bytes, which is what Solidity's
+/// `abi.encodePacked(string destination, uint64 sequence)` produces. With
+/// `Keccak256` a contract can verify an ID as `keccak256` of that packing.
+/// Namespaced IDs hash the namespace and base ID the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PaymentIdHash {
+    /// Used by every earlier release, so existing records keep their IDs
+    #[default]
+    Sha256,
+    Keccak256,
+}
+
+impl PaymentIdHash {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(PaymentIdHash::Sha256),
+            "keccak256" => Some(PaymentIdHash::Keccak256),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PaymentIdHash::Sha256 => "sha256",
+            PaymentIdHash::Keccak256 => "keccak256",
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> [u8; 32] {
+        match self {
+            PaymentIdHash::Sha256 => sha256(data),
+            PaymentIdHash::Keccak256 => keccak256(data),
+        }
+    }
+
+    /// Payment ID of the payout for `destination` and `sequence`, without a namespace
+    pub fn payment_id(self, destination: &str, sequence: u64) -> [u8; 32] {
+        let mut data = destination.as_bytes().to_vec();
+        data.extend_from_slice(&sequence.to_be_bytes());
+        self.digest(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            hex::encode(sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(keccak256(b"")),
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
+        );
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(
+            hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            hex::encode(hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn payment_id_vectors() {
+        let destination = "example.alice.eth.USDC.0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+        let mut preimage = destination.as_bytes().to_vec();
+        preimage.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 7]);
+        for hash in [PaymentIdHash::Sha256, PaymentIdHash::Keccak256] {
+            assert_eq!(hash.payment_id(destination, 7), hash.digest(&preimage));
+        }
+        assert_eq!(
+            hex::encode(PaymentIdHash::Sha256.payment_id(destination, 7)),
+            "e740c75f700e19072c6f444bec43b59abaa2c5acefd76b801fa2f3fe9205da83"
+        );
+        assert_eq!(
+            hex::encode(PaymentIdHash::Keccak256.payment_id(destination, 7)),
+            "1b98280e375609ff801660a95942f514e90743c63ab31b4bb10d7854e85fced4"
+        );
+        assert_eq!(
+            PaymentIdHash::parse("keccak256"),
+            Some(PaymentIdHash::Keccak256)
+        );
+        assert_eq!(PaymentIdHash::parse("md5"), None);
+    }
+}
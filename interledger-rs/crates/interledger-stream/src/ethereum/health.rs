@@ -0,0 +1,341 @@
+//! Health and readiness reporting for orchestration probes
+//!
+//! Checks that need the node (chain ID, operator balance) are cached for
+//! `health_cache_ttl`, so probes can poll frequently without loading it.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::standby::{payout_router, payout_service};
+use super::{
+    skipped_payouts, AuthorizationState, EndpointStats, EthereumPayoutService, PayoutError,
+    RetrySchedule, SkipStats, Timestamp,
+};
+use serde_json::json;
+use tracing::debug;
+
+/// Overall classification of the payout subsystem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthStatus {
+    /// Payouts can be executed
+    Ready,
+    /// Payouts are accepted but delayed or at risk
+    Degraded,
+    /// Payouts cannot be executed
+    Down,
+}
+
+/// Structured result of [`EthereumPayoutService::health`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    /// The node answered recently or responded to a ping
+    pub rpc_reachable: bool,
+    /// Chain ID reported by the node, if it could be fetched
+    pub chain_id: Option<u64>,
+    pub chain_id_verified: bool,
+    /// Operator balance in wei, if it could be fetched
+    pub operator_balance: Option<u128>,
+    /// `None` when no floor is configured or the balance is unknown
+    pub balance_above_floor: Option<bool>,
+    pub authorization: AuthorizationState,
+    pub paused: bool,
+    /// Payouts deferred until the Treasury is unpaused
+    pub queue_depth: usize,
+    pub store_reachable: bool,
+    /// Per-endpoint statistics, empty with a single RPC endpoint
+    pub endpoints: Vec<EndpointStats>,
+    /// Payments `maybe_execute_payout` skipped since startup
+    pub skipped: SkipStats,
+    /// How failed payouts are retried
+    pub retry_schedule: RetrySchedule,
+}
+
+/// Node-backed results kept between probes
+#[derive(Debug, Default)]
+pub(super) struct HealthCache {
+    chain_id: Option<(Timestamp, Option<u64>)>,
+    balance: Option<(Timestamp, Option<u128>)>,
+}
+
+impl EthereumPayoutService {
+    /// Check the subsystem's dependencies and classify its readiness
+    pub async fn health(&self) -> HealthReport {
+        let chain_id = self.cached_chain_id().await;
+        let rpc_reachable = chain_id.is_some() || self.answered_recently() || self.ping().await;
+        let chain_id_verified = chain_id == Some(self.config.expected_chain_id);
+        let operator_balance = if rpc_reachable {
+            self.cached_balance().await
+        } else {
+            None
+        };
+        let balance_above_floor = match (self.config.balance_floor, operator_balance) {
+            (Some(floor), Some(balance)) => Some(balance >= floor),
+            _ => None,
+        };
+        let authorization = self.authorization();
+        let paused = self.is_paused();
+        let queue_depth = self.deferred_count();
+        let store_reachable = self.store.is_healthy();
+
+        let status = if !rpc_reachable
+            || !chain_id_verified
+            || !store_reachable
+            || authorization == AuthorizationState::Unauthorized
+        {
+            HealthStatus::Down
+        } else if paused || queue_depth > 0 || balance_above_floor == Some(false) {
+            HealthStatus::Degraded
+        } else {
+            HealthStatus::Ready
+        };
+
+        HealthReport {
+            status,
+            rpc_reachable,
+            chain_id,
+            chain_id_verified,
+            operator_balance,
+            balance_above_floor,
+            authorization,
+            paused,
+            queue_depth,
+            store_reachable,
+            endpoints: self.endpoint_stats(),
+            skipped: skipped_payouts(),
+            retry_schedule: self.retry_schedule(),
+        }
+    }
+
+    /// Whether the node answered any request within the cache TTL
+    fn answered_recently(&self) -> bool {
+        match *self.last_rpc_response.lock().unwrap() {
+            Some(at) => self.is_fresh(at),
+            None => false,
+        }
+    }
+
+    fn is_fresh(&self, at: Timestamp) -> bool {
+        let ttl = chrono::Duration::from_std(self.config.health_cache_ttl)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        self.clock.now() - at < ttl
+    }
+
+    async fn ping(&self) -> bool {
+        self.probe(rpc_request("net_version", json!([])))
+            .await
+            .is_ok()
+    }
+
+    async fn cached_chain_id(&self) -> Option<u64> {
+        if let Some((at, chain_id)) = self.health_cache.lock().unwrap().chain_id {
+            if self.is_fresh(at) {
+                return chain_id;
+            }
+        }
+        let chain_id = self
+            .probe(rpc_request("eth_chainId", json!([])))
+            .await
+            .and_then(|result| parse_quantity(&result))
+            .map_err(|err| debug!("Health check could not fetch the chain ID: {}", err))
+            .ok();
+        self.health_cache.lock().unwrap().chain_id = Some((self.clock.now(), chain_id));
+        chain_id
+    }
+
+    async fn cached_balance(&self) -> Option<u128> {
+        if let Some((at, balance)) = self.health_cache.lock().unwrap().balance {
+            if self.is_fresh(at) {
+                return balance;
+            }
+        }
+        let balance = self
+            .probe(rpc_request(
+                "eth_getBalance",
+                json!([self.operator_address(), "latest"]),
+            ))
+            .await
+            .and_then(|result| {
+                let hex = result.as_str().unwrap_or_default().trim_start_matches("0x");
+                u128::from_str_radix(hex, 16).map_err(|_| {
+                    PayoutError::InvalidResponse(format!("Invalid balance: {}", result))
+                })
+            })
+            .map_err(|err| debug!("Health check could not fetch the balance: {}", err))
+            .ok();
+        self.health_cache.lock().unwrap().balance = Some((self.clock.now(), balance));
+        balance
+    }
+
+    /// An RPC call bounded by `health_timeout`
+    async fn probe(&self, request: serde_json::Value) -> Result<serde_json::Value, PayoutError> {
+        tokio::time::timeout(self.config.health_timeout, self.rpc(request))
+            .await
+            .unwrap_or_else(|_| Err(PayoutError::Transport("Health probe timed out".to_string())))
+    }
+}
+
+/// Health of each tenant's payout service, empty without tenants
+pub async fn tenant_health() -> Vec<(String, HealthReport)> {
+    match payout_router() {
+        Some(router) => router.health().await,
+        None => Vec::new(),
+    }
+}
+
+/// Health of the global payout service, `None` if it is not configured
+pub async fn payout_health() -> Option<HealthReport> {
+    match payout_service() {
+        Some(service) => Some(service.health().await),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{
+        InMemoryPayoutStore, PageCursor, PayoutRecord, PayoutStore, RoleCheckConfig,
+    };
+    use super::*;
+    use serde_json::Value;
+    use std::sync::Arc;
+
+    fn healthy_node() -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.on_result("eth_chainId", json!("0x7a69"));
+        transport.on_result("net_version", json!("31337"));
+        transport.on_result("eth_getBalance", json!("0xde0b6b3a7640000"));
+        transport
+    }
+
+    fn floored_service(transport: Arc<MockTransport>) -> EthereumPayoutService {
+        let mut config = test_config();
+        config.balance_floor = Some(100_000_000_000_000_000);
+        test_service(config, transport).with_clock(FakeClock::new())
+    }
+
+    #[tokio::test]
+    async fn healthy_node_is_ready_and_cached() {
+        let transport = healthy_node();
+        let service = floored_service(transport.clone());
+        let report = service.health().await;
+        assert_eq!(report.status, HealthStatus::Ready);
+        assert_eq!(report.chain_id, Some(31337));
+        assert_eq!(report.operator_balance, Some(1_000_000_000_000_000_000));
+        assert_eq!(report.balance_above_floor, Some(true));
+
+        // Served from the cache
+        assert_eq!(service.health().await, report);
+        assert_eq!(transport.call_count("eth_chainId"), 1);
+        assert_eq!(transport.call_count("eth_getBalance"), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_node_is_down() {
+        let service = floored_service(MockTransport::new()).with_transport(Arc::new(DownTransport));
+        let report = service.health().await;
+        assert_eq!(report.status, HealthStatus::Down);
+        assert!(!report.rpc_reachable);
+        assert_eq!(report.operator_balance, None);
+    }
+
+    struct DownTransport;
+
+    #[async_trait::async_trait]
+    impl super::super::RpcTransport for DownTransport {
+        async fn send(&self, _: Value) -> Result<Value, PayoutError> {
+            Err(PayoutError::Transport("connection refused".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_chain_is_down() {
+        let transport = healthy_node();
+        transport.on_result("eth_chainId", json!("0x1"));
+        let report = floored_service(transport).health().await;
+        assert_eq!(report.status, HealthStatus::Down);
+        assert!(report.rpc_reachable);
+        assert!(!report.chain_id_verified);
+    }
+
+    #[tokio::test]
+    async fn low_balance_is_degraded() {
+        let transport = healthy_node();
+        transport.on_result("eth_getBalance", json!("0x1"));
+        let report = floored_service(transport).health().await;
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert_eq!(report.balance_above_floor, Some(false));
+    }
+
+    #[tokio::test]
+    async fn paused_queue_is_degraded() {
+        let transport = healthy_node();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 1)));
+        let service = floored_service(transport);
+        service.check_paused().await.unwrap();
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let report = service.health().await;
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert!(report.paused);
+        assert_eq!(report.queue_depth, 1);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_operator_is_down() {
+        let transport = healthy_node();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 0)));
+        let mut config = test_config();
+        config.role_check = Some(RoleCheckConfig::default());
+        let service = test_service(config, transport);
+        service.check_operator_role().await.unwrap();
+        assert_eq!(service.health().await.status, HealthStatus::Down);
+    }
+
+    /// Store whose backing medium has gone away
+    struct BrokenStore(InMemoryPayoutStore);
+
+    impl PayoutStore for BrokenStore {
+        fn save(&self, record: PayoutRecord) {
+            self.0.save(record)
+        }
+        fn get(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+            self.0.get(payment_id)
+        }
+        fn remove(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+            self.0.remove(payment_id)
+        }
+        fn list_range(
+            &self,
+            from: Timestamp,
+            to: Timestamp,
+            after: Option<PageCursor>,
+            limit: usize,
+        ) -> Vec<PayoutRecord> {
+            self.0.list_range(from, to, after, limit)
+        }
+        fn highest_sequence(&self, destination: &str) -> Option<u64> {
+            self.0.highest_sequence(destination)
+        }
+        fn is_healthy(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_store_is_down() {
+        let service = floored_service(healthy_node())
+            .with_store(Arc::new(BrokenStore(InMemoryPayoutStore::new())));
+        let report = service.health().await;
+        assert_eq!(report.status, HealthStatus::Down);
+        assert!(!report.store_reachable);
+    }
+
+    #[tokio::test]
+    async fn unconfigured_service_reports_nothing() {
+        assert_eq!(payout_health().await, None);
+    }
+}
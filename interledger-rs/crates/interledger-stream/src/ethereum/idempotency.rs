@@ -0,0 +1,468 @@
+//! Reconciling the store with the Treasury's record of processed payouts
+//!
+//! The store and the Treasury's `isProcessed` view can disagree, say once
+//! the store is restored from an old backup. For each payment ID given to
+//! [`EthereumPayoutService::reconcile_idempotency`], in ascending order and
+//! once each, the store counts as having paid it when its record is
+//! confirmed, and the Treasury when `isProcessed` says so:
+//!
+//! - both or neither: they agree, and nothing changes;
+//! - only the Treasury: the store missed the payout, so its record is
+//!   confirmed with the transaction of the Treasury's `PayoutExecuted` log,
+//!   or written from the log if the store had none;
+//! - only the store: the payout may never have happened, so its record is
+//!   flagged `SuspectedPhantom` with an alert, and is neither retried nor
+//!   resent on its own. An operator who finds it unpaid resends it with
+//!   [`EthereumPayoutService::reexecute_phantom`].
+//!
+//! Only payouts through the Treasury are checked; direct transfers leave no
+//! trace in `isProcessed`.
+
+use super::abi::{decode_words, encode_call, selector};
+use super::hash::keccak256;
+use super::rpc::{parse_quantity, rpc_request};
+use super::{
+    EthereumPayoutService, ImportedPayout, PayoutError, PayoutMode, PayoutOutcome, PayoutStatus,
+    IS_PROCESSED_SIGNATURE, PAYOUT_EXECUTED_EVENT_SIGNATURE,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use tracing::{error, info, warn};
+
+/// What reconciling one payment ID found and did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyResolution {
+    /// Store and Treasury both say it was paid
+    Paid,
+    /// Neither says it was paid
+    Unpaid,
+    /// Only the Treasury said it was paid; the store now has it confirmed
+    Backfilled { tx_hash: String, block_number: u64 },
+    /// Only the Treasury said it was paid, and it has no `PayoutExecuted`
+    /// log to backfill the store from
+    Unlogged,
+    /// Only the store said it was paid; its record is flagged
+    SuspectedPhantom,
+    /// Not paid through the Treasury, so `isProcessed` says nothing about it
+    NotApplicable,
+    /// `isProcessed` or the logs could not be read
+    Unknown { reason: String },
+}
+
+/// Outcome of reconciling one payment ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotencyEntry {
+    pub payment_id: [u8; 32],
+    pub resolution: IdempotencyResolution,
+}
+
+/// `0x`-prefixed address in the low 20 bytes of a log topic
+fn topic_address(topic: &Value) -> Option<String> {
+    let word = decode_words(topic.as_str()?)?.into_iter().next()?;
+    Some(format!("0x{}", hex::encode(&word[12..])))
+}
+
+/// First word of `data` as an amount, `None` if it does not fit
+fn first_amount(data: &Value) -> Option<u64> {
+    let word = decode_words(data.as_str()?)?.into_iter().next()?;
+    if word[..24].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    let mut low = [0u8; 8];
+    low.copy_from_slice(&word[24..]);
+    Some(u64::from_be_bytes(low))
+}
+
+impl EthereumPayoutService {
+    /// Reconcile the store with the Treasury for each of `payment_ids`, see
+    /// [`idempotency`](self)
+    pub async fn reconcile_idempotency(
+        &self,
+        payment_ids: impl IntoIterator<Item = [u8; 32]>,
+    ) -> Result<Vec<IdempotencyEntry>, PayoutError> {
+        let known_missing = self
+            .treasury_capabilities()
+            .is_some_and(|capabilities| capabilities.is_processed == Some(false));
+        if known_missing {
+            return Err(PayoutError::Config(format!(
+                "Treasury {} has no {}",
+                self.config.treasury_address, IS_PROCESSED_SIGNATURE
+            )));
+        }
+        let payment_ids: BTreeSet<[u8; 32]> = payment_ids.into_iter().collect();
+        let mut entries = Vec::with_capacity(payment_ids.len());
+        for payment_id in payment_ids {
+            let resolution = match self.reconcile_payment_id(&payment_id).await {
+                Ok(resolution) => resolution,
+                Err(err) => IdempotencyResolution::Unknown {
+                    reason: err.to_string(),
+                },
+            };
+            entries.push(IdempotencyEntry {
+                payment_id,
+                resolution,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Resend a payout flagged `SuspectedPhantom` once an operator found it
+    /// unpaid, unless the Treasury has processed it since
+    pub async fn reexecute_phantom(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let mut record = self.lookup(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        if record.status != PayoutStatus::SuspectedPhantom {
+            return Err(PayoutError::Config(format!(
+                "Payout {} is {:?}, not a suspected phantom",
+                record.payment_id_hex(),
+                record.status
+            )));
+        }
+        if self.is_processed(payment_id).await? {
+            record.status = PayoutStatus::Confirmed;
+            record.last_error = None;
+            self.store.save(record);
+            return Err(PayoutError::Config(format!(
+                "Payout 0x{} was processed by the Treasury after all",
+                hex::encode(payment_id)
+            )));
+        }
+        info!(
+            "Re-executing suspected phantom payout {}",
+            record.payment_id_hex()
+        );
+        record.status = PayoutStatus::Failed;
+        record.last_error = Some("suspected phantom, re-execution approved".to_string());
+        self.store.save(record);
+        self.retry_failed(payment_id).await
+    }
+
+    async fn reconcile_payment_id(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<IdempotencyResolution, PayoutError> {
+        let record = self.lookup(payment_id);
+        let through_treasury = record.as_ref().is_none_or(|record| {
+            self.config
+                .assets
+                .get(&record.asset_code)
+                .is_none_or(|asset| asset.mode == PayoutMode::Treasury)
+        });
+        if !through_treasury {
+            return Ok(IdempotencyResolution::NotApplicable);
+        }
+        let store_paid = record.as_ref().is_some_and(|record| {
+            matches!(
+                record.status,
+                PayoutStatus::Confirmed | PayoutStatus::SuspectedPhantom
+            )
+        });
+        let contract_paid = self.is_processed(payment_id).await?;
+        match (store_paid, contract_paid) {
+            (true, true) => {
+                if let Some(mut record) =
+                    record.filter(|record| record.status == PayoutStatus::SuspectedPhantom)
+                {
+                    info!(
+                        "Suspected phantom payout {} was processed after all",
+                        record.payment_id_hex()
+                    );
+                    record.status = PayoutStatus::Confirmed;
+                    record.last_error = None;
+                    self.store.save(record);
+                }
+                Ok(IdempotencyResolution::Paid)
+            }
+            (false, false) => Ok(IdempotencyResolution::Unpaid),
+            (false, true) => self.backfill(payment_id).await,
+            (true, false) => {
+                let mut record = record.expect("the store said it was paid");
+                error!(
+                    "ALERT: payout {} of {} {} to {} is {} in the store but not processed by the Treasury; re-execute it once found unpaid",
+                    record.payment_id_hex(),
+                    record.amount,
+                    record.asset_code,
+                    self.display_address(&record.recipient),
+                    record.status.as_str()
+                );
+                record.status = PayoutStatus::SuspectedPhantom;
+                record.last_error = Some("not processed by the Treasury".to_string());
+                self.store.save(record);
+                Ok(IdempotencyResolution::SuspectedPhantom)
+            }
+        }
+    }
+
+    /// Confirm the record of a payout the Treasury processed from its log
+    async fn backfill(&self, payment_id: &[u8; 32]) -> Result<IdempotencyResolution, PayoutError> {
+        let log = match self.payout_log(payment_id).await? {
+            Some(log) => log,
+            None => {
+                warn!(
+                    "Treasury processed payout 0x{} but has no PayoutExecuted log for it",
+                    hex::encode(payment_id)
+                );
+                return Ok(IdempotencyResolution::Unlogged);
+            }
+        };
+        let invalid = |what: &str| PayoutError::InvalidResponse(format!("PayoutExecuted {}", what));
+        let tx_hash = log["transactionHash"]
+            .as_str()
+            .ok_or_else(|| invalid("log without a transaction hash"))?
+            .to_string();
+        let block_number = parse_quantity(&log["blockNumber"])?;
+        let mut record = match self.lookup(payment_id) {
+            Some(record) => record,
+            None => {
+                let token = topic_address(&log["topics"][3]).ok_or_else(|| invalid("token"))?;
+                let amount = first_amount(&log["data"]).ok_or_else(|| invalid("amount"))?;
+                let asset_code = self
+                    .config
+                    .assets
+                    .by_token_address(&token)
+                    .first()
+                    .map_or(token, |asset| asset.code.clone());
+                let payout = ImportedPayout {
+                    payment_id: Some(format!("0x{}", hex::encode(payment_id))),
+                    destination: None,
+                    sequence: None,
+                    recipient: topic_address(&log["topics"][2])
+                        .ok_or_else(|| invalid("recipient"))?,
+                    asset_code,
+                    amount,
+                    tx_hash: tx_hash.clone(),
+                    timestamp: self.clock.now(),
+                };
+                self.imported_record(&payout)
+                    .map_err(PayoutError::InvalidResponse)?
+            }
+        };
+        info!(
+            "Backfilling payout {} the Treasury processed in {}",
+            record.payment_id_hex(),
+            tx_hash
+        );
+        record.status = PayoutStatus::Confirmed;
+        record.tx_hash = Some(tx_hash.clone());
+        record.block_number = Some(block_number);
+        record.last_error = None;
+        self.store.save(record);
+        Ok(IdempotencyResolution::Backfilled {
+            tx_hash,
+            block_number,
+        })
+    }
+
+    async fn is_processed(&self, payment_id: &[u8; 32]) -> Result<bool, PayoutError> {
+        let call = json!({
+            "to": self.config.treasury_address,
+            "data": encode_call(selector(IS_PROCESSED_SIGNATURE), &[*payment_id]),
+        });
+        let result = self
+            .rpc(rpc_request("eth_call", json!([call, "latest"])))
+            .await?;
+        let word = result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .ok_or_else(|| {
+                PayoutError::InvalidResponse(format!("isProcessed returned {}", result))
+            })?;
+        Ok(word.iter().any(|byte| *byte != 0))
+    }
+
+    /// The Treasury's `PayoutExecuted` log for `payment_id`, if any
+    async fn payout_log(&self, payment_id: &[u8; 32]) -> Result<Option<Value>, PayoutError> {
+        let filter = json!({
+            "address": self.config.treasury_address,
+            "topics": [
+                format!("0x{}", hex::encode(keccak256(PAYOUT_EXECUTED_EVENT_SIGNATURE.as_bytes()))),
+                format!("0x{}", hex::encode(payment_id)),
+            ],
+            "fromBlock": "earliest",
+            "toBlock": "latest",
+        });
+        let logs = self
+            .rpc(rpc_request("eth_getLogs", json!([filter])))
+            .await?;
+        Ok(logs.as_array().and_then(|logs| logs.first()).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::abi::encode_address;
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    const RECIPIENT: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+    const TX_HASH: &str = "0xfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeedfeed";
+
+    /// Chain whose Treasury processed the payment IDs in `processed`
+    fn treasury(processed: Arc<Mutex<HashSet<[u8; 32]>>>) -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on("eth_call", move |params| {
+            let data = params[0]["data"].as_str().unwrap_or_default();
+            let mut payment_id = [0u8; 32];
+            if data.len() != 74 || hex::decode_to_slice(&data[10..], &mut payment_id).is_err() {
+                return Ok(json!("0x"));
+            }
+            let paid = processed.lock().unwrap().contains(&payment_id);
+            Ok(json!(format!("0x{:064x}", paid as u8)))
+        });
+        transport
+    }
+
+    fn executed_log(payment_id: &[u8; 32], amount: u64) -> Value {
+        json!({
+            "transactionHash": TX_HASH,
+            "blockNumber": "0x2a",
+            "topics": [
+                format!("0x{}", hex::encode(keccak256(PAYOUT_EXECUTED_EVENT_SIGNATURE.as_bytes()))),
+                format!("0x{}", hex::encode(payment_id)),
+                format!("0x{}", hex::encode(encode_address(RECIPIENT).unwrap())),
+                format!("0x{}", hex::encode([0u8; 32])),
+            ],
+            "data": format!("0x{:064x}", amount),
+        })
+    }
+
+    #[tokio::test]
+    async fn treasury_payouts_missing_from_the_store_are_backfilled() {
+        let processed = Arc::new(Mutex::new(HashSet::new()));
+        let transport = treasury(processed.clone());
+        let service = test_service(test_config(), transport.clone());
+        // Sent and confirmed, as both agree
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let paid = service.config().payment_id(TEST_DESTINATION, 1);
+        let mut record = service.store().get(&paid).unwrap();
+        record.status = PayoutStatus::Confirmed;
+        service.store().save(record);
+        // Recorded as failed, from an old backup, but paid
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        let failed = service.config().payment_id(TEST_DESTINATION, 2);
+        let mut record = service.store().get(&failed).unwrap();
+        record.status = PayoutStatus::Failed;
+        service.store().save(record);
+        // Unknown to the store
+        let missing = [7u8; 32];
+        let unpaid = [9u8; 32];
+        processed.lock().unwrap().extend([paid, failed, missing]);
+        transport.on("eth_getLogs", move |params| {
+            let topic = params[0]["topics"][1].as_str().unwrap().to_string();
+            let logs: Vec<Value> = [(failed, 100), (missing, 250)]
+                .iter()
+                .filter(|(id, _)| topic == format!("0x{}", hex::encode(id)))
+                .map(|(id, amount)| executed_log(id, *amount))
+                .collect();
+            Ok(json!(logs))
+        });
+
+        let entries = service
+            .reconcile_idempotency(vec![unpaid, missing, paid, failed, missing])
+            .await
+            .unwrap();
+        let mut expected = vec![
+            (paid, IdempotencyResolution::Paid),
+            (failed, backfilled()),
+            (missing, backfilled()),
+            (unpaid, IdempotencyResolution::Unpaid),
+        ];
+        expected.sort_by_key(|(payment_id, _)| *payment_id);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.payment_id, entry.resolution.clone()))
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        let record = service.store().get(&failed).unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!(record.tx_hash.as_deref(), Some(TX_HASH));
+        assert_eq!(record.block_number, Some(42));
+        let record = service.store().get(&missing).unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!((record.recipient.as_str(), record.amount), (RECIPIENT, 250));
+        assert!(service.store().get(&unpaid).is_none());
+    }
+
+    fn backfilled() -> IdempotencyResolution {
+        IdempotencyResolution::Backfilled {
+            tx_hash: TX_HASH.to_string(),
+            block_number: 42,
+        }
+    }
+
+    #[tokio::test]
+    async fn phantom_payouts_are_flagged_and_resent_once_approved() {
+        let processed = Arc::new(Mutex::new(HashSet::new()));
+        let transport = treasury(processed.clone());
+        let service = test_service(test_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        let mut record = service.store().get(&payment_id).unwrap();
+        record.status = PayoutStatus::Confirmed;
+        service.store().save(record);
+
+        let entries = service
+            .reconcile_idempotency(vec![payment_id])
+            .await
+            .unwrap();
+        assert_eq!(
+            entries[0].resolution,
+            IdempotencyResolution::SuspectedPhantom
+        );
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::SuspectedPhantom);
+        assert!(!record.is_retryable());
+        // Still unpaid when checked again, and not flagged twice over
+        let entries = service
+            .reconcile_idempotency(vec![payment_id])
+            .await
+            .unwrap();
+        assert_eq!(
+            entries[0].resolution,
+            IdempotencyResolution::SuspectedPhantom
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        let outcome = service.reexecute_phantom(&payment_id).await.unwrap();
+        assert_eq!(outcome.tx_hashes(), vec!["0xabc"]);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().status,
+            PayoutStatus::Submitted
+        );
+        assert!(service.reexecute_phantom(&payment_id).await.is_err());
+
+        // Processed after all: re-execution is refused and the record confirmed
+        let mut record = service.store().get(&payment_id).unwrap();
+        record.status = PayoutStatus::SuspectedPhantom;
+        service.store().save(record);
+        processed.lock().unwrap().insert(payment_id);
+        assert!(service.reexecute_phantom(&payment_id).await.is_err());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().status,
+            PayoutStatus::Confirmed
+        );
+    }
+}
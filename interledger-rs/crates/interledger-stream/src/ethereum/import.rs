@@ -0,0 +1,393 @@
+//! Backfill of payouts made before the store kept them
+//!
+//! Payouts sent by releases that only logged their transaction hash can be
+//! imported with [`EthereumPayoutService::import_records`], so reconciliation
+//! and statements cover them too. Each entry names its payout by payment ID,
+//! by destination and sequence, or both; when the destination and sequence
+//! are given, the payment ID is recomputed from them and must match the one
+//! given, and the destination must pay the entry's recipient and asset. The
+//! transaction hash must be 32 bytes of hex. Entries whose payment ID the
+//! store already has are skipped, so an import can be run again.
+//!
+//! Imported payouts are recorded as confirmed, with serial 0 like records
+//! from before serials were assigned. With `verify_onchain` set, each entry's
+//! transaction is first checked against the chain as
+//! [`verify_onchain`](EthereumPayoutService::verify_onchain) checks stored
+//! payouts, and only entries it matches are recorded, with their block.
+
+use super::destination::is_hex_address;
+use super::store::Timestamp;
+use super::{
+    Attribution, EthereumPayoutService, OnchainVerification, PayoutRecord, PayoutRequest,
+    PayoutStatus, PhaseTimings,
+};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use tracing::info;
+
+/// A payout made before the store kept it, as far as it is known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedPayout {
+    /// `0x`-prefixed hex, required without `destination` and `sequence`
+    pub payment_id: Option<String>,
+    pub destination: Option<String>,
+    pub sequence: Option<u64>,
+    pub recipient: String,
+    pub asset_code: String,
+    /// Amount in the token's base units
+    pub amount: u64,
+    pub tx_hash: String,
+    pub timestamp: Timestamp,
+}
+
+impl ImportedPayout {
+    /// Parse an entry with the fields named as in [`PayoutRecord::to_json`],
+    /// `asset` standing for the asset code and `timestamp` in RFC 3339
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        let text = |name: &str| {
+            value[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("missing {}", name))
+        };
+        let optional = |name: &str| match &value[name] {
+            Value::Null => Ok(None),
+            Value::String(text) => Ok(Some(text.clone())),
+            other => Err(format!("invalid {} {}", name, other)),
+        };
+        let number = |name: &str| match &value[name] {
+            Value::Number(number) => number.as_u64(),
+            Value::String(number) => number.parse().ok(),
+            _ => None,
+        };
+        let sequence = match &value["sequence"] {
+            Value::Null => None,
+            _ => Some(number("sequence").ok_or("invalid sequence")?),
+        };
+        let timestamp = DateTime::parse_from_rfc3339(&text("timestamp")?)
+            .map_err(|err| format!("invalid timestamp: {}", err))?;
+        Ok(ImportedPayout {
+            payment_id: optional("payment_id")?,
+            destination: optional("destination")?,
+            sequence,
+            recipient: text("recipient")?,
+            asset_code: text("asset")?,
+            amount: number("amount").ok_or("missing or invalid amount")?,
+            tx_hash: text("tx_hash")?,
+            timestamp: timestamp.with_timezone(&Utc),
+        })
+    }
+}
+
+/// Whether an import is checked against the chain
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Record only entries whose transaction the chain confirms
+    pub verify_onchain: bool,
+}
+
+/// An entry that was not imported, by its position among the entries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportIssue {
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Outcome of an import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Payment IDs recorded
+    pub accepted: Vec<[u8; 32]>,
+    /// Entries already in the store
+    pub skipped: Vec<ImportIssue>,
+    /// Entries that did not validate or verify
+    pub invalid: Vec<ImportIssue>,
+}
+
+/// Whether `tx_hash` is `0x` and 64 hex digits
+fn is_tx_hash(tx_hash: &str) -> bool {
+    tx_hash
+        .strip_prefix("0x")
+        .is_some_and(|digits| digits.len() == 64 && digits.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+fn parse_payment_id(payment_id: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(payment_id.strip_prefix("0x")?, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+impl EthereumPayoutService {
+    /// Record historical payouts in the store, see [`import`](self)
+    pub async fn import_records(
+        &self,
+        payouts: impl IntoIterator<Item = ImportedPayout>,
+        options: ImportOptions,
+    ) -> ImportReport {
+        let mut report = ImportReport::default();
+        for (index, payout) in payouts.into_iter().enumerate() {
+            let issue = |reason: String| ImportIssue { index, reason };
+            let mut record = match self.imported_record(&payout) {
+                Ok(record) => record,
+                Err(reason) => {
+                    report.invalid.push(issue(reason));
+                    continue;
+                }
+            };
+            if self.store.get(&record.payment_id).is_some() {
+                report.skipped.push(issue(format!(
+                    "payout {} is already in the store",
+                    record.payment_id_hex()
+                )));
+                continue;
+            }
+            if options.verify_onchain {
+                match self.verify_transaction(&record, &payout.tx_hash).await {
+                    Ok(OnchainVerification::Match { block_number, .. }) => {
+                        record.block_number = Some(block_number)
+                    }
+                    Ok(verification) => {
+                        report
+                            .invalid
+                            .push(issue(format!("not verified: {:?}", verification)));
+                        continue;
+                    }
+                    Err(err) => {
+                        report.invalid.push(issue(format!("not verified: {}", err)));
+                        continue;
+                    }
+                }
+            }
+            report.accepted.push(record.payment_id);
+            self.store.save(record);
+        }
+        info!(
+            "Imported {} payouts, skipped {} already stored, {} invalid",
+            report.accepted.len(),
+            report.skipped.len(),
+            report.invalid.len()
+        );
+        report
+    }
+
+    /// Record of an imported payout, or why it does not validate
+    pub(super) fn imported_record(&self, payout: &ImportedPayout) -> Result<PayoutRecord, String> {
+        if !is_tx_hash(&payout.tx_hash) {
+            return Err(format!("malformed tx hash {}", payout.tx_hash));
+        }
+        if !is_hex_address(&payout.recipient) {
+            return Err(format!("malformed recipient {}", payout.recipient));
+        }
+        let given = match &payout.payment_id {
+            Some(payment_id) => Some(
+                parse_payment_id(payment_id)
+                    .ok_or_else(|| format!("malformed payment ID {}", payment_id))?,
+            ),
+            None => None,
+        };
+        let (payment_id, namespace, strategy) = match (&payout.destination, payout.sequence) {
+            (Some(destination), Some(sequence)) => {
+                let eth_dest = self
+                    .config
+                    .parse_destination(destination)
+                    .ok_or_else(|| self.config.destination_error(destination).to_string())?;
+                if !eth_dest.recipient.eq_ignore_ascii_case(&payout.recipient)
+                    || eth_dest.asset_code != payout.asset_code
+                {
+                    return Err(format!(
+                        "destination pays {} {}, not {} {}",
+                        eth_dest.asset_code,
+                        eth_dest.recipient,
+                        payout.asset_code,
+                        payout.recipient
+                    ));
+                }
+                let payment_id = self.config.payment_id(destination, sequence);
+                if given.is_some_and(|given| given != payment_id) {
+                    return Err(format!(
+                        "payment ID does not match destination and sequence, which yield 0x{}",
+                        hex::encode(payment_id)
+                    ));
+                }
+                let request = PayoutRequest::new(destination.as_str(), payout.amount, sequence);
+                (
+                    payment_id,
+                    self.config.payment_id_namespace(),
+                    Some(self.config.payment_id_label(&request)),
+                )
+            }
+            (None, None) => match given {
+                Some(payment_id) => (payment_id, None, None),
+                None => return Err("needs a payment ID or destination and sequence".to_string()),
+            },
+            _ => return Err("destination and sequence must be given together".to_string()),
+        };
+        let decimals = self
+            .config
+            .assets
+            .get(&payout.asset_code)
+            .map_or(0, |asset| asset.decimals);
+        Ok(PayoutRecord {
+            payment_id,
+            namespace,
+            payment_id_strategy: strategy,
+            attribution: Attribution::default(),
+            destination: payout.destination.clone().unwrap_or_default(),
+            sequence: payout.sequence.unwrap_or_default(),
+            recipient: payout.recipient.clone(),
+            asset_code: payout.asset_code.clone(),
+            asset_segment: None,
+            amount: payout.amount,
+            decimals,
+            tx_hash: Some(payout.tx_hash.to_ascii_lowercase()),
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: None,
+            memo: None,
+            status: PayoutStatus::Confirmed,
+            deadline: None,
+            last_error: None,
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: payout.timestamp,
+            serial: 0,
+            pinned_block: None,
+            treasury_payout_id: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport, TEST_DESTINATION};
+    use super::*;
+    use serde_json::json;
+
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    fn tx_hash(n: u8) -> String {
+        format!("0x{}", hex::encode([n; 32]))
+    }
+
+    #[tokio::test]
+    async fn imports_valid_rows_once_and_reports_the_rest() {
+        let service = test_service(test_config(), MockTransport::new());
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        let other_id = format!("0x{}", hex::encode([9; 32]));
+        let rows = [
+            // By destination and sequence, the ID recomputed to match
+            json!({"destination": TEST_DESTINATION, "sequence": 1, "recipient": RECIPIENT,
+                   "asset": "EURC", "amount": 100, "tx_hash": tx_hash(1),
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            // By payment ID alone, the amount as a string
+            json!({"payment_id": other_id, "recipient": RECIPIENT, "asset": "EURC",
+                   "amount": "250", "tx_hash": tx_hash(2), "timestamp": "2024-01-03T00:00:00Z"}),
+            // Duplicates of both
+            json!({"payment_id": format!("0x{}", hex::encode(payment_id)),
+                   "destination": TEST_DESTINATION, "sequence": 1, "recipient": RECIPIENT,
+                   "asset": "EURC", "amount": 100, "tx_hash": tx_hash(1),
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            json!({"payment_id": other_id, "recipient": RECIPIENT, "asset": "EURC",
+                   "amount": 250, "tx_hash": tx_hash(2), "timestamp": "2024-01-03T00:00:00Z"}),
+            // Malformed
+            json!({"destination": TEST_DESTINATION, "sequence": 2, "recipient": RECIPIENT,
+                   "asset": "EURC", "amount": 100, "tx_hash": "0xabc",
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            json!({"payment_id": other_id, "destination": TEST_DESTINATION, "sequence": 3,
+                   "recipient": RECIPIENT, "asset": "EURC", "amount": 1, "tx_hash": tx_hash(3),
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            json!({"destination": TEST_DESTINATION, "sequence": 4, "recipient": RECIPIENT,
+                   "asset": "USDC", "amount": 1, "tx_hash": tx_hash(4),
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            json!({"recipient": RECIPIENT, "asset": "EURC", "amount": 1, "tx_hash": tx_hash(5),
+                   "timestamp": "2024-01-02T03:04:05Z"}),
+            json!({"payment_id": other_id, "recipient": RECIPIENT, "asset": "EURC",
+                   "amount": 1, "tx_hash": tx_hash(6), "timestamp": "yesterday"}),
+        ];
+        let text: String = rows.iter().map(|row| format!("{}\n", row)).collect();
+        let mut unparsed = Vec::new();
+        let payouts: Vec<ImportedPayout> = text
+            .lines()
+            .enumerate()
+            .filter_map(|(line, row)| {
+                let value = serde_json::from_str(row).unwrap();
+                ImportedPayout::from_json(&value)
+                    .map_err(|reason| unparsed.push((line, reason)))
+                    .ok()
+            })
+            .collect();
+        assert_eq!(unparsed.len(), 1);
+        assert!(unparsed[0].1.starts_with("invalid timestamp"));
+
+        let report = service
+            .import_records(payouts.clone(), ImportOptions::default())
+            .await;
+        assert_eq!(report.accepted, [payment_id, [9; 32]]);
+        let indices = |issues: &[ImportIssue]| issues.iter().map(|i| i.index).collect::<Vec<_>>();
+        assert_eq!(indices(&report.skipped), [2, 3]);
+        assert_eq!(indices(&report.invalid), [4, 5, 6, 7]);
+        assert!(report.invalid[0].reason.contains("malformed tx hash"));
+        assert!(report.invalid[1].reason.contains("does not match"));
+        assert!(report.invalid[2].reason.contains("destination pays EURC"));
+        assert!(report.invalid[3].reason.contains("needs a payment ID"));
+
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!((record.amount, record.decimals), (100, 6));
+        assert_eq!(record.tx_hash, Some(tx_hash(1)));
+        assert_eq!(
+            service
+                .store()
+                .get(&[9; 32])
+                .unwrap()
+                .timestamp
+                .to_rfc3339(),
+            "2024-01-03T00:00:00+00:00"
+        );
+
+        // Running it again imports nothing
+        let again = service
+            .import_records(payouts, ImportOptions::default())
+            .await;
+        assert!(again.accepted.is_empty());
+        assert_eq!(again.skipped.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn verified_imports_need_the_chain_to_agree() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionReceipt", Value::Null);
+        transport.on_result("eth_getTransactionByHash", Value::Null);
+        let service = test_service(test_config(), transport);
+        let payout = ImportedPayout {
+            payment_id: None,
+            destination: Some(TEST_DESTINATION.to_string()),
+            sequence: Some(1),
+            recipient: RECIPIENT.to_string(),
+            asset_code: "EURC".to_string(),
+            amount: 100,
+            tx_hash: tx_hash(1),
+            timestamp: Utc::now(),
+        };
+        let options = ImportOptions {
+            verify_onchain: true,
+        };
+        let report = service.import_records(vec![payout], options).await;
+        assert!(report.accepted.is_empty());
+        assert!(
+            report.invalid[0].reason.contains("NotFound"),
+            "{:?}",
+            report
+        );
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        assert!(service.store().get(&payment_id).is_none());
+    }
+}
@@ -0,0 +1,261 @@
+//! JSON-RPC over the IPC socket of a node on the same host
+//!
+//! Endpoints given as a filesystem path or an `ipc://` URL are reached through
+//! the node's unix socket, with one newline-terminated JSON request or
+//! response per line. Requests share one connection and are sent one at a
+//! time. A connection the node closed is replaced by a new one, and a request
+//! that could not be written to it is sent again on the new one. Windows named
+//! pipes are not supported.
+
+use super::{HttpTransport, PayoutError, RpcTransport};
+use async_trait::async_trait;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Whether `endpoint` names an IPC socket rather than an HTTP URL
+pub fn is_ipc_endpoint(endpoint: &str) -> bool {
+    endpoint.starts_with("ipc://") || endpoint.starts_with('/') || endpoint.starts_with("./")
+}
+
+/// Transport for `endpoint`, over IPC for socket paths and HTTP otherwise
+pub(super) fn transport_for(client: &reqwest::Client, endpoint: &str) -> Arc<dyn RpcTransport> {
+    if is_ipc_endpoint(endpoint) {
+        Arc::new(IpcTransport::new(endpoint))
+    } else {
+        Arc::new(HttpTransport::new(client.clone(), endpoint))
+    }
+}
+
+/// Transport writing requests to a node's IPC socket
+pub struct IpcTransport {
+    path: PathBuf,
+    #[cfg(unix)]
+    connection: tokio::sync::Mutex<Option<unix::Connection>>,
+}
+
+impl IpcTransport {
+    /// Connect to the socket at `endpoint`, a path or `ipc://` URL, on first use
+    pub fn new(endpoint: &str) -> Self {
+        IpcTransport {
+            path: PathBuf::from(endpoint.strip_prefix("ipc://").unwrap_or(endpoint)),
+            #[cfg(unix)]
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::io;
+    use std::time::Instant;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tracing::debug;
+
+    pub(super) type Connection = BufReader<UnixStream>;
+
+    impl IpcTransport {
+        async fn connect(&self) -> Result<Connection, PayoutError> {
+            let stream = UnixStream::connect(&self.path).await.map_err(|err| {
+                PayoutError::Transport(format!(
+                    "Cannot connect to IPC socket {}: {}",
+                    self.path.display(),
+                    err
+                ))
+            })?;
+            Ok(BufReader::new(stream))
+        }
+    }
+
+    /// Whether a write failed because the node closed its end
+    fn is_disconnect(err: &io::Error) -> bool {
+        matches!(
+            err.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+        )
+    }
+
+    #[async_trait]
+    impl RpcTransport for IpcTransport {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            let method = request["method"].as_str().unwrap_or_default().to_string();
+            let started = Instant::now();
+            let mut line = request.to_string();
+            line.push('\n');
+
+            let mut connection = self.connection.lock().await;
+            let mut reconnected = false;
+            loop {
+                if connection.is_none() {
+                    *connection = Some(self.connect().await?);
+                }
+                let stream = connection.as_mut().expect("connected above");
+                match stream.get_mut().write_all(line.as_bytes()).await {
+                    Ok(()) => break,
+                    Err(err) if is_disconnect(&err) && !reconnected => {
+                        debug!("IPC socket {} closed, reconnecting", self.path.display());
+                        *connection = None;
+                        reconnected = true;
+                    }
+                    Err(err) => {
+                        *connection = None;
+                        return Err(PayoutError::Transport(err.to_string()));
+                    }
+                }
+            }
+
+            let stream = connection.as_mut().expect("connected above");
+            let mut response = String::new();
+            let read = stream.read_line(&mut response).await;
+            // The request may have reached the node, so it is not sent again
+            let read = match read {
+                Ok(0) => Err("IPC socket closed before responding".to_string()),
+                Ok(_) => Ok(()),
+                Err(err) => Err(err.to_string()),
+            };
+            if let Err(err) = read {
+                *connection = None;
+                return Err(PayoutError::Transport(err));
+            }
+            debug!(
+                rpc.method = %method,
+                rpc.duration_ms = started.elapsed().as_millis() as u64,
+                "JSON-RPC request completed"
+            );
+            serde_json::from_str(&response)
+                .map_err(|err| PayoutError::InvalidResponse(err.to_string()))
+        }
+
+        fn reset(&self) {
+            // A connection in use is replaced by the request holding it if it fails
+            if let Ok(mut connection) = self.connection.try_lock() {
+                *connection = None;
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+#[async_trait]
+impl RpcTransport for IpcTransport {
+    async fn send(&self, _request: Value) -> Result<Value, PayoutError> {
+        Err(PayoutError::Config(format!(
+            "IPC endpoint {} needs unix sockets; Windows named pipes are not supported, use HTTP",
+            self.path.display()
+        )))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("node-{}.ipc", uuid::Uuid::new_v4()))
+    }
+
+    /// Answer `eth_blockNumber` requests on `path`, closing each connection
+    /// after `per_connection` responses
+    fn serve(path: &Path, per_connection: usize) -> Arc<AtomicUsize> {
+        let listener = UnixListener::bind(path).unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                for _ in 0..per_connection {
+                    let line = match lines.next_line().await {
+                        Ok(Some(line)) => line,
+                        _ => break,
+                    };
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    let response = json!({
+                        "jsonrpc": "2.0",
+                        "id": request["id"],
+                        "result": format!("0x{:x}", request["id"].as_u64().unwrap()),
+                    });
+                    let mut line = response.to_string();
+                    line.push('\n');
+                    write.write_all(line.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        connections
+    }
+
+    fn request(id: u64) -> Value {
+        json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": [], "id": id})
+    }
+
+    #[test]
+    fn recognizes_socket_endpoints() {
+        assert!(is_ipc_endpoint("/var/run/nethermind.ipc"));
+        assert!(is_ipc_endpoint("ipc:///data/geth.ipc"));
+        assert!(!is_ipc_endpoint("http://localhost:8545"));
+        assert_eq!(
+            IpcTransport::new("ipc:///data/geth.ipc").path(),
+            Path::new("/data/geth.ipc")
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_requests_over_one_connection() {
+        let path = socket_path();
+        let connections = serve(&path, usize::MAX);
+        let transport = IpcTransport::new(path.to_str().unwrap());
+        for id in 1..=3 {
+            let response = transport.send(request(id)).await.unwrap();
+            assert_eq!(response["id"], id);
+            assert_eq!(response["result"], format!("0x{:x}", id));
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_the_node_closes_the_socket() {
+        let path = socket_path();
+        let connections = serve(&path, 1);
+        let transport = IpcTransport::new(&format!("ipc://{}", path.display()));
+        assert_eq!(transport.send(request(1)).await.unwrap()["result"], "0x1");
+        // The node hung up after answering, so each request after the first
+        // needs a new connection
+        for id in 2..=4 {
+            let response = loop {
+                match transport.send(request(id)).await {
+                    Ok(response) => break response,
+                    // Closed between the write and the read; safe to try again here
+                    Err(PayoutError::Transport(_)) => continue,
+                    Err(err) => panic!("{}", err),
+                }
+            };
+            assert_eq!(response["id"], id);
+        }
+        assert!(connections.load(Ordering::SeqCst) >= 4);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn missing_socket_is_a_transport_error() {
+        let transport = IpcTransport::new(socket_path().to_str().unwrap());
+        assert!(matches!(
+            transport.send(request(1)).await,
+            Err(PayoutError::Transport(message)) if message.contains("Cannot connect")
+        ));
+    }
+}
@@ -0,0 +1,187 @@
+//! Connector-wide kill switch
+//!
+//! A sentinel file that every instance watches, e.g. on a shared volume, stops
+//! payouts everywhere at once without reaching each process's admin API.
+//! While the file exists the service behaves as if the Treasury were paused:
+//! payouts are deferred, or refused under [`KillSwitchMode::Reject`]. Once it
+//! is removed, payouts resume and the deferred ones are drained.
+
+use super::shutdown::unless_cancelled;
+use super::EthereumPayoutService;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// What happens to payouts while the kill switch is engaged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KillSwitchMode {
+    /// Deferred like payouts to a paused Treasury, and drained on removal
+    #[default]
+    Defer,
+    /// Refused with `KillSwitchEngaged`
+    Reject,
+}
+
+impl KillSwitchMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "defer" => Some(KillSwitchMode::Defer),
+            "reject" => Some(KillSwitchMode::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// Sentinel file stopping payouts while it exists
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KillSwitchConfig {
+    pub path: PathBuf,
+    /// How often the file is looked for
+    pub interval: Duration,
+    pub mode: KillSwitchMode,
+}
+
+impl KillSwitchConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        KillSwitchConfig {
+            path: path.into(),
+            interval: Duration::from_secs(5),
+            mode: KillSwitchMode::default(),
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Whether the kill switch was present when last looked for
+    pub fn is_kill_switch_engaged(&self) -> bool {
+        self.kill_switch.load(Ordering::SeqCst)
+    }
+
+    /// Look for the kill switch, pausing or resuming payouts on a change.
+    ///
+    /// When it turns out to be removed after having been present, deferred
+    /// payouts are drained before returning.
+    pub async fn check_kill_switch(&self) -> bool {
+        let config = match &self.config.kill_switch {
+            Some(config) => config,
+            None => return false,
+        };
+        let engaged = config.path.exists();
+        if engaged {
+            if !self.kill_switch.swap(true, Ordering::SeqCst) {
+                error!(
+                    "ALERT: kill switch {} is present; {} payouts until it is removed",
+                    config.path.display(),
+                    match config.mode {
+                        KillSwitchMode::Defer => "deferring",
+                        KillSwitchMode::Reject => "refusing",
+                    }
+                );
+            }
+        } else if self.kill_switch.swap(false, Ordering::SeqCst) {
+            info!(
+                "Kill switch {} was removed; draining {} deferred payouts",
+                config.path.display(),
+                self.deferred_count()
+            );
+            self.drain_deferred().await;
+        }
+        engaged
+    }
+
+    /// Spawn a task looking for the kill switch on its interval.
+    ///
+    /// Returns `None` when no kill switch is configured. The task stops on shutdown.
+    pub fn spawn_kill_switch_monitor(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval = self.config.kill_switch.as_ref()?.interval;
+        let service = Arc::clone(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                service.check_kill_switch().await;
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{PayoutError, PayoutOutcome};
+    use super::*;
+
+    fn sentinel() -> PathBuf {
+        std::env::temp_dir().join(format!("payouts-stop-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn sentinel_pauses_and_its_removal_drains() {
+        let path = sentinel();
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.kill_switch = Some(KillSwitchConfig {
+            interval: Duration::from_millis(10),
+            ..KillSwitchConfig::new(&path)
+        });
+        let service = Arc::new(test_service(config, transport.clone()));
+        let monitor = service.spawn_kill_switch_monitor().unwrap();
+
+        std::fs::write(&path, "incident 42").unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(service.is_kill_switch_engaged());
+        assert!(service.is_paused());
+        for sequence in 1..=2 {
+            assert_eq!(
+                service
+                    .execute_payout(TEST_DESTINATION, 100, sequence)
+                    .await
+                    .unwrap(),
+                PayoutOutcome::Deferred
+            );
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        std::fs::remove_file(&path).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!service.is_kill_switch_engaged());
+        assert_eq!(service.deferred_count(), 0);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+        monitor.abort();
+    }
+
+    #[tokio::test]
+    async fn reject_mode_refuses_payouts() {
+        let path = sentinel();
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.kill_switch = Some(KillSwitchConfig {
+            mode: KillSwitchMode::Reject,
+            ..KillSwitchConfig::new(&path)
+        });
+        let service = test_service(config, transport.clone());
+
+        std::fs::write(&path, "").unwrap();
+        assert!(service.check_kill_switch().await);
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::KillSwitchEngaged { .. }));
+        assert!(err.is_transient());
+        assert_eq!(service.deferred_count(), 0);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(!service.check_kill_switch().await);
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_ok());
+    }
+}
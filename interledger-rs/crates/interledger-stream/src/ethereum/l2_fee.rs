@@ -0,0 +1,334 @@
+//! Fee components of L2 chains that `gasPrice` does not reflect
+//!
+//! OP-stack chains (Optimism, Base) charge every transaction an L1 data fee on
+//! top of its L2 gas. It is quoted by the `GasPriceOracle` predeploy for the
+//! serialized transaction before sending, counted against `max_gas_cost`, and
+//! added to the recorded cost from the receipt's `l1Fee`, or from the quote if
+//! the receipt does not report it.
+//!
+//! Arbitrum instead charges its L1 component as extra L2 gas, so receipts
+//! already account for it. It is only estimated, with `gasEstimateComponents`
+//! of the `NodeInterface`, for the `max_gas_cost` check; the submitted gas
+//! limit is left as it is.
+
+use super::abi::{decode_words, encode_address, encode_uint, selector};
+use super::rlp::unsigned_transaction;
+use super::rpc::parse_quantity;
+use super::{EthereumPayoutService, PayoutError, PayoutPlan, TxParams, Wei};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+/// `GasPriceOracle` predeploy of OP-stack chains
+pub const GAS_PRICE_ORACLE_ADDRESS: &str = "0x420000000000000000000000000000000000000F";
+
+/// `NodeInterface` precompile of Arbitrum chains, only reachable through `eth_call`
+pub const NODE_INTERFACE_ADDRESS: &str = "0x00000000000000000000000000000000000000C8";
+
+/// How the chain charges for publishing transactions to L1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum L2FeeModel {
+    /// No fee beyond gas used times gas price
+    #[default]
+    None,
+    /// L1 data fee quoted by the `GasPriceOracle` and paid on top of L2 gas
+    OpStack,
+    /// L1 component charged as L2 gas, estimated by the `NodeInterface`
+    Arbitrum,
+}
+
+impl L2FeeModel {
+    pub fn parse(model: &str) -> Option<Self> {
+        match model {
+            "none" => Some(L2FeeModel::None),
+            "op-stack" => Some(L2FeeModel::OpStack),
+            "arbitrum" => Some(L2FeeModel::Arbitrum),
+            _ => None,
+        }
+    }
+}
+
+/// Calldata of `getL1Fee(bytes)` for a serialized transaction
+pub fn get_l1_fee_calldata(tx: &[u8]) -> String {
+    let mut data = selector("getL1Fee(bytes)").to_vec();
+    data.extend_from_slice(&dynamic_bytes(tx, 1));
+    format!("0x{}", hex::encode(data))
+}
+
+/// Calldata of `gasEstimateComponents(address,bool,bytes)` for a call to `to`
+fn gas_estimate_components_calldata(to: &str, data: &str) -> Option<String> {
+    let data = hex::decode(data.strip_prefix("0x").unwrap_or(data)).ok()?;
+    let mut call = selector("gasEstimateComponents(address,bool,bytes)").to_vec();
+    call.extend_from_slice(&encode_address(to)?);
+    call.extend_from_slice(&[0u8; 32]);
+    call.extend_from_slice(&dynamic_bytes(&data, 3));
+    Some(format!("0x{}", hex::encode(call)))
+}
+
+/// Offset, length and padded contents of the only dynamic argument, following
+/// `static_words` static ones (itself included)
+fn dynamic_bytes(bytes: &[u8], static_words: usize) -> Vec<u8> {
+    let mut encoded = encode_uint(static_words as u128 * 32).to_vec();
+    encoded.extend_from_slice(&encode_uint(bytes.len() as u128));
+    encoded.extend_from_slice(bytes);
+    encoded.resize(encoded.len() + (32 - bytes.len() % 32) % 32, 0);
+    encoded
+}
+
+/// L1 data fee an OP-stack receipt reports, `None` if it has none
+pub(super) fn receipt_l1_fee(receipt: &Value) -> Result<Option<Wei>, PayoutError> {
+    match receipt.get("l1Fee") {
+        Some(fee) if !fee.is_null() => Ok(Some(Wei::new(u128::from(parse_quantity(fee)?)))),
+        _ => Ok(None),
+    }
+}
+
+impl EthereumPayoutService {
+    /// L1 fee the planned transaction is expected to cost beyond its L2 gas,
+    /// `None` on chains without one
+    pub(super) async fn l1_fee(
+        &self,
+        plan: &PayoutPlan,
+        params: TxParams,
+    ) -> Result<Option<Wei>, PayoutError> {
+        let (to, data) = match self.config.l2_fee_model {
+            L2FeeModel::None => return Ok(None),
+            L2FeeModel::OpStack => {
+                let tx = unsigned_transaction(
+                    &plan.to,
+                    &plan.data,
+                    plan.value,
+                    params,
+                    self.config.expected_chain_id,
+                )
+                .ok_or_else(|| {
+                    PayoutError::Config(format!("Cannot serialize a transaction to {}", plan.to))
+                })?;
+                (GAS_PRICE_ORACLE_ADDRESS, get_l1_fee_calldata(&tx))
+            }
+            L2FeeModel::Arbitrum => {
+                let data =
+                    gas_estimate_components_calldata(&plan.to, &plan.data).ok_or_else(|| {
+                        PayoutError::Config(format!("Invalid payout target {}", plan.to))
+                    })?;
+                (NODE_INTERFACE_ADDRESS, data)
+            }
+        };
+        let result = self
+            .read_at_block("eth_call", vec![json!({ "to": to, "data": data })])
+            .await?;
+        let words = result.as_str().and_then(decode_words).ok_or_else(|| {
+            PayoutError::InvalidResponse(format!("L1 fee call returned {}", result))
+        })?;
+        let fee = match (self.config.l2_fee_model, words.as_slice()) {
+            (L2FeeModel::OpStack, [fee, ..]) => low_u128(fee).map(Wei::new),
+            // (gasEstimate, gasEstimateForL1, baseFee, l1BaseFeeEstimate)
+            (L2FeeModel::Arbitrum, [_, l1_gas, base_fee, ..]) => low_u128(l1_gas)
+                .and_then(|gas| u64::try_from(gas).ok())
+                .zip(low_u128(base_fee))
+                .and_then(|(gas, price)| Wei::for_gas(gas, Wei::new(price))),
+            _ => None,
+        };
+        fee.map(Some)
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("L1 fee call returned {}", result)))
+    }
+
+    /// Fail with `GasCostTooHigh` if the transaction may cost more than
+    /// `max_gas_cost`, returning its expected L1 fee
+    pub(super) async fn check_gas_cost(
+        &self,
+        plan: &PayoutPlan,
+        params: TxParams,
+    ) -> Result<Option<Wei>, PayoutError> {
+        if self.config.l2_fee_model == L2FeeModel::None && self.config.max_gas_cost.is_none() {
+            return Ok(None);
+        }
+        let l1_fee = self.l1_fee(plan, params).await?;
+        if let Some(max) = self.config.max_gas_cost {
+            let cost = Wei::for_gas(params.gas_limit, Wei::new(u128::from(params.gas_price)))
+                .and_then(|gas| gas.checked_add(l1_fee.unwrap_or_default()))
+                .unwrap_or(Wei::new(u128::MAX));
+            if cost.value() > max {
+                return Err(PayoutError::GasCostTooHigh {
+                    cost: cost.value(),
+                    max,
+                });
+            }
+        }
+        // Arbitrum's component is already part of the gas the receipt reports
+        Ok(l1_fee.filter(|_| self.config.l2_fee_model == L2FeeModel::OpStack))
+    }
+}
+
+/// Word as a u128, `None` if it does not fit
+fn low_u128(word: &[u8; 32]) -> Option<u128> {
+    if word[..16].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    let mut low = [0u8; 16];
+    low.copy_from_slice(&word[16..]);
+    Some(u128::from_be_bytes(low))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, MockTransport, TEST_DESTINATION, TEST_TREASURY,
+    };
+    use super::super::EthereumPayoutConfig;
+    use super::*;
+    use std::sync::Arc;
+
+    fn node(l1_fee: u64) -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_result("eth_sendRawTransaction", json!(format!("0x{:064x}", 1)));
+        transport.on_result("eth_call", json!(format!("0x{:064x}", l1_fee)));
+        transport
+    }
+
+    /// Calls made to quote the L1 fee, leaving out the payout's simulation
+    fn fee_calls(transport: &MockTransport) -> Vec<Value> {
+        transport
+            .calls("eth_call")
+            .into_iter()
+            .map(|call| call["params"][0].clone())
+            .filter(|call| {
+                !call["to"]
+                    .as_str()
+                    .unwrap()
+                    .eq_ignore_ascii_case(TEST_TREASURY)
+            })
+            .collect()
+    }
+
+    fn op_stack(max_gas_cost: Option<u128>) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.l2_fee_model = L2FeeModel::OpStack;
+        config.max_gas_cost = max_gas_cost;
+        config
+    }
+
+    #[test]
+    fn wraps_the_transaction_in_get_l1_fee_calldata() {
+        let params = TxParams {
+            nonce: 9,
+            gas_limit: 21_000,
+            gas_price: 20_000_000_000,
+        };
+        let tx = unsigned_transaction(
+            "0x3535353535353535353535353535353535353535",
+            "0x",
+            1_000_000_000_000_000_000,
+            params,
+            1,
+        )
+        .unwrap();
+        let calldata = get_l1_fee_calldata(&tx);
+        let words = decode_words(&calldata[10..]).unwrap();
+        assert_eq!(low_u128(&words[0]), Some(32));
+        assert_eq!(low_u128(&words[1]), Some(tx.len() as u128));
+        assert_eq!(words.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn records_the_quoted_l1_fee_with_the_payout() {
+        let transport = node(7_000_000_000_000);
+        let service = test_service(op_stack(None), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        let call = &fee_calls(&transport)[0];
+        assert_eq!(call["to"], GAS_PRICE_ORACLE_ADDRESS);
+        assert!(call["data"]
+            .as_str()
+            .unwrap()
+            .starts_with(&format!("0x{}", hex::encode(selector("getL1Fee(bytes)")))));
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().l1_fee,
+            Some(7_000_000_000_000)
+        );
+
+        // The receipt's own figure replaces the quote and adds to the gas cost
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({
+                "blockNumber": "0x5",
+                "gasUsed": "0x5208",
+                "effectiveGasPrice": "0x3b9aca00",
+                "status": "0x1",
+                "l1Fee": format!("0x{:x}", 6_000_000_000_000u64),
+            }),
+        );
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.l1_fee, Some(6_000_000_000_000));
+        assert_eq!(
+            record.gas_cost,
+            Some(21_000 * 1_000_000_000 + 6_000_000_000_000)
+        );
+    }
+
+    #[tokio::test]
+    async fn caps_the_combined_cost() {
+        // 100000 gas at 1 gwei is 1e14 wei of L2 gas
+        let l2_cost = 100_000u128 * 1_000_000_000;
+        let transport = node(50_000_000_000_000);
+        let service = test_service(op_stack(Some(l2_cost + 40_000_000_000_000)), transport);
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::GasCostTooHigh { cost, .. } if cost == l2_cost + 50_000_000_000_000
+        ));
+        assert!(err.is_transient());
+
+        let service = test_service(
+            op_stack(Some(l2_cost + 50_000_000_000_000)),
+            node(50_000_000_000_000),
+        );
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn estimates_arbitrum_l1_gas_for_the_cap_only() {
+        let transport = node(0);
+        // 30000 gas of the estimate is for L1, at a base fee of 0.1 gwei
+        transport.on_result(
+            "eth_call",
+            json!(format!(
+                "0x{:064x}{:064x}{:064x}{:064x}",
+                90_000, 30_000, 100_000_000, 20_000_000_000u64
+            )),
+        );
+        let mut config = test_config();
+        config.l2_fee_model = L2FeeModel::Arbitrum;
+        config.max_gas_cost = Some(100_000 * 1_000_000_000 + 3_000_000_000_000);
+        let service = test_service(config, transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(fee_calls(&transport)[0]["to"], NODE_INTERFACE_ADDRESS);
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        assert_eq!(service.store().get(&payment_id).unwrap().l1_fee, None);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_chains_alone() {
+        let transport = node(1);
+        let service = test_service(test_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert!(fee_calls(&transport).is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+//! Daily caps on the value paid out per asset and per recipient
+//!
+//! Totals are summed from the payout store for the current UTC day, so they
+//! survive restarts with a persistent store and reset at midnight UTC.
+//!
+//! Payouts being sent are not in the store yet, so the amounts that passed the
+//! caps are reserved until their records are saved or the send fails. Without
+//! that, concurrent payouts could each pass the check and together exceed a cap.
+
+use super::{metrics, EthereumPayoutService, PayoutError, PayoutPlan};
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Amounts that passed the caps and are being sent, by payment ID
+#[derive(Default)]
+pub(super) struct Reservations {
+    held: HashMap<[u8; 32], Held>,
+}
+
+struct Held {
+    asset_code: String,
+    recipient: String,
+    day: NaiveDate,
+    amount: u64,
+}
+
+/// Amounts held against the day's caps, released when dropped
+pub(super) struct Reservation {
+    reservations: Arc<Mutex<Reservations>>,
+    payment_ids: Vec<[u8; 32]>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut reservations = self.reservations.lock().unwrap();
+        for payment_id in &self.payment_ids {
+            reservations.held.remove(payment_id);
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Refuse the planned payouts if they would take the day's totals past a cap.
+    ///
+    /// Chunks already committed by an earlier attempt count towards the totals
+    /// but not towards the new amount, so retries are not refused twice over.
+    pub(super) fn check_daily_limits(&self, plans: &[PayoutPlan]) -> Result<(), PayoutError> {
+        let reservations = self.reservations.lock().unwrap();
+        self.limit_breach(plans, &reservations).map(|_| ())
+    }
+
+    /// Check the caps as [`Self::check_daily_limits`] does and hold the new
+    /// amount against them until the returned reservation is dropped
+    pub(super) fn reserve_daily_limits(
+        &self,
+        plans: &[PayoutPlan],
+    ) -> Result<Reservation, PayoutError> {
+        let mut reservations = self.reservations.lock().unwrap();
+        let unreserved = match self.limit_breach(plans, &reservations) {
+            Ok(unreserved) => unreserved,
+            Err(err) => {
+                // Validation passed, so a concurrent payout took the room first
+                if let PayoutError::LimitExceeded {
+                    asset_code, scope, ..
+                } = &err
+                {
+                    metrics::limit_exceeded(self.config.tenant.as_deref(), asset_code, scope);
+                }
+                return Err(err);
+            }
+        };
+        let day = self.clock.now().naive_utc().date();
+        let mut payment_ids = Vec::with_capacity(unreserved.len());
+        for plan in unreserved {
+            reservations.held.insert(
+                plan.payment_id,
+                Held {
+                    asset_code: plan.destination.asset_code.clone(),
+                    recipient: plan.destination.recipient.clone(),
+                    day,
+                    amount: plan.amount,
+                },
+            );
+            payment_ids.push(plan.payment_id);
+        }
+        Ok(Reservation {
+            reservations: self.reservations.clone(),
+            payment_ids,
+        })
+    }
+
+    /// The plans neither committed nor reserved yet, unless their amount
+    /// would take the day's totals past a cap
+    fn limit_breach<'a>(
+        &self,
+        plans: &'a [PayoutPlan],
+        reservations: &Reservations,
+    ) -> Result<Vec<&'a PayoutPlan>, PayoutError> {
+        let destination = &plans[0].destination;
+        let asset = match self.config.assets.get(&destination.asset_code) {
+            Some(asset) if asset.daily_cap.is_some() || asset.recipient_daily_cap.is_some() => {
+                asset
+            }
+            _ => return Ok(Vec::new()),
+        };
+        let committed = |payment_id: &[u8; 32]| {
+            self.store
+                .get(payment_id)
+                .is_some_and(|record| record.is_committed())
+        };
+        let unreserved: Vec<&PayoutPlan> = plans
+            .iter()
+            .filter(|plan| {
+                !committed(&plan.payment_id) && !reservations.held.contains_key(&plan.payment_id)
+            })
+            .collect();
+        let amount: u64 = unreserved.iter().map(|plan| plan.amount).sum();
+        if amount == 0 {
+            return Ok(unreserved);
+        }
+
+        let day = self.clock.now().naive_utc().date();
+        let mut totals = self
+            .store
+            .daily_totals(&asset.code, &destination.recipient, day);
+        // Reservations whose record was saved are in the store's totals already
+        for (payment_id, held) in &reservations.held {
+            if held.asset_code != asset.code || held.day != day || committed(payment_id) {
+                continue;
+            }
+            totals.asset += u128::from(held.amount);
+            if held.recipient.eq_ignore_ascii_case(&destination.recipient) {
+                totals.recipient += u128::from(held.amount);
+            }
+        }
+        let breached = [
+            ("asset", asset.daily_cap, totals.asset),
+            ("recipient", asset.recipient_daily_cap, totals.recipient),
+        ]
+        .iter()
+        .find_map(|&(scope, cap, total)| {
+            cap.filter(|cap| total + u128::from(amount) > u128::from(*cap))
+                .map(|cap| (scope, cap))
+        });
+        match breached {
+            Some((scope, cap)) => Err(PayoutError::LimitExceeded {
+                asset_code: asset.code.clone(),
+                recipient: destination.recipient.clone(),
+                amount,
+                scope,
+                cap,
+                asset_total: totals.asset,
+                recipient_total: totals.recipient,
+            }),
+            None => Ok(unreserved),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, TEST_DESTINATION,
+    };
+    use super::super::{EthereumPayoutConfig, PayoutStatus};
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+
+    const OTHER_DESTINATION: &str =
+        "test.receiver.eth.31337.EURC.0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC.abc123";
+
+    fn capped_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.apply_daily_caps("EURC:1000").unwrap();
+        config
+            .assets
+            .apply_recipient_daily_caps("EURC:600")
+            .unwrap();
+        config
+    }
+
+    #[tokio::test]
+    async fn accumulates_until_a_cap_is_breached() {
+        let transport = mock_chain();
+        let service = test_service(capped_config(), transport.clone()).with_clock(FakeClock::new());
+
+        service
+            .execute_payout(TEST_DESTINATION, 400, 1)
+            .await
+            .unwrap();
+        let err = service
+            .execute_payout(TEST_DESTINATION, 201, 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::LimitExceeded {
+                scope: "recipient",
+                cap: 600,
+                asset_total: 400,
+                recipient_total: 400,
+                ..
+            }
+        ));
+
+        // Other recipients still fit under the asset cap, until it is reached
+        service
+            .execute_payout(OTHER_DESTINATION, 600, 1)
+            .await
+            .unwrap();
+        let err = service
+            .execute_payout(TEST_DESTINATION, 1, 3)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::LimitExceeded {
+                scope: "asset",
+                asset_total: 1000,
+                recipient_total: 400,
+                ..
+            }
+        ));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_payouts_do_not_count_and_replays_are_not_refused() {
+        let transport = mock_chain();
+        let service = test_service(capped_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 600, 1)
+            .await
+            .unwrap();
+        // Resending the same payment is deduplicated rather than counted again
+        service
+            .execute_payout(TEST_DESTINATION, 600, 1)
+            .await
+            .unwrap();
+
+        let id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let mut record = service.store.get(&id).unwrap();
+        record.status = PayoutStatus::Failed;
+        service.store.save(record);
+        service
+            .execute_payout(TEST_DESTINATION, 600, 2)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn totals_reset_at_the_day_boundary() {
+        let clock = FakeClock::new();
+        let service = test_service(capped_config(), mock_chain()).with_clock(clock.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 600, 1)
+            .await
+            .unwrap();
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 1, 2)
+            .await
+            .is_err());
+
+        clock.advance(Duration::from_secs(24 * 60 * 60));
+        service
+            .execute_payout(TEST_DESTINATION, 600, 2)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn totals_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut config = capped_config();
+        config.store_path = Some(path.clone());
+        test_service(config.clone(), mock_chain())
+            .execute_payout(TEST_DESTINATION, 600, 1)
+            .await
+            .unwrap();
+
+        let restarted = test_service(config, mock_chain());
+        assert!(matches!(
+            restarted.execute_payout(TEST_DESTINATION, 1, 2).await,
+            Err(PayoutError::LimitExceeded {
+                recipient_total: 600,
+                ..
+            })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_payouts_cannot_both_take_the_last_room() {
+        let transport = mock_chain();
+        // Keeps both payouts in flight at once
+        transport.delay("eth_sendRawTransaction", Duration::from_millis(50));
+        let service = test_service(capped_config(), transport.clone());
+
+        let (first, second) = tokio::join!(
+            service.execute_payout(TEST_DESTINATION, 400, 1),
+            service.execute_payout(TEST_DESTINATION, 400, 2),
+        );
+        let refused = [&first, &second]
+            .iter()
+            .filter(|outcome| {
+                matches!(
+                    outcome,
+                    Err(PayoutError::LimitExceeded {
+                        scope: "recipient",
+                        ..
+                    })
+                )
+            })
+            .count();
+        assert_eq!(refused, 1);
+        assert!(first.is_ok() || second.is_ok());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_sends_release_their_reservation() {
+        let transport = mock_chain();
+        transport.on_error(
+            "eth_sendRawTransaction",
+            -32000,
+            "insufficient funds for gas",
+        );
+        let service = test_service(capped_config(), transport.clone());
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 600, 1)
+            .await
+            .is_err());
+        assert!(service.reservations.lock().unwrap().held.is_empty());
+
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        service
+            .execute_payout(TEST_DESTINATION, 600, 2)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! Counters reported by the Ethereum payout service
+//!
+//! Written to the global `metrics` recorder, which the node exports over
+//! Prometheus when instrumentation is enabled. Metrics of a service set up for
+//! a tenant carry a `tenant` label.
+
+use super::Wei;
+use metrics::{labels, recorder, Key, Label};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// Key of metric `name`, labelled with the tenant if there is one
+fn key(name: &'static str, tenant: Option<&str>, mut labels: Vec<Label>) -> Key {
+    if let Some(tenant) = tenant {
+        labels.push(Label::new("tenant", tenant.to_string()));
+    }
+    Key::from_name_and_labels(name, labels)
+}
+
+/// Connections to an endpoint were dropped, `reason` being "max_age", "stale",
+/// "repeated_failures" or "requested"
+pub(super) fn connection_reset(tenant: Option<&str>, endpoint: &str, reason: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.connection_resets",
+            tenant,
+            labels!("endpoint" => endpoint.to_string(), "reason" => reason),
+        ),
+        1,
+    );
+}
+
+/// A payout was refused for a sequence too far behind its destination's highest
+pub(super) fn sequence_rejected(tenant: Option<&str>) {
+    recorder().increment_counter(
+        key("payouts.ethereum.sequence_rejected", tenant, Vec::new()),
+        1,
+    );
+}
+
+/// A payment was not paid out, see `SkipReason::label`
+pub(super) fn payout_skipped(tenant: Option<&str>, reason: &'static str, amount: u64) {
+    let key = |name| key(name, tenant, labels!("reason" => reason));
+    recorder().increment_counter(key("payouts.ethereum.skipped"), 1);
+    recorder().increment_counter(key("payouts.ethereum.skipped_amount"), amount);
+}
+
+/// A payout arrived for a sequence that already had a record
+pub(super) fn sequence_repeated(tenant: Option<&str>) {
+    recorder().increment_counter(
+        key("payouts.ethereum.sequence_repeated", tenant, Vec::new()),
+        1,
+    );
+}
+
+/// Gas paid by a mined payout transaction, accumulated per asset and chain
+pub(super) fn gas_cost(tenant: Option<&str>, asset_code: &str, chain_id: u64, cost: Wei) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.gas_cost_wei",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "chain_id" => chain_id.to_string()),
+        ),
+        u64::try_from(cost.value()).unwrap_or(u64::MAX),
+    );
+}
+
+/// Share of a mined payout's gas cost attributed to `tag`, `other` outside
+/// the top tags
+pub(super) fn attributed_gas_cost(tenant: Option<&str>, tag: &str, cost: u128) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.attributed_gas_cost_wei",
+            tenant,
+            labels!("tag" => tag.to_string()),
+        ),
+        u64::try_from(cost).unwrap_or(u64::MAX),
+    );
+}
+
+/// A gas estimate lookup, `result` being "hit", "miss", "invalidated" or
+/// "fallback" when estimating failed
+pub(super) fn gas_estimate(tenant: Option<&str>, result: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.gas_estimate_cache",
+            tenant,
+            labels!("result" => result),
+        ),
+        1,
+    );
+}
+
+/// Time a payout spent in `phase`, see `PayoutPhase::as_str`
+pub(super) fn phase_duration(tenant: Option<&str>, phase: &'static str, duration: Duration) {
+    recorder().record_histogram(
+        key(
+            "payouts.ethereum.phase_duration_us",
+            tenant,
+            labels!("phase" => phase),
+        ),
+        u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+    );
+}
+
+/// Latency a payout's concurrent pre-flight reads saved
+pub(super) fn preflight_saved(tenant: Option<&str>, duration: Duration) {
+    recorder().record_histogram(
+        key("payouts.ethereum.preflight_saved_us", tenant, Vec::new()),
+        u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+    );
+}
+
+/// An RPC endpoint refused a request for exceeding its rate limit
+pub(super) fn throttled(tenant: Option<&str>, endpoint: &str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.rpc_throttled",
+            tenant,
+            labels!("endpoint" => endpoint.to_string()),
+        ),
+        1,
+    );
+}
+
+/// An RPC endpoint answered with a pending nonce lower than one read before
+pub(super) fn nonce_regression(tenant: Option<&str>, endpoint: &str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.nonce_regressions",
+            tenant,
+            labels!("endpoint" => endpoint.to_string()),
+        ),
+        1,
+    );
+}
+
+/// Rolling statistics of an RPC endpoint and its share of traffic
+pub(super) fn endpoint_stats(tenant: Option<&str>, stats: &super::EndpointStats) {
+    let gauge = |name: &'static str, value: i64| {
+        recorder().update_gauge(
+            key(name, tenant, labels!("endpoint" => stats.endpoint.clone())),
+            value,
+        )
+    };
+    gauge(
+        "payouts.ethereum.rpc_endpoint_weight",
+        i64::from(stats.weight),
+    );
+    gauge(
+        "payouts.ethereum.rpc_endpoint_requests",
+        stats.requests as i64,
+    );
+    gauge(
+        "payouts.ethereum.rpc_endpoint_successes",
+        stats.successes as i64,
+    );
+    if let Some(p95) = stats.p95_latency {
+        gauge(
+            "payouts.ethereum.rpc_endpoint_p95_ms",
+            p95.as_millis() as i64,
+        );
+    }
+}
+
+/// Payouts to a recipient waiting for or holding their turn
+pub(super) fn recipient_queue_depth(tenant: Option<&str>, recipient: &str, depth: u64) {
+    recorder().update_gauge(
+        key(
+            "payouts.ethereum.recipient_queue_depth",
+            tenant,
+            labels!("recipient" => recipient.to_string()),
+        ),
+        depth as i64,
+    );
+}
+
+/// A payout was abandoned at its deadline
+pub(super) fn abandoned(tenant: Option<&str>, asset_code: &str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.abandoned",
+            tenant,
+            labels!("asset_code" => asset_code.to_string()),
+        ),
+        1,
+    );
+}
+
+/// A payout was refused because it would exceed a daily cap
+pub(super) fn limit_exceeded(tenant: Option<&str>, asset_code: &str, scope: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.limit_exceeded",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "scope" => scope),
+        ),
+        1,
+    );
+}
+
+/// A payout was refused because of its recipient
+pub(super) fn recipient_rejected(tenant: Option<&str>, asset_code: &str, reason: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.recipient_rejected",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "reason" => reason),
+        ),
+        1,
+    );
+}
+
+/// A token payout delivered another amount than was sent; `expected` for fee-on-transfer assets
+pub(super) fn amount_mismatch(tenant: Option<&str>, asset_code: &str, expected: bool) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.amount_mismatch",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "expected" => expected.to_string()),
+        ),
+        1,
+    );
+}
+
+/// A transiently failed payout was scheduled for retry, given up on or
+/// dropped from the full retry queue
+pub(super) fn retry(tenant: Option<&str>, asset_code: &str, result: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.retry",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "result" => result),
+        ),
+        1,
+    );
+}
+
+/// An `approve` topping up the Treasury's allowance was sent, or the check failed
+pub(super) fn allowance_approval(tenant: Option<&str>, asset_code: &str, result: &'static str) {
+    recorder().increment_counter(
+        key(
+            "payouts.ethereum.allowance_approvals",
+            tenant,
+            labels!("asset_code" => asset_code.to_string(), "result" => result),
+        ),
+        1,
+    );
+}
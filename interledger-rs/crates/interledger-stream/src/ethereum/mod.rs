@@ -0,0 +1,2304 @@
+//! Ethereum payout module for ILP STREAM payments
+//!
+//! This module handles on-chain payouts when STREAM payments are received.
+//! It parses the destination address to extract the recipient wallet and
+//! triggers a Treasury contract payout via direct JSON-RPC calls.
+
+mod abi;
+mod acceptance;
+mod access;
+mod address;
+mod allowance;
+mod amount;
+mod approval;
+mod asset_segment;
+mod assets;
+mod attribution;
+mod authorization;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod call_layout;
+mod cancel;
+mod capabilities;
+mod clock;
+mod composite;
+mod config;
+mod connection;
+mod deadline;
+mod delivery;
+mod destination;
+mod dispatch;
+mod dry_run;
+mod dust;
+mod eip712;
+mod endpoints;
+mod error;
+mod estimate;
+mod events;
+mod export;
+mod exposition;
+#[cfg(any(test, feature = "testing"))]
+mod fake_chain;
+mod gas;
+mod gas_fallback;
+mod hash;
+mod health;
+mod idempotency;
+mod import;
+mod ipc;
+mod kill_switch;
+mod l2_fee;
+mod limits;
+mod metrics;
+mod namespace;
+mod nonce;
+mod ordering;
+mod pause;
+mod payload;
+mod payment_id;
+mod pinning;
+mod preflight;
+mod preview;
+mod proof;
+mod provider;
+mod rate;
+mod read_only;
+mod receipt;
+mod recipient;
+mod recipient_info;
+mod recipient_state;
+mod redaction;
+#[cfg(feature = "redis-nonce-store")]
+mod redis_nonce;
+mod replay;
+mod retry;
+mod revert;
+mod rlp;
+mod rotation;
+mod rounding;
+mod rpc;
+mod safe;
+mod self_test;
+mod sequence;
+mod shutdown;
+mod signature;
+mod signer;
+mod simulation;
+mod skip;
+mod standby;
+mod statement;
+mod status;
+mod store;
+mod streak;
+mod submission;
+mod tenant;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod throttle;
+mod timing;
+#[cfg(feature = "otel")]
+mod trace;
+#[cfg(feature = "erc4337")]
+mod user_op;
+mod verification;
+mod version;
+
+pub use acceptance::{would_accept_stream_payout, RejectKind, RejectReason};
+pub use access::{has_role_calldata, AuthorizationState};
+pub use address::{AddressFormat, AddressFormats, StreamTokenFormat, TokenAlphabet};
+pub use allowance::{
+    allowance_calldata, approve_calldata, AllowanceApproval, AllowanceCap, AllowancePolicy,
+    TokenAllowance,
+};
+pub use amount::{IlpAmount, TokenAmount, Wei};
+pub use approval::ApprovalObserver;
+pub use assets::{AssetInfo, AssetRegistry, PayoutMode, TokenDomain};
+pub use attribution::{AttributedCost, Attribution, OTHER_TAG, UNTAGGED};
+pub use authorization::{TransferAuthorization, TRANSFER_WITH_AUTHORIZATION_TYPE};
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingPayoutService;
+pub use call_layout::{PayoutArgument, PayoutCall};
+pub use cancel::CancelOutcome;
+pub use capabilities::{
+    TreasuryCapabilities, BATCH_PAYOUT_SIGNATURE, DEFAULT_VERSION_VIEW, IS_PROCESSED_SIGNATURE,
+};
+pub use clock::{Clock, SystemClock};
+pub use composite::{ChildSummary, CompositePayout, CompositeStatus};
+pub use config::{EthereumPayoutConfig, RoleCheckConfig, DEFAULT_OPERATOR_ROLE};
+pub use connection::{
+    is_stale_connection, ConnectionPolicy, HttpClientFactory, RecyclingTransport,
+};
+pub use delivery::{received_amount, AmountMismatch, TRANSFER_EVENT_SIGNATURE};
+pub use destination::EthereumDestination;
+pub use dispatch::{Dispatched, ExecutionMode, PayoutObserver};
+pub use dry_run::{parse_traffic, BlockedPayout, DryRunReport, PayoutTotals};
+pub use dust::{dust_payment_id, DustPayout, FlushSchedule};
+pub use eip712::{hash_struct, typed_data_hash, Eip712Domain};
+pub use endpoints::{EndpointPool, EndpointStats};
+pub use error::PayoutError;
+pub use estimate::GasEstimateStats;
+pub use events::{PayoutEvent, StampedEvent};
+pub use export::format_amount;
+pub use gas_fallback::{GasFallback, GasFallbacks};
+pub use hash::PaymentIdHash;
+pub use health::{payout_health, tenant_health, HealthReport, HealthStatus};
+pub use idempotency::{IdempotencyEntry, IdempotencyResolution};
+pub use import::{ImportIssue, ImportOptions, ImportReport, ImportedPayout};
+pub use ipc::{is_ipc_endpoint, IpcTransport};
+pub use kill_switch::{KillSwitchConfig, KillSwitchMode};
+pub use l2_fee::{
+    get_l1_fee_calldata, L2FeeModel, GAS_PRICE_ORACLE_ADDRESS, NODE_INTERFACE_ADDRESS,
+};
+pub use namespace::migrate_payment_ids;
+pub use nonce::{InMemoryNonceStore, NonceLease, NonceStore};
+pub use ordering::RecipientOrdering;
+pub use pause::{is_pause_revert, ENFORCED_PAUSE_SELECTOR};
+pub use payload::{
+    chunk_payment_id, payout_calldata, payout_memo_calldata, plan_payout, plan_payouts,
+    plan_request, send_transaction_request, transfer_calldata, PayoutPlan, PayoutRequest, TxParams,
+    DEFAULT_GAS_LIMIT, NATIVE_TRANSFER_GAS_LIMIT, PAYOUT_TO_USER_SELECTOR,
+    PAYOUT_WITH_MEMO_SIGNATURE,
+};
+pub use payment_id::{PaymentIdStrategy, EXTERNAL_PAYMENT_ID};
+pub use pinning::{is_missing_state, BlockPinning};
+pub use preview::{PayoutBlocker, PayoutPreview};
+pub use proof::{payment_id_preimage, verify_proof, PayoutProof, ProofMismatch};
+pub use provider::{is_unsupported_method, ProviderCapabilities};
+pub use rate::{Conversion, ExchangeRate, RateProvider, RoundingMode, StaticRateProvider};
+pub use read_only::is_signer_unavailable;
+pub use receipt::TransactionReceipt;
+pub use recipient::RecipientDenyList;
+pub use recipient_info::{RecipientInfo, RecipientKind};
+pub use recipient_state::DEFAULT_RECIPIENT_STATE_CAPACITY;
+pub use redaction::{DestinationPolicy, LogMask, TRUNCATED_TOKEN_CHARS};
+#[cfg(feature = "redis-nonce-store")]
+pub use redis_nonce::{RedisNonceStore, DEFAULT_NONCE_KEY_PREFIX};
+pub use replay::{replay, ReplayResult};
+pub use retry::{RetryAttempt, RetryPolicy, RetrySchedule, RetryState};
+pub use revert::{AbiType, DecodedRevert, ErrorSignature, RevertDecoder};
+pub use rlp::unsigned_transaction;
+pub use rotation::KeyRotation;
+pub use rpc::{HttpTransport, RpcTransport};
+pub use safe::{SafeConfig, SafeTx, SAFE_TX_TYPE};
+pub use self_test::{
+    CanaryPayout, CheckResult, SelfTestCheck, SelfTestOptions, SelfTestReport, DEV_CHAIN_IDS,
+};
+pub use sequence::SequenceStats;
+pub use signature::{
+    legacy_signing_hash, signed_legacy_transaction, Signature, VEncoding, MAX_EIP155_CHAIN_ID,
+};
+pub use signer::{is_node_signer_error, normalize_private_key, LocalSigner, Signer};
+pub use skip::{skipped_payouts, SkipReason, SkipStats, SkipTotals};
+pub use standby::init_payout_service;
+pub use statement::{AssetTotal, RecipientStatement, StatementEntry};
+pub use status::{status, ServiceStatus, MISCONFIGURED_WARN_INTERVAL, REQUIRED_ENV};
+pub use store::{
+    DailyTotals, FilePayoutStore, InMemoryPayoutStore, PageCursor, PayoutRecord, PayoutStatus,
+    PayoutStore, StoreMigration, Timestamp, STORE_SCHEMA_VERSION,
+};
+pub use streak::{FailureStreak, FailureStreakPolicy};
+pub use tenant::{load_tenants, PayoutRouter, Tenant, TenantConfig, TENANTS_FILE_ENV};
+pub use throttle::parse_retry_after;
+pub use timing::{PayoutPhase, PhaseTimings};
+#[cfg(feature = "otel")]
+pub use trace::{set_trace_context_source, TraceContext, TraceContextSource};
+#[cfg(feature = "erc4337")]
+pub use user_op::{
+    eth_signed_message_hash, execute_calldata, UserOpConfig, UserOperation, ENTRY_POINT_V06,
+};
+pub use verification::{is_pruned_history, OnchainVerification, PAYOUT_EXECUTED_EVENT_SIGNATURE};
+pub use version::{compiled_features, VersionInfo, CRATE_VERSION};
+
+use preflight::Preflight;
+use recipient_state::RecipientStates;
+use retry::Jitter;
+use rpc::rpc_response;
+use serde_json::{json, Value};
+use signer::Submission;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex, RwLock};
+use timing::timed;
+use tokio::sync::{broadcast, Notify};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn, Instrument};
+
+/// Result of a payout that did not fail
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PayoutOutcome {
+    /// Transaction broadcast with the given hash
+    Submitted { tx_hash: String },
+    /// Split into several transactions, in chunk order. Chunks proposed to
+    /// the Safe are listed by their `safeTxHash` until the owners execute them
+    Split {
+        tx_hashes: Vec<String>,
+        safe_tx_hashes: Vec<String>,
+    },
+    /// Proposed to the Safe, which executes it once enough owners approve
+    PendingApproval { safe_tx_hash: String },
+    /// Above the asset's approval threshold; sent once approved
+    HeldForApproval { payment_id: String },
+    /// Queued until the Treasury is unpaused
+    Deferred,
+    /// Interrupted by shutdown before it was sent; resume with `retry_failed`
+    Cancelled { payment_id: String },
+    /// Below the asset's minimum; added to the recipient's dust balance
+    Accumulated { balance: u64 },
+    /// Nothing to pay; the request's amount was zero
+    SkippedZeroAmount,
+    /// Nothing to pay; a nonzero amount converted to zero in the payout asset
+    RoundedToZero {
+        source_asset: String,
+        source_amount: u64,
+    },
+}
+
+impl PayoutOutcome {
+    /// Hash of a payout sent as a single transaction
+    pub fn tx_hash(&self) -> Option<&str> {
+        match self {
+            PayoutOutcome::Submitted { tx_hash } => Some(tx_hash),
+            _ => None,
+        }
+    }
+
+    /// Hashes of all transactions sent for the payout
+    pub fn tx_hashes(&self) -> Vec<&str> {
+        match self {
+            PayoutOutcome::Submitted { tx_hash } => vec![tx_hash.as_str()],
+            PayoutOutcome::Split { tx_hashes, .. } => {
+                tx_hashes.iter().map(String::as_str).collect()
+            }
+            PayoutOutcome::PendingApproval { .. }
+            | PayoutOutcome::HeldForApproval { .. }
+            | PayoutOutcome::Deferred
+            | PayoutOutcome::Cancelled { .. }
+            | PayoutOutcome::Accumulated { .. }
+            | PayoutOutcome::SkippedZeroAmount
+            | PayoutOutcome::RoundedToZero { .. } => Vec::new(),
+        }
+    }
+}
+
+/// A request that passed every check, and what executing it does
+struct Validated {
+    eth_dest: EthereumDestination,
+    /// The sequence was paid before, so this is an idempotent retry
+    repeated: bool,
+    /// Rate applied to the request's amount, if it is in another asset
+    conversion: Option<Conversion>,
+    disposition: Disposition,
+}
+
+enum Disposition {
+    /// Nothing to pay
+    Skipped(PayoutOutcome),
+    /// Deferred until the Treasury is unpaused
+    Paused,
+    /// Added to the recipient's dust balance, being below `min_payout`
+    Dust {
+        amount: TokenAmount,
+        min_payout: u64,
+    },
+    /// Sent as the planned transactions, once approved if needed
+    Send {
+        plans: Vec<PayoutPlan>,
+        amount: TokenAmount,
+        approval: approval::Approval,
+    },
+}
+
+/// Ethereum payout service using raw JSON-RPC
+pub struct EthereumPayoutService {
+    config: EthereumPayoutConfig,
+    transport: Arc<dyn RpcTransport>,
+    /// Set when requests are spread over several endpoints
+    endpoints: Option<Arc<EndpointPool>>,
+    /// Bundler endpoint for UserOperations
+    #[cfg(feature = "erc4337")]
+    bundler: Option<Arc<dyn RpcTransport>>,
+    http: reqwest::Client,
+    /// Account payouts are sent from, see [`EthereumPayoutService::rotate_operator_key`]
+    operator: RwLock<rotation::Operator>,
+    /// Held for reading while a transaction is nonced and sent, and for
+    /// writing while the operator is replaced
+    signing: tokio::sync::RwLock<()>,
+    store: Arc<dyn PayoutStore>,
+    /// Converts ILP amounts received in another asset than the payout asset
+    rates: Option<Arc<dyn RateProvider>>,
+    authorization: Mutex<AuthorizationState>,
+    paused: AtomicBool,
+    /// Whether the kill switch file was present when last looked for
+    kill_switch: AtomicBool,
+    /// Signs digests in place of the operator key, see [`EthereumPayoutService::with_signer`]
+    signer: Option<Arc<dyn Signer>>,
+    /// Whether payouts are held until the signer responds again
+    signer_unavailable: AtomicBool,
+    deferred: Mutex<VecDeque<PayoutRequest>>,
+    /// Payouts waiting for the queue worker in `Queued` mode
+    queue: Mutex<VecDeque<PayoutRequest>>,
+    queue_ready: Arc<Notify>,
+    sequence_counters: RecipientStates<SequenceStats>,
+    /// Safe nonce to use after the proposals made so far, held while proposing
+    safe_nonce: tokio::sync::Mutex<Option<u64>>,
+    /// Runtime background tasks are spawned on, the current one if `None`
+    runtime: Option<tokio::runtime::Handle>,
+    clock: Arc<dyn Clock>,
+    jitter: Jitter,
+    /// When the node last answered a request, for health reporting
+    last_rpc_response: Mutex<Option<Timestamp>>,
+    health_cache: Mutex<health::HealthCache>,
+    /// Latest gas price from the background sampler
+    gas_quote: Mutex<Option<gas::GasQuote>>,
+    gas_estimates: Mutex<estimate::GasEstimateCache>,
+    /// Code presence and payouts per recipient, shared by every feature
+    recipients: RecipientStates<RecipientInfo>,
+    /// What the Treasury was found to support at startup
+    treasury_capabilities: Mutex<Option<TreasuryCapabilities>>,
+    /// Optional RPC methods the provider was found to serve
+    provider_capabilities: Mutex<ProviderCapabilities>,
+    /// Gas cost attributed to each tag so far, ranking metric labels
+    attribution_totals: Mutex<attribution::AttributionTotals>,
+    /// Split payouts whose chunks have not all ended
+    splits: Mutex<composite::Splits>,
+    events: broadcast::Sender<StampedEvent>,
+    retry_queue: Mutex<retry::RetryQueue>,
+    /// Turns of payouts per recipient when they are ordered
+    lanes: Arc<Mutex<ordering::Lanes>>,
+    /// Amounts held against the daily caps while their payouts are sent
+    reservations: Arc<Mutex<limits::Reservations>>,
+    approval_observer: Option<Arc<dyn ApprovalObserver>>,
+    payout_observer: Option<Arc<dyn PayoutObserver>>,
+    dust: RecipientStates<dust::DustBalance>,
+    /// Rounding residue not yet carried into a payout, per recipient
+    residue: RecipientStates<rounding::Residue>,
+    /// Consecutive failed payouts per recipient
+    failure_streaks: RecipientStates<streak::StreakState>,
+    /// Time until which RPC requests wait after the provider throttled one
+    throttled_until: Mutex<Option<Timestamp>>,
+    /// Id of the next JSON-RPC request, so responses can be matched to requests
+    rpc_ids: AtomicU64,
+    /// Last serial given to a record or event
+    serials: AtomicU64,
+    /// Nonces shared with other senders using the operator key
+    nonce_store: Option<Arc<dyn NonceStore>>,
+    /// Nonces handed out by this service when no shared store is set
+    local_nonces: InMemoryNonceStore,
+    /// Highest pending nonce read since the last resync
+    pending_nonces: nonce::PendingNonces,
+    /// Host shutdown signal observed by every wait
+    cancellation: CancellationToken,
+    /// Counters rendered by [`EthereumPayoutService::metrics_text`]
+    counters: exposition::PayoutCounters,
+}
+
+impl EthereumPayoutService {
+    /// Create a new Ethereum payout service
+    pub fn new(mut config: EthereumPayoutConfig) -> Result<Self, PayoutError> {
+        let build: HttpClientFactory = Arc::new(reqwest::Client::new);
+        let http = build();
+        let endpoints = rpc_endpoints(&http, Some(&build), &config);
+        let transport = match &endpoints {
+            Some(pool) => pool.clone(),
+            None => connection::endpoint_transport(&http, Some(&build), &config.rpc_url, &config),
+        };
+
+        let operator_address = config.load_operator_key()?;
+        if config.expected_chain_id > MAX_EIP155_CHAIN_ID {
+            return Err(PayoutError::Config(format!(
+                "CHAIN_ID {} is above the largest EIP-155 chain ID {}",
+                config.expected_chain_id, MAX_EIP155_CHAIN_ID
+            )));
+        }
+
+        let authorization = match config.role_check {
+            Some(_) => AuthorizationState::Unchecked,
+            None => AuthorizationState::Disabled,
+        };
+
+        #[cfg(feature = "erc4337")]
+        let bundler = config.user_op.as_ref().map(|user_op| {
+            Arc::new(HttpTransport::new(
+                http.clone(),
+                user_op.bundler_url.clone(),
+            )) as Arc<dyn RpcTransport>
+        });
+
+        let store: Arc<dyn PayoutStore> = match &config.store_path {
+            Some(path) => Arc::new(
+                FilePayoutStore::open_with(path, config.store_migration).map_err(|err| {
+                    PayoutError::Config(format!(
+                        "Cannot open payout store {}: {}",
+                        path.display(),
+                        err
+                    ))
+                })?,
+            ),
+            None => Arc::new(InMemoryPayoutStore::new()),
+        };
+
+        #[cfg(feature = "redis-nonce-store")]
+        let nonce_store = match &config.nonce_store_url {
+            Some(url) => Some(Arc::new(RedisNonceStore::open(url)?) as Arc<dyn NonceStore>),
+            None => None,
+        };
+        #[cfg(not(feature = "redis-nonce-store"))]
+        let nonce_store = None;
+
+        let rates = config
+            .static_rates
+            .clone()
+            .map(|rates| Arc::new(rates) as Arc<dyn RateProvider>);
+        let (events, _) = broadcast::channel(config.event_channel_capacity.max(1));
+        let counters = exposition::PayoutCounters::new(&config.assets);
+        let capacity = config.recipient_state_capacity;
+        let provider = ProviderCapabilities::unknown(&config.rpc_url);
+        let operator = rotation::Operator {
+            address: operator_address,
+            key: config.operator_private_key.clone(),
+        };
+
+        let service = EthereumPayoutService {
+            config,
+            transport,
+            endpoints,
+            #[cfg(feature = "erc4337")]
+            bundler,
+            http,
+            operator: RwLock::new(operator),
+            signing: tokio::sync::RwLock::new(()),
+            store,
+            rates,
+            authorization: Mutex::new(authorization),
+            paused: AtomicBool::new(false),
+            kill_switch: AtomicBool::new(false),
+            signer: None,
+            signer_unavailable: AtomicBool::new(false),
+            deferred: Mutex::new(VecDeque::new()),
+            queue: Mutex::new(VecDeque::new()),
+            queue_ready: Arc::new(Notify::new()),
+            sequence_counters: RecipientStates::new("sequence", capacity),
+            safe_nonce: tokio::sync::Mutex::new(None),
+            runtime: None,
+            clock: Arc::new(SystemClock),
+            jitter: Jitter::from_time(),
+            last_rpc_response: Mutex::new(None),
+            health_cache: Mutex::default(),
+            gas_quote: Mutex::new(None),
+            gas_estimates: Mutex::default(),
+            recipients: RecipientStates::new("recipient_info", capacity),
+            treasury_capabilities: Mutex::new(None),
+            provider_capabilities: Mutex::new(provider),
+            attribution_totals: Mutex::default(),
+            splits: Mutex::default(),
+            events,
+            retry_queue: Mutex::default(),
+            lanes: Arc::default(),
+            reservations: Arc::default(),
+            approval_observer: None,
+            payout_observer: None,
+            dust: RecipientStates::new("dust", capacity),
+            residue: RecipientStates::new("residue", capacity),
+            failure_streaks: RecipientStates::new(streak::FAILURE_STREAK_STATE, capacity),
+            throttled_until: Mutex::new(None),
+            rpc_ids: AtomicU64::new(1),
+            serials: AtomicU64::new(0),
+            nonce_store,
+            local_nonces: InMemoryNonceStore::new(),
+            pending_nonces: Default::default(),
+            cancellation: CancellationToken::new(),
+            counters,
+        };
+        info!(
+            "Ethereum payout service initialized: treasury={}, chain_id={}, operator={}, version={}",
+            service.config.treasury_address,
+            service.config.expected_chain_id,
+            service.operator_address(),
+            service.version_info().compact()
+        );
+        Ok(service)
+    }
+
+    /// Send JSON-RPC requests through the given transport instead of `rpc_url`
+    pub fn with_transport(mut self, transport: Arc<dyn RpcTransport>) -> Self {
+        self.transport = transport;
+        self.endpoints = None;
+        self
+    }
+
+    /// Spread JSON-RPC requests over the pool's endpoints by their performance
+    pub fn with_endpoint_pool(mut self, pool: Arc<EndpointPool>) -> Self {
+        self.transport = pool.clone();
+        self.endpoints = Some(pool);
+        self
+    }
+
+    /// Make all HTTP requests with the host's client, e.g. to share its
+    /// connection pool and proxy settings.
+    ///
+    /// This rebuilds the HTTP transports, so it replaces a transport set
+    /// earlier through `with_transport`. The client is never replaced, so
+    /// resetting connections to the node relies on its pool settings.
+    pub fn with_http_client(self, http: reqwest::Client) -> Self {
+        self.use_http_client(http, None)
+    }
+
+    /// Like [`Self::with_http_client`], with clients from `build`, which is
+    /// called again for a new client whenever connections to the node are reset
+    pub fn with_http_client_builder(
+        self,
+        build: impl Fn() -> reqwest::Client + Send + Sync + 'static,
+    ) -> Self {
+        let build: HttpClientFactory = Arc::new(build);
+        self.use_http_client(build(), Some(build))
+    }
+
+    fn use_http_client(mut self, http: reqwest::Client, build: Option<HttpClientFactory>) -> Self {
+        self.endpoints = rpc_endpoints(&http, build.as_ref(), &self.config);
+        self.transport = match &self.endpoints {
+            Some(pool) => pool.clone(),
+            None => connection::endpoint_transport(
+                &http,
+                build.as_ref(),
+                &self.config.rpc_url,
+                &self.config,
+            ),
+        };
+        #[cfg(feature = "erc4337")]
+        if let Some(user_op) = &self.config.user_op {
+            self.bundler = Some(Arc::new(HttpTransport::new(
+                http.clone(),
+                user_op.bundler_url.clone(),
+            )));
+        }
+        self.http = http;
+        self
+    }
+
+    /// Spawn background tasks on the given runtime instead of the current one
+    pub fn with_runtime(mut self, runtime: tokio::runtime::Handle) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
+    /// Use the given clock for deadlines, retries and timestamps
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Seed the jitter of retry delays, making their schedule reproducible
+    pub fn with_jitter_seed(mut self, seed: u64) -> Self {
+        self.jitter = Jitter::new(seed);
+        self
+    }
+
+    /// Send bundler requests through the given transport instead of HTTP to `bundler_url`
+    #[cfg(feature = "erc4337")]
+    pub fn with_bundler_transport(mut self, transport: Arc<dyn RpcTransport>) -> Self {
+        self.bundler = Some(transport);
+        self
+    }
+
+    /// Use the given store for payout records instead of the default in-memory one
+    pub fn with_store(mut self, store: Arc<dyn PayoutStore>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Quote rates through the given provider instead of the configured static rates
+    pub fn with_rate_provider(mut self, rates: Arc<dyn RateProvider>) -> Self {
+        self.rates = Some(rates);
+        self
+    }
+
+    /// Notify `observer` when payouts are held for approval and when they are resolved
+    pub fn with_approval_observer(mut self, observer: Arc<dyn ApprovalObserver>) -> Self {
+        self.approval_observer = Some(observer);
+        self
+    }
+
+    /// Report the results of spawned and queued payouts to `observer`
+    pub fn with_payout_observer(mut self, observer: Arc<dyn PayoutObserver>) -> Self {
+        self.payout_observer = Some(observer);
+        self
+    }
+
+    /// Sign transactions, Safe proposals, authorizations and user operations
+    /// with `signer` instead of the operator key. Payouts are sent from the
+    /// signer's account, whose nonces and roles are the ones read.
+    pub fn with_signer(mut self, signer: Arc<dyn Signer>) -> Self {
+        let operator = self.operator.get_mut().unwrap();
+        let address = signer.address();
+        if !address.eq_ignore_ascii_case(&operator.address) {
+            info!(
+                "Operator is {}, the account of the injected signer, not {} of the configured key",
+                address, operator.address
+            );
+        }
+        operator.address = address;
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Reserve nonces through a store shared with other senders of the operator key
+    pub fn with_nonce_store(mut self, store: Arc<dyn NonceStore>) -> Self {
+        self.nonce_store = Some(store);
+        self
+    }
+
+    /// Stop waiting on polls, retries and background tasks once `token` is cancelled
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Rolling statistics per RPC endpoint, empty with a single endpoint
+    pub fn endpoint_stats(&self) -> Vec<EndpointStats> {
+        self.endpoints
+            .as_ref()
+            .map_or_else(Vec::new, |pool| pool.stats())
+    }
+
+    pub fn config(&self) -> &EthereumPayoutConfig {
+        &self.config
+    }
+
+    pub fn store(&self) -> &dyn PayoutStore {
+        self.store.as_ref()
+    }
+
+    /// Write confirmed payouts with `from <= timestamp < to` to `writer` as CSV
+    pub fn export_csv<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        from: Timestamp,
+        to: Timestamp,
+    ) -> std::io::Result<usize> {
+        export::export_csv(self.store.as_ref(), writer, from, to)
+    }
+
+    /// Execute a payout to the recipient
+    pub async fn execute_payout(
+        &self,
+        destination: &str,
+        amount: u64,
+        sequence: u64,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        self.execute(&PayoutRequest::new(destination, amount, sequence))
+            .await
+    }
+
+    /// Execute a payout of `amount` received in `source_asset`, converting it
+    /// to the payout asset if the two differ
+    pub async fn execute_payout_from(
+        &self,
+        source_asset: &str,
+        destination: &str,
+        amount: u64,
+        sequence: u64,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        self.execute(
+            &PayoutRequest::new(destination, amount, sequence).with_source_asset(source_asset),
+        )
+        .await
+    }
+
+    async fn execute(&self, request: &PayoutRequest) -> Result<PayoutOutcome, PayoutError> {
+        let turn = self.take_turn(request);
+        self.execute_in_turn(request, turn).await
+    }
+
+    /// Execute the request once the payouts ahead of it in its recipient's lane finished
+    async fn execute_in_turn(
+        &self,
+        request: &PayoutRequest,
+        turn: Option<ordering::Turn>,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let span = self.payout_span(
+            &request.destination,
+            request.sequence,
+            &self.request_payment_id(request),
+        );
+        let result = async {
+            match &turn {
+                Some(turn) => match self.wait_turn(turn, request).await {
+                    Ok(()) => self.execute_request(request).await,
+                    Err(err) => Err(err),
+                },
+                None => self.execute_request(request).await,
+            }
+        }
+        .instrument(span)
+        .await;
+        if let Some(turn) = turn {
+            self.finish_turn(turn, request, result.is_err());
+        }
+        self.track_failure_streak(request, &result);
+        self.note_recipient_payout(request, &result);
+        if let Err(err) = &result {
+            if let Some(destination) = self.config.parse_destination(&request.destination) {
+                self.counters.failed(&destination.asset_code);
+            }
+            // Split payouts report their failed chunk, and the parent once it ends
+            if !matches!(err, PayoutError::PartialPayout { .. }) {
+                self.publish_payout(
+                    &request.destination,
+                    request.sequence,
+                    PayoutEvent::Failed {
+                        payment_id: self.request_payment_id(request),
+                        reason: err.to_string(),
+                    },
+                );
+            }
+        }
+        result
+    }
+
+    /// Hex payment ID of the request's payout, or of its first chunk if split
+    fn request_payment_id(&self, request: &PayoutRequest) -> String {
+        format!("0x{}", hex::encode(self.config.payment_id_of(request)))
+    }
+
+    async fn execute_request(&self, request: &PayoutRequest) -> Result<PayoutOutcome, PayoutError> {
+        let validated = self.validate(request).await;
+        let destination = request.destination.as_str();
+        match &validated {
+            Ok(validated) => {
+                self.count_sequence(destination, request.sequence, Ok(validated.repeated))
+            }
+            Err(err) => {
+                self.count_sequence(destination, request.sequence, Err(err));
+                if let PayoutError::LimitExceeded {
+                    asset_code, scope, ..
+                } = err
+                {
+                    metrics::limit_exceeded(self.config.tenant.as_deref(), asset_code, scope);
+                }
+            }
+        }
+        let Validated {
+            eth_dest,
+            conversion,
+            disposition,
+            ..
+        } = validated?;
+        // Rounding residue counts once the converted amount is paid or dropped
+        let settle_residue = || {
+            if let Some(conversion) = &conversion {
+                self.settle_residue(&eth_dest, conversion);
+            }
+        };
+
+        match disposition {
+            Disposition::Skipped(outcome) => {
+                match &outcome {
+                    PayoutOutcome::RoundedToZero {
+                        source_asset,
+                        source_amount,
+                    } => warn!(
+                        "Skipping payout to {}: {} {} rounds to zero {}",
+                        self.display_destination(destination),
+                        source_amount,
+                        source_asset,
+                        eth_dest.asset_code
+                    ),
+                    _ => debug!(
+                        "Skipping zero-amount payout to {}",
+                        self.display_destination(destination)
+                    ),
+                }
+                let reason = match outcome {
+                    PayoutOutcome::RoundedToZero { .. } => "rounded to zero",
+                    _ => "zero amount",
+                };
+                self.publish_payout(
+                    &request.destination,
+                    request.sequence,
+                    PayoutEvent::Skipped {
+                        payment_id: self.request_payment_id(request),
+                        reason: reason.to_string(),
+                    },
+                );
+                settle_residue();
+                Ok(outcome)
+            }
+            Disposition::Paused => Ok(self.defer(request.clone())),
+            Disposition::Dust { amount, .. } => {
+                settle_residue();
+                Ok(self.accumulate_dust(request, &eth_dest, amount.base_units))
+            }
+            Disposition::Send {
+                plans,
+                amount,
+                approval,
+            } => {
+                if let Some(outcome) = self.hold_for_approval(request, &plans, &amount, approval) {
+                    return Ok(outcome);
+                }
+                // Released once the sent payouts are in the store's totals
+                let _reservation = self.reserve_daily_limits(&plans)?;
+                let outcome = self.execute_plans(request, &plans).await?;
+                settle_residue();
+                Ok(outcome)
+            }
+        }
+    }
+
+    /// Run every check on a request and decide what executing it does,
+    /// without recording or sending anything
+    ///
+    /// Execution and previews both go through here, so a preview cannot
+    /// disagree with what execution would do.
+    async fn validate(&self, request: &PayoutRequest) -> Result<Validated, PayoutError> {
+        let PayoutRequest {
+            destination,
+            amount,
+            sequence,
+            ..
+        } = request;
+        let (destination, amount, sequence) = (destination.as_str(), *amount, *sequence);
+
+        let eth_dest = self.check_destination(request)?;
+        if amount == 0 {
+            if self.config.reject_zero_amounts {
+                return Err(PayoutError::ZeroAmount {
+                    destination: destination.to_string(),
+                });
+            }
+            return Ok(Validated {
+                eth_dest,
+                repeated: false,
+                conversion: None,
+                disposition: Disposition::Skipped(PayoutOutcome::SkippedZeroAmount),
+            });
+        }
+        let repeated = self.check_sequence(destination, sequence)?;
+        self.check_operational()?;
+        if self.is_paused() {
+            return Ok(Validated {
+                eth_dest,
+                repeated,
+                conversion: None,
+                disposition: Disposition::Paused,
+            });
+        }
+
+        let source = self.rescale(request, &eth_dest)?;
+        let conversion = self.convert(request, source).await?;
+        let amount = match &conversion {
+            Some((_, converted)) => converted.clone(),
+            None => source.in_asset(&eth_dest.asset_code),
+        };
+        let conversion = conversion.map(|(conversion, _)| conversion);
+        let validated = |eth_dest, disposition| Validated {
+            eth_dest,
+            repeated,
+            conversion: conversion.clone(),
+            disposition,
+        };
+        if amount.is_zero() {
+            // Usually a sign that the asset scales or the rate are misconfigured
+            let source_asset = request
+                .source_asset
+                .clone()
+                .unwrap_or_else(|| eth_dest.asset_code.clone());
+            if self.config.reject_zero_amounts {
+                return Err(PayoutError::RoundedToZero {
+                    source_asset,
+                    source_amount: request.amount,
+                    asset_code: eth_dest.asset_code.clone(),
+                });
+            }
+            return Ok(validated(
+                eth_dest,
+                Disposition::Skipped(PayoutOutcome::RoundedToZero {
+                    source_asset,
+                    source_amount: request.amount,
+                }),
+            ));
+        }
+        // A memo references this payout alone, so it is never merged into dust
+        if request.memo.is_none() {
+            if let Some(min_payout) = dust::dust_minimum(&self.config, &eth_dest, &amount) {
+                return Ok(validated(
+                    eth_dest,
+                    Disposition::Dust { amount, min_payout },
+                ));
+            }
+        }
+        let plans = plan_request(&self.config, request, &amount, conversion.as_ref())?;
+        self.check_daily_limits(&plans)?;
+        let approval = self.approval(request, &plans, &amount)?;
+        Ok(validated(
+            eth_dest,
+            Disposition::Send {
+                plans,
+                amount,
+                approval,
+            },
+        ))
+    }
+
+    /// The request's destination, failing unless it parses, its recipient may
+    /// be paid and its memo can be carried
+    fn check_destination(
+        &self,
+        request: &PayoutRequest,
+    ) -> Result<EthereumDestination, PayoutError> {
+        let destination = request.destination.as_str();
+        let eth_dest = self
+            .config
+            .parse_destination(destination)
+            .ok_or_else(|| self.config.destination_error(destination))?;
+        self.config.denied_recipients.check(
+            &eth_dest,
+            self.config.tenant.as_deref(),
+            self.config.log_mask.as_ref(),
+        )?;
+        self.check_failure_hold(destination)?;
+        payload::check_memo(&self.config, request)?;
+        self.check_memo_support(request)?;
+        Ok(eth_dest)
+    }
+
+    /// Fail if the operator lost its role or the kill switch rejects payouts
+    fn check_operational(&self) -> Result<(), PayoutError> {
+        if self.is_degraded() {
+            return Err(PayoutError::NotAuthorized {
+                operator: self.operator_address(),
+            });
+        }
+        if let Some(kill_switch) = &self.config.kill_switch {
+            if kill_switch.mode == KillSwitchMode::Reject && self.is_kill_switch_engaged() {
+                return Err(PayoutError::KillSwitchEngaged {
+                    path: kill_switch.path.display().to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the planned transactions of a validated request
+    async fn execute_plans(
+        &self,
+        request: &PayoutRequest,
+        plans: &[PayoutPlan],
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let amount: u64 = plans.iter().map(|plan| plan.amount).sum();
+        let eth_dest = &plans[0].destination;
+
+        if eth_dest.chain_id != self.config.expected_chain_id {
+            warn!(
+                "Chain ID mismatch: destination has {}, expected {}",
+                eth_dest.chain_id, self.config.expected_chain_id
+            );
+        }
+
+        info!(
+            "Executing Ethereum payout: {} {} to {} (payment_id: 0x{}{})",
+            amount,
+            eth_dest.asset_code,
+            self.display_address(&eth_dest.recipient),
+            hex::encode(self.config.payment_id_of(request)),
+            plans[0]
+                .memo
+                .as_ref()
+                .map(|memo| format!(", memo: 0x{}", hex::encode(memo)))
+                .unwrap_or_default()
+        );
+
+        let deadline = self.deadline_for(request);
+        if plans.len() == 1 {
+            return self.execute_plan(request, &plans[0], deadline).await;
+        }
+
+        // Chunks run one after another. Each has its own payment ID, so chunks
+        // recorded by an earlier attempt are skipped and only the rest retried.
+        let total = plans.len();
+        info!("Splitting payout into {} transactions", total);
+        let chunks: Vec<[u8; 32]> = plans.iter().map(|plan| plan.payment_id).collect();
+        self.track_split(self.config.payment_id_of(request), &chunks);
+        let mut tx_hashes = Vec::with_capacity(total);
+        let mut safe_tx_hashes = Vec::new();
+        for (index, plan) in plans.iter().enumerate() {
+            let recorded = self
+                .lookup(&plan.payment_id)
+                .filter(|record| !record.is_retryable());
+            if let Some(record) = recorded {
+                if let Some(tx_hash) = record.tx_hash {
+                    tx_hashes.push(tx_hash);
+                    continue;
+                }
+                if record.status == PayoutStatus::PendingApproval {
+                    if let Some(safe_tx_hash) = record.safe_tx_hash {
+                        safe_tx_hashes.push(safe_tx_hash);
+                        continue;
+                    }
+                }
+            }
+            match self.execute_plan(request, plan, deadline).await {
+                Ok(PayoutOutcome::Submitted { tx_hash }) => tx_hashes.push(tx_hash),
+                // Executed by the Safe owners later
+                Ok(PayoutOutcome::PendingApproval { safe_tx_hash }) => {
+                    safe_tx_hashes.push(safe_tx_hash)
+                }
+                // The whole request is queued; sent chunks are skipped once it resumes
+                Ok(outcome) => return Ok(outcome),
+                Err(err) => {
+                    // Abandoned and queued chunks were recorded by `execute_plan`
+                    let recorded = matches!(err, PayoutError::Abandoned { .. })
+                        || (err.is_transient() && self.config.retry_queue.is_some());
+                    if !recorded {
+                        self.store.save(self.failed_record(request, plan, &err));
+                    }
+                    self.publish_payout(
+                        &request.destination,
+                        request.sequence,
+                        PayoutEvent::Failed {
+                            payment_id: format!("0x{}", hex::encode(plan.payment_id)),
+                            reason: err.to_string(),
+                        },
+                    );
+                    self.skip_unsent_chunks(request, &plans[index + 1..], index + 1, total);
+                    self.settle_split(&plan.payment_id);
+                    return Err(PayoutError::PartialPayout {
+                        completed: tx_hashes,
+                        total,
+                        source: Box::new(err),
+                    });
+                }
+            }
+        }
+        self.settle_split(&chunks[0]);
+        Ok(PayoutOutcome::Split {
+            tx_hashes,
+            safe_tx_hashes,
+        })
+    }
+
+    /// Record the chunks left unsent after chunk `failed` of `total` failed,
+    /// unless an earlier attempt recorded them
+    fn skip_unsent_chunks(
+        &self,
+        request: &PayoutRequest,
+        unsent: &[PayoutPlan],
+        failed: usize,
+        total: usize,
+    ) {
+        for plan in unsent {
+            if self.lookup(&plan.payment_id).is_some() {
+                continue;
+            }
+            let reason = format!("not sent after chunk {} of {} failed", failed, total);
+            let mut record = self.payout_record(request, plan, None, PayoutStatus::Skipped);
+            record.last_error = Some(reason.clone());
+            self.store.save(record);
+            self.publish_payout(
+                &request.destination,
+                request.sequence,
+                PayoutEvent::Skipped {
+                    payment_id: format!("0x{}", hex::encode(plan.payment_id)),
+                    reason,
+                },
+            );
+        }
+    }
+
+    /// The request's amount at the registry's decimals for the asset it was
+    /// received in, rounded down if its STREAM connection had a finer scale.
+    /// Amounts in assets outside the registry are left as they are.
+    fn rescale(
+        &self,
+        request: &PayoutRequest,
+        eth_dest: &EthereumDestination,
+    ) -> Result<IlpAmount, PayoutError> {
+        let amount = IlpAmount::new(request.amount);
+        let asset_code = request
+            .source_asset
+            .as_deref()
+            .unwrap_or(&eth_dest.asset_code);
+        let (from, to) = match (request.source_scale, self.config.assets.get(asset_code)) {
+            (Some(scale), Some(asset)) => (scale, asset.decimals),
+            _ => return Ok(amount),
+        };
+        amount.rescale(from, to).ok_or_else(|| {
+            PayoutError::Config(format!(
+                "Rescaling {} {} from scale {} to {} overflows",
+                request.amount, asset_code, from, to
+            ))
+        })
+    }
+
+    /// Apply the current rate if the request's amount, `source`, is in another
+    /// asset than its payout, returning the conversion and the converted amount
+    async fn convert(
+        &self,
+        request: &PayoutRequest,
+        source: IlpAmount,
+    ) -> Result<Option<(Conversion, TokenAmount)>, PayoutError> {
+        let source_asset = match &request.source_asset {
+            Some(asset) => asset,
+            None => return Ok(None),
+        };
+        let eth_dest = self
+            .config
+            .parse_destination(&request.destination)
+            .ok_or_else(|| PayoutError::InvalidDestination(request.destination.clone()))?;
+        if *source_asset == eth_dest.asset_code {
+            return Ok(None);
+        }
+
+        let rates = self.rates.as_ref().ok_or_else(|| {
+            PayoutError::Config(format!(
+                "No rate provider to convert {} to {}",
+                source_asset, eth_dest.asset_code
+            ))
+        })?;
+        let rate = rates.rate(source_asset, &eth_dest.asset_code).await?;
+        let age = self.clock.now().signed_duration_since(rate.timestamp);
+        if age.num_milliseconds() > self.config.rate_max_age.as_millis() as i64 {
+            return Err(PayoutError::StaleRate {
+                from_asset: rate.from_asset,
+                to_asset: rate.to_asset,
+                age_secs: age.num_seconds(),
+                max_age_secs: self.config.rate_max_age.as_secs(),
+            });
+        }
+        let decimal_rate = rate.to_decimal_string();
+        let mut conversion =
+            Conversion::new(source, rate, self.config.rounding).ok_or_else(|| {
+                PayoutError::Config(format!(
+                    "Converting {} {} at {} overflows",
+                    source.value(),
+                    source_asset,
+                    decimal_rate
+                ))
+            })?;
+        self.carry_residue(&eth_dest, &mut conversion);
+        let converted = conversion.amount();
+        info!(
+            "Converted {} {} to {} at {} (exactly {}, {} carried)",
+            source.value(),
+            source_asset,
+            converted,
+            decimal_rate,
+            conversion.unrounded_amount(),
+            conversion.carried
+        );
+        Ok(Some((conversion, converted)))
+    }
+
+    /// Submit a single planned transaction and record it, retrying transient
+    /// errors until `deadline`
+    async fn execute_plan(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        deadline: Option<Timestamp>,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        self.with_pinned_block(self.execute_pinned_plan(request, plan, deadline))
+            .await
+    }
+
+    async fn execute_pinned_plan(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        deadline: Option<Timestamp>,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let eth_dest = &plan.destination;
+
+        if let Some(safe) = &self.config.safe {
+            if plan.mode == PayoutMode::Treasury && plan.amount > safe.threshold {
+                let result = self.propose_to_safe(request, plan, safe).await;
+                return self.hold_if_signer_unavailable(request, result);
+            }
+        }
+        if plan.mode == PayoutMode::Authorization {
+            let result = self.relay_authorization(request, plan).await;
+            return self.hold_if_signer_unavailable(request, result);
+        }
+        #[cfg(feature = "erc4337")]
+        if let Some(user_op) = &self.config.user_op {
+            if plan.mode == PayoutMode::Treasury {
+                let result = self.submit_user_operation(request, plan, user_op).await;
+                return self.hold_if_signer_unavailable(request, result);
+            }
+        }
+
+        let mut attempts = self.earlier_attempts(&plan.payment_id);
+        // Without a Treasury nothing on-chain rejects a replayed payment ID, so
+        // the store is the only guard and is written before sending
+        let direct = plan.mode.relies_on_store();
+        if direct {
+            if !self.store.is_persistent() {
+                return Err(PayoutError::Config(format!(
+                    "Payouts of {} bypass the Treasury and require a persistent payout store",
+                    eth_dest.asset_code
+                )));
+            }
+            if let Some(existing) = self.lookup(&plan.payment_id) {
+                if !existing.is_retryable() {
+                    info!(
+                        "Payment {} already recorded, not transferring again (idempotent)",
+                        existing.payment_id_hex()
+                    );
+                    return Ok(PayoutOutcome::Submitted {
+                        tx_hash: existing
+                            .tx_hash
+                            .unwrap_or_else(|| "already_processed".to_string()),
+                    });
+                }
+            }
+            // Sending without the record would let a restart transfer it again
+            let record = self.payout_record(request, plan, None, PayoutStatus::Submitted);
+            if let Err(err) = self.store.try_save(record) {
+                error!(
+                    "Not sending payout 0x{}: its record could not be persisted: {}",
+                    hex::encode(plan.payment_id),
+                    err
+                );
+                return Err(PayoutError::StoreWrite(err.to_string()));
+            }
+        } else if let Some(existing) = self
+            .lookup(&plan.payment_id)
+            .filter(|existing| existing.status == PayoutStatus::Confirmed)
+        {
+            // The Treasury would refuse it, but a record written for the
+            // refusal must not replace the confirmed one
+            info!(
+                "Payment {} already confirmed, not transferring again (idempotent)",
+                existing.payment_id_hex()
+            );
+            return Ok(PayoutOutcome::Submitted {
+                tx_hash: existing
+                    .tx_hash
+                    .unwrap_or_else(|| "already_processed".to_string()),
+            });
+        }
+
+        let mut timings = PhaseTimings::default();
+        let (tx_hash, preflight, l1_fee) = loop {
+            let err = match self.send_payout(plan, &mut timings).await {
+                Ok(sent) => break sent,
+                Err(err) => err,
+            };
+            if is_pause_revert(&err) {
+                self.mark_paused();
+                return Ok(self.defer(request.clone()));
+            }
+            if is_signer_unavailable(&err) {
+                self.mark_signer_unavailable(&err);
+                return Ok(self.defer(request.clone()));
+            }
+            if matches!(err, PayoutError::Cancelled) {
+                return Ok(self.interrupted(request, plan, deadline));
+            }
+            match deadline {
+                Some(deadline) if err.is_transient() => {
+                    let delay = self.jittered(self.config.retry_interval);
+                    let retry_at = self.clock.now()
+                        + chrono::Duration::from_std(delay)
+                            .unwrap_or_else(|_| chrono::Duration::zero());
+                    if retry_at >= deadline {
+                        self.record_attempt(request, plan, &mut attempts, &err, None);
+                        let mut record =
+                            self.payout_record(request, plan, None, PayoutStatus::Failed);
+                        record.deadline = Some(deadline);
+                        record.attempts = attempts;
+                        return Err(self.abandon(record, err.to_string()));
+                    }
+                    warn!(
+                        "Payout 0x{} failed, retrying in {:?}: {}",
+                        hex::encode(plan.payment_id),
+                        delay,
+                        self.scrub_address(&err.to_string(), &plan.destination.recipient)
+                    );
+                    self.record_attempt(request, plan, &mut attempts, &err, Some(delay));
+                    if !self.sleep_unless_cancelled(delay).await {
+                        return Ok(self.interrupted(request, plan, Some(deadline)));
+                    }
+                }
+                _ => {
+                    match &self.config.retry_queue {
+                        Some(policy) if err.is_transient() => {
+                            self.schedule_retry(request, plan, &err, policy, attempts)
+                        }
+                        _ if direct => {
+                            let mut record = self.failed_record(request, plan, &err);
+                            if !attempts.is_empty() {
+                                self.record_attempt(request, plan, &mut attempts, &err, None);
+                            }
+                            record.attempts = attempts;
+                            self.store.save(record);
+                        }
+                        _ => {}
+                    }
+                    return Err(err);
+                }
+            }
+        };
+
+        info!("Payout transaction sent: {}", tx_hash);
+
+        let mut record = self.payout_record(
+            request,
+            plan,
+            Some(tx_hash.clone()),
+            PayoutStatus::Submitted,
+        );
+        record.gas_price = Some(preflight.gas_price.into());
+        record.l1_fee = l1_fee.map(Wei::value);
+        record.deadline = deadline;
+        record.timings = timings;
+        record.attempts = attempts;
+        record.treasury_payout_id = preflight.treasury_payout_id.clone();
+        self.report_submission(&record);
+        self.publish_payout(
+            &request.destination,
+            request.sequence,
+            PayoutEvent::Submitted {
+                payment_id: record.payment_id_hex(),
+                tx_hash: tx_hash.clone(),
+                treasury_payout_id: preflight.treasury_payout_id,
+            },
+        );
+        self.store.save(record);
+
+        Ok(PayoutOutcome::Submitted { tx_hash })
+    }
+
+    /// Read the gas price and limit, then take the nonce and send the planned
+    /// transaction, returning its hash, what was read for it and its quoted L1 fee
+    async fn send_payout(
+        &self,
+        plan: &PayoutPlan,
+        timings: &mut PhaseTimings,
+    ) -> Result<(String, Preflight, Option<Wei>), PayoutError> {
+        let _signing = self.signing.read().await;
+        let preflight = self.preflight(plan, timings).await?;
+        if preflight.already_processed {
+            info!(
+                "Payment 0x{} already processed by the Treasury, not sending it (idempotent)",
+                hex::encode(plan.payment_id)
+            );
+            return Ok(("already_processed".to_string(), preflight, None));
+        }
+        let lease = timed(timings, PayoutPhase::Nonce, self.lease_nonce()).await?;
+        let result = self
+            .send_payout_with_nonce(plan, lease.nonce, &preflight, timings)
+            .await
+            .map(|(tx_hash, l1_fee)| (tx_hash, preflight, l1_fee));
+        self.settle_nonce(&lease, result.as_ref().err()).await;
+        result
+    }
+
+    async fn send_payout_with_nonce(
+        &self,
+        plan: &PayoutPlan,
+        nonce: u64,
+        preflight: &Preflight,
+        timings: &mut PhaseTimings,
+    ) -> Result<(String, Option<Wei>), PayoutError> {
+        let params = TxParams {
+            nonce,
+            gas_limit: preflight.gas_limit,
+            gas_price: preflight.gas_price,
+        };
+        let l1_fee = timed(timings, PayoutPhase::Gas, self.check_gas_cost(plan, params)).await?;
+        let tx_hash = timed(timings, PayoutPhase::Broadcast, async {
+            let submission = self
+                .sign_transaction(&plan.to, &plan.data, plan.value, params)
+                .await?;
+            match self.broadcast(&submission, plan.mode).await {
+                Err(PayoutError::TimedOut { .. }) => {
+                    self.verify_submission(&submission, params.nonce, plan.mode)
+                        .await
+                }
+                result => result,
+            }
+        })
+        .await?;
+        Ok((tx_hash, l1_fee))
+    }
+
+    async fn gas_limit(&self, plan: &PayoutPlan) -> Result<u64, PayoutError> {
+        let estimated = match plan.mode {
+            PayoutMode::Native => self.native_gas_limit(plan).await,
+            PayoutMode::Treasury | PayoutMode::DirectTransfer
+                if self.config.gas_estimate_ttl.is_some() =>
+            {
+                self.estimated_gas_limit(plan).await
+            }
+            _ => return Ok(DEFAULT_GAS_LIMIT),
+        };
+        match estimated {
+            Err(err) if err.is_transient() => {
+                let gas_limit = self.fallback_gas_limit(plan).await;
+                warn!(
+                    "Could not estimate gas of a payout to {}, sending {}: {}",
+                    self.display_address(&plan.destination.recipient),
+                    gas_limit,
+                    self.scrub_address(&err.to_string(), &plan.destination.recipient)
+                );
+                Ok(gas_limit)
+            }
+            result => result,
+        }
+    }
+
+    /// 21000 for an externally owned recipient; contracts may run code on receipt,
+    /// so their gas is estimated
+    async fn native_gas_limit(&self, plan: &PayoutPlan) -> Result<u64, PayoutError> {
+        if !self.recipient_is_contract(&plan.to).await? {
+            return Ok(NATIVE_TRANSFER_GAS_LIMIT);
+        }
+        self.estimate_gas(plan).await
+    }
+
+    fn payout_record(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        tx_hash: Option<String>,
+        status: PayoutStatus,
+    ) -> PayoutRecord {
+        let eth_dest = &plan.destination;
+        let decimals = match self.config.assets.get(&eth_dest.asset_code) {
+            Some(asset) => asset.decimals,
+            None => {
+                debug!(
+                    "Asset {} not in registry, recording amount without decimals",
+                    eth_dest.asset_code
+                );
+                0
+            }
+        };
+        PayoutRecord {
+            payment_id: plan.payment_id,
+            namespace: self.config.payment_id_namespace(),
+            payment_id_strategy: Some(self.config.payment_id_label(request)),
+            attribution: request.attribution.clone(),
+            destination: request.destination.clone(),
+            sequence: request.sequence,
+            recipient: eth_dest.recipient.clone(),
+            asset_code: eth_dest.asset_code.clone(),
+            asset_segment: eth_dest.written_asset(),
+            amount: plan.amount,
+            decimals,
+            tx_hash,
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: plan.conversion.clone(),
+            memo: plan.memo.clone(),
+            status,
+            deadline: None,
+            last_error: None,
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: self.clock.now(),
+            serial: self.record_serial(&plan.payment_id),
+            pinned_block: self.pinned_block(),
+            treasury_payout_id: None,
+        }
+    }
+
+    /// Record of a payout that failed with `err`
+    fn failed_record(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        err: &PayoutError,
+    ) -> PayoutRecord {
+        let mut record = self.payout_record(request, plan, None, PayoutStatus::Failed);
+        record.last_error = Some(err.to_string());
+        record
+    }
+
+    /// Spawn a background task on the configured runtime
+    fn spawn<F>(&self, task: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match &self.runtime {
+            Some(runtime) => runtime.spawn(task),
+            None => tokio::spawn(task),
+        }
+    }
+
+    /// Send a JSON-RPC request and return its result
+    ///
+    /// Backs off and retries while the provider throttles us. A response
+    /// answering another request id is retried once with a fresh id.
+    async fn rpc(&self, mut request: Value) -> Result<Value, PayoutError> {
+        let mut retries = 0;
+        let mut resent_mismatch = false;
+        loop {
+            self.wait_for_throttle().await?;
+            let id = self.next_rpc_ids(1);
+            request["id"] = json!(id);
+            let method = request["method"].as_str().unwrap_or_default().to_string();
+            let timeout = self.rpc_timeout(&method);
+            let started = self.clock.now();
+            let sent = tokio::time::timeout(timeout, self.transport.send(request.clone()))
+                .await
+                .unwrap_or(Err(PayoutError::TimedOut {
+                    method,
+                    after: timeout,
+                }));
+            // A pool counts the requests it sends per endpoint
+            if self.endpoints.is_none() {
+                let latency = (self.clock.now() - started).to_std().unwrap_or_default();
+                let failed = !matches!(&sent, Ok(response) if response.get("error").is_none());
+                self.counters.endpoint.record(latency, failed);
+            }
+            let result = match sent {
+                Ok(response) => {
+                    *self.last_rpc_response.lock().unwrap() = Some(self.clock.now());
+                    if !resent_mismatch {
+                        if let Err(err) = rpc::check_response_id(&response, id) {
+                            warn!("Resending JSON-RPC request: {}", err);
+                            resent_mismatch = true;
+                            continue;
+                        }
+                    }
+                    rpc_response(response, id)
+                }
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if err.is_throttle() => {
+                    self.note_throttle(&err);
+                    if retries == self.config.throttle_retries {
+                        return Err(err);
+                    }
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Sign the operator's transaction to `to` and broadcast it, as a plain
+    /// transaction that no Treasury deduplicates
+    async fn send_raw_transaction(
+        &self,
+        to: &str,
+        data: &str,
+        value: u64,
+        params: TxParams,
+    ) -> Result<String, PayoutError> {
+        let submission = self.sign_transaction(to, data, value, params).await?;
+        self.broadcast(&submission, PayoutMode::Native).await
+    }
+
+    /// Broadcast a signed transaction of a payout in `mode`, returning its hash
+    async fn broadcast(
+        &self,
+        submission: &Submission,
+        mode: PayoutMode,
+    ) -> Result<String, PayoutError> {
+        let err = match self.rpc(submission.request.clone()).await {
+            Ok(result) => {
+                self.note_broadcast(submission.nonce);
+                return result.as_str().map(str::to_string).ok_or_else(|| {
+                    PayoutError::InvalidResponse("No transaction hash in response".to_string())
+                });
+            }
+            Err(PayoutError::Rpc { message, .. }) if is_node_signer_error(&message) => {
+                return Err(PayoutError::SignerUnavailable(message))
+            }
+            // The pending nonce kept was ahead of the chain, e.g. after a dropped transaction
+            Err(err) if nonce::is_nonce_too_high(&err) => {
+                self.resync_nonce();
+                err
+            }
+            Err(err) => err,
+        };
+        self.revert_outcome(err, mode)
+    }
+
+    /// Hash to report for a call or send in `mode` that failed with `err`: a
+    /// payment the Treasury already processed is a success, `already_processed`
+    ///
+    /// Without a Treasury nothing deduplicates on-chain, so any revert is a failure.
+    fn revert_outcome(&self, err: PayoutError, mode: PayoutMode) -> Result<String, PayoutError> {
+        // A paused Treasury also reverts, but must not be mistaken for a processed payment
+        if is_pause_revert(&err) {
+            return Err(err);
+        }
+        // Custom errors come back as revert data; decode them so logs show their arguments
+        let revert = match &err {
+            PayoutError::Rpc {
+                data: Some(data), ..
+            } => self.config.revert_errors.decode(data),
+            _ => None,
+        };
+        let deduplicated = !mode.relies_on_store();
+        match (revert, err) {
+            (Some(revert), _) if deduplicated && revert.is("PaymentIdAlreadyUsed") => {
+                info!("Payment already processed by the Treasury (idempotent)");
+                Ok("already_processed".to_string())
+            }
+            (Some(revert), _) => {
+                warn!("Payout reverted: {}", revert);
+                Err(PayoutError::Reverted(revert))
+            }
+            // Check for idempotency - payment already processed
+            (None, PayoutError::Rpc { message, .. })
+                if deduplicated
+                    && (message.contains("already processed") || message.contains("revert")) =>
+            {
+                info!("Payment may have been already processed (idempotent)");
+                Ok("already_processed".to_string())
+            }
+            (None, err) => Err(err),
+        }
+    }
+
+    /// Generate a unique payment ID from destination and sequence with the default hash
+    #[cfg(test)]
+    fn generate_payment_id(destination: &str, sequence: u64) -> [u8; 32] {
+        PaymentIdHash::default().payment_id(destination, sequence)
+    }
+}
+
+/// Pool over `rpc_url` and the fallback URLs, `None` if there are no fallbacks
+fn rpc_endpoints(
+    http: &reqwest::Client,
+    build: Option<&HttpClientFactory>,
+    config: &EthereumPayoutConfig,
+) -> Option<Arc<EndpointPool>> {
+    if config.fallback_rpc_urls.is_empty() {
+        return None;
+    }
+    let endpoints = std::iter::once(&config.rpc_url)
+        .chain(&config.fallback_rpc_urls)
+        .map(|url| {
+            (
+                throttle::endpoint_label(url),
+                connection::endpoint_transport(http, build, url, config),
+            )
+        })
+        .collect();
+    Some(Arc::new(
+        EndpointPool::new(endpoints).with_tenant(config.tenant.clone()),
+    ))
+}
+
+/// Execute a payout if the service is configured and destination is valid
+///
+/// The amount is taken to be in the destination's payout asset already.
+pub async fn maybe_execute_payout(destination: &str, amount: u64, sequence: u64) {
+    maybe_dispatch(PayoutRequest::new(destination, amount, sequence)).await
+}
+
+/// Like [`maybe_execute_payout`], converting `amount` from `source_asset`
+/// when the destination pays out another asset
+pub async fn maybe_execute_payout_from(
+    source_asset: &str,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+) {
+    maybe_execute_payout_with_memo(source_asset, destination, amount, sequence, None).await
+}
+
+/// Like [`maybe_execute_payout`], recording `memo` on-chain with the payout
+pub async fn maybe_execute_payout_with_memo(
+    source_asset: &str,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+    memo: Option<Vec<u8>>,
+) {
+    let mut request =
+        PayoutRequest::new(destination, amount, sequence).with_source_asset(source_asset);
+    request.memo = memo;
+    maybe_dispatch(request).await
+}
+
+/// Execute a payout for a STREAM packet of `packet_amount` fulfilled on a
+/// connection in `source_asset` at `asset_scale`, see [`PayoutRequest::from_stream`]
+pub async fn maybe_execute_stream_payout(
+    source_asset: &str,
+    asset_scale: u8,
+    destination: &str,
+    packet_amount: u64,
+    sequence: u64,
+) {
+    let request = PayoutRequest::from_stream(destination, packet_amount, sequence, asset_scale)
+        .with_source_asset(source_asset);
+    maybe_dispatch(request).await
+}
+
+/// Hand `request` to the router, or to the standalone service if there is none
+async fn maybe_dispatch(request: PayoutRequest) {
+    match standby::payout_router() {
+        Some(router) => router.dispatch(request).await,
+        None => {
+            let service = standby::payout_service();
+            route_payout(service.as_ref(), &skip::SKIPPED_PAYOUTS, request).await
+        }
+    }
+}
+
+/// Dispatch the request to `service`, counting it in `skips` if it is not paid out
+async fn route_payout(
+    service: Option<&Arc<EthereumPayoutService>>,
+    skips: &skip::SkipCounters,
+    request: PayoutRequest,
+) {
+    // Check if destination looks like an Ethereum payout
+    if !request.destination.contains(".eth.") {
+        skip::skip(skips, None, &request, SkipReason::NotEthereum);
+        return;
+    }
+    let service = match service {
+        Some(service) => service,
+        None => {
+            skip::skip(skips, None, &request, SkipReason::ServiceUnconfigured);
+            return;
+        }
+    };
+    match service.skip_reason(&request) {
+        // Dispatched anyway, to be deferred until the Treasury is unpaused
+        Some(SkipReason::Paused) => skip::skip(skips, Some(service), &request, SkipReason::Paused),
+        Some(reason) => {
+            skip::skip(skips, Some(service), &request, reason);
+            return;
+        }
+        None => {}
+    }
+
+    let destination = request.destination.clone();
+    match service.dispatch_payout(request).await {
+        Dispatched::Completed(result) => log_outcome(service, &destination, &result),
+        Dispatched::Spawned { payment_id, .. } => {
+            debug!("Ethereum payout {} running in the background", payment_id);
+        }
+        Dispatched::Queued {
+            payment_id,
+            position,
+        } => {
+            debug!(
+                "Ethereum payout {} queued at position {}",
+                payment_id, position
+            );
+        }
+    }
+}
+
+/// Log how a payout to `destination` ended
+fn log_outcome(
+    service: &EthereumPayoutService,
+    destination: &str,
+    result: &Result<PayoutOutcome, PayoutError>,
+) {
+    let scrub = |e: &PayoutError| service.scrub_destination(&format!("{:?}", e), destination);
+    match result {
+        Ok(PayoutOutcome::Submitted { tx_hash }) => {
+            info!("Ethereum payout executed: tx={}", tx_hash);
+        }
+        Ok(PayoutOutcome::Split {
+            tx_hashes,
+            safe_tx_hashes,
+        }) => {
+            info!("Ethereum payout executed: txs={}", tx_hashes.join(","));
+            if !safe_tx_hashes.is_empty() {
+                info!(
+                    "Ethereum payout chunks proposed to Safe: safeTxHashes={}",
+                    safe_tx_hashes.join(",")
+                );
+            }
+        }
+        Ok(PayoutOutcome::PendingApproval { safe_tx_hash }) => {
+            info!(
+                "Ethereum payout proposed to Safe: safeTxHash={}",
+                safe_tx_hash
+            );
+        }
+        Ok(PayoutOutcome::HeldForApproval { payment_id }) => {
+            info!("Ethereum payout {} held for approval", payment_id);
+        }
+        Ok(PayoutOutcome::Deferred) => {
+            info!("Ethereum payout deferred until the Treasury is unpaused");
+        }
+        Ok(PayoutOutcome::Cancelled { payment_id }) => {
+            info!("Ethereum payout {} interrupted by shutdown", payment_id);
+        }
+        Ok(PayoutOutcome::Accumulated { balance }) => {
+            info!("Ethereum payout accumulated as dust, balance {}", balance);
+        }
+        Ok(PayoutOutcome::SkippedZeroAmount) => {
+            debug!("Ethereum payout skipped: zero amount");
+        }
+        Ok(PayoutOutcome::RoundedToZero {
+            source_asset,
+            source_amount,
+        }) => {
+            warn!(
+                "Ethereum payout skipped: {} {} rounds to zero",
+                source_amount, source_asset
+            );
+        }
+        // Summarized by its recipient's failure streak instead
+        Err(e) if service.is_failure_collapsed(destination) => {
+            debug!("Ethereum payout failed: {}", scrub(e));
+        }
+        Err(e) => {
+            warn!("Ethereum payout failed: {}", scrub(e));
+            // Don't fail the ILP payment - just log the error
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{
+        deployed_chain, test_config, test_service, FakeChain, FAKE_CHAIN_BALANCE, TEST_DESTINATION,
+    };
+    use super::*;
+
+    /// Address the test destination pays
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    /// Chain on which the first payout to the test destination reverts with `data`
+    fn reverting_chain(data: &str) -> Arc<FakeChain> {
+        let chain = deployed_chain();
+        let payment_id = test_config().payment_id(TEST_DESTINATION, 1);
+        chain.revert_payment_with(&payment_id, data);
+        chain
+    }
+
+    #[tokio::test]
+    async fn custom_errors_surface_as_decoded_reverts() {
+        let data = format!("0xcf479181{:064x}{:064x}", 100, 42);
+        let chain = reverting_chain(&data);
+        let service = test_service(test_config(), chain.clone());
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Transaction reverted: InsufficientBalance(100, 42)"
+        );
+        // The simulation saw the revert, so no gas was spent on it
+        assert_eq!(chain.call_count("eth_call"), 1);
+        assert!(chain.sent_transactions().is_empty());
+    }
+
+    fn direct_transfer_config(store_path: Option<std::path::PathBuf>) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.insert(AssetInfo::direct_transfer(
+            "EURC",
+            6,
+            "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512",
+        ));
+        config.store_path = store_path;
+        config
+    }
+
+    #[tokio::test]
+    async fn direct_transfer_refuses_in_memory_store() {
+        let chain = deployed_chain();
+        let service = test_service(direct_transfer_config(None), chain.clone());
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::Config(_)));
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[tokio::test]
+    async fn direct_transfer_deduplicates_through_the_store() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let chain = deployed_chain();
+        chain.set_code("0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512", "0x6080");
+        let service = test_service(direct_transfer_config(Some(path.clone())), chain.clone());
+
+        let first = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(first.tx_hash(), Some(chain.pending()[0].as_str()));
+        let sent = &chain.sent_transactions()[0];
+        assert_eq!(sent["to"], "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512");
+
+        // Replays are answered from the store, also after a restart
+        let replay = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(replay, first);
+        let restarted = test_service(direct_transfer_config(Some(path.clone())), chain.clone());
+        restarted
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 1);
+
+        // Confirmed once mined, on the restarted service too
+        chain.advance_block();
+        let first_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let record = restarted.refresh_receipt(&first_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+
+        // A failed transfer is recorded as such and may be retried
+        chain.fail(
+            "eth_sendRawTransaction",
+            json!({"code": -32000, "message": "insufficient funds"}),
+        );
+        assert!(restarted
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .is_err());
+        let failed_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 2);
+        assert_eq!(
+            restarted.store().get(&failed_id).unwrap().status,
+            PayoutStatus::Failed
+        );
+        chain.heal("eth_sendRawTransaction");
+        let retried = restarted
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(retried.tx_hash(), Some(chain.pending()[0].as_str()));
+        assert_eq!(chain.sent_transactions()[2]["nonce"], "0x1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_transfer_reverts_are_failures() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let chain = deployed_chain();
+        chain.set_code("0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512", "0x6080");
+        let service = test_service(direct_transfer_config(Some(path.clone())), chain.clone());
+
+        // A Treasury would mean a replay, but a token has no payment IDs to replay
+        chain.fail(
+            "eth_sendRawTransaction",
+            json!({
+                "code": -32000,
+                "message": "execution reverted: ERC20: transfer amount exceeds balance"
+            }),
+        );
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::Rpc { .. }));
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Failed);
+        assert_eq!(record.tx_hash, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn native_config(store_path: std::path::PathBuf) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.insert(AssetInfo::native("EURC"));
+        config.store_path = Some(store_path);
+        config
+    }
+
+    #[tokio::test]
+    async fn native_payout_to_eoa_uses_plain_transfer_gas() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let chain = deployed_chain();
+        let service = test_service(native_config(path.clone()), chain.clone());
+
+        service
+            .execute_payout(TEST_DESTINATION, 1_000_000_000_000_000, 1)
+            .await
+            .unwrap();
+        let tx = &chain.sent_transactions()[0];
+        assert_eq!(tx["to"], RECIPIENT);
+        assert_eq!(tx["value"], "0x38d7ea4c68000");
+        assert_eq!(tx["data"], "0x");
+        assert_eq!(tx["gas"], "0x5208");
+        assert_eq!(chain.call_count("eth_estimateGas"), 0);
+
+        chain.advance_block();
+        assert_eq!(
+            chain.balance(RECIPIENT),
+            FAKE_CHAIN_BALANCE + 1_000_000_000_000_000
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn native_payout_to_contract_estimates_gas() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let chain = deployed_chain();
+        chain.set_code(RECIPIENT, "0x6080604052");
+        chain.set_gas_used(30_000);
+        let service = test_service(native_config(path.clone()), chain.clone());
+
+        service
+            .execute_payout(TEST_DESTINATION, 5, 1)
+            .await
+            .unwrap();
+        let estimate = &chain.calls("eth_estimateGas")[0]["params"][0];
+        assert_eq!(estimate["to"], RECIPIENT);
+        assert_eq!(estimate["value"], "0x5");
+        let tx = &chain.sent_transactions()[0];
+        assert_eq!(tx["gas"], "0x7530");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn split_payout_resumes_after_mid_sequence_failure() {
+        let mut config = test_config();
+        config.assets.apply_caps("EURC:100").unwrap();
+        let chain = deployed_chain();
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        chain.reject_payment(
+            &chunk_payment_id(&payment_id, 1),
+            json!({"code": -32000, "message": "nonce too low"}),
+        );
+        let service = test_service(config, chain.clone());
+
+        let err = service
+            .execute_payout(TEST_DESTINATION, 250, 1)
+            .await
+            .unwrap_err();
+        match err {
+            PayoutError::PartialPayout {
+                completed, total, ..
+            } => {
+                assert_eq!(completed, chain.pending());
+                assert_eq!(total, 3);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Only the failed chunk and the one after it are sent again
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 250, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hashes(), chain.pending());
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 4);
+        let amounts: Vec<String> = chain
+            .sent_transactions()
+            .iter()
+            .map(|call| call["data"].as_str().unwrap()[138..].to_string())
+            .collect();
+        assert_eq!(
+            amounts,
+            vec![
+                format!("{:064x}", 100),
+                format!("{:064x}", 100),
+                format!("{:064x}", 100),
+                format!("{:064x}", 50)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn memos_reach_the_treasury_and_the_record() {
+        let chain = deployed_chain();
+        let mut config = test_config();
+        config.max_memo_len = 16;
+        let service = Arc::new(test_service(config, chain.clone()));
+
+        let request = PayoutRequest::new(TEST_DESTINATION, 100, 1).with_memo(b"INV-42".to_vec());
+        match service.dispatch_payout(request).await {
+            Dispatched::Completed(result) => assert!(result.is_ok()),
+            other => panic!("expected an inline payout, got {:?}", other),
+        }
+        let sent = &chain.sent_transactions()[0]["data"];
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let mut plan = plan_payout(service.config(), TEST_DESTINATION, 100, 1).unwrap();
+        plan.attach_memo(b"INV-42").unwrap();
+        assert_eq!(sent, &json!(plan.data.to_ascii_lowercase()));
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.memo.as_deref(), Some(&b"INV-42"[..]));
+
+        let request = PayoutRequest::new(TEST_DESTINATION, 100, 2).with_memo(vec![0; 17]);
+        match service.dispatch_payout(request).await {
+            Dispatched::Completed(Err(PayoutError::MemoTooLong { len: 17, max: 16 })) => {}
+            other => panic!("expected the memo to be refused, got {:?}", other),
+        }
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_amounts_are_skipped_or_refused() {
+        let chain = deployed_chain();
+        let service = test_service(test_config(), chain.clone());
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 0, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome, PayoutOutcome::SkippedZeroAmount);
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        assert!(service.store().get(&payment_id).is_none());
+
+        let mut config = test_config();
+        config.reject_zero_amounts = true;
+        let service = test_service(config, chain.clone());
+        let err = service
+            .execute_payout(TEST_DESTINATION, 0, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::ZeroAmount { .. }));
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[tokio::test]
+    async fn stream_amounts_are_rescaled_to_the_asset_decimals() {
+        let chain = deployed_chain();
+        let service = Arc::new(test_service(test_config(), chain.clone()));
+        let paid = |sequence| {
+            let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, sequence);
+            service.store().get(&payment_id).map(|record| record.amount)
+        };
+
+        // A connection at scale 9 pays 1.5 EURC, which has 6 decimals
+        let request = PayoutRequest::from_stream(TEST_DESTINATION, 1_500_000_999, 1, 9);
+        assert!(matches!(
+            service.dispatch_payout(request).await,
+            Dispatched::Completed(Ok(PayoutOutcome::Submitted { .. }))
+        ));
+        assert_eq!(paid(1), Some(1_500_000));
+
+        // A coarser scale is widened, and the same scale left as it is
+        let request = PayoutRequest::from_stream(TEST_DESTINATION, 15, 2, 1);
+        service.dispatch_payout(request).await;
+        assert_eq!(paid(2), Some(1_500_000));
+        let request = PayoutRequest::from_stream(TEST_DESTINATION, 42, 3, 6);
+        service.dispatch_payout(request).await;
+        assert_eq!(paid(3), Some(42));
+
+        // Less than one base unit rounds to zero, and too much overflows
+        let request = PayoutRequest::from_stream(TEST_DESTINATION, 999, 4, 9);
+        match service.dispatch_payout(request).await {
+            Dispatched::Completed(Ok(PayoutOutcome::RoundedToZero {
+                source_asset,
+                source_amount: 999,
+            })) => assert_eq!(source_asset, "EURC"),
+            other => panic!("expected the amount to round to zero, got {:?}", other),
+        }
+        let request = PayoutRequest::from_stream(TEST_DESTINATION, u64::MAX, 5, 0);
+        match service.dispatch_payout(request).await {
+            Dispatched::Completed(Err(PayoutError::Config(message))) => {
+                assert!(message.contains("from scale 0 to 6 overflows"))
+            }
+            other => panic!("expected the rescaling to overflow, got {:?}", other),
+        }
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 3);
+    }
+
+    #[tokio::test]
+    async fn used_payment_id_is_treated_as_processed() {
+        let data = format!("0xcf4cf60d{}", "ab".repeat(32));
+        let chain = reverting_chain(&data);
+        let service = test_service(test_config(), chain.clone());
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("already_processed"));
+        assert!(chain.sent_transactions().is_empty());
+        assert_eq!(chain.call_count("eth_getTransactionCount"), 0);
+    }
+
+    #[tokio::test]
+    async fn repeated_sequence_keeps_its_confirmed_record() {
+        let chain = deployed_chain();
+        let service = test_service(test_config(), chain.clone());
+        let sent = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        chain.advance_block();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        service.refresh_receipt(&payment_id).await.unwrap();
+
+        let repeated = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(repeated, sent);
+        assert_eq!(chain.sent_transactions().len(), 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+    }
+
+    #[tokio::test]
+    async fn injected_http_client_carries_payout_requests() {
+        use mockito::{mock, Matcher};
+
+        let rpc = |method: &str, id: u64, result: Value| {
+            mock("POST", "/rpc")
+                .match_header("x-host-client", "connector")
+                .match_body(Matcher::PartialJson(json!({ "method": method, "id": id })))
+                .with_body(json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string())
+                .expect(1)
+                .create()
+        };
+        let mocks = [
+            rpc("eth_gasPrice", 1, json!("0x1")),
+            rpc("eth_call", 2, json!("0x")),
+            rpc("eth_getTransactionCount", 3, json!("0x0")),
+            rpc("eth_sendRawTransaction", 4, json!("0xabc")),
+        ];
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-host-client", "connector".parse().unwrap());
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap();
+        let mut config = test_config();
+        config.rpc_url = format!("{}/rpc", mockito::server_url());
+        let service = EthereumPayoutService::new(config)
+            .unwrap()
+            .with_http_client(client);
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        for mock in &mocks {
+            mock.assert();
+        }
+    }
+
+    #[test]
+    fn background_tasks_run_on_the_injected_runtime() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let chain = deployed_chain();
+        chain.on("eth_call", |_| Ok(json!(format!("0x{:064x}", 0))));
+        let mut config = test_config();
+        config.pause_check_interval = Some(std::time::Duration::from_millis(10));
+        let service =
+            Arc::new(test_service(config, chain.clone()).with_runtime(runtime.handle().clone()));
+
+        // Spawning outside of any runtime context only works through the handle
+        let monitor = service.spawn_pause_monitor().unwrap();
+        runtime.block_on(async {
+            tokio::time::sleep(std::time::Duration::from_millis(35)).await;
+        });
+        monitor.abort();
+        assert!(chain.call_count("eth_call") >= 2);
+    }
+}
@@ -0,0 +1,232 @@
+//! Payment IDs namespaced per Treasury deployment
+//!
+//! Without a namespace a payment ID only depends on the ILP destination and
+//! sequence, so a Treasury deployed at a new address would see the same IDs
+//! as the old one. With `payment_id_domain` set, IDs also commit to a
+//! namespace hashed from the domain, chain ID and Treasury address, records
+//! store the namespace they were made under, and idempotency lookups ignore
+//! records from other namespaces.
+//!
+//! Records made before a namespace was enabled are not found by lookups
+//! until they are re-keyed with [`migrate_payment_ids`].
+
+use super::{
+    chunk_payment_id, EthereumPayoutConfig, EthereumPayoutService, PayoutRecord, PayoutStore,
+};
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+/// Chunk indices tried when re-keying a record of a split payout
+const MAX_MIGRATED_CHUNKS: u32 = 1024;
+
+/// Records read per page while migrating
+const MIGRATION_PAGE_SIZE: usize = 500;
+
+impl EthereumPayoutConfig {
+    /// Namespace of payment IDs sent to the configured Treasury, `None` without a domain
+    pub fn payment_id_namespace(&self) -> Option<[u8; 32]> {
+        let domain = self.payment_id_domain.as_ref()?;
+        let mut data = domain.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&self.expected_chain_id.to_be_bytes());
+        data.extend_from_slice(self.treasury_address.to_ascii_lowercase().as_bytes());
+        Some(self.payment_id_hash.digest(&data))
+    }
+
+    /// Payment ID of the payout for `destination` and `sequence` in the configured namespace
+    pub fn payment_id(&self, destination: &str, sequence: u64) -> [u8; 32] {
+        self.namespaced(self.payment_id_strategy().payment_id(destination, sequence))
+    }
+
+    /// Move an ID derived without a namespace into the configured one
+    pub fn namespaced(&self, payment_id: [u8; 32]) -> [u8; 32] {
+        let namespace = match self.payment_id_namespace() {
+            Some(namespace) => namespace,
+            None => return payment_id,
+        };
+        let mut data = namespace.to_vec();
+        data.extend_from_slice(&payment_id);
+        self.payment_id_hash.digest(&data)
+    }
+}
+
+impl EthereumPayoutService {
+    /// Record of a payout made under the configured namespace
+    pub(super) fn lookup(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        self.store
+            .get(payment_id)
+            .filter(|record| record.namespace == self.config.payment_id_namespace())
+    }
+}
+
+/// Re-key records made without a namespace under the namespace of `config`
+///
+/// Run once when enabling `payment_id_domain`, with the configuration of the
+/// Treasury the records were paid through and before the service starts.
+/// Records whose ID is not derived from their destination and sequence, such
+/// as dust flushes, are left as they are. Returns the number of records re-keyed.
+pub fn migrate_payment_ids(store: &dyn PayoutStore, config: &EthereumPayoutConfig) -> usize {
+    let namespace = match config.payment_id_namespace() {
+        Some(namespace) => namespace,
+        None => return 0,
+    };
+
+    // Collect first, since re-keyed records move within the listing
+    let mut legacy = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = store.list_range(
+            DateTime::<Utc>::MIN_UTC,
+            DateTime::<Utc>::MAX_UTC,
+            cursor,
+            MIGRATION_PAGE_SIZE,
+        );
+        legacy.extend(page.iter().filter(|r| r.namespace.is_none()).cloned());
+        if page.len() < MIGRATION_PAGE_SIZE {
+            break;
+        }
+        cursor = page.last().map(Into::into);
+    }
+
+    let mut migrated = 0;
+    for record in legacy {
+        let base = config
+            .payment_id_strategy()
+            .payment_id(&record.destination, record.sequence);
+        let namespaced = config.namespaced(base);
+        let payment_id = if record.payment_id == base {
+            namespaced
+        } else {
+            match (0..MAX_MIGRATED_CHUNKS)
+                .find(|i| chunk_payment_id(&base, *i) == record.payment_id)
+            {
+                Some(index) => chunk_payment_id(&namespaced, index),
+                None => {
+                    warn!(
+                        "Payout {} is not derived from its destination and sequence, leaving it unmigrated",
+                        record.payment_id_hex()
+                    );
+                    continue;
+                }
+            }
+        };
+        store.remove(&record.payment_id);
+        store.save(PayoutRecord {
+            payment_id,
+            namespace: Some(namespace),
+            ..record
+        });
+        migrated += 1;
+    }
+    migrated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{plan_payouts, FilePayoutStore, InMemoryPayoutStore};
+    use super::*;
+    use std::sync::Arc;
+
+    const TREASURY_V2: &str = "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512";
+
+    fn namespaced_config(treasury: &str) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.treasury_address = treasury.to_string();
+        config.payment_id_domain = Some("ilp-payout".to_string());
+        config
+    }
+
+    #[test]
+    fn treasuries_yield_different_payment_ids() {
+        let legacy = test_config();
+        let v1 = namespaced_config(&legacy.treasury_address);
+        let v2 = namespaced_config(TREASURY_V2);
+        let mut other_chain = namespaced_config(&legacy.treasury_address);
+        other_chain.expected_chain_id = 1;
+
+        assert_eq!(
+            legacy.payment_id(TEST_DESTINATION, 1),
+            EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1)
+        );
+        let ids = [
+            legacy.payment_id(TEST_DESTINATION, 1),
+            v1.payment_id(TEST_DESTINATION, 1),
+            v2.payment_id(TEST_DESTINATION, 1),
+            other_chain.payment_id(TEST_DESTINATION, 1),
+        ];
+        for (i, a) in ids.iter().enumerate() {
+            for b in &ids[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+        // The address's casing does not change the namespace
+        assert_eq!(
+            v2.payment_id_namespace(),
+            namespaced_config(&TREASURY_V2.to_lowercase()).payment_id_namespace()
+        );
+    }
+
+    #[tokio::test]
+    async fn lookups_do_not_cross_namespaces() {
+        let transport = mock_chain();
+        let store: Arc<dyn PayoutStore> = Arc::new(InMemoryPayoutStore::new());
+        let v1_config = namespaced_config(&test_config().treasury_address);
+        let v1 = test_service(v1_config.clone(), transport.clone()).with_store(store.clone());
+        let v2 = test_service(namespaced_config(TREASURY_V2), transport.clone())
+            .with_store(store.clone());
+
+        v1.execute_payout(TEST_DESTINATION, 100, 1).await.unwrap();
+        let v1_id = v1_config.payment_id(TEST_DESTINATION, 1);
+        assert!(v1.lookup(&v1_id).is_some());
+        assert!(v2.lookup(&v1_id).is_none());
+
+        // v2 pays the same destination and sequence under its own ID
+        v2.execute_payout(TEST_DESTINATION, 100, 1).await.unwrap();
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+        let v2_id = v2.config().payment_id(TEST_DESTINATION, 1);
+        assert_eq!(
+            store.get(&v2_id).unwrap().namespace,
+            v2.config().payment_id_namespace()
+        );
+        assert!(v1.lookup(&v2_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn migration_rekeys_legacy_records() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let store = Arc::new(FilePayoutStore::open(&path).unwrap());
+        let transport = mock_chain();
+        let mut legacy_config = test_config();
+        legacy_config.assets.apply_caps("EURC:60").unwrap();
+        let legacy = test_service(legacy_config.clone(), transport).with_store(store.clone());
+        legacy
+            .execute_payout(TEST_DESTINATION, 100, 7)
+            .await
+            .unwrap();
+        let legacy_ids: Vec<[u8; 32]> = plan_payouts(&legacy_config, TEST_DESTINATION, 100, 7)
+            .unwrap()
+            .iter()
+            .map(|plan| plan.payment_id)
+            .collect();
+        assert_eq!(legacy_ids.len(), 2);
+
+        let mut config = legacy_config.clone();
+        config.payment_id_domain = Some("ilp-payout".to_string());
+        assert_eq!(migrate_payment_ids(store.as_ref(), &config), 2);
+        // Already namespaced records are left alone
+        assert_eq!(migrate_payment_ids(store.as_ref(), &config), 0);
+
+        let reopened = FilePayoutStore::open(&path).unwrap();
+        for (legacy_id, plan) in legacy_ids
+            .iter()
+            .zip(plan_payouts(&config, TEST_DESTINATION, 100, 7).unwrap())
+        {
+            assert!(reopened.get(legacy_id).is_none());
+            let record = reopened.get(&plan.payment_id).unwrap();
+            assert_eq!(record.namespace, config.payment_id_namespace());
+            assert_eq!(record.amount, plan.amount);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+//! Nonce coordination with other senders using the operator key
+//!
+//! Processes sharing the key reserve nonces from a common [`NonceStore`].
+//! A lease is confirmed once its transaction is broadcast or released if it
+//! never was; leases of a crashed holder expire after `nonce_lease_ttl` and
+//! their nonces are handed out again. Processes on other hosts share a
+//! [`RedisNonceStore`](super::RedisNonceStore) (feature `redis-nonce-store`,
+//! `NONCE_STORE_URL`). Without a shared store the service leases from its
+//! own, so concurrent payouts never sign with the same nonce.
+//!
+//! Load-balanced providers may answer from backends that are behind each
+//! other, so a pending nonce read can be lower than one read moments before.
+//! Such regressions are logged and counted per endpoint, and an
+//! [`EndpointPool`](super::EndpointPool) scores them as failures of the
+//! endpoint that answered. The service keeps using the highest pending nonce
+//! read, or one past the last it broadcast a transaction with, until it
+//! resyncs, when a node refuses a transaction's nonce as too high or on
+//! [`EthereumPayoutService::resync_nonce`].
+
+use super::allowance::leaves_nonce_unused;
+use super::rpc::{parse_quantity, rpc_request};
+use super::throttle::endpoint_label;
+use super::{metrics, EthereumPayoutService, PayoutError, Timestamp};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// A nonce reserved for one transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NonceLease {
+    pub address: String,
+    pub nonce: u64,
+    /// Distinguishes this lease from a later one for the same nonce
+    pub id: String,
+    pub expires_at: Timestamp,
+}
+
+/// Backend shared by every process sending from the same address
+#[async_trait]
+pub trait NonceStore: Send + Sync {
+    /// Lease the lowest nonce at or above `chain_nonce` that is neither
+    /// leased nor confirmed, reclaiming leases that expired before `now`
+    async fn reserve(
+        &self,
+        address: &str,
+        chain_nonce: u64,
+        now: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<NonceLease, PayoutError>;
+
+    /// Give the nonce back unused. Returns `false` if the lease had already expired.
+    async fn release(&self, lease: &NonceLease) -> Result<bool, PayoutError>;
+
+    /// Mark the nonce as used. Returns `false` if the lease had already expired.
+    async fn confirm(&self, lease: &NonceLease) -> Result<bool, PayoutError>;
+}
+
+#[derive(Debug, Default)]
+struct AddressNonces {
+    /// One past the highest confirmed nonce
+    next: u64,
+    /// Lease ID and expiry keyed by nonce
+    leases: BTreeMap<u64, (String, Timestamp)>,
+}
+
+/// Nonce store for processes sharing memory, e.g. services in one connector
+#[derive(Debug, Default)]
+pub struct InMemoryNonceStore {
+    addresses: Mutex<HashMap<String, AddressNonces>>,
+}
+
+impl InMemoryNonceStore {
+    pub fn new() -> Self {
+        InMemoryNonceStore::default()
+    }
+
+    fn take_lease(&self, lease: &NonceLease) -> Option<()> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let nonces = addresses.get_mut(&lease.address.to_ascii_lowercase())?;
+        match nonces.leases.get(&lease.nonce) {
+            Some((id, _)) if *id == lease.id => {
+                nonces.leases.remove(&lease.nonce);
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl InMemoryNonceStore {
+    /// Forget the nonces confirmed for `address`, so the next lease starts
+    /// from the chain's pending count again
+    pub(super) fn forget_confirmed(&self, address: &str) {
+        if let Some(nonces) = self
+            .addresses
+            .lock()
+            .unwrap()
+            .get_mut(&address.to_ascii_lowercase())
+        {
+            nonces.next = 0;
+        }
+    }
+}
+
+#[async_trait]
+impl NonceStore for InMemoryNonceStore {
+    async fn reserve(
+        &self,
+        address: &str,
+        chain_nonce: u64,
+        now: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<NonceLease, PayoutError> {
+        let mut addresses = self.addresses.lock().unwrap();
+        let nonces = addresses.entry(address.to_ascii_lowercase()).or_default();
+        nonces.leases.retain(|_, (_, expiry)| *expiry > now);
+        let mut nonce = chain_nonce.max(nonces.next);
+        while nonces.leases.contains_key(&nonce) {
+            nonce += 1;
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        nonces.leases.insert(nonce, (id.clone(), expires_at));
+        Ok(NonceLease {
+            address: address.to_string(),
+            nonce,
+            id,
+            expires_at,
+        })
+    }
+
+    async fn release(&self, lease: &NonceLease) -> Result<bool, PayoutError> {
+        Ok(self.take_lease(lease).is_some())
+    }
+
+    async fn confirm(&self, lease: &NonceLease) -> Result<bool, PayoutError> {
+        if self.take_lease(lease).is_none() {
+            return Ok(false);
+        }
+        let mut addresses = self.addresses.lock().unwrap();
+        let nonces = addresses
+            .entry(lease.address.to_ascii_lowercase())
+            .or_default();
+        nonces.next = nonces.next.max(lease.nonce + 1);
+        Ok(true)
+    }
+}
+
+/// Highest pending nonce read per address, to notice readings going back
+#[derive(Debug, Default)]
+pub(super) struct PendingNonces(Mutex<HashMap<String, u64>>);
+
+impl PendingNonces {
+    /// Note that `pending` was read for `address`, returning the highest
+    /// nonce read before if `pending` is lower
+    pub(super) fn observe(&self, address: &str, pending: u64) -> Option<u64> {
+        let mut highest = self.0.lock().unwrap();
+        let highest = highest
+            .entry(address.to_ascii_lowercase())
+            .or_insert(pending);
+        if pending < *highest {
+            return Some(*highest);
+        }
+        *highest = pending;
+        None
+    }
+
+    /// Note that a transaction from `address` took `nonce`, so no lower
+    /// pending nonce is trusted until resynced
+    pub(super) fn raise(&self, address: &str, nonce: u64) {
+        let mut highest = self.0.lock().unwrap();
+        let highest = highest.entry(address.to_ascii_lowercase()).or_default();
+        *highest = (*highest).max(nonce + 1);
+    }
+
+    /// Forget what was read for `address`, trusting the next reading
+    pub(super) fn forget(&self, address: &str) {
+        self.0.lock().unwrap().remove(&address.to_ascii_lowercase());
+    }
+}
+
+/// The address and pending nonce of an `eth_getTransactionCount` request
+/// answered with `response`, if it was one for the pending block
+pub(super) fn pending_nonce_read(
+    request: &serde_json::Value,
+    response: &serde_json::Value,
+) -> Option<(String, u64)> {
+    if request["method"] != "eth_getTransactionCount" || request["params"][1] != "pending" {
+        return None;
+    }
+    let address = request["params"][0].as_str()?.to_string();
+    let nonce = parse_quantity(response.get("result")?).ok()?;
+    Some((address, nonce))
+}
+
+/// Whether the node refused a transaction for a nonce ahead of the account's
+pub(super) fn is_nonce_too_high(err: &PayoutError) -> bool {
+    matches!(err, PayoutError::Rpc { message, .. } if message.to_ascii_lowercase().contains("nonce too high"))
+}
+
+impl EthereumPayoutService {
+    /// Trust the node's next pending nonce even if it is lower than one read
+    /// before, e.g. after the transactions behind it were dropped
+    pub fn resync_nonce(&self) {
+        let address = self.operator_address();
+        info!("Resyncing the pending nonce of {}", address);
+        self.pending_nonces.forget(&address);
+        self.local_nonces.forget_confirmed(&address);
+        if let Some(pool) = &self.endpoints {
+            pool.forget_pending_nonce(&address);
+        }
+    }
+
+    /// Reserve the operator's next nonce through the shared nonce store
+    pub async fn reserve_nonce(&self) -> Result<NonceLease, PayoutError> {
+        if self.nonce_store.is_none() {
+            return Err(PayoutError::Config(
+                "No shared nonce store is configured".to_string(),
+            ));
+        }
+        self.lease_nonce().await
+    }
+
+    /// Lease the operator's next nonce from the shared store, or from the
+    /// service's own if none is configured
+    pub(super) async fn lease_nonce(&self) -> Result<NonceLease, PayoutError> {
+        let chain_nonce = self.chain_nonce().await?;
+        let now = self.clock.now();
+        let ttl = chrono::Duration::from_std(self.config.nonce_lease_ttl)
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        self.nonces()
+            .reserve(&self.operator_address(), chain_nonce, now, now + ttl)
+            .await
+    }
+
+    /// The shared nonce store, or the service's own if none is configured
+    fn nonces(&self) -> &dyn NonceStore {
+        match &self.nonce_store {
+            Some(store) => store.as_ref(),
+            None => &self.local_nonces,
+        }
+    }
+
+    /// Confirm a leased nonce after its transaction was sent, or release it
+    /// if the send failed with `err` before reaching the chain. A send that
+    /// may have been broadcast keeps the nonce leased until it expires, as
+    /// does one the store cannot be reached to settle.
+    pub(super) async fn settle_nonce(&self, lease: &NonceLease, err: Option<&PayoutError>) {
+        let store = self.nonces();
+        let settled = match err {
+            None => store.confirm(lease).await,
+            Some(err) if leaves_nonce_unused(err) => store.release(lease).await,
+            Some(_) => return,
+        };
+        match settled {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "Nonce lease for {} expired before its transaction was settled; it may be reused",
+                lease.nonce
+            ),
+            Err(err) => warn!(
+                "Could not settle the nonce lease for {}, leaving it to expire: {}",
+                lease.nonce, err
+            ),
+        }
+    }
+
+    /// Return an unused nonce to the shared store
+    pub async fn release_nonce(&self, lease: &NonceLease) -> Result<(), PayoutError> {
+        let released = match &self.nonce_store {
+            Some(store) => store.release(lease).await?,
+            None => false,
+        };
+        if !released {
+            warn!("Nonce lease for {} had already expired", lease.nonce);
+        }
+        Ok(())
+    }
+
+    /// Record that a nonce was used by a broadcast transaction
+    pub async fn confirm_nonce(&self, lease: &NonceLease) -> Result<(), PayoutError> {
+        let confirmed = match &self.nonce_store {
+            Some(store) => store.confirm(lease).await?,
+            None => false,
+        };
+        if !confirmed {
+            warn!(
+                "Nonce lease for {} expired before its transaction was sent; it may be reused",
+                lease.nonce
+            );
+        }
+        Ok(())
+    }
+
+    /// Keep later nonces above `nonce`, just taken by a broadcast transaction,
+    /// however long the node's pending count lags behind it
+    pub(super) fn note_broadcast(&self, nonce: u64) {
+        self.pending_nonces.raise(&self.operator_address(), nonce);
+    }
+
+    /// Pending transaction count of the operator according to the node, or
+    /// the highest known since the last resync if the node's is behind it
+    pub(super) async fn chain_nonce(&self) -> Result<u64, PayoutError> {
+        let address = self.operator_address();
+        let result = self
+            .rpc(rpc_request(
+                "eth_getTransactionCount",
+                json!([address, "pending"]),
+            ))
+            .await?;
+        let pending = parse_quantity(&result)?;
+        let highest = match self.pending_nonces.observe(&address, pending) {
+            Some(highest) => highest,
+            None => return Ok(pending),
+        };
+        warn!(
+            "Pending nonce of {} went back from {} to {}, keeping {}",
+            address, highest, pending, highest
+        );
+        // A pool counts regressions of the endpoint that answered
+        if self.endpoints.is_none() {
+            self.counters.endpoint.nonce_regression();
+            metrics::nonce_regression(
+                self.config.tenant.as_deref(),
+                &endpoint_label(&self.config.rpc_url),
+            );
+        }
+        Ok(highest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, FakeChain, FakeClock, TEST_DESTINATION,
+        TEST_OPERATOR,
+    };
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn chain(nonce: u64) -> Arc<FakeChain> {
+        let chain = deployed_chain();
+        chain.set_nonce(TEST_OPERATOR, nonce);
+        chain
+    }
+
+    fn sharing(
+        store: &Arc<InMemoryNonceStore>,
+        clock: &Arc<FakeClock>,
+        chain: Arc<FakeChain>,
+    ) -> EthereumPayoutService {
+        test_service(test_config(), chain)
+            .with_nonce_store(store.clone())
+            .with_clock(clock.clone())
+    }
+
+    #[tokio::test]
+    async fn services_never_share_a_nonce() {
+        let store = Arc::new(InMemoryNonceStore::new());
+        let clock = FakeClock::new();
+        let chain = chain(5);
+        let payouts = sharing(&store, &clock, chain.clone());
+        let settlement = sharing(&store, &clock, chain);
+
+        let first = payouts.reserve_nonce().await.unwrap();
+        let second = settlement.reserve_nonce().await.unwrap();
+        let third = payouts.reserve_nonce().await.unwrap();
+        assert_eq!((first.nonce, second.nonce, third.nonce), (5, 6, 7));
+
+        // A released nonce is handed out again, a confirmed one never
+        settlement.release_nonce(&second).await.unwrap();
+        payouts.confirm_nonce(&first).await.unwrap();
+        assert_eq!(settlement.reserve_nonce().await.unwrap().nonce, 6);
+        assert_eq!(settlement.reserve_nonce().await.unwrap().nonce, 8);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_reclaimed() {
+        let store = Arc::new(InMemoryNonceStore::new());
+        let clock = FakeClock::new();
+        let chain = chain(0);
+        let crashed = sharing(&store, &clock, chain.clone());
+        let survivor = sharing(&store, &clock, chain);
+
+        let abandoned = crashed.reserve_nonce().await.unwrap();
+        assert_eq!(survivor.reserve_nonce().await.unwrap().nonce, 1);
+
+        clock.advance(survivor.config.nonce_lease_ttl + Duration::from_secs(1));
+        let reclaimed = survivor.reserve_nonce().await.unwrap();
+        assert_eq!(reclaimed.nonce, abandoned.nonce);
+        assert_ne!(reclaimed.id, abandoned.id);
+        // The crashed holder's lease no longer counts
+        assert!(!store.confirm(&abandoned).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn payouts_lease_nonces_from_the_shared_store() {
+        let store = Arc::new(InMemoryNonceStore::new());
+        let clock = FakeClock::new();
+        let chain = chain(0);
+        let first = sharing(&store, &clock, chain.clone());
+        let second = sharing(&store, &clock, chain.clone());
+
+        first
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        second
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        let sent = chain.sent_transactions();
+        assert_eq!(sent[0]["nonce"], "0x0");
+        assert_eq!(sent[1]["nonce"], "0x1");
+        assert_eq!(chain.advance_block().len(), 2);
+        assert_eq!(chain.nonce(TEST_OPERATOR), 2);
+    }
+
+    #[tokio::test]
+    async fn concurrent_payouts_sign_distinct_nonces() {
+        let chain = chain(3);
+        // Every payout takes its nonce before the node has any of them pending
+        chain.delay("eth_sendRawTransaction", Duration::from_millis(20));
+        let service = test_service(test_config(), chain.clone());
+
+        let payouts =
+            (1..=3).map(|sequence| service.execute_payout(TEST_DESTINATION, 100, sequence));
+        for outcome in futures::future::join_all(payouts).await {
+            outcome.unwrap();
+        }
+        let mut nonces: Vec<_> = chain
+            .sent_transactions()
+            .iter()
+            .map(|tx| parse_quantity(&tx["nonce"]).unwrap())
+            .collect();
+        nonces.sort_unstable();
+        assert_eq!(nonces, [3, 4, 5]);
+        assert_eq!(chain.advance_block().len(), 3);
+        assert_eq!(chain.nonce(TEST_OPERATOR), 6);
+    }
+
+    #[tokio::test]
+    async fn a_refused_send_gives_its_nonce_back() {
+        let chain = chain(0);
+        chain.fail_next(
+            "eth_sendRawTransaction",
+            1,
+            json!({"code": -32000, "message": "insufficient funds for gas * price + value"}),
+        );
+        let service = test_service(test_config(), chain.clone());
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(chain.sent_transactions()[0]["nonce"], "0x0");
+        assert_eq!(chain.advance_block().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pending_nonce_never_goes_back_until_resynced() {
+        let chain = chain(0);
+        let readings = Arc::new(Mutex::new(vec![7u64, 5, 8, 6, 6]));
+        let script = readings.clone();
+        chain.on("eth_getTransactionCount", move |_| {
+            Ok(json!(format!("0x{:x}", script.lock().unwrap().remove(0))))
+        });
+        let service = test_service(test_config(), chain.clone());
+        let regressions = |service: &EthereumPayoutService| {
+            service
+                .metrics_text()
+                .lines()
+                .find(|line| line.starts_with("ethereum_rpc_nonce_regressions_total"))
+                .and_then(|line| line.rsplit(' ').next().map(str::to_string))
+        };
+
+        let mut read = Vec::new();
+        for _ in 0..3 {
+            read.push(service.chain_nonce().await.unwrap());
+        }
+        assert_eq!(read, [7, 7, 8]);
+        assert_eq!(regressions(&service).as_deref(), Some("1"));
+
+        // The chain refuses 8 as too high, so the lower reading is trusted from then on
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        assert!(chain.pending().is_empty());
+        assert_eq!(service.chain_nonce().await.unwrap(), 6);
+        assert!(readings.lock().unwrap().is_empty());
+        assert_eq!(regressions(&service).as_deref(), Some("2"));
+    }
+
+    #[tokio::test]
+    async fn lagging_reads_never_hand_out_a_broadcast_nonce_again() {
+        let chain = chain(7);
+        let service = test_service(test_config(), chain.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(chain.sent_transactions()[0]["nonce"], "0x7");
+        assert_eq!(service.chain_nonce().await.unwrap(), 8);
+
+        // The backend answering next has not seen the transaction at 7 yet
+        chain.on("eth_getTransactionCount", |_| Ok(json!("0x5")));
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(chain.sent_transactions()[1]["nonce"], "0x8");
+        assert_eq!(chain.pending().len(), 2);
+        assert_eq!(service.chain_nonce().await.unwrap(), 9);
+    }
+}
@@ -0,0 +1,329 @@
+//! Ordering of payouts to the same recipient
+//!
+//! Unless `recipient_ordering` is `Unordered`, every payout takes a turn in
+//! its recipient's lane when it is executed, or dispatched to a task, and runs
+//! once the payouts that took a turn before it have finished. Payouts to other
+//! recipients are not held up. Lanes are keyed by the lowercase recipient
+//! address, so every asset and destination paying an address shares its lane.
+//!
+//! Under `Blocking`, a payout that fails but may still be retried holds back
+//! the later payouts of its lane, which fail with `RecipientBlocked`, until its
+//! record is paid, abandoned, cancelled or rejected, or the operator calls
+//! [`EthereumPayoutService::unblock_recipient`]. A retry of the blocking
+//! payout itself is let through.
+
+use super::shutdown::unless_cancelled;
+use super::{metrics, EthereumPayoutService, PayoutError, PayoutRequest, PayoutStatus};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
+
+/// Whether payouts to a recipient run in the order they arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecipientOrdering {
+    #[default]
+    Unordered,
+    /// One payout per recipient at a time, in arrival order
+    Serialized,
+    /// Serialized, and a failed payout holds back the recipient's later ones
+    Blocking,
+}
+
+impl RecipientOrdering {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "unordered" => Some(RecipientOrdering::Unordered),
+            "serialized" => Some(RecipientOrdering::Serialized),
+            "blocking" => Some(RecipientOrdering::Blocking),
+            _ => None,
+        }
+    }
+}
+
+/// Turns handed out per recipient
+#[derive(Default)]
+pub(super) struct Lanes {
+    lanes: HashMap<String, Lane>,
+}
+
+struct Lane {
+    /// Turns taken so far
+    issued: u64,
+    /// Turns before this one are finished
+    served: watch::Sender<u64>,
+    /// Finished turns after `served`, which must wait for the earlier ones
+    finished: BTreeSet<u64>,
+    /// Payout holding back the lane under `Blocking`
+    blocked_by: Option<[u8; 32]>,
+}
+
+impl Lane {
+    fn depth(&self) -> u64 {
+        self.issued - *self.served.borrow()
+    }
+}
+
+/// A payout's place in its recipient's lane, given up when dropped
+pub(super) struct Turn {
+    lanes: Arc<Mutex<Lanes>>,
+    recipient: String,
+    ticket: u64,
+    tenant: Option<String>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut lanes = self.lanes.lock().unwrap();
+        let lane = match lanes.lanes.get_mut(&self.recipient) {
+            Some(lane) => lane,
+            None => return,
+        };
+        lane.finished.insert(self.ticket);
+        let mut served = *lane.served.borrow();
+        while lane.finished.remove(&served) {
+            served += 1;
+        }
+        lane.served.send_replace(served);
+        let depth = lane.depth();
+        metrics::recipient_queue_depth(self.tenant.as_deref(), &self.recipient, depth);
+        if depth == 0 && lane.blocked_by.is_none() {
+            lanes.lanes.remove(&self.recipient);
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Take the request's turn in its recipient's lane, `None` if payouts are
+    /// unordered or the destination does not parse
+    pub(super) fn take_turn(&self, request: &PayoutRequest) -> Option<Turn> {
+        if self.config.recipient_ordering == RecipientOrdering::Unordered {
+            return None;
+        }
+        let recipient = self
+            .config
+            .parse_destination(&request.destination)?
+            .recipient
+            .to_ascii_lowercase();
+        let mut lanes = self.lanes.lock().unwrap();
+        let lane = lanes
+            .lanes
+            .entry(recipient.clone())
+            .or_insert_with(|| Lane {
+                issued: 0,
+                served: watch::channel(0).0,
+                finished: BTreeSet::new(),
+                blocked_by: None,
+            });
+        let ticket = lane.issued;
+        lane.issued += 1;
+        metrics::recipient_queue_depth(self.config.tenant.as_deref(), &recipient, lane.depth());
+        Some(Turn {
+            lanes: self.lanes.clone(),
+            recipient,
+            ticket,
+            tenant: self.config.tenant.clone(),
+        })
+    }
+
+    /// Wait until the payouts ahead of `turn` finished, failing if one of them
+    /// blocks the lane
+    pub(super) async fn wait_turn(
+        &self,
+        turn: &Turn,
+        request: &PayoutRequest,
+    ) -> Result<(), PayoutError> {
+        let mut served = match self.lanes.lock().unwrap().lanes.get(&turn.recipient) {
+            Some(lane) => lane.served.subscribe(),
+            None => return Ok(()),
+        };
+        unless_cancelled(
+            &self.cancellation,
+            served.wait_for(|served| *served == turn.ticket),
+        )
+        .await
+        .ok_or(PayoutError::Cancelled)?
+        .map_err(|_| PayoutError::Cancelled)?;
+
+        let blocked_by = match self.lane_blocker(&turn.recipient) {
+            Some(blocked_by) => blocked_by,
+            None => return Ok(()),
+        };
+        let own = self.config.payment_id_of(request);
+        let resolved = !matches!(
+            self.lookup(&blocked_by).map(|record| record.status),
+            Some(PayoutStatus::Failed) | Some(PayoutStatus::RetryScheduled)
+        );
+        if resolved || blocked_by == own {
+            self.unblock_recipient(&turn.recipient);
+            return Ok(());
+        }
+        Err(PayoutError::RecipientBlocked {
+            recipient: turn.recipient.clone(),
+            payment_id: format!("0x{}", hex::encode(blocked_by)),
+        })
+    }
+
+    /// Block the lane behind a failed payout that may still be retried
+    pub(super) fn finish_turn(&self, turn: Turn, request: &PayoutRequest, failed: bool) {
+        if self.config.recipient_ordering != RecipientOrdering::Blocking || !failed {
+            return;
+        }
+        let payment_id = self.config.payment_id_of(request);
+        let retryable = matches!(
+            self.lookup(&payment_id).map(|record| record.status),
+            Some(PayoutStatus::Failed) | Some(PayoutStatus::RetryScheduled)
+        );
+        if !retryable {
+            return;
+        }
+        if let Some(lane) = self.lanes.lock().unwrap().lanes.get_mut(&turn.recipient) {
+            lane.blocked_by.get_or_insert(payment_id);
+        }
+    }
+
+    fn lane_blocker(&self, recipient: &str) -> Option<[u8; 32]> {
+        self.lanes.lock().unwrap().lanes.get(recipient)?.blocked_by
+    }
+
+    /// Let payouts to `recipient` run again after a failure blocked them,
+    /// returning the ID of the payout that blocked them
+    pub fn unblock_recipient(&self, recipient: &str) -> Option<[u8; 32]> {
+        self.lanes
+            .lock()
+            .unwrap()
+            .lanes
+            .get_mut(&recipient.to_ascii_lowercase())?
+            .blocked_by
+            .take()
+    }
+
+    /// Payouts to `recipient` waiting for or holding their turn
+    pub fn recipient_queue_depth(&self, recipient: &str) -> u64 {
+        self.lanes
+            .lock()
+            .unwrap()
+            .lanes
+            .get(&recipient.to_ascii_lowercase())
+            .map_or(0, Lane::depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{ExecutionMode, RetryPolicy};
+    use super::*;
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    const OTHER_DESTINATION: &str =
+        "test.receiver.eth.31337.EURC.0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC.abc123";
+
+    /// Node taking longer to accept payouts to the test destination's recipient
+    fn node() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.delay("eth_sendRawTransaction", Duration::from_millis(150));
+        transport
+    }
+
+    fn ordered(ordering: RecipientOrdering) -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.execution_mode = ExecutionMode::Spawned;
+        config.recipient_ordering = ordering;
+        config
+    }
+
+    /// Amounts of the sent payouts to `recipient`, in the order they were sent
+    fn sent_to(transport: &MockTransport, recipient: &str) -> Vec<u64> {
+        transport
+            .sent_transactions()
+            .iter()
+            .map(|call| call["data"].as_str().unwrap().to_string())
+            .filter(|data| {
+                data.to_ascii_lowercase()
+                    .contains(&recipient[2..].to_ascii_lowercase())
+            })
+            .map(|data| u64::from_str_radix(&data[data.len() - 64..], 16).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_each_recipients_payouts_in_order() {
+        let transport = node();
+        let service = Arc::new(test_service(
+            ordered(RecipientOrdering::Serialized),
+            transport.clone(),
+        ));
+        let mut handles = Vec::new();
+        for sequence in 1..=4u64 {
+            for destination in [TEST_DESTINATION, OTHER_DESTINATION] {
+                match service
+                    .dispatch_payout(PayoutRequest::new(destination, 100 + sequence, sequence))
+                    .await
+                {
+                    super::super::Dispatched::Spawned { handle, .. } => handles.push(handle),
+                    other => panic!("{:?}", other),
+                }
+            }
+        }
+        let first = TEST_DESTINATION.split('.').nth(5).unwrap();
+        let second = OTHER_DESTINATION.split('.').nth(5).unwrap();
+        assert_eq!(service.recipient_queue_depth(first), 4);
+
+        // Both lanes make progress while the first payout of either is sent
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert!(!sent_to(&transport, first).is_empty());
+        assert!(!sent_to(&transport, second).is_empty());
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(sent_to(&transport, first), [101, 102, 103, 104]);
+        assert_eq!(sent_to(&transport, second), [101, 102, 103, 104]);
+        assert_eq!(service.recipient_queue_depth(first), 0);
+    }
+
+    #[tokio::test]
+    async fn a_failed_payout_holds_back_its_recipient() {
+        let transport = node();
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let fail = failing.clone();
+        transport.on("eth_sendRawTransaction", move |_: &Value| {
+            if fail.load(std::sync::atomic::Ordering::SeqCst) {
+                Err(json!({"code": -32603, "message": "node overloaded"}))
+            } else {
+                Ok(json!("0xabc"))
+            }
+        });
+        let mut config = ordered(RecipientOrdering::Blocking);
+        config.execution_mode = ExecutionMode::Inline;
+        config.retry_queue = Some(RetryPolicy::default());
+        let service = Arc::new(test_service(config, transport.clone()));
+
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        assert!(matches!(
+            service.execute_payout(TEST_DESTINATION, 100, 2).await,
+            Err(PayoutError::RecipientBlocked { .. })
+        ));
+        failing.store(false, std::sync::atomic::Ordering::SeqCst);
+        // Other recipients are unaffected
+        assert!(service
+            .execute_payout(OTHER_DESTINATION, 100, 1)
+            .await
+            .is_ok());
+
+        // Retrying the blocking payout itself goes through and releases the lane
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_ok());
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,256 @@
+//! Handling of a paused Treasury
+//!
+//! An OpenZeppelin `Pausable` Treasury reverts every payout with
+//! `EnforcedPause()` (or "Pausable: paused" on older versions) while paused.
+//! The service detects this either through a periodic `paused()` view call or
+//! from the revert itself, defers payouts while paused, and drains them once a
+//! later check shows the Treasury is unpaused.
+
+use super::abi::{decode_bool, encode_call, selector};
+use super::shutdown::unless_cancelled;
+use super::{EthereumPayoutService, PayoutError, PayoutOutcome, PayoutRequest};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Selector of OpenZeppelin's `EnforcedPause()` custom error
+pub const ENFORCED_PAUSE_SELECTOR: &str = "d93c0665";
+
+/// Whether an error returned while submitting a payout means the Treasury is paused
+pub fn is_pause_revert(err: &PayoutError) -> bool {
+    match err {
+        PayoutError::Rpc { message, data, .. } => {
+            let selector_match = data
+                .as_deref()
+                .map(|d| {
+                    d.trim_start_matches("0x")
+                        .starts_with(ENFORCED_PAUSE_SELECTOR)
+                })
+                .unwrap_or(false);
+            selector_match
+                || message.contains("EnforcedPause")
+                || message.contains("Pausable: paused")
+        }
+        _ => false,
+    }
+}
+
+impl EthereumPayoutService {
+    /// Whether the Treasury is currently believed to be paused, or payouts
+    /// are stopped by the kill switch or held for an unavailable signer
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst) || self.is_kill_switch_engaged() || self.is_read_only()
+    }
+
+    /// Number of payouts waiting for the Treasury to be unpaused
+    pub fn deferred_count(&self) -> usize {
+        self.deferred.lock().unwrap().len()
+    }
+
+    /// Enter the paused state, alerting only on the transition
+    pub(super) fn mark_paused(&self) {
+        if !self.paused.swap(true, Ordering::SeqCst) {
+            error!(
+                "ALERT: Treasury {} is paused; deferring payouts until it is unpaused",
+                self.config.treasury_address
+            );
+        }
+    }
+
+    /// Queue a payout to be executed once the Treasury is unpaused
+    pub(super) fn defer(&self, request: PayoutRequest) -> PayoutOutcome {
+        info!(
+            "Deferring payout for {} (sequence {}) while Treasury is paused",
+            self.display_destination(&request.destination),
+            request.sequence
+        );
+        self.deferred.lock().unwrap().push_back(request);
+        PayoutOutcome::Deferred
+    }
+
+    /// Query `paused()` on the Treasury, updating the paused state.
+    ///
+    /// When the Treasury turns out to be unpaused after having been paused,
+    /// deferred payouts are drained before returning.
+    pub async fn check_paused(&self) -> Result<bool, PayoutError> {
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({
+                    "to": &self.config.treasury_address,
+                    "data": encode_call(selector("paused()"), &[]),
+                })],
+            )
+            .await?;
+        let paused = result
+            .as_str()
+            .and_then(decode_bool)
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("paused returned {}", result)))?;
+
+        if paused {
+            self.mark_paused();
+        } else if self.paused.swap(false, Ordering::SeqCst) {
+            info!(
+                "Treasury {} is no longer paused; draining {} deferred payouts",
+                self.config.treasury_address,
+                self.deferred_count()
+            );
+            self.drain_deferred().await;
+        }
+        Ok(paused)
+    }
+
+    /// Execute deferred payouts in arrival order, stopping if payouts pause again
+    pub(super) async fn drain_deferred(&self) {
+        loop {
+            if self.is_paused() {
+                return;
+            }
+            let next = self.deferred.lock().unwrap().pop_front();
+            let request = match next {
+                Some(request) => request,
+                None => return,
+            };
+            match self.execute(&request).await {
+                Ok(outcome) => info!("Deferred payout executed: {:?}", outcome),
+                Err(err) => warn!(
+                    "Deferred payout for {} (sequence {}) failed: {}",
+                    self.display_destination(&request.destination),
+                    request.sequence,
+                    self.scrub_destination(&err.to_string(), &request.destination)
+                ),
+            }
+        }
+    }
+
+    /// Spawn a task checking `paused()` on the configured interval.
+    ///
+    /// Returns `None` when the periodic pause check is disabled. Without it, a
+    /// pause detected from a revert only clears when `check_paused` is called.
+    /// The task stops on shutdown.
+    pub fn spawn_pause_monitor(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let interval = self.config.pause_check_interval?;
+        let service = Arc::clone(self);
+        let cancellation = self.cancellation.clone();
+        Some(self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                if let Err(err) = service.check_paused().await {
+                    warn!("Treasury pause check failed: {}", err);
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::*;
+    use serde_json::Value;
+
+    fn word(value: bool) -> Value {
+        json!(format!("0x{:064x}", value as u8))
+    }
+
+    #[test]
+    fn pause_selectors_match_signatures() {
+        assert_eq!(
+            hex::encode(selector("EnforcedPause()")),
+            ENFORCED_PAUSE_SELECTOR
+        );
+        assert_eq!(hex::encode(selector("paused()")), "5c975abb");
+    }
+
+    #[test]
+    fn recognizes_pause_reverts() {
+        let custom = PayoutError::Rpc {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some("0xd93c0665".to_string()),
+        };
+        let legacy = PayoutError::Rpc {
+            code: -32000,
+            message: "execution reverted: Pausable: paused".to_string(),
+            data: None,
+        };
+        let other = PayoutError::Rpc {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some("0x1f2a2005".to_string()),
+        };
+        assert!(is_pause_revert(&custom));
+        assert!(is_pause_revert(&legacy));
+        assert!(!is_pause_revert(&other));
+        assert!(!is_pause_revert(&PayoutError::Transport("down".into())));
+    }
+
+    #[tokio::test]
+    async fn view_check_defers_and_resume_drains() {
+        let transport = mock_chain();
+        transport.on_result("eth_call", word(true));
+        let service = test_service(test_config(), transport.clone());
+
+        assert!(service.check_paused().await.unwrap());
+        assert!(service.is_paused());
+        for sequence in 0..3 {
+            let outcome = service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .unwrap();
+            assert_eq!(outcome, PayoutOutcome::Deferred);
+        }
+        assert_eq!(service.deferred_count(), 3);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        // Still paused: nothing drains
+        assert!(service.check_paused().await.unwrap());
+        assert_eq!(service.deferred_count(), 3);
+
+        transport.on_result("eth_call", word(false));
+        assert!(!service.check_paused().await.unwrap());
+        assert!(!service.is_paused());
+        assert_eq!(service.deferred_count(), 0);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 3);
+    }
+
+    #[tokio::test]
+    async fn revert_during_execution_enters_paused_state() {
+        let transport = mock_chain();
+        // The payout's simulation reverts, as the paused Treasury would
+        transport.on("eth_call", |_| {
+            Err(json!({
+                "code": 3,
+                "message": "execution reverted",
+                "data": "0xd93c0665"
+            }))
+        });
+        let service = test_service(test_config(), transport.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome, PayoutOutcome::Deferred);
+        assert!(service.is_paused());
+        assert_eq!(service.deferred_count(), 1);
+
+        // Later payouts are deferred without another attempt
+        service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(transport.call_count("eth_call"), 1);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        transport.on_result("eth_call", word(false));
+        service.check_paused().await.unwrap();
+        assert_eq!(service.deferred_count(), 0);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 2);
+    }
+}
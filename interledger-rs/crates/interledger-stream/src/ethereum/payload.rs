@@ -0,0 +1,618 @@
+//! Pure construction of payout transactions
+//!
+//! Nothing in here performs I/O, so the exact bytes and JSON-RPC payloads the
+//! service sends can be reproduced from fixed inputs.
+
+use super::abi::{address_word_hex, encode_address, encode_call, encode_uint, selector};
+use super::hash::sha256;
+use super::{
+    Attribution, Conversion, EthereumDestination, EthereumPayoutConfig, PayoutError, PayoutMode,
+    TokenAmount,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// Gas limit used for payout calls (a reasonable default for `payoutToUser`)
+pub const DEFAULT_GAS_LIMIT: u64 = 100_000;
+
+/// Gas used by a plain value transfer to an externally owned account
+pub const NATIVE_TRANSFER_GAS_LIMIT: u64 = 21_000;
+
+/// Function selector for `payoutToUser(bytes32,address,uint256)`
+/// Computed with: cast sig "payoutToUser(bytes32,address,uint256)" = 0xb77276d8
+pub const PAYOUT_TO_USER_SELECTOR: &str = "b77276d8";
+
+/// Overload of `payoutToUser` recording an opaque memo alongside the payout
+pub const PAYOUT_WITH_MEMO_SIGNATURE: &str = "payoutToUser(bytes32,address,uint256,bytes)";
+
+/// Nonce and fee parameters of a transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxParams {
+    pub nonce: u64,
+    pub gas_limit: u64,
+    pub gas_price: u64,
+}
+
+/// The inputs of a payout as received from the STREAM layer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutRequest {
+    pub destination: String,
+    /// Amount in the base units of `source_asset`
+    pub amount: u64,
+    pub sequence: u64,
+    /// Asset of the ILP account the amount was received in, `None` if it is
+    /// already in the payout asset
+    pub source_asset: Option<String>,
+    /// Scale `amount` was received at, rescaled to the registry's decimals
+    /// for its asset if they differ; `None` if it is already at that scale
+    pub source_scale: Option<u8>,
+    /// Overrides the configured payout deadline
+    pub deadline: Option<Duration>,
+    /// Opaque data recorded on-chain with the payout, e.g. an invoice reference
+    pub memo: Option<Vec<u8>>,
+    /// Paid under instead of a derived ID, see [`PayoutRequest::with_payment_id`]
+    pub payment_id: Option<[u8; 32]>,
+    /// Tags the payout's cost is attributed to, see [`Attribution`]
+    pub attribution: Attribution,
+}
+
+impl PayoutRequest {
+    pub fn new(destination: impl Into<String>, amount: u64, sequence: u64) -> Self {
+        PayoutRequest {
+            destination: destination.into(),
+            amount,
+            sequence,
+            source_asset: None,
+            source_scale: None,
+            deadline: None,
+            memo: None,
+            payment_id: None,
+            attribution: Attribution::default(),
+        }
+    }
+
+    /// The request for a STREAM packet of `packet_amount` fulfilled on a
+    /// connection at `asset_scale`, as reported by the receiver
+    pub fn from_stream(
+        destination: impl Into<String>,
+        packet_amount: u64,
+        sequence: u64,
+        asset_scale: u8,
+    ) -> Self {
+        PayoutRequest::new(destination, packet_amount, sequence).with_source_scale(asset_scale)
+    }
+
+    pub fn with_source_scale(mut self, asset_scale: u8) -> Self {
+        self.source_scale = Some(asset_scale);
+        self
+    }
+
+    pub fn with_memo(mut self, memo: impl Into<Vec<u8>>) -> Self {
+        self.memo = Some(memo.into());
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_source_asset(mut self, asset_code: impl Into<String>) -> Self {
+        self.source_asset = Some(asset_code.into());
+        self
+    }
+}
+
+/// A payout derived purely from its inputs, ready to be submitted
+#[derive(Debug, Clone)]
+pub struct PayoutPlan {
+    pub payment_id: [u8; 32],
+    pub destination: EthereumDestination,
+    /// Amount paid by this transaction, less than the request's if split
+    pub amount: u64,
+    pub mode: PayoutMode,
+    /// Contract the transaction is sent to
+    pub to: String,
+    /// 0x-prefixed ABI-encoded call data
+    pub data: String,
+    /// Wei sent along with the transaction
+    pub value: u64,
+    /// Rate applied to the ILP amount, if it was in another asset
+    pub conversion: Option<Conversion>,
+    /// Memo passed to the Treasury, see [`PayoutPlan::attach_memo`]
+    pub memo: Option<Vec<u8>>,
+}
+
+impl PayoutPlan {
+    /// Call the memo overload of `payoutToUser` instead of the plain one.
+    ///
+    /// Only Treasury payouts can carry a memo; other modes fail rather than drop it.
+    pub fn attach_memo(&mut self, memo: &[u8]) -> Result<(), PayoutError> {
+        if self.mode != PayoutMode::Treasury {
+            return Err(PayoutError::Config(format!(
+                "Asset {} is paid out without the Treasury and cannot carry a memo",
+                self.destination.asset_code
+            )));
+        }
+        self.data = payout_memo_calldata(
+            &self.payment_id,
+            &self.destination.recipient,
+            self.amount,
+            memo,
+        )
+        .ok_or_else(|| PayoutError::InvalidDestination(self.destination.recipient.clone()))?;
+        self.memo = Some(memo.to_vec());
+        Ok(())
+    }
+}
+
+/// Parse the destination and build the payout call without touching the network
+pub fn plan_payout(
+    config: &EthereumPayoutConfig,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+) -> Result<PayoutPlan, PayoutError> {
+    let eth_dest = config
+        .parse_destination(destination)
+        .ok_or_else(|| config.destination_error(destination))?;
+
+    // Generate payment ID from destination + sequence (for idempotency)
+    let payment_id = config.payment_id(destination, sequence);
+    plan_call(config, eth_dest, payment_id, amount, destination)
+}
+
+/// Fail with `MemoTooLong` if the request's memo exceeds `max_memo_len`
+pub(super) fn check_memo(
+    config: &EthereumPayoutConfig,
+    request: &PayoutRequest,
+) -> Result<(), PayoutError> {
+    match &request.memo {
+        Some(memo) if memo.len() > config.max_memo_len => Err(PayoutError::MemoTooLong {
+            len: memo.len(),
+            max: config.max_memo_len,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Plan the transactions paying `amount`, already converted, for `request`,
+/// with its memo and conversion attached
+pub fn plan_request(
+    config: &EthereumPayoutConfig,
+    request: &PayoutRequest,
+    amount: &TokenAmount,
+    conversion: Option<&Conversion>,
+) -> Result<Vec<PayoutPlan>, PayoutError> {
+    let mut plans = match request.payment_id {
+        Some(payment_id) => {
+            let eth_dest = config
+                .parse_destination(&request.destination)
+                .ok_or_else(|| config.destination_error(&request.destination))?;
+            let plan = plan_call(
+                config,
+                eth_dest,
+                payment_id,
+                amount.base_units,
+                &request.destination,
+            )?;
+            split_plan(config, plan, amount.base_units, &request.destination)?
+        }
+        None => plan_payouts(
+            config,
+            &request.destination,
+            amount.base_units,
+            request.sequence,
+        )?,
+    };
+    for plan in &mut plans {
+        plan.conversion = conversion.cloned();
+        if let Some(memo) = &request.memo {
+            if let Some(call) = &config.payout_call {
+                return Err(PayoutError::Config(format!(
+                    "Payouts calling {} cannot carry a memo",
+                    call.signature()
+                )));
+            }
+            plan.attach_memo(memo)?;
+        }
+    }
+    Ok(plans)
+}
+
+/// Plan a payout, splitting it into chunks of at most the asset's
+/// `max_payout_per_tx`, each with its own payment ID (see [`chunk_payment_id`])
+pub fn plan_payouts(
+    config: &EthereumPayoutConfig,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+) -> Result<Vec<PayoutPlan>, PayoutError> {
+    let plan = plan_payout(config, destination, amount, sequence)?;
+    split_plan(config, plan, amount, destination)
+}
+
+/// Split `plan`, paying `amount` to `destination`, into chunks of at most the
+/// asset's `max_payout_per_tx`
+fn split_plan(
+    config: &EthereumPayoutConfig,
+    plan: PayoutPlan,
+    amount: u64,
+    destination: &str,
+) -> Result<Vec<PayoutPlan>, PayoutError> {
+    let cap = match config
+        .assets
+        .get(&plan.destination.asset_code)
+        .and_then(|asset| asset.max_payout_per_tx)
+    {
+        Some(cap) if amount > cap => cap,
+        _ => return Ok(vec![plan]),
+    };
+
+    let chunks = amount.div_ceil(cap);
+    (0..chunks)
+        .map(|index| {
+            let chunk_amount = cap.min(amount - index * cap);
+            plan_call(
+                config,
+                plan.destination.clone(),
+                chunk_payment_id(&plan.payment_id, index as u32),
+                chunk_amount,
+                destination,
+            )
+        })
+        .collect()
+}
+
+/// Payment ID of chunk `index` of a split payout: SHA-256 over the base ID and the index
+pub fn chunk_payment_id(base: &[u8; 32], index: u32) -> [u8; 32] {
+    let mut data = base.to_vec();
+    data.extend_from_slice(&index.to_be_bytes());
+    sha256(&data)
+}
+
+/// Build the call paying `amount` to the destination under `payment_id`
+pub(super) fn plan_call(
+    config: &EthereumPayoutConfig,
+    eth_dest: EthereumDestination,
+    payment_id: [u8; 32],
+    amount: u64,
+    destination: &str,
+) -> Result<PayoutPlan, PayoutError> {
+    // Destinations built by hand may not have been parsed, and calldata must
+    // never pay a padded approximation of the recipient
+    if encode_address(&eth_dest.recipient).is_none() {
+        return Err(PayoutError::InvalidDestination(destination.to_string()));
+    }
+    let unregistered = config
+        .unregistered_token(&eth_dest.asset_code)
+        .map(str::to_string);
+    let mode = match &unregistered {
+        Some(token) => Some((PayoutMode::DirectTransfer, Some(token))),
+        None => config
+            .assets
+            .get(&eth_dest.asset_code)
+            .map(|asset| (asset.mode, asset.token_address.as_ref())),
+    };
+    let (to, data, value) = match mode {
+        Some((PayoutMode::Native, _)) => (eth_dest.recipient.clone(), "0x".to_string(), amount),
+        Some((PayoutMode::Authorization, token)) => {
+            let token = token.ok_or_else(|| {
+                PayoutError::Config(format!(
+                    "Asset {} uses authorizations but has no token address",
+                    eth_dest.asset_code
+                ))
+            })?;
+            // Signed and relayed rather than sent as call data
+            (token.clone(), "0x".to_string(), 0)
+        }
+        Some((PayoutMode::DirectTransfer, token)) => {
+            let token = token.ok_or_else(|| {
+                PayoutError::Config(format!(
+                    "Asset {} uses direct transfers but has no token address",
+                    eth_dest.asset_code
+                ))
+            })?;
+            let data = transfer_calldata(&eth_dest.recipient, amount)
+                .ok_or_else(|| PayoutError::InvalidDestination(destination.to_string()))?;
+            (token.clone(), data, 0)
+        }
+        _ => {
+            let data = match &config.payout_call {
+                Some(call) => call.calldata(&payment_id, &eth_dest.recipient, amount),
+                None => payout_calldata(&payment_id, &eth_dest.recipient, amount),
+            }
+            .ok_or_else(|| PayoutError::InvalidDestination(destination.to_string()))?;
+            (config.treasury_address.clone(), data, 0)
+        }
+    };
+
+    Ok(PayoutPlan {
+        payment_id,
+        mode: mode.map_or(PayoutMode::Treasury, |(mode, _)| mode),
+        destination: eth_dest,
+        amount,
+        to,
+        data,
+        value,
+        conversion: None,
+        memo: None,
+    })
+}
+
+/// ABI-encode a `payoutToUser(bytes32,address,uint256)` call as 0x-prefixed hex,
+/// `None` if `recipient` is not a 20-byte hex address
+pub fn payout_calldata(payment_id: &[u8; 32], recipient: &str, amount: u64) -> Option<String> {
+    // bytes32 paymentId - 32 bytes
+    // address recipient - 32 bytes (left-padded)
+    // uint256 amount - 32 bytes
+    Some(format!(
+        "0x{}{}{}{:0>64x}",
+        PAYOUT_TO_USER_SELECTOR,
+        hex::encode(payment_id),
+        address_word_hex(recipient)?,
+        amount
+    ))
+}
+
+/// ABI-encode a `payoutToUser(bytes32,address,uint256,bytes)` call as 0x-prefixed hex,
+/// `None` if `recipient` is not a 20-byte hex address
+pub fn payout_memo_calldata(
+    payment_id: &[u8; 32],
+    recipient: &str,
+    amount: u64,
+    memo: &[u8],
+) -> Option<String> {
+    // The head holds the offset of the dynamic bytes, which follow the four
+    // head words as their length and the data right-padded to whole words
+    let mut padded = memo.to_vec();
+    padded.resize(memo.len().div_ceil(32) * 32, 0);
+    Some(format!(
+        "0x{}{}{}{:0>64x}{:064x}{:064x}{}",
+        hex::encode(selector(PAYOUT_WITH_MEMO_SIGNATURE)),
+        hex::encode(payment_id),
+        address_word_hex(recipient)?,
+        amount,
+        4 * 32,
+        memo.len(),
+        hex::encode(padded)
+    ))
+}
+
+/// ABI-encode an ERC-20 `transfer(address,uint256)` call as 0x-prefixed hex
+pub fn transfer_calldata(recipient: &str, amount: u64) -> Option<String> {
+    Some(encode_call(
+        selector("transfer(address,uint256)"),
+        &[encode_address(recipient)?, encode_uint(amount.into())],
+    ))
+}
+
+/// Build the `eth_sendTransaction` JSON-RPC request body for a call from `from` to `to`
+///
+/// `value` is only included when non-zero, so contract calls keep their shape.
+pub fn send_transaction_request(
+    from: &str,
+    to: &str,
+    data: &str,
+    value: u64,
+    params: TxParams,
+) -> Value {
+    let mut tx = json!({
+        "from": from,
+        "to": to,
+        "gas": format!("0x{:x}", params.gas_limit),
+        "gasPrice": format!("0x{:x}", params.gas_price),
+        "nonce": format!("0x{:x}", params.nonce),
+        "data": data
+    });
+    if value > 0 {
+        tx["value"] = json!(format!("0x{:x}", value));
+    }
+    json!({
+        "jsonrpc": "2.0",
+        "method": "eth_sendTransaction",
+        "params": [tx],
+        "id": 1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::EthereumPayoutService;
+    use super::*;
+
+    #[test]
+    fn encodes_payout_calldata() {
+        let data = payout_calldata(
+            &[0xab; 32],
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+            1_000_000,
+        )
+        .unwrap();
+        assert_eq!(data.len(), 2 + 8 + 3 * 64);
+        assert!(data.starts_with("0xb77276d8abab"));
+        assert!(data.ends_with("00000000000f4240"));
+    }
+
+    #[test]
+    fn refuses_to_pad_malformed_recipients() {
+        let recipient = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+        for malformed in [
+            &recipient[..41],
+            &format!("{}00", recipient)[..],
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79CZ",
+            "70997970C51812dc3A010C7d01b50e0d17dc79C8",
+        ] {
+            assert_eq!(address_word_hex(malformed), None, "{}", malformed);
+            assert_eq!(payout_calldata(&[0xab; 32], malformed, 1), None);
+            assert_eq!(
+                payout_memo_calldata(&[0xab; 32], malformed, 1, b"INV-42"),
+                None
+            );
+            assert_eq!(transfer_calldata(malformed, 1), None);
+        }
+    }
+
+    #[test]
+    fn hand_built_destinations_are_still_checked() {
+        let config = super::super::testing::test_config();
+        let destination = super::super::testing::TEST_DESTINATION;
+        let mut eth_dest = EthereumDestination::parse(destination).unwrap();
+        eth_dest.recipient.pop();
+        assert!(matches!(
+            plan_call(&config, eth_dest.clone(), [1; 32], 5, destination),
+            Err(PayoutError::InvalidDestination(_))
+        ));
+        assert!(EthereumDestination::new(
+            eth_dest.chain_id,
+            &eth_dest.asset_code,
+            &eth_dest.recipient,
+            &eth_dest.token
+        )
+        .unwrap_err()
+        .contains("wrong length"));
+    }
+
+    #[test]
+    fn encodes_memo_as_dynamic_bytes() {
+        let recipient = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+        let words = |memo: &[u8]| {
+            let data = payout_memo_calldata(&[0xab; 32], recipient, 7, memo).unwrap();
+            let body = data[10..].to_string();
+            assert_eq!(body.len() % 64, 0);
+            (0..body.len() / 64)
+                .map(|i| body[i * 64..(i + 1) * 64].to_string())
+                .collect::<Vec<_>>()
+        };
+        let offset = format!("{:064x}", 128);
+
+        let empty = words(b"");
+        assert_eq!(empty.len(), 5);
+        assert_eq!(empty[3], offset);
+        assert_eq!(empty[4], format!("{:064x}", 0));
+
+        let short = words(b"INV-42");
+        assert_eq!(short.len(), 6);
+        assert_eq!(short[4], format!("{:064x}", 6));
+        assert_eq!(short[5], format!("{:0<64}", hex::encode("INV-42")));
+
+        let boundary = words(&[0x11; 32]);
+        assert_eq!(boundary.len(), 6);
+        assert_eq!(boundary[5], "11".repeat(32));
+        let over = words(&[0x11; 33]);
+        assert_eq!(over.len(), 7);
+        assert_eq!(over[6], format!("{:0<64}", "11"));
+    }
+
+    #[test]
+    fn memos_only_apply_to_treasury_payouts() {
+        let config = super::super::testing::test_config();
+        let mut plan = plan_payout(&config, super::super::testing::TEST_DESTINATION, 5, 1).unwrap();
+        plan.attach_memo(b"INV-42").unwrap();
+        assert!(plan.data.starts_with(&format!(
+            "0x{}",
+            hex::encode(selector(PAYOUT_WITH_MEMO_SIGNATURE))
+        )));
+
+        let mut config = super::super::testing::test_config();
+        config
+            .assets
+            .insert(super::super::AssetInfo::native("EURC"));
+        let mut plan = plan_payout(&config, super::super::testing::TEST_DESTINATION, 5, 1).unwrap();
+        assert!(plan.attach_memo(b"INV-42").is_err());
+    }
+
+    #[test]
+    fn encodes_transfer_calldata() {
+        let data =
+            transfer_calldata("0x70997970C51812dc3A010C7d01b50e0d17dc79C8", 1_000_000).unwrap();
+        assert_eq!(
+            data,
+            concat!(
+                "0xa9059cbb",
+                "00000000000000000000000070997970c51812dc3a010c7d01b50e0d17dc79c8",
+                "00000000000000000000000000000000000000000000000000000000000f4240"
+            )
+        );
+        assert!(transfer_calldata("0x1234", 1).is_none());
+    }
+
+    fn capped_config(cap: u64) -> EthereumPayoutConfig {
+        let mut config = super::super::testing::test_config();
+        config.assets.apply_caps(&format!("EURC:{}", cap)).unwrap();
+        config
+    }
+
+    #[test]
+    fn splits_exact_multiples_evenly() {
+        let config = capped_config(100);
+        let plans = plan_payouts(&config, super::super::testing::TEST_DESTINATION, 300, 1).unwrap();
+        let amounts: Vec<u64> = plans.iter().map(|plan| plan.amount).collect();
+        assert_eq!(amounts, vec![100, 100, 100]);
+
+        let base =
+            EthereumPayoutService::generate_payment_id(super::super::testing::TEST_DESTINATION, 1);
+        for (index, plan) in plans.iter().enumerate() {
+            assert_eq!(plan.payment_id, chunk_payment_id(&base, index as u32));
+            assert!(plan.data.contains(&hex::encode(plan.payment_id)));
+        }
+        assert_ne!(plans[0].payment_id, plans[1].payment_id);
+    }
+
+    #[test]
+    fn splits_remainder_into_last_chunk() {
+        let config = capped_config(100);
+        let plans = plan_payouts(&config, super::super::testing::TEST_DESTINATION, 250, 1).unwrap();
+        let amounts: Vec<u64> = plans.iter().map(|plan| plan.amount).collect();
+        assert_eq!(amounts, vec![100, 100, 50]);
+
+        // At or below the cap the payout keeps its undivided payment ID
+        let single =
+            plan_payouts(&config, super::super::testing::TEST_DESTINATION, 100, 1).unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(
+            single[0].payment_id,
+            plan_payout(&config, super::super::testing::TEST_DESTINATION, 100, 1)
+                .unwrap()
+                .payment_id
+        );
+    }
+
+    #[test]
+    fn native_assets_send_value_to_the_recipient() {
+        let mut config = super::super::testing::test_config();
+        config
+            .assets
+            .insert(super::super::AssetInfo::native("EURC"));
+        let plan = plan_payout(&config, super::super::testing::TEST_DESTINATION, 5, 1).unwrap();
+        assert_eq!(plan.mode, PayoutMode::Native);
+        assert_eq!(plan.to, "0x70997970C51812dc3A010C7d01b50e0d17dc79C8");
+        assert_eq!(plan.data, "0x");
+        assert_eq!(plan.value, 5);
+    }
+
+    #[test]
+    fn direct_transfer_assets_target_the_token() {
+        let mut config = super::super::testing::test_config();
+        config
+            .assets
+            .insert(super::super::AssetInfo::direct_transfer(
+                "EURC",
+                6,
+                "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512",
+            ));
+        let plan = plan_payout(&config, super::super::testing::TEST_DESTINATION, 5, 1).unwrap();
+        assert_eq!(plan.mode, PayoutMode::DirectTransfer);
+        assert_eq!(plan.to, "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512");
+        assert!(plan.data.starts_with("0xa9059cbb"));
+
+        config.assets.insert(super::super::AssetInfo {
+            token_address: None,
+            ..super::super::AssetInfo::direct_transfer("EURC", 6, "")
+        });
+        assert!(matches!(
+            plan_payout(&config, super::super::testing::TEST_DESTINATION, 5, 1),
+            Err(PayoutError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+//! Strategies deriving payment IDs
+//!
+//! A payment ID makes a payout idempotent, so it must be derived from a
+//! request the same way every time. By default it is the configured
+//! [`PaymentIdHash`] over the destination and sequence. Deployments using
+//! another scheme, e.g. UUIDv5 in their own namespace, set a
+//! [`PaymentIdStrategy`] with
+//! [`EthereumPayoutService::with_payment_id_strategy`]. A request may also
+//! carry an ID of its own, such as an invoice ID, with
+//! [`PayoutRequest::with_payment_id`], which is used as it is, without a
+//! namespace. Derived or not, IDs of split payouts are chunked as usual, and
+//! records note the strategy's label, or `external`, in
+//! `payment_id_strategy`.
+
+use super::{
+    EthereumPayoutConfig, EthereumPayoutService, PaymentIdHash, PayoutError, PayoutRequest,
+};
+use std::fmt;
+use std::sync::Arc;
+
+/// Label recorded for payment IDs supplied with the request
+pub const EXTERNAL_PAYMENT_ID: &str = "external";
+
+/// Derives the payment ID of a payout from its destination and sequence
+pub trait PaymentIdStrategy: fmt::Debug + Send + Sync {
+    /// Name recorded with the payouts whose IDs it derived
+    fn label(&self) -> &str;
+
+    /// ID of the payout for `destination` and `sequence`, before any namespace
+    fn payment_id(&self, destination: &str, sequence: u64) -> [u8; 32];
+}
+
+impl PaymentIdStrategy for PaymentIdHash {
+    fn label(&self) -> &str {
+        self.as_str()
+    }
+
+    fn payment_id(&self, destination: &str, sequence: u64) -> [u8; 32] {
+        PaymentIdHash::payment_id(*self, destination, sequence)
+    }
+}
+
+impl PayoutRequest {
+    /// Pay under `payment_id` instead of one derived from the request, failing
+    /// unless it is exactly 32 bytes
+    pub fn with_payment_id(mut self, payment_id: &[u8]) -> Result<Self, PayoutError> {
+        let mut id = [0u8; 32];
+        if payment_id.len() != id.len() {
+            return Err(PayoutError::InvalidPaymentId {
+                len: payment_id.len(),
+            });
+        }
+        id.copy_from_slice(payment_id);
+        self.payment_id = Some(id);
+        Ok(self)
+    }
+}
+
+impl EthereumPayoutConfig {
+    /// Strategy deriving payment IDs, the configured hash unless one is set
+    pub fn payment_id_strategy(&self) -> &dyn PaymentIdStrategy {
+        match &self.payment_id_strategy {
+            Some(strategy) => strategy.as_ref(),
+            None => &self.payment_id_hash,
+        }
+    }
+
+    /// Payment ID of the request's payout, or of its first chunk if split
+    pub fn payment_id_of(&self, request: &PayoutRequest) -> [u8; 32] {
+        match request.payment_id {
+            Some(payment_id) => payment_id,
+            None => self.payment_id(&request.destination, request.sequence),
+        }
+    }
+
+    /// Label recorded for the request's payment ID
+    pub fn payment_id_label(&self, request: &PayoutRequest) -> String {
+        match request.payment_id {
+            Some(_) => EXTERNAL_PAYMENT_ID.to_string(),
+            None => self.payment_id_strategy().label().to_string(),
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Derive payment IDs with `strategy` instead of the configured hash
+    pub fn with_payment_id_strategy(mut self, strategy: Arc<dyn PaymentIdStrategy>) -> Self {
+        self.config.payment_id_strategy = Some(strategy);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hash::sha256;
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{Dispatched, PayoutOutcome};
+    use super::*;
+
+    /// Name-based IDs in the style of UUIDv5, widened to 32 bytes
+    #[derive(Debug)]
+    struct Namespaced(&'static str);
+
+    impl PaymentIdStrategy for Namespaced {
+        fn label(&self) -> &str {
+            "namespaced"
+        }
+
+        fn payment_id(&self, destination: &str, sequence: u64) -> [u8; 32] {
+            sha256(format!("{}/{}/{}", self.0, destination, sequence).as_bytes())
+        }
+    }
+
+    #[tokio::test]
+    async fn strategies_label_the_ids_they_derive() {
+        let default = test_service(test_config(), mock_chain());
+        let custom = test_service(test_config(), mock_chain())
+            .with_payment_id_strategy(Arc::new(Namespaced("invoices")));
+        for service in [&default, &custom] {
+            service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .unwrap();
+        }
+
+        let default_id = default.config().payment_id(TEST_DESTINATION, 1);
+        let custom_id = custom.config().payment_id(TEST_DESTINATION, 1);
+        assert_ne!(default_id, custom_id);
+        assert_eq!(
+            custom_id,
+            Namespaced("invoices").payment_id(TEST_DESTINATION, 1)
+        );
+        let label = |service: &EthereumPayoutService, id| {
+            service.store().get(&id).unwrap().payment_id_strategy
+        };
+        assert_eq!(label(&default, default_id).as_deref(), Some("sha256"));
+        assert_eq!(label(&custom, custom_id).as_deref(), Some("namespaced"));
+        assert!(default.store().get(&custom_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn external_ids_bypass_derivation() {
+        let transport = mock_chain();
+        let service = Arc::new(
+            test_service(test_config(), transport.clone())
+                .with_payment_id_strategy(Arc::new(Namespaced("invoices"))),
+        );
+        let invoice = [0x42; 32];
+        let request = PayoutRequest::new(TEST_DESTINATION, 100, 1)
+            .with_payment_id(&invoice)
+            .unwrap();
+        match service.dispatch_payout(request.clone()).await {
+            Dispatched::Completed(Ok(PayoutOutcome::Submitted { .. })) => {}
+            other => panic!("expected a submitted payout, got {:?}", other),
+        }
+        let record = service.store().get(&invoice).unwrap();
+        assert_eq!(record.payment_id_strategy.as_deref(), Some("external"));
+        assert!(service
+            .store()
+            .get(&service.config().payment_id(TEST_DESTINATION, 1))
+            .is_none());
+
+        // The Treasury sees the invoice's ID, so it rejects paying it twice
+        service.dispatch_payout(request).await;
+        for call in transport.sent_transactions() {
+            let data = call["data"].as_str().unwrap().to_string();
+            assert!(data.contains(&hex::encode(invoice)));
+        }
+
+        assert!(matches!(
+            PayoutRequest::new(TEST_DESTINATION, 100, 1).with_payment_id(&[0x42; 31]),
+            Err(PayoutError::InvalidPaymentId { len: 31 })
+        ));
+    }
+}
@@ -0,0 +1,286 @@
+//! Pinning the pre-flight reads of a payout to one block
+//!
+//! Before a payout is sent the chain is read several times: whether the
+//! recipient has code, the token's domain separator, the Safe's nonce, the
+//! L1 fee. Against `latest`, blocks produced between two reads can make them
+//! disagree. Under [`BlockPinning::Number`] the head is fetched once per
+//! payout and every read passes its number; [`BlockPinning::Hash`] passes its
+//! hash as an EIP-1898 block parameter instead, which also refuses to read a
+//! block that was reorged out. When the node cannot serve the pinned block's
+//! state, as pruned nodes and load-balanced providers lagging behind the head
+//! do, the read is repeated against `latest` and the rest of the payout is
+//! unpinned. The block a payout's reads were pinned to is kept on its record.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::{EthereumPayoutService, PayoutError};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::future::Future;
+use tracing::warn;
+
+/// Block the pre-flight reads of a payout are made at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockPinning {
+    /// Every read at `latest`
+    #[default]
+    Latest,
+    /// Every read at the number of the head when the payout started
+    Number,
+    /// Every read at the hash of the head when the payout started (EIP-1898)
+    Hash,
+}
+
+impl BlockPinning {
+    pub fn parse(pinning: &str) -> Option<Self> {
+        match pinning {
+            "latest" => Some(BlockPinning::Latest),
+            "number" => Some(BlockPinning::Number),
+            "hash" => Some(BlockPinning::Hash),
+            _ => None,
+        }
+    }
+}
+
+/// Whether `err` means the node has no state for the requested block
+pub fn is_missing_state(err: &PayoutError) -> bool {
+    let message = match err {
+        PayoutError::Rpc { message, .. } => message.to_ascii_lowercase(),
+        _ => return false,
+    };
+    [
+        "header not found",
+        "unknown block",
+        "block not found",
+        "missing trie node",
+        "historical state",
+        "state not available",
+        "state is not available",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PinnedBlock {
+    number: u64,
+    /// Set under `BlockPinning::Hash`
+    hash: Option<String>,
+}
+
+impl PinnedBlock {
+    fn block_parameter(&self) -> Value {
+        match &self.hash {
+            Some(hash) => json!({ "blockHash": hash }),
+            None => json!(format!("0x{:x}", self.number)),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Block the reads of the payout running on this task are pinned to
+    static PINNED: RefCell<Option<PinnedBlock>>;
+}
+
+impl EthereumPayoutService {
+    /// Run `payout` with its pre-flight reads pinned to the current head,
+    /// unpinned if pinning is off or the head cannot be fetched
+    pub(super) async fn with_pinned_block<F: Future>(&self, payout: F) -> F::Output {
+        let pinned = match self.config.block_pinning {
+            BlockPinning::Latest => None,
+            pinning => match self.fetch_head(pinning).await {
+                Ok(pinned) => Some(pinned),
+                Err(err) => {
+                    warn!("Cannot pin pre-flight reads, reading at latest: {}", err);
+                    None
+                }
+            },
+        };
+        PINNED.scope(RefCell::new(pinned), payout).await
+    }
+
+    async fn fetch_head(&self, pinning: BlockPinning) -> Result<PinnedBlock, PayoutError> {
+        if pinning == BlockPinning::Number {
+            let number = self.rpc(rpc_request("eth_blockNumber", json!([]))).await?;
+            return Ok(PinnedBlock {
+                number: parse_quantity(&number)?,
+                hash: None,
+            });
+        }
+        let block = self
+            .rpc(rpc_request(
+                "eth_getBlockByNumber",
+                json!(["latest", false]),
+            ))
+            .await?;
+        let hash = block["hash"].as_str().ok_or_else(|| {
+            PayoutError::InvalidResponse(format!("Block without hash: {}", block))
+        })?;
+        Ok(PinnedBlock {
+            number: parse_quantity(&block["number"])?,
+            hash: Some(hash.to_string()),
+        })
+    }
+
+    /// Number of the block the running payout's reads are pinned to
+    pub(super) fn pinned_block(&self) -> Option<u64> {
+        PINNED
+            .try_with(|pinned| pinned.borrow().as_ref().map(|pinned| pinned.number))
+            .ok()
+            .flatten()
+    }
+
+    /// Send the read `method` with `params` followed by the block parameter,
+    /// the pinned block while a payout runs and `latest` otherwise
+    pub(super) async fn read_at_block(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+    ) -> Result<Value, PayoutError> {
+        let pinned = PINNED
+            .try_with(|pinned| pinned.borrow().clone())
+            .ok()
+            .flatten();
+        let at = |block: Value| {
+            let mut params = params.clone();
+            params.push(block);
+            rpc_request(method, Value::Array(params))
+        };
+        let pinned = match pinned {
+            Some(pinned) => pinned,
+            None => return self.rpc(at(json!("latest"))).await,
+        };
+        match self.rpc(at(pinned.block_parameter())).await {
+            Err(err) if is_missing_state(&err) => {
+                warn!(
+                    "Node has no state for pinned block {}, reading at latest: {}",
+                    pinned.number, err
+                );
+                PINNED.with(|pinned| pinned.borrow_mut().take());
+                self.rpc(at(json!("latest"))).await
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{AssetInfo, L2FeeModel, PayoutOutcome};
+    use super::*;
+    use std::sync::Arc;
+
+    /// Node with a contract recipient and an OP-stack L1 fee, so a native
+    /// payout reads both `eth_getCode` and `eth_call`
+    fn node() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on_result("eth_blockNumber", json!("0x2a"));
+        transport.on_result(
+            "eth_getBlockByNumber",
+            json!({ "number": "0x2a", "hash": format!("0x{:064x}", 42) }),
+        );
+        transport.on_result("eth_getCode", json!("0x6080"));
+        transport.on_result("eth_estimateGas", json!("0x7530"));
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 1)));
+        transport
+    }
+
+    fn block_parameters(transport: &MockTransport) -> Vec<Value> {
+        let mut parameters: Vec<Value> = transport
+            .calls("eth_getCode")
+            .iter()
+            .map(|call| call["params"][1].clone())
+            .collect();
+        parameters.extend(
+            transport
+                .calls("eth_call")
+                .iter()
+                .map(|call| call["params"][1].clone()),
+        );
+        parameters
+    }
+
+    fn pinned_config(pinning: BlockPinning) -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.insert(AssetInfo::native("EURC"));
+        config.store_path =
+            Some(std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4())));
+        config.block_pinning = pinning;
+        config.l2_fee_model = L2FeeModel::OpStack;
+        config
+    }
+
+    /// Pay the test destination, returning the block its record was pinned to
+    async fn pay(transport: &Arc<MockTransport>, pinning: BlockPinning) -> Option<u64> {
+        let config = pinned_config(pinning);
+        let path = config.store_path.clone().unwrap();
+        let service = test_service(config, transport.clone());
+        assert!(matches!(
+            service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .unwrap(),
+            PayoutOutcome::Submitted { .. }
+        ));
+        let id = service.config().payment_id(TEST_DESTINATION, 1);
+        let pinned = service.store().get(&id).unwrap().pinned_block;
+        std::fs::remove_file(&path).unwrap();
+        pinned
+    }
+
+    #[tokio::test]
+    async fn reads_of_a_payout_share_one_block() {
+        let transport = node();
+        assert_eq!(pay(&transport, BlockPinning::Number).await, Some(42));
+        let parameters = block_parameters(&transport);
+        assert_eq!(parameters.len(), 2);
+        assert!(parameters.iter().all(|block| *block == json!("0x2a")));
+        assert_eq!(transport.call_count("eth_blockNumber"), 1);
+
+        let transport = node();
+        assert_eq!(pay(&transport, BlockPinning::Hash).await, Some(42));
+        let parameters = block_parameters(&transport);
+        assert_eq!(parameters.len(), 2);
+        assert!(parameters
+            .iter()
+            .all(|block| *block == json!({ "blockHash": format!("0x{:064x}", 42) })));
+
+        let transport = node();
+        assert_eq!(pay(&transport, BlockPinning::Latest).await, None);
+        assert!(block_parameters(&transport)
+            .iter()
+            .all(|block| *block == json!("latest")));
+        assert_eq!(transport.call_count("eth_blockNumber"), 0);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_latest_without_historical_state() {
+        let transport = node();
+        transport.on("eth_getCode", |params: &Value| {
+            if params[1] == "latest" {
+                Ok(json!("0x6080"))
+            } else {
+                Err(json!({"code": -32000, "message": "header not found"}))
+            }
+        });
+        assert_eq!(pay(&transport, BlockPinning::Number).await, None);
+        // The rest of the payout reads at latest rather than failing again
+        let parameters = block_parameters(&transport);
+        assert_eq!(
+            parameters,
+            [json!("0x2a"), json!("latest"), json!("latest")]
+        );
+
+        // Reads outside a payout are never pinned
+        let transport = node();
+        let service = test_service(pinned_config(BlockPinning::Number), transport.clone());
+        assert_eq!(service.pinned_block(), None);
+        assert!(service.preview_payout(TEST_DESTINATION, 100).await.is_ok());
+        assert!(block_parameters(&transport)
+            .iter()
+            .all(|block| *block == json!("latest")));
+        assert_eq!(transport.call_count("eth_blockNumber"), 0);
+    }
+}
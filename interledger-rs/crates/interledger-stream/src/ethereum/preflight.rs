@@ -0,0 +1,142 @@
+//! Reads made before a payout's nonce is taken
+//!
+//! The gas price, the gas limit (with its `eth_getCode` and estimate) and
+//! the Treasury simulation do not depend on each
+//! other, so they are requested concurrently and cost the slowest of them
+//! rather than their sum. The first to fail stops the others. The nonce is
+//! only taken once they all answered, right before the transaction is
+//! signed, so a failing read never leases one. Their wall time counts as
+//! the payout's gas phase, and [`PhaseTimings::preflight_saved`] records how
+//! much longer reading them one after another would have taken.
+
+use super::simulation::Simulated;
+use super::timing::{PayoutPhase, PhaseTimings};
+use super::{EthereumPayoutService, PayoutError, PayoutPlan};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// What a payout's transaction needs besides its nonce
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Preflight {
+    pub gas_price: u64,
+    pub gas_limit: u64,
+    /// ID the simulated Treasury call returned, see `payout_returns_id`
+    pub treasury_payout_id: Option<String>,
+    /// The simulation showed the Treasury already processed the payment
+    pub already_processed: bool,
+}
+
+/// Run `future`, returning its output along with how long it took
+async fn measured<T, F>(future: F) -> Result<(T, Duration), PayoutError>
+where
+    F: Future<Output = Result<T, PayoutError>>,
+{
+    let started = Instant::now();
+    let output = future.await?;
+    Ok((output, started.elapsed()))
+}
+
+impl EthereumPayoutService {
+    /// Read what sending `plan` needs before its nonce, all at once
+    pub(super) async fn preflight(
+        &self,
+        plan: &PayoutPlan,
+        timings: &mut PhaseTimings,
+    ) -> Result<Preflight, PayoutError> {
+        let started = Instant::now();
+        let result = tokio::try_join!(
+            measured(self.get_gas_price()),
+            measured(self.gas_limit(plan)),
+            measured(self.simulate_payout(plan)),
+        );
+        let elapsed = started.elapsed();
+        timings.add(PayoutPhase::Gas, elapsed);
+        let ((gas_price, price_took), (gas_limit, limit_took), (simulated, simulation_took)) =
+            result?;
+        let sequential = price_took + limit_took + simulation_took;
+        timings.preflight_saved += sequential.saturating_sub(elapsed);
+        let (treasury_payout_id, already_processed) = match simulated {
+            Simulated::Sends(payout_id) => (payout_id, false),
+            Simulated::AlreadyProcessed => (None, true),
+        };
+        Ok(Preflight {
+            gas_price,
+            gas_limit,
+            treasury_payout_id,
+            already_processed,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport, TEST_DESTINATION};
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    const READ_DELAY: Duration = Duration::from_millis(150);
+
+    /// Node taking `READ_DELAY` to answer each pre-flight read
+    fn slow_node() -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        transport.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        transport.on_result("eth_getCode", json!("0x"));
+        transport.on_result("eth_estimateGas", json!("0xc350"));
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 7)));
+        transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+        for method in ["eth_gasPrice", "eth_estimateGas", "eth_call"] {
+            transport.delay(method, READ_DELAY);
+        }
+        transport
+    }
+
+    fn slow_config() -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.gas_estimate_ttl = Some(Duration::from_secs(300));
+        config.payout_returns_id = true;
+        config
+    }
+
+    #[tokio::test]
+    async fn reads_take_as_long_as_the_slowest() {
+        let transport = slow_node();
+        let service = test_service(slow_config(), transport.clone());
+        let started = Instant::now();
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let took = started.elapsed();
+        // Three reads of 150ms each, which would take 450ms in turn
+        assert!(took < READ_DELAY * 2, "took {:?}", took);
+
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert!(record.timings.gas >= READ_DELAY);
+        assert!(record.timings.gas < READ_DELAY * 2);
+        assert!(
+            record.timings.preflight_saved >= READ_DELAY,
+            "{:?}",
+            record.timings
+        );
+        assert_eq!(record.treasury_payout_id.as_deref(), Some("7"));
+    }
+
+    #[tokio::test]
+    async fn first_failing_read_stops_the_payout_before_its_nonce() {
+        let transport = slow_node();
+        transport.on_error("eth_gasPrice", -32602, "invalid params");
+        transport.delay("eth_gasPrice", Duration::ZERO);
+        let service = test_service(slow_config(), transport.clone());
+        let started = Instant::now();
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        assert!(started.elapsed() < READ_DELAY, "{:?}", started.elapsed());
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 0);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+}
@@ -0,0 +1,264 @@
+//! Previews of what a payout would do, without sending it
+//!
+//! A preview runs the same validation as execution: destination and memo
+//! checks, the sequence window, conversion, dust minimums, splitting, daily
+//! limits and approval thresholds. It reads from the store, the rate provider
+//! and the node, but records nothing, leases no nonce and sends nothing.
+
+use super::{approval::Approval, Validated};
+use super::{Conversion, Disposition, EthereumPayoutService, PayoutError, PayoutRequest, Wei};
+use serde_json::{json, Value};
+
+/// What executing a payout request would do
+#[derive(Debug)]
+pub struct PayoutPreview {
+    pub recipient: String,
+    pub chain_id: u64,
+    pub asset_code: String,
+    /// Amount paid in the payout asset's base units, `None` if nothing would be sent
+    pub amount: Option<u64>,
+    /// Rate applied to the request's amount, if it is in another asset
+    pub conversion: Option<Conversion>,
+    /// Transactions the payout would be split into
+    pub transactions: usize,
+    /// Gas limit times the current gas price over all transactions, in wei,
+    /// `None` if it was not estimated or the node could not be reached
+    pub estimated_gas_cost: Option<u128>,
+    /// Why the payout would not be sent right away
+    pub blocked: Option<PayoutBlocker>,
+}
+
+/// Reason a previewed payout would not be sent right away
+#[derive(Debug)]
+pub enum PayoutBlocker {
+    /// Execution would fail with this error
+    Refused(PayoutError),
+    /// The amount is zero, or converts to zero
+    NothingToPay,
+    /// The Treasury is paused, so the payout would be deferred
+    Paused,
+    /// Added to the recipient's dust balance rather than sent
+    BelowMinimum { min_payout: u64 },
+    /// Above the approval threshold; held until an operator approves it
+    AwaitingApproval,
+}
+
+impl PayoutBlocker {
+    /// Name of the rule blocking the payout, as in replay and dry-run reports
+    pub fn rule(&self) -> &'static str {
+        match self {
+            PayoutBlocker::Refused(_) => "refused",
+            PayoutBlocker::NothingToPay => "nothing_to_pay",
+            PayoutBlocker::Paused => "paused",
+            PayoutBlocker::BelowMinimum { .. } => "below_minimum",
+            PayoutBlocker::AwaitingApproval => "awaiting_approval",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut blocked = json!({ "rule": self.rule() });
+        match self {
+            PayoutBlocker::Refused(err) => blocked["error"] = json!(err.to_string()),
+            PayoutBlocker::BelowMinimum { min_payout } => blocked["min_payout"] = json!(min_payout),
+            _ => {}
+        }
+        blocked
+    }
+}
+
+impl EthereumPayoutService {
+    /// Preview executing `request`, estimating its gas cost if `estimate_gas`
+    ///
+    /// Only fails if the destination cannot be parsed; a payout that would be
+    /// refused is previewed with [`PayoutBlocker::Refused`].
+    pub async fn preview(
+        &self,
+        request: &PayoutRequest,
+        estimate_gas: bool,
+    ) -> Result<PayoutPreview, PayoutError> {
+        let eth_dest = self
+            .config
+            .parse_destination(&request.destination)
+            .ok_or_else(|| PayoutError::InvalidDestination(request.destination.clone()))?;
+        let mut preview = PayoutPreview {
+            recipient: eth_dest.recipient,
+            chain_id: eth_dest.chain_id,
+            asset_code: eth_dest.asset_code,
+            amount: None,
+            conversion: None,
+            transactions: 0,
+            estimated_gas_cost: None,
+            blocked: None,
+        };
+
+        let disposition = match self.validate(request).await {
+            Ok(Validated {
+                conversion,
+                disposition,
+                ..
+            }) => {
+                preview.conversion = conversion;
+                disposition
+            }
+            Err(err) => {
+                preview.blocked = Some(PayoutBlocker::Refused(err));
+                return Ok(preview);
+            }
+        };
+        let (plans, approval) = match disposition {
+            Disposition::Skipped(_) => {
+                preview.blocked = Some(PayoutBlocker::NothingToPay);
+                return Ok(preview);
+            }
+            Disposition::Paused => {
+                preview.blocked = Some(PayoutBlocker::Paused);
+                return Ok(preview);
+            }
+            Disposition::Dust { amount, min_payout } => {
+                preview.amount = Some(amount.base_units);
+                preview.blocked = Some(PayoutBlocker::BelowMinimum { min_payout });
+                return Ok(preview);
+            }
+            Disposition::Send {
+                plans,
+                amount,
+                approval,
+            } => {
+                preview.amount = Some(amount.base_units);
+                (plans, approval)
+            }
+        };
+        preview.transactions = plans.len();
+        if !matches!(approval, Approval::NotNeeded) {
+            preview.blocked = Some(PayoutBlocker::AwaitingApproval);
+        }
+
+        if estimate_gas {
+            preview.estimated_gas_cost = async {
+                let gas_price = Wei::new(u128::from(self.get_gas_price().await?));
+                let mut cost = Wei::default();
+                for plan in &plans {
+                    let gas = Wei::for_gas(self.gas_limit(plan).await?, gas_price);
+                    cost = cost.saturating_add(gas.unwrap_or(Wei::new(u128::MAX)));
+                }
+                Ok::<_, PayoutError>(cost.value())
+            }
+            .await
+            .ok();
+        }
+        Ok(preview)
+    }
+
+    /// Preview paying `amount` to `destination` as its next sequence
+    pub async fn preview_payout(
+        &self,
+        destination: &str,
+        amount: u64,
+    ) -> Result<PayoutPreview, PayoutError> {
+        let sequence = self
+            .store
+            .highest_sequence(destination)
+            .map_or(0, |highest| highest + 1);
+        self.preview(&PayoutRequest::new(destination, amount, sequence), true)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{PayoutOutcome, StaticRateProvider};
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn preview_matches_execution_without_sending() {
+        let transport = mock_chain();
+        let service = test_service(test_config(), transport.clone());
+
+        let preview = service.preview_payout(TEST_DESTINATION, 100).await.unwrap();
+        assert_eq!(preview.asset_code, "EURC");
+        assert_eq!(preview.chain_id, 31337);
+        assert_eq!(preview.amount, Some(100));
+        assert_eq!(preview.transactions, 1);
+        assert!(preview.estimated_gas_cost.unwrap() > 0);
+        assert!(preview.blocked.is_none());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 0);
+        assert!(service.store().highest_sequence(TEST_DESTINATION).is_none());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 0)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hashes().len(), preview.transactions);
+        // Previewing a replay is not counted as one
+        let replay = PayoutRequest::new(TEST_DESTINATION, 100, 0);
+        assert!(service
+            .preview(&replay, false)
+            .await
+            .unwrap()
+            .blocked
+            .is_none());
+        assert_eq!(service.sequence_stats(TEST_DESTINATION).repeated, 0);
+    }
+
+    #[tokio::test]
+    async fn preview_shows_conversion_and_splits() {
+        let mut config = test_config();
+        config.assets.apply_caps("EURC:600").unwrap();
+        let transport = mock_chain();
+        let service = test_service(config, transport.clone()).with_rate_provider(Arc::new(
+            StaticRateProvider::new().with_rate("XRP", "EURC", 512, 6),
+        ));
+        let request = PayoutRequest::new(TEST_DESTINATION, 2_500_000, 1).with_source_asset("XRP");
+
+        let preview = service.preview(&request, false).await.unwrap();
+        assert_eq!(preview.amount, Some(1280));
+        assert_eq!(preview.conversion.unwrap().source_amount, 2_500_000);
+        assert_eq!(preview.transactions, 3);
+        assert!(preview.estimated_gas_cost.is_none());
+
+        let outcome = service.execute(&request).await.unwrap();
+        assert_eq!(outcome.tx_hashes().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn preview_reports_what_blocks_execution() {
+        let mut config = test_config();
+        config.assets.apply_daily_caps("EURC:150").unwrap();
+        config.assets.apply_min_payouts("EURC:20").unwrap();
+        let service = test_service(config, mock_chain());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        let over_limit = PayoutRequest::new(TEST_DESTINATION, 100, 2);
+        let err = service.execute(&over_limit).await.unwrap_err();
+        match service.preview(&over_limit, true).await.unwrap().blocked {
+            Some(PayoutBlocker::Refused(refused)) => {
+                assert_eq!(refused.to_string(), err.to_string())
+            }
+            other => panic!("expected a refusal, got {:?}", other),
+        }
+
+        let dust = PayoutRequest::new(TEST_DESTINATION, 10, 3);
+        assert!(matches!(
+            service.preview(&dust, true).await.unwrap().blocked,
+            Some(PayoutBlocker::BelowMinimum { min_payout: 20 })
+        ));
+        assert!(matches!(
+            service.execute(&dust).await.unwrap(),
+            PayoutOutcome::Accumulated { balance: 10 }
+        ));
+
+        let burn = "test.receiver.eth.31337.EURC.0x000000000000000000000000000000000000dEaD.x";
+        let preview = service.preview_payout(burn, 100).await.unwrap();
+        assert!(matches!(
+            preview.blocked,
+            Some(PayoutBlocker::Refused(PayoutError::RecipientInvalid { .. }))
+        ));
+        assert!(service.execute_payout(burn, 100, 0).await.is_err());
+    }
+}
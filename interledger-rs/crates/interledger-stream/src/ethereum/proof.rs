@@ -0,0 +1,492 @@
+//! Self-contained proofs of how a payout's payment ID came about
+//!
+//! A proof carries the stored inputs of a payout, the exact bytes hashed for
+//! its payment ID, the ID as stored, the call data that pays it and its
+//! transaction receipt. [`verify_proof`] recomputes everything derivable from
+//! the inputs, so an auditor can check a proof without the service or the
+//! node. The service verifies each proof it exports and alerts on records
+//! whose stored values no longer match their inputs.
+
+use super::payload::{payout_calldata, payout_memo_calldata, transfer_calldata};
+use super::rpc::rpc_request;
+use super::{
+    chunk_payment_id, EthereumPayoutService, PaymentIdHash, PayoutCall, PayoutError, PayoutMode,
+    PayoutRecord, RetryAttempt,
+};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use tracing::error;
+
+/// Chunk indices tried when recovering which chunk of a split payout a record is
+const MAX_PROVEN_CHUNKS: u32 = 1024;
+
+/// Everything needed to check a payout's payment ID independently
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayoutProof {
+    pub destination: String,
+    pub sequence: u64,
+    pub hash: PaymentIdHash,
+    /// UTF-8 destination followed by the big-endian sequence
+    pub preimage: Vec<u8>,
+    /// Namespace the ID was derived in, `None` for unnamespaced IDs
+    pub namespace: Option<[u8; 32]>,
+    /// Index of the chunk if the payout was split
+    pub chunk: Option<u32>,
+    /// Payment ID as stored
+    pub payment_id: [u8; 32],
+    pub recipient: String,
+    pub asset_code: String,
+    pub amount: u64,
+    pub mode: PayoutMode,
+    pub memo: Option<Vec<u8>>,
+    /// Function a Treasury payout called, `None` for `payoutToUser`
+    pub call: Option<PayoutCall>,
+    /// Call data of the payout, `None` for modes sending none
+    pub calldata: Option<String>,
+    pub tx_hash: Option<String>,
+    /// Receipt as returned by `eth_getTransactionReceipt`, `None` if not mined
+    pub receipt: Option<Value>,
+    /// Differences between stored and recomputed values found on export
+    pub mismatches: Vec<ProofMismatch>,
+    /// Failed attempts before the payout was sent, or given up on
+    pub attempts: Vec<RetryAttempt>,
+}
+
+/// A value of a proof that does not match what its inputs give
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProofMismatch {
+    pub field: String,
+    pub expected: String,
+    pub found: String,
+}
+
+impl ProofMismatch {
+    fn to_json(&self) -> Value {
+        json!({"field": self.field, "expected": self.expected, "found": self.found})
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(ProofMismatch {
+            field: value["field"].as_str()?.to_string(),
+            expected: value["expected"].as_str()?.to_string(),
+            found: value["found"].as_str()?.to_string(),
+        })
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn from_hex(value: &Value) -> Option<Vec<u8>> {
+    hex::decode(value.as_str()?.trim_start_matches("0x")).ok()
+}
+
+fn word_from_hex(value: &Value) -> Option<[u8; 32]> {
+    let bytes = from_hex(value)?;
+    let mut word = [0u8; 32];
+    if bytes.len() != word.len() {
+        return None;
+    }
+    word.copy_from_slice(&bytes);
+    Some(word)
+}
+
+/// Bytes hashed for the payment ID of `destination` and `sequence`
+pub fn payment_id_preimage(destination: &str, sequence: u64) -> Vec<u8> {
+    let mut preimage = destination.as_bytes().to_vec();
+    preimage.extend_from_slice(&sequence.to_be_bytes());
+    preimage
+}
+
+impl PayoutProof {
+    pub fn to_json(&self) -> Value {
+        let mut proof = json!({
+            "destination": self.destination,
+            "sequence": self.sequence,
+            "hash": self.hash.as_str(),
+            "preimage": to_hex(&self.preimage),
+            "namespace": self.namespace.map(|namespace| to_hex(&namespace)),
+            "chunk": self.chunk,
+            "payment_id": to_hex(&self.payment_id),
+            "recipient": self.recipient,
+            "asset_code": self.asset_code,
+            "amount": self.amount,
+            "mode": self.mode.as_str(),
+            "memo": self.memo.as_deref().map(to_hex),
+            "calldata": self.calldata,
+            "tx_hash": self.tx_hash,
+            "receipt": self.receipt,
+            "mismatches": self.mismatches.iter().map(ProofMismatch::to_json).collect::<Vec<_>>(),
+        });
+        if let Some(call) = &self.call {
+            proof["call"] = json!({
+                "signature": call.signature(),
+                "arguments": call.arguments_spec(),
+            });
+        }
+        if !self.attempts.is_empty() {
+            proof["attempts"] = json!(self
+                .attempts
+                .iter()
+                .map(RetryAttempt::to_json)
+                .collect::<Vec<_>>());
+        }
+        proof
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        Some(PayoutProof {
+            destination: value["destination"].as_str()?.to_string(),
+            sequence: value["sequence"].as_u64()?,
+            hash: PaymentIdHash::parse(value["hash"].as_str()?)?,
+            preimage: from_hex(&value["preimage"])?,
+            namespace: match &value["namespace"] {
+                Value::Null => None,
+                namespace => Some(word_from_hex(namespace)?),
+            },
+            chunk: match &value["chunk"] {
+                Value::Null => None,
+                chunk => Some(u32::try_from(chunk.as_u64()?).ok()?),
+            },
+            payment_id: word_from_hex(&value["payment_id"])?,
+            recipient: value["recipient"].as_str()?.to_string(),
+            asset_code: value["asset_code"].as_str()?.to_string(),
+            amount: value["amount"].as_u64()?,
+            mode: PayoutMode::parse(value["mode"].as_str()?)?,
+            memo: match &value["memo"] {
+                Value::Null => None,
+                memo => Some(from_hex(memo)?),
+            },
+            call: match value.get("call") {
+                None | Some(Value::Null) => None,
+                Some(call) => Some(
+                    PayoutCall::parse(call["signature"].as_str()?, call["arguments"].as_str()?)
+                        .ok()?,
+                ),
+            },
+            calldata: value["calldata"].as_str().map(str::to_string),
+            tx_hash: value["tx_hash"].as_str().map(str::to_string),
+            receipt: value
+                .get("receipt")
+                .filter(|receipt| !receipt.is_null())
+                .cloned(),
+            mismatches: value["mismatches"]
+                .as_array()?
+                .iter()
+                .map(ProofMismatch::from_json)
+                .collect::<Option<_>>()?,
+            attempts: match value.get("attempts") {
+                None | Some(Value::Null) => Vec::new(),
+                Some(attempts) => attempts
+                    .as_array()?
+                    .iter()
+                    .map(RetryAttempt::from_json)
+                    .collect::<Option<_>>()?,
+            },
+        })
+    }
+}
+
+/// Payment ID the proof's preimage, namespace and chunk give
+fn recompute_payment_id(proof: &PayoutProof) -> [u8; 32] {
+    let mut payment_id = proof.hash.digest(&proof.preimage);
+    if let Some(namespace) = proof.namespace {
+        let mut data = namespace.to_vec();
+        data.extend_from_slice(&payment_id);
+        payment_id = proof.hash.digest(&data);
+    }
+    match proof.chunk {
+        Some(index) => chunk_payment_id(&payment_id, index),
+        None => payment_id,
+    }
+}
+
+/// Call data paying the proof's amount to its recipient under its payment ID
+fn recompute_calldata(proof: &PayoutProof) -> Option<String> {
+    match proof.mode {
+        PayoutMode::Treasury => match (&proof.call, &proof.memo) {
+            (Some(call), _) => call.calldata(&proof.payment_id, &proof.recipient, proof.amount),
+            (None, Some(memo)) => {
+                payout_memo_calldata(&proof.payment_id, &proof.recipient, proof.amount, memo)
+            }
+            (None, None) => payout_calldata(&proof.payment_id, &proof.recipient, proof.amount),
+        },
+        PayoutMode::DirectTransfer => transfer_calldata(&proof.recipient, proof.amount),
+        PayoutMode::Native | PayoutMode::Authorization => None,
+    }
+}
+
+/// Recompute what the proof's inputs determine, returning every value that
+/// differs from the proof's; empty if the proof holds
+pub fn verify_proof(proof: &PayoutProof) -> Vec<ProofMismatch> {
+    let mut mismatches = Vec::new();
+    let mut check = |field: &str, expected: String, found: String| {
+        if !expected.eq_ignore_ascii_case(&found) {
+            mismatches.push(ProofMismatch {
+                field: field.to_string(),
+                expected,
+                found,
+            });
+        }
+    };
+    check(
+        "preimage",
+        to_hex(&payment_id_preimage(&proof.destination, proof.sequence)),
+        to_hex(&proof.preimage),
+    );
+    check(
+        "payment_id",
+        to_hex(&recompute_payment_id(proof)),
+        to_hex(&proof.payment_id),
+    );
+    check(
+        "calldata",
+        recompute_calldata(proof).unwrap_or_default(),
+        proof.calldata.clone().unwrap_or_default(),
+    );
+    if let Some(receipt) = &proof.receipt {
+        check(
+            "receipt",
+            proof.tx_hash.clone().unwrap_or_default(),
+            receipt["transactionHash"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        );
+    }
+    mismatches
+}
+
+impl EthereumPayoutService {
+    /// Assemble and verify the proof of the stored payout `payment_id`,
+    /// fetching its receipt from the node.
+    ///
+    /// Mismatches between the stored and recomputed values are returned in
+    /// the proof's `mismatches` and alerted, as the record may have been
+    /// tampered with. Only payment IDs derived with a [`PaymentIdHash`] can be
+    /// recomputed, so payouts under other strategies' IDs have no proof.
+    pub async fn export_proof(&self, payment_id: &[u8; 32]) -> Result<PayoutProof, PayoutError> {
+        let record = self.store.get(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        let hash = match record.payment_id_strategy.as_deref() {
+            Some(label) => PaymentIdHash::parse(label).ok_or_else(|| {
+                PayoutError::Config(format!(
+                    "Payout {} has a payment ID from {}, which proofs cannot recompute",
+                    record.payment_id_hex(),
+                    label
+                ))
+            })?,
+            None => self.config.payment_id_hash,
+        };
+        let receipt = match &record.tx_hash {
+            Some(tx_hash) if tx_hash.starts_with("0x") => Some(
+                self.rpc(rpc_request("eth_getTransactionReceipt", json!([tx_hash])))
+                    .await?,
+            )
+            .filter(|receipt| !receipt.is_null()),
+            _ => None,
+        };
+        let mut proof = self.proof_of(&record, hash, receipt);
+        proof.mismatches = verify_proof(&proof);
+        if !proof.mismatches.is_empty() {
+            let fields: Vec<&str> = proof
+                .mismatches
+                .iter()
+                .map(|mismatch| mismatch.field.as_str())
+                .collect();
+            error!(
+                "ALERT: Stored payout {} does not match its inputs ({}); it may have been tampered with",
+                record.payment_id_hex(),
+                fields.join(", ")
+            );
+        }
+        Ok(proof)
+    }
+
+    fn proof_of(
+        &self,
+        record: &PayoutRecord,
+        hash: PaymentIdHash,
+        receipt: Option<Value>,
+    ) -> PayoutProof {
+        let mode = self
+            .config
+            .assets
+            .get(&record.asset_code)
+            .map_or(PayoutMode::Treasury, |asset| asset.mode);
+        let mut proof = PayoutProof {
+            destination: record.destination.clone(),
+            sequence: record.sequence,
+            hash,
+            preimage: payment_id_preimage(&record.destination, record.sequence),
+            namespace: record.namespace,
+            chunk: None,
+            payment_id: record.payment_id,
+            recipient: record.recipient.clone(),
+            asset_code: record.asset_code.clone(),
+            amount: record.amount,
+            mode,
+            memo: record.memo.clone(),
+            call: match mode {
+                PayoutMode::Treasury => self.config.payout_call.clone(),
+                _ => None,
+            },
+            calldata: None,
+            tx_hash: record.tx_hash.clone(),
+            receipt,
+            mismatches: Vec::new(),
+            attempts: record.attempts.clone(),
+        };
+        // A split payout's record is one of its chunks
+        let base = recompute_payment_id(&proof);
+        if base != record.payment_id {
+            proof.chunk = (0..MAX_PROVEN_CHUNKS)
+                .find(|index| chunk_payment_id(&base, *index) == record.payment_id);
+        }
+        proof.calldata = recompute_calldata(&proof);
+        proof
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        assert_golden, mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{AssetInfo, PayoutStatus};
+    use super::*;
+    use std::sync::Arc;
+
+    const TX_HASH: &str = "0x00000000000000000000000000000000000000000000000000000000000000ab";
+
+    fn node() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        // A full-length hash, as the golden proof records it
+        transport.on_result("eth_sendRawTransaction", json!(TX_HASH));
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({
+                "transactionHash": TX_HASH,
+                "blockNumber": "0x5",
+                "gasUsed": "0x5208",
+                "status": "0x1",
+            }),
+        );
+        transport
+    }
+
+    #[tokio::test]
+    async fn exported_proofs_verify_independently() {
+        let mut config = test_config();
+        config.payment_id_domain = Some("audit".to_string());
+        let service = test_service(config, node());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+
+        let proof = service.export_proof(&payment_id).await.unwrap();
+        assert_eq!(proof.mismatches, []);
+        assert!(proof.namespace.is_some());
+        assert_eq!(proof.chunk, None);
+        assert_golden("payout_proof", &proof.to_json());
+
+        let imported = PayoutProof::from_json(&proof.to_json()).unwrap();
+        assert_eq!(imported, proof);
+        assert_eq!(verify_proof(&imported), []);
+    }
+
+    #[tokio::test]
+    async fn proofs_carry_a_custom_payout_call() {
+        let mut config = test_config();
+        config.payout_call = Some(
+            PayoutCall::parse(
+                "payout(address,uint256,bytes32,uint16)",
+                "recipient,amount,payment_id,uint:7",
+            )
+            .unwrap(),
+        );
+        let transport = node();
+        let service = test_service(config, transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+
+        let proof = service.export_proof(&payment_id).await.unwrap();
+        assert_eq!(proof.mismatches, []);
+        let sent = &transport.sent_transactions()[0]["data"];
+        assert_eq!(
+            proof
+                .calldata
+                .as_ref()
+                .map(|calldata| calldata.to_ascii_lowercase()),
+            sent.as_str().map(str::to_string)
+        );
+        let imported = PayoutProof::from_json(&proof.to_json()).unwrap();
+        assert_eq!(imported, proof);
+        assert_eq!(verify_proof(&imported), []);
+    }
+
+    #[tokio::test]
+    async fn finds_the_chunk_of_split_payouts() {
+        let mut config = test_config();
+        config.assets.insert(AssetInfo {
+            max_payout_per_tx: Some(60),
+            ..AssetInfo::new("EURC", 6)
+        });
+        let service = test_service(config, node());
+        let _ = service.execute_payout(TEST_DESTINATION, 100, 1).await;
+        let base = service.config().payment_id(TEST_DESTINATION, 1);
+
+        let proof = service
+            .export_proof(&chunk_payment_id(&base, 1))
+            .await
+            .unwrap();
+        assert_eq!(proof.chunk, Some(1));
+        assert_eq!(proof.amount, 40);
+        assert_eq!(proof.mismatches, []);
+    }
+
+    #[tokio::test]
+    async fn flags_tampered_records_and_proofs() {
+        let service = test_service(test_config(), node());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+
+        // A proof edited to claim a larger payout no longer matches its call data
+        let mut proof = service.export_proof(&payment_id).await.unwrap();
+        proof.amount = 1_000_000;
+        let fields: Vec<String> = verify_proof(&proof)
+            .into_iter()
+            .map(|mismatch| mismatch.field)
+            .collect();
+        assert_eq!(fields, ["calldata"]);
+        proof.destination.push('x');
+        assert_eq!(verify_proof(&proof).len(), 2);
+
+        // A record whose sequence was rewritten in the store
+        let mut record = service.store().get(&payment_id).unwrap();
+        record.sequence = 2;
+        record.status = PayoutStatus::Confirmed;
+        service.store().save(record);
+        let proof = service.export_proof(&payment_id).await.unwrap();
+        let fields: Vec<&str> = proof
+            .mismatches
+            .iter()
+            .map(|mismatch| mismatch.field.as_str())
+            .collect();
+        assert_eq!(fields, ["payment_id"]);
+        assert_eq!(
+            proof.mismatches[0].found,
+            format!("0x{}", hex::encode(payment_id))
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! What the RPC provider supports beyond the basic methods
+//!
+//! Not every endpoint serves `eth_maxPriorityFeePerGas`, `eth_feeHistory` or
+//! JSON-RPC batches. At startup [`EthereumPayoutService::probe_provider`]
+//! makes a cheap call of each and records which answered, a "method not
+//! found" error (code -32601) or a batch refused as a whole meaning it is
+//! missing. Callers consult the result and fall back instead of failing:
+//! [`EthereumPayoutService::priority_fee`] from `eth_maxPriorityFeePerGas` to
+//! the median tip of `eth_feeHistory` to the whole gas price, and
+//! [`EthereumPayoutService::rpc_batch`] to sending each request on its own.
+//! A method found missing later, say behind a pool whose endpoints differ, is
+//! recorded then, so only the first call to it fails over.
+//!
+//! The result is in [`status`](super::status()), next to the Treasury's.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::throttle::endpoint_label;
+use super::{EthereumPayoutService, PayoutError};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+/// Blocks of tips `eth_feeHistory` is asked for
+const FEE_HISTORY_BLOCKS: u64 = 5;
+
+/// What the provider was found to support; `None` until probed or used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderCapabilities {
+    /// Host of the RPC endpoint, without credentials or path
+    pub endpoint: String,
+    pub max_priority_fee: Option<bool>,
+    pub fee_history: Option<bool>,
+    pub batch: Option<bool>,
+}
+
+impl ProviderCapabilities {
+    pub(super) fn unknown(rpc_url: &str) -> Self {
+        ProviderCapabilities {
+            endpoint: endpoint_label(rpc_url),
+            max_priority_fee: None,
+            fee_history: None,
+            batch: None,
+        }
+    }
+}
+
+/// Whether `err` is the provider not serving the method called
+pub fn is_unsupported_method(err: &PayoutError) -> bool {
+    match err {
+        PayoutError::Rpc { code, message, .. } => {
+            let message = message.to_ascii_lowercase();
+            *code == -32601
+                || (message.contains("method")
+                    && [
+                        "not found",
+                        "does not exist",
+                        "not supported",
+                        "not available",
+                    ]
+                    .iter()
+                    .any(|phrase| message.contains(phrase)))
+        }
+        _ => false,
+    }
+}
+
+/// Whether `err`, failing a batch as a whole, is the provider refusing batches
+pub(super) fn is_batch_refusal(err: &PayoutError) -> bool {
+    matches!(
+        err,
+        PayoutError::Rpc { .. } | PayoutError::InvalidResponse(_)
+    )
+}
+
+/// How the answer to a probe reads: any answer but "method not found" means
+/// the method is there, while transport failures tell nothing
+fn probe_result(result: &Result<Value, PayoutError>) -> Option<bool> {
+    match result {
+        Ok(_) => Some(true),
+        Err(err) if is_unsupported_method(err) => Some(false),
+        Err(PayoutError::Rpc { .. }) => Some(true),
+        Err(_) => None,
+    }
+}
+
+/// Median of the first reward percentile over the blocks `eth_feeHistory` returned
+fn median_tip(history: &Value) -> Option<u64> {
+    let mut tips = history["reward"]
+        .as_array()?
+        .iter()
+        .map(|block| parse_quantity(&block[0]).ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if tips.is_empty() {
+        return None;
+    }
+    tips.sort_unstable();
+    Some(tips[tips.len() / 2])
+}
+
+impl EthereumPayoutService {
+    /// Call each optional method once, recording which the provider serves
+    pub async fn probe_provider(&self) -> ProviderCapabilities {
+        let max_priority_fee = probe_result(
+            &self
+                .rpc(rpc_request("eth_maxPriorityFeePerGas", json!([])))
+                .await,
+        );
+        let fee_history = probe_result(
+            &self
+                .rpc(rpc_request("eth_feeHistory", json!(["0x1", "latest", []])))
+                .await,
+        );
+        let batch = match self
+            .send_batch(vec![rpc_request("eth_chainId", json!([]))])
+            .await
+        {
+            Ok(_) => Some(true),
+            Err(err) if is_batch_refusal(&err) => Some(false),
+            Err(_) => None,
+        };
+        let capabilities = {
+            let mut known = self.provider_capabilities.lock().unwrap();
+            known.max_priority_fee = max_priority_fee;
+            known.fee_history = fee_history;
+            known.batch = batch;
+            known.clone()
+        };
+        info!(
+            "RPC provider {} supports eth_maxPriorityFeePerGas {:?}, eth_feeHistory {:?}, batches {:?}",
+            capabilities.endpoint,
+            capabilities.max_priority_fee,
+            capabilities.fee_history,
+            capabilities.batch
+        );
+        capabilities
+    }
+
+    /// What is known of the provider so far
+    pub fn provider_capabilities(&self) -> ProviderCapabilities {
+        self.provider_capabilities.lock().unwrap().clone()
+    }
+
+    /// Tip per gas for a transaction paying fees by EIP-1559, in wei
+    pub async fn priority_fee(&self) -> Result<u64, PayoutError> {
+        if self.provider_capabilities().max_priority_fee != Some(false) {
+            match self
+                .rpc(rpc_request("eth_maxPriorityFeePerGas", json!([])))
+                .await
+            {
+                Ok(tip) => {
+                    self.note_provider(|known| known.max_priority_fee = Some(true));
+                    return parse_quantity(&tip);
+                }
+                Err(err) if is_unsupported_method(&err) => {
+                    warn!("RPC provider has no eth_maxPriorityFeePerGas, using eth_feeHistory");
+                    self.note_provider(|known| known.max_priority_fee = Some(false));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if self.provider_capabilities().fee_history != Some(false) {
+            let params = json!([format!("0x{:x}", FEE_HISTORY_BLOCKS), "latest", [50]]);
+            match self.rpc(rpc_request("eth_feeHistory", params)).await {
+                Ok(history) => {
+                    self.note_provider(|known| known.fee_history = Some(true));
+                    if let Some(tip) = median_tip(&history) {
+                        return Ok(tip);
+                    }
+                }
+                Err(err) if is_unsupported_method(&err) => {
+                    warn!("RPC provider has no eth_feeHistory, tipping the whole gas price");
+                    self.note_provider(|known| known.fee_history = Some(false));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.get_gas_price().await
+    }
+
+    pub(super) fn note_provider(&self, note: impl FnOnce(&mut ProviderCapabilities)) {
+        note(&mut self.provider_capabilities.lock().unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rpc::RpcTransport;
+    use super::super::testing::{test_config, test_service, MockTransport};
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::Arc;
+
+    /// Refuses batches as a whole, answering single requests through a mock
+    struct NoBatches(Arc<MockTransport>);
+
+    #[async_trait]
+    impl RpcTransport for NoBatches {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            match request {
+                Value::Array(_) => Ok(json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {"code": -32600, "message": "batch requests are not supported"},
+                })),
+                single => self.0.send(single).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fee_estimation_falls_back_to_what_the_provider_serves() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_gasPrice", json!("0x64"));
+        transport.on_result(
+            "eth_feeHistory",
+            json!({"reward": [["0x3"], ["0x1"], ["0x2"]], "baseFeePerGas": []}),
+        );
+        let service = test_service(test_config(), transport.clone());
+        let probed = service.probe_provider().await;
+        assert_eq!(probed.endpoint, endpoint_label(&service.config().rpc_url));
+        assert_eq!(
+            (probed.max_priority_fee, probed.fee_history, probed.batch),
+            (Some(false), Some(true), Some(true))
+        );
+        assert_eq!(service.priority_fee().await.unwrap(), 2);
+        // The missing method is not asked again
+        assert_eq!(transport.call_count("eth_maxPriorityFeePerGas"), 1);
+
+        // Neither fee method: the gas price, found missing while in use
+        let transport = MockTransport::new();
+        transport.on_result("eth_gasPrice", json!("0x64"));
+        let service = test_service(test_config(), transport.clone());
+        assert_eq!(service.priority_fee().await.unwrap(), 100);
+        assert_eq!(service.priority_fee().await.unwrap(), 100);
+        assert_eq!(transport.call_count("eth_feeHistory"), 1);
+        let known = service.provider_capabilities();
+        assert_eq!(
+            (known.max_priority_fee, known.fee_history),
+            (Some(false), Some(false))
+        );
+
+        transport.on_result("eth_maxPriorityFeePerGas", json!("0x7"));
+        let service = test_service(test_config(), transport);
+        assert_eq!(service.priority_fee().await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn batches_fall_back_to_single_requests() {
+        let transport = MockTransport::new();
+        transport.on_result("eth_chainId", json!("0x7a69"));
+        transport.on_result("eth_blockNumber", json!("0x10"));
+        let service = test_service(test_config(), Arc::new(NoBatches(transport.clone())));
+        let requests = || {
+            vec![
+                rpc_request("eth_chainId", json!([])),
+                rpc_request("eth_blockNumber", json!([])),
+            ]
+        };
+
+        // Found out by the first batch, which is then sent request by request
+        let answers = service.rpc_batch(requests()).await.unwrap();
+        assert_eq!(answers[0].as_ref().unwrap(), "0x7a69");
+        assert_eq!(answers[1].as_ref().unwrap(), "0x10");
+        assert_eq!(service.provider_capabilities().batch, Some(false));
+        let answers = service.rpc_batch(requests()).await.unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(transport.call_count("eth_blockNumber"), 2);
+
+        assert_eq!(service.probe_provider().await.batch, Some(false));
+    }
+}
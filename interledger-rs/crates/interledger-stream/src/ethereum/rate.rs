@@ -0,0 +1,502 @@
+//! Conversion from the ILP amount to the payout amount
+//!
+//! STREAM delivers amounts in the receiving account's asset, which need not be
+//! the asset paid out on-chain. When the two differ, a [`RateProvider`] quotes
+//! a rate that is applied with the configured [`RoundingMode`] and recorded
+//! with the payout.
+
+use super::{IlpAmount, PayoutError, Timestamp, TokenAmount};
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// A quoted rate between the base units of two assets.
+///
+/// One base unit of `from_asset` is worth `rate / 10^scale` base units of
+/// `to_asset`, so the rate already accounts for the decimals of both assets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExchangeRate {
+    pub from_asset: String,
+    pub to_asset: String,
+    pub rate: u128,
+    pub scale: u8,
+    /// When the rate was observed
+    pub timestamp: Timestamp,
+}
+
+/// How a converted amount between two base units is rounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// Towards zero, never paying more than the exact amount
+    Down,
+    /// Away from zero, never paying less than the exact amount
+    Up,
+    /// To the nearest unit, ties to the even one
+    #[default]
+    HalfEven,
+}
+
+impl RoundingMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RoundingMode::Down => "down",
+            RoundingMode::Up => "up",
+            RoundingMode::HalfEven => "half_even",
+        }
+    }
+
+    /// Parse `down`, `up` or `half_even`
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "down" => Some(RoundingMode::Down),
+            "up" => Some(RoundingMode::Up),
+            "half_even" => Some(RoundingMode::HalfEven),
+            _ => None,
+        }
+    }
+}
+
+impl ExchangeRate {
+    /// Convert `amount`, rounding half to even. `None` if the result overflows.
+    pub fn convert(&self, amount: u64) -> Option<u64> {
+        self.convert_rounded(amount, RoundingMode::HalfEven)
+            .map(|(converted, _)| converted)
+    }
+
+    /// Convert `amount` rounding by `mode`, along with the residue rounding
+    /// left, in `10^-scale` base units: the exact amount is
+    /// `converted + residue / 10^scale`. `None` if the result overflows.
+    pub fn convert_rounded(&self, amount: u64, mode: RoundingMode) -> Option<(u64, i128)> {
+        let divisor = 10u128.checked_pow(u32::from(self.scale))?;
+        let product = u128::from(amount).checked_mul(self.rate)?;
+        let (quotient, remainder) = (product / divisor, product % divisor);
+        let round_up = match mode {
+            RoundingMode::Down => false,
+            RoundingMode::Up => remainder > 0,
+            RoundingMode::HalfEven => {
+                let twice = remainder * 2;
+                twice > divisor || (twice == divisor && quotient % 2 == 1)
+            }
+        };
+        let converted = u64::try_from(quotient + round_up as u128).ok()?;
+        let residue = i128::try_from(remainder).ok()?
+            - if round_up {
+                i128::try_from(divisor).ok()?
+            } else {
+                0
+            };
+        Some((converted, residue))
+    }
+
+    /// Whole-unit rendering of the rate, e.g. "0.000512"
+    pub fn to_decimal_string(&self) -> String {
+        decimal_string(self.rate, self.scale)
+    }
+}
+
+/// The conversion applied to a payout, kept in its record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conversion {
+    /// Amount received over ILP, in the source asset's base units
+    pub source_amount: u64,
+    pub rate: ExchangeRate,
+    pub rounding: RoundingMode,
+    /// Converted amount once rounded, before any carried residue is added
+    pub rounded_amount: u64,
+    /// Exact converted amount less `rounded_amount`, in `10^-scale` base units
+    pub residue: i128,
+    /// Whole base units of earlier payouts' rounding residue added to this one
+    pub carried: i64,
+}
+
+impl Conversion {
+    /// Convert `source` at `rate`, `None` if the result overflows
+    pub fn new(source: IlpAmount, rate: ExchangeRate, rounding: RoundingMode) -> Option<Self> {
+        let (rounded, residue) = source.convert(&rate, rounding)?;
+        Some(Conversion {
+            source_amount: source.value(),
+            rate,
+            rounding,
+            rounded_amount: rounded.base_units,
+            residue,
+            carried: 0,
+        })
+    }
+
+    /// Amount paid out, including carried residue
+    pub fn amount(&self) -> TokenAmount {
+        let base_units = (i128::from(self.rounded_amount) + i128::from(self.carried)).max(0);
+        TokenAmount::new(self.rate.to_asset.clone(), base_units as u64)
+    }
+
+    /// Exact converted amount before rounding, e.g. "0.009"
+    pub fn unrounded_amount(&self) -> String {
+        decimal_string(
+            u128::from(self.source_amount) * self.rate.rate,
+            self.rate.scale,
+        )
+    }
+}
+
+/// `value / 10^scale` with all `scale` decimals
+fn decimal_string(value: u128, scale: u8) -> String {
+    let digits = format!("{:0>width$}", value, width = scale as usize + 1);
+    let (int, frac) = digits.split_at(digits.len() - scale as usize);
+    if frac.is_empty() {
+        int.to_string()
+    } else {
+        format!("{}.{}", int, frac)
+    }
+}
+
+/// Source of exchange rates between the ILP asset and payout assets
+#[async_trait]
+pub trait RateProvider: Send + Sync {
+    /// Current rate from `from_asset` to `to_asset`
+    async fn rate(&self, from_asset: &str, to_asset: &str) -> Result<ExchangeRate, PayoutError>;
+}
+
+/// Fixed rates, quoted as observed at the time of the request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StaticRateProvider {
+    rates: HashMap<(String, String), (u128, u8)>,
+}
+
+impl StaticRateProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rate(
+        mut self,
+        from_asset: impl Into<String>,
+        to_asset: impl Into<String>,
+        rate: u128,
+        scale: u8,
+    ) -> Self {
+        self.rates
+            .insert((from_asset.into(), to_asset.into()), (rate, scale));
+        self
+    }
+
+    /// Parse a comma-separated list of `FROM:TO:rate:scale`, e.g. "XRP:EURC:512:6"
+    pub fn parse(spec: &str) -> Option<Self> {
+        let mut provider = StaticRateProvider::new();
+        for entry in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut parts = entry.split(':');
+            let (from, to) = (parts.next()?, parts.next()?);
+            let rate = parts.next()?.parse().ok()?;
+            let scale = parts.next()?.parse().ok()?;
+            if parts.next().is_some() {
+                return None;
+            }
+            provider = provider.with_rate(from, to, rate, scale);
+        }
+        Some(provider)
+    }
+}
+
+#[async_trait]
+impl RateProvider for StaticRateProvider {
+    async fn rate(&self, from_asset: &str, to_asset: &str) -> Result<ExchangeRate, PayoutError> {
+        let (rate, scale) = self
+            .rates
+            .get(&(from_asset.to_string(), to_asset.to_string()))
+            .copied()
+            .ok_or_else(|| {
+                PayoutError::Config(format!("No rate from {} to {}", from_asset, to_asset))
+            })?;
+        Ok(ExchangeRate {
+            from_asset: from_asset.to_string(),
+            to_asset: to_asset.to_string(),
+            rate,
+            scale,
+            timestamp: Utc::now(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{EthereumDestination, EthereumPayoutService, PayoutOutcome};
+    use super::*;
+    use std::sync::Arc;
+
+    fn rate(rate: u128, scale: u8) -> ExchangeRate {
+        ExchangeRate {
+            from_asset: "XRP".to_string(),
+            to_asset: "EURC".to_string(),
+            rate,
+            scale,
+            timestamp: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn rounds_half_to_even() {
+        // 0.5 of a unit per unit
+        let half = rate(5, 1);
+        assert_eq!(half.convert(1), Some(0)); // 0.5 -> 0
+        assert_eq!(half.convert(3), Some(2)); // 1.5 -> 2
+        assert_eq!(half.convert(5), Some(2)); // 2.5 -> 2
+        assert_eq!(half.convert(7), Some(4)); // 3.5 -> 4
+
+        let third = rate(3333, 4);
+        assert_eq!(third.convert(1), Some(0)); // 0.3333
+        assert_eq!(third.convert(2), Some(1)); // 0.6666
+        assert_eq!(rate(15001, 4).convert(1), Some(2)); // 1.5001, just above half
+
+        assert_eq!(rate(1, 0).convert(u64::MAX), Some(u64::MAX));
+        assert_eq!(rate(2, 0).convert(u64::MAX), None);
+        assert_eq!(rate(1, 40).convert(1), None);
+    }
+
+    #[test]
+    fn rounds_by_mode() {
+        use RoundingMode::{Down, HalfEven, Up};
+        /// Amount, rate and scale, with the converted amount and residue per mode
+        type Case = (u64, u128, u8, [(RoundingMode, u64, i128); 3]);
+        let cases: &[Case] = &[
+            // 0.009 EURC from a scale-2 amount at 1:1
+            (9, 1, 3, [(Down, 0, 9), (Up, 1, -991), (HalfEven, 0, 9)]),
+            (15, 1, 1, [(Down, 1, 5), (Up, 2, -5), (HalfEven, 2, -5)]),
+            (25, 1, 1, [(Down, 2, 5), (Up, 3, -5), (HalfEven, 2, 5)]),
+            // 18 to 6 decimals: 0.000123456789
+            (
+                123_456_789,
+                1,
+                12,
+                [
+                    (Down, 0, 123_456_789),
+                    (Up, 1, -999_876_543_211),
+                    (HalfEven, 0, 123_456_789),
+                ],
+            ),
+            // 1.999999999999
+            (
+                1_999_999_999_999,
+                1,
+                12,
+                [(Down, 1, 999_999_999_999), (Up, 2, -1), (HalfEven, 2, -1)],
+            ),
+            // Exact conversions leave no residue, e.g. 6 to 18 decimals
+            (
+                1_234_567,
+                1_000_000_000_000,
+                0,
+                [(Down, 1_234_567_000_000_000_000, 0); 3],
+            ),
+            (
+                2_500_000,
+                512,
+                6,
+                [(Down, 1280, 0), (Up, 1280, 0), (HalfEven, 1280, 0)],
+            ),
+        ];
+        for (amount, quoted, scale, expected) in cases {
+            for (mode, converted, residue) in expected {
+                let result = rate(*quoted, *scale).convert_rounded(*amount, *mode);
+                assert_eq!(
+                    result,
+                    Some((*converted, *residue)),
+                    "{} at {}e-{} rounding {:?}",
+                    amount,
+                    quoted,
+                    scale,
+                    mode
+                );
+            }
+        }
+        assert_eq!(rate(2, 0).convert_rounded(u64::MAX, Down), None);
+    }
+
+    #[test]
+    fn records_unrounded_amount() {
+        let conversion = Conversion::new(IlpAmount::new(9), rate(1, 3), RoundingMode::Up).unwrap();
+        assert_eq!(conversion.unrounded_amount(), "0.009");
+        assert_eq!(conversion.rounded_amount, 1);
+        assert_eq!(conversion.amount().base_units, 1);
+    }
+
+    #[test]
+    fn renders_decimal_rate() {
+        assert_eq!(rate(512, 6).to_decimal_string(), "0.000512");
+        assert_eq!(rate(1_250, 3).to_decimal_string(), "1.250");
+        assert_eq!(rate(7, 0).to_decimal_string(), "7");
+    }
+
+    #[tokio::test]
+    async fn static_provider_quotes_configured_pairs() {
+        let provider = StaticRateProvider::parse("XRP:EURC:512:6, XRP:USDC:600:6").unwrap();
+        let quote = provider.rate("XRP", "EURC").await.unwrap();
+        assert_eq!((quote.rate, quote.scale), (512, 6));
+        assert!(provider.rate("EURC", "XRP").await.is_err());
+        assert!(StaticRateProvider::parse("XRP:EURC:512").is_none());
+    }
+
+    #[tokio::test]
+    async fn carries_rounding_residue_into_later_payouts() {
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.rounding = RoundingMode::Down;
+        config.carry_rounding_residue = true;
+        let service = test_service(config, transport.clone()).with_rate_provider(Arc::new(
+            StaticRateProvider::new().with_rate("XRP", "EURC", 4, 1),
+        ));
+        let eth_dest = EthereumDestination::parse(TEST_DESTINATION).unwrap();
+
+        // 0.4 rounds to nothing but is kept, 1.6 + 0.4 pays 2, and 2.8 pays 2 keeping 0.8
+        for sequence in 1..=3 {
+            service
+                .execute_payout_from("XRP", TEST_DESTINATION, sequence * 3 - 2, sequence)
+                .await
+                .unwrap();
+        }
+        let amounts: Vec<u64> = transport
+            .sent_transactions()
+            .iter()
+            .map(|call| {
+                let data = call["data"].as_str().unwrap();
+                u64::from_str_radix(&data[data.len() - 16..], 16).unwrap()
+            })
+            .collect();
+        assert_eq!(amounts, vec![2, 2]);
+        assert_eq!(service.rounding_residue(&eth_dest), 8 * 10i128.pow(17));
+
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 2);
+        let conversion = service
+            .store()
+            .get(&payment_id)
+            .unwrap()
+            .conversion
+            .unwrap();
+        assert_eq!(conversion.unrounded_amount(), "1.6");
+        assert_eq!((conversion.rounded_amount, conversion.carried), (1, 1));
+    }
+
+    /// Provider quoting a rate observed `age` ago
+    struct AgedRate(chrono::Duration);
+
+    #[async_trait]
+    impl RateProvider for AgedRate {
+        async fn rate(&self, from: &str, to: &str) -> Result<ExchangeRate, PayoutError> {
+            Ok(ExchangeRate {
+                from_asset: from.to_string(),
+                to_asset: to.to_string(),
+                rate: 5,
+                scale: 1,
+                timestamp: Utc::now() - self.0,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn converts_and_records_rate() {
+        let transport = mock_chain();
+        let service = test_service(test_config(), transport.clone()).with_rate_provider(Arc::new(
+            StaticRateProvider::new().with_rate("XRP", "EURC", 512, 6),
+        ));
+
+        service
+            .execute_payout_from("XRP", TEST_DESTINATION, 2_500_000, 1)
+            .await
+            .unwrap();
+        // 2_500_000 * 0.000512 = 1280
+        let data = transport.sent_transactions()[0]["data"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(data.ends_with(&format!("{:064x}", 1280)));
+
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.amount, 1280);
+        let conversion = record.conversion.unwrap();
+        assert_eq!(conversion.source_amount, 2_500_000);
+        assert_eq!(conversion.rate.to_decimal_string(), "0.000512");
+    }
+
+    #[tokio::test]
+    async fn amounts_rounding_to_zero_are_distinguished() {
+        let rates = Arc::new(StaticRateProvider::new().with_rate("XRP", "EURC", 512, 6));
+        let transport = mock_chain();
+        let service =
+            test_service(test_config(), transport.clone()).with_rate_provider(rates.clone());
+        // 900 * 0.000512 = 0.46, which rounds to zero
+        let outcome = service
+            .execute_payout_from("XRP", TEST_DESTINATION, 900, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            outcome,
+            PayoutOutcome::RoundedToZero {
+                source_asset: "XRP".to_string(),
+                source_amount: 900,
+            }
+        );
+
+        let mut config = test_config();
+        config.reject_zero_amounts = true;
+        let service = test_service(config, transport.clone()).with_rate_provider(rates);
+        let err = service
+            .execute_payout_from("XRP", TEST_DESTINATION, 900, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "900 XRP rounds to zero EURC; check the asset scales and rate"
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[tokio::test]
+    async fn same_asset_skips_the_provider() {
+        let transport = mock_chain();
+        let service = test_service(test_config(), transport.clone());
+        service
+            .execute_payout_from("EURC", TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!((record.amount, record.conversion), (100, None));
+
+        // Another asset without a provider cannot be paid out
+        assert!(matches!(
+            service
+                .execute_payout_from("XRP", TEST_DESTINATION, 100, 2)
+                .await,
+            Err(PayoutError::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn stale_rate_fails_the_payout() {
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.rate_max_age = std::time::Duration::from_secs(60);
+        let service = test_service(config.clone(), transport.clone())
+            .with_rate_provider(Arc::new(AgedRate(chrono::Duration::seconds(61))));
+        let err = service
+            .execute_payout_from("XRP", TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::StaleRate { age_secs: 61, .. }));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        let service = test_service(config, transport.clone())
+            .with_rate_provider(Arc::new(AgedRate(chrono::Duration::seconds(59))));
+        service
+            .execute_payout_from("XRP", TEST_DESTINATION, 5, 1)
+            .await
+            .unwrap();
+        // 5 * 0.5 = 2.5 rounds to 2
+        let data = transport.sent_transactions()[0]["data"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(data.ends_with(&format!("{:064x}", 2)));
+    }
+}
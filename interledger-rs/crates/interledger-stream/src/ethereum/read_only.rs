@@ -0,0 +1,275 @@
+//! Read-only mode while the operator's signer is unavailable
+//!
+//! A remote signer that cannot be reached, be it a KMS, Vault or, under
+//! `node_signing`, the node's account, fails every payout. Once a payout
+//! fails with `SignerUnavailable`, the service stops attempting payouts and
+//! defers them as while the Treasury is paused; previews and queries keep
+//! working. A background probe asks whoever signs transactions to sign a
+//! fixed digest every `signer_probe_interval` and, once it does, leaves the
+//! mode and drains the deferred payouts.
+
+use super::hash::keccak256;
+use super::rpc::rpc_request;
+use super::shutdown::unless_cancelled;
+use super::{
+    is_node_signer_error, EthereumPayoutService, PayoutError, PayoutOutcome, PayoutRequest,
+};
+use serde_json::json;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// Message signed to probe the signer
+const PROBE_MESSAGE: &[u8] = b"interledger payout signer probe";
+
+/// Whether `err` means the signer could not be reached
+pub fn is_signer_unavailable(err: &PayoutError) -> bool {
+    matches!(err, PayoutError::SignerUnavailable(_))
+}
+
+impl EthereumPayoutService {
+    /// Whether payouts are held because the signer is unavailable
+    pub fn is_read_only(&self) -> bool {
+        self.signer_unavailable.load(Ordering::SeqCst)
+    }
+
+    /// Enter read-only mode, alerting only on the transition
+    pub(super) fn mark_signer_unavailable(&self, err: &PayoutError) {
+        if !self.signer_unavailable.swap(true, Ordering::SeqCst) {
+            error!(
+                "ALERT: signer of operator {} is unavailable, holding payouts until it responds: {}",
+                self.operator_address(),
+                err
+            );
+        }
+    }
+
+    /// Defer the request if `result` failed for want of a signer
+    pub(super) fn hold_if_signer_unavailable(
+        &self,
+        request: &PayoutRequest,
+        result: Result<PayoutOutcome, PayoutError>,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        match result {
+            Err(err) if is_signer_unavailable(&err) => {
+                self.mark_signer_unavailable(&err);
+                Ok(self.defer(request.clone()))
+            }
+            result => result,
+        }
+    }
+
+    /// Have the signer sign the probe message, leaving read-only mode and
+    /// draining held payouts once it does. Returns whether it is available.
+    pub async fn check_signer(&self) -> bool {
+        match self.sign_digest(&keccak256(PROBE_MESSAGE)).await {
+            Ok(()) => {
+                if self.signer_unavailable.swap(false, Ordering::SeqCst) {
+                    warn!(
+                        "ALERT: signer of operator {} responds again; draining {} held payouts",
+                        self.operator_address(),
+                        self.deferred_count()
+                    );
+                    self.drain_deferred().await;
+                }
+                true
+            }
+            Err(err) if is_signer_unavailable(&err) => {
+                self.mark_signer_unavailable(&err);
+                false
+            }
+            Err(err) => {
+                warn!("Signer probe failed: {}", err);
+                !self.is_read_only()
+            }
+        }
+    }
+
+    /// Have whoever signs the operator's transactions sign `digest`: the
+    /// node's account under `node_signing`, unless a signer was injected,
+    /// and the operator's signer otherwise
+    pub(super) async fn sign_digest(&self, digest: &[u8; 32]) -> Result<(), PayoutError> {
+        match self.config.node_signing && self.signer.is_none() {
+            false => self.operator_signer()?.sign_hash(digest).await.map(drop),
+            true => self
+                .rpc(rpc_request(
+                    "eth_sign",
+                    json!([
+                        self.operator_address(),
+                        format!("0x{}", hex::encode(digest))
+                    ]),
+                ))
+                .await
+                .map(drop)
+                .map_err(|err| match err {
+                    PayoutError::Rpc { message, .. } if is_node_signer_error(&message) => {
+                        PayoutError::SignerUnavailable(message)
+                    }
+                    err => err,
+                }),
+        }
+    }
+
+    /// Spawn a task probing the signer on `signer_probe_interval` while the
+    /// service is read-only. The task stops on shutdown.
+    pub fn spawn_signer_probe(self: &Arc<Self>) -> JoinHandle<()> {
+        let interval = self.config.signer_probe_interval;
+        let service = Arc::clone(self);
+        let cancellation = self.cancellation.clone();
+        self.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            while unless_cancelled(&cancellation, ticker.tick())
+                .await
+                .is_some()
+            {
+                if service.is_read_only() {
+                    service.check_signer().await;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{LocalSigner, SafeConfig, Signature, Signer};
+    use super::*;
+    use async_trait::async_trait;
+    use mockito::mock;
+    use serde_json::Value;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    const SAFE: &str = "0x2222222222222222222222222222222222222222";
+
+    /// Signer that can be taken down and brought back
+    struct FlakySigner {
+        key: LocalSigner,
+        up: AtomicBool,
+    }
+
+    #[async_trait]
+    impl Signer for FlakySigner {
+        fn address(&self) -> String {
+            self.key.address()
+        }
+
+        async fn sign_hash(&self, hash: &[u8; 32]) -> Result<Signature, PayoutError> {
+            if !self.up.load(Ordering::SeqCst) {
+                return Err(PayoutError::SignerUnavailable(
+                    "connection refused".to_string(),
+                ));
+            }
+            self.key.sign_hash(hash)
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_payouts_until_the_signer_is_back() {
+        let path = format!("/api/v1/safes/{}/multisig-transactions/", SAFE);
+        let proposals = mock("POST", path.as_str())
+            .with_status(201)
+            .expect(2)
+            .create();
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 0)));
+        let mut config = test_config();
+        config.safe = Some(SafeConfig {
+            address: SAFE.to_string(),
+            service_url: mockito::server_url(),
+            threshold: 1_000,
+        });
+        config.signer_probe_interval = Duration::from_millis(10);
+        let signer = Arc::new(FlakySigner {
+            key: LocalSigner::from_hex(&hex::encode(keccak256(b"kms"))).unwrap(),
+            up: AtomicBool::new(false),
+        });
+        let service = Arc::new(test_service(config, transport.clone()).with_signer(signer.clone()));
+        let probe = service.spawn_signer_probe();
+
+        for sequence in 1..=2 {
+            assert_eq!(
+                service
+                    .execute_payout(TEST_DESTINATION, 5_000, sequence)
+                    .await
+                    .unwrap(),
+                PayoutOutcome::Deferred
+            );
+        }
+        assert!(service.is_read_only());
+        assert_eq!(service.deferred_count(), 2);
+        // Previews still answer while payouts are held
+        assert!(service
+            .preview_payout(TEST_DESTINATION, 5_000)
+            .await
+            .is_ok());
+
+        signer.up.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!service.is_read_only());
+        assert_eq!(service.deferred_count(), 0);
+        proposals.assert();
+        probe.abort();
+    }
+
+    /// Node whose account refuses to sign while `locked` is set
+    fn locking_node(locked: &Arc<AtomicBool>) -> Arc<MockTransport> {
+        let transport = mock_chain();
+        for method in ["eth_sendTransaction", "eth_sign"] {
+            let locked = locked.clone();
+            transport.on(method, move |_: &Value| {
+                if locked.load(Ordering::SeqCst) {
+                    Err(json!({"code": -32000, "message": "authentication needed: password or unlock"}))
+                } else {
+                    Ok(json!("0xabc"))
+                }
+            });
+        }
+        transport
+    }
+
+    #[tokio::test]
+    async fn a_locked_node_account_makes_the_service_read_only() {
+        let locked = Arc::new(AtomicBool::new(true));
+        let transport = locking_node(&locked);
+        let mut config = test_config();
+        config.node_signing = true;
+        let service = test_service(config, transport.clone());
+
+        assert_eq!(
+            service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .unwrap(),
+            PayoutOutcome::Deferred
+        );
+        assert!(service.is_read_only());
+        assert!(!service.check_signer().await);
+
+        locked.store(false, Ordering::SeqCst);
+        assert!(service.check_signer().await);
+        assert!(!service.is_read_only());
+        assert_eq!(service.deferred_count(), 0);
+        assert_eq!(transport.call_count("eth_sendTransaction"), 2);
+    }
+
+    #[tokio::test]
+    async fn locally_signing_services_probe_the_operator_key() {
+        let locked = Arc::new(AtomicBool::new(true));
+        let transport = locking_node(&locked);
+        let service = test_service(test_config(), transport.clone());
+
+        assert!(service.check_signer().await);
+        assert_eq!(transport.call_count("eth_sign"), 0);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert!(!service.is_read_only());
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+}
@@ -0,0 +1,235 @@
+//! Settlement of submitted payouts from their transaction receipts
+//!
+//! The submitted gas limit and price only bound the cost; the receipt tells
+//! what was paid. EIP-1559 receipts carry `effectiveGasPrice`, legacy ones may
+//! not, in which case the price the transaction was submitted with is used.
+//! On OP-stack chains the L1 data fee is added to the cost.
+
+use super::l2_fee::receipt_l1_fee;
+use super::rpc::{parse_quantity, rpc_request};
+use super::{
+    metrics, EthereumPayoutService, L2FeeModel, PayoutError, PayoutEvent, PayoutRecord,
+    PayoutStatus, Wei,
+};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+use tracing::{info, warn, Instrument};
+
+/// The parts of `eth_getTransactionReceipt` relevant to a payout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionReceipt {
+    pub block_number: u64,
+    pub gas_used: u64,
+    /// `None` on legacy chains that do not report it
+    pub effective_gas_price: Option<u128>,
+    pub success: bool,
+}
+
+impl TransactionReceipt {
+    pub fn parse(receipt: &Value) -> Result<Self, PayoutError> {
+        let effective_gas_price = match receipt.get("effectiveGasPrice") {
+            Some(price) if !price.is_null() => Some(u128::from(parse_quantity(price)?)),
+            _ => None,
+        };
+        // Pre-Byzantium receipts have a state root instead of a status
+        let success = match receipt.get("status") {
+            Some(status) if !status.is_null() => parse_quantity(status)? == 1,
+            _ => true,
+        };
+        Ok(TransactionReceipt {
+            block_number: parse_quantity(&receipt["blockNumber"])?,
+            gas_used: parse_quantity(&receipt["gasUsed"])?,
+            effective_gas_price,
+            success,
+        })
+    }
+}
+
+impl EthereumPayoutService {
+    /// Fetch the receipt of a submitted payout and record its outcome and cost.
+    ///
+    /// Returns the updated record, or `None` if the payout has no transaction
+    /// to look up or it has not been mined yet.
+    pub async fn refresh_receipt(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<Option<PayoutRecord>, PayoutError> {
+        let record = match self.store.get(payment_id) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let span = self.payout_span(
+            &record.destination,
+            record.sequence,
+            &record.payment_id_hex(),
+        );
+        self.settle_receipt(record).instrument(span).await
+    }
+
+    async fn settle_receipt(
+        &self,
+        mut record: PayoutRecord,
+    ) -> Result<Option<PayoutRecord>, PayoutError> {
+        let tx_hash = match &record.tx_hash {
+            Some(tx_hash) if tx_hash.starts_with("0x") => tx_hash.clone(),
+            _ => return Ok(None),
+        };
+        let raw = self
+            .rpc(rpc_request("eth_getTransactionReceipt", json!([tx_hash])))
+            .await?;
+        if raw.is_null() {
+            return Ok(None);
+        }
+        let receipt = TransactionReceipt::parse(&raw)?;
+
+        let price = receipt.effective_gas_price.or(record.gas_price);
+        if self.config.l2_fee_model == L2FeeModel::OpStack {
+            record.l1_fee = receipt_l1_fee(&raw)?.map(Wei::value).or(record.l1_fee);
+        }
+        let cost = price
+            .and_then(|price| Wei::for_gas(receipt.gas_used, Wei::new(price)))
+            .map(|gas| gas.saturating_add(Wei::new(record.l1_fee.unwrap_or_default())));
+        record.block_number = Some(receipt.block_number);
+        record.gas_used = Some(receipt.gas_used);
+        self.observe_gas_used(&record.asset_code, receipt.gas_used);
+        record.effective_gas_price = price;
+        record.gas_cost = cost.map(Wei::value);
+        record.status = if receipt.success {
+            PayoutStatus::Confirmed
+        } else {
+            PayoutStatus::Failed
+        };
+        if receipt.success {
+            self.check_delivery(&mut record, &raw["logs"]);
+        }
+        self.report_receipt(&mut record);
+
+        match cost {
+            Some(cost) => {
+                info!(
+                    "Payout {} mined in block {}: {} gas, {}",
+                    tx_hash, receipt.block_number, receipt.gas_used, cost
+                );
+                metrics::gas_cost(
+                    self.config.tenant.as_deref(),
+                    &record.asset_code,
+                    self.config.expected_chain_id,
+                    cost,
+                );
+                self.counters.gas_cost(
+                    &record.asset_code,
+                    u64::try_from(cost.value()).unwrap_or(u64::MAX),
+                );
+                self.attribute_gas_cost(&record);
+            }
+            None => warn!(
+                "Payout {} mined in block {} but its gas price is unknown",
+                tx_hash, receipt.block_number
+            ),
+        }
+        let event = if receipt.success {
+            PayoutEvent::Confirmed {
+                payment_id: record.payment_id_hex(),
+                block_number: receipt.block_number,
+            }
+        } else {
+            PayoutEvent::Failed {
+                payment_id: record.payment_id_hex(),
+                reason: format!("reverted in block {}", receipt.block_number),
+            }
+        };
+        self.publish_payout(&record.destination, record.sequence, event);
+        self.store.save(record.clone());
+        self.settle_split(&record.payment_id);
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, FakeChain, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Arc;
+
+    fn submitted() -> (EthereumPayoutService, Arc<FakeChain>, [u8; 32]) {
+        let chain = deployed_chain();
+        let service = test_service(test_config(), chain.clone());
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        (service, chain, payment_id)
+    }
+
+    #[tokio::test]
+    async fn eip1559_receipt_uses_effective_price() {
+        let (service, chain, payment_id) = submitted();
+        // Legacy transactions pay what they bid, so the chain cannot mine
+        // one below it; answer as a node would for a dynamic-fee one
+        chain.on("eth_getTransactionReceipt", |_| {
+            Ok(json!({
+                "blockNumber": "0x10",
+                "gasUsed": "0xc350",
+                "effectiveGasPrice": "0x2540be400",
+                "status": "0x1",
+                "type": "0x2"
+            }))
+        });
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().gas_price,
+            Some(1_000_000_000)
+        );
+
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!(record.block_number, Some(16));
+        assert_eq!(record.gas_used, Some(50_000));
+        assert_eq!(record.effective_gas_price, Some(10_000_000_000));
+        assert_eq!(record.gas_cost, Some(500_000_000_000_000));
+        assert_eq!(service.store().get(&payment_id), Some(record));
+    }
+
+    #[tokio::test]
+    async fn legacy_receipt_falls_back_to_submitted_price() {
+        let (service, chain, payment_id) = submitted();
+        chain.set_legacy_receipts(true);
+        chain.set_gas_used(21_000);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        chain.revert_payment(&payment_id);
+        chain.advance_block();
+
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Failed);
+        assert_eq!(record.effective_gas_price, Some(1_000_000_000));
+        assert_eq!(record.gas_cost, Some(21_000_000_000_000));
+    }
+
+    #[tokio::test]
+    async fn pending_and_unknown_payouts_are_left_alone() {
+        let (service, chain, payment_id) = submitted();
+        assert_eq!(service.refresh_receipt(&payment_id).await.unwrap(), None);
+        assert_eq!(chain.call_count("eth_getTransactionReceipt"), 0);
+
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(service.refresh_receipt(&payment_id).await.unwrap(), None);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().status,
+            PayoutStatus::Submitted
+        );
+
+        // Settled once mined
+        chain.advance_block();
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        assert_eq!(record.block_number, Some(1));
+    }
+}
@@ -0,0 +1,167 @@
+//! Rejection of recipients that would destroy the funds paid to them
+//!
+//! Addresses are compared as raw bytes, so a checksummed, lower- or
+//! upper-case rendering of a denied address is rejected alike.
+
+use super::{metrics, EthereumDestination, LogMask, PayoutError};
+use std::collections::HashSet;
+use tracing::warn;
+
+/// Well-known burn addresses tokens are sent to in order to destroy them
+const BURN_ADDRESSES: &[&str] = &[
+    "0x000000000000000000000000000000000000dEaD",
+    "0xdEAD000000000000000042069420694206942069",
+];
+
+/// Decode a 0x-prefixed address into its 20 bytes
+fn address_bytes(address: &str) -> Option<[u8; 20]> {
+    let bytes = hex::decode(address.strip_prefix("0x").unwrap_or(address)).ok()?;
+    let mut result = [0u8; 20];
+    if bytes.len() != result.len() {
+        return None;
+    }
+    result.copy_from_slice(&bytes);
+    Some(result)
+}
+
+/// Recipients payouts are never sent to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientDenyList {
+    denied: HashSet<[u8; 20]>,
+}
+
+impl Default for RecipientDenyList {
+    /// The built-in burn addresses
+    fn default() -> Self {
+        RecipientDenyList {
+            denied: BURN_ADDRESSES
+                .iter()
+                .map(|address| address_bytes(address).expect("valid burn address"))
+                .collect(),
+        }
+    }
+}
+
+impl RecipientDenyList {
+    /// Add addresses from a comma-separated list, failing on any invalid entry
+    pub fn extend_from_spec(&mut self, spec: &str) -> Option<()> {
+        for address in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            self.denied.insert(address_bytes(address)?);
+        }
+        Some(())
+    }
+
+    /// Why `recipient` must not be paid, if it must not
+    pub fn rejection(&self, recipient: &str) -> Option<&'static str> {
+        match address_bytes(recipient) {
+            None => Some("malformed address"),
+            Some(address) if address == [0u8; 20] => Some("zero address"),
+            Some(address) if self.denied.contains(&address) => Some("denied address"),
+            Some(_) => None,
+        }
+    }
+
+    /// Fail with `RecipientInvalid` if the destination's recipient is denied,
+    /// counting the refusal for `tenant` and logging it masked by `mask`
+    pub(super) fn check(
+        &self,
+        destination: &EthereumDestination,
+        tenant: Option<&str>,
+        mask: Option<&LogMask>,
+    ) -> Result<(), PayoutError> {
+        match self.rejection(&destination.recipient) {
+            Some(reason) => {
+                warn!(
+                    "Refusing payout of {} to {}: {}",
+                    destination.asset_code,
+                    mask.map_or_else(
+                        || destination.recipient.clone(),
+                        |mask| mask.mask_address(&destination.recipient)
+                    ),
+                    reason
+                );
+                metrics::recipient_rejected(tenant, &destination.asset_code, reason);
+                Err(PayoutError::RecipientInvalid {
+                    recipient: destination.recipient.clone(),
+                    reason,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport};
+    use super::*;
+
+    #[test]
+    fn rejects_zero_burn_and_denied_addresses() {
+        let mut deny_list = RecipientDenyList::default();
+        deny_list
+            .extend_from_spec("0x70997970C51812dc3A010C7d01b50e0d17dc79C8")
+            .unwrap();
+        for (recipient, reason) in [
+            ("0x0000000000000000000000000000000000000000", "zero address"),
+            (
+                "0x000000000000000000000000000000000000dEaD",
+                "denied address",
+            ),
+            (
+                "0x000000000000000000000000000000000000dead",
+                "denied address",
+            ),
+            (
+                "0x000000000000000000000000000000000000DEAD",
+                "denied address",
+            ),
+            (
+                "0xdead000000000000000042069420694206942069",
+                "denied address",
+            ),
+            (
+                "0x70997970c51812dc3a010c7d01b50e0d17dc79c8",
+                "denied address",
+            ),
+            (
+                "0x70997970C51812DC3A010C7D01B50E0D17DC79C8",
+                "denied address",
+            ),
+        ] {
+            assert_eq!(
+                deny_list.rejection(recipient),
+                Some(reason),
+                "{}",
+                recipient
+            );
+        }
+        assert_eq!(
+            deny_list.rejection("0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC"),
+            None
+        );
+        assert!(deny_list.extend_from_spec("0x1234").is_none());
+    }
+
+    #[tokio::test]
+    async fn payout_to_burn_address_is_refused() {
+        let transport = MockTransport::new();
+        let service = test_service(test_config(), transport.clone());
+        let err = service
+            .execute_payout(
+                "test.receiver.eth.31337.EURC.0x000000000000000000000000000000000000dead.abc",
+                100,
+                1,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::RecipientInvalid {
+                reason: "denied address",
+                ..
+            }
+        ));
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 0);
+    }
+}
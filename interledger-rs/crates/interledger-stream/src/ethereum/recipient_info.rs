@@ -0,0 +1,232 @@
+//! What the service knows about each recipient address
+//!
+//! Gas estimates, the gas fallback table and native transfers all depend on
+//! whether a recipient has code. Rather than each reading `eth_getCode`, they
+//! go through [`EthereumPayoutService::classify`], which reads it once per
+//! address and remembers the answer for `gas_fallbacks.code_ttl`, since a
+//! contract may later be deployed to an address that had none. Code that
+//! cannot be read is not remembered. Entries also note when the address was
+//! first seen and how many payouts to it were sent.
+//!
+//! Entries are kept per lowercase address in a
+//! [`RecipientStates`](super::recipient_state) cache and written through to
+//! the store on every change, so a persistent store keeps them across
+//! restarts.
+
+use super::recipient_state::SpilledState;
+use super::store::Timestamp;
+use super::{EthereumPayoutService, PayoutError, PayoutOutcome, PayoutRequest};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use tracing::warn;
+
+/// Whether an address runs code when paid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientKind {
+    /// Externally owned account, without code
+    Eoa,
+    Contract,
+    /// Its code could not be read
+    Unknown,
+}
+
+impl RecipientKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecipientKind::Eoa => "eoa",
+            RecipientKind::Contract => "contract",
+            RecipientKind::Unknown => "unknown",
+        }
+    }
+}
+
+/// What is known about one recipient
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecipientInfo {
+    /// Whether it had code when last read, and when that was
+    pub code: Option<(bool, Timestamp)>,
+    /// When it was first classified or paid
+    pub first_seen: Option<Timestamp>,
+    /// Payouts sent to it
+    pub payouts: u64,
+}
+
+fn timestamp(value: &Value) -> Option<Option<Timestamp>> {
+    match value {
+        Value::Null => Some(None),
+        value => DateTime::parse_from_rfc3339(value.as_str()?)
+            .ok()
+            .map(|at| Some(at.with_timezone(&Utc))),
+    }
+}
+
+impl SpilledState for RecipientInfo {
+    fn to_json(&self) -> Value {
+        json!({
+            "has_code": self.code.map(|(has_code, _)| has_code),
+            "code_read_at": self.code.map(|(_, read_at)| read_at.to_rfc3339()),
+            "first_seen": self.first_seen.map(|at| at.to_rfc3339()),
+            "payouts": self.payouts,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let code = match (&value["has_code"], timestamp(&value["code_read_at"])?) {
+            (Value::Bool(has_code), Some(read_at)) => Some((*has_code, read_at)),
+            (Value::Null, None) => None,
+            _ => return None,
+        };
+        Some(RecipientInfo {
+            code,
+            first_seen: timestamp(&value["first_seen"])?,
+            payouts: value["payouts"].as_u64()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == RecipientInfo::default()
+    }
+}
+
+impl EthereumPayoutService {
+    /// Whether `recipient` is a contract, reading its code unless a fresh
+    /// answer is cached
+    pub async fn classify(&self, recipient: &str) -> RecipientKind {
+        match self.recipient_is_contract(recipient).await {
+            Ok(true) => RecipientKind::Contract,
+            Ok(false) => RecipientKind::Eoa,
+            Err(err) => {
+                warn!(
+                    "Could not read the code of {}: {}",
+                    self.display_address(recipient),
+                    self.scrub_address(&err.to_string(), recipient)
+                );
+                RecipientKind::Unknown
+            }
+        }
+    }
+
+    /// What is known about `recipient`, without reading anything from the node
+    pub fn recipient_info(&self, recipient: &str) -> RecipientInfo {
+        self.recipients
+            .with(&*self.store, &recipient.to_lowercase(), |info| info.clone())
+    }
+
+    /// Whether `recipient` has code, from the cache while fresh
+    pub(super) async fn recipient_is_contract(&self, recipient: &str) -> Result<bool, PayoutError> {
+        let key = recipient.to_lowercase();
+        let now = self.clock.now();
+        let ttl = chrono::Duration::from_std(self.config.gas_fallbacks.code_ttl)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        let cached = self.recipients.with(&*self.store, &key, |info| info.code);
+        if let Some((has_code, read_at)) = cached {
+            if now - read_at < ttl {
+                return Ok(has_code);
+            }
+        }
+        let has_code = self.read_has_code(recipient).await?;
+        self.update_recipient(&key, |info| info.code = Some((has_code, now)));
+        Ok(has_code)
+    }
+
+    /// Count a payout sent to the request's recipient
+    pub(super) fn note_recipient_payout(
+        &self,
+        request: &PayoutRequest,
+        result: &Result<PayoutOutcome, PayoutError>,
+    ) {
+        if !matches!(
+            result,
+            Ok(PayoutOutcome::Submitted { .. }) | Ok(PayoutOutcome::Split { .. })
+        ) {
+            return;
+        }
+        if let Some(eth_dest) = self.config.parse_destination(&request.destination) {
+            let key = eth_dest.recipient.to_lowercase();
+            self.update_recipient(&key, |info| info.payouts += 1);
+        }
+    }
+
+    /// Change the entry of `key`, noting when it was first seen, and write it
+    /// through to the store
+    fn update_recipient(&self, key: &str, change: impl FnOnce(&mut RecipientInfo)) {
+        let now = self.clock.now();
+        self.recipients.with_written(&*self.store, key, |info| {
+            info.first_seen.get_or_insert(now);
+            change(info)
+        });
+    }
+
+    async fn read_has_code(&self, address: &str) -> Result<bool, PayoutError> {
+        let code = self
+            .read_at_block("eth_getCode", vec![json!(address)])
+            .await?;
+        Ok(!matches!(code.as_str(), None | Some("") | Some("0x")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::Clock;
+    use super::*;
+    use std::time::Duration;
+
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    /// Node whose gas estimates fail as under load, so payouts consult both
+    /// the estimate cache and the fallback table
+    fn overloaded() -> std::sync::Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on_error("eth_estimateGas", -32603, "internal error");
+        transport.on_result("eth_getCode", json!("0x"));
+        transport
+    }
+
+    #[tokio::test]
+    async fn code_is_read_once_per_recipient_until_it_expires() {
+        let transport = overloaded();
+        let clock = FakeClock::new();
+        let mut config = test_config();
+        config.gas_estimate_ttl = Some(Duration::from_secs(300));
+        let service = test_service(config, transport.clone()).with_clock(clock.clone());
+        for sequence in 1..=3 {
+            service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .unwrap();
+        }
+        assert_eq!(service.classify(RECIPIENT).await, RecipientKind::Eoa);
+        assert_eq!(
+            service.classify(&RECIPIENT.to_lowercase()).await,
+            RecipientKind::Eoa
+        );
+        assert_eq!(transport.call_count("eth_getCode"), 1);
+        let info = service.recipient_info(RECIPIENT);
+        assert_eq!(info.payouts, 3);
+        assert_eq!(info.first_seen, Some(clock.now()));
+        // Written through, so a persistent store would keep it
+        let stored = service
+            .store
+            .recipient_state("recipient_info", &RECIPIENT.to_lowercase())
+            .unwrap();
+        assert_eq!(RecipientInfo::from_json(&stored), Some(info));
+
+        // Deployed to, but not noticed until the cached answer expires
+        transport.on_result("eth_getCode", json!("0x6080604052"));
+        clock.advance(Duration::from_secs(599));
+        assert_eq!(service.classify(RECIPIENT).await, RecipientKind::Eoa);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(service.classify(RECIPIENT).await, RecipientKind::Contract);
+        assert_eq!(transport.call_count("eth_getCode"), 2);
+
+        // Unreadable code is not cached
+        transport.on_error("eth_getCode", -32603, "internal error");
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(service.classify(RECIPIENT).await, RecipientKind::Unknown);
+        assert_eq!(service.classify(RECIPIENT).await, RecipientKind::Unknown);
+        assert_eq!(transport.call_count("eth_getCode"), 4);
+    }
+}
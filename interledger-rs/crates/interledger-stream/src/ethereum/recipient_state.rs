@@ -0,0 +1,270 @@
+//! Per-recipient state bounded in memory and spilled to the store
+//!
+//! Dust balances, rounding residue, sequence counters and cached recipient
+//! code readings are kept per recipient or destination, so with millions of
+//! distinct recipients they would grow without bound. Each is held in a
+//! [`RecipientStates`] cache keeping at most `recipient_state_capacity`
+//! entries in memory. The least recently used entry beyond that is written
+//! to the store under its kind and key and dropped; the next access reloads
+//! it transparently. An entry in use is never evicted, so the cache may
+//! briefly hold more while every entry is busy.
+//!
+//! Entries are locked one by one: the cache's own lock is only held to find,
+//! load or evict an entry, and updates to different recipients do not wait
+//! for each other. Entries spilled to a persistent store survive a restart;
+//! those still in memory do not, as before. Stores that do not keep
+//! recipient state lose the entries evicted to them. Failure streaks are
+//! kept in the store already, and ordering lanes are dropped once idle.
+
+use super::PayoutStore;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Entries kept in memory per kind of state unless configured otherwise
+pub const DEFAULT_RECIPIENT_STATE_CAPACITY: usize = 100_000;
+
+/// State that can be written to and reloaded from the store
+pub(super) trait SpilledState: Default + Send {
+    fn to_json(&self) -> Value;
+    fn from_json(value: &Value) -> Option<Self>;
+    /// Whether the state is as if it was never set, so need not be stored
+    fn is_empty(&self) -> bool;
+}
+
+/// An entry's state, retired once taken so late holders reload it
+struct Entry<V> {
+    state: V,
+    retired: bool,
+}
+
+type Shared<V> = Arc<Mutex<Entry<V>>>;
+
+struct Slot<V> {
+    state: Shared<V>,
+    /// Tick of the last access, its key in `recency`
+    used: u64,
+    /// Whether the store holds a copy, which must be removed if it empties
+    stored: bool,
+}
+
+struct Slots<V> {
+    entries: HashMap<String, Slot<V>>,
+    /// Keys by last access, least recent first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// State of one kind per key, at most `capacity` entries of it in memory
+pub(super) struct RecipientStates<V> {
+    kind: &'static str,
+    capacity: usize,
+    slots: Mutex<Slots<V>>,
+}
+
+impl<V: SpilledState> RecipientStates<V> {
+    pub fn new(kind: &'static str, capacity: usize) -> Self {
+        RecipientStates {
+            kind,
+            capacity: capacity.max(1),
+            slots: Mutex::new(Slots {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+        }
+    }
+
+    /// Run `f` on the state of `key`, loading it from `store` if it was evicted
+    pub fn with<R>(&self, store: &dyn PayoutStore, key: &str, f: impl FnOnce(&mut V) -> R) -> R {
+        loop {
+            let entry = self.entry(store, key);
+            let mut entry = entry.lock().unwrap();
+            if !entry.retired {
+                return f(&mut entry.state);
+            }
+        }
+    }
+
+    /// Like [`Self::with`], then write the state to `store` right away
+    pub fn with_written<R>(
+        &self,
+        store: &dyn PayoutStore,
+        key: &str,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> R {
+        let result = self.with(store, key, |state| {
+            let result = f(state);
+            let written = Some(state.to_json()).filter(|_| !state.is_empty());
+            store.save_recipient_state(self.kind, key, written);
+            result
+        });
+        if let Some(slot) = self.slots.lock().unwrap().entries.get_mut(key) {
+            slot.stored = true;
+        }
+        result
+    }
+
+    /// Remove the state of `key`, from memory and the store
+    pub fn take(&self, store: &dyn PayoutStore, key: &str) -> V {
+        let state = self.entry(store, key);
+        let mut slots = self.slots.lock().unwrap();
+        if let Some(slot) = slots.entries.remove(key) {
+            slots.recency.remove(&slot.used);
+            if slot.stored {
+                store.save_recipient_state(self.kind, key, None);
+            }
+        }
+        drop(slots);
+        let mut entry = state.lock().unwrap();
+        entry.retired = true;
+        std::mem::take(&mut entry.state)
+    }
+
+    /// Keys with state in memory or in `store`, in order
+    pub fn keys(&self, store: &dyn PayoutStore) -> Vec<String> {
+        let mut keys: BTreeSet<String> =
+            store.recipient_state_keys(self.kind).into_iter().collect();
+        keys.extend(self.slots.lock().unwrap().entries.keys().cloned());
+        keys.into_iter().collect()
+    }
+
+    /// Number of entries in memory
+    #[cfg(test)]
+    pub fn resident(&self) -> usize {
+        self.slots.lock().unwrap().entries.len()
+    }
+
+    /// The entry of `key`, marked as the most recently used
+    fn entry(&self, store: &dyn PayoutStore, key: &str) -> Shared<V> {
+        let mut slots = self.slots.lock().unwrap();
+        slots.tick += 1;
+        let tick = slots.tick;
+        let state = match slots.entries.get_mut(key) {
+            Some(slot) => {
+                let used = std::mem::replace(&mut slot.used, tick);
+                let state = slot.state.clone();
+                slots.recency.remove(&used);
+                state
+            }
+            None => {
+                let stored = store
+                    .recipient_state(self.kind, key)
+                    .and_then(|value| V::from_json(&value));
+                let slot = Slot {
+                    stored: stored.is_some(),
+                    state: Arc::new(Mutex::new(Entry {
+                        state: stored.unwrap_or_default(),
+                        retired: false,
+                    })),
+                    used: tick,
+                };
+                let state = slot.state.clone();
+                slots.entries.insert(key.to_string(), slot);
+                state
+            }
+        };
+        slots.recency.insert(tick, key.to_string());
+        self.evict(&mut slots, store);
+        state
+    }
+
+    /// Spill the least recently used entries not in use until at most
+    /// `capacity` are left
+    fn evict(&self, slots: &mut Slots<V>, store: &dyn PayoutStore) {
+        let mut excess = slots.entries.len().saturating_sub(self.capacity);
+        let mut idle = Vec::new();
+        for (used, key) in &slots.recency {
+            if excess == 0 {
+                break;
+            }
+            // Only the cache holds an idle entry; `entry` clones it under this lock
+            if Arc::strong_count(&slots.entries[key].state) == 1 {
+                idle.push((*used, key.clone()));
+                excess -= 1;
+            }
+        }
+        for (used, key) in idle {
+            slots.recency.remove(&used);
+            let slot = slots
+                .entries
+                .remove(&key)
+                .expect("recency only names entries");
+            let entry = slot.state.lock().unwrap();
+            let state = &entry.state;
+            if !state.is_empty() {
+                store.save_recipient_state(self.kind, &key, Some(state.to_json()));
+            } else if slot.stored {
+                store.save_recipient_state(self.kind, &key, None);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::InMemoryPayoutStore;
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Counter(u64);
+
+    impl SpilledState for Counter {
+        fn to_json(&self) -> Value {
+            json!(self.0)
+        }
+
+        fn from_json(value: &Value) -> Option<Self> {
+            value.as_u64().map(Counter)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.0 == 0
+        }
+    }
+
+    #[test]
+    fn evicted_entries_reload_from_the_store() {
+        let store = InMemoryPayoutStore::new();
+        let states = RecipientStates::<Counter>::new("counter", 2);
+        for key in ["a", "b", "c"] {
+            states.with(&store, key, |counter| counter.0 += 1);
+        }
+        assert_eq!(states.resident(), 2);
+        assert_eq!(store.recipient_state("counter", "a"), Some(json!(1)));
+
+        // Reloading "a" evicts "b", now the least recently used
+        states.with(&store, "a", |counter| counter.0 += 1);
+        assert_eq!(store.recipient_state("counter", "b"), Some(json!(1)));
+        assert_eq!(states.with(&store, "a", |counter| counter.0), 2);
+        assert_eq!(states.keys(&store), ["a", "b", "c"]);
+
+        assert_eq!(states.take(&store, "b"), Counter(1));
+        assert_eq!(store.recipient_state("counter", "b"), None);
+        assert_eq!(states.keys(&store), ["a", "c"]);
+    }
+
+    #[test]
+    fn entries_in_use_are_not_evicted() {
+        let store = Arc::new(InMemoryPayoutStore::new());
+        let states = Arc::new(RecipientStates::<Counter>::new("counter", 1));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (states, store) = (states.clone(), store.clone());
+                std::thread::spawn(move || {
+                    let key = if i % 2 == 0 { "even" } else { "odd" };
+                    for _ in 0..100 {
+                        states.with(&*store, key, |counter| counter.0 += 1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // Every increment landed despite the entries swapping in and out
+        assert_eq!(states.with(&*store, "even", |counter| counter.0), 400);
+        assert_eq!(states.with(&*store, "odd", |counter| counter.0), 400);
+    }
+}
@@ -0,0 +1,437 @@
+//! How destinations are shown outside the store
+//!
+//! A destination ends with its STREAM token, which may be sensitive, while
+//! the rest of it is what debugging a payout needs. Logs, the payout span
+//! and published events show destinations as [`DestinationPolicy`] says,
+//! by default with the token cut to its first 6 characters and its length,
+//! and error messages they carry have the destination replaced the same
+//! way. The store keeps the full destination, which recomputing payment IDs
+//! and proofs needs, and CSV exports and statements show recipients only.
+//!
+//! With a [`LogMask`] configured, recipient addresses are also replaced in
+//! logs and events, by a short HMAC-SHA256 of the lowercase address under a
+//! per-deployment key, e.g. `addr:6f1c0a9e2b3d4c5e`. The same address always
+//! masks the same way, so its payouts can still be followed, but only someone
+//! holding the key can tell which address it stands for, by masking
+//! candidates with [`LogMask::unmask`]. With `destinations` set the whole
+//! destination is masked instead, as `dest:` and its HMAC. Records in the
+//! store keep the real values.
+
+use super::destination::is_hex_address;
+use super::hash::{hmac_sha256, sha256};
+use super::{EthereumPayoutService, PayoutEvent};
+use std::fmt;
+
+/// Characters of the stream token kept by [`DestinationPolicy::Truncated`]
+pub const TRUNCATED_TOKEN_CHARS: usize = 6;
+
+/// How a destination's stream token is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DestinationPolicy {
+    /// As it is
+    Full,
+    /// Its first 6 characters and its length, e.g. `abc123...(22)`
+    #[default]
+    Truncated,
+    /// The first 8 bytes of its SHA-256, e.g. `sha256:8d969eef6ecad3c2`
+    Hashed,
+}
+
+impl DestinationPolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "full" => Some(DestinationPolicy::Full),
+            "truncated" => Some(DestinationPolicy::Truncated),
+            "hashed" => Some(DestinationPolicy::Hashed),
+            _ => None,
+        }
+    }
+
+    /// `destination` with its last segment, the stream token, shown as the
+    /// policy says
+    pub fn render(self, destination: &str) -> String {
+        let (address, token) = match destination.rsplit_once('.') {
+            Some(split) => split,
+            None => return destination.to_string(),
+        };
+        let token = match self {
+            DestinationPolicy::Full => return destination.to_string(),
+            DestinationPolicy::Truncated => {
+                // Short tokens are not shown at all, rather than in full
+                let chars = token.chars().count();
+                let kept: String = if chars > TRUNCATED_TOKEN_CHARS {
+                    token.chars().take(TRUNCATED_TOKEN_CHARS).collect()
+                } else {
+                    String::new()
+                };
+                format!("{}...({})", kept, chars)
+            }
+            DestinationPolicy::Hashed => {
+                format!("sha256:{}", hex::encode(&sha256(token.as_bytes())[..8]))
+            }
+        };
+        format!("{}.{}", address, token)
+    }
+
+    /// `text` with every occurrence of `destination` rendered
+    pub fn scrub(self, text: &str, destination: &str) -> String {
+        if self == DestinationPolicy::Full || destination.is_empty() {
+            return text.to_string();
+        }
+        text.replace(destination, &self.render(destination))
+    }
+}
+
+/// Bytes of the HMAC kept in a masked value
+const MASK_BYTES: usize = 8;
+
+/// Keyed masking of recipients in logs and events, off unless configured
+#[derive(Clone, PartialEq, Eq)]
+pub struct LogMask {
+    key: Vec<u8>,
+    /// Whether whole destinations are masked rather than only their recipient
+    pub destinations: bool,
+}
+
+impl fmt::Debug for LogMask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LogMask")
+            .field("key", &"<redacted>")
+            .field("destinations", &self.destinations)
+            .finish()
+    }
+}
+
+impl LogMask {
+    pub fn new(key: impl Into<Vec<u8>>) -> Self {
+        LogMask {
+            key: key.into(),
+            destinations: false,
+        }
+    }
+
+    /// Mask whole destinations, including their asset and stream token
+    pub fn with_destinations(mut self) -> Self {
+        self.destinations = true;
+        self
+    }
+
+    fn hmac(&self, prefix: &str, value: &str) -> String {
+        let mac = hmac_sha256(&self.key, value.as_bytes());
+        format!("{}:{}", prefix, hex::encode(&mac[..MASK_BYTES]))
+    }
+
+    /// `address` masked, ignoring its case
+    pub fn mask_address(&self, address: &str) -> String {
+        self.hmac("addr", &address.to_ascii_lowercase())
+    }
+
+    /// `destination` masked as a whole
+    pub fn mask_destination(&self, destination: &str) -> String {
+        self.hmac("dest", destination)
+    }
+
+    /// The candidate address or destination that `masked` stands for
+    pub fn unmask<'a>(
+        &self,
+        masked: &str,
+        candidates: impl IntoIterator<Item = &'a str>,
+    ) -> Option<&'a str> {
+        candidates.into_iter().find(|candidate| {
+            self.mask_address(candidate) == masked || self.mask_destination(candidate) == masked
+        })
+    }
+
+    /// `text` with `destination` masked, or its recipient wherever it
+    /// appears in any case
+    pub fn scrub(&self, text: &str, destination: &str) -> String {
+        if self.destinations {
+            if destination.is_empty() {
+                return text.to_string();
+            }
+            return text.replace(destination, &self.mask_destination(destination));
+        }
+        match recipient_of(destination) {
+            Some(recipient) => self.scrub_address(text, recipient),
+            None => text.to_string(),
+        }
+    }
+
+    /// `text` with every occurrence of `address`, in any case, masked
+    pub fn scrub_address(&self, text: &str, address: &str) -> String {
+        let needle = address.to_ascii_lowercase();
+        if needle.is_empty() {
+            return text.to_string();
+        }
+        // Lowercasing ASCII keeps byte offsets, so matches index `text` too
+        let haystack = text.to_ascii_lowercase();
+        let masked = self.mask_address(address);
+        let mut scrubbed = String::with_capacity(text.len());
+        let mut rest = 0;
+        for (start, _) in haystack.match_indices(&needle) {
+            scrubbed.push_str(&text[rest..start]);
+            scrubbed.push_str(&masked);
+            rest = start + needle.len();
+        }
+        scrubbed.push_str(&text[rest..]);
+        scrubbed
+    }
+}
+
+/// Recipient segment of a destination, the hex address before its token
+fn recipient_of(destination: &str) -> Option<&str> {
+    destination
+        .rsplit('.')
+        .nth(1)
+        .filter(|segment| is_hex_address(segment))
+}
+
+/// How destinations and the text mentioning them are shown
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Display<'a> {
+    pub policy: DestinationPolicy,
+    pub mask: Option<&'a LogMask>,
+}
+
+impl Display<'_> {
+    pub fn render(&self, destination: &str) -> String {
+        match self.mask {
+            Some(mask) if mask.destinations => mask.mask_destination(destination),
+            Some(mask) => mask.scrub(&self.policy.render(destination), destination),
+            None => self.policy.render(destination),
+        }
+    }
+
+    pub fn scrub(&self, text: &str, destination: &str) -> String {
+        match self.mask {
+            // Masked first, since the policy would render the destination
+            Some(mask) if mask.destinations => mask.scrub(text, destination),
+            Some(mask) => mask.scrub(&self.policy.scrub(text, destination), destination),
+            None => self.policy.scrub(text, destination),
+        }
+    }
+}
+
+impl PayoutEvent {
+    /// The event with `destination` rendered wherever its messages mention it
+    pub(super) fn scrubbed(self, display: Display<'_>, destination: &str) -> Self {
+        let scrub = |text: String| display.scrub(&text, destination);
+        match self {
+            PayoutEvent::Failed { payment_id, reason } => PayoutEvent::Failed {
+                payment_id,
+                reason: scrub(reason),
+            },
+            PayoutEvent::Skipped { payment_id, reason } => PayoutEvent::Skipped {
+                payment_id,
+                reason: scrub(reason),
+            },
+            PayoutEvent::AttemptFailed {
+                payment_id,
+                mut attempt,
+            } => {
+                attempt.error = scrub(attempt.error);
+                PayoutEvent::AttemptFailed {
+                    payment_id,
+                    attempt,
+                }
+            }
+            event => event,
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// `destination` as logs and events show it
+    pub fn display_destination(&self, destination: &str) -> String {
+        self.display().render(destination)
+    }
+
+    /// `address` as logs and events show it, masked if configured
+    pub fn display_address(&self, address: &str) -> String {
+        match &self.config.log_mask {
+            Some(mask) => mask.mask_address(address),
+            None => address.to_string(),
+        }
+    }
+
+    /// `text` with `address` masked, if configured
+    pub(super) fn scrub_address(&self, text: &str, address: &str) -> String {
+        match &self.config.log_mask {
+            Some(mask) => mask.scrub_address(text, address),
+            None => text.to_string(),
+        }
+    }
+
+    /// `text` mentioning `destination`, as logs and events show it
+    pub(super) fn scrub_destination(&self, text: &str, destination: &str) -> String {
+        self.display().scrub(text, destination)
+    }
+
+    pub(super) fn display(&self) -> Display<'_> {
+        Display {
+            policy: self.config.destination_policy,
+            mask: self.config.log_mask.as_ref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+    use super::super::{Dispatched, PayoutRequest};
+    use super::*;
+    use std::sync::Arc;
+
+    const TOKEN: &str = "3q2-7_Vz9KLmQx4bT8wYcA";
+
+    fn destination() -> String {
+        format!(
+            "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.{}",
+            TOKEN
+        )
+    }
+
+    #[test]
+    fn renders_the_token_per_policy() {
+        let address = "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+        assert_eq!(
+            DestinationPolicy::Full.render(&destination()),
+            destination()
+        );
+        assert_eq!(
+            DestinationPolicy::Truncated.render(&destination()),
+            format!("{}.3q2-7_...(22)", address)
+        );
+        assert_eq!(
+            DestinationPolicy::Hashed.render(&destination()),
+            format!(
+                "{}.sha256:{}",
+                address,
+                hex::encode(&sha256(TOKEN.as_bytes())[..8])
+            )
+        );
+        assert_eq!(
+            DestinationPolicy::Truncated.render("test.receiver.abc"),
+            "test.receiver....(3)"
+        );
+        assert_eq!(DestinationPolicy::Hashed.render("local"), "local");
+        assert_eq!(
+            DestinationPolicy::Truncated.scrub(
+                &format!(
+                    "Invalid destination: {} (twice: {})",
+                    destination(),
+                    destination()
+                ),
+                &destination()
+            ),
+            format!(
+                "Invalid destination: {0}.3q2-7_...(22) (twice: {0}.3q2-7_...(22))",
+                address
+            )
+        );
+        assert_eq!(
+            DestinationPolicy::parse("hashed"),
+            Some(DestinationPolicy::Hashed)
+        );
+        assert_eq!(DestinationPolicy::parse("masked"), None);
+    }
+
+    #[tokio::test]
+    async fn events_never_carry_the_full_token_by_default() {
+        let transport = mock_chain();
+        let service = Arc::new(test_service(test_config(), transport));
+        let mut events = service.subscribe();
+
+        service
+            .dispatch_payout(PayoutRequest::new(destination(), 100, 1))
+            .await;
+        service
+            .dispatch_payout(PayoutRequest::new(destination(), 0, 2))
+            .await;
+        // An invalid recipient fails with the destination in its message
+        let invalid = destination().replace("0x7099", "0xZZ99");
+        match service
+            .dispatch_payout(PayoutRequest::new(invalid.clone(), 100, 3))
+            .await
+        {
+            Dispatched::Completed(Err(err)) => assert!(err.to_string().contains(TOKEN)),
+            other => panic!("expected the destination to be refused, got {:?}", other),
+        }
+
+        let mut published = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            published.push(event.to_json().to_string());
+        }
+        assert!(published.len() >= 3, "{:?}", published);
+        assert!(published.iter().all(|event| !event.contains(TOKEN)));
+        assert!(published
+            .iter()
+            .all(|event| event.contains("3q2-7_...(22)")));
+
+        // The store still has the full destination
+        let payment_id = service.config().payment_id(&destination(), 1);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().destination,
+            destination()
+        );
+        assert_eq!(
+            service.display_destination(TEST_DESTINATION),
+            "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8....(6)"
+        );
+    }
+
+    #[tokio::test]
+    async fn masked_recipients_are_stable_and_unmasked_with_the_key() {
+        const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+        let mask = LogMask::new("deployment-key");
+        let masked = "addr:f89bbd0c08feaf92";
+        assert_eq!(mask.mask_address(RECIPIENT), masked);
+        assert_eq!(mask.mask_address(&RECIPIENT.to_lowercase()), masked);
+        assert_ne!(LogMask::new("other-key").mask_address(RECIPIENT), masked);
+        assert_eq!(
+            mask.scrub(
+                &format!(
+                    "Refused {}, i.e. {}",
+                    destination(),
+                    RECIPIENT.to_lowercase()
+                ),
+                &destination()
+            ),
+            format!(
+                "Refused test.receiver.eth.31337.EURC.{0}.{1}, i.e. {0}",
+                masked, TOKEN
+            )
+        );
+        let candidates = [TEST_DESTINATION, RECIPIENT];
+        assert_eq!(mask.unmask(masked, candidates), Some(RECIPIENT));
+        assert_eq!(mask.unmask("addr:0000000000000000", candidates), None);
+        let whole = mask.clone().with_destinations();
+        let masked_destination = whole.mask_destination(&destination());
+        assert_eq!(masked_destination, "dest:b5157f950f09c7c8");
+        assert_eq!(
+            whole.unmask(&masked_destination, [RECIPIENT, destination().as_str()]),
+            Some(destination().as_str())
+        );
+        assert!(!format!("{:?}", mask).contains("deployment-key"));
+
+        // Events mask the recipient under the policy's token rendering
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.log_mask = Some(mask);
+        let service = Arc::new(test_service(config, transport));
+        let mut events = service.subscribe();
+        service
+            .dispatch_payout(PayoutRequest::new(destination(), 100, 1))
+            .await;
+        let event = events.try_recv().unwrap().to_json().to_string();
+        assert!(!event
+            .to_lowercase()
+            .contains(&RECIPIENT[2..].to_lowercase()));
+        assert!(event.contains(&format!("EURC.{}.3q2-7_...(22)", masked)));
+        let payment_id = service.config().payment_id(&destination(), 1);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().recipient,
+            RECIPIENT
+        );
+    }
+}
@@ -0,0 +1,320 @@
+//! Nonce store kept in Redis, for senders of the operator key running in
+//! separate processes or on separate hosts
+//!
+//! Each address has three keys under the store's prefix: `next`, one past
+//! its highest confirmed nonce, and the `leases` and `expiries` hashes, the
+//! lease ID and expiry in milliseconds keyed by leased nonce. Every change
+//! runs as one Lua script, so processes reserving at the same time never
+//! lease the same nonce.
+
+use super::nonce::{NonceLease, NonceStore};
+use super::{PayoutError, Timestamp};
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::{Client, RedisError, Script};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// Key prefix used unless another is set with [`RedisNonceStore::with_prefix`]
+pub const DEFAULT_NONCE_KEY_PREFIX: &str = "ethereum-payout:nonces";
+
+const RESERVE: &str = r"
+local now = tonumber(ARGV[2])
+local expiries = redis.call('HGETALL', KEYS[3])
+for i = 1, #expiries, 2 do
+    if tonumber(expiries[i + 1]) <= now then
+        redis.call('HDEL', KEYS[2], expiries[i])
+        redis.call('HDEL', KEYS[3], expiries[i])
+    end
+end
+local nonce = math.max(tonumber(ARGV[1]), tonumber(redis.call('GET', KEYS[1]) or '0'))
+while redis.call('HEXISTS', KEYS[2], string.format('%d', nonce)) == 1 do
+    nonce = nonce + 1
+end
+local field = string.format('%d', nonce)
+redis.call('HSET', KEYS[2], field, ARGV[4])
+redis.call('HSET', KEYS[3], field, ARGV[3])
+return field
+";
+
+/// Drops the lease of nonce `ARGV[1]` if it is still `ARGV[2]`, raising
+/// `next` to `ARGV[3]` if given
+const TAKE: &str = r"
+if redis.call('HGET', KEYS[2], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+redis.call('HDEL', KEYS[2], ARGV[1])
+redis.call('HDEL', KEYS[3], ARGV[1])
+if ARGV[3] and tonumber(ARGV[3]) > tonumber(redis.call('GET', KEYS[1]) or '0') then
+    redis.call('SET', KEYS[1], ARGV[3])
+end
+return 1
+";
+
+/// Nonce store shared through a Redis server
+pub struct RedisNonceStore {
+    client: Client,
+    prefix: String,
+    /// Opened on first use and again after the connection is lost
+    connection: Mutex<Option<MultiplexedConnection>>,
+    reserve: Script,
+    take: Script,
+}
+
+impl RedisNonceStore {
+    /// Use the server at `url`, e.g. `redis://127.0.0.1:6379/0`. Nothing is
+    /// connected until the first lease.
+    pub fn open(url: &str) -> Result<Self, PayoutError> {
+        let client = Client::open(url)
+            .map_err(|err| PayoutError::Config(format!("Invalid nonce store URL: {}", err)))?;
+        Ok(RedisNonceStore {
+            client,
+            prefix: DEFAULT_NONCE_KEY_PREFIX.to_string(),
+            connection: Mutex::new(None),
+            reserve: Script::new(RESERVE),
+            take: Script::new(TAKE),
+        })
+    }
+
+    /// Keep the keys under `prefix`, e.g. to run several deployments on one server
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn keys(&self, address: &str) -> [String; 3] {
+        let address = address.to_ascii_lowercase();
+        ["next", "leases", "expiries"].map(|key| format!("{}:{}:{}", self.prefix, address, key))
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection, PayoutError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+        let opened = self
+            .client
+            .get_multiplexed_tokio_connection()
+            .await
+            .map_err(|err| PayoutError::NonceStore(err.to_string()))?;
+        *connection = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Convert `err`, dropping the connection if it no longer works
+    async fn failed(&self, err: RedisError) -> PayoutError {
+        if err.is_io_error() || err.is_connection_dropped() {
+            warn!("Lost the connection to the nonce store: {}", err);
+            self.connection.lock().await.take();
+        }
+        PayoutError::NonceStore(err.to_string())
+    }
+
+    async fn take_lease(&self, lease: &NonceLease, next: Option<u64>) -> Result<bool, PayoutError> {
+        let mut connection = self.connection().await?;
+        let mut invocation = self.take.prepare_invoke();
+        for key in self.keys(&lease.address) {
+            invocation.key(key);
+        }
+        invocation.arg(lease.nonce.to_string()).arg(&lease.id);
+        if let Some(next) = next {
+            invocation.arg(next.to_string());
+        }
+        match invocation.invoke_async::<_, i64>(&mut connection).await {
+            Ok(taken) => Ok(taken == 1),
+            Err(err) => Err(self.failed(err).await),
+        }
+    }
+}
+
+#[async_trait]
+impl NonceStore for RedisNonceStore {
+    async fn reserve(
+        &self,
+        address: &str,
+        chain_nonce: u64,
+        now: Timestamp,
+        expires_at: Timestamp,
+    ) -> Result<NonceLease, PayoutError> {
+        let mut connection = self.connection().await?;
+        let id = uuid::Uuid::new_v4().to_string();
+        let mut invocation = self.reserve.prepare_invoke();
+        for key in self.keys(address) {
+            invocation.key(key);
+        }
+        invocation
+            .arg(chain_nonce.to_string())
+            .arg(now.timestamp_millis())
+            .arg(expires_at.timestamp_millis())
+            .arg(&id);
+        let nonce = match invocation.invoke_async::<_, String>(&mut connection).await {
+            Ok(nonce) => nonce,
+            Err(err) => return Err(self.failed(err).await),
+        };
+        let nonce = nonce.parse().map_err(|_| {
+            PayoutError::NonceStore(format!("Nonce store leased an invalid nonce {}", nonce))
+        })?;
+        Ok(NonceLease {
+            address: address.to_string(),
+            nonce,
+            id,
+            expires_at,
+        })
+    }
+
+    async fn release(&self, lease: &NonceLease) -> Result<bool, PayoutError> {
+        self.take_lease(lease, None).await
+    }
+
+    async fn confirm(&self, lease: &NonceLease) -> Result<bool, PayoutError> {
+        self.take_lease(lease, Some(lease.nonce + 1)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, FakeClock, TEST_DESTINATION, TEST_OPERATOR,
+    };
+    use super::super::{EthereumPayoutConfig, EthereumPayoutService};
+    use super::*;
+    use chrono::Utc;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Server the ignored tests run against, e.g. `redis://127.0.0.1:6379/15`
+    const TEST_URL_ENV: &str = "NONCE_STORE_TEST_URL";
+
+    /// A store on the test server under a prefix no other test run uses
+    fn test_store() -> Arc<RedisNonceStore> {
+        let url = std::env::var(TEST_URL_ENV)
+            .unwrap_or_else(|_| panic!("{} must name a Redis server to use", TEST_URL_ENV));
+        let prefix = format!("nonce-store-test:{}", uuid::Uuid::new_v4());
+        Arc::new(RedisNonceStore::open(&url).unwrap().with_prefix(prefix))
+    }
+
+    fn sharing(store: &Arc<RedisNonceStore>, clock: &Arc<FakeClock>) -> EthereumPayoutService {
+        test_service(test_config(), deployed_chain())
+            .with_nonce_store(store.clone())
+            .with_clock(clock.clone())
+    }
+
+    #[test]
+    fn the_store_url_comes_from_the_environment() {
+        let vars = [
+            ("ETHEREUM_RPC_URL", "http://127.0.0.1:8545"),
+            ("TREASURY_ADDRESS", super::super::testing::TEST_TREASURY),
+            (
+                "OPERATOR_PRIVATE_KEY",
+                super::super::testing::TEST_OPERATOR_KEY,
+            ),
+            ("CHAIN_ID", "31337"),
+            ("NONCE_STORE_URL", "redis://nonces.internal:6379/2"),
+        ];
+        let config = EthereumPayoutConfig::from_vars(|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+        .unwrap();
+        assert_eq!(
+            config.nonce_store_url.as_deref(),
+            Some("redis://nonces.internal:6379/2")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        let mut config = test_config();
+        config.nonce_store_url = Some("not a url".to_string());
+        let err = EthereumPayoutService::new(config).err().unwrap();
+        assert!(matches!(err, PayoutError::Config(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn keys_are_per_address_under_the_prefix() {
+        let store = RedisNonceStore::open("redis://127.0.0.1/")
+            .unwrap()
+            .with_prefix("payouts");
+        assert_eq!(
+            store.keys("0xABC"),
+            [
+                "payouts:0xabc:next",
+                "payouts:0xabc:leases",
+                "payouts:0xabc:expiries"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn payouts_fail_before_signing_when_the_store_is_unreachable() {
+        let chain = deployed_chain();
+        let mut config = test_config();
+        // Nothing listens on the discard port
+        config.nonce_store_url = Some("redis://127.0.0.1:9/".to_string());
+        let service = test_service(config, chain.clone());
+
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::NonceStore(_)), "{:?}", err);
+        assert!(err.is_transient());
+        assert!(chain.sent_transactions().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server named by NONCE_STORE_TEST_URL"]
+    async fn services_never_share_a_nonce() {
+        let store = test_store();
+        let clock = FakeClock::new();
+        let payouts = sharing(&store, &clock);
+        let settlement = sharing(&store, &clock);
+
+        let first = payouts.reserve_nonce().await.unwrap();
+        let second = settlement.reserve_nonce().await.unwrap();
+        let third = payouts.reserve_nonce().await.unwrap();
+        assert_eq!((first.nonce, second.nonce, third.nonce), (0, 1, 2));
+
+        // A released nonce is handed out again, a confirmed one never
+        settlement.release_nonce(&second).await.unwrap();
+        payouts.confirm_nonce(&first).await.unwrap();
+        assert_eq!(settlement.reserve_nonce().await.unwrap().nonce, 1);
+        assert_eq!(settlement.reserve_nonce().await.unwrap().nonce, 3);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server named by NONCE_STORE_TEST_URL"]
+    async fn expired_leases_are_reclaimed() {
+        let store = test_store();
+        let clock = FakeClock::new();
+        let crashed = sharing(&store, &clock);
+        let survivor = sharing(&store, &clock);
+
+        let abandoned = crashed.reserve_nonce().await.unwrap();
+        assert_eq!(survivor.reserve_nonce().await.unwrap().nonce, 1);
+
+        clock.advance(survivor.config.nonce_lease_ttl + Duration::from_secs(1));
+        let reclaimed = survivor.reserve_nonce().await.unwrap();
+        assert_eq!(reclaimed.nonce, abandoned.nonce);
+        assert!(!store.confirm(&abandoned).await.unwrap());
+        assert!(store.confirm(&reclaimed).await.unwrap());
+        assert_eq!(survivor.reserve_nonce().await.unwrap().nonce, 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server named by NONCE_STORE_TEST_URL"]
+    async fn concurrent_reservations_take_distinct_nonces() {
+        let store = test_store();
+        let now = Utc::now();
+        let expires_at = now + chrono::Duration::minutes(1);
+        let leases = (0..20).map(|_| store.reserve(TEST_OPERATOR, 4, now, expires_at));
+        let mut nonces: Vec<_> = futures::future::join_all(leases)
+            .await
+            .into_iter()
+            .map(|lease| lease.unwrap().nonce)
+            .collect();
+        nonces.sort_unstable();
+        assert_eq!(nonces, (4..24).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,248 @@
+//! Offline replay of payout requests for incident forensics
+//!
+//! [`replay`] runs the pure part of executing a request against a config:
+//! destination parsing, the deny list, memo, zero amount and dust checks,
+//! payment ID derivation, splitting and call data construction. It needs no
+//! node, store or clock, so the same inputs and config always give the same
+//! results. Rules that depend on service state are not evaluated: the sequence
+//! window, pausing, daily limits, earlier approvals and exchange rates. A
+//! request in another asset than its payout asset is reported as refused for
+//! want of a rate.
+
+use super::dust::dust_minimum;
+use super::payload::{check_memo, plan_request};
+use super::{
+    EthereumPayoutConfig, IlpAmount, PayoutBlocker, PayoutError, PayoutPlan, PayoutRequest,
+};
+use serde_json::{json, Value};
+
+/// What a request would have done under the replayed config
+#[derive(Debug)]
+pub struct ReplayResult {
+    pub request: PayoutRequest,
+    /// Payment ID of the request, before splitting
+    pub payment_id: [u8; 32],
+    /// Amount that would have been paid or added to dust, in base units
+    pub amount: Option<u64>,
+    /// Transactions that would have been sent, each with its target and call data
+    pub plans: Vec<PayoutPlan>,
+    /// Rule that would have kept the request from being sent right away
+    pub blocked: Option<PayoutBlocker>,
+}
+
+impl ReplayResult {
+    pub fn to_json(&self) -> Value {
+        let blocked = self.blocked.as_ref().map(PayoutBlocker::to_json);
+        let calls: Vec<Value> = self
+            .plans
+            .iter()
+            .map(|plan| {
+                json!({
+                    "payment_id": format!("0x{}", hex::encode(plan.payment_id)),
+                    "to": plan.to,
+                    "data": plan.data,
+                    "value": plan.value,
+                    "amount": plan.amount,
+                })
+            })
+            .collect();
+        json!({
+            "destination": self.request.destination,
+            "sequence": self.request.sequence,
+            "payment_id": format!("0x{}", hex::encode(self.payment_id)),
+            "amount": self.amount,
+            "calls": calls,
+            "blocked": blocked,
+        })
+    }
+}
+
+/// Replay `inputs` against `at_config` without any I/O, one result per input
+pub fn replay(
+    inputs: impl Iterator<Item = PayoutRequest>,
+    at_config: &EthereumPayoutConfig,
+) -> Vec<ReplayResult> {
+    inputs
+        .map(|request| replay_one(request, at_config))
+        .collect()
+}
+
+fn replay_one(request: PayoutRequest, config: &EthereumPayoutConfig) -> ReplayResult {
+    let mut result = ReplayResult {
+        payment_id: config.payment_id_of(&request),
+        request,
+        amount: None,
+        plans: Vec::new(),
+        blocked: None,
+    };
+    match evaluate(&result.request, config) {
+        Ok((amount, plans, blocked)) => {
+            result.amount = amount;
+            result.plans = plans;
+            result.blocked = blocked;
+        }
+        Err(err) => result.blocked = Some(PayoutBlocker::Refused(err)),
+    }
+    result
+}
+
+type Evaluated = (Option<u64>, Vec<PayoutPlan>, Option<PayoutBlocker>);
+
+/// The same checks, in the same order, as the service's validation
+fn evaluate(
+    request: &PayoutRequest,
+    config: &EthereumPayoutConfig,
+) -> Result<Evaluated, PayoutError> {
+    let eth_dest = config
+        .parse_destination(&request.destination)
+        .ok_or_else(|| PayoutError::InvalidDestination(request.destination.clone()))?;
+    if let Some(reason) = config.denied_recipients.rejection(&eth_dest.recipient) {
+        return Err(PayoutError::RecipientInvalid {
+            recipient: eth_dest.recipient,
+            reason,
+        });
+    }
+    check_memo(config, request)?;
+    if request.amount == 0 {
+        if config.reject_zero_amounts {
+            return Err(PayoutError::ZeroAmount {
+                destination: request.destination.clone(),
+            });
+        }
+        return Ok((None, Vec::new(), Some(PayoutBlocker::NothingToPay)));
+    }
+    if let Some(source_asset) = &request.source_asset {
+        if *source_asset != eth_dest.asset_code {
+            return Err(PayoutError::Config(format!(
+                "Replay has no rate to convert {} to {}",
+                source_asset, eth_dest.asset_code
+            )));
+        }
+    }
+
+    let amount = IlpAmount::new(request.amount).in_asset(&eth_dest.asset_code);
+    if request.memo.is_none() {
+        if let Some(min_payout) = dust_minimum(config, &eth_dest, &amount) {
+            return Ok((
+                Some(amount.base_units),
+                Vec::new(),
+                Some(PayoutBlocker::BelowMinimum { min_payout }),
+            ));
+        }
+    }
+    let plans = plan_request(config, request, &amount, None)?;
+    let held = config
+        .assets
+        .get(&eth_dest.asset_code)
+        .and_then(|asset| asset.approval_threshold)
+        .is_some_and(|threshold| amount.base_units > threshold);
+    Ok((
+        Some(amount.base_units),
+        plans,
+        Some(PayoutBlocker::AwaitingApproval).filter(|_| held),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{assert_golden, test_config, TEST_DESTINATION, TEST_TREASURY};
+    use super::super::TokenAmount;
+    use super::*;
+
+    fn corpus() -> Vec<PayoutRequest> {
+        vec![
+            PayoutRequest::new(TEST_DESTINATION, 100, 1),
+            PayoutRequest::new(TEST_DESTINATION, 1_500, 2),
+            PayoutRequest::new(TEST_DESTINATION, 0, 3),
+            PayoutRequest::new(TEST_DESTINATION, 5, 4),
+            PayoutRequest::new(TEST_DESTINATION, 5, 5).with_memo(b"inv-5".to_vec()),
+            PayoutRequest::new("example.eth.USDC.0x123", 100, 6),
+            PayoutRequest::new(
+                "test.receiver.eth.31337.EURC.0x0000000000000000000000000000000000000000.abc123",
+                100,
+                7,
+            ),
+            PayoutRequest::new(TEST_DESTINATION, 100, 8).with_source_asset("XRP"),
+            PayoutRequest::new(TEST_DESTINATION, 100, 9).with_memo(vec![0; 1024]),
+        ]
+    }
+
+    fn replay_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.assets.apply_caps("EURC:1000").unwrap();
+        config.assets.apply_min_payouts("EURC:10").unwrap();
+        config
+            .assets
+            .apply_approval_thresholds("EURC:1000")
+            .unwrap();
+        config
+    }
+
+    fn rule(result: &ReplayResult) -> Value {
+        result.to_json()["blocked"]["rule"].clone()
+    }
+
+    #[test]
+    fn replays_a_mixed_corpus() {
+        let config = replay_config();
+        let results = replay(corpus().into_iter(), &config);
+        assert_eq!(results.len(), 9);
+        let rules: Vec<Value> = results.iter().map(rule).collect();
+        assert_eq!(
+            rules,
+            [
+                Value::Null,
+                json!("awaiting_approval"),
+                json!("nothing_to_pay"),
+                json!("below_minimum"),
+                Value::Null,
+                json!("refused"),
+                json!("refused"),
+                json!("refused"),
+                json!("refused"),
+            ]
+        );
+        for (result, request) in results.iter().zip(corpus()) {
+            assert_eq!(
+                result.payment_id,
+                config.payment_id(&request.destination, request.sequence)
+            );
+        }
+        assert!(results[6].to_json()["blocked"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("zero address"));
+
+        // Split at the cap, each chunk calling the Treasury
+        let split = &results[1];
+        assert_eq!(split.amount, Some(1_500));
+        assert_eq!(
+            split
+                .plans
+                .iter()
+                .map(|plan| plan.amount)
+                .collect::<Vec<_>>(),
+            [1_000, 500]
+        );
+        assert!(split.plans.iter().all(|plan| plan.to == TEST_TREASURY));
+        assert!(results[4].plans[0].memo.is_some());
+    }
+
+    #[test]
+    fn replay_is_deterministic_and_matches_planning() {
+        let config = replay_config();
+        let first: Vec<Value> = replay(corpus().into_iter(), &config)
+            .iter()
+            .map(ReplayResult::to_json)
+            .collect();
+        let second: Vec<Value> = replay(corpus().into_iter(), &config)
+            .iter()
+            .map(ReplayResult::to_json)
+            .collect();
+        assert_eq!(first, second);
+        let plan =
+            plan_request(&config, &corpus()[0], &TokenAmount::new("EURC", 100), None).unwrap();
+        assert_eq!(first[0]["calls"][0]["data"], plan[0].data);
+        assert_golden("replay_corpus", &Value::Array(first));
+    }
+}
@@ -0,0 +1,742 @@
+//! Retry queue for payouts that failed transiently
+//!
+//! With `retry_queue` set, a payout without a deadline that fails with a
+//! transient error is recorded as `RetryScheduled` with its attempt count and
+//! the time of its next attempt, backing off exponentially. The retry worker
+//! submits due payouts again through [`EthereumPayoutService::retry_failed`],
+//! the same idempotent path as a manual retry. Scheduled payouts live in the
+//! store, so [`EthereumPayoutService::recover_retries`] queues them again after
+//! a restart. A payout still failing after `max_attempts`, or pushed out of a
+//! full queue, is marked failed and alerted about.
+//!
+//! Each failed attempt of a payout that is retried, here or before its
+//! deadline, is kept on its record with the error and the delay chosen, and
+//! published as an `AttemptFailed` event. With `retry_jitter_percent` set,
+//! every delay is cut by a random part of up to that share of it, drawn from
+//! a source [`EthereumPayoutService::with_jitter_seed`] makes reproducible.
+
+use super::store::{PayoutRecord, Timestamp};
+use super::{
+    metrics, EthereumPayoutService, PayoutError, PayoutEvent, PayoutPlan, PayoutRequest,
+    PayoutStatus,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Number of records read per page while scanning the store
+const SCAN_PAGE_SIZE: usize = 500;
+
+/// How transiently failed payouts are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Wait after the first failure, doubled after each further one
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Attempts, including the first, before a payout is given up on
+    pub max_attempts: u32,
+    /// Payouts queued at once; the oldest is dropped to make room
+    pub capacity: usize,
+    /// How often the worker looks for due payouts
+    pub poll_interval: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: Duration::from_secs(30),
+            max_delay: Duration::from_secs(3600),
+            max_attempts: 8,
+            capacity: 1000,
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the next attempt of a payout that failed `attempts` times
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// Progress of a payout through the retry queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryState {
+    /// Failed attempts so far
+    pub attempts: u32,
+    pub next_attempt: Timestamp,
+}
+
+impl RetryState {
+    pub(super) fn to_json(self) -> Value {
+        json!({
+            "attempts": self.attempts,
+            "next_attempt": self.next_attempt.to_rfc3339(),
+        })
+    }
+
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        Some(RetryState {
+            attempts: value["attempts"].as_u64()? as u32,
+            next_attempt: DateTime::parse_from_rfc3339(value["next_attempt"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+        })
+    }
+}
+
+/// A failed attempt of a payout and the wait chosen before the next one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryAttempt {
+    pub at: Timestamp,
+    /// Kind of error, see [`PayoutError::class`]
+    pub error_class: String,
+    pub error: String,
+    /// Wait before the next attempt, `None` if the payout was given up on
+    pub delay: Option<Duration>,
+}
+
+impl RetryAttempt {
+    pub(super) fn to_json(&self) -> Value {
+        json!({
+            "at": self.at.to_rfc3339(),
+            "error_class": self.error_class,
+            "error": self.error,
+            "delay_ms": self.delay.map(|delay| delay.as_millis() as u64),
+        })
+    }
+
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        Some(RetryAttempt {
+            at: DateTime::parse_from_rfc3339(value["at"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+            error_class: value["error_class"].as_str()?.to_string(),
+            error: value["error"].as_str()?.to_string(),
+            delay: match &value["delay_ms"] {
+                Value::Null => None,
+                millis => Some(Duration::from_millis(millis.as_u64()?)),
+            },
+        })
+    }
+}
+
+/// How failed payouts are retried, as configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetrySchedule {
+    /// Wait between attempts of payouts with a deadline
+    pub retry_interval: Duration,
+    /// Most of each wait that jitter cuts off, in percent
+    pub jitter_percent: u32,
+    /// Backoff of the retry queue, `None` when it is disabled
+    pub queue: Option<RetryPolicy>,
+}
+
+/// Source of the jitter spreading out retries, a xorshift generator seeded
+/// from the system time unless a seed is given for reproducible schedules
+#[derive(Debug)]
+pub(super) struct Jitter {
+    state: Mutex<u64>,
+}
+
+impl Jitter {
+    pub(super) fn new(seed: u64) -> Self {
+        Jitter {
+            // Xorshift never leaves a zero state
+            state: Mutex::new(seed.max(1)),
+        }
+    }
+
+    pub(super) fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Jitter::new(nanos)
+    }
+
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// `delay` shortened by a random part of up to `percent` percent of it,
+    /// in whole milliseconds as attempts record delays
+    pub(super) fn apply(&self, delay: Duration, percent: u32) -> Duration {
+        let spread = delay.as_millis() * u128::from(percent.min(100)) / 100;
+        if spread == 0 {
+            return delay;
+        }
+        let cut = u128::from(self.next()) % (spread + 1);
+        delay.saturating_sub(Duration::from_millis(cut.min(u128::from(u64::MAX)) as u64))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct RetryQueue {
+    /// Payment IDs of scheduled payouts, oldest first
+    entries: VecDeque<[u8; 32]>,
+}
+
+impl EthereumPayoutService {
+    /// How failed payouts are retried
+    pub fn retry_schedule(&self) -> RetrySchedule {
+        RetrySchedule {
+            retry_interval: self.config.retry_interval,
+            jitter_percent: self.config.retry_jitter_percent,
+            queue: self.config.retry_queue,
+        }
+    }
+
+    /// `delay` with the configured jitter applied
+    pub(super) fn jittered(&self, delay: Duration) -> Duration {
+        self.jitter.apply(delay, self.config.retry_jitter_percent)
+    }
+
+    /// Attempts of the payout made before this run, kept while it is
+    /// scheduled for retry
+    pub(super) fn earlier_attempts(&self, payment_id: &[u8; 32]) -> Vec<RetryAttempt> {
+        match self.store.get(payment_id) {
+            Some(record) if record.status == PayoutStatus::RetryScheduled => record.attempts,
+            _ => Vec::new(),
+        }
+    }
+
+    /// Add a failed attempt to `attempts` and publish it
+    pub(super) fn record_attempt(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        attempts: &mut Vec<RetryAttempt>,
+        err: &PayoutError,
+        delay: Option<Duration>,
+    ) {
+        let attempt = RetryAttempt {
+            at: self.clock.now(),
+            error_class: err.class().to_string(),
+            error: err.to_string(),
+            delay,
+        };
+        attempts.push(attempt.clone());
+        self.publish_payout(
+            &request.destination,
+            request.sequence,
+            PayoutEvent::AttemptFailed {
+                payment_id: format!("0x{}", hex::encode(plan.payment_id)),
+                attempt,
+            },
+        );
+    }
+
+    /// Queue a payout that failed with transient `err` for another attempt,
+    /// or mark it failed once it is out of attempts. `attempts` are the
+    /// payout's failed attempts before this one.
+    pub(super) fn schedule_retry(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        err: &PayoutError,
+        policy: &RetryPolicy,
+        mut history: Vec<RetryAttempt>,
+    ) {
+        let tenant = self.config.tenant.as_deref();
+        let attempts = self
+            .store
+            .get(&plan.payment_id)
+            .and_then(|record| record.retry)
+            .map_or(0, |retry| retry.attempts)
+            + 1;
+        let mut record = self.failed_record(request, plan, err);
+        if attempts >= policy.max_attempts {
+            self.record_attempt(request, plan, &mut history, err, None);
+            record.attempts = history;
+            error!(
+                "ALERT: payout {} of {} {} to {} failed {} times, giving up: {}",
+                record.payment_id_hex(),
+                record.amount,
+                record.asset_code,
+                self.display_address(&record.recipient),
+                attempts,
+                self.scrub_address(&err.to_string(), &record.recipient)
+            );
+            metrics::retry(tenant, &record.asset_code, "exhausted");
+            record.last_error = Some(format!("Gave up after {} attempts: {}", attempts, err));
+            self.store.save(record);
+            self.unqueue(&plan.payment_id);
+            return;
+        }
+
+        let delay = self.jittered(policy.delay(attempts));
+        let next_attempt = self.clock.now()
+            + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+        warn!(
+            "Payout {} failed, attempt {} of {} at {}: {}",
+            record.payment_id_hex(),
+            attempts + 1,
+            policy.max_attempts,
+            next_attempt.to_rfc3339(),
+            self.scrub_address(&err.to_string(), &record.recipient)
+        );
+        metrics::retry(tenant, &record.asset_code, "scheduled");
+        self.record_attempt(request, plan, &mut history, err, Some(delay));
+        record.attempts = history;
+        record.status = PayoutStatus::RetryScheduled;
+        record.retry = Some(RetryState {
+            attempts,
+            next_attempt,
+        });
+        self.store.save(record);
+        self.enqueue(plan.payment_id, policy);
+    }
+
+    /// Add a payout to the queue, keeping its place if already queued
+    fn enqueue(&self, payment_id: [u8; 32], policy: &RetryPolicy) {
+        let dropped = {
+            let mut queue = self.retry_queue.lock().unwrap();
+            if !queue.entries.contains(&payment_id) {
+                queue.entries.push_back(payment_id);
+            }
+            let excess = queue.entries.len().saturating_sub(policy.capacity);
+            queue.entries.drain(..excess).collect::<Vec<_>>()
+        };
+        for payment_id in dropped {
+            if let Some(record) = self.store.get(&payment_id) {
+                self.drop_retry(record, policy);
+            }
+        }
+    }
+
+    fn unqueue(&self, payment_id: &[u8; 32]) {
+        self.retry_queue
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|queued| queued != payment_id);
+    }
+
+    /// Mark a payout pushed out of the full queue as failed
+    fn drop_retry(&self, mut record: PayoutRecord, policy: &RetryPolicy) {
+        if record.status != PayoutStatus::RetryScheduled {
+            return;
+        }
+        error!(
+            "ALERT: retry queue full at {} payouts, dropped payout {} of {} {} to {}, needs manual handling",
+            policy.capacity,
+            record.payment_id_hex(),
+            record.amount,
+            record.asset_code,
+            self.display_address(&record.recipient)
+        );
+        metrics::retry(self.config.tenant.as_deref(), &record.asset_code, "dropped");
+        record.status = PayoutStatus::Failed;
+        record.retry = None;
+        record.last_error = Some(format!(
+            "Dropped from the full retry queue: {}",
+            record.last_error.as_deref().unwrap_or_default()
+        ));
+        self.store.save(record);
+    }
+
+    /// IDs of the stored payouts with `status`, oldest first
+    pub(super) fn payment_ids_with_status(&self, status: PayoutStatus) -> Vec<[u8; 32]> {
+        let mut payment_ids = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.store.list_range(
+                DateTime::<Utc>::MIN_UTC,
+                DateTime::<Utc>::MAX_UTC,
+                cursor,
+                SCAN_PAGE_SIZE,
+            );
+            payment_ids.extend(
+                page.iter()
+                    .filter(|record| record.status == status)
+                    .map(|record| record.payment_id),
+            );
+            if page.len() < SCAN_PAGE_SIZE {
+                break;
+            }
+            cursor = page.last().map(Into::into);
+        }
+        payment_ids
+    }
+
+    /// Queue the payouts the store has scheduled for retry, oldest first,
+    /// returning how many there were
+    pub fn recover_retries(&self) -> usize {
+        let policy = match &self.config.retry_queue {
+            Some(policy) => *policy,
+            None => return 0,
+        };
+        let scheduled = self.payment_ids_with_status(PayoutStatus::RetryScheduled);
+        let recovered = scheduled.len();
+        for payment_id in scheduled {
+            self.enqueue(payment_id, &policy);
+        }
+        if recovered > 0 {
+            info!("Recovered {} payouts scheduled for retry", recovered);
+        }
+        recovered
+    }
+
+    /// Attempt the queued payouts whose next attempt is due, returning how
+    /// many were attempted
+    pub async fn retry_due(&self) -> usize {
+        // A degraded operator cannot send; the payouts wait for it to recover
+        if self.is_degraded() {
+            return 0;
+        }
+        let now = self.clock.now();
+        let queued: Vec<[u8; 32]> = self
+            .retry_queue
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .copied()
+            .collect();
+        let mut attempted = 0;
+        for payment_id in queued {
+            let retry = match self.store.get(&payment_id) {
+                Some(record) if record.status == PayoutStatus::RetryScheduled => record.retry,
+                _ => {
+                    self.unqueue(&payment_id);
+                    continue;
+                }
+            };
+            if retry.is_some_and(|retry| retry.next_attempt > now) {
+                continue;
+            }
+            attempted += 1;
+            if let Err(err) = self.retry_failed(&payment_id).await {
+                // Transient errors were scheduled again by `execute_plan`
+                if let Some(mut record) = self
+                    .store
+                    .get(&payment_id)
+                    .filter(|record| record.retry == retry)
+                {
+                    warn!(
+                        "Retry of payout {} failed permanently: {}",
+                        record.payment_id_hex(),
+                        err
+                    );
+                    record.status = PayoutStatus::Failed;
+                    record.retry = None;
+                    record.last_error = Some(err.to_string());
+                    self.store.save(record);
+                }
+            }
+            if self.store.get(&payment_id).map(|record| record.status)
+                != Some(PayoutStatus::RetryScheduled)
+            {
+                self.unqueue(&payment_id);
+            }
+        }
+        attempted
+    }
+
+    /// Payouts in the retry queue
+    pub fn retry_queue_len(&self) -> usize {
+        self.retry_queue.lock().unwrap().entries.len()
+    }
+
+    /// Recover the stored retry queue and spawn a task draining it, if enabled
+    pub fn spawn_retry_worker(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        let poll_interval = self.config.retry_queue?.poll_interval;
+        self.recover_retries();
+        let service = Arc::downgrade(self);
+        Some(self.spawn(async move {
+            loop {
+                let service = match service.upgrade() {
+                    Some(service) => service,
+                    None => break,
+                };
+                if !service.sleep_unless_cancelled(poll_interval).await {
+                    break;
+                }
+                service.retry_due().await;
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, test_config, test_service, FakeChain, FakeClock, TEST_DESTINATION,
+    };
+    use super::super::{Clock, EthereumPayoutConfig, PayoutProof};
+    use super::*;
+
+    fn retry_config() -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.retry_queue = Some(RetryPolicy {
+            initial_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(25),
+            max_attempts: 4,
+            capacity: 2,
+            poll_interval: Duration::from_secs(1),
+        });
+        config
+    }
+
+    fn internal_error() -> Value {
+        json!({"code": -32603, "message": "internal error"})
+    }
+
+    /// Chain whose node fails every send with a transient error until healed
+    fn down_chain() -> Arc<FakeChain> {
+        let chain = deployed_chain();
+        chain.fail("eth_sendRawTransaction", internal_error());
+        chain
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let policy = retry_config().retry_queue.unwrap();
+        let delays: Vec<u64> = (1..=4).map(|n| policy.delay(n).as_secs()).collect();
+        assert_eq!(delays, vec![10, 20, 25, 25]);
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(25));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_back_off_until_attempts_run_out() {
+        let clock = FakeClock::new();
+        let chain = down_chain();
+        let service = test_service(retry_config(), chain.clone()).with_clock(clock.clone());
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::RetryScheduled);
+        let retry = record.retry.unwrap();
+        assert_eq!(retry.attempts, 1);
+        assert_eq!(
+            retry.next_attempt,
+            clock.now() + chrono::Duration::seconds(10)
+        );
+
+        // Nothing is attempted before it is due
+        assert_eq!(service.retry_due().await, 0);
+        for (wait, attempts) in [(10, 2), (20, 3)] {
+            clock.advance(Duration::from_secs(wait));
+            assert_eq!(service.retry_due().await, 1);
+            let retry = service.store().get(&payment_id).unwrap().retry.unwrap();
+            assert_eq!(retry.attempts, attempts);
+        }
+        clock.advance(Duration::from_secs(25));
+        assert_eq!(service.retry_due().await, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Failed);
+        assert!(record
+            .last_error
+            .unwrap()
+            .starts_with("Gave up after 4 attempts"));
+        assert_eq!(service.retry_queue_len(), 0);
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 4);
+        assert!(chain.pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_queued_and_full_queue_drops_oldest() {
+        let clock = FakeClock::new();
+        let chain = down_chain();
+        let service = test_service(retry_config(), chain.clone()).with_clock(clock.clone());
+
+        for sequence in 1..=3 {
+            clock.advance(Duration::from_secs(1));
+            assert!(service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .is_err());
+        }
+        assert_eq!(service.retry_queue_len(), 2);
+        let status = |sequence| {
+            let payment_id = service.config().payment_id(TEST_DESTINATION, sequence);
+            service.store().get(&payment_id).unwrap().status
+        };
+        assert_eq!(status(1), PayoutStatus::Failed);
+        assert_eq!(status(2), PayoutStatus::RetryScheduled);
+        assert_eq!(status(3), PayoutStatus::RetryScheduled);
+
+        chain.fail(
+            "eth_sendRawTransaction",
+            json!({"code": -32000, "message": "insufficient funds"}),
+        );
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 4)
+            .await
+            .is_err());
+        assert_eq!(service.retry_queue_len(), 2);
+        // Retries failing permanently leave the queue too
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(service.retry_due().await, 2);
+        assert_eq!(status(2), PayoutStatus::Failed);
+        assert_eq!(service.retry_queue_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn scheduled_retries_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut config = retry_config();
+        config.store_path = Some(path.clone());
+        let clock = FakeClock::new();
+        let payment_id = config.payment_id(TEST_DESTINATION, 1);
+        {
+            let service = test_service(config.clone(), down_chain()).with_clock(clock.clone());
+            assert!(service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .is_err());
+        }
+
+        let chain = deployed_chain();
+        let service = Arc::new(test_service(config, chain.clone()).with_clock(clock.clone()));
+        assert_eq!(service.recover_retries(), 1);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(service.retry_due().await, 1);
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Submitted);
+        assert_eq!(record.retry, None);
+        assert_eq!(service.retry_queue_len(), 0);
+        assert_eq!(chain.pending(), vec![record.tx_hash.unwrap()]);
+
+        // Already sent, so a second drain does not send it again
+        assert_eq!(service.retry_due().await, 0);
+        assert!(matches!(
+            service.retry_failed(&payment_id).await,
+            Err(PayoutError::Config(_))
+        ));
+        assert_eq!(chain.call_count("eth_sendRawTransaction"), 1);
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn retry_state_round_trips() {
+        let state = RetryState {
+            attempts: 3,
+            next_attempt: Utc::now(),
+        };
+        let parsed = RetryState::from_json(&state.to_json()).unwrap();
+        assert_eq!(parsed.attempts, 3);
+        assert_eq!(
+            parsed.next_attempt.timestamp_millis(),
+            state.next_attempt.timestamp_millis()
+        );
+    }
+
+    /// Chain whose node fails the first `failures` sends with a transient error
+    fn failing_chain(failures: usize) -> Arc<FakeChain> {
+        let chain = deployed_chain();
+        chain.fail_next("eth_sendRawTransaction", failures, internal_error());
+        chain
+    }
+
+    #[test]
+    fn seeded_jitter_is_reproducible_and_bounded() {
+        let delay = Duration::from_secs(10);
+        let (a, b) = (Jitter::new(42), Jitter::new(42));
+        for _ in 0..100 {
+            let jittered = a.apply(delay, 50);
+            assert_eq!(jittered, b.apply(delay, 50));
+            assert!(jittered >= delay / 2 && jittered <= delay);
+        }
+        assert_ne!(
+            Jitter::new(1).apply(delay, 50),
+            Jitter::new(2).apply(delay, 50)
+        );
+        assert_eq!(a.apply(delay, 0), delay);
+    }
+
+    #[tokio::test]
+    async fn queued_retries_record_their_attempts() {
+        let clock = FakeClock::new();
+        let mut config = retry_config();
+        config.retry_jitter_percent = 50;
+        let service = test_service(config, failing_chain(2))
+            .with_clock(clock.clone())
+            .with_jitter_seed(42);
+        let mut events = service.subscribe();
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        let schedule = service.retry_schedule();
+        assert_eq!(schedule.jitter_percent, 50);
+        assert_eq!(schedule.queue, retry_config().retry_queue);
+
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_err());
+        for _ in 0..2 {
+            let next_attempt = service.store().get(&payment_id).unwrap().retry.unwrap();
+            clock.advance((next_attempt.next_attempt - clock.now()).to_std().unwrap());
+            assert_eq!(service.retry_due().await, 1);
+        }
+
+        let expected = Jitter::new(42);
+        let delays = vec![
+            expected.apply(Duration::from_secs(10), 50),
+            expected.apply(Duration::from_secs(20), 50),
+        ];
+        let record = service.store().get(&payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::Submitted);
+        let recorded: Vec<_> = record.attempts.iter().map(|a| a.delay.unwrap()).collect();
+        assert_eq!(recorded, delays);
+        assert!(record.attempts.iter().all(|a| a.error_class == "rpc"));
+        assert_eq!(
+            record.attempts[1].at - record.attempts[0].at,
+            chrono::Duration::from_std(delays[0]).unwrap()
+        );
+
+        let proof = service.export_proof(&payment_id).await.unwrap();
+        assert_eq!(proof.attempts, record.attempts);
+        assert_eq!(
+            PayoutProof::from_json(&proof.to_json()).unwrap().attempts,
+            record.attempts
+        );
+        let mut published = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let PayoutEvent::AttemptFailed { attempt, .. } = event.event {
+                published.push(attempt);
+            }
+        }
+        assert_eq!(published, record.attempts);
+    }
+
+    #[tokio::test]
+    async fn retries_before_a_deadline_record_their_attempts() {
+        let mut config = test_config();
+        config.payout_deadline = Some(Duration::from_secs(600));
+        config.retry_interval = Duration::from_millis(20);
+        config.retry_jitter_percent = 100;
+        let service = test_service(config, failing_chain(3)).with_jitter_seed(7);
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .is_ok());
+        let expected = Jitter::new(7);
+        let attempts = service.store().get(&payment_id).unwrap().attempts;
+        assert_eq!(attempts.len(), 3);
+        for attempt in &attempts {
+            assert_eq!(
+                attempt.delay,
+                Some(expected.apply(Duration::from_millis(20), 100))
+            );
+            assert_eq!(attempt.error, "RPC error -32603: internal error");
+        }
+    }
+}
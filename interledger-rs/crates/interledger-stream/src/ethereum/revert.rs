@@ -0,0 +1,332 @@
+//! Decoding of Solidity revert data into readable errors
+//!
+//! A revert carries a 4-byte selector followed by ABI-encoded arguments. The
+//! [`RevertDecoder`] maps selectors to known error signatures so that a
+//! failed payout reports e.g. `InsufficientBalance(100, 42)` instead of hex.
+
+use super::abi::{decode_words, selector};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Errors raised by the bundled Treasury contract
+const TREASURY_ERRORS: &[&str] = &[
+    "NotOperator()",
+    "PaymentIdAlreadyUsed(bytes32)",
+    "ZeroAmount()",
+    "ZeroAddress()",
+    "InsufficientBalance(uint256,uint256)",
+];
+
+/// Errors raised by the OpenZeppelin contracts the Treasury builds on
+const OPENZEPPELIN_ERRORS: &[&str] = &[
+    "OwnableUnauthorizedAccount(address)",
+    "OwnableInvalidOwner(address)",
+    "ReentrancyGuardReentrantCall()",
+    "SafeERC20FailedOperation(address)",
+    "EnforcedPause()",
+    "ExpectedPause()",
+    "ERC20InsufficientBalance(address,uint256,uint256)",
+    "ERC20InvalidReceiver(address)",
+];
+
+/// Solidity's built-in `require` message and panic codes
+const BUILTIN_ERRORS: &[&str] = &["Error(string)", "Panic(uint256)"];
+
+/// Argument types supported in error signatures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbiType {
+    Address,
+    Bool,
+    Bytes32,
+    Uint256,
+    String,
+}
+
+impl AbiType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "address" => Some(AbiType::Address),
+            "bool" => Some(AbiType::Bool),
+            "bytes32" => Some(AbiType::Bytes32),
+            "uint256" | "uint" => Some(AbiType::Uint256),
+            "string" => Some(AbiType::String),
+            _ => None,
+        }
+    }
+}
+
+/// A custom error's name and argument types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorSignature {
+    pub name: String,
+    pub args: Vec<AbiType>,
+}
+
+impl ErrorSignature {
+    /// Parse a canonical signature such as `InsufficientBalance(uint256,uint256)`
+    pub fn parse(signature: &str) -> Option<Self> {
+        let (name, rest) = signature.split_once('(')?;
+        let args = rest.strip_suffix(')')?;
+        if name.is_empty() {
+            return None;
+        }
+        let args = if args.is_empty() {
+            Vec::new()
+        } else {
+            args.split(',')
+                .map(|arg| AbiType::parse(arg.trim()))
+                .collect::<Option<_>>()?
+        };
+        Some(ErrorSignature {
+            name: name.to_string(),
+            args,
+        })
+    }
+
+    /// Canonical signature the selector is derived from
+    pub fn canonical(&self) -> String {
+        let args: Vec<&str> = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                AbiType::Address => "address",
+                AbiType::Bool => "bool",
+                AbiType::Bytes32 => "bytes32",
+                AbiType::Uint256 => "uint256",
+                AbiType::String => "string",
+            })
+            .collect();
+        format!("{}({})", self.name, args.join(","))
+    }
+
+    pub fn selector(&self) -> [u8; 4] {
+        selector(&self.canonical())
+    }
+}
+
+/// A revert decoded as far as the table allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRevert {
+    pub selector: [u8; 4],
+    /// Error name, `None` when the selector is not in the table
+    pub name: Option<String>,
+    /// Arguments rendered as strings, empty if unknown or undecodable
+    pub args: Vec<String>,
+}
+
+impl DecodedRevert {
+    /// Whether this is the named error
+    pub fn is(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name)
+    }
+}
+
+impl fmt::Display for DecodedRevert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}({})", name, self.args.join(", ")),
+            None => write!(f, "unknown error 0x{}", hex::encode(self.selector)),
+        }
+    }
+}
+
+/// Table of known error signatures keyed by selector
+#[derive(Debug, Clone)]
+pub struct RevertDecoder {
+    errors: HashMap<[u8; 4], ErrorSignature>,
+}
+
+impl Default for RevertDecoder {
+    /// Treasury, OpenZeppelin and built-in Solidity errors
+    fn default() -> Self {
+        let mut decoder = RevertDecoder::empty();
+        for signature in TREASURY_ERRORS
+            .iter()
+            .chain(OPENZEPPELIN_ERRORS)
+            .chain(BUILTIN_ERRORS)
+        {
+            decoder.insert(ErrorSignature::parse(signature).expect("valid built-in signature"));
+        }
+        decoder
+    }
+}
+
+impl RevertDecoder {
+    pub fn empty() -> Self {
+        RevertDecoder {
+            errors: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, signature: ErrorSignature) {
+        self.errors.insert(signature.selector(), signature);
+    }
+
+    pub fn get(&self, selector: &[u8; 4]) -> Option<&ErrorSignature> {
+        self.errors.get(selector)
+    }
+
+    /// Add signatures from a `;`-separated list, e.g. "AlreadyPaid(bytes32);Halted()"
+    pub fn extend_from_spec(&mut self, spec: &str) -> Option<()> {
+        for signature in spec.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            self.insert(ErrorSignature::parse(signature)?);
+        }
+        Some(())
+    }
+
+    /// Decode 0x-prefixed revert data, returning `None` if it holds no selector
+    pub fn decode(&self, data: &str) -> Option<DecodedRevert> {
+        let bytes = hex::decode(data.strip_prefix("0x").unwrap_or(data)).ok()?;
+        if bytes.len() < 4 {
+            return None;
+        }
+        let mut selector = [0u8; 4];
+        selector.copy_from_slice(&bytes[..4]);
+
+        let (name, args) = match self.errors.get(&selector) {
+            Some(signature) => (
+                Some(signature.name.clone()),
+                decode_args(&signature.args, &bytes[4..]).unwrap_or_default(),
+            ),
+            None => (None, Vec::new()),
+        };
+        Some(DecodedRevert {
+            selector,
+            name,
+            args,
+        })
+    }
+}
+
+fn decode_args(types: &[AbiType], data: &[u8]) -> Option<Vec<String>> {
+    let words = decode_words(&hex::encode(data))?;
+    types
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| {
+            let word = words.get(i)?;
+            match ty {
+                AbiType::Address => Some(format!("0x{}", hex::encode(&word[12..]))),
+                AbiType::Bool => Some((word[31] != 0).to_string()),
+                AbiType::Bytes32 => Some(format!("0x{}", hex::encode(word))),
+                AbiType::Uint256 => Some(format_uint(word)),
+                AbiType::String => decode_string(word, data),
+            }
+        })
+        .collect()
+}
+
+/// Render a uint256 in decimal when it fits a u128, in hex otherwise
+fn format_uint(word: &[u8; 32]) -> String {
+    if word[..16].iter().all(|b| *b == 0) {
+        let mut low = [0u8; 16];
+        low.copy_from_slice(&word[16..]);
+        u128::from_be_bytes(low).to_string()
+    } else {
+        format!("0x{}", hex::encode(word))
+    }
+}
+
+/// Decode a dynamic `string` whose head word holds its offset into `data`
+fn decode_string(head: &[u8; 32], data: &[u8]) -> Option<String> {
+    let offset = word_to_usize(head)?;
+    let len_word = data.get(offset..offset.checked_add(32)?)?;
+    let len = word_to_usize(len_word)?;
+    let start = offset + 32;
+    let bytes = data.get(start..start.checked_add(len)?)?;
+    Some(format!("{:?}", String::from_utf8_lossy(bytes)))
+}
+
+/// Read a length or offset word, rejecting values that do not fit a usize
+fn word_to_usize(word: &[u8]) -> Option<usize> {
+    if word[..24].iter().any(|b| *b != 0) {
+        return None;
+    }
+    let mut low = [0u8; 8];
+    low.copy_from_slice(&word[24..32]);
+    usize::try_from(u64::from_be_bytes(low)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_bytes32_argument() {
+        // PaymentIdAlreadyUsed(0x11…11) as returned by anvil
+        let data = format!("0xcf4cf60d{}", "11".repeat(32));
+        let decoded = RevertDecoder::default().decode(&data).unwrap();
+        assert!(decoded.is("PaymentIdAlreadyUsed"));
+        assert_eq!(decoded.args, vec![format!("0x{}", "11".repeat(32))]);
+    }
+
+    #[test]
+    fn decodes_two_uint256_arguments() {
+        // InsufficientBalance(requested = 5000000, available = 1234)
+        let data = format!("0xcf479181{:064x}{:064x}", 5_000_000u64, 1234u64);
+        let decoded = RevertDecoder::default().decode(&data).unwrap();
+        assert_eq!(decoded.to_string(), "InsufficientBalance(5000000, 1234)");
+    }
+
+    #[test]
+    fn decodes_argumentless_and_string_errors() {
+        let decoded = RevertDecoder::default().decode("0x7c214f04").unwrap();
+        assert_eq!(decoded.to_string(), "NotOperator()");
+
+        // Error("Pausable: paused")
+        let data = concat!(
+            "0x08c379a0",
+            "0000000000000000000000000000000000000000000000000000000000000020",
+            "0000000000000000000000000000000000000000000000000000000000000010",
+            "5061757361626c653a2070617573656400000000000000000000000000000000"
+        );
+        let decoded = RevertDecoder::default().decode(data).unwrap();
+        assert_eq!(decoded.to_string(), "Error(\"Pausable: paused\")");
+    }
+
+    #[test]
+    fn selectors_match_treasury_abi() {
+        let decoder = RevertDecoder::default();
+        for (signature, selector) in [
+            ("NotOperator()", "7c214f04"),
+            ("PaymentIdAlreadyUsed(bytes32)", "cf4cf60d"),
+            ("InsufficientBalance(uint256,uint256)", "cf479181"),
+            ("EnforcedPause()", "d93c0665"),
+            ("Error(string)", "08c379a0"),
+        ] {
+            let parsed = ErrorSignature::parse(signature).unwrap();
+            assert_eq!(hex::encode(parsed.selector()), selector, "{}", signature);
+            assert_eq!(decoder.get(&parsed.selector()), Some(&parsed));
+        }
+    }
+
+    #[test]
+    fn unknown_selector_keeps_raw_selector() {
+        let decoded = RevertDecoder::default()
+            .decode(&format!("0xdeadbeef{:064x}", 1))
+            .unwrap();
+        assert_eq!(decoded.name, None);
+        assert_eq!(decoded.to_string(), "unknown error 0xdeadbeef");
+        assert_eq!(RevertDecoder::default().decode("0x"), None);
+    }
+
+    #[test]
+    fn configured_signatures_extend_the_table() {
+        let mut decoder = RevertDecoder::empty();
+        decoder
+            .extend_from_spec("AlreadyPaid(bytes32); InsufficientTreasuryBalance(uint256,uint256)")
+            .unwrap();
+        let data = format!(
+            "0x{}{:064x}{:064x}",
+            hex::encode(selector("InsufficientTreasuryBalance(uint256,uint256)")),
+            7,
+            3
+        );
+        assert_eq!(
+            decoder.decode(&data).unwrap().to_string(),
+            "InsufficientTreasuryBalance(7, 3)"
+        );
+        assert!(decoder.extend_from_spec("Broken(uint7)").is_none());
+    }
+}
@@ -0,0 +1,111 @@
+//! RLP encoding of legacy transactions
+//!
+//! Shared by the signers, which serialize the transactions they sign, and by
+//! the L2 fee quote, which the oracle takes the unsigned transaction for.
+
+use super::TxParams;
+
+/// RLP of the unsigned EIP-155 legacy transaction, as it is signed and as
+/// `getL1Fee` expects it
+pub fn unsigned_transaction(
+    to: &str,
+    data: &str,
+    value: u64,
+    params: TxParams,
+    chain_id: u64,
+) -> Option<Vec<u8>> {
+    let to = hex::decode(to.strip_prefix("0x")?).ok()?;
+    let data = hex::decode(data.strip_prefix("0x").unwrap_or(data)).ok()?;
+    let fields = [
+        rlp_uint(params.nonce.into()),
+        rlp_uint(params.gas_price.into()),
+        rlp_uint(params.gas_limit.into()),
+        rlp_bytes(&to),
+        rlp_uint(value.into()),
+        rlp_bytes(&data),
+        rlp_uint(chain_id.into()),
+        rlp_uint(0),
+        rlp_uint(0),
+    ];
+    Some(rlp_list(&fields.concat()))
+}
+
+/// RLP of an unsigned integer, big-endian without leading zeros
+pub(super) fn rlp_uint(value: u128) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let first = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    rlp_bytes(&bytes[first..])
+}
+
+/// RLP of a byte string
+pub(super) fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() == 1 && bytes[0] < 0x80 {
+        return bytes.to_vec();
+    }
+    let mut encoded = rlp_length(bytes.len(), 0x80);
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+/// RLP of a list whose items are already encoded in `payload`
+pub(super) fn rlp_list(payload: &[u8]) -> Vec<u8> {
+    let mut encoded = rlp_length(payload.len(), 0xc0);
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+/// Prefix of an item of `len` bytes, short or long form
+fn rlp_length(len: usize, offset: u8) -> Vec<u8> {
+    if len < 56 {
+        return vec![offset + len as u8];
+    }
+    let bytes = (len as u64).to_be_bytes();
+    let first = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    let mut encoded = vec![offset + 55 + (bytes.len() - first) as u8];
+    encoded.extend_from_slice(&bytes[first..]);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_unsigned_legacy_transactions() {
+        // Example of EIP-155
+        let params = TxParams {
+            nonce: 9,
+            gas_limit: 21_000,
+            gas_price: 20_000_000_000,
+        };
+        let tx = unsigned_transaction(
+            "0x3535353535353535353535353535353535353535",
+            "0x",
+            1_000_000_000_000_000_000,
+            params,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&tx),
+            "ec098504a817c800825208943535353535353535353535353535353535353535\
+             880de0b6b3a764000080018080"
+        );
+    }
+
+    #[test]
+    fn long_items_take_a_length_of_length_prefix() {
+        assert_eq!(rlp_bytes(&[0x7f]), vec![0x7f]);
+        assert_eq!(rlp_bytes(&[0x80]), vec![0x81, 0x80]);
+        let long = rlp_bytes(&[0xaa; 56]);
+        assert_eq!(&long[..2], &[0xb8, 56]);
+        assert_eq!(long.len(), 58);
+        assert_eq!(rlp_uint(0), vec![0x80]);
+    }
+}
@@ -0,0 +1,313 @@
+//! Replacing the operator key while the service runs
+//!
+//! Every transaction holds the signing gate for reading from fetching its
+//! nonce until the node accepted it. A rotation takes the gate for writing, so
+//! it waits for the sends in progress and then switches all later payouts to
+//! the new account at once. Nonces are counted per account, so the new
+//! operator starts from its own and no nonce of the old account is used
+//! again. Payouts the old account broadcast keep being followed to their
+//! receipts by a task the rotation returns.
+//!
+//! `config().operator_private_key` stays the key the service started with.
+//! A service signing with an injected [`Signer`](super::Signer) refuses to
+//! rotate, as the key it would switch from is not the one signing.
+
+use super::{
+    normalize_private_key, EthereumPayoutService, LocalSigner, PayoutError, PayoutEvent,
+    PayoutStatus,
+};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info};
+
+/// Account the service signs and sends payouts with
+pub(super) struct Operator {
+    pub(super) address: String,
+    pub(super) key: String,
+}
+
+/// What a rotation of the operator key handed over
+#[derive(Debug)]
+pub struct KeyRotation {
+    pub old_address: String,
+    pub new_address: String,
+    /// Payouts sent by the old account that were not mined yet
+    pub in_flight: Vec<[u8; 32]>,
+    /// Task refreshing their receipts until all are mined, `None` if there were none
+    pub monitor: Option<JoinHandle<()>>,
+}
+
+impl EthereumPayoutService {
+    /// Address payouts are currently sent from
+    pub fn operator_address(&self) -> String {
+        self.operator.read().unwrap().address.clone()
+    }
+
+    pub(super) fn operator_key(&self) -> String {
+        self.operator.read().unwrap().key.clone()
+    }
+
+    /// Send later payouts with `new_key`, once those being sent with the
+    /// current key reached the node.
+    ///
+    /// Fails without changing anything if the key is malformed or already the
+    /// operator's, if role checks are on and its account lacks the role, or
+    /// if an injected signer signs for the operator instead of its key.
+    pub async fn rotate_operator_key(
+        self: &Arc<Self>,
+        new_key: &str,
+    ) -> Result<KeyRotation, PayoutError> {
+        if self.signer.is_some() {
+            return Err(PayoutError::Config(
+                "The operator signs with an injected signer; rotate the key it holds instead"
+                    .to_string(),
+            ));
+        }
+        let key = normalize_private_key(new_key)?;
+        let new_address = LocalSigner::from_hex(&key)?.address();
+        if new_address.eq_ignore_ascii_case(&self.operator_address()) {
+            return Err(PayoutError::Config(format!(
+                "{} is already the operator",
+                new_address
+            )));
+        }
+        if let Some(check) = &self.config.role_check {
+            if !self.has_role(&check.role, &new_address).await? {
+                return Err(PayoutError::NotAuthorized {
+                    operator: new_address,
+                });
+            }
+        }
+
+        let signing = self.signing.write().await;
+        let old = std::mem::replace(
+            &mut *self.operator.write().unwrap(),
+            Operator {
+                address: new_address.clone(),
+                key,
+            },
+        );
+        let in_flight = self.payment_ids_with_status(PayoutStatus::Submitted);
+        drop(signing);
+
+        info!(
+            "Rotated operator from {} to {}; {} payouts from the old account are in flight",
+            old.address,
+            new_address,
+            in_flight.len()
+        );
+        self.publish(PayoutEvent::OperatorRotated {
+            old_address: old.address.clone(),
+            new_address: new_address.clone(),
+        });
+        if let Some(observer) = &self.payout_observer {
+            observer.operator_rotated(&old.address, &new_address);
+        }
+        let monitor = if in_flight.is_empty() {
+            None
+        } else {
+            Some(self.monitor_retired(old.address.clone(), in_flight.clone()))
+        };
+        Ok(KeyRotation {
+            old_address: old.address,
+            new_address,
+            in_flight,
+            monitor,
+        })
+    }
+
+    /// Refresh the receipts of the old operator's payouts until none is still pending
+    fn monitor_retired(
+        self: &Arc<Self>,
+        old_address: String,
+        mut pending: Vec<[u8; 32]>,
+    ) -> JoinHandle<()> {
+        let service = Arc::downgrade(self);
+        let poll_interval = self.config.receipt_poll_interval;
+        self.spawn(async move {
+            loop {
+                let service = match service.upgrade() {
+                    Some(service) => service,
+                    None => break,
+                };
+                let mut unmined = Vec::new();
+                for payment_id in pending {
+                    if let Err(err) = service.refresh_receipt(&payment_id).await {
+                        debug!(
+                            "Receipt of 0x{} unavailable: {}",
+                            hex::encode(payment_id),
+                            err
+                        );
+                    }
+                    // Also drops payouts cancelled or abandoned in the meantime
+                    let status = service.store.get(&payment_id).map(|record| record.status);
+                    if status == Some(PayoutStatus::Submitted) {
+                        unmined.push(payment_id);
+                    }
+                }
+                pending = unmined;
+                if pending.is_empty() {
+                    info!(
+                        "All payouts sent by retired operator {} were mined",
+                        old_address
+                    );
+                    break;
+                }
+                if !service.sleep_unless_cancelled(poll_interval).await {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        decode_raw_transaction, test_config, test_service, MockTransport, TEST_DESTINATION,
+        TEST_OPERATOR, TEST_OPERATOR_KEY,
+    };
+    use super::super::{ExecutionMode, PayoutRequest, RoleCheckConfig};
+    use super::*;
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// Second Anvil development account
+    const NEW_KEY: &str = "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+    const NEW_OPERATOR: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+
+    /// Node counting the transactions of each account to serve pending nonces
+    fn node() -> Arc<MockTransport> {
+        let transport = MockTransport::new();
+        let sent: Arc<Mutex<HashMap<String, u64>>> = Arc::default();
+        let counts = sent.clone();
+        transport.on("eth_getTransactionCount", move |params: &Value| {
+            let address = params[0].as_str().unwrap().to_ascii_lowercase();
+            let count = counts.lock().unwrap().get(&address).copied();
+            Ok(json!(format!("0x{:x}", count.unwrap_or_default())))
+        });
+        transport.on("eth_sendRawTransaction", move |params: &Value| {
+            let tx = decode_raw_transaction(params[0].as_str().unwrap()).unwrap();
+            let address = tx["from"].as_str().unwrap().to_ascii_lowercase();
+            let mut sent = sent.lock().unwrap();
+            let total: u64 = sent.values().sum();
+            *sent.entry(address).or_default() += 1;
+            Ok(json!(format!("0x{:064x}", total + 1)))
+        });
+        transport.on_result("eth_gasPrice", json!("0x1"));
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x9", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        transport.delay("eth_sendRawTransaction", Duration::from_millis(40));
+        transport
+    }
+
+    #[tokio::test]
+    async fn rotates_mid_queue_without_losing_payouts() {
+        let mut config = test_config();
+        config.execution_mode = ExecutionMode::Queued;
+        config.receipt_poll_interval = Duration::from_millis(10);
+        let transport = node();
+        let service = Arc::new(test_service(config, transport.clone()));
+        let mut events = service.subscribe();
+        let worker = service.spawn_payout_worker().unwrap();
+        for sequence in 1..=6 {
+            service
+                .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, sequence))
+                .await;
+        }
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let rotation = service.rotate_operator_key(NEW_KEY).await.unwrap();
+        assert!(rotation.old_address.eq_ignore_ascii_case(TEST_OPERATOR));
+        assert_eq!(rotation.new_address, NEW_OPERATOR);
+        assert_eq!(service.operator_address(), NEW_OPERATOR);
+        while transport.call_count("eth_sendRawTransaction") < 6 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        rotation.monitor.unwrap().await.unwrap();
+        worker.abort();
+
+        // Every payout was sent once, the old account's ones before any of the new one's
+        let senders: Vec<(String, String)> = transport
+            .sent_transactions()
+            .iter()
+            .map(|call| {
+                let tx = call;
+                (
+                    tx["from"].as_str().unwrap().to_ascii_lowercase(),
+                    tx["nonce"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        let unique: HashSet<_> = senders.iter().collect();
+        assert_eq!(unique.len(), 6);
+        let old_sends = senders
+            .iter()
+            .take_while(|(from, _)| from.eq_ignore_ascii_case(TEST_OPERATOR))
+            .count();
+        assert!((1..6).contains(&old_sends), "{:?}", senders);
+        assert!(senders[old_sends..]
+            .iter()
+            .all(|(from, _)| from.eq_ignore_ascii_case(NEW_OPERATOR)));
+        assert_eq!(senders[old_sends].1, "0x0");
+        assert_eq!(rotation.in_flight.len(), old_sends);
+
+        for sequence in 1..=6 {
+            let payment_id = service.config().payment_id(TEST_DESTINATION, sequence);
+            let record = service.store().get(&payment_id).unwrap();
+            if rotation.in_flight.contains(&payment_id) {
+                assert_eq!(record.status, PayoutStatus::Confirmed);
+            } else {
+                assert_eq!(record.status, PayoutStatus::Submitted);
+            }
+        }
+        let mut rotated = None;
+        while let Ok(event) = events.try_recv() {
+            if let PayoutEvent::OperatorRotated { new_address, .. } = event.event {
+                rotated = Some(new_address);
+            }
+        }
+        assert_eq!(rotated.as_deref(), Some(NEW_OPERATOR));
+    }
+
+    #[tokio::test]
+    async fn refuses_unusable_keys() {
+        let mut config = test_config();
+        config.role_check = Some(RoleCheckConfig::default());
+        let transport = node();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 0)));
+        let service = Arc::new(test_service(config, transport));
+
+        assert!(matches!(
+            service.rotate_operator_key("0x1234").await,
+            Err(PayoutError::InvalidOperatorKey(_))
+        ));
+        let current = service.config().operator_private_key.clone();
+        assert!(matches!(
+            service.rotate_operator_key(&current).await,
+            Err(PayoutError::Config(message)) if message.contains("already")
+        ));
+        assert!(matches!(
+            service.rotate_operator_key(NEW_KEY).await,
+            Err(PayoutError::NotAuthorized { operator }) if operator == NEW_OPERATOR
+        ));
+        assert!(service
+            .operator_address()
+            .eq_ignore_ascii_case(TEST_OPERATOR));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_rotate_past_an_injected_signer() {
+        let signer = LocalSigner::from_hex(NEW_KEY).unwrap();
+        let service = Arc::new(test_service(test_config(), node()).with_signer(Arc::new(signer)));
+        assert!(matches!(
+            service.rotate_operator_key(TEST_OPERATOR_KEY).await,
+            Err(PayoutError::Config(message)) if message.contains("injected signer")
+        ));
+        assert_eq!(service.operator_address(), NEW_OPERATOR);
+    }
+}
@@ -0,0 +1,90 @@
+//! Rounding residue carried between a recipient's payouts
+//!
+//! Rounding a converted amount leaves a fraction of a base unit unpaid, or
+//! overpaid when rounding up. With `carry_rounding_residue`, these fractions
+//! are summed per recipient and asset, and each whole unit they add up to is
+//! added to, or taken from, the recipient's next converted payout. Balances
+//! are kept in memory, spilling to the store beyond its capacity, so a
+//! fraction not yet carried is lost on restart unless it was spilled.
+
+use super::recipient_state::SpilledState;
+use super::{Conversion, EthereumDestination, EthereumPayoutService};
+use serde_json::{json, Value};
+use std::convert::TryFrom;
+
+/// Decimals residue balances are kept in, whatever the scale of the rates
+const RESIDUE_SCALE: u32 = 18;
+
+/// Residue of a recipient in an asset, in `10^-RESIDUE_SCALE` base units
+#[derive(Debug, Default)]
+pub(super) struct Residue(i128);
+
+impl SpilledState for Residue {
+    fn to_json(&self) -> Value {
+        json!(self.0.to_string())
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        value.as_str()?.parse().ok().map(Residue)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+fn ledger_key(eth_dest: &EthereumDestination) -> String {
+    format!(
+        "{}/{}",
+        eth_dest.recipient.to_ascii_lowercase(),
+        eth_dest.asset_code
+    )
+}
+
+/// A conversion's residue in `10^-RESIDUE_SCALE` base units
+fn normalized_residue(conversion: &Conversion) -> i128 {
+    let scale = u32::from(conversion.rate.scale);
+    if scale <= RESIDUE_SCALE {
+        conversion.residue * 10i128.pow(RESIDUE_SCALE - scale)
+    } else {
+        conversion.residue / 10i128.pow((scale - RESIDUE_SCALE).min(38))
+    }
+}
+
+impl EthereumPayoutService {
+    /// Carry the whole units of the recipient's residue, including this
+    /// conversion's, into `conversion`
+    pub(super) fn carry_residue(
+        &self,
+        eth_dest: &EthereumDestination,
+        conversion: &mut Conversion,
+    ) {
+        if !self.config.carry_rounding_residue {
+            return;
+        }
+        let balance = self.rounding_residue(eth_dest) + normalized_residue(conversion);
+        let units =
+            (balance / 10i128.pow(RESIDUE_SCALE)).max(-i128::from(conversion.rounded_amount));
+        conversion.carried = i64::try_from(units).unwrap_or(0);
+    }
+
+    /// Update the recipient's residue once the conversion's amount was paid
+    pub(super) fn settle_residue(&self, eth_dest: &EthereumDestination, conversion: &Conversion) {
+        if !self.config.carry_rounding_residue {
+            return;
+        }
+        let change = normalized_residue(conversion)
+            - i128::from(conversion.carried) * 10i128.pow(RESIDUE_SCALE);
+        self.residue
+            .with(&*self.store, &ledger_key(eth_dest), |residue| {
+                residue.0 += change
+            });
+    }
+
+    /// Rounding residue not yet carried into a payout to the recipient, in
+    /// `10^-18` base units; negative if rounding overpaid
+    pub fn rounding_residue(&self, eth_dest: &EthereumDestination) -> i128 {
+        self.residue
+            .with(&*self.store, &ledger_key(eth_dest), |residue| residue.0)
+    }
+}
@@ -0,0 +1,418 @@
+//! JSON-RPC transport used to talk to the Ethereum node
+
+use super::connection::{is_stale_connection, HttpClientFactory};
+use super::provider::is_batch_refusal;
+use super::throttle::{endpoint_label, parse_retry_after};
+use super::{EthereumPayoutService, PayoutError};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::RwLock;
+use std::time::Instant;
+use tracing::{debug, warn};
+
+/// Sends JSON-RPC request bodies to an Ethereum node
+#[async_trait]
+pub trait RpcTransport: Send + Sync {
+    /// Send a request body and return the raw response body
+    async fn send(&self, request: Value) -> Result<Value, PayoutError>;
+
+    /// Drop pooled connections, so the next request resolves the host again
+    /// and opens a new one
+    fn reset(&self) {}
+}
+
+/// Transport posting requests to an HTTP JSON-RPC endpoint
+pub struct HttpTransport {
+    client: RwLock<reqwest::Client>,
+    /// Builds the client replacing the current one on reset, `None` to keep it
+    build: Option<HttpClientFactory>,
+    url: String,
+}
+
+impl HttpTransport {
+    pub fn new(client: reqwest::Client, url: impl Into<String>) -> Self {
+        HttpTransport {
+            client: RwLock::new(client),
+            build: None,
+            url: url.into(),
+        }
+    }
+
+    /// Transport with a client from `build`, replaced by a new one from
+    /// `build` whenever the transport is reset
+    pub fn rebuilding(build: HttpClientFactory, url: impl Into<String>) -> Self {
+        HttpTransport {
+            client: RwLock::new(build()),
+            build: Some(build),
+            url: url.into(),
+        }
+    }
+
+    /// `StaleConnection` for failures of a closed pooled connection, `Transport` otherwise
+    fn send_error(&self, err: reqwest::Error) -> PayoutError {
+        if !err.is_timeout() && is_stale_connection(&err) {
+            return PayoutError::StaleConnection {
+                endpoint: endpoint_label(&self.url),
+                message: err.to_string(),
+            };
+        }
+        err.into()
+    }
+}
+
+#[async_trait]
+impl RpcTransport for HttpTransport {
+    async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let started = Instant::now();
+        let client = self.client.read().unwrap().clone();
+        let http_request = client.post(&self.url).json(&request);
+        #[cfg(feature = "otel")]
+        let http_request = super::trace::inject(http_request);
+        let response = http_request
+            .send()
+            .await
+            .map_err(|err| self.send_error(err))?;
+        debug!(
+            rpc.method = %method,
+            rpc.duration_ms = started.elapsed().as_millis() as u64,
+            "JSON-RPC request completed"
+        );
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| parse_retry_after(value, chrono::Utc::now()));
+            return Err(PayoutError::Throttled {
+                endpoint: endpoint_label(&self.url),
+                retry_after,
+            });
+        }
+        Ok(response.json().await?)
+    }
+
+    fn reset(&self) {
+        if let Some(build) = &self.build {
+            *self.client.write().unwrap() = build();
+        }
+    }
+}
+
+/// Build a JSON-RPC request body; the service replaces the `id` with a fresh one when sending
+pub fn rpc_request(method: &str, params: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1
+    })
+}
+
+/// Extract the `result` of a JSON-RPC response, turning `error` objects into [`PayoutError::Rpc`]
+pub fn rpc_result(response: Value) -> Result<Value, PayoutError> {
+    if let Some(error) = response.get("error") {
+        return Err(PayoutError::Rpc {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"]
+                .as_str()
+                .unwrap_or("Unknown error")
+                .to_string(),
+            data: error["data"].as_str().map(str::to_string),
+        });
+    }
+    match response.get("result") {
+        Some(result) => Ok(result.clone()),
+        None => Err(PayoutError::InvalidResponse(
+            "No result in response".to_string(),
+        )),
+    }
+}
+
+/// Check that a response answers the request with the given id
+///
+/// Nodes answer requests they could not parse with a `null` id, so error
+/// responses without an id are let through.
+pub fn check_response_id(response: &Value, id: u64) -> Result<(), PayoutError> {
+    match response.get("id") {
+        None | Some(Value::Null) if response.get("error").is_some() => Ok(()),
+        Some(received) if received.as_u64() == Some(id) => Ok(()),
+        received => Err(PayoutError::Transport(format!(
+            "Response id {} does not match request id {}",
+            received.unwrap_or(&Value::Null),
+            id
+        ))),
+    }
+}
+
+/// [`rpc_result`] of a response that must answer the request with the given id
+pub fn rpc_response(response: Value, id: u64) -> Result<Value, PayoutError> {
+    check_response_id(&response, id)?;
+    rpc_result(response)
+}
+
+/// Parse a 0x-prefixed hex quantity such as a nonce or gas price
+pub fn parse_quantity(value: &Value) -> Result<u64, PayoutError> {
+    let hex = value
+        .as_str()
+        .ok_or_else(|| PayoutError::InvalidResponse(format!("Expected hex quantity: {}", value)))?;
+    u64::from_str_radix(hex.trim_start_matches("0x"), 16)
+        .map_err(|_| PayoutError::InvalidResponse(format!("Invalid hex quantity: {}", hex)))
+}
+
+impl EthereumPayoutService {
+    /// Reserve `count` consecutive request ids, returning the first
+    pub(super) fn next_rpc_ids(&self, count: u64) -> u64 {
+        self.rpc_ids.fetch_add(count, Ordering::Relaxed)
+    }
+
+    /// Send several requests as one JSON-RPC batch, matching each response by id
+    ///
+    /// The outer error fails the batch as a whole. Members the node did not
+    /// answer fail individually with [`PayoutError::Transport`]. Providers
+    /// refusing batches are sent each request on its own instead.
+    pub async fn rpc_batch(
+        &self,
+        requests: Vec<Value>,
+    ) -> Result<Vec<Result<Value, PayoutError>>, PayoutError> {
+        if self.provider_capabilities().batch != Some(false) {
+            match self.send_batch(requests.clone()).await {
+                Err(err) if is_batch_refusal(&err) => {
+                    warn!(
+                        "RPC provider refused a batch, sending requests one by one: {}",
+                        err
+                    );
+                    self.note_provider(|known| known.batch = Some(false));
+                }
+                result => return result,
+            }
+        }
+        let mut answers = Vec::with_capacity(requests.len());
+        for request in requests {
+            answers.push(self.rpc(request).await);
+        }
+        Ok(answers)
+    }
+
+    /// Send `requests` as one batch, whatever the provider was found to support
+    pub(super) async fn send_batch(
+        &self,
+        requests: Vec<Value>,
+    ) -> Result<Vec<Result<Value, PayoutError>>, PayoutError> {
+        if requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.wait_for_throttle().await?;
+        let first = self.next_rpc_ids(requests.len() as u64);
+        let ids = first..first + requests.len() as u64;
+        let batch = requests
+            .into_iter()
+            .zip(ids.clone())
+            .map(|(mut request, id)| {
+                request["id"] = json!(id);
+                request
+            })
+            .collect();
+        let timeout = self.config.read_timeout;
+        let response = tokio::time::timeout(timeout, self.transport.send(Value::Array(batch)))
+            .await
+            .unwrap_or(Err(PayoutError::TimedOut {
+                method: "batch".to_string(),
+                after: timeout,
+            }))
+            .inspect_err(|err| {
+                if err.is_throttle() {
+                    self.note_throttle(err);
+                }
+            })?;
+        *self.last_rpc_response.lock().unwrap() = Some(self.clock.now());
+
+        let members = match response {
+            Value::Array(members) => members,
+            // A batch refused as a whole is answered with a single error
+            other => {
+                return Err(rpc_result(other).err().unwrap_or_else(|| {
+                    PayoutError::InvalidResponse("Batch answered with a single result".to_string())
+                }))
+            }
+        };
+        let mut answers: HashMap<u64, Value> = members
+            .into_iter()
+            .filter_map(|member| Some((member["id"].as_u64()?, member)))
+            .collect();
+        Ok(ids
+            .map(|id| match answers.remove(&id) {
+                Some(answer) => rpc_result(answer),
+                None => Err(PayoutError::Transport(format!(
+                    "No response to batched request id {}",
+                    id
+                ))),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, MockTransport};
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    #[test]
+    fn extracts_results_and_errors() {
+        let ok = json!({"jsonrpc": "2.0", "id": 1, "result": "0x2a"});
+        assert_eq!(parse_quantity(&rpc_result(ok).unwrap()).unwrap(), 42);
+
+        let err = json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "nope"}});
+        match rpc_result(err) {
+            Err(PayoutError::Rpc { code, message, .. }) => {
+                assert_eq!(code, -32000);
+                assert_eq!(message, "nope");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(rpc_result(json!({"jsonrpc": "2.0", "id": 1})).is_err());
+        assert!(parse_quantity(&json!("0xzz")).is_err());
+    }
+
+    #[test]
+    fn matches_response_ids() {
+        assert!(rpc_response(json!({"id": 7, "result": "0x1"}), 7).is_ok());
+        assert!(matches!(
+            rpc_response(json!({"id": 8, "result": "0x1"}), 7),
+            Err(PayoutError::Transport(_))
+        ));
+        assert!(check_response_id(&json!({"id": "7", "result": "0x1"}), 7).is_err());
+        assert!(check_response_id(&json!({"result": "0x1"}), 7).is_err());
+        // Parse errors are answered without the request's id
+        let parse_error = json!({"id": null, "error": {"code": -32700, "message": "Parse error"}});
+        assert!(matches!(
+            rpc_response(parse_error, 7),
+            Err(PayoutError::Rpc { code: -32700, .. })
+        ));
+    }
+
+    /// Answers through a mock, but with the wrong id for the first `mismatches` requests
+    struct MismatchedIds {
+        inner: Arc<MockTransport>,
+        mismatches: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RpcTransport for MismatchedIds {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            let mut response = self.inner.send(request).await?;
+            let remaining = self.mismatches.load(Ordering::SeqCst);
+            if remaining > 0 {
+                self.mismatches.store(remaining - 1, Ordering::SeqCst);
+                response["id"] = json!(response["id"].as_u64().unwrap() + 1000);
+            }
+            Ok(response)
+        }
+    }
+
+    fn mismatching_service(mismatches: usize) -> (EthereumPayoutService, Arc<MockTransport>) {
+        let mock = MockTransport::new();
+        mock.on_result("eth_blockNumber", json!("0x2a"));
+        let transport = Arc::new(MismatchedIds {
+            inner: mock.clone(),
+            mismatches: AtomicUsize::new(mismatches),
+        });
+        let service = EthereumPayoutService::new(test_config())
+            .unwrap()
+            .with_transport(transport);
+        (service, mock)
+    }
+
+    #[tokio::test]
+    async fn resends_once_on_a_mismatched_response_id() {
+        let (service, mock) = mismatching_service(1);
+        let result = service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap();
+        assert_eq!(result, json!("0x2a"));
+        let ids: Vec<Value> = mock
+            .calls("eth_blockNumber")
+            .iter()
+            .map(|call| call["id"].clone())
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+
+        let (service, _) = mismatching_service(2);
+        let err = service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::Transport(_)), "{:?}", err);
+    }
+
+    /// Answers batches through a mock in reverse order, dropping one member
+    struct ReversedBatches {
+        inner: Arc<MockTransport>,
+        drop_method: &'static str,
+    }
+
+    #[async_trait]
+    impl RpcTransport for ReversedBatches {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            let batch = match request {
+                Value::Array(batch) => batch,
+                single => return self.inner.send(single).await,
+            };
+            let mut answers = Vec::new();
+            for member in batch.into_iter().rev() {
+                if member["method"] != self.drop_method {
+                    answers.push(self.inner.send(member).await?);
+                }
+            }
+            Ok(Value::Array(answers))
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_batch_members_by_id() {
+        let mock = MockTransport::new();
+        mock.on_result("eth_chainId", json!("0x7a69"));
+        mock.on_result("eth_blockNumber", json!("0x2a"));
+        mock.on_error("eth_gasPrice", -32000, "unavailable");
+        let service = EthereumPayoutService::new(test_config())
+            .unwrap()
+            .with_transport(Arc::new(ReversedBatches {
+                inner: mock.clone(),
+                drop_method: "net_version",
+            }));
+        service
+            .rpc(rpc_request("eth_chainId", json!([])))
+            .await
+            .unwrap();
+
+        let results = service
+            .rpc_batch(vec![
+                rpc_request("eth_chainId", json!([])),
+                rpc_request("eth_blockNumber", json!([])),
+                rpc_request("eth_gasPrice", json!([])),
+                rpc_request("net_version", json!([])),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(results[0].as_ref().unwrap(), &json!("0x7a69"));
+        assert_eq!(results[1].as_ref().unwrap(), &json!("0x2a"));
+        assert!(matches!(
+            results[2],
+            Err(PayoutError::Rpc { code: -32000, .. })
+        ));
+        assert!(matches!(results[3], Err(PayoutError::Transport(_))));
+
+        // Contiguous ids following the single request before the batch
+        let first = mock.calls("eth_chainId")[0]["id"].as_u64().unwrap();
+        assert_eq!(mock.calls("eth_chainId")[1]["id"], json!(first + 1));
+        assert_eq!(mock.calls("eth_blockNumber")[0]["id"], json!(first + 2));
+        assert_eq!(mock.calls("eth_gasPrice")[0]["id"], json!(first + 3));
+    }
+}
@@ -0,0 +1,400 @@
+//! Gnosis Safe proposals for large payouts
+//!
+//! Payouts above the configured threshold are not sent with the hot operator
+//! key. The `payoutToUser` call is wrapped in a Safe transaction, signed by the
+//! operator as the first owner confirmation, and proposed to the Safe
+//! Transaction Service where the remaining owners approve it.
+
+use super::abi::{decode_words, encode_address, encode_call, encode_uint, selector};
+use super::eip712::{hash_struct, typed_data_hash, Eip712Domain};
+use super::hash::keccak256;
+use super::{
+    EthereumPayoutService, PayoutError, PayoutOutcome, PayoutPlan, PayoutRequest, PayoutStatus,
+};
+use serde_json::{json, Value};
+use tracing::info;
+
+/// EIP-712 type of a Safe transaction (Safe contracts v1.3 and later)
+pub const SAFE_TX_TYPE: &str = "SafeTx(address to,uint256 value,bytes data,uint8 operation,uint256 safeTxGas,uint256 baseGas,uint256 gasPrice,address gasToken,address refundReceiver,uint256 nonce)";
+
+const ZERO_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+
+/// Where and when payouts are proposed to a Safe instead of sent directly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SafeConfig {
+    /// Address of the Safe holding the Treasury's operator role
+    pub address: String,
+    /// Base URL of the Safe Transaction Service, e.g. `https://safe-transaction-mainnet.safe.global`
+    pub service_url: String,
+    /// Payouts with an amount above this go through the Safe
+    pub threshold: u64,
+}
+
+/// A Safe transaction calling a contract without gas refunds
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SafeTx {
+    pub to: String,
+    pub value: u64,
+    /// 0x-prefixed call data
+    pub data: String,
+    /// 0 for CALL, 1 for DELEGATECALL
+    pub operation: u8,
+    pub nonce: u64,
+}
+
+impl SafeTx {
+    /// A plain CALL of `to` with `data`
+    pub fn call(to: impl Into<String>, data: impl Into<String>, nonce: u64) -> Self {
+        SafeTx {
+            to: to.into(),
+            value: 0,
+            data: data.into(),
+            operation: 0,
+            nonce,
+        }
+    }
+
+    pub fn struct_hash(&self) -> Result<[u8; 32], PayoutError> {
+        let to = encode_address(&self.to)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid Safe call target {}", self.to)))?;
+        let data = hex::decode(self.data.trim_start_matches("0x"))
+            .map_err(|_| PayoutError::Config("Safe call data is not hex".to_string()))?;
+        let zero = [0u8; 32];
+        Ok(hash_struct(
+            &keccak256(SAFE_TX_TYPE.as_bytes()),
+            &[
+                to,
+                encode_uint(self.value.into()),
+                keccak256(&data),
+                encode_uint(self.operation.into()),
+                zero, // safeTxGas
+                zero, // baseGas
+                zero, // gasPrice
+                zero, // gasToken
+                zero, // refundReceiver
+                encode_uint(self.nonce.into()),
+            ],
+        ))
+    }
+
+    /// The `safeTxHash` owners sign, for the Safe at `safe` on `chain_id`
+    pub fn signing_hash(&self, chain_id: u64, safe: &str) -> Result<[u8; 32], PayoutError> {
+        let domain = Eip712Domain {
+            chain_id: Some(chain_id),
+            verifying_contract: Some(safe.to_string()),
+            ..Eip712Domain::default()
+        };
+        Ok(typed_data_hash(&domain.separator()?, &self.struct_hash()?))
+    }
+
+    /// Request body for `POST /api/v1/safes/{safe}/multisig-transactions/`
+    pub fn proposal(&self, safe_tx_hash: &[u8; 32], sender: &str, signature: &str) -> Value {
+        json!({
+            "to": self.to,
+            "value": self.value.to_string(),
+            "data": self.data,
+            "operation": self.operation,
+            "safeTxGas": "0",
+            "baseGas": "0",
+            "gasPrice": "0",
+            "gasToken": ZERO_ADDRESS,
+            "refundReceiver": ZERO_ADDRESS,
+            "nonce": self.nonce,
+            "contractTransactionHash": format!("0x{}", hex::encode(safe_tx_hash)),
+            "sender": sender,
+            "signature": signature,
+            "origin": "interledger-ethereum-payout"
+        })
+    }
+}
+
+impl EthereumPayoutService {
+    /// Propose `plan` to the Safe and record it as pending approval
+    pub(super) async fn propose_to_safe(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        safe: &SafeConfig,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        // Held until the proposal is accepted, so concurrent payouts take
+        // successive nonces instead of racing for the same one
+        let mut local_nonce = self.safe_nonce.lock().await;
+        if let Some(existing) = self.lookup(&plan.payment_id) {
+            if existing.status == PayoutStatus::PendingApproval {
+                if let Some(safe_tx_hash) = existing.safe_tx_hash {
+                    return Ok(PayoutOutcome::PendingApproval { safe_tx_hash });
+                }
+            }
+        }
+
+        let nonce = self.next_safe_nonce(safe, *local_nonce).await?;
+        let tx = SafeTx::call(plan.to.clone(), plan.data.clone(), nonce);
+        let hash = tx.signing_hash(self.config.expected_chain_id, &safe.address)?;
+        let signer = self.operator_signer()?;
+        let signature = signer.sign_normalized(&hash).await?;
+        let body = tx.proposal(&hash, &signer.address(), &signature.to_hex());
+
+        let url = format!(
+            "{}/api/v1/safes/{}/multisig-transactions/",
+            safe.service_url.trim_end_matches('/'),
+            safe.address
+        );
+        let response = self.http.post(&url).json(&body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await.unwrap_or_default();
+            return Err(PayoutError::InvalidResponse(format!(
+                "Safe Transaction Service returned {}: {}",
+                status, text
+            )));
+        }
+        *local_nonce = Some(local_nonce.unwrap_or(0).max(nonce + 1));
+        drop(local_nonce);
+
+        let safe_tx_hash = format!("0x{}", hex::encode(hash));
+        info!(
+            "Proposed payout of {} to Safe {} (safeTxHash {}, nonce {})",
+            plan.amount, safe.address, safe_tx_hash, nonce
+        );
+        let mut record = self.payout_record(request, plan, None, PayoutStatus::PendingApproval);
+        record.safe_tx_hash = Some(safe_tx_hash.clone());
+        self.store.save(record);
+        Ok(PayoutOutcome::PendingApproval { safe_tx_hash })
+    }
+
+    /// The Safe's on-chain nonce, advanced past proposals this service made
+    /// that have not been executed yet
+    async fn next_safe_nonce(
+        &self,
+        safe: &SafeConfig,
+        local: Option<u64>,
+    ) -> Result<u64, PayoutError> {
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({
+                    "to": &safe.address,
+                    "data": encode_call(selector("nonce()"), &[]),
+                })],
+            )
+            .await?;
+        let word = result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .filter(|word| word[..24].iter().all(|b| *b == 0))
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("nonce returned {}", result)))?;
+        let mut low = [0u8; 8];
+        low.copy_from_slice(&word[24..]);
+        let on_chain = u64::from_be_bytes(low);
+        Ok(on_chain.max(local.unwrap_or(0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION, TEST_OPERATOR,
+        TEST_TREASURY,
+    };
+    use super::*;
+    use mockito::{mock, Matcher};
+
+    const SAFE: &str = "0x1111111111111111111111111111111111111111";
+
+    fn safe_config(threshold: u64) -> super::super::EthereumPayoutConfig {
+        let mut config = test_config();
+        config.safe = Some(SafeConfig {
+            address: SAFE.to_string(),
+            service_url: mockito::server_url(),
+            threshold,
+        });
+        config
+    }
+
+    #[test]
+    fn type_hashes_match_safe_contracts() {
+        // SAFE_TX_TYPEHASH and DOMAIN_SEPARATOR_TYPEHASH from Safe.sol
+        assert_eq!(
+            hex::encode(keccak256(SAFE_TX_TYPE.as_bytes())),
+            "bb8310d486368db6bd6f849402fdd73ad53d316b5a4b2644ad6efe0f941286d8"
+        );
+        assert_eq!(
+            hex::encode(keccak256(
+                b"EIP712Domain(uint256 chainId,address verifyingContract)"
+            )),
+            "47e79534a245952e8b16893a336b85a3d9ea9fa8c573f3d803afb92a79469218"
+        );
+    }
+
+    #[test]
+    fn signing_hash_commits_to_every_field() {
+        let tx = SafeTx::call(TEST_TREASURY, "0xb77276d8", 3);
+        let hash = tx.signing_hash(31337, SAFE).unwrap();
+        assert_ne!(
+            hash,
+            SafeTx {
+                nonce: 4,
+                ..tx.clone()
+            }
+            .signing_hash(31337, SAFE)
+            .unwrap()
+        );
+        assert_ne!(hash, tx.signing_hash(1, SAFE).unwrap());
+        assert_ne!(
+            hash,
+            SafeTx::call(TEST_TREASURY, "0xb77276d9", 3)
+                .signing_hash(31337, SAFE)
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn proposes_large_payouts_to_the_safe() {
+        let path = format!("/api/v1/safes/{}/multisig-transactions/", SAFE);
+        let proposal = mock("POST", path.as_str())
+            .match_body(Matcher::PartialJson(json!({
+                "to": TEST_TREASURY,
+                "value": "0",
+                "operation": 0,
+                "nonce": 5,
+                "sender": TEST_OPERATOR,
+                "safeTxGas": "0",
+                "gasToken": ZERO_ADDRESS,
+            })))
+            .with_status(201)
+            .create();
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 5)));
+        let service = test_service(safe_config(1_000), transport.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 5_000, 1)
+            .await
+            .unwrap();
+        proposal.assert();
+        let safe_tx_hash = match outcome {
+            PayoutOutcome::PendingApproval { safe_tx_hash } => safe_tx_hash,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+
+        let plan = super::super::plan_payout(service.config(), TEST_DESTINATION, 5_000, 1).unwrap();
+        let expected = SafeTx::call(TEST_TREASURY, plan.data, 5)
+            .signing_hash(31337, SAFE)
+            .unwrap();
+        assert_eq!(safe_tx_hash, format!("0x{}", hex::encode(expected)));
+        let record = service.store().get(&plan.payment_id).unwrap();
+        assert_eq!(record.status, PayoutStatus::PendingApproval);
+        assert_eq!(record.safe_tx_hash, Some(safe_tx_hash.clone()));
+
+        // Replaying the payout does not propose it twice
+        let replay = service
+            .execute_payout(TEST_DESTINATION, 5_000, 1)
+            .await
+            .unwrap();
+        assert_eq!(replay, PayoutOutcome::PendingApproval { safe_tx_hash });
+        proposal.assert();
+    }
+
+    #[tokio::test]
+    async fn concurrent_proposals_take_successive_nonces() {
+        const BUSY_SAFE: &str = "0x3333333333333333333333333333333333333333";
+        let path = format!("/api/v1/safes/{}/multisig-transactions/", BUSY_SAFE);
+        let proposal = mock("POST", path.as_str())
+            .with_status(201)
+            .expect(2)
+            .create();
+        let mut config = safe_config(1_000);
+        config.safe.as_mut().unwrap().address = BUSY_SAFE.to_string();
+        let transport = MockTransport::new();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 5)));
+        let service = test_service(config, transport);
+
+        let (first, second) = tokio::join!(
+            service.execute_payout(TEST_DESTINATION, 5_000, 1),
+            service.execute_payout(TEST_DESTINATION, 6_000, 2),
+        );
+        proposal.assert();
+        let mut nonces = Vec::new();
+        for (outcome, amount, sequence) in [(first, 5_000, 1), (second, 6_000, 2)] {
+            let safe_tx_hash = match outcome.unwrap() {
+                PayoutOutcome::PendingApproval { safe_tx_hash } => safe_tx_hash,
+                other => panic!("unexpected {:?}", other),
+            };
+            let plan =
+                super::super::plan_payout(service.config(), TEST_DESTINATION, amount, sequence)
+                    .unwrap();
+            let nonce = (5..7)
+                .find(|nonce| {
+                    let hash = SafeTx::call(TEST_TREASURY, plan.data.clone(), *nonce)
+                        .signing_hash(31337, BUSY_SAFE)
+                        .unwrap();
+                    safe_tx_hash == format!("0x{}", hex::encode(hash))
+                })
+                .expect("proposed with an unexpected nonce");
+            nonces.push(nonce);
+        }
+        nonces.sort_unstable();
+        assert_eq!(nonces, vec![5, 6]);
+    }
+
+    #[tokio::test]
+    async fn split_chunks_pending_approval_are_reported_once() {
+        const SPLIT_SAFE: &str = "0x2222222222222222222222222222222222222222";
+        let path = format!("/api/v1/safes/{}/multisig-transactions/", SPLIT_SAFE);
+        let proposal = mock("POST", path.as_str())
+            .with_status(201)
+            .expect(2)
+            .create();
+        let mut config = safe_config(500);
+        config.safe.as_mut().unwrap().address = SPLIT_SAFE.to_string();
+        config.assets.apply_caps("EURC:600").unwrap();
+        let transport = mock_chain();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 5)));
+        let service = test_service(config, transport.clone());
+
+        // Two chunks of 600 go to the Safe, the last 300 is sent directly
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 1_500, 1)
+            .await
+            .unwrap();
+        let safe_tx_hashes = match &outcome {
+            PayoutOutcome::Split {
+                tx_hashes,
+                safe_tx_hashes,
+            } => {
+                assert_eq!(tx_hashes, &vec!["0xabc".to_string()]);
+                safe_tx_hashes.clone()
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(safe_tx_hashes.len(), 2);
+        assert_ne!(safe_tx_hashes[0], safe_tx_hashes[1]);
+
+        // A rerun proposes and sends nothing again
+        let calls = transport.call_count("eth_call");
+        let replay = service
+            .execute_payout(TEST_DESTINATION, 1_500, 1)
+            .await
+            .unwrap();
+        assert_eq!(replay, outcome);
+        proposal.assert();
+        assert_eq!(transport.call_count("eth_call"), calls);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn small_payouts_keep_the_direct_path() {
+        let transport = mock_chain();
+        let service = test_service(safe_config(1_000), transport.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 1_000, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        // Only the payout's simulation, nothing read from the Safe
+        assert_eq!(transport.call_count("eth_call"), 1);
+    }
+}
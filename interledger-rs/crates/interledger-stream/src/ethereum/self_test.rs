@@ -0,0 +1,500 @@
+//! Self-test run before a service takes traffic
+//!
+//! [`EthereumPayoutService::self_test`] checks, one after another, that the
+//! node is on the expected chain, that the Treasury has code and answers the
+//! capability probes, that the operator has a balance to pay gas with, that
+//! the signer signs a throwaway digest and that the store reads back what it
+//! wrote. On dev chains it may also pay a canary recipient a tiny amount end
+//! to end. The canary's payment ID is derived in a namespace of its own,
+//! salted per run, so it never collides with a payout's. All checks share
+//! one time budget; a check still running when it is spent fails as timed
+//! out. With `self_test` configured, `init_payout_service` only publishes a
+//! service whose self-test passed, and retries otherwise.
+
+use super::hash::keccak256;
+use super::rpc::{parse_quantity, rpc_request};
+use super::{
+    EthereumPayoutService, PayoutError, PayoutRecord, PayoutRequest, PayoutStatus, PhaseTimings,
+};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+/// Chain IDs of local development chains, on which a canary payout may be sent
+pub const DEV_CHAIN_IDS: [u64; 2] = [1337, 31337];
+
+/// Prefix of the data canary payment IDs are hashed from
+const CANARY_NAMESPACE: &[u8] = b"interledger payout self-test\0";
+
+/// Tiny payout sent end to end by the self-test
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanaryPayout {
+    /// ILP destination of the canary recipient
+    pub destination: String,
+    /// Amount in the destination asset's base units
+    pub amount: u64,
+}
+
+/// What a self-test runs and how long it may take
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestOptions {
+    /// Time all checks may take together
+    pub timeout: Duration,
+    /// Payout sent on dev chains, `None` to skip it
+    pub canary: Option<CanaryPayout>,
+}
+
+impl Default for SelfTestOptions {
+    fn default() -> Self {
+        SelfTestOptions {
+            timeout: Duration::from_secs(30),
+            canary: None,
+        }
+    }
+}
+
+/// Outcome of one self-test check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckResult {
+    Passed(String),
+    Failed(String),
+    /// Not run, e.g. the canary payout on a chain that is not a dev chain
+    Skipped(String),
+}
+
+impl CheckResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckResult::Passed(_) => "passed",
+            CheckResult::Failed(_) => "failed",
+            CheckResult::Skipped(_) => "skipped",
+        }
+    }
+
+    pub fn detail(&self) -> &str {
+        match self {
+            CheckResult::Passed(detail)
+            | CheckResult::Failed(detail)
+            | CheckResult::Skipped(detail) => detail,
+        }
+    }
+}
+
+/// One check of a self-test and how long it took
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestCheck {
+    pub name: &'static str,
+    pub result: CheckResult,
+    pub duration: Duration,
+}
+
+/// Results of [`EthereumPayoutService::self_test`], in the order checks ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &SelfTestCheck> {
+        self.checks
+            .iter()
+            .filter(|check| matches!(check.result, CheckResult::Failed(_)))
+    }
+
+    pub fn check(&self, name: &str) -> Option<&SelfTestCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let checks: Vec<Value> = self
+            .checks
+            .iter()
+            .map(|check| {
+                json!({
+                    "name": check.name,
+                    "result": check.result.as_str(),
+                    "detail": check.result.detail(),
+                    "duration_ms": check.duration.as_millis() as u64,
+                })
+            })
+            .collect();
+        json!({ "passed": self.passed(), "checks": checks })
+    }
+}
+
+/// Collects check results against the self-test's time budget
+struct Checks {
+    deadline: Instant,
+    checks: Vec<SelfTestCheck>,
+}
+
+impl Checks {
+    /// Run `check` in what is left of the budget and note its result
+    async fn run<F>(&mut self, name: &'static str, check: F)
+    where
+        F: Future<Output = CheckResult>,
+    {
+        let started = Instant::now();
+        let budget = self.deadline.saturating_duration_since(started);
+        let result = tokio::time::timeout(budget, check)
+            .await
+            .unwrap_or_else(|_| CheckResult::Failed("timed out".to_string()));
+        self.checks.push(SelfTestCheck {
+            name,
+            result,
+            duration: started.elapsed(),
+        });
+    }
+}
+
+/// Passed with `detail` if `result` is `Ok`, failed with the error otherwise
+fn checked(result: Result<String, PayoutError>) -> CheckResult {
+    match result {
+        Ok(detail) => CheckResult::Passed(detail),
+        Err(err) => CheckResult::Failed(err.to_string()),
+    }
+}
+
+impl EthereumPayoutService {
+    /// Check the node, Treasury, operator, signer and store before taking
+    /// traffic, and pay the canary on dev chains
+    pub async fn self_test(&self, options: &SelfTestOptions) -> SelfTestReport {
+        let mut checks = Checks {
+            deadline: Instant::now() + options.timeout,
+            checks: Vec::new(),
+        };
+        checks.run("chain_id", self.test_chain_id()).await;
+        checks.run("treasury_code", self.test_treasury_code()).await;
+        checks
+            .run("treasury_capabilities", self.test_capabilities())
+            .await;
+        checks.run("operator_balance", self.test_balance()).await;
+        checks.run("signer", self.test_signer()).await;
+        checks.run("store", async { self.test_store() }).await;
+        checks
+            .run("canary_payout", self.test_canary(options.canary.as_ref()))
+            .await;
+        SelfTestReport {
+            checks: checks.checks,
+        }
+    }
+
+    async fn test_chain_id(&self) -> CheckResult {
+        let expected = self.config.expected_chain_id;
+        match self
+            .rpc(rpc_request("eth_chainId", json!([])))
+            .await
+            .and_then(|result| parse_quantity(&result))
+        {
+            Ok(chain_id) if chain_id == expected => CheckResult::Passed(chain_id.to_string()),
+            Ok(chain_id) => {
+                CheckResult::Failed(format!("node is on chain {}, not {}", chain_id, expected))
+            }
+            Err(err) => CheckResult::Failed(err.to_string()),
+        }
+    }
+
+    async fn test_treasury_code(&self) -> CheckResult {
+        let treasury = &self.config.treasury_address;
+        let code = self
+            .rpc(rpc_request("eth_getCode", json!([treasury, "latest"])))
+            .await;
+        match code.as_ref().map(|code| code.as_str().unwrap_or_default()) {
+            Ok(code) if code.trim_start_matches("0x").is_empty() => {
+                CheckResult::Failed(format!("no code at {}", treasury))
+            }
+            Ok(code) => {
+                CheckResult::Passed(format!("{} bytes", code.trim_start_matches("0x").len() / 2))
+            }
+            Err(err) => CheckResult::Failed(err.to_string()),
+        }
+    }
+
+    async fn test_capabilities(&self) -> CheckResult {
+        let capabilities = self.probe_treasury().await;
+        let detail = format!(
+            "version {}, memo {:?}, batch {:?}, isProcessed {:?}",
+            capabilities.version.as_deref().unwrap_or("unknown"),
+            capabilities.memo,
+            capabilities.batch,
+            capabilities.is_processed
+        );
+        let probes = [
+            capabilities.memo,
+            capabilities.batch,
+            capabilities.is_processed,
+        ];
+        if capabilities.version.is_none() && probes.iter().all(Option::is_none) {
+            return CheckResult::Failed(format!("Treasury answered no probe: {}", detail));
+        }
+        CheckResult::Passed(detail)
+    }
+
+    async fn test_balance(&self) -> CheckResult {
+        let balance = self
+            .rpc(rpc_request(
+                "eth_getBalance",
+                json!([self.operator_address(), "latest"]),
+            ))
+            .await
+            .and_then(|result| {
+                let hex = result.as_str().unwrap_or_default().trim_start_matches("0x");
+                u128::from_str_radix(hex, 16).map_err(|_| {
+                    PayoutError::InvalidResponse(format!("Invalid balance: {}", result))
+                })
+            });
+        match (balance, self.config.balance_floor) {
+            (Ok(0), _) => CheckResult::Failed("operator has no balance to pay gas".to_string()),
+            (Ok(balance), Some(floor)) if balance < floor => CheckResult::Failed(format!(
+                "balance of {} wei is below the floor of {}",
+                balance, floor
+            )),
+            (Ok(balance), _) => CheckResult::Passed(format!("{} wei", balance)),
+            (Err(err), _) => CheckResult::Failed(err.to_string()),
+        }
+    }
+
+    async fn test_signer(&self) -> CheckResult {
+        let digest = keccak256(uuid::Uuid::new_v4().as_bytes());
+        checked(
+            self.sign_digest(&digest)
+                .await
+                .map(|()| format!("signed for {}", self.operator_address())),
+        )
+    }
+
+    /// Write a record under a throwaway payment ID, read it back and delete it
+    fn test_store(&self) -> CheckResult {
+        let payment_id = keccak256(uuid::Uuid::new_v4().as_bytes());
+        self.store.save(PayoutRecord {
+            payment_id,
+            namespace: None,
+            payment_id_strategy: None,
+            attribution: Default::default(),
+            destination: String::new(),
+            sequence: 0,
+            recipient: String::new(),
+            asset_code: String::new(),
+            asset_segment: None,
+            amount: 0,
+            decimals: 0,
+            tx_hash: None,
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: None,
+            memo: None,
+            status: PayoutStatus::Skipped,
+            deadline: None,
+            last_error: Some("self-test".to_string()),
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: self.clock.now(),
+            serial: 0,
+            pinned_block: None,
+            treasury_payout_id: None,
+        });
+        let read = self.store.get(&payment_id);
+        let removed = self.store.remove(&payment_id);
+        match (read, removed) {
+            (Some(_), Some(_)) => CheckResult::Passed(if self.store.is_persistent() {
+                "persistent".to_string()
+            } else {
+                "in memory".to_string()
+            }),
+            (None, _) => CheckResult::Failed("record written was not read back".to_string()),
+            (Some(_), None) => CheckResult::Failed("record written was not deleted".to_string()),
+        }
+    }
+
+    async fn test_canary(&self, canary: Option<&CanaryPayout>) -> CheckResult {
+        let canary = match canary {
+            Some(canary) => canary,
+            None => return CheckResult::Skipped("no canary recipient".to_string()),
+        };
+        let chain_id = self.config.expected_chain_id;
+        if !DEV_CHAIN_IDS.contains(&chain_id) && !self.config.dev_mode {
+            return CheckResult::Skipped(format!("chain {} is not a dev chain", chain_id));
+        }
+        let sequence = self
+            .store
+            .highest_sequence(&canary.destination)
+            .map_or(0, |highest| highest + 1);
+        let request = PayoutRequest::new(&canary.destination, canary.amount, sequence)
+            .with_payment_id(&self.canary_payment_id(&canary.destination));
+        let outcome = match request {
+            Ok(request) => self.execute(&request).await,
+            Err(err) => Err(err),
+        };
+        match outcome {
+            Ok(outcome) if !outcome.tx_hashes().is_empty() => {
+                CheckResult::Passed(outcome.tx_hashes().join(","))
+            }
+            Ok(outcome) => CheckResult::Failed(format!("nothing was sent: {:?}", outcome)),
+            Err(err) => CheckResult::Failed(err.to_string()),
+        }
+    }
+
+    /// Payment ID of a canary payout, unique to this run
+    fn canary_payment_id(&self, destination: &str) -> [u8; 32] {
+        let mut data = CANARY_NAMESPACE.to_vec();
+        data.extend_from_slice(destination.as_bytes());
+        data.push(0);
+        data.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+        self.config.payment_id_hash.digest(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Arc;
+
+    fn healthy_node() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on_result("eth_chainId", json!("0x7a69"));
+        transport.on_result("eth_getCode", json!("0x6080604052"));
+        transport.on_result("eth_getBalance", json!("0xde0b6b3a7640000"));
+        transport.on_result("eth_sign", json!(format!("0x{}", "11".repeat(65))));
+        transport
+    }
+
+    fn results(report: &SelfTestReport) -> Vec<(&'static str, &'static str)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.result.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn passes_and_pays_the_canary_in_its_own_namespace() {
+        let transport = healthy_node();
+        let service = test_service(test_config(), transport.clone());
+        let options = SelfTestOptions {
+            canary: Some(CanaryPayout {
+                destination: TEST_DESTINATION.to_string(),
+                amount: 1,
+            }),
+            ..SelfTestOptions::default()
+        };
+        let report = service.self_test(&options).await;
+        assert!(report.passed(), "{}", report.to_json());
+        assert_eq!(
+            results(&report),
+            [
+                ("chain_id", "passed"),
+                ("treasury_code", "passed"),
+                ("treasury_capabilities", "passed"),
+                ("operator_balance", "passed"),
+                ("signer", "passed"),
+                ("store", "passed"),
+                ("canary_payout", "passed"),
+            ]
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+        let record = service
+            .store()
+            .find_by_sequence(TEST_DESTINATION, 0)
+            .remove(0);
+        assert_ne!(
+            record.payment_id,
+            service.config().payment_id(TEST_DESTINATION, 0)
+        );
+        assert_eq!(record.payment_id_strategy.as_deref(), Some("external"));
+        // Nothing but the canary is left in the store
+        assert_eq!(service.store().highest_serial(), record.serial);
+
+        // Off dev chains the canary is never sent
+        let mut config = test_config();
+        config.expected_chain_id = 1;
+        let transport = healthy_node();
+        transport.on_result("eth_chainId", json!("0x1"));
+        let report = test_service(config, transport.clone())
+            .self_test(&options)
+            .await;
+        assert!(report.passed());
+        assert_eq!(
+            report.check("canary_payout").unwrap().result,
+            CheckResult::Skipped("chain 1 is not a dev chain".to_string())
+        );
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[tokio::test]
+    async fn reports_each_failing_check() {
+        let transport = healthy_node();
+        transport.on_result("eth_chainId", json!("0x1"));
+        transport.on_result("eth_getCode", json!("0x"));
+        transport.on_result("eth_getBalance", json!("0x0"));
+        transport.on_error(
+            "eth_sign",
+            -32000,
+            "authentication needed: password or unlock",
+        );
+        // The node's locked account signs the transactions
+        let mut config = test_config();
+        config.node_signing = true;
+        let report = test_service(config, transport)
+            .self_test(&SelfTestOptions::default())
+            .await;
+        assert!(!report.passed());
+        assert_eq!(
+            results(&report),
+            [
+                ("chain_id", "failed"),
+                ("treasury_code", "failed"),
+                ("treasury_capabilities", "passed"),
+                ("operator_balance", "failed"),
+                ("signer", "failed"),
+                ("store", "passed"),
+                ("canary_payout", "skipped"),
+            ]
+        );
+        assert_eq!(
+            report.check("chain_id").unwrap().result.detail(),
+            "node is on chain 1, not 31337"
+        );
+        let json = report.to_json();
+        assert_eq!(json["passed"], false);
+        assert_eq!(
+            json["checks"][1]["detail"],
+            format!("no code at {}", test_config().treasury_address)
+        );
+    }
+
+    #[tokio::test]
+    async fn checks_share_one_time_budget() {
+        let transport = healthy_node();
+        transport.delay("eth_chainId", Duration::from_millis(500));
+        let report = test_service(test_config(), transport)
+            .self_test(&SelfTestOptions {
+                timeout: Duration::from_millis(100),
+                canary: None,
+            })
+            .await;
+        let chain_id = report.check("chain_id").unwrap();
+        assert_eq!(
+            chain_id.result,
+            CheckResult::Failed("timed out".to_string())
+        );
+        assert!(chain_id.duration < Duration::from_millis(400));
+        // Once the budget is spent only checks needing no wait still pass
+        assert_eq!(report.check("store").unwrap().result.as_str(), "passed");
+    }
+}
@@ -0,0 +1,276 @@
+//! Sanity checks on the STREAM sequence of each destination
+//!
+//! Payment IDs are derived from the destination and sequence, so a sender
+//! reusing old sequences produces duplicate IDs. The highest sequence paid per
+//! destination comes from the store; sequences too far behind it are refused
+//! and exact repeats are reported.
+//!
+//! Connector logs name payouts by destination and sequence, so records can be
+//! looked up by them with [`EthereumPayoutService::find_by_sequence`], and the
+//! payout path logs and publishes both along with the payment ID.
+
+use super::recipient_state::SpilledState;
+use super::{chunk_payment_id, metrics, EthereumPayoutService, PayoutError, PayoutRecord};
+use serde_json::{json, Value};
+use tracing::{info_span, warn, Span};
+
+/// What has been seen for one destination since startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceStats {
+    /// Highest sequence with a payout record, `None` if there is none yet
+    pub highest: Option<u64>,
+    /// Payouts for a sequence that already had a record
+    pub repeated: u64,
+    /// Payouts refused for being too far behind `highest`
+    pub rejected: u64,
+}
+
+impl SpilledState for SequenceStats {
+    /// Only the counters, `highest` being read from the store
+    fn to_json(&self) -> Value {
+        json!({ "repeated": self.repeated, "rejected": self.rejected })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(SequenceStats {
+            highest: None,
+            repeated: value["repeated"].as_u64()?,
+            rejected: value["rejected"].as_u64()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.repeated == 0 && self.rejected == 0
+    }
+}
+
+impl EthereumPayoutService {
+    /// Sequence statistics for an ILP destination
+    pub fn sequence_stats(&self, destination: &str) -> SequenceStats {
+        let counters = self
+            .sequence_counters
+            .with(&*self.store, destination, |counters| *counters);
+        SequenceStats {
+            highest: self.store.highest_sequence(destination),
+            ..counters
+        }
+    }
+
+    /// Records of the payouts of `sequence` to destinations starting with
+    /// `destination_prefix`, e.g. every chunk of a split payout; an empty
+    /// prefix matches every destination
+    pub fn find_by_sequence(&self, destination_prefix: &str, sequence: u64) -> Vec<PayoutRecord> {
+        let namespace = self.config.payment_id_namespace();
+        self.store
+            .find_by_sequence(destination_prefix, sequence)
+            .into_iter()
+            .filter(|record| record.namespace == namespace)
+            .collect()
+    }
+
+    /// Span carrying the destination, sequence and payment ID into every log
+    /// line of a payout
+    pub(super) fn payout_span(&self, destination: &str, sequence: u64, payment_id: &str) -> Span {
+        let destination = self.display_destination(destination);
+        info_span!("payout", %destination, sequence, %payment_id)
+    }
+
+    /// Apply the configured replay window to a payout's sequence, returning
+    /// whether the sequence was already paid out
+    pub(super) fn check_sequence(
+        &self,
+        destination: &str,
+        sequence: u64,
+    ) -> Result<bool, PayoutError> {
+        let highest = match self.store.highest_sequence(destination) {
+            Some(highest) => highest,
+            None => return Ok(false),
+        };
+        if let Some(max_lag) = self.config.sequence_max_lag {
+            if sequence.saturating_add(max_lag) < highest {
+                return Err(PayoutError::SequenceOutOfWindow {
+                    destination: destination.to_string(),
+                    sequence,
+                    highest,
+                    max_lag,
+                });
+            }
+        }
+        let payment_id = self.config.payment_id(destination, sequence);
+        Ok(self.lookup(&payment_id).is_some()
+            || self.lookup(&chunk_payment_id(&payment_id, 0)).is_some())
+    }
+
+    /// Count the result of [`Self::check_sequence`] for an executed payout
+    pub(super) fn count_sequence(
+        &self,
+        destination: &str,
+        sequence: u64,
+        checked: Result<bool, &PayoutError>,
+    ) {
+        let count = |counter: fn(&mut SequenceStats) -> &mut u64| {
+            self.sequence_counters
+                .with(&*self.store, destination, |counters| {
+                    *counter(counters) += 1
+                })
+        };
+        match checked {
+            Ok(true) => {
+                count(|counters| &mut counters.repeated);
+                metrics::sequence_repeated(self.config.tenant.as_deref());
+                warn!(
+                    "Sequence {} for {} was already paid out; retrying idempotently",
+                    sequence,
+                    self.display_destination(destination)
+                );
+            }
+            Err(PayoutError::SequenceOutOfWindow {
+                highest, max_lag, ..
+            }) => {
+                count(|counters| &mut counters.rejected);
+                metrics::sequence_rejected(self.config.tenant.as_deref());
+                warn!(
+                    "Refusing payout for {}: sequence {} is more than {} behind {}",
+                    self.display_destination(destination),
+                    sequence,
+                    max_lag,
+                    highest
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Arc;
+
+    fn sequenced_service(max_lag: Option<u64>) -> (EthereumPayoutService, Arc<MockTransport>) {
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.sequence_max_lag = max_lag;
+        (test_service(config, transport.clone()), transport)
+    }
+
+    #[tokio::test]
+    async fn in_order_and_slightly_behind_sequences_pass() {
+        let (service, transport) = sequenced_service(Some(5));
+        for sequence in [1, 2, 3, 10, 7, 5] {
+            service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .unwrap();
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 6);
+        assert_eq!(
+            service.sequence_stats(TEST_DESTINATION),
+            SequenceStats {
+                highest: Some(10),
+                repeated: 0,
+                rejected: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn far_behind_sequence_is_refused() {
+        let (service, transport) = sequenced_service(Some(5));
+        service
+            .execute_payout(TEST_DESTINATION, 100, 20)
+            .await
+            .unwrap();
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 14)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::SequenceOutOfWindow {
+                sequence: 14,
+                highest: 20,
+                ..
+            }
+        ));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+        assert_eq!(service.sequence_stats(TEST_DESTINATION).rejected, 1);
+
+        // Other destinations have their own window
+        let other = TEST_DESTINATION.replace("abc123", "def456");
+        service.execute_payout(&other, 100, 1).await.unwrap();
+
+        // Without a window nothing is refused
+        let (service, _) = sequenced_service(None);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1_000)
+            .await
+            .unwrap();
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn repeated_sequence_is_counted() {
+        let (service, _) = sequenced_service(Some(5));
+        service
+            .execute_payout(TEST_DESTINATION, 100, 3)
+            .await
+            .unwrap();
+        service
+            .execute_payout(TEST_DESTINATION, 250, 3)
+            .await
+            .unwrap();
+        let stats = service.sequence_stats(TEST_DESTINATION);
+        assert_eq!((stats.highest, stats.repeated), (Some(3), 1));
+    }
+
+    #[tokio::test]
+    async fn finds_payouts_by_sequence() {
+        let (service, _) = sequenced_service(None);
+        let other = TEST_DESTINATION.replace("test.receiver", "test.other");
+        for (destination, sequence) in [
+            (TEST_DESTINATION, 4),
+            (other.as_str(), 4),
+            (TEST_DESTINATION, 5),
+        ] {
+            service
+                .execute_payout(destination, 100, sequence)
+                .await
+                .unwrap();
+        }
+        let ids = |records: Vec<PayoutRecord>| -> Vec<[u8; 32]> {
+            records.iter().map(|record| record.payment_id).collect()
+        };
+
+        assert_eq!(
+            ids(service.find_by_sequence("", 4)),
+            [
+                service.config().payment_id(TEST_DESTINATION, 4),
+                service.config().payment_id(&other, 4)
+            ]
+        );
+        assert_eq!(
+            ids(service.find_by_sequence("test.receiver.", 4)),
+            [service.config().payment_id(TEST_DESTINATION, 4)]
+        );
+        assert!(service.find_by_sequence("test.nobody.", 4).is_empty());
+        assert!(service.find_by_sequence("", 6).is_empty());
+
+        // An ID scheme over more than destination and sequence maps one
+        // sequence to several payouts
+        let mut record = service
+            .store()
+            .get(&service.config().payment_id(TEST_DESTINATION, 5))
+            .unwrap();
+        record.payment_id = [0x55; 32];
+        record.serial = 0;
+        service.store().save(record);
+        assert_eq!(service.find_by_sequence("test.receiver.", 5).len(), 2);
+    }
+}
@@ -0,0 +1,168 @@
+//! Cooperative shutdown through the host's cancellation token
+//!
+//! Polls, retries and background tasks stop waiting once the token passed to
+//! [`EthereumPayoutService::with_cancellation_token`] is cancelled. RPC requests
+//! in flight are left to finish, since dropping a send midway would leave it
+//! unknown whether the transaction was broadcast.
+
+use super::{EthereumPayoutService, PayoutOutcome, PayoutPlan, PayoutRequest, Timestamp};
+use std::future::Future;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+/// Run `future` to completion unless `token` is cancelled first
+pub(super) async fn unless_cancelled<F: Future>(
+    token: &CancellationToken,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        _ = token.cancelled() => None,
+        output = future => Some(output),
+    }
+}
+
+impl EthereumPayoutService {
+    /// Whether the host has asked the service to shut down
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Shut the service down as if the host had asked, stopping its
+    /// background tasks, e.g. once another service replaced it
+    pub(super) fn stop(&self) {
+        self.cancellation.cancel()
+    }
+
+    /// Sleep on the service clock, returning `false` if shutdown came first
+    pub(super) async fn sleep_unless_cancelled(&self, duration: Duration) -> bool {
+        unless_cancelled(&self.cancellation, self.clock.sleep(duration))
+            .await
+            .is_some()
+    }
+
+    /// Record a payout stopped by shutdown before anything was broadcast, so
+    /// `retry_failed` can pick it up again
+    pub(super) fn interrupted(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        deadline: Option<Timestamp>,
+    ) -> PayoutOutcome {
+        let mut record = self.failed_record(request, plan, &super::PayoutError::Cancelled);
+        record.deadline = deadline;
+        let payment_id = record.payment_id_hex();
+        info!("Payout {} interrupted by shutdown", payment_id);
+        self.store.save(record);
+        PayoutOutcome::Cancelled { payment_id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, TEST_DESTINATION,
+    };
+    use super::super::{InMemoryPayoutStore, PayoutError, PayoutStatus, PayoutStore};
+    use super::*;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn payment_id() -> [u8; 32] {
+        EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1)
+    }
+
+    #[tokio::test]
+    async fn cancelling_receipt_polling_leaves_the_payout_resumable() {
+        let store: Arc<dyn PayoutStore> = Arc::new(InMemoryPayoutStore::new());
+        let token = CancellationToken::new();
+        let transport = mock_chain();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (counter, cancel) = (polls.clone(), token.clone());
+        transport.on("eth_getTransactionReceipt", move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 2 {
+                cancel.cancel();
+            }
+            Ok(Value::Null)
+        });
+        let service = test_service(test_config(), transport)
+            .with_store(store.clone())
+            .with_clock(FakeClock::new())
+            .with_cancellation_token(token);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        let err = service.wait_for_receipt(&payment_id()).await.unwrap_err();
+        assert!(matches!(err, PayoutError::Cancelled));
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+        let record = store.get(&payment_id()).unwrap();
+        assert_eq!(record.status, PayoutStatus::Submitted);
+        assert_eq!(record.tx_hash.as_deref(), Some("0xabc"));
+
+        // After a restart the wait picks up where it stopped
+        let restarted = mock_chain();
+        restarted.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x1", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        let service = test_service(test_config(), restarted).with_store(store.clone());
+        let record = service.wait_for_receipt(&payment_id()).await.unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+    }
+
+    #[tokio::test]
+    async fn cancelling_a_retry_records_the_payout_as_failed() {
+        let token = CancellationToken::new();
+        let transport = mock_chain();
+        let cancel = token.clone();
+        transport.on("eth_getTransactionCount", move |_| {
+            cancel.cancel();
+            Err(json!({"code": -32603, "message": "internal error"}))
+        });
+        let mut config = test_config();
+        config.payout_deadline = Some(Duration::from_secs(600));
+        let service = test_service(config, transport.clone())
+            .with_clock(FakeClock::new())
+            .with_cancellation_token(token);
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let record = service.store().get(&payment_id()).unwrap();
+        assert_eq!(
+            outcome,
+            PayoutOutcome::Cancelled {
+                payment_id: record.payment_id_hex()
+            }
+        );
+        assert_eq!(transport.call_count("eth_getTransactionCount"), 1);
+        assert_eq!(record.status, PayoutStatus::Failed);
+        assert!(record.deadline.is_some());
+
+        transport.on_result("eth_getTransactionCount", json!("0x0"));
+        let outcome = service.retry_failed(&payment_id()).await.unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+    }
+
+    #[tokio::test]
+    async fn background_tasks_stop_on_cancellation() {
+        let token = CancellationToken::new();
+        let mut config = test_config();
+        config.gas_sample_interval = Some(Duration::from_millis(10));
+        let service =
+            Arc::new(test_service(config, mock_chain()).with_cancellation_token(token.clone()));
+
+        let sampler = service.spawn_gas_sampler().unwrap();
+        token.cancel();
+        tokio::time::timeout(Duration::from_secs(1), sampler)
+            .await
+            .expect("sampler stops on shutdown")
+            .unwrap();
+        assert_eq!(Arc::strong_count(&service), 1);
+    }
+}
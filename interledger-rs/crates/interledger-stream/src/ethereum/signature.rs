@@ -0,0 +1,350 @@
+//! Recoverable secp256k1 signatures and their `v` values
+//!
+//! Signers hand back signatures in several shapes: the local key knows its
+//! recovery id, remote and KMS signers may return only `r` and `s`, or a `v`
+//! in any of the conventions below, and `s` in either half of the curve
+//! order. [`Signature::normalize`] turns all of them into one form, with a low
+//! `s` as EIP-2 requires and the recovery id found by recovering the signing
+//! address and comparing it with the signer's, so a signature that recovers
+//! to anyone else is refused here rather than by the node with a cryptic
+//! error. [`Signature::v`] then encodes the recovery id as each use expects:
+//! `27 + recid` for `ecrecover`, `chainId * 2 + 35 + recid` for EIP-155 legacy
+//! transactions and the bare recovery id for typed transactions.
+
+use super::abi::to_checksum_address;
+use super::hash::keccak256;
+use super::rlp::{rlp_bytes, rlp_list, rlp_uint, unsigned_transaction};
+use super::{PayoutError, TxParams};
+use k256::ecdsa::{RecoveryId, Signature as EcdsaSignature, VerifyingKey};
+
+/// Largest chain ID whose EIP-155 `v` fits in a `u64` for either recovery id
+pub const MAX_EIP155_CHAIN_ID: u64 = (u64::MAX - 36) / 2;
+
+/// A 65-byte recoverable ECDSA signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub r: [u8; 32],
+    pub s: [u8; 32],
+    /// Recovery id plus 27, as expected by `ecrecover`
+    pub v: u8,
+}
+
+/// Where a signature's `v` goes, which decides how it encodes the recovery id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VEncoding {
+    /// `27 + recid`, for `ecrecover` and off-chain signatures
+    Ecrecover,
+    /// `chainId * 2 + 35 + recid`, for EIP-155 legacy transactions
+    Eip155 { chain_id: u64 },
+    /// The bare recovery id, for EIP-2718 typed transactions
+    Typed,
+}
+
+impl Signature {
+    /// `r || s || v` encoding used by Safe and most off-chain APIs
+    pub fn to_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[..32].copy_from_slice(&self.r);
+        bytes[32..64].copy_from_slice(&self.s);
+        bytes[64] = self.v;
+        bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.to_bytes()))
+    }
+
+    /// 0 or 1, the parity of the signature's `R` point
+    pub fn recovery_id(&self) -> u8 {
+        self.v.saturating_sub(27) & 1
+    }
+
+    /// `v` as `encoding` expects it, failing for chain IDs above
+    /// [`MAX_EIP155_CHAIN_ID`] whose EIP-155 `v` would overflow
+    pub fn v(&self, encoding: VEncoding) -> Result<u64, PayoutError> {
+        let recid = u64::from(self.recovery_id());
+        match encoding {
+            VEncoding::Ecrecover => Ok(27 + recid),
+            VEncoding::Eip155 { chain_id } => chain_id
+                .checked_mul(2)
+                .and_then(|v| v.checked_add(35 + recid))
+                .ok_or_else(|| {
+                    PayoutError::Config(format!(
+                        "Chain ID {} is too large for an EIP-155 signature",
+                        chain_id
+                    ))
+                }),
+            VEncoding::Typed => Ok(recid),
+        }
+    }
+
+    /// Checksummed address the signature of `hash` recovers to, `None` if it
+    /// is not a valid signature
+    pub fn recover(&self, hash: &[u8; 32]) -> Option<String> {
+        let signature = EcdsaSignature::from_scalars(self.r, self.s).ok()?;
+        let recid = RecoveryId::from_byte(self.recovery_id())?;
+        let key = VerifyingKey::recover_from_prehash(hash, &signature, recid).ok()?;
+        Some(address_of(&key))
+    }
+
+    /// The signature of `hash` by `address` with `r` and `s`, with `s` made
+    /// low and the recovery id the one that recovers to `address`.
+    ///
+    /// `v` is only a hint and may be in any [`VEncoding`], or missing as from
+    /// signers returning bare `r` and `s`. Fails unless one of the two
+    /// recovery ids recovers to `address`.
+    pub fn normalize(
+        hash: &[u8; 32],
+        r: [u8; 32],
+        s: [u8; 32],
+        v: Option<u64>,
+        address: &str,
+    ) -> Result<Self, PayoutError> {
+        let invalid = |reason: &str| {
+            PayoutError::InvalidResponse(format!("Signature by {} {}", address, reason))
+        };
+        let signature =
+            EcdsaSignature::from_scalars(r, s).map_err(|_| invalid("is not a valid signature"))?;
+        let mut hint = v.map_or(0, recovery_id_hint);
+        // Negating s mirrors R, flipping the recovery id
+        let signature = match signature.normalize_s() {
+            Some(low) => {
+                hint ^= 1;
+                low
+            }
+            None => signature,
+        };
+        let mut s = [0u8; 32];
+        s.copy_from_slice(&signature.s().to_bytes());
+        for recid in [hint, hint ^ 1] {
+            let candidate = Signature {
+                r,
+                s,
+                v: 27 + recid,
+            };
+            if candidate
+                .recover(hash)
+                .is_some_and(|recovered| recovered.eq_ignore_ascii_case(address))
+            {
+                return Ok(candidate);
+            }
+        }
+        Err(invalid("does not recover to its signer"))
+    }
+}
+
+/// Recovery id a `v` of any [`VEncoding`] suggests
+fn recovery_id_hint(v: u64) -> u8 {
+    match v {
+        0 | 1 => v as u8,
+        27 | 28 => (v - 27) as u8,
+        v if v >= 35 => ((v - 35) % 2) as u8,
+        _ => 0,
+    }
+}
+
+/// EIP-55 checksummed address of a public key
+pub(super) fn address_of(key: &VerifyingKey) -> String {
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    to_checksum_address(&address)
+}
+
+/// Hash an EIP-155 legacy transaction is signed over
+pub fn legacy_signing_hash(
+    to: &str,
+    data: &str,
+    value: u64,
+    params: TxParams,
+    chain_id: u64,
+) -> Option<[u8; 32]> {
+    Some(keccak256(&unsigned_transaction(
+        to, data, value, params, chain_id,
+    )?))
+}
+
+/// RLP of an EIP-155 legacy transaction signed with `signature`, as
+/// `eth_sendRawTransaction` takes it
+pub fn signed_legacy_transaction(
+    to: &str,
+    data: &str,
+    value: u64,
+    params: TxParams,
+    chain_id: u64,
+    signature: &Signature,
+) -> Result<Vec<u8>, PayoutError> {
+    let unencodable = || PayoutError::Config(format!("Cannot encode a transaction to {}", to));
+    let to_bytes = to
+        .strip_prefix("0x")
+        .and_then(|to| hex::decode(to).ok())
+        .ok_or_else(unencodable)?;
+    let data = hex::decode(data.strip_prefix("0x").unwrap_or(data)).map_err(|_| unencodable())?;
+    let v = signature.v(VEncoding::Eip155 { chain_id })?;
+    let fields = [
+        rlp_uint(params.nonce.into()),
+        rlp_uint(params.gas_price.into()),
+        rlp_uint(params.gas_limit.into()),
+        rlp_bytes(&to_bytes),
+        rlp_uint(value.into()),
+        rlp_bytes(&data),
+        rlp_uint(v.into()),
+        rlp_word(&signature.r),
+        rlp_word(&signature.s),
+    ];
+    Ok(rlp_list(&fields.concat()))
+}
+
+/// RLP of a 32-byte big-endian integer, without leading zeros
+fn rlp_word(word: &[u8; 32]) -> Vec<u8> {
+    let first = word.iter().position(|byte| *byte != 0).unwrap_or(32);
+    rlp_bytes(&word[first..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::test_config;
+    use super::super::{EthereumPayoutService, LocalSigner};
+    use super::*;
+    use k256::elliptic_curve::PrimeField;
+
+    /// Key and transaction of the EIP-155 example
+    const KEY: &str = "4646464646464646464646464646464646464646464646464646464646464646";
+    const TO: &str = "0x3535353535353535353535353535353535353535";
+    const ONE_ETH: u64 = 1_000_000_000_000_000_000;
+    const PARAMS: TxParams = TxParams {
+        nonce: 9,
+        gas_limit: 21000,
+        gas_price: 20_000_000_000,
+    };
+
+    fn sign(chain_id: u64) -> (Signature, String) {
+        let hash = legacy_signing_hash(TO, "0x", ONE_ETH, PARAMS, chain_id).unwrap();
+        let signature = LocalSigner::from_hex(KEY)
+            .unwrap()
+            .sign_hash(&hash)
+            .unwrap();
+        let raw = signed_legacy_transaction(TO, "0x", ONE_ETH, PARAMS, chain_id, &signature);
+        (signature, hex::encode(raw.unwrap()))
+    }
+
+    #[test]
+    fn matches_known_signed_transactions() {
+        // The example of EIP-155 itself
+        let (signature, raw) = sign(1);
+        assert_eq!(signature.v(VEncoding::Eip155 { chain_id: 1 }).unwrap(), 37);
+        assert_eq!(
+            raw,
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+             8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d899\
+             7f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+
+        // Polygon, whose v no longer fits in one byte
+        let (signature, raw) = sign(137);
+        assert_eq!(
+            signature.v(VEncoding::Eip155 { chain_id: 137 }).unwrap(),
+            310
+        );
+        assert_eq!(
+            raw,
+            "f86e098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+             80820136a00d02a2ce7ed82574448f5581c0c45a2eb0b6e2ccf6971eff1dae61b6bb1cec81a00ce65a\
+             784c4aa7fe79935ec1fadee65e7a0238186fffd3848275dc275b26ccf1"
+        );
+
+        // Sepolia, a chain ID of several bytes
+        let (signature, raw) = sign(11_155_111);
+        assert_eq!(
+            signature
+                .v(VEncoding::Eip155 {
+                    chain_id: 11_155_111
+                })
+                .unwrap(),
+            22_310_258
+        );
+        assert_eq!(
+            raw,
+            "f870098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+             808401546d72a0c36215381dac1dfec75d248a6cef00a0f6880b27c7e0d9f880fdd09499329534a030\
+             05693f696a9aee8daf28e1063aebfe60f58f7a120faecfc2bbf1faea3fc494"
+        );
+        assert_eq!(signature.v(VEncoding::Typed).unwrap(), 1);
+        assert_eq!(signature.v(VEncoding::Ecrecover).unwrap(), 28);
+    }
+
+    #[test]
+    fn eip155_v_stops_at_the_largest_chain_id() {
+        let signature = Signature {
+            r: [1; 32],
+            s: [1; 32],
+            v: 28,
+        };
+        let at = |chain_id| signature.v(VEncoding::Eip155 { chain_id });
+        assert_eq!(at(MAX_EIP155_CHAIN_ID).unwrap(), u64::MAX - 1);
+        assert!(matches!(
+            at(MAX_EIP155_CHAIN_ID + 1),
+            Err(PayoutError::Config(_))
+        ));
+        assert!(at(u64::MAX).is_err());
+        assert!(signed_legacy_transaction(
+            TO,
+            "0x",
+            0,
+            PARAMS,
+            MAX_EIP155_CHAIN_ID + 1,
+            &signature
+        )
+        .is_err());
+
+        let mut config = test_config();
+        config.expected_chain_id = MAX_EIP155_CHAIN_ID + 1;
+        assert!(matches!(
+            EthereumPayoutService::new(config),
+            Err(PayoutError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn normalizes_signatures_of_remote_signers() {
+        let signer = LocalSigner::from_hex(KEY).unwrap();
+        let address = signer.address();
+        let hash = legacy_signing_hash(TO, "0x", ONE_ETH, PARAMS, 1).unwrap();
+        let signature = signer.sign_hash(&hash).unwrap();
+        assert_eq!(signature.recover(&hash).as_deref(), Some(address.as_str()));
+
+        // Bare r and s, and a v in any convention, even a wrong one
+        for v in [
+            None,
+            Some(0),
+            Some(1),
+            Some(27),
+            Some(28),
+            Some(37),
+            Some(38),
+        ] {
+            assert_eq!(
+                Signature::normalize(&hash, signature.r, signature.s, v, &address).unwrap(),
+                signature
+            );
+        }
+
+        // A high s is negated, flipping the recovery id
+        let s = k256::Scalar::from_repr(signature.s.into()).unwrap();
+        let mut high = [0u8; 32];
+        high.copy_from_slice(&(-s).to_bytes());
+        assert!(high > signature.s);
+        let flipped = u64::from(signature.recovery_id() ^ 1);
+        assert_eq!(
+            Signature::normalize(&hash, signature.r, high, Some(flipped), &address).unwrap(),
+            signature
+        );
+
+        // Signatures by anyone else are refused
+        let other = LocalSigner::from_hex(&"11".repeat(32)).unwrap().address();
+        let err = Signature::normalize(&hash, signature.r, signature.s, None, &other).unwrap_err();
+        assert!(err.to_string().contains("does not recover to its signer"));
+        assert!(Signature::normalize(&hash, [0; 32], signature.s, None, &address).is_err());
+    }
+}
@@ -0,0 +1,393 @@
+//! Local secp256k1 signing with the operator key
+//!
+//! Transactions and the digests the service signs itself, for Safe
+//! proposals, authorizations and user operations, go through a [`Signer`]:
+//! the operator key in memory by default, or a remote one injected with
+//! `with_signer`, whose account then becomes the operator. Whatever form a signer returns, [`Signer::sign_normalized`]
+//! checks and normalizes it. Transactions are sent as EIP-155 legacy
+//! transactions with `eth_sendRawTransaction`, unless `node_signing` leaves
+//! signing them to the node's unlocked account.
+
+use super::hash::keccak256;
+use super::rpc::rpc_request;
+use super::signature::{address_of, legacy_signing_hash, signed_legacy_transaction};
+use super::{
+    send_transaction_request, EthereumPayoutConfig, EthereumPayoutService, PayoutError, Signature,
+    TxParams,
+};
+use async_trait::async_trait;
+use k256::ecdsa::SigningKey;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Order n of the secp256k1 group; private keys must lie in `1..n`
+const CURVE_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+/// First default Anvil account, used in `dev_mode` when the configured key is unusable
+const ANVIL_DEV_KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+
+/// Normalize a hex private key to 64 lowercase hex digits without 0x prefix
+///
+/// Surrounding whitespace, as left by secret files, is ignored. Errors never
+/// include the key itself.
+pub fn normalize_private_key(private_key: &str) -> Result<String, PayoutError> {
+    let invalid = |reason: String| Err(PayoutError::InvalidOperatorKey(reason));
+    let trimmed = private_key.trim();
+    let digits = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+    if digits.is_empty() {
+        return invalid("key is empty".to_string());
+    }
+    if let Some(position) = digits.find(|c: char| !c.is_ascii_hexdigit()) {
+        if (digits.len() == 44 && digits.ends_with('=')) || digits.contains(['+', '/']) {
+            return invalid("key looks base64-encoded, expected hex".to_string());
+        }
+        return invalid(format!("non-hex character at position {}", position));
+    }
+    if digits.len() != 64 {
+        return invalid(format!("{} hex digits, expected 64", digits.len()));
+    }
+    let digits = digits.to_ascii_lowercase();
+    let mut scalar = [0u8; 32];
+    hex::decode_to_slice(&digits, &mut scalar)
+        .map_err(|err| PayoutError::InvalidOperatorKey(err.to_string()))?;
+    if scalar == [0u8; 32] {
+        return invalid("key is zero".to_string());
+    }
+    if scalar >= CURVE_ORDER {
+        return invalid("key is not below the secp256k1 curve order".to_string());
+    }
+    Ok(digits)
+}
+
+impl EthereumPayoutConfig {
+    /// Normalize the operator key in place and return the operator address
+    ///
+    /// In `dev_mode` an unusable key is replaced by the first default Anvil
+    /// account's rather than refused.
+    pub(super) fn load_operator_key(&mut self) -> Result<String, PayoutError> {
+        let key = match normalize_private_key(&self.operator_private_key) {
+            Ok(key) => key,
+            Err(err) if self.dev_mode => {
+                warn!("{}; dev mode is on, using the default Anvil account", err);
+                ANVIL_DEV_KEY.to_string()
+            }
+            Err(err) => return Err(err),
+        };
+        let address = LocalSigner::from_hex(&key)?.address();
+        self.operator_private_key = key;
+        Ok(address)
+    }
+}
+
+/// Signs 32-byte digests on behalf of the operator
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// EIP-55 checksummed address of the account signatures recover to
+    fn address(&self) -> String;
+
+    /// Sign a digest that has already been hashed. A signer that cannot be
+    /// reached, as opposed to one refusing the digest, fails with
+    /// `SignerUnavailable`.
+    async fn sign_hash(&self, hash: &[u8; 32]) -> Result<Signature, PayoutError>;
+
+    /// Sign a digest and normalize the signature, see [`Signature::normalize`]
+    async fn sign_normalized(&self, hash: &[u8; 32]) -> Result<Signature, PayoutError> {
+        let signature = self.sign_hash(hash).await?;
+        Signature::normalize(
+            hash,
+            signature.r,
+            signature.s,
+            Some(signature.v.into()),
+            &self.address(),
+        )
+    }
+}
+
+/// Whether the node refused to submit a transaction because the account's
+/// signer could not sign it, rather than because of the transaction
+pub fn is_node_signer_error(message: &str) -> bool {
+    let message = message.to_ascii_lowercase();
+    [
+        "authentication needed",
+        "unknown account",
+        "signer unavailable",
+        "external signer",
+        "failed to sign",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+/// Signs 32-byte digests with a private key held in memory
+pub struct LocalSigner {
+    key: SigningKey,
+}
+
+impl LocalSigner {
+    /// Parse a hex private key, see [`normalize_private_key`]
+    pub fn from_hex(private_key: &str) -> Result<Self, PayoutError> {
+        let bytes = hex::decode(normalize_private_key(private_key)?)
+            .map_err(|err| PayoutError::InvalidOperatorKey(err.to_string()))?;
+        let key = SigningKey::from_slice(&bytes)
+            .map_err(|err| PayoutError::InvalidOperatorKey(err.to_string()))?;
+        Ok(LocalSigner { key })
+    }
+
+    /// EIP-55 checksummed Ethereum address of the key
+    pub fn address(&self) -> String {
+        address_of(self.key.verifying_key())
+    }
+
+    /// Sign a digest that has already been hashed, e.g. an EIP-712 hash
+    pub fn sign_hash(&self, hash: &[u8; 32]) -> Result<Signature, PayoutError> {
+        let (signature, recovery_id) = self
+            .key
+            .sign_prehash_recoverable(hash)
+            .map_err(|err| PayoutError::Config(format!("Signing failed: {}", err)))?;
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        r.copy_from_slice(&signature.r().to_bytes());
+        s.copy_from_slice(&signature.s().to_bytes());
+        Signature::normalize(
+            hash,
+            r,
+            s,
+            Some(recovery_id.to_byte().into()),
+            &self.address(),
+        )
+    }
+}
+
+#[async_trait]
+impl Signer for LocalSigner {
+    fn address(&self) -> String {
+        LocalSigner::address(self)
+    }
+
+    async fn sign_hash(&self, hash: &[u8; 32]) -> Result<Signature, PayoutError> {
+        LocalSigner::sign_hash(self, hash)
+    }
+}
+
+/// A transaction from the operator, ready to broadcast
+#[derive(Debug, Clone)]
+pub(super) struct Submission {
+    /// `eth_sendRawTransaction` request, or `eth_sendTransaction` under `node_signing`
+    pub request: Value,
+    /// Hash of the signed transaction, `None` when the node signs it
+    pub tx_hash: Option<String>,
+    pub nonce: u64,
+}
+
+impl EthereumPayoutService {
+    /// Signer of the transactions and digests the service signs itself
+    pub(super) fn operator_signer(&self) -> Result<Arc<dyn Signer>, PayoutError> {
+        match &self.signer {
+            Some(signer) => Ok(signer.clone()),
+            None => Ok(Arc::new(LocalSigner::from_hex(&self.operator_key())?)),
+        }
+    }
+
+    /// Sign the operator's transaction to `to` for broadcasting
+    pub(super) async fn sign_transaction(
+        &self,
+        to: &str,
+        data: &str,
+        value: u64,
+        params: TxParams,
+    ) -> Result<Submission, PayoutError> {
+        if self.config.node_signing {
+            return Ok(Submission {
+                request: send_transaction_request(
+                    &self.operator_address(),
+                    to,
+                    data,
+                    value,
+                    params,
+                ),
+                tx_hash: None,
+                nonce: params.nonce,
+            });
+        }
+        let chain_id = self.config.expected_chain_id;
+        let unencodable = || PayoutError::Config(format!("Cannot encode a transaction to {}", to));
+        let hash =
+            legacy_signing_hash(to, data, value, params, chain_id).ok_or_else(unencodable)?;
+        let signature = self.operator_signer()?.sign_normalized(&hash).await?;
+        let raw = signed_legacy_transaction(to, data, value, params, chain_id, &signature)?;
+        Ok(Submission {
+            request: rpc_request(
+                "eth_sendRawTransaction",
+                json!([format!("0x{}", hex::encode(&raw))]),
+            ),
+            tx_hash: Some(format!("0x{}", hex::encode(keccak256(&raw)))),
+            nonce: params.nonce,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        deployed_chain, mock_chain, test_config, test_service, TEST_DESTINATION, TEST_OPERATOR,
+        TEST_TREASURY,
+    };
+    use super::*;
+
+    #[test]
+    fn derives_anvil_address() {
+        let signer = LocalSigner::from_hex(
+            "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        )
+        .unwrap();
+        assert_eq!(
+            signer.address(),
+            "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+        );
+        assert!(LocalSigner::from_hex("0x00").is_err());
+        assert!(LocalSigner::from_hex("zz").is_err());
+    }
+
+    fn reason(private_key: &str) -> String {
+        match normalize_private_key(private_key) {
+            Err(PayoutError::InvalidOperatorKey(reason)) => reason,
+            other => panic!("expected an invalid key, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn normalizes_key_formats() {
+        for private_key in [
+            ANVIL_DEV_KEY.to_string(),
+            format!("0x{}", ANVIL_DEV_KEY),
+            format!("  0X{}\n", ANVIL_DEV_KEY.to_uppercase()),
+            format!("{}\r\n", ANVIL_DEV_KEY),
+        ] {
+            assert_eq!(normalize_private_key(&private_key).unwrap(), ANVIL_DEV_KEY);
+        }
+        // One below the curve order is the largest valid key
+        let mut largest = CURVE_ORDER;
+        largest[31] -= 1;
+        assert!(normalize_private_key(&hex::encode(largest)).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_keys() {
+        assert_eq!(reason(""), "key is empty");
+        assert_eq!(reason(" 0x \n"), "key is empty");
+        assert_eq!(reason(&ANVIL_DEV_KEY[1..]), "63 hex digits, expected 64");
+        assert_eq!(
+            reason(&format!("{}00", ANVIL_DEV_KEY)),
+            "66 hex digits, expected 64"
+        );
+        assert_eq!(
+            reason(&format!("0x0x{}", &ANVIL_DEV_KEY[2..])),
+            "non-hex character at position 1"
+        );
+        assert_eq!(
+            reason(&format!(
+                "{} {}",
+                &ANVIL_DEV_KEY[..32],
+                &ANVIL_DEV_KEY[32..]
+            )),
+            "non-hex character at position 32"
+        );
+        assert_eq!(
+            reason("rAl0vsOaF+NrptI4/5RL1HrnhNe/T4f/ynhNe/T0/4A="),
+            "key looks base64-encoded, expected hex"
+        );
+        assert_eq!(reason(&"0".repeat(64)), "key is zero");
+        let order = hex::encode(CURVE_ORDER);
+        assert_eq!(reason(&order), "key is not below the secp256k1 curve order");
+        assert_eq!(
+            reason(&"f".repeat(64)),
+            "key is not below the secp256k1 curve order"
+        );
+        // The key never appears in the error
+        let err = normalize_private_key(&format!("{}zz", ANVIL_DEV_KEY)).unwrap_err();
+        assert!(!err.to_string().contains(ANVIL_DEV_KEY));
+    }
+
+    #[test]
+    fn unusable_keys_only_fall_back_in_dev_mode() {
+        let mut config = EthereumPayoutConfig::new("http://127.0.0.1:8545", "0x0", "0x1234", 31337);
+        assert!(matches!(
+            config.clone().load_operator_key(),
+            Err(PayoutError::InvalidOperatorKey(_))
+        ));
+        config.dev_mode = true;
+        assert_eq!(
+            config.load_operator_key().unwrap(),
+            "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266"
+        );
+        assert_eq!(config.operator_private_key, ANVIL_DEV_KEY);
+
+        // Valid keys are used as given either way, not mapped to an Anvil account
+        let key = "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d\n";
+        let mut config = EthereumPayoutConfig::new("http://127.0.0.1:8545", "0x0", key, 31337);
+        config.dev_mode = true;
+        assert_eq!(
+            config.load_operator_key().unwrap(),
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8"
+        );
+        assert_eq!(config.operator_private_key, key.trim()[2..]);
+    }
+
+    #[tokio::test]
+    async fn transactions_are_signed_locally_unless_the_node_signs_them() {
+        let transport = mock_chain();
+        transport.on_result("eth_sendTransaction", json!("0xdef"));
+        let service = test_service(test_config(), transport.clone());
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xabc"));
+        // Recovering the operator also checks the chain id in `v`
+        let sent = &transport.sent_transactions()[0];
+        assert_eq!(sent["from"], TEST_OPERATOR);
+        assert_eq!(sent["to"], TEST_TREASURY);
+        assert_eq!(sent["nonce"], "0x0");
+        assert_eq!(transport.call_count("eth_sendTransaction"), 0);
+
+        let mut config = test_config();
+        config.node_signing = true;
+        let service = test_service(config, transport.clone());
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 2)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xdef"));
+        let sent = &transport.calls("eth_sendTransaction")[0]["params"][0];
+        assert_eq!(sent["from"], TEST_OPERATOR);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+
+    #[tokio::test]
+    async fn an_injected_signer_sends_from_its_own_account() {
+        let signer = LocalSigner::from_hex(&hex::encode(keccak256(b"kms"))).unwrap();
+        let address = signer.address();
+        let chain = deployed_chain();
+        chain.set_nonce(&address, 4);
+        let service = test_service(test_config(), chain.clone()).with_signer(Arc::new(signer));
+        assert_eq!(service.operator_address(), address);
+
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let sent = &chain.sent_transactions()[0];
+        assert_eq!(sent["from"], address);
+        assert_eq!(sent["nonce"], "0x4");
+        let nonce_read = &chain.calls("eth_getTransactionCount")[0]["params"][0];
+        assert_eq!(nonce_read, &json!(address));
+        assert_eq!(chain.nonce(TEST_OPERATOR), 0);
+    }
+}
@@ -0,0 +1,175 @@
+//! Simulating Treasury payouts before sending them
+//!
+//! Every Treasury payout is first run through `eth_call` from the operator's
+//! address. Nodes do not run a signed transaction when it is submitted, so
+//! this is where a revert is seen before gas is spent on it: a payment ID the
+//! Treasury already used counts as processed and nothing is signed, a paused
+//! Treasury defers the payout and other reverts fail it.
+//!
+//! A transaction's return value never reaches its sender, so the simulation
+//! is also the only way to see what `payoutToUser` returns: when
+//! `payout_returns_id` declares that it returns the Treasury's `uint256
+//! payoutId`, as Treasury v3 does, the simulated word is kept on the payout's
+//! record and `Submitted` event. A simulation that failed without reverting
+//! is logged and the payout sent anyway, leaving the ID empty.
+
+use super::abi::{decode_words, uint_to_decimal};
+use super::pause::is_pause_revert;
+use super::{EthereumPayoutService, PayoutError, PayoutMode, PayoutPlan};
+use serde_json::json;
+use tracing::{debug, warn};
+
+/// What simulating a payout showed about sending it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Simulated {
+    /// The call succeeds, returning this Treasury payout ID if it has one
+    Sends(Option<String>),
+    /// The Treasury already processed the payment ID
+    AlreadyProcessed,
+}
+
+impl EthereumPayoutService {
+    /// Run `plan` through `eth_call` if it calls the Treasury, failing with
+    /// the revert it would be mined with
+    pub(super) async fn simulate_payout(
+        &self,
+        plan: &PayoutPlan,
+    ) -> Result<Simulated, PayoutError> {
+        if plan.mode != PayoutMode::Treasury {
+            return Ok(Simulated::Sends(None));
+        }
+        let err = match self.simulate(plan).await {
+            Ok(payout_id) => return Ok(Simulated::Sends(payout_id)),
+            Err(err) => err,
+        };
+        if is_revert(&err) {
+            return self
+                .revert_outcome(err, plan.mode)
+                .map(|_| Simulated::AlreadyProcessed);
+        }
+        warn!(
+            "Simulating payout 0x{} failed, sending it without a Treasury payout ID: {}",
+            hex::encode(plan.payment_id),
+            err
+        );
+        Ok(Simulated::Sends(None))
+    }
+
+    async fn simulate(&self, plan: &PayoutPlan) -> Result<Option<String>, PayoutError> {
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({
+                    "from": self.operator_address(),
+                    "to": &plan.to,
+                    "value": format!("0x{:x}", plan.value),
+                    "data": &plan.data,
+                })],
+            )
+            .await?;
+        if !self.config.payout_returns_id {
+            return Ok(None);
+        }
+        let word = result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .ok_or_else(|| {
+                PayoutError::InvalidResponse(format!("payoutToUser returned {}", result))
+            })?;
+        let payout_id = uint_to_decimal(&word);
+        debug!(
+            "Treasury assigns payout ID {} to payment 0x{}",
+            payout_id,
+            hex::encode(plan.payment_id)
+        );
+        Ok(Some(payout_id))
+    }
+}
+
+/// Whether a call failed because it reverted, rather than the node failing to run it
+fn is_revert(err: &PayoutError) -> bool {
+    match err {
+        PayoutError::Rpc { message, data, .. } => {
+            data.is_some() || message.contains("revert") || is_pause_revert(err)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{EthereumPayoutConfig, PayoutEvent, PayoutOutcome};
+    use super::*;
+    use std::sync::Arc;
+
+    /// Pay the test destination, returning the Treasury payout ID kept on
+    /// its record and its `Submitted` event
+    async fn pay(
+        config: EthereumPayoutConfig,
+        transport: &Arc<MockTransport>,
+    ) -> (Option<String>, Option<String>) {
+        let service = test_service(config, transport.clone());
+        let mut events = service.subscribe();
+        assert_eq!(
+            service
+                .execute_payout(TEST_DESTINATION, 100, 1)
+                .await
+                .unwrap(),
+            PayoutOutcome::Submitted {
+                tx_hash: "0xabc".to_string()
+            }
+        );
+        let id = service.config().payment_id(TEST_DESTINATION, 1);
+        let recorded = service.store().get(&id).unwrap().treasury_payout_id;
+        let published = match events.recv().await.unwrap().event {
+            PayoutEvent::Submitted {
+                treasury_payout_id, ..
+            } => treasury_payout_id,
+            other => panic!("{:?}", other),
+        };
+        (recorded, published)
+    }
+
+    fn returning_id(returns_id: bool) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.payout_returns_id = returns_id;
+        config
+    }
+
+    #[tokio::test]
+    async fn keeps_the_simulated_payout_id() {
+        let transport = mock_chain();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 0x1_0000_0000_u64)));
+        let (recorded, published) = pay(returning_id(true), &transport).await;
+        assert_eq!(recorded.as_deref(), Some("4294967296"));
+        assert_eq!(published, recorded);
+
+        let simulated = &transport.calls("eth_call")[0]["params"][0];
+        let sent = &transport.sent_transactions()[0];
+        assert_eq!(
+            simulated["data"].as_str().unwrap().to_ascii_lowercase(),
+            sent["data"]
+        );
+        assert_eq!(simulated["to"], sent["to"]);
+        assert_eq!(simulated["from"], sent["from"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_the_id_empty_without_a_simulated_return_value() {
+        // A function declared without a return value
+        let transport = mock_chain();
+        assert_eq!(pay(returning_id(false), &transport).await, (None, None));
+        assert_eq!(transport.call_count("eth_call"), 1);
+
+        // A node failing to simulate does not stop the payout
+        let transport = mock_chain();
+        transport.on_error("eth_call", -32603, "internal error");
+        assert_eq!(pay(returning_id(true), &transport).await, (None, None));
+        assert_eq!(transport.call_count("eth_call"), 1);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+    }
+}
@@ -0,0 +1,374 @@
+//! Accounting of payments `maybe_execute_payout` did not pay out
+//!
+//! Every skip is counted per reason with the amount it carried, in the
+//! source asset's base units, and reported as metrics. With `record_skips`,
+//! skipped Ethereum destinations are also kept in the payout store of the
+//! configured service as `Skipped` records. Payouts deferred while the
+//! Treasury is paused are counted as skips but not recorded, since they are
+//! paid once it unpauses.
+
+use super::{
+    metrics, status, DestinationPolicy, EthereumDestination, EthereumPayoutService, PayoutEvent,
+    PayoutRecord, PayoutRequest, PayoutStatus, PhaseTimings,
+};
+use chrono::Utc;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Why a payment was not paid out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The destination is not an Ethereum payout address
+    NotEthereum,
+    /// The destination looks like an Ethereum payout address but is malformed
+    ParseFailed(String),
+    /// No payout service is configured
+    ServiceUnconfigured,
+    /// The Treasury is paused; the payout was deferred
+    Paused,
+    /// The recipient is denied
+    Blocked,
+}
+
+impl SkipReason {
+    /// Label used in metrics
+    pub fn label(&self) -> &'static str {
+        match self {
+            SkipReason::NotEthereum => "not_ethereum",
+            SkipReason::ParseFailed(_) => "parse_failed",
+            SkipReason::ServiceUnconfigured => "service_unconfigured",
+            SkipReason::Paused => "paused",
+            SkipReason::Blocked => "blocked",
+        }
+    }
+}
+
+/// Skipped payments of one reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkipTotals {
+    pub count: u64,
+    /// Sum of the skipped amounts, in their source assets' base units
+    pub amount: u128,
+}
+
+/// Skipped payments since startup, per reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkipStats {
+    pub not_ethereum: SkipTotals,
+    pub parse_failed: SkipTotals,
+    pub service_unconfigured: SkipTotals,
+    pub paused: SkipTotals,
+    pub blocked: SkipTotals,
+}
+
+const NO_SKIPS: SkipTotals = SkipTotals {
+    count: 0,
+    amount: 0,
+};
+
+impl SkipStats {
+    const EMPTY: SkipStats = SkipStats {
+        not_ethereum: NO_SKIPS,
+        parse_failed: NO_SKIPS,
+        service_unconfigured: NO_SKIPS,
+        paused: NO_SKIPS,
+        blocked: NO_SKIPS,
+    };
+
+    pub fn get(&self, reason: &SkipReason) -> SkipTotals {
+        match reason {
+            SkipReason::NotEthereum => self.not_ethereum,
+            SkipReason::ParseFailed(_) => self.parse_failed,
+            SkipReason::ServiceUnconfigured => self.service_unconfigured,
+            SkipReason::Paused => self.paused,
+            SkipReason::Blocked => self.blocked,
+        }
+    }
+
+    fn totals_mut(&mut self, reason: &SkipReason) -> &mut SkipTotals {
+        match reason {
+            SkipReason::NotEthereum => &mut self.not_ethereum,
+            SkipReason::ParseFailed(_) => &mut self.parse_failed,
+            SkipReason::ServiceUnconfigured => &mut self.service_unconfigured,
+            SkipReason::Paused => &mut self.paused,
+            SkipReason::Blocked => &mut self.blocked,
+        }
+    }
+}
+
+/// Counters of skipped payments
+pub(super) struct SkipCounters {
+    stats: Mutex<SkipStats>,
+    /// Tenant labelling the skip metrics
+    tenant: Option<String>,
+}
+
+impl SkipCounters {
+    pub(super) const fn new() -> Self {
+        SkipCounters {
+            stats: Mutex::new(SkipStats::EMPTY),
+            tenant: None,
+        }
+    }
+
+    pub(super) fn for_tenant(tenant: &str) -> Self {
+        SkipCounters {
+            tenant: Some(tenant.to_string()),
+            ..SkipCounters::new()
+        }
+    }
+
+    pub(super) fn record(&self, reason: &SkipReason, amount: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        let totals = stats.totals_mut(reason);
+        totals.count += 1;
+        totals.amount += u128::from(amount);
+        metrics::payout_skipped(self.tenant.as_deref(), reason.label(), amount);
+    }
+
+    pub(super) fn stats(&self) -> SkipStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+/// Skips counted by `maybe_execute_payout`
+pub(super) static SKIPPED_PAYOUTS: SkipCounters = SkipCounters::new();
+
+/// Payments `maybe_execute_payout` skipped since startup
+pub fn skipped_payouts() -> SkipStats {
+    SKIPPED_PAYOUTS.stats()
+}
+
+impl EthereumPayoutService {
+    /// Why the request would be skipped before it is executed, if at all
+    pub(super) fn skip_reason(&self, request: &PayoutRequest) -> Option<SkipReason> {
+        let formats = &self.config.address_formats;
+        let eth_dest = match EthereumDestination::try_parse_with(&request.destination, formats) {
+            Ok(eth_dest) => eth_dest,
+            Err(reason) => return Some(SkipReason::ParseFailed(reason)),
+        };
+        if self
+            .config
+            .denied_recipients
+            .check(
+                &eth_dest,
+                self.config.tenant.as_deref(),
+                self.config.log_mask.as_ref(),
+            )
+            .is_err()
+        {
+            return Some(SkipReason::Blocked);
+        }
+        if self.is_paused() {
+            return Some(SkipReason::Paused);
+        }
+        None
+    }
+
+    /// Keep a skipped request in the store if `record_skips` is set
+    pub(super) fn record_skip(&self, request: &PayoutRequest, reason: &SkipReason) {
+        if !self.config.record_skips || *reason == SkipReason::Paused {
+            return;
+        }
+        let eth_dest = self.config.parse_destination(&request.destination);
+        let asset_code = request
+            .source_asset
+            .clone()
+            .or_else(|| eth_dest.as_ref().map(|dest| dest.asset_code.clone()))
+            .unwrap_or_default();
+        let asset_segment = eth_dest
+            .as_ref()
+            .and_then(EthereumDestination::written_asset);
+        let last_error = match reason {
+            SkipReason::ParseFailed(message) => message.clone(),
+            reason => reason.label().to_string(),
+        };
+        debug!(
+            "Recording skipped payout to {}: {}",
+            self.display_destination(&request.destination),
+            last_error
+        );
+        let payment_id = self.config.payment_id_of(request);
+        self.store.save(PayoutRecord {
+            payment_id,
+            namespace: self.config.payment_id_namespace(),
+            payment_id_strategy: Some(self.config.payment_id_label(request)),
+            attribution: request.attribution.clone(),
+            destination: request.destination.clone(),
+            sequence: request.sequence,
+            recipient: eth_dest.map(|dest| dest.recipient).unwrap_or_default(),
+            asset_code,
+            asset_segment,
+            amount: request.amount,
+            decimals: 0,
+            tx_hash: None,
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: None,
+            memo: request.memo.clone(),
+            status: PayoutStatus::Skipped,
+            deadline: None,
+            last_error: Some(last_error),
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: self.clock.now(),
+            serial: self.record_serial(&payment_id),
+            pinned_block: None,
+            treasury_payout_id: None,
+        });
+    }
+}
+
+/// Count a skip, and record it with the service that skipped it
+pub(super) fn skip(
+    counters: &SkipCounters,
+    service: Option<&EthereumPayoutService>,
+    request: &PayoutRequest,
+    reason: SkipReason,
+) {
+    let destination = match service {
+        Some(service) => service.display_destination(&request.destination),
+        None => DestinationPolicy::default().render(&request.destination),
+    };
+    match &reason {
+        SkipReason::NotEthereum => {}
+        SkipReason::ServiceUnconfigured => {
+            status::UNAVAILABLE.report(&status::status(), &destination, Utc::now());
+        }
+        SkipReason::Paused => debug!(
+            "Deferring payout to {} until the Treasury is unpaused",
+            destination
+        ),
+        reason => warn!(
+            "Skipping payout of {} to {}: {}",
+            request.amount,
+            destination,
+            reason.label()
+        ),
+    }
+    counters.record(&reason, request.amount);
+    if let Some(service) = service {
+        service.record_skip(request, &reason);
+        if reason != SkipReason::Paused {
+            service.publish_payout(
+                &request.destination,
+                request.sequence,
+                PayoutEvent::Skipped {
+                    payment_id: service.request_payment_id(request),
+                    reason: match &reason {
+                        SkipReason::ParseFailed(message) => message.clone(),
+                        reason => reason.label().to_string(),
+                    },
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::route_payout;
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    const BURN_DESTINATION: &str =
+        "test.receiver.eth.31337.EURC.0x000000000000000000000000000000000000dEaD.abc123";
+
+    fn recording_service() -> (Arc<EthereumPayoutService>, Arc<MockTransport>) {
+        let transport = mock_chain();
+        let mut config = test_config();
+        config.record_skips = true;
+        (Arc::new(test_service(config, transport.clone())), transport)
+    }
+
+    fn request(destination: &str, amount: u64) -> PayoutRequest {
+        PayoutRequest::new(destination, amount, 1).with_source_asset("EURC")
+    }
+
+    #[tokio::test]
+    async fn counts_skips_without_a_service() {
+        let skips = SkipCounters::new();
+        route_payout(None, &skips, request("g.alice.usd", 5)).await;
+        route_payout(None, &skips, request(TEST_DESTINATION, 100)).await;
+        route_payout(None, &skips, request(TEST_DESTINATION, 200)).await;
+
+        let stats = skips.stats();
+        assert_eq!(
+            stats.not_ethereum,
+            SkipTotals {
+                count: 1,
+                amount: 5
+            }
+        );
+        assert_eq!(
+            stats.get(&SkipReason::ServiceUnconfigured),
+            SkipTotals {
+                count: 2,
+                amount: 300
+            }
+        );
+        assert_eq!(stats.parse_failed, SkipTotals::default());
+    }
+
+    #[tokio::test]
+    async fn records_unparseable_and_blocked_destinations() {
+        let (service, transport) = recording_service();
+        let skips = SkipCounters::new();
+        let malformed = "test.receiver.eth.+1.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.t";
+        route_payout(Some(&service), &skips, request(malformed, 100)).await;
+        route_payout(Some(&service), &skips, request(BURN_DESTINATION, 40)).await;
+        route_payout(Some(&service), &skips, request(TEST_DESTINATION, 70)).await;
+
+        let stats = skips.stats();
+        assert_eq!(stats.parse_failed.count, 1);
+        assert_eq!(stats.blocked.amount, 40);
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 1);
+
+        let record = service
+            .store()
+            .get(&service.config().payment_id(malformed, 1))
+            .unwrap();
+        assert_eq!(record.status, PayoutStatus::Skipped);
+        assert_eq!(record.amount, 100);
+        assert_eq!(record.asset_code, "EURC");
+        assert_eq!(
+            record.last_error.as_deref(),
+            Some("Invalid chain ID \"+1\"")
+        );
+        let record = service
+            .store()
+            .get(&service.config().payment_id(BURN_DESTINATION, 1))
+            .unwrap();
+        assert_eq!(record.status, PayoutStatus::Skipped);
+        assert_eq!(record.last_error.as_deref(), Some("blocked"));
+    }
+
+    #[tokio::test]
+    async fn payouts_deferred_while_paused_are_counted_but_queued() {
+        let (service, transport) = recording_service();
+        transport.on_result("eth_call", json!(format!("0x{:064x}", 1)));
+        service.check_paused().await.unwrap();
+        let skips = SkipCounters::new();
+        route_payout(Some(&service), &skips, request(TEST_DESTINATION, 100)).await;
+
+        assert_eq!(skips.stats().paused.count, 1);
+        assert_eq!(service.deferred_count(), 1);
+        // Deferred payouts are paid later, so they are not recorded as skipped
+        assert!(service
+            .store()
+            .get(&service.config().payment_id(TEST_DESTINATION, 1))
+            .is_none());
+    }
+}
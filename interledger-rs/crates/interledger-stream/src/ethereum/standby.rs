@@ -0,0 +1,502 @@
+//! Initialization of the global payout service, retried until it succeeds
+//!
+//! The configuration may only become available after startup, e.g. when a
+//! secret is mounted late. Until a service can be built from it, a background
+//! task retries with exponential backoff and publishes the service, without a
+//! restart, in the slot `maybe_execute_payout` reads. With a tenants file, a
+//! router over the tenants' services is published instead.
+//!
+//! Startup paths may each call [`init_payout_service`]. Concurrent calls wait
+//! for the first one's attempt and later calls do nothing, so one service and
+//! one set of background tasks is ever built by them. Each change of status
+//! is logged once, however many attempts it takes. A service replacing one
+//! already published stops the background tasks of the one it replaces.
+
+use super::status::{config_from_vars, set_status, ServiceStatus};
+use super::tenant::{load_tenants, PayoutRouter, TenantConfig, TENANTS_FILE_ENV};
+use super::{EthereumPayoutConfig, EthereumPayoutService, PayoutError};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// Delay before the first retry, doubled after each failure
+const INIT_RETRY_INITIAL: Duration = Duration::from_secs(5);
+const INIT_RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// Where the global service is published once it is running
+type ServiceSlot = RwLock<Option<Arc<EthereumPayoutService>>>;
+
+static PAYOUT_SERVICE: ServiceSlot = RwLock::new(None);
+
+/// Where the tenants' router is published once all of them are running
+type RouterSlot = RwLock<Option<Arc<PayoutRouter>>>;
+
+static PAYOUT_ROUTER: RouterSlot = RwLock::new(None);
+
+/// Makes repeated and concurrent initialization calls share one attempt
+struct InitGuard {
+    /// Held while an attempt runs, so concurrent callers wait for its outcome
+    /// instead of building another service
+    attempt: tokio::sync::Mutex<()>,
+    /// Set once an attempt succeeded or a task retries it in the background
+    settled: AtomicBool,
+    /// Status last logged, so each change is logged once
+    logged: Mutex<Option<ServiceStatus>>,
+}
+
+static INIT_GUARD: InitGuard = InitGuard::new();
+
+impl InitGuard {
+    const fn new() -> Self {
+        InitGuard {
+            attempt: tokio::sync::Mutex::const_new(()),
+            settled: AtomicBool::new(false),
+            logged: Mutex::new(None),
+        }
+    }
+
+    /// Run `attempt` unless an earlier call did, retrying it in the background
+    /// until it is ready
+    async fn run<F, Fut>(&'static self, attempt: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ServiceStatus> + Send + 'static,
+    {
+        let _attempt = self.attempt.lock().await;
+        if self.settled.load(Ordering::SeqCst) {
+            debug!("Ethereum payout service initialization already ran");
+            return;
+        }
+        let ready = self.report(attempt().await);
+        self.settled.store(true, Ordering::SeqCst);
+        if !ready {
+            tokio::spawn(retry_with_backoff(
+                self,
+                INIT_RETRY_INITIAL,
+                INIT_RETRY_MAX,
+                attempt,
+            ));
+        }
+    }
+
+    /// Publish the status of an attempt, logging it if it changed, and
+    /// return whether the attempt succeeded
+    fn report(&self, status: ServiceStatus) -> bool {
+        let mut logged = self.logged.lock().unwrap();
+        if logged.as_ref() != Some(&status) {
+            match &status {
+                ServiceStatus::Ready { .. } => {
+                    info!("Ethereum payout service initialized successfully")
+                }
+                ServiceStatus::Disabled => debug!("Ethereum payouts disabled (none of ETHEREUM_RPC_URL, TREASURY_ADDRESS, OPERATOR_PRIVATE_KEY, CHAIN_ID set)"),
+                ServiceStatus::Misconfigured { .. } => {
+                    error!("Ethereum payout service is misconfigured: {:?}", status)
+                }
+            }
+            *logged = Some(status.clone());
+        }
+        let ready = matches!(status, ServiceStatus::Ready { .. });
+        set_status(status);
+        ready
+    }
+}
+
+/// The global payout service, `None` until it is initialized
+pub(super) fn payout_service() -> Option<Arc<EthereumPayoutService>> {
+    PAYOUT_SERVICE.read().unwrap().clone()
+}
+
+/// The router over the tenants' services, `None` without a tenants file or
+/// until they are initialized
+pub(super) fn payout_router() -> Option<Arc<PayoutRouter>> {
+    PAYOUT_ROUTER.read().unwrap().clone()
+}
+
+/// Initialize the global payout service, or the tenants' services if
+/// `PAYOUT_TENANTS_FILE` is set, retrying in the background until their
+/// configuration is available and valid
+///
+/// The outcome of each attempt is reported by [`status`](super::status()).
+/// Calls after the first, or made while it runs, do nothing.
+pub async fn init_payout_service() {
+    if let Some(path) = std::env::var_os(TENANTS_FILE_ENV).map(PathBuf::from) {
+        INIT_GUARD
+            .run(move || {
+                let path = path.clone();
+                async move { initialize_tenants(&PAYOUT_ROUTER, || load_tenants(&path)).await }
+            })
+            .await;
+        return;
+    }
+    INIT_GUARD
+        .run(|| initialize(&PAYOUT_SERVICE, config_from_env))
+        .await;
+}
+
+fn config_from_env() -> Result<EthereumPayoutConfig, ServiceStatus> {
+    config_from_vars(|name| std::env::var(name).ok())
+}
+
+/// Build a service from the configuration `load` returns, start it and
+/// publish it in `slot`, returning the resulting status
+///
+/// With `self_test` configured, a service failing it is not published. A
+/// service already in `slot` is stopped once replaced.
+async fn initialize(
+    slot: &ServiceSlot,
+    load: impl Fn() -> Result<EthereumPayoutConfig, ServiceStatus>,
+) -> ServiceStatus {
+    let config = match load() {
+        Ok(config) => config,
+        Err(status) => return status,
+    };
+    match EthereumPayoutService::new(config) {
+        Ok(service) => {
+            if let Err(status) = require_self_test(&service).await {
+                return status;
+            }
+            let service = Arc::new(service);
+            service.start().await;
+            let treasuries = service.treasury_capabilities().into_iter().collect();
+            let providers = vec![service.provider_capabilities()];
+            let versions = vec![service.version_info().compact()];
+            let previous = slot.write().unwrap().replace(service);
+            if let Some(previous) = previous {
+                info!("Stopping the background tasks of the replaced Ethereum payout service");
+                previous.stop();
+            }
+            ServiceStatus::Ready {
+                treasuries,
+                providers,
+                versions,
+            }
+        }
+        Err(e) => ServiceStatus::misconfigured(e),
+    }
+}
+
+/// Build the tenants' services from the configurations `load` returns,
+/// start them and publish their router, returning whether it was published
+///
+/// Nothing is published unless every tenant's service could be built and
+/// passed its self-test, if it has one.
+async fn initialize_tenants(
+    slot: &RouterSlot,
+    load: impl Fn() -> Result<Vec<TenantConfig>, PayoutError>,
+) -> ServiceStatus {
+    let router = match load().and_then(PayoutRouter::from_configs) {
+        Ok(router) => router,
+        Err(e) => return ServiceStatus::misconfigured(e),
+    };
+    for tenant in router.tenants() {
+        if let Err(status) = require_self_test(&tenant.service).await {
+            return status;
+        }
+    }
+    let mut treasuries = Vec::new();
+    let mut providers = Vec::new();
+    let mut versions = Vec::new();
+    for tenant in router.tenants() {
+        tenant.service.start().await;
+        treasuries.extend(tenant.service.treasury_capabilities());
+        providers.push(tenant.service.provider_capabilities());
+        versions.push(tenant.service.version_info().compact());
+    }
+    debug!(
+        "Ethereum payout services started for {} tenants",
+        router.tenants().len()
+    );
+    let previous = slot.write().unwrap().replace(Arc::new(router));
+    if let Some(previous) = previous {
+        info!("Stopping the background tasks of the replaced tenant payout services");
+        for tenant in previous.tenants() {
+            tenant.service.stop();
+        }
+    }
+    ServiceStatus::Ready {
+        treasuries,
+        providers,
+        versions,
+    }
+}
+
+/// Run the service's self-test, if it has one, failing with the status to
+/// report unless it passed
+async fn require_self_test(service: &EthereumPayoutService) -> Result<(), ServiceStatus> {
+    let options = match &service.config().self_test {
+        Some(options) => options,
+        None => return Ok(()),
+    };
+    let report = service.self_test(options).await;
+    info!("Ethereum payout service self-test: {}", report.to_json());
+    if report.passed() {
+        return Ok(());
+    }
+    let failures: Vec<String> = report
+        .failures()
+        .map(|check| format!("{}: {}", check.name, check.result.detail()))
+        .collect();
+    Err(ServiceStatus::misconfigured(format!(
+        "Self-test failed: {}",
+        failures.join("; ")
+    )))
+}
+
+/// Run `attempt` until it is ready, doubling the delay between attempts up
+/// to `max` and reporting the status of each to `guard`
+async fn retry_with_backoff<F, Fut>(guard: &InitGuard, initial: Duration, max: Duration, attempt: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = ServiceStatus>,
+{
+    let mut backoff = initial;
+    loop {
+        debug!(
+            "Retrying Ethereum payout service initialization in {:?}",
+            backoff
+        );
+        tokio::time::sleep(backoff).await;
+        if guard.report(attempt().await) {
+            return;
+        }
+        backoff = (backoff * 2).min(max);
+    }
+}
+
+impl EthereumPayoutService {
+    /// Run the initial checks and spawn the background tasks
+    pub(super) async fn start(self: &Arc<Self>) {
+        self.probe_treasury().await;
+        self.probe_provider().await;
+        if let Err(err) = self.check_operator_role().await {
+            warn!("Initial operator role check failed: {}", err);
+        }
+        self.spawn_role_monitor();
+        if self.config.pause_check_interval.is_some() {
+            if let Err(err) = self.check_paused().await {
+                warn!("Initial Treasury pause check failed: {}", err);
+            }
+            self.spawn_pause_monitor();
+        }
+        if self.config.kill_switch.is_some() {
+            self.check_kill_switch().await;
+            self.spawn_kill_switch_monitor();
+        }
+        if self.config.gas_sample_interval.is_some() {
+            if let Err(err) = self.sample_gas_price().await {
+                warn!("Initial gas price sample failed: {}", err);
+            }
+            self.spawn_gas_sampler();
+        }
+        self.spawn_signer_probe();
+        self.spawn_dust_flusher();
+        self.spawn_payout_worker();
+        self.spawn_retry_worker();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{TEST_OPERATOR_KEY, TEST_TREASURY};
+    use super::super::SelfTestOptions;
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use tracing::span;
+
+    /// Counts the info lines this module logs
+    struct InfoCounter(Arc<AtomicUsize>);
+
+    impl tracing::Subscriber for InfoCounter {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            *metadata.level() == tracing::Level::INFO
+                && metadata.target() == module_path!().trim_end_matches("::tests")
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    fn unreachable_node() -> Result<EthereumPayoutConfig, ServiceStatus> {
+        Ok(EthereumPayoutConfig::new(
+            "http://127.0.0.1:1",
+            TEST_TREASURY,
+            TEST_OPERATOR_KEY,
+            31337,
+        ))
+    }
+
+    const ENV: [&str; 4] = [
+        "ETHEREUM_RPC_URL",
+        "TREASURY_ADDRESS",
+        "OPERATOR_PRIVATE_KEY",
+        "CHAIN_ID",
+    ];
+
+    #[tokio::test]
+    async fn publishes_the_service_once_its_config_appears() {
+        for var in &ENV {
+            std::env::remove_var(var);
+        }
+        let slot: &'static ServiceSlot = leak(RwLock::new(None));
+        assert_eq!(
+            initialize(slot, config_from_env).await,
+            ServiceStatus::Disabled
+        );
+        let retry = tokio::spawn(retry_with_backoff(
+            leak(InitGuard::new()),
+            Duration::from_millis(10),
+            Duration::from_millis(40),
+            move || initialize(slot, config_from_env),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(slot.read().unwrap().is_none());
+
+        for (var, value) in ENV.iter().zip([
+            "http://127.0.0.1:1",
+            TEST_TREASURY,
+            TEST_OPERATOR_KEY,
+            "31337",
+        ]) {
+            std::env::set_var(var, value);
+        }
+        tokio::time::timeout(Duration::from_secs(5), retry)
+            .await
+            .expect("initialization was not retried")
+            .unwrap();
+        let service = slot.read().unwrap().clone().unwrap();
+        assert_eq!(service.config().expected_chain_id, 31337);
+        for var in &ENV {
+            std::env::remove_var(var);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_construction_is_retried() {
+        let slot: &'static ServiceSlot = leak(RwLock::new(None));
+        // The store's volume is only mounted before the third attempt
+        let dir = std::env::temp_dir().join(format!("payouts-{}", uuid::Uuid::new_v4()));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let store_dir = dir.clone();
+        let load = move || {
+            if counter.fetch_add(1, Ordering::SeqCst) == 2 {
+                std::fs::create_dir(&store_dir).unwrap();
+            }
+            let mut config = EthereumPayoutConfig::new(
+                "http://127.0.0.1:1",
+                TEST_TREASURY,
+                TEST_OPERATOR_KEY,
+                31337,
+            );
+            config.store_path = Some(store_dir.join("payouts.jsonl"));
+            Ok(config)
+        };
+        // The missing directory fails the store and so the service
+        assert!(matches!(
+            initialize(slot, &load).await,
+            ServiceStatus::Misconfigured { missing, errors } if missing.is_empty() && errors.len() == 1
+        ));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            retry_with_backoff(
+                leak(InitGuard::new()),
+                Duration::from_millis(1),
+                Duration::from_millis(4),
+                || initialize(slot, &load),
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        let first = slot.read().unwrap().clone().unwrap();
+        assert!(matches!(
+            initialize(slot, &load).await,
+            ServiceStatus::Ready { .. }
+        ));
+        let second = slot.read().unwrap().clone().unwrap();
+        assert!(second.store().is_persistent());
+        // The replaced service's background tasks were stopped
+        assert!(first.is_cancelled());
+        assert!(!second.is_cancelled());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn services_failing_their_self_test_are_not_published() {
+        let slot: &'static ServiceSlot = leak(RwLock::new(None));
+        let load = || {
+            let mut config = unreachable_node()?;
+            config.self_test = Some(SelfTestOptions {
+                timeout: Duration::from_secs(2),
+                canary: None,
+            });
+            Ok(config)
+        };
+        match initialize(slot, load).await {
+            ServiceStatus::Misconfigured { errors, .. } => {
+                assert!(errors[0].starts_with("Self-test failed: chain_id: "))
+            }
+            other => panic!("expected the self-test to fail, got {:?}", other),
+        }
+        assert!(slot.read().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_attempt() {
+        let guard = leak(InitGuard::new());
+        let slot: &'static ServiceSlot = leak(RwLock::new(None));
+        let constructions = Arc::new(AtomicUsize::new(0));
+        let logged = Arc::new(AtomicUsize::new(0));
+        let _logging = tracing::subscriber::set_default(InfoCounter(logged.clone()));
+        let init = || {
+            let constructions = constructions.clone();
+            guard.run(move || {
+                let constructions = constructions.clone();
+                async move {
+                    constructions.fetch_add(1, Ordering::SeqCst);
+                    // Slow enough for the other callers to arrive meanwhile
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    initialize(slot, unreachable_node).await
+                }
+            })
+        };
+
+        let calls: Vec<_> = (0..16).map(|_| tokio::spawn(init())).collect();
+        for call in calls {
+            call.await.unwrap();
+        }
+        let service = slot.read().unwrap().clone().unwrap();
+        let references = Arc::strong_count(&service);
+        init().await;
+
+        assert_eq!(constructions.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(
+            &service,
+            &slot.read().unwrap().clone().unwrap()
+        ));
+        assert!(!service.is_cancelled());
+        // No more tasks hold the service than the one start spawned
+        assert_eq!(Arc::strong_count(&service), references);
+        assert_eq!(logged.load(Ordering::SeqCst), 1);
+    }
+}
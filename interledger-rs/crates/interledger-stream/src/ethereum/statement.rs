@@ -0,0 +1,362 @@
+//! Statements of what a recipient was paid over a period
+//!
+//! Built from the payout store, which has no index by recipient, so every
+//! statement scans the period page by page. Totals always cover the whole
+//! period; the listed payouts are paged with the store's cursors.
+
+use super::destination::is_hex_address;
+use super::export::format_amount;
+use super::store::{PageCursor, PayoutRecord, PayoutStatus, Timestamp};
+use super::{EthereumPayoutService, PayoutError};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Number of records read from the store per page while building a statement
+const STATEMENT_PAGE_SIZE: usize = 500;
+
+/// One payout listed in a statement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementEntry {
+    pub payment_id: String,
+    pub asset_code: String,
+    /// Amount in the asset's base units
+    pub amount: u64,
+    pub decimals: u8,
+    pub tx_hash: Option<String>,
+    pub status: PayoutStatus,
+    pub timestamp: Timestamp,
+}
+
+/// What a recipient was paid in one asset
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssetTotal {
+    pub asset_code: String,
+    pub decimals: u8,
+    /// Confirmed payouts, in base units
+    pub paid: u128,
+    pub count: u64,
+    /// Payouts sent but not confirmed yet, or held for approval
+    pub pending: u128,
+    /// Dust owed and not yet paid out, regardless of the period
+    pub accumulated: u64,
+}
+
+/// Payouts to one recipient over a period
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipientStatement {
+    pub recipient: String,
+    pub from: Timestamp,
+    pub to: Timestamp,
+    /// Per asset, ordered by asset code
+    pub totals: Vec<AssetTotal>,
+    /// Confirmed and pending payouts of the requested page, oldest first
+    pub payouts: Vec<StatementEntry>,
+    /// Where the next page starts, `None` on the last page
+    pub next_page: Option<PageCursor>,
+}
+
+impl RecipientStatement {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "recipient": self.recipient,
+            "from": self.from.to_rfc3339(),
+            "to": self.to.to_rfc3339(),
+            // u128 does not round-trip through JSON numbers
+            "totals": self.totals.iter().map(|total| json!({
+                "asset_code": total.asset_code,
+                "paid": total.paid.to_string(),
+                "count": total.count,
+                "pending": total.pending.to_string(),
+                "accumulated": total.accumulated,
+            })).collect::<Vec<_>>(),
+            "payouts": self.payouts.iter().map(|entry| json!({
+                "payment_id": entry.payment_id,
+                "asset_code": entry.asset_code,
+                "amount": format_amount(entry.amount, entry.decimals),
+                "tx_hash": entry.tx_hash,
+                "status": entry.status.as_str(),
+                "timestamp": entry.timestamp.to_rfc3339(),
+            })).collect::<Vec<_>>(),
+            "next_page": self.next_page.map(|cursor| json!({
+                "timestamp": cursor.timestamp.to_rfc3339(),
+                "payment_id": format!("0x{}", hex::encode(cursor.payment_id)),
+            })),
+        })
+    }
+}
+
+impl From<&PayoutRecord> for StatementEntry {
+    fn from(record: &PayoutRecord) -> Self {
+        StatementEntry {
+            payment_id: record.payment_id_hex(),
+            asset_code: record.asset_code.clone(),
+            amount: record.amount,
+            decimals: record.decimals,
+            tx_hash: record.tx_hash.clone(),
+            status: record.status,
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Statement of the payouts to `recipient` with `from <= timestamp < to`,
+    /// listing up to `limit` of them after `after`
+    ///
+    /// The recipient may be checksummed or in any case. A `limit` of zero
+    /// lists one payout.
+    pub fn statement(
+        &self,
+        recipient: &str,
+        from: Timestamp,
+        to: Timestamp,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Result<RecipientStatement, PayoutError> {
+        if !is_hex_address(recipient) {
+            return Err(PayoutError::RecipientInvalid {
+                recipient: recipient.to_string(),
+                reason: "not an address",
+            });
+        }
+        let limit = limit.max(1);
+        let mut totals: BTreeMap<String, AssetTotal> = BTreeMap::new();
+        let mut payouts = Vec::new();
+        let mut next_page = None;
+        let mut last_listed = None;
+
+        let mut cursor = None;
+        loop {
+            let page = self.store.list_range(from, to, cursor, STATEMENT_PAGE_SIZE);
+            for record in page
+                .iter()
+                .filter(|r| r.recipient.eq_ignore_ascii_case(recipient))
+                .filter(|r| r.is_committed())
+            {
+                let total = totals
+                    .entry(record.asset_code.clone())
+                    .or_insert_with(|| AssetTotal {
+                        asset_code: record.asset_code.clone(),
+                        decimals: record.decimals,
+                        ..AssetTotal::default()
+                    });
+                if record.status == PayoutStatus::Confirmed {
+                    total.paid += u128::from(record.amount);
+                    total.count += 1;
+                } else {
+                    total.pending += u128::from(record.amount);
+                }
+
+                let position = PageCursor::from(record);
+                if after.is_some_and(|after| position <= after) || next_page.is_some() {
+                    continue;
+                }
+                if payouts.len() == limit {
+                    next_page = last_listed;
+                } else {
+                    payouts.push(StatementEntry::from(record));
+                    last_listed = Some(position);
+                }
+            }
+            if page.len() < STATEMENT_PAGE_SIZE {
+                break;
+            }
+            cursor = page.last().map(PageCursor::from);
+        }
+
+        for (asset_code, accumulated) in self.dust_balances(recipient) {
+            totals
+                .entry(asset_code.clone())
+                .or_insert_with(|| AssetTotal {
+                    asset_code: asset_code.clone(),
+                    decimals: self
+                        .config
+                        .assets
+                        .get(&asset_code)
+                        .map_or(0, |a| a.decimals),
+                    ..AssetTotal::default()
+                })
+                .accumulated = accumulated;
+        }
+
+        Ok(RecipientStatement {
+            recipient: recipient.to_string(),
+            from,
+            to,
+            totals: totals.into_values().collect(),
+            payouts,
+            next_page,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport};
+    use super::super::{PayoutRequest, PhaseTimings};
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    const RECIPIENT: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+    const OTHER: &str = "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC";
+
+    fn record(i: u32, recipient: &str, asset_code: &str, status: PayoutStatus) -> PayoutRecord {
+        let mut payment_id = [0u8; 32];
+        payment_id[..4].copy_from_slice(&i.to_be_bytes());
+        PayoutRecord {
+            payment_id,
+            namespace: None,
+            payment_id_strategy: None,
+            attribution: Default::default(),
+            destination: format!("test.receiver.eth.31337.{}.{}.t", asset_code, recipient),
+            sequence: u64::from(i),
+            recipient: recipient.to_string(),
+            asset_code: asset_code.to_string(),
+            asset_segment: None,
+            amount: 100 + u64::from(i),
+            decimals: 6,
+            tx_hash: Some(format!("0x{:064x}", i)),
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: None,
+            memo: None,
+            status,
+            deadline: None,
+            last_error: None,
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: Utc.timestamp_opt(1_700_000_000 + i64::from(i), 0).unwrap(),
+            serial: u64::from(i) + 1,
+            pinned_block: None,
+            treasury_payout_id: None,
+        }
+    }
+
+    /// 1200 confirmed EURC payouts to `RECIPIENT`, interleaved with USDC,
+    /// failed and other recipients' payouts
+    fn seeded_service() -> EthereumPayoutService {
+        let mut config = test_config();
+        config.assets.apply_min_payouts("EURC:1000").unwrap();
+        let service = test_service(config, MockTransport::new());
+        for i in 0..1200 {
+            service
+                .store
+                .save(record(i * 4, RECIPIENT, "EURC", PayoutStatus::Confirmed));
+            service
+                .store
+                .save(record(i * 4 + 1, OTHER, "EURC", PayoutStatus::Confirmed));
+            let status = if i % 2 == 0 {
+                PayoutStatus::Failed
+            } else {
+                PayoutStatus::Submitted
+            };
+            service
+                .store
+                .save(record(i * 4 + 2, &RECIPIENT.to_lowercase(), "EURC", status));
+        }
+        for i in 0..5 {
+            service.store.save(record(
+                10_000 + i,
+                RECIPIENT,
+                "USDC",
+                PayoutStatus::Confirmed,
+            ));
+        }
+        service
+    }
+
+    fn all_time() -> (Timestamp, Timestamp) {
+        (
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Utc.timestamp_opt(2_000_000_000, 0).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn totals_cover_assets_pending_and_dust() {
+        let service = seeded_service();
+        let destination = format!("test.receiver.eth.31337.EURC.{}.t", RECIPIENT);
+        service
+            .execute(&PayoutRequest::new(destination, 7, 1))
+            .await
+            .unwrap();
+
+        let (from, to) = all_time();
+        let statement = service
+            .statement(&RECIPIENT.to_lowercase(), from, to, None, 10)
+            .unwrap();
+        let eurc = &statement.totals[0];
+        assert_eq!(eurc.asset_code, "EURC");
+        assert_eq!(eurc.count, 1200);
+        assert_eq!(eurc.paid, (0..1200u128).map(|i| 100 + i * 4).sum::<u128>());
+        // Every other unconfirmed record is submitted, the rest failed
+        assert_eq!(
+            eurc.pending,
+            (0..1200u128)
+                .filter(|i| i % 2 == 1)
+                .map(|i| 102 + i * 4)
+                .sum::<u128>()
+        );
+        assert_eq!(eurc.accumulated, 7);
+        assert_eq!(statement.totals[1].asset_code, "USDC");
+        assert_eq!(statement.totals[1].paid, (10_100..10_105).sum::<u128>());
+        assert_eq!(statement.payouts.len(), 10);
+        assert_eq!(statement.to_json()["totals"][0]["count"], 1200);
+
+        assert_eq!(
+            service
+                .statement(RECIPIENT, from, to, None, 10)
+                .unwrap()
+                .totals,
+            statement.totals
+        );
+        assert!(service.statement("alice", from, to, None, 10).is_err());
+    }
+
+    #[test]
+    fn pages_through_every_payout_once() {
+        let service = seeded_service();
+        let (from, to) = all_time();
+        // 1200 confirmed, 600 submitted and 5 USDC payouts
+        let listed = 1805;
+
+        for limit in [7, 600, 1804, 1805, 2000] {
+            let mut seen = Vec::new();
+            let mut after = None;
+            loop {
+                let statement = service
+                    .statement(RECIPIENT, from, to, after, limit)
+                    .unwrap();
+                assert!(statement.payouts.len() <= limit);
+                seen.extend(
+                    statement
+                        .payouts
+                        .iter()
+                        .map(|entry| entry.payment_id.clone()),
+                );
+                match statement.next_page {
+                    Some(cursor) => after = Some(cursor),
+                    None => break,
+                }
+            }
+            assert_eq!(seen.len(), listed, "limit {}", limit);
+            seen.sort();
+            seen.dedup();
+            assert_eq!(seen.len(), listed, "limit {}", limit);
+        }
+        // A period boundary excludes records at its end
+        let to = Utc.timestamp_opt(1_700_000_004, 0).unwrap();
+        let statement = service.statement(RECIPIENT, from, to, None, 10).unwrap();
+        assert_eq!(statement.totals[0].count, 1);
+        assert_eq!(statement.totals[0].pending, 0);
+        assert!(statement.next_page.is_none());
+    }
+}
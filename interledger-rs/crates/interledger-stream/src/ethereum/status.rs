@@ -0,0 +1,234 @@
+//! Whether payouts are enabled, and if not, whether that was intended
+//!
+//! Running without any of the required settings disables payouts. Setting
+//! only some of them, or settings that do not validate, is a misconfiguration,
+//! which `maybe_execute_payout` keeps warning about at most once per
+//! [`MISCONFIGURED_WARN_INTERVAL`].
+
+use super::{EthereumPayoutConfig, ProviderCapabilities, Timestamp, TreasuryCapabilities};
+use std::cell::RefCell;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Settings without which no payout service is built
+pub const REQUIRED_ENV: [&str; 4] = [
+    "ETHEREUM_RPC_URL",
+    "TREASURY_ADDRESS",
+    "OPERATOR_PRIVATE_KEY",
+    "CHAIN_ID",
+];
+
+/// Shortest time between two warnings about a misconfigured service
+pub const MISCONFIGURED_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// State of the payout service from its last initialization attempt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// None of the required settings are present; payouts are off on purpose
+    Disabled,
+    /// Some settings are present, but no service could be built from them
+    Misconfigured {
+        /// Required settings that are absent
+        missing: Vec<&'static str>,
+        /// Settings or services that failed to validate
+        errors: Vec<String>,
+    },
+    /// The service is running
+    Ready {
+        /// What each service's Treasury was found to support, one per tenant
+        treasuries: Vec<TreasuryCapabilities>,
+        /// Optional RPC methods each service's provider serves, one per tenant
+        providers: Vec<ProviderCapabilities>,
+        /// Compact [`VersionInfo`](super::VersionInfo) of each service, one per tenant
+        versions: Vec<String>,
+    },
+}
+
+static STATUS: RwLock<ServiceStatus> = RwLock::new(ServiceStatus::Disabled);
+
+/// State of the global payout service, `Disabled` before it is initialized
+pub fn status() -> ServiceStatus {
+    STATUS.read().unwrap().clone()
+}
+
+pub(super) fn set_status(status: ServiceStatus) {
+    *STATUS.write().unwrap() = status;
+}
+
+impl ServiceStatus {
+    pub(super) fn misconfigured(error: impl ToString) -> Self {
+        ServiceStatus::Misconfigured {
+            missing: Vec::new(),
+            errors: vec![error.to_string()],
+        }
+    }
+}
+
+/// Build a config from `var` as [`EthereumPayoutConfig::from_vars`] does,
+/// or tell why there is none
+pub(super) fn config_from_vars(
+    var: impl Fn(&str) -> Option<String>,
+) -> Result<EthereumPayoutConfig, ServiceStatus> {
+    let missing: Vec<&'static str> = REQUIRED_ENV
+        .iter()
+        .copied()
+        .filter(|name| var(name).is_none())
+        .collect();
+    if missing.len() == REQUIRED_ENV.len() {
+        return Err(ServiceStatus::Disabled);
+    }
+    if !missing.is_empty() {
+        return Err(ServiceStatus::Misconfigured {
+            missing,
+            errors: Vec::new(),
+        });
+    }
+    // Settings are read in order and parsing stops at the first bad one,
+    // so the last setting read is the one that failed
+    let last_read = RefCell::new(None);
+    EthereumPayoutConfig::from_vars(|name| {
+        let value = var(name);
+        if value.is_some() {
+            *last_read.borrow_mut() = Some(name.to_string());
+        }
+        value
+    })
+    .ok_or_else(|| {
+        ServiceStatus::misconfigured(format!(
+            "{} is invalid or incomplete",
+            last_read.into_inner().unwrap_or_default()
+        ))
+    })
+}
+
+/// Limits how often an unavailable service is warned about
+pub(super) struct UnavailableLog {
+    last_warning: Mutex<Option<Timestamp>>,
+}
+
+impl UnavailableLog {
+    pub(super) const fn new() -> Self {
+        UnavailableLog {
+            last_warning: Mutex::new(None),
+        }
+    }
+
+    /// Log that a payout found no service in `status`, returning whether it
+    /// was warned about rather than logged at debug level
+    pub(super) fn report(&self, status: &ServiceStatus, destination: &str, now: Timestamp) -> bool {
+        let (missing, errors) = match status {
+            ServiceStatus::Misconfigured { missing, errors } => (missing, errors),
+            ServiceStatus::Disabled => {
+                debug!("Ethereum payouts are disabled, not paying {}", destination);
+                return false;
+            }
+            ServiceStatus::Ready { .. } => {
+                debug!("No Ethereum payout service pays {}", destination);
+                return false;
+            }
+        };
+        let interval = chrono::Duration::from_std(MISCONFIGURED_WARN_INTERVAL).unwrap();
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|last| now < last + interval) {
+            debug!(
+                "Ethereum payout service is misconfigured, not paying {}",
+                destination
+            );
+            return false;
+        }
+        *last_warning = Some(now);
+        warn!(
+            "Ethereum payout service is misconfigured, payouts are skipped (missing: {:?}, errors: {:?})",
+            missing, errors
+        );
+        true
+    }
+}
+
+/// Warnings about skips for want of a service
+pub(super) static UNAVAILABLE: UnavailableLog = UnavailableLog::new();
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{TEST_OPERATOR_KEY, TEST_TREASURY};
+    use super::*;
+    use chrono::Utc;
+    use std::collections::HashMap;
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    const COMPLETE: [(&str, &str); 4] = [
+        ("ETHEREUM_RPC_URL", "http://127.0.0.1:8545"),
+        ("TREASURY_ADDRESS", TEST_TREASURY),
+        ("OPERATOR_PRIVATE_KEY", TEST_OPERATOR_KEY),
+        ("CHAIN_ID", "31337"),
+    ];
+
+    #[test]
+    fn distinguishes_disabled_from_misconfigured() {
+        assert_eq!(
+            config_from_vars(vars(&[("PAYOUT_ROUNDING", "up")])).unwrap_err(),
+            ServiceStatus::Disabled
+        );
+        assert_eq!(
+            config_from_vars(vars(&COMPLETE[..2])).unwrap_err(),
+            ServiceStatus::Misconfigured {
+                missing: vec!["OPERATOR_PRIVATE_KEY", "CHAIN_ID"],
+                errors: Vec::new(),
+            }
+        );
+
+        let mut settings = COMPLETE.to_vec();
+        settings.push(("PAYOUT_ROUNDING", "sideways"));
+        assert_eq!(
+            config_from_vars(vars(&settings)).unwrap_err(),
+            ServiceStatus::misconfigured("PAYOUT_ROUNDING is invalid or incomplete")
+        );
+        settings[3] = ("CHAIN_ID", "mainnet");
+        assert_eq!(
+            config_from_vars(vars(&settings)).unwrap_err(),
+            ServiceStatus::misconfigured("CHAIN_ID is invalid or incomplete")
+        );
+
+        let config = config_from_vars(vars(&COMPLETE)).unwrap();
+        assert_eq!(config.expected_chain_id, 31337);
+    }
+
+    #[test]
+    fn warns_about_misconfiguration_once_per_interval() {
+        let log = UnavailableLog::new();
+        let misconfigured = ServiceStatus::Misconfigured {
+            missing: vec!["CHAIN_ID"],
+            errors: Vec::new(),
+        };
+        let start = Utc::now();
+        let at = |secs| start + chrono::Duration::seconds(secs);
+
+        assert!(log.report(&misconfigured, "g.a", at(0)));
+        assert!(!log.report(&misconfigured, "g.a", at(1)));
+        assert!(!log.report(&misconfigured, "g.a", at(59)));
+        assert!(log.report(&misconfigured, "g.a", at(60)));
+        assert!(!log.report(&misconfigured, "g.a", at(61)));
+
+        // Disabled and ready services are never warned about
+        let quiet = UnavailableLog::new();
+        assert!(!quiet.report(&ServiceStatus::Disabled, "g.a", at(0)));
+        assert!(!quiet.report(
+            &ServiceStatus::Ready {
+                treasuries: Vec::new(),
+                providers: Vec::new(),
+                versions: Vec::new(),
+            },
+            "g.a",
+            at(0)
+        ));
+        assert!(quiet.report(&misconfigured, "g.a", at(0)));
+    }
+}
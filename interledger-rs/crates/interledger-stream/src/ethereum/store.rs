@@ -0,0 +1,1360 @@
+//! Payout record storage
+//!
+//! Every payout the service submits is recorded here so that it can later be
+//! queried, reconciled and exported.
+
+use super::allowance::AllowanceApproval;
+use super::delivery::AmountMismatch;
+use super::retry::{RetryAttempt, RetryState};
+use super::streak::{FailureStreak, FAILURE_STREAK_STATE};
+use super::timing::PhaseTimings;
+use super::{Attribution, Conversion, ExchangeRate, IlpAmount, RoundingMode, TokenAmount, Wei};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info};
+
+/// Layout of the records a [`FilePayoutStore`] writes
+///
+/// 1. No header; every save and removal appended as its own line
+/// 2. A `{"schema_version": 2}` header line before the records
+pub const STORE_SCHEMA_VERSION: u32 = 2;
+
+/// Lines of a store file after its header, with their line numbers
+type Lines = Vec<(usize, Value)>;
+
+/// Steps upgrading the lines of a file from version `i + 1` to `i + 2`
+const MIGRATIONS: [fn(Lines) -> Lines; STORE_SCHEMA_VERSION as usize - 1] = [compact_log];
+
+/// What opening a store written at an older schema version does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreMigration {
+    /// Copy the file aside as `<path>.v<version>.bak`, then migrate it
+    #[default]
+    Backup,
+    /// Fail to open, leaving the file untouched
+    Refuse,
+}
+
+impl StoreMigration {
+    pub fn parse(migration: &str) -> Option<Self> {
+        match migration {
+            "backup" => Some(StoreMigration::Backup),
+            "refuse" => Some(StoreMigration::Refuse),
+            _ => None,
+        }
+    }
+}
+
+/// Point in time used for payout records
+pub type Timestamp = DateTime<Utc>;
+
+/// Lifecycle state of a payout record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutStatus {
+    /// Transaction was broadcast but no receipt has been seen yet
+    Submitted,
+    /// Transaction was mined successfully
+    Confirmed,
+    /// Transaction could not be submitted or reverted on-chain
+    Failed,
+    /// Proposed to a Safe and waiting for the other owners to approve
+    PendingApproval,
+    /// Not completed by its deadline; left for manual handling
+    Abandoned,
+    /// Replaced on-chain by a self-transfer before it was mined
+    Cancelled,
+    /// Approved after being held; replaced by the record of its transaction once sent
+    Approved,
+    /// Refused by an approver or not approved in time
+    Rejected,
+    /// Never attempted; see `last_error` for why
+    Skipped,
+    /// Failed transiently and waiting in the retry queue
+    RetryScheduled,
+    /// Confirmed in the store, but the Treasury has not processed it; left
+    /// for an operator to re-execute
+    SuspectedPhantom,
+}
+
+/// A single payout as seen by the service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutRecord {
+    pub payment_id: [u8; 32],
+    /// Namespace the payment ID was derived in, `None` for unnamespaced IDs
+    pub namespace: Option<[u8; 32]>,
+    /// Label of the [`PaymentIdStrategy`](super::PaymentIdStrategy) that made
+    /// the payment ID, `None` for records from before it was noted
+    pub payment_id_strategy: Option<String>,
+    /// Tags its gas cost is attributed to
+    pub attribution: Attribution,
+    pub destination: String,
+    pub sequence: u64,
+    pub recipient: String,
+    pub asset_code: String,
+    /// Asset segment of the destination when it was a token address rather
+    /// than the code
+    pub asset_segment: Option<String>,
+    /// Amount in the token's base units
+    pub amount: u64,
+    /// Decimals of the asset at the time of the payout
+    pub decimals: u8,
+    pub tx_hash: Option<String>,
+    /// Hash of the Safe transaction for payouts proposed to a Safe
+    pub safe_tx_hash: Option<String>,
+    /// Self-transfer sent to replace the payout's transaction
+    pub cancel_tx_hash: Option<String>,
+    pub block_number: Option<u64>,
+    /// Gas price the transaction was submitted with, in wei
+    pub gas_price: Option<u128>,
+    /// Gas used according to the receipt
+    pub gas_used: Option<u64>,
+    /// Price actually paid per gas according to the receipt, in wei
+    pub effective_gas_price: Option<u128>,
+    /// Total gas cost of the transaction in wei, including `l1_fee`
+    pub gas_cost: Option<u128>,
+    /// L1 data fee paid on top of L2 gas on OP-stack chains, in wei
+    pub l1_fee: Option<u128>,
+    /// Rate applied if the ILP amount was in another asset
+    pub conversion: Option<Conversion>,
+    /// Memo recorded on-chain with the payout
+    pub memo: Option<Vec<u8>>,
+    pub status: PayoutStatus,
+    /// Time by which the payout must be mined before it is abandoned, or
+    /// approved before it is rejected while held for approval
+    pub deadline: Option<Timestamp>,
+    /// Error that made the payout fail or be abandoned
+    pub last_error: Option<String>,
+    /// Time spent sending the payout and waiting for it to be mined
+    pub timings: PhaseTimings,
+    /// Attempts so far and the next one while the payout is scheduled for retry
+    pub retry: Option<RetryState>,
+    /// Failed attempts of a payout that was retried, oldest first
+    pub attempts: Vec<RetryAttempt>,
+    /// Set when the recipient received another amount than was sent
+    pub amount_mismatch: Option<AmountMismatch>,
+    /// Wall-clock time of the latest change
+    pub timestamp: Timestamp,
+    /// Position among the records the service created, unaffected by steps of
+    /// the wall clock; 0 for records from before serials were assigned
+    pub serial: u64,
+    /// Block the chain reads made before sending were pinned to, `None` if
+    /// they were made at `latest`
+    pub pinned_block: Option<u64>,
+    /// ID the Treasury assigned to the payout, as its simulation returned it
+    pub treasury_payout_id: Option<String>,
+}
+
+impl PayoutStatus {
+    pub(super) fn as_str(self) -> &'static str {
+        match self {
+            PayoutStatus::Submitted => "submitted",
+            PayoutStatus::Confirmed => "confirmed",
+            PayoutStatus::Failed => "failed",
+            PayoutStatus::PendingApproval => "pending_approval",
+            PayoutStatus::Abandoned => "abandoned",
+            PayoutStatus::Cancelled => "cancelled",
+            PayoutStatus::Approved => "approved",
+            PayoutStatus::Rejected => "rejected",
+            PayoutStatus::Skipped => "skipped",
+            PayoutStatus::RetryScheduled => "retry_scheduled",
+            PayoutStatus::SuspectedPhantom => "suspected_phantom",
+        }
+    }
+
+    fn parse(status: &str) -> Option<Self> {
+        match status {
+            "submitted" => Some(PayoutStatus::Submitted),
+            "confirmed" => Some(PayoutStatus::Confirmed),
+            "failed" => Some(PayoutStatus::Failed),
+            "pending_approval" => Some(PayoutStatus::PendingApproval),
+            "abandoned" => Some(PayoutStatus::Abandoned),
+            "cancelled" => Some(PayoutStatus::Cancelled),
+            "approved" => Some(PayoutStatus::Approved),
+            "rejected" => Some(PayoutStatus::Rejected),
+            "skipped" => Some(PayoutStatus::Skipped),
+            "retry_scheduled" => Some(PayoutStatus::RetryScheduled),
+            "suspected_phantom" => Some(PayoutStatus::SuspectedPhantom),
+            _ => None,
+        }
+    }
+}
+
+impl PayoutRecord {
+    pub fn payment_id_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.payment_id))
+    }
+
+    /// Amount paid, in the asset's base units
+    pub fn token_amount(&self) -> TokenAmount {
+        TokenAmount::new(self.asset_code.clone(), self.amount)
+    }
+
+    /// Gas and L1 data fees the payout cost, once its receipt is in
+    pub fn fee(&self) -> Option<Wei> {
+        self.gas_cost.map(Wei::new)
+    }
+
+    /// Whether the payout did not happen and may be attempted again.
+    ///
+    /// An abandoned payout whose transaction was broadcast may still be mined,
+    /// so it only counts if nothing on-chain rejects a second attempt.
+    pub fn is_retryable(&self) -> bool {
+        match self.status {
+            PayoutStatus::Failed | PayoutStatus::Approved | PayoutStatus::RetryScheduled => true,
+            PayoutStatus::Abandoned => self.tx_hash.is_none(),
+            _ => false,
+        }
+    }
+
+    /// Whether the payout's value has left, or may still leave, the Treasury
+    pub fn is_committed(&self) -> bool {
+        match self.status {
+            PayoutStatus::Submitted
+            | PayoutStatus::Confirmed
+            | PayoutStatus::PendingApproval
+            | PayoutStatus::SuspectedPhantom => true,
+            PayoutStatus::Abandoned => self.tx_hash.is_some(),
+            PayoutStatus::Failed
+            | PayoutStatus::Cancelled
+            | PayoutStatus::Approved
+            | PayoutStatus::Rejected
+            | PayoutStatus::Skipped
+            | PayoutStatus::RetryScheduled => false,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "payment_id": self.payment_id_hex(),
+            "namespace": self.namespace.map(|namespace| format!("0x{}", hex::encode(namespace))),
+            "payment_id_strategy": self.payment_id_strategy,
+            "attribution": self.attribution.to_json(),
+            "destination": self.destination,
+            "sequence": self.sequence,
+            "recipient": self.recipient,
+            "asset_code": self.asset_code,
+            "asset_segment": self.asset_segment,
+            "amount": self.amount,
+            "decimals": self.decimals,
+            "tx_hash": self.tx_hash,
+            "safe_tx_hash": self.safe_tx_hash,
+            "cancel_tx_hash": self.cancel_tx_hash,
+            "block_number": self.block_number,
+            "gas_price": self.gas_price.map(|price| price.to_string()),
+            "gas_used": self.gas_used,
+            "effective_gas_price": self.effective_gas_price.map(|price| price.to_string()),
+            // u128 does not round-trip through JSON numbers
+            "gas_cost": self.gas_cost.map(|cost| cost.to_string()),
+            "l1_fee": self.l1_fee.map(|fee| fee.to_string()),
+            "conversion": self.conversion.as_ref().map(|conversion| json!({
+                "source_asset": conversion.rate.from_asset,
+                "source_amount": conversion.source_amount,
+                "rate": conversion.rate.rate.to_string(),
+                "scale": conversion.rate.scale,
+                "rate_timestamp": conversion.rate.timestamp.to_rfc3339(),
+                "rounding": conversion.rounding.as_str(),
+                "unrounded_amount": conversion.unrounded_amount(),
+                "rounded_amount": conversion.rounded_amount,
+                "carried": conversion.carried,
+            })),
+            "memo": self.memo.as_ref().map(|memo| format!("0x{}", hex::encode(memo))),
+            "status": self.status.as_str(),
+            "deadline": self.deadline.map(|deadline| deadline.to_rfc3339()),
+            "last_error": self.last_error,
+            "timings": self.timings.to_json(),
+            "retry": self.retry.map(RetryState::to_json),
+            "attempts": self.attempts.iter().map(RetryAttempt::to_json).collect::<Vec<_>>(),
+            "amount_mismatch": self.amount_mismatch.map(AmountMismatch::to_json),
+            "timestamp": self.timestamp.to_rfc3339(),
+            "serial": self.serial,
+            "pinned_block": self.pinned_block,
+            "treasury_payout_id": self.treasury_payout_id,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let mut payment_id = [0u8; 32];
+        let id = hex::decode(value["payment_id"].as_str()?.trim_start_matches("0x")).ok()?;
+        if id.len() != payment_id.len() {
+            return None;
+        }
+        payment_id.copy_from_slice(&id);
+        Some(PayoutRecord {
+            payment_id,
+            namespace: match value["namespace"].as_str() {
+                Some(namespace) => {
+                    let mut bytes = [0u8; 32];
+                    hex::decode_to_slice(namespace.trim_start_matches("0x"), &mut bytes).ok()?;
+                    Some(bytes)
+                }
+                None => None,
+            },
+            payment_id_strategy: value["payment_id_strategy"].as_str().map(str::to_string),
+            attribution: Attribution::from_json(&value["attribution"])?,
+            destination: value["destination"].as_str()?.to_string(),
+            sequence: value["sequence"].as_u64()?,
+            recipient: value["recipient"].as_str()?.to_string(),
+            asset_code: value["asset_code"].as_str()?.to_string(),
+            asset_segment: value["asset_segment"].as_str().map(str::to_string),
+            amount: value["amount"].as_u64()?,
+            decimals: u8::try_from(value["decimals"].as_u64()?).ok()?,
+            tx_hash: value["tx_hash"].as_str().map(str::to_string),
+            safe_tx_hash: value["safe_tx_hash"].as_str().map(str::to_string),
+            cancel_tx_hash: value["cancel_tx_hash"].as_str().map(str::to_string),
+            block_number: value["block_number"].as_u64(),
+            gas_price: parse_wei(&value["gas_price"])?,
+            gas_used: value["gas_used"].as_u64(),
+            effective_gas_price: parse_wei(&value["effective_gas_price"])?,
+            gas_cost: parse_wei(&value["gas_cost"])?,
+            l1_fee: parse_wei(&value["l1_fee"])?,
+            conversion: match value.get("conversion").filter(|c| !c.is_null()) {
+                Some(conversion) => {
+                    let rate = ExchangeRate {
+                        from_asset: conversion["source_asset"].as_str()?.to_string(),
+                        to_asset: value["asset_code"].as_str()?.to_string(),
+                        rate: conversion["rate"].as_str()?.parse().ok()?,
+                        scale: u8::try_from(conversion["scale"].as_u64()?).ok()?,
+                        timestamp: DateTime::parse_from_rfc3339(
+                            conversion["rate_timestamp"].as_str()?,
+                        )
+                        .ok()?
+                        .with_timezone(&Utc),
+                    };
+                    // Records from before rounding modes were half-even and carried nothing
+                    let rounding = match conversion["rounding"].as_str() {
+                        Some(rounding) => RoundingMode::parse(rounding)?,
+                        None => RoundingMode::HalfEven,
+                    };
+                    Some(Conversion {
+                        carried: conversion["carried"].as_i64().unwrap_or_default(),
+                        ..Conversion::new(
+                            IlpAmount::new(conversion["source_amount"].as_u64()?),
+                            rate,
+                            rounding,
+                        )?
+                    })
+                }
+                None => None,
+            },
+            memo: match value["memo"].as_str() {
+                Some(memo) => Some(hex::decode(memo.trim_start_matches("0x")).ok()?),
+                None => None,
+            },
+            status: PayoutStatus::parse(value["status"].as_str()?)?,
+            deadline: match value["deadline"].as_str() {
+                Some(deadline) => Some(
+                    DateTime::parse_from_rfc3339(deadline)
+                        .ok()?
+                        .with_timezone(&Utc),
+                ),
+                None => None,
+            },
+            last_error: value["last_error"].as_str().map(str::to_string),
+            timings: PhaseTimings::from_json(&value["timings"]),
+            retry: match value.get("retry").filter(|retry| !retry.is_null()) {
+                Some(retry) => Some(RetryState::from_json(retry)?),
+                None => None,
+            },
+            attempts: match value["attempts"].as_array() {
+                Some(attempts) => attempts
+                    .iter()
+                    .map(RetryAttempt::from_json)
+                    .collect::<Option<_>>()?,
+                None => Vec::new(),
+            },
+            amount_mismatch: match value
+                .get("amount_mismatch")
+                .filter(|mismatch| !mismatch.is_null())
+            {
+                Some(mismatch) => Some(AmountMismatch::from_json(mismatch)?),
+                None => None,
+            },
+            timestamp: DateTime::parse_from_rfc3339(value["timestamp"].as_str()?)
+                .ok()?
+                .with_timezone(&Utc),
+            serial: value["serial"].as_u64().unwrap_or_default(),
+            pinned_block: value["pinned_block"].as_u64(),
+            treasury_payout_id: value["treasury_payout_id"].as_str().map(str::to_string),
+        })
+    }
+}
+
+/// Read an optional wei amount stored as a decimal string
+fn parse_wei(value: &Value) -> Option<Option<u128>> {
+    match value.as_str() {
+        Some(wei) => Some(Some(wei.parse().ok()?)),
+        None => Some(None),
+    }
+}
+
+/// Position in an ordered listing of records, used to resume paging
+///
+/// Records are listed by serial, so a wall clock stepping back does not
+/// reorder them. Records without a serial come first, by timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageCursor {
+    pub serial: u64,
+    pub timestamp: Timestamp,
+    pub payment_id: [u8; 32],
+}
+
+impl From<&PayoutRecord> for PageCursor {
+    fn from(record: &PayoutRecord) -> Self {
+        PageCursor {
+            serial: record.serial,
+            timestamp: record.timestamp,
+            payment_id: record.payment_id,
+        }
+    }
+}
+
+/// Value of an asset paid out during one UTC day, in base units
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DailyTotals {
+    pub asset: u128,
+    /// Part of `asset` paid to a single recipient
+    pub recipient: u128,
+}
+
+/// Number of records read per page while summing daily totals
+const TOTALS_PAGE_SIZE: usize = 500;
+
+/// Storage backend for payout records
+pub trait PayoutStore: Send + Sync {
+    /// Insert a record, replacing any existing record with the same payment ID
+    fn save(&self, record: PayoutRecord);
+
+    /// Insert a record as [`Self::save`] does, failing if the backing storage
+    /// could not be written instead of only logging it
+    ///
+    /// Payouts that nothing on-chain deduplicates are sent only once this
+    /// succeeds, so persistent stores should report their write errors here.
+    fn try_save(&self, record: PayoutRecord) -> io::Result<()> {
+        self.save(record);
+        Ok(())
+    }
+
+    /// Look up a record by payment ID
+    fn get(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord>;
+
+    /// Delete a record, returning it if it existed
+    fn remove(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord>;
+
+    /// Return up to `limit` records with `from <= timestamp < to`, in
+    /// [`PageCursor`] order, starting strictly after `after` if given.
+    fn list_range(
+        &self,
+        from: Timestamp,
+        to: Timestamp,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Vec<PayoutRecord>;
+
+    /// Highest sequence of any record for the ILP destination
+    fn highest_sequence(&self, destination: &str) -> Option<u64>;
+
+    /// Highest serial of any record, so serials keep increasing across restarts
+    fn highest_serial(&self) -> u64 {
+        0
+    }
+
+    /// Records paying `sequence` to destinations starting with
+    /// `destination_prefix`, in [`PageCursor`] order
+    ///
+    /// More than one record matches when a payout was split, or when payment
+    /// IDs are derived from more than the destination and sequence.
+    fn find_by_sequence(&self, destination_prefix: &str, sequence: u64) -> Vec<PayoutRecord> {
+        let from = Utc.timestamp_opt(0, 0).unwrap();
+        let to = DateTime::<Utc>::MAX_UTC;
+        let mut found = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.list_range(from, to, cursor, TOTALS_PAGE_SIZE);
+            found.extend(
+                page.iter()
+                    .filter(|record| {
+                        record.sequence == sequence
+                            && record.destination.starts_with(destination_prefix)
+                    })
+                    .cloned(),
+            );
+            if page.len() < TOTALS_PAGE_SIZE {
+                return found;
+            }
+            cursor = page.last().map(PageCursor::from);
+        }
+    }
+
+    /// Approvals sent to top up the Treasury's allowance, oldest first.
+    /// Stores that do not keep approvals return none.
+    fn allowance_approvals(&self) -> Vec<AllowanceApproval> {
+        Vec::new()
+    }
+
+    /// Keep an approval sent to top up the Treasury's allowance
+    fn save_allowance_approval(&self, _approval: AllowanceApproval) {}
+
+    /// Per-recipient state of `kind` evicted from memory under `key`, see
+    /// [`recipient_state`](super::recipient_state). Stores that do not keep
+    /// it lose what is evicted.
+    fn recipient_state(&self, _kind: &str, _key: &str) -> Option<Value> {
+        None
+    }
+
+    /// Replace the evicted state of `kind` under `key`, removing it if `None`
+    fn save_recipient_state(&self, _kind: &str, _key: &str, _state: Option<Value>) {}
+
+    /// Keys with evicted state of `kind`, in order
+    fn recipient_state_keys(&self, _kind: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Whether records survive a restart of the connector
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    /// Kind of storage, for version reports: `memory`, `file`, or `custom`
+    /// for stores outside this crate
+    fn backend(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Whether the backing storage is currently usable
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Schema version the records are stored at; backends migrate older
+    /// data to [`STORE_SCHEMA_VERSION`] when opened
+    fn schema_version(&self) -> u32 {
+        STORE_SCHEMA_VERSION
+    }
+
+    /// Committed value of `asset_code` for records timestamped on `day`
+    fn daily_totals(&self, asset_code: &str, recipient: &str, day: NaiveDate) -> DailyTotals {
+        let from = Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).expect("midnight is valid"));
+        let to = from + chrono::Duration::days(1);
+        let mut totals = DailyTotals::default();
+        let mut cursor = None;
+        loop {
+            let page = self.list_range(from, to, cursor, TOTALS_PAGE_SIZE);
+            for record in page
+                .iter()
+                .filter(|r| r.asset_code == asset_code && r.is_committed())
+            {
+                totals.asset += u128::from(record.amount);
+                if record.recipient.eq_ignore_ascii_case(recipient) {
+                    totals.recipient += u128::from(record.amount);
+                }
+            }
+            if page.len() < TOTALS_PAGE_SIZE {
+                return totals;
+            }
+            cursor = page.last().map(PageCursor::from);
+        }
+    }
+}
+
+/// Non-persistent store keeping all records in memory
+#[derive(Default)]
+pub struct InMemoryPayoutStore {
+    inner: Mutex<InMemoryState>,
+}
+
+#[derive(Default)]
+struct InMemoryState {
+    /// Records in [`PageCursor`] order, so pages resume where the last ended
+    records: BTreeMap<PageCursor, PayoutRecord>,
+    /// Cursors by timestamp, to find where a range's first page starts
+    by_time: BTreeMap<(Timestamp, [u8; 32]), PageCursor>,
+    index: HashMap<[u8; 32], PageCursor>,
+    /// Cursors by the ILP sequence their record pays
+    by_sequence: HashMap<u64, BTreeSet<PageCursor>>,
+    highest_sequence: HashMap<String, u64>,
+    highest_serial: u64,
+    allowance_approvals: Vec<AllowanceApproval>,
+    /// Evicted per-recipient state by kind and key
+    recipient_states: BTreeMap<(String, String), Value>,
+}
+
+impl InMemoryState {
+    fn remove(&mut self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        let cursor = self.index.remove(payment_id)?;
+        self.by_time.remove(&(cursor.timestamp, cursor.payment_id));
+        let record = self.records.remove(&cursor)?;
+        if let Some(cursors) = self.by_sequence.get_mut(&record.sequence) {
+            cursors.remove(&cursor);
+            if cursors.is_empty() {
+                self.by_sequence.remove(&record.sequence);
+            }
+        }
+        Some(record)
+    }
+}
+
+impl InMemoryPayoutStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PayoutStore for InMemoryPayoutStore {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    fn save(&self, record: PayoutRecord) {
+        let mut state = self.inner.lock().unwrap();
+        let highest = state
+            .highest_sequence
+            .entry(record.destination.clone())
+            .or_insert(record.sequence);
+        *highest = (*highest).max(record.sequence);
+        state.highest_serial = state.highest_serial.max(record.serial);
+        state.remove(&record.payment_id);
+        let cursor = PageCursor::from(&record);
+        state.index.insert(record.payment_id, cursor);
+        state
+            .by_time
+            .insert((record.timestamp, record.payment_id), cursor);
+        state
+            .by_sequence
+            .entry(record.sequence)
+            .or_default()
+            .insert(cursor);
+        state.records.insert(cursor, record);
+    }
+
+    fn get(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        let state = self.inner.lock().unwrap();
+        let cursor = state.index.get(payment_id)?;
+        state.records.get(cursor).cloned()
+    }
+
+    fn remove(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        self.inner.lock().unwrap().remove(payment_id)
+    }
+
+    fn list_range(
+        &self,
+        from: Timestamp,
+        to: Timestamp,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Vec<PayoutRecord> {
+        if from >= to {
+            return Vec::new();
+        }
+        let state = self.inner.lock().unwrap();
+        // Serials follow the clock closely, so records in range are close
+        // together; the first page starts at the earliest of them rather
+        // than at the oldest record
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => match state
+                .by_time
+                .range((from, [0u8; 32])..(to, [0u8; 32]))
+                .map(|(_, cursor)| *cursor)
+                .min()
+            {
+                Some(first) => Bound::Included(first),
+                None => return Vec::new(),
+            },
+        };
+        state
+            .records
+            .range((start, Bound::Unbounded))
+            .map(|(_, record)| record)
+            .filter(|record| from <= record.timestamp && record.timestamp < to)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn highest_sequence(&self, destination: &str) -> Option<u64> {
+        let state = self.inner.lock().unwrap();
+        state.highest_sequence.get(destination).copied()
+    }
+
+    fn highest_serial(&self) -> u64 {
+        self.inner.lock().unwrap().highest_serial
+    }
+
+    fn allowance_approvals(&self) -> Vec<AllowanceApproval> {
+        self.inner.lock().unwrap().allowance_approvals.clone()
+    }
+
+    fn recipient_state(&self, kind: &str, key: &str) -> Option<Value> {
+        let state = self.inner.lock().unwrap();
+        state
+            .recipient_states
+            .get(&(kind.to_string(), key.to_string()))
+            .cloned()
+    }
+
+    fn save_recipient_state(&self, kind: &str, key: &str, value: Option<Value>) {
+        let mut state = self.inner.lock().unwrap();
+        let key = (kind.to_string(), key.to_string());
+        match value {
+            Some(value) => state.recipient_states.insert(key, value),
+            None => state.recipient_states.remove(&key),
+        };
+    }
+
+    fn recipient_state_keys(&self, kind: &str) -> Vec<String> {
+        let state = self.inner.lock().unwrap();
+        state
+            .recipient_states
+            .keys()
+            .filter(|(of, _)| of == kind)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    fn save_allowance_approval(&self, approval: AllowanceApproval) {
+        self.inner
+            .lock()
+            .unwrap()
+            .allowance_approvals
+            .push(approval);
+    }
+
+    fn find_by_sequence(&self, destination_prefix: &str, sequence: u64) -> Vec<PayoutRecord> {
+        let state = self.inner.lock().unwrap();
+        state
+            .by_sequence
+            .get(&sequence)
+            .into_iter()
+            .flatten()
+            .map(|cursor| &state.records[cursor])
+            .filter(|record| record.destination.starts_with(destination_prefix))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Store persisting records to an append-only file of JSON lines
+///
+/// The first line records the file's schema version. Every save appends the
+/// full record and syncs the file; on open the file is replayed so the latest
+/// line for each payment ID wins. Removals append a line with only the
+/// payment ID and `"removed": true`. Allowance approvals are kept as lines
+/// of an `allowance_approval`, and recipient state, failure streaks among
+/// it, as lines of a `recipient_state` kind, its `key` and `state`, `null`
+/// once removed. Lines of a `failure_streak` recipient and its `streak`, as
+/// streaks were kept before, are read as recipient state.
+pub struct FilePayoutStore {
+    path: PathBuf,
+    records: InMemoryPayoutStore,
+    file: Mutex<File>,
+}
+
+impl FilePayoutStore {
+    /// Open the store at `path`, creating the file if needed and migrating
+    /// an older one after backing it up
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        FilePayoutStore::open_with(path, StoreMigration::Backup)
+    }
+
+    /// Open the store at `path`, handling a file at an older schema version
+    /// as `migration` says. Files at a newer version are never opened.
+    pub fn open_with(path: impl AsRef<Path>, migration: StoreMigration) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (version, mut lines) = if path.exists() {
+            read_lines(&path)?
+        } else {
+            (STORE_SCHEMA_VERSION, Vec::new())
+        };
+        if version > STORE_SCHEMA_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is at payout store schema version {}, newer than the supported {}",
+                    path.display(),
+                    version,
+                    STORE_SCHEMA_VERSION
+                ),
+            ));
+        }
+        let migrate = version < STORE_SCHEMA_VERSION;
+        if migrate {
+            if migration == StoreMigration::Refuse {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is at payout store schema version {} and needs migrating to {}",
+                        path.display(),
+                        version,
+                        STORE_SCHEMA_VERSION
+                    ),
+                ));
+            }
+            let backup = PathBuf::from(format!("{}.v{}.bak", path.display(), version));
+            std::fs::copy(&path, &backup)?;
+            for step in &MIGRATIONS[version as usize - 1..] {
+                lines = step(lines);
+            }
+            info!(
+                "Migrated payout store {} from schema version {} to {}, original kept at {}",
+                path.display(),
+                version,
+                STORE_SCHEMA_VERSION,
+                backup.display()
+            );
+        }
+
+        let records = InMemoryPayoutStore::new();
+        for (number, value) in &lines {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: invalid payout record", path.display(), number),
+                )
+            };
+            if let Some(recipient) = value["failure_streak"].as_str() {
+                let streak = match &value["streak"] {
+                    Value::Null => None,
+                    streak => Some(FailureStreak::from_json(streak).ok_or_else(invalid)?),
+                };
+                let streak = streak.as_ref().map(FailureStreak::to_json);
+                records.save_recipient_state(FAILURE_STREAK_STATE, recipient, streak);
+                continue;
+            }
+            if let Some(approval) = value.get("allowance_approval") {
+                let approval = AllowanceApproval::from_json(approval).ok_or_else(invalid)?;
+                records.save_allowance_approval(approval);
+                continue;
+            }
+            if let Some(kind) = value["recipient_state"].as_str() {
+                let key = value["key"].as_str().ok_or_else(invalid)?;
+                let state = Some(value["state"].clone()).filter(|state| !state.is_null());
+                records.save_recipient_state(kind, key, state);
+                continue;
+            }
+            if value["removed"] == true {
+                let mut payment_id = [0u8; 32];
+                let id = value["payment_id"].as_str().ok_or_else(invalid)?;
+                hex::decode_to_slice(id.trim_start_matches("0x"), &mut payment_id)
+                    .map_err(|_| invalid())?;
+                records.remove(&payment_id);
+                continue;
+            }
+            records.save(PayoutRecord::from_json(value).ok_or_else(invalid)?);
+        }
+
+        let fresh = !path.exists() || std::fs::metadata(&path)?.len() == 0;
+        if migrate || fresh {
+            // Replace the file in one rename so a crash leaves either version intact
+            let temp = PathBuf::from(format!("{}.tmp", path.display()));
+            let mut file = File::create(&temp)?;
+            writeln!(
+                file,
+                "{}",
+                json!({ "schema_version": STORE_SCHEMA_VERSION })
+            )?;
+            for (_, value) in &lines {
+                writeln!(file, "{}", value)?;
+            }
+            file.sync_all()?;
+            std::fs::rename(&temp, &path)?;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(FilePayoutStore {
+            path,
+            records,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn append(&self, line: &Value) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.sync_data()
+    }
+}
+
+/// Schema version of the file at `path` and its lines after the header
+fn read_lines(path: &Path) -> io::Result<(u32, Lines)> {
+    let mut version = None;
+    let mut lines = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(&line).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: invalid payout record", path.display(), number + 1),
+            )
+        })?;
+        if version.is_none() {
+            if let Some(header) = value.get("schema_version") {
+                version = header.as_u64().map(|version| version as u32);
+                if version.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}: invalid schema version {}", path.display(), header),
+                    ));
+                }
+                continue;
+            }
+            version = Some(1);
+        }
+        lines.push((number + 1, value));
+    }
+    Ok((version.unwrap_or(STORE_SCHEMA_VERSION), lines))
+}
+
+/// Version 1 to 2: keep only the latest line of each record still present
+fn compact_log(lines: Lines) -> Lines {
+    let id = |value: &Value| value["payment_id"].as_str().unwrap_or_default().to_string();
+    let mut latest = HashMap::new();
+    for (index, (_, value)) in lines.iter().enumerate() {
+        latest.insert(id(value), index);
+    }
+    lines
+        .into_iter()
+        .enumerate()
+        .filter(|(index, (_, value))| {
+            latest.get(&id(value)) == Some(index) && value["removed"] != true
+        })
+        .map(|(_, line)| line)
+        .collect()
+}
+
+impl PayoutStore for FilePayoutStore {
+    fn save(&self, record: PayoutRecord) {
+        if let Err(err) = self.append(&record.to_json()) {
+            error!(
+                "Failed to persist payout {} to {}: {}",
+                record.payment_id_hex(),
+                self.path.display(),
+                err
+            );
+        }
+        self.records.save(record);
+    }
+
+    fn try_save(&self, record: PayoutRecord) -> io::Result<()> {
+        self.append(&record.to_json())?;
+        self.records.save(record);
+        Ok(())
+    }
+
+    fn get(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        self.records.get(payment_id)
+    }
+
+    fn remove(&self, payment_id: &[u8; 32]) -> Option<PayoutRecord> {
+        let removed =
+            json!({ "payment_id": format!("0x{}", hex::encode(payment_id)), "removed": true });
+        if let Err(err) = self.append(&removed) {
+            error!(
+                "Failed to persist removal of payout 0x{} to {}: {}",
+                hex::encode(payment_id),
+                self.path.display(),
+                err
+            );
+        }
+        self.records.remove(payment_id)
+    }
+
+    fn highest_sequence(&self, destination: &str) -> Option<u64> {
+        self.records.highest_sequence(destination)
+    }
+
+    fn highest_serial(&self) -> u64 {
+        self.records.highest_serial()
+    }
+
+    fn find_by_sequence(&self, destination_prefix: &str, sequence: u64) -> Vec<PayoutRecord> {
+        self.records.find_by_sequence(destination_prefix, sequence)
+    }
+
+    fn allowance_approvals(&self) -> Vec<AllowanceApproval> {
+        self.records.allowance_approvals()
+    }
+
+    fn recipient_state(&self, kind: &str, key: &str) -> Option<Value> {
+        self.records.recipient_state(kind, key)
+    }
+
+    fn save_recipient_state(&self, kind: &str, key: &str, state: Option<Value>) {
+        let line = json!({ "recipient_state": kind, "key": key, "state": state });
+        if let Err(err) = self.append(&line) {
+            error!(
+                "Failed to persist {} state of {} to {}: {}",
+                kind,
+                key,
+                self.path.display(),
+                err
+            );
+        }
+        self.records.save_recipient_state(kind, key, state);
+    }
+
+    fn recipient_state_keys(&self, kind: &str) -> Vec<String> {
+        self.records.recipient_state_keys(kind)
+    }
+
+    fn save_allowance_approval(&self, approval: AllowanceApproval) {
+        let line = json!({ "allowance_approval": approval.to_json() });
+        if let Err(err) = self.append(&line) {
+            error!(
+                "Failed to persist allowance approval {} to {}: {}",
+                approval.tx_hash,
+                self.path.display(),
+                err
+            );
+        }
+        self.records.save_allowance_approval(approval);
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.path.is_file()
+    }
+
+    fn list_range(
+        &self,
+        from: Timestamp,
+        to: Timestamp,
+        after: Option<PageCursor>,
+        limit: usize,
+    ) -> Vec<PayoutRecord> {
+        self.records.list_range(from, to, after, limit)
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    fn backend(&self) -> &'static str {
+        "file"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u8, secs: i64) -> PayoutRecord {
+        PayoutRecord {
+            payment_id: [id; 32],
+            namespace: None,
+            payment_id_strategy: None,
+            attribution: Attribution::default(),
+            destination:
+                "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.abc"
+                    .to_string(),
+            sequence: id as u64,
+            recipient: "0x70997970C51812dc3A010C7d01b50e0d17dc79C8".to_string(),
+            asset_code: "EURC".to_string(),
+            asset_segment: None,
+            amount: 1000,
+            decimals: 6,
+            tx_hash: None,
+            safe_tx_hash: None,
+            cancel_tx_hash: None,
+            block_number: None,
+            gas_price: None,
+            gas_used: None,
+            effective_gas_price: None,
+            gas_cost: None,
+            l1_fee: None,
+            conversion: None,
+            memo: None,
+            status: PayoutStatus::Submitted,
+            deadline: None,
+            last_error: None,
+            timings: PhaseTimings::default(),
+            retry: None,
+            attempts: Vec::new(),
+            amount_mismatch: None,
+            timestamp: Utc.timestamp_opt(secs, 0).unwrap(),
+            serial: 0,
+            pinned_block: None,
+            treasury_payout_id: None,
+        }
+    }
+
+    #[test]
+    fn save_replaces_existing_record() {
+        let store = InMemoryPayoutStore::new();
+        store.save(record(1, 10));
+        let mut updated = record(1, 20);
+        updated.status = PayoutStatus::Confirmed;
+        store.save(updated.clone());
+
+        assert_eq!(store.get(&[1; 32]), Some(updated));
+        let all = store.list_range(
+            Utc.timestamp_opt(0, 0).unwrap(),
+            Utc.timestamp_opt(100, 0).unwrap(),
+            None,
+            10,
+        );
+        assert_eq!(all.len(), 1);
+    }
+
+    #[test]
+    fn list_range_pages_in_order() {
+        let store = InMemoryPayoutStore::new();
+        for i in 0..10u8 {
+            store.save(record(i, 100 - i as i64));
+        }
+        let from = Utc.timestamp_opt(92, 0).unwrap();
+        let to = Utc.timestamp_opt(99, 0).unwrap();
+
+        let first = store.list_range(from, to, None, 4);
+        let times: Vec<i64> = first.iter().map(|r| r.timestamp.timestamp()).collect();
+        assert_eq!(times, vec![92, 93, 94, 95]);
+
+        let cursor = first.last().map(PageCursor::from);
+        let second = store.list_range(from, to, cursor, 4);
+        let times: Vec<i64> = second.iter().map(|r| r.timestamp.timestamp()).collect();
+        assert_eq!(times, vec![96, 97, 98]);
+    }
+
+    #[test]
+    fn list_range_pages_by_serial_across_clock_steps() {
+        let store = InMemoryPayoutStore::new();
+        // The clock stepped back after the third record
+        for (serial, time) in [(1, 50), (2, 60), (3, 70), (4, 40), (5, 55), (6, 90)] {
+            let mut record = record(serial as u8, time);
+            record.serial = serial;
+            store.save(record);
+        }
+        let from = Utc.timestamp_opt(45, 0).unwrap();
+        let to = Utc.timestamp_opt(80, 0).unwrap();
+
+        let mut serials = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = store.list_range(from, to, cursor, 2);
+            if page.is_empty() {
+                break;
+            }
+            serials.extend(page.iter().map(|record| record.serial));
+            cursor = page.last().map(PageCursor::from);
+        }
+        assert_eq!(serials, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        {
+            let store = FilePayoutStore::open(&path).unwrap();
+            assert!(store.is_persistent());
+            store.save(record(1, 10));
+            let mut confirmed = record(2, 20);
+            confirmed.status = PayoutStatus::Confirmed;
+            confirmed.tx_hash = Some("0xabc".to_string());
+            confirmed.gas_cost = Some(u128::MAX);
+            confirmed.serial = 7;
+            confirmed.memo = Some(b"INV-42".to_vec());
+            confirmed.conversion = Conversion::new(
+                IlpAmount::new(2_500_003),
+                ExchangeRate {
+                    from_asset: "XRP".to_string(),
+                    to_asset: confirmed.asset_code.clone(),
+                    rate: 512,
+                    scale: 6,
+                    timestamp: Utc.timestamp_opt(15, 0).unwrap(),
+                },
+                RoundingMode::Down,
+            );
+            store.save(confirmed.clone());
+            let mut failed = record(1, 30);
+            failed.status = PayoutStatus::Failed;
+            store.save(failed);
+            store.save_recipient_state("dust", "EURC/0xa", Some(json!({ "amount": 5 })));
+            store.save_recipient_state("dust", "EURC/0xb", Some(json!({ "amount": 6 })));
+            store.save_recipient_state("dust", "EURC/0xb", None);
+        }
+
+        let store = FilePayoutStore::open(&path).unwrap();
+        assert_eq!(store.recipient_state_keys("dust"), ["EURC/0xa"]);
+        assert_eq!(
+            store.recipient_state("dust", "EURC/0xa"),
+            Some(json!({ "amount": 5 }))
+        );
+        assert_eq!(store.get(&[1; 32]).unwrap().status, PayoutStatus::Failed);
+        assert_eq!(store.highest_serial(), 7);
+        let confirmed = store.get(&[2; 32]).unwrap();
+        assert_eq!(confirmed.gas_cost, Some(u128::MAX));
+        assert_eq!(confirmed.tx_hash.as_deref(), Some("0xabc"));
+        assert_eq!(confirmed.timestamp, Utc.timestamp_opt(20, 0).unwrap());
+        assert_eq!(confirmed.memo.as_deref(), Some(&b"INV-42"[..]));
+        let conversion = confirmed.conversion.unwrap();
+        assert_eq!(conversion.rate.rate, 512);
+        assert_eq!(conversion.rounding, RoundingMode::Down);
+        assert_eq!(conversion.rounded_amount, 1280);
+        assert_eq!(conversion.unrounded_amount(), "1280.001536");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn streak_lines_load_as_recipient_state() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let streak = json!({
+            "failures": 3,
+            "last_error": "execution reverted",
+            "last_failure": "1970-01-01T00:00:10+00:00",
+            "summarized_at": null,
+            "unsummarized": 0,
+            "held": true,
+        });
+        let lines = [
+            json!({ "schema_version": STORE_SCHEMA_VERSION }),
+            json!({ "failure_streak": "0xa", "streak": streak }),
+            json!({ "failure_streak": "0xb", "streak": streak }),
+            json!({ "failure_streak": "0xb", "streak": null }),
+        ];
+        let text: Vec<String> = lines.iter().map(Value::to_string).collect();
+        std::fs::write(&path, text.join("\n")).unwrap();
+
+        let store = FilePayoutStore::open(&path).unwrap();
+        assert_eq!(store.recipient_state_keys(FAILURE_STREAK_STATE), ["0xa"]);
+        let loaded = store.recipient_state(FAILURE_STREAK_STATE, "0xa").unwrap();
+        assert_eq!(loaded, streak);
+        assert!(FailureStreak::from_json(&loaded).unwrap().held);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Records as written before schema versions, including a superseded
+    /// line and a removal
+    fn v1_fixture() -> String {
+        let destination =
+            "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.abc";
+        let line = |id: &str, status: &str, extra: &str| {
+            format!(
+                r#"{{"payment_id":"0x{}","destination":"{}","sequence":1,"recipient":"0x70997970C51812dc3A010C7d01b50e0d17dc79C8","asset_code":"EURC","amount":1000,"decimals":6,{}"status":"{}","timestamp":"1970-01-01T00:00:10+00:00"}}"#,
+                id.repeat(32),
+                destination,
+                extra,
+                status
+            )
+        };
+        [
+            line(
+                "01",
+                "submitted",
+                r#""tx_hash":"0xabc","block_number":null,"gas_cost":null,"#,
+            ),
+            line(
+                "01",
+                "confirmed",
+                r#""tx_hash":"0xabc","block_number":7,"gas_cost":"21000000000000","#,
+            ),
+            line(
+                "02",
+                "failed",
+                r#""tx_hash":null,"block_number":null,"gas_cost":null,"#,
+            ),
+            format!(r#"{{"payment_id":"0x{}","removed":true}}"#, "02".repeat(32)),
+            line(
+                "03",
+                "submitted",
+                r#""tx_hash":"0xdef","block_number":null,"gas_cost":null,"#,
+            ),
+        ]
+        .join("\n")
+    }
+
+    #[test]
+    fn v1_file_is_backed_up_and_migrated() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, v1_fixture()).unwrap();
+
+        let store = FilePayoutStore::open(&path).unwrap();
+        assert_eq!(store.schema_version(), STORE_SCHEMA_VERSION);
+        let confirmed = store.get(&[1; 32]).unwrap();
+        assert_eq!(confirmed.status, PayoutStatus::Confirmed);
+        assert_eq!(confirmed.block_number, Some(7));
+        assert_eq!(confirmed.gas_cost, Some(21_000_000_000_000));
+        assert_eq!(confirmed.namespace, None);
+        assert_eq!(confirmed.timings, PhaseTimings::default());
+        assert_eq!(store.get(&[2; 32]), None);
+        assert_eq!(
+            store.get(&[3; 32]).unwrap().tx_hash.as_deref(),
+            Some("0xdef")
+        );
+
+        let backup = PathBuf::from(format!("{}.v1.bak", path.display()));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), v1_fixture());
+        let migrated = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = migrated.lines().collect();
+        assert_eq!(lines[0], r#"{"schema_version":2}"#);
+        // Superseded lines and removed records are compacted away
+        assert_eq!(lines.len(), 3);
+
+        store.save(record(4, 40));
+        drop(store);
+        let reopened = FilePayoutStore::open_with(&path, StoreMigration::Refuse).unwrap();
+        assert_eq!(reopened.get(&[1; 32]), Some(confirmed));
+        assert!(reopened.get(&[4; 32]).is_some());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&backup).unwrap();
+    }
+
+    #[test]
+    fn refuses_unmigrated_and_newer_files() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        std::fs::write(&path, v1_fixture()).unwrap();
+        let err = FilePayoutStore::open_with(&path, StoreMigration::Refuse)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("needs migrating to 2"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), v1_fixture());
+
+        std::fs::write(&path, "{\"schema_version\":3}\n").unwrap();
+        let err = FilePayoutStore::open(&path).err().unwrap();
+        assert!(
+            err.to_string().contains("newer than the supported 2"),
+            "{}",
+            err
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn direct_transfers_are_not_sent_when_the_log_cannot_be_written() {
+        use super::super::testing::{mock_chain, test_config, test_service, TEST_DESTINATION};
+        use super::super::{AssetInfo, PayoutError};
+        use std::sync::Arc;
+
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let store = FilePayoutStore::open(&path).unwrap();
+        // A read-only handle fails every append, as a full or read-only disk would
+        *store.file.lock().unwrap() = File::open(&path).unwrap();
+        let mut config = test_config();
+        config.assets.insert(AssetInfo::direct_transfer(
+            "EURC",
+            6,
+            "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512",
+        ));
+        let transport = mock_chain();
+        let service = test_service(config, transport.clone()).with_store(Arc::new(store));
+
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PayoutError::StoreWrite(_)));
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,498 @@
+//! Collapsing the alerts of a recipient whose payouts keep failing
+//!
+//! A recipient whose wallet rejects every transfer, such as a broken smart
+//! wallet, would otherwise log a failure for every payout. With
+//! `failure_streaks` set, the consecutive failures of payouts to each
+//! recipient are counted. Once `threshold` of them failed in a row, one alert
+//! is raised and later failures are only logged at debug level, summed up in
+//! an alert every `summary_interval`. Under `hold`, the recipient's later
+//! payouts are refused with `RecipientHeld` until the operator calls
+//! [`EthereumPayoutService::release_recipient`]. The first successful payout
+//! ends the streak. Streaks are held in the bounded per-recipient cache and
+//! written to the store whenever they change, so they survive a restart.
+//!
+//! Only failures of payouts that were attempted count: payouts refused by
+//! limits, the kill switch, an unavailable signer or a held recipient do not.
+
+use super::recipient_state::SpilledState;
+use super::store::Timestamp;
+use super::{EthereumPayoutService, PayoutError, PayoutOutcome, PayoutRequest};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::error;
+
+/// When failing payouts to one recipient stop being alerted one by one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailureStreakPolicy {
+    /// Consecutive failures after which alerts are collapsed
+    pub threshold: u64,
+    /// How often collapsed failures are summed up in an alert
+    pub summary_interval: Duration,
+    /// Refuse further payouts to the recipient until it is released
+    pub hold: bool,
+}
+
+impl Default for FailureStreakPolicy {
+    fn default() -> Self {
+        FailureStreakPolicy {
+            threshold: 10,
+            summary_interval: Duration::from_secs(3600),
+            hold: false,
+        }
+    }
+}
+
+/// Consecutive failed payouts to one recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailureStreak {
+    pub failures: u64,
+    pub last_error: String,
+    pub last_failure: Timestamp,
+    /// When the streak was last alerted about, once it reached the threshold
+    pub summarized_at: Option<Timestamp>,
+    /// Failures since `summarized_at`
+    pub unsummarized: u64,
+    /// Whether payouts to the recipient are refused until it is released
+    pub held: bool,
+}
+
+impl FailureStreak {
+    pub(super) fn to_json(&self) -> Value {
+        json!({
+            "failures": self.failures,
+            "last_error": self.last_error,
+            "last_failure": self.last_failure.to_rfc3339(),
+            "summarized_at": self.summarized_at.map(|at| at.to_rfc3339()),
+            "unsummarized": self.unsummarized,
+            "held": self.held,
+        })
+    }
+
+    pub(super) fn from_json(value: &Value) -> Option<Self> {
+        let timestamp = |value: &Value| {
+            DateTime::parse_from_rfc3339(value.as_str()?)
+                .ok()
+                .map(|at| at.with_timezone(&Utc))
+        };
+        Some(FailureStreak {
+            failures: value["failures"].as_u64()?,
+            last_error: value["last_error"].as_str()?.to_string(),
+            last_failure: timestamp(&value["last_failure"])?,
+            summarized_at: match &value["summarized_at"] {
+                Value::Null => None,
+                at => Some(timestamp(at)?),
+            },
+            unsummarized: value["unsummarized"].as_u64()?,
+            held: value["held"].as_bool()?,
+        })
+    }
+}
+
+/// Kind streaks are kept under in the store's recipient state
+pub(super) const FAILURE_STREAK_STATE: &str = "failure_streak";
+
+/// Streak of a recipient as kept in the per-recipient cache, `None` if none
+#[derive(Debug, Default)]
+pub(super) struct StreakState(pub(super) Option<FailureStreak>);
+
+impl SpilledState for StreakState {
+    fn to_json(&self) -> Value {
+        self.0.as_ref().map_or(Value::Null, FailureStreak::to_json)
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        FailureStreak::from_json(value).map(|streak| StreakState(Some(streak)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+/// Whether `err` is a failure of the payout itself rather than a refusal to
+/// attempt it
+fn counts_toward_streak(err: &PayoutError) -> bool {
+    !matches!(
+        err,
+        PayoutError::RecipientHeld { .. }
+            | PayoutError::RecipientBlocked { .. }
+            | PayoutError::KillSwitchEngaged { .. }
+            | PayoutError::SignerUnavailable(_)
+            | PayoutError::NotAuthorized { .. }
+            | PayoutError::LimitExceeded { .. }
+            | PayoutError::SequenceOutOfWindow { .. }
+            | PayoutError::Cancelled
+    )
+}
+
+impl EthereumPayoutService {
+    /// Lowercase recipient of the request, `None` if streaks are not tracked
+    /// or the destination does not parse
+    fn streak_recipient(&self, destination: &str) -> Option<String> {
+        self.config.failure_streaks?;
+        Some(
+            self.config
+                .parse_destination(destination)?
+                .recipient
+                .to_ascii_lowercase(),
+        )
+    }
+
+    /// Consecutive failed payouts to `recipient`
+    pub fn failure_streak(&self, recipient: &str) -> Option<FailureStreak> {
+        self.failure_streaks
+            .with(&*self.store, &recipient.to_ascii_lowercase(), |state| {
+                state.0.clone()
+            })
+    }
+
+    /// Refuse the payout if its recipient is held after failing repeatedly
+    pub(super) fn check_failure_hold(&self, destination: &str) -> Result<(), PayoutError> {
+        let recipient = match self.streak_recipient(destination) {
+            Some(recipient) => recipient,
+            None => return Ok(()),
+        };
+        match self.failure_streak(&recipient) {
+            Some(streak) if streak.held => Err(PayoutError::RecipientHeld {
+                recipient,
+                failures: streak.failures,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether failures of payouts to the destination's recipient are only
+    /// summarized rather than alerted one by one
+    pub(super) fn is_failure_collapsed(&self, destination: &str) -> bool {
+        let (policy, recipient) = match (
+            self.config.failure_streaks,
+            self.streak_recipient(destination),
+        ) {
+            (Some(policy), Some(recipient)) => (policy, recipient),
+            _ => return false,
+        };
+        self.failure_streak(&recipient)
+            .is_some_and(|streak| streak.failures >= policy.threshold)
+    }
+
+    /// Extend or end the recipient's streak with the payout's result
+    pub(super) fn track_failure_streak(
+        &self,
+        request: &PayoutRequest,
+        result: &Result<PayoutOutcome, PayoutError>,
+    ) {
+        let (policy, recipient) = match (
+            self.config.failure_streaks,
+            self.streak_recipient(&request.destination),
+        ) {
+            (Some(policy), Some(recipient)) => (policy, recipient),
+            _ => return,
+        };
+        let err = match result {
+            Ok(PayoutOutcome::Submitted { .. })
+            | Ok(PayoutOutcome::Split { .. })
+            | Ok(PayoutOutcome::PendingApproval { .. }) => {
+                if self.failure_streak(&recipient).is_some() {
+                    self.failure_streaks
+                        .with_written(&*self.store, &recipient, |state| state.0 = None);
+                }
+                return;
+            }
+            Ok(_) => return,
+            Err(err) if !counts_toward_streak(err) => return,
+            Err(err) => err,
+        };
+
+        let now = self.clock.now();
+        self.failure_streaks
+            .with_written(&*self.store, &recipient, |state| {
+                let streak = state.0.get_or_insert_with(|| FailureStreak {
+                    failures: 0,
+                    last_error: String::new(),
+                    last_failure: now,
+                    summarized_at: None,
+                    unsummarized: 0,
+                    held: false,
+                });
+                self.extend_streak(streak, &policy, &recipient, err, now);
+            });
+    }
+
+    /// Count a failure of a payout to `recipient` in its streak, alerting
+    /// once it reaches the threshold and then every summary interval
+    fn extend_streak(
+        &self,
+        streak: &mut FailureStreak,
+        policy: &FailureStreakPolicy,
+        recipient: &str,
+        err: &PayoutError,
+        now: Timestamp,
+    ) {
+        streak.failures += 1;
+        streak.last_error = err.to_string();
+        streak.last_failure = now;
+        match streak.summarized_at {
+            None if streak.failures >= policy.threshold => {
+                error!(
+                    "ALERT: {} payouts in a row to {} failed, last error: {}; {}",
+                    streak.failures,
+                    self.display_address(recipient),
+                    self.scrub_address(&streak.last_error, recipient),
+                    if policy.hold {
+                        "holding its payouts for review"
+                    } else {
+                        "summarizing further failures"
+                    }
+                );
+                streak.summarized_at = Some(now);
+                streak.held = policy.hold;
+            }
+            None => {}
+            Some(summarized_at) => {
+                streak.unsummarized += 1;
+                let elapsed = now - summarized_at;
+                if elapsed.to_std().unwrap_or_default() >= policy.summary_interval {
+                    error!(
+                        "ALERT: recipient {}: {} failures in the last {} minutes, last error: {}",
+                        self.display_address(recipient),
+                        streak.unsummarized,
+                        elapsed.num_minutes(),
+                        self.scrub_address(&streak.last_error, recipient)
+                    );
+                    streak.summarized_at = Some(now);
+                    streak.unsummarized = 0;
+                }
+            }
+        }
+    }
+
+    /// Let payouts to a recipient held after repeated failures run again,
+    /// ending its streak. Returns the streak it ended.
+    pub fn release_recipient(&self, recipient: &str) -> Option<FailureStreak> {
+        let recipient = recipient.to_ascii_lowercase();
+        self.failure_streaks.take(&*self.store, &recipient).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, FakeClock, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::EthereumPayoutConfig;
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span;
+
+    const RECIPIENT: &str = "0x70997970c51812dc3a010c7d01b50e0d17dc79c8";
+
+    /// Collects the alerts this module logs
+    struct Alerts(Arc<Mutex<Vec<String>>>);
+
+    struct Message<'a>(&'a mut String);
+
+    impl Visit for Message<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl tracing::Subscriber for Alerts {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            *metadata.level() == tracing::Level::ERROR
+                && metadata.target() == module_path!().trim_end_matches("::tests")
+        }
+
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.0.lock().unwrap().push(message);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    /// Node rejecting transfers to the recipient while `broken` is set
+    fn node(broken: Arc<AtomicBool>) -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.on("eth_sendRawTransaction", move |_: &Value| {
+            if broken.load(Ordering::SeqCst) {
+                Err(json!({"code": -32000, "message": "recipient rejected the transfer"}))
+            } else {
+                Ok(json!("0xabc"))
+            }
+        });
+        transport
+    }
+
+    fn streak_config(hold: bool) -> EthereumPayoutConfig {
+        let mut config = test_config();
+        config.failure_streaks = Some(FailureStreakPolicy {
+            threshold: 3,
+            summary_interval: Duration::from_secs(3600),
+            hold,
+        });
+        config
+    }
+
+    #[tokio::test]
+    async fn collapses_alerts_and_resets_on_success() {
+        let broken = Arc::new(AtomicBool::new(true));
+        let clock = FakeClock::new();
+        let service =
+            test_service(streak_config(false), node(broken.clone())).with_clock(clock.clone());
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let _logging = tracing::subscriber::set_default(Alerts(alerts.clone()));
+
+        for sequence in 1..=10 {
+            assert!(service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .is_err());
+            clock.advance(Duration::from_secs(60));
+        }
+        assert_eq!(service.failure_streak(RECIPIENT).unwrap().failures, 10);
+        assert!(service.is_failure_collapsed(TEST_DESTINATION));
+        // One alert on reaching the threshold, none for the seven failures after it
+        assert_eq!(alerts.lock().unwrap().len(), 1);
+        assert!(alerts.lock().unwrap()[0].contains("3 payouts in a row"));
+
+        // Once the summary interval passed, the collapsed failures are summed up
+        clock.advance(Duration::from_secs(3600));
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 11)
+            .await
+            .is_err());
+        {
+            let alerts = alerts.lock().unwrap();
+            assert_eq!(alerts.len(), 2);
+            assert!(alerts[1].starts_with(&format!("ALERT: recipient {}: 8 failures", RECIPIENT)));
+            assert!(alerts[1].contains("recipient rejected the transfer"));
+        }
+
+        broken.store(false, Ordering::SeqCst);
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 12)
+            .await
+            .is_ok());
+        assert_eq!(service.failure_streak(RECIPIENT), None);
+        assert!(!service.is_failure_collapsed(TEST_DESTINATION));
+    }
+
+    #[tokio::test]
+    async fn holds_the_recipient_across_restarts_until_released() {
+        let path = std::env::temp_dir().join(format!("payouts-{}.jsonl", uuid::Uuid::new_v4()));
+        let mut config = streak_config(true);
+        config.store_path = Some(path.clone());
+        let broken = Arc::new(AtomicBool::new(true));
+        {
+            let service = test_service(config.clone(), node(broken.clone()));
+            for sequence in 1..=3 {
+                assert!(service
+                    .execute_payout(TEST_DESTINATION, 100, sequence)
+                    .await
+                    .is_err());
+            }
+        }
+
+        broken.store(false, Ordering::SeqCst);
+        let transport = node(broken);
+        let service = test_service(config, transport.clone());
+        let streak = service.failure_streak(RECIPIENT).unwrap();
+        assert_eq!(streak.failures, 3);
+        assert!(streak.held);
+        match service.execute_payout(TEST_DESTINATION, 100, 4).await {
+            Err(PayoutError::RecipientHeld { failures: 3, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(transport.call_count("eth_sendRawTransaction"), 0);
+        // Refusing the payout does not extend the streak
+        assert_eq!(service.failure_streak(RECIPIENT).unwrap().failures, 3);
+
+        assert_eq!(service.release_recipient(RECIPIENT).unwrap().failures, 3);
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 4)
+            .await
+            .is_ok());
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn streaks_survive_eviction_from_the_recipient_cache() {
+        let mut config = streak_config(true);
+        config.recipient_state_capacity = 1;
+        let broken = Arc::new(AtomicBool::new(true));
+        let service = test_service(config, node(broken.clone()));
+        let other = TEST_DESTINATION.replace(
+            "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+            "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC",
+        );
+        for sequence in 1..=3 {
+            assert!(service
+                .execute_payout(TEST_DESTINATION, 100, sequence)
+                .await
+                .is_err());
+        }
+
+        // Failing payouts to another recipient evict the held streak
+        for sequence in 1..=2 {
+            assert!(service.execute_payout(&other, 100, sequence).await.is_err());
+        }
+        assert_eq!(service.failure_streaks.resident(), 1);
+        assert!(service
+            .store()
+            .recipient_state(FAILURE_STREAK_STATE, RECIPIENT)
+            .is_some());
+
+        // Reloaded, it still holds the recipient, and the other streak still ends
+        broken.store(false, Ordering::SeqCst);
+        match service.execute_payout(TEST_DESTINATION, 100, 4).await {
+            Err(PayoutError::RecipientHeld { failures: 3, .. }) => {}
+            other => panic!("{:?}", other),
+        }
+        assert!(service.execute_payout(&other, 100, 3).await.is_ok());
+        assert_eq!(
+            service.failure_streak("0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC"),
+            None
+        );
+        assert_eq!(service.release_recipient(RECIPIENT).unwrap().failures, 3);
+        assert_eq!(
+            service
+                .store()
+                .recipient_state(FAILURE_STREAK_STATE, RECIPIENT),
+            None
+        );
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 100, 4)
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn streaks_round_trip() {
+        let streak = FailureStreak {
+            failures: 214,
+            last_error: "execution reverted".to_string(),
+            last_failure: Utc::now(),
+            summarized_at: Some(Utc::now()),
+            unsummarized: 3,
+            held: true,
+        };
+        assert_eq!(FailureStreak::from_json(&streak.to_json()), Some(streak));
+    }
+}
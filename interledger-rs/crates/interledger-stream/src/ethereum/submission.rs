@@ -0,0 +1,295 @@
+//! Recovering from transaction submissions that timed out
+//!
+//! A send that times out may still have reached the node, so it is never
+//! retried blindly. A transaction signed here is looked up by its hash, which
+//! is known before it is broadcast. Otherwise the operator's pending nonce
+//! tells whether a transaction with the send's nonce exists: if not, the
+//! same transaction is sent once more. If the nonce was used, a transaction
+//! the node signed under `node_signing` is looked up by sender and nonce in
+//! the pending and latest blocks, its hash being unknown. One that could not
+//! be found fails the payout for an operator to investigate rather than
+//! risking a second broadcast.
+
+use super::rpc::{parse_quantity, rpc_request};
+use super::signer::Submission;
+use super::{EthereumPayoutService, PayoutError, PayoutMode};
+use serde_json::json;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Methods that broadcast a transaction and must not be retried after a timeout
+const SUBMISSION_METHODS: [&str; 2] = ["eth_sendTransaction", "eth_sendRawTransaction"];
+
+impl EthereumPayoutService {
+    /// Time allowed for a request to the node, depending on whether it submits a transaction
+    pub(super) fn rpc_timeout(&self, method: &str) -> Duration {
+        if SUBMISSION_METHODS.contains(&method) {
+            self.config.submit_timeout
+        } else {
+            self.config.read_timeout
+        }
+    }
+
+    /// Hash of a transaction whose submission timed out, sending it again only
+    /// if the node never received it
+    pub(super) async fn verify_submission(
+        &self,
+        submission: &Submission,
+        nonce: u64,
+        mode: PayoutMode,
+    ) -> Result<String, PayoutError> {
+        if let Some(tx_hash) = &submission.tx_hash {
+            if self.transaction_known(tx_hash).await? {
+                info!(
+                    "Submission with nonce {} timed out but reached the node as {}",
+                    nonce, tx_hash
+                );
+                self.note_broadcast(nonce);
+                return Ok(tx_hash.clone());
+            }
+        }
+        let pending = self.chain_nonce().await?;
+        if pending <= nonce {
+            info!(
+                "Submission with nonce {} timed out before reaching the node, sending again",
+                nonce
+            );
+            return match self.broadcast(submission, mode).await {
+                Err(PayoutError::TimedOut { .. }) => {
+                    Err(PayoutError::SubmissionUnverified { nonce })
+                }
+                result => result,
+            };
+        }
+        let found = match &submission.tx_hash {
+            Some(_) => None,
+            None => self.find_transaction(nonce).await?,
+        };
+        match found {
+            Some(tx_hash) => {
+                info!(
+                    "Submission with nonce {} timed out but reached the node as {}",
+                    nonce, tx_hash
+                );
+                self.note_broadcast(nonce);
+                Ok(tx_hash)
+            }
+            None => {
+                warn!(
+                    "Nonce {} was used after a submission timed out, but no transaction was found",
+                    nonce
+                );
+                Err(PayoutError::SubmissionUnverified { nonce })
+            }
+        }
+    }
+
+    /// Whether the node has the transaction `tx_hash`, pending or mined
+    async fn transaction_known(&self, tx_hash: &str) -> Result<bool, PayoutError> {
+        for method in ["eth_getTransactionByHash", "eth_getTransactionReceipt"] {
+            let found = self.rpc(rpc_request(method, json!([tx_hash]))).await?;
+            if !found.is_null() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Hash of the operator's transaction with `nonce` in the pending or latest block
+    async fn find_transaction(&self, nonce: u64) -> Result<Option<String>, PayoutError> {
+        let operator = self.operator_address();
+        for block in ["pending", "latest"] {
+            let result = self
+                .rpc(rpc_request("eth_getBlockByNumber", json!([block, true])))
+                .await?;
+            let found = result["transactions"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|tx| {
+                    tx["from"]
+                        .as_str()
+                        .is_some_and(|from| from.eq_ignore_ascii_case(&operator))
+                        && parse_quantity(&tx["nonce"]).ok() == Some(nonce)
+                });
+            if let Some(tx) = found {
+                return Ok(tx["hash"].as_str().map(str::to_string));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hash::keccak256;
+    use super::super::rpc::RpcTransport;
+    use super::super::testing::{
+        test_config, test_service, MockTransport, TEST_DESTINATION, TEST_OPERATOR,
+    };
+    use super::*;
+    use async_trait::async_trait;
+    use serde_json::Value;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Delivers requests to a mock, but never answers the first `lost` sends
+    struct LostResponses {
+        inner: Arc<MockTransport>,
+        lost: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RpcTransport for LostResponses {
+        async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+            let response = self.inner.send(request.clone()).await?;
+            if SUBMISSION_METHODS
+                .iter()
+                .any(|method| request["method"] == *method)
+                && self
+                    .lost
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok()
+            {
+                std::future::pending::<()>().await;
+            }
+            Ok(response)
+        }
+    }
+
+    /// Service whose node loses the answer to the first send. When `lands`
+    /// every send it receives uses up its nonce, and when `known` the node
+    /// also serves the signed transactions it received by hash.
+    fn timing_out_service(
+        node_signing: bool,
+        lands: bool,
+        known: bool,
+        block: Value,
+    ) -> (EthereumPayoutService, Arc<MockTransport>) {
+        let mock = MockTransport::new();
+        let mined = Arc::new(AtomicU64::new(0));
+        let hashes: Arc<Mutex<HashSet<String>>> = Arc::default();
+        let counter = mined.clone();
+        let received = hashes.clone();
+        let send = move |params: &Value| {
+            if lands {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            if let Some(raw) = params[0].as_str().filter(|_| known) {
+                let raw = hex::decode(&raw[2..]).unwrap();
+                let hash = format!("0x{}", hex::encode(keccak256(&raw)));
+                received.lock().unwrap().insert(hash);
+            }
+            Ok(json!("0xresent"))
+        };
+        if node_signing {
+            mock.on("eth_sendTransaction", send);
+        } else {
+            mock.on("eth_sendRawTransaction", send);
+        }
+        mock.on("eth_getTransactionCount", move |_| {
+            Ok(json!(format!("0x{:x}", mined.load(Ordering::SeqCst))))
+        });
+        mock.on("eth_getTransactionByHash", move |params| {
+            let hash = params[0].as_str().unwrap();
+            Ok(match hashes.lock().unwrap().contains(hash) {
+                true => json!({"hash": hash, "blockNumber": null}),
+                false => Value::Null,
+            })
+        });
+        mock.on_result("eth_getTransactionReceipt", Value::Null);
+        mock.on_result("eth_gasPrice", json!("0x1"));
+        mock.on_result("eth_getBlockByNumber", block);
+
+        let mut config = test_config();
+        config.submit_timeout = Duration::from_millis(50);
+        config.node_signing = node_signing;
+        let service = test_service(config, mock.clone()).with_transport(Arc::new(LostResponses {
+            inner: mock.clone(),
+            lost: AtomicUsize::new(1),
+        }));
+        (service, mock)
+    }
+
+    #[tokio::test]
+    async fn timed_out_send_that_landed_is_found_by_hash() {
+        let (service, mock) = timing_out_service(false, true, true, json!({"transactions": []}));
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let raw = &mock.calls("eth_sendRawTransaction")[0]["params"][0];
+        let raw = hex::decode(&raw.as_str().unwrap()[2..]).unwrap();
+        let tx_hash = format!("0x{}", hex::encode(keccak256(&raw)));
+        assert_eq!(outcome.tx_hash(), Some(tx_hash.as_str()));
+        assert_eq!(mock.call_count("eth_sendRawTransaction"), 1);
+        assert_eq!(mock.call_count("eth_getBlockByNumber"), 0);
+    }
+
+    #[tokio::test]
+    async fn timed_out_send_that_never_arrived_is_resent_with_its_nonce() {
+        let (service, mock) = timing_out_service(false, false, false, json!({"transactions": []}));
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xresent"));
+        let sends = mock.calls("eth_sendRawTransaction");
+        assert_eq!(sends.len(), 2);
+        assert_eq!(sends[0]["params"], sends[1]["params"]);
+        assert_eq!(mock.call_count("eth_getTransactionByHash"), 1);
+        assert_eq!(mock.call_count("eth_getBlockByNumber"), 0);
+    }
+
+    #[tokio::test]
+    async fn nonce_used_by_another_transaction_is_not_resent() {
+        let (service, mock) = timing_out_service(false, true, false, json!({"transactions": []}));
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, PayoutError::SubmissionUnverified { nonce: 0 }),
+            "{:?}",
+            err
+        );
+        assert!(!err.is_transient());
+        assert_eq!(mock.call_count("eth_sendRawTransaction"), 1);
+        // The hash settles it, without searching blocks
+        assert_eq!(mock.call_count("eth_getBlockByNumber"), 0);
+    }
+
+    #[tokio::test]
+    async fn node_signed_send_that_landed_is_found_by_nonce() {
+        let block = json!({"transactions": [
+            {"from": "0x0000000000000000000000000000000000000001", "nonce": "0x0", "hash": "0xother"},
+            {"from": TEST_OPERATOR.to_lowercase(), "nonce": "0x0", "hash": "0xlanded"},
+        ]});
+        let (service, mock) = timing_out_service(true, true, false, block);
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xlanded"));
+        assert_eq!(mock.call_count("eth_sendTransaction"), 1);
+        assert_eq!(mock.call_count("eth_getTransactionByHash"), 0);
+    }
+
+    #[tokio::test]
+    async fn node_signed_used_nonce_without_a_transaction_is_not_resent() {
+        let (service, mock) = timing_out_service(true, true, false, json!({"transactions": []}));
+        let err = service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, PayoutError::SubmissionUnverified { nonce: 0 }),
+            "{:?}",
+            err
+        );
+        assert_eq!(mock.call_count("eth_sendTransaction"), 1);
+        // Both blocks were searched
+        assert_eq!(mock.call_count("eth_getBlockByNumber"), 2);
+    }
+}
@@ -0,0 +1,462 @@
+//! Payout services of several tenants behind one connector
+//!
+//! Each tenant owns the destinations under an ILP address prefix and is paid
+//! out by its own service, with its own Treasury, operator key, nonces and
+//! store. Tenants are listed in the JSON file named by `PAYOUT_TENANTS_FILE`:
+//!
+//! ```text
+//! {"tenants": [
+//!     {"name": "shop1", "prefix": "test.shop1", "settings": {
+//!         "TREASURY_ADDRESS": "0x...", "OPERATOR_PRIVATE_KEY": "..."}},
+//!     {"name": "shop2", "prefix": "test.shop2", "settings": {...}}
+//! ]}
+//! ```
+//!
+//! A tenant's settings are named like the environment variables of
+//! [`EthereumPayoutConfig::from_env`] and override them, so settings shared by
+//! all tenants, such as the RPC URL, can stay in the environment.
+
+use super::health::HealthReport;
+use super::route_payout;
+use super::skip::{SkipCounters, SKIPPED_PAYOUTS};
+use super::{
+    normalize_private_key, EthereumPayoutConfig, EthereumPayoutService, PayoutError, PayoutRequest,
+    SkipStats,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Environment variable naming the tenants file
+pub const TENANTS_FILE_ENV: &str = "PAYOUT_TENANTS_FILE";
+
+/// Configuration of one tenant's payout service
+#[derive(Clone, Debug)]
+pub struct TenantConfig {
+    pub name: String,
+    /// ILP address prefix of the tenant's destinations
+    pub prefix: String,
+    pub config: EthereumPayoutConfig,
+}
+
+/// Read the tenants file at `path`, with settings missing from it taken from the environment
+pub fn load_tenants(path: &Path) -> Result<Vec<TenantConfig>, PayoutError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        PayoutError::Config(format!(
+            "Cannot read tenants file {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+    parse_tenants(&contents, |name| std::env::var(name).ok())
+}
+
+/// Parse a tenants file, taking settings it does not set from `env`
+fn parse_tenants(
+    contents: &str,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Vec<TenantConfig>, PayoutError> {
+    let invalid = |message: String| PayoutError::Config(format!("Tenants file: {}", message));
+    let file: Value =
+        serde_json::from_str(contents).map_err(|err| invalid(format!("not JSON: {}", err)))?;
+    let entries = file["tenants"]
+        .as_array()
+        .ok_or_else(|| invalid("no \"tenants\" list".to_string()))?;
+
+    let mut tenants: Vec<TenantConfig> = Vec::new();
+    for entry in entries {
+        let field = |key: &str| {
+            entry[key]
+                .as_str()
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+                .ok_or_else(|| invalid(format!("tenant without a {}", key)))
+        };
+        let name = field("name")?;
+        let prefix = field("prefix")?.trim_end_matches('.').to_string();
+        let mut settings = HashMap::new();
+        if let Some(object) = entry["settings"].as_object() {
+            for (key, value) in object {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    Value::Number(_) | Value::Bool(_) => value.to_string(),
+                    _ => {
+                        return Err(invalid(format!(
+                            "setting {} of tenant {} is not a string, number or boolean",
+                            key, name
+                        )))
+                    }
+                };
+                settings.insert(key.clone(), value);
+            }
+        }
+        let mut config =
+            EthereumPayoutConfig::from_vars(|key| settings.get(key).cloned().or_else(|| env(key)))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "settings of tenant {} are incomplete or invalid",
+                        name
+                    ))
+                })?;
+        config.tenant = Some(name.clone());
+
+        for other in &tenants {
+            if other.name == name {
+                return Err(invalid(format!("tenant {} is listed twice", name)));
+            }
+            if other.prefix == prefix {
+                return Err(invalid(format!(
+                    "tenants {} and {} share the prefix {}",
+                    other.name, name, prefix
+                )));
+            }
+            if config.store_path.is_some() && other.config.store_path == config.store_path {
+                return Err(invalid(format!(
+                    "tenants {} and {} share a payout store",
+                    other.name, name
+                )));
+            }
+            // Their nonces would collide, since each service tracks its own
+            if other.config.expected_chain_id == config.expected_chain_id
+                && normalize_private_key(&other.config.operator_private_key).ok()
+                    == normalize_private_key(&config.operator_private_key).ok()
+            {
+                return Err(invalid(format!(
+                    "tenants {} and {} share an operator key",
+                    other.name, name
+                )));
+            }
+        }
+        tenants.push(TenantConfig {
+            name,
+            prefix,
+            config,
+        });
+    }
+    Ok(tenants)
+}
+
+/// A tenant and the service paying it out
+pub struct Tenant {
+    pub name: String,
+    pub prefix: String,
+    pub service: Arc<EthereumPayoutService>,
+    skips: SkipCounters,
+}
+
+impl Tenant {
+    /// Whether `destination` is under the tenant's prefix
+    fn owns(&self, destination: &str) -> bool {
+        destination
+            .strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    }
+
+    /// Payments to the tenant that `maybe_execute_payout` skipped since startup
+    pub fn skipped_payouts(&self) -> SkipStats {
+        self.skips.stats()
+    }
+
+    /// Health of the tenant's service, with the tenant's skips
+    pub async fn health(&self) -> HealthReport {
+        HealthReport {
+            skipped: self.skipped_payouts(),
+            ..self.service.health().await
+        }
+    }
+}
+
+/// Selects the payout service of a destination by its longest matching tenant prefix
+#[derive(Default)]
+pub struct PayoutRouter {
+    /// Longest prefix first
+    tenants: Vec<Tenant>,
+}
+
+impl PayoutRouter {
+    pub fn new() -> Self {
+        PayoutRouter::default()
+    }
+
+    /// Build and add a service for each tenant
+    pub fn from_configs(configs: Vec<TenantConfig>) -> Result<Self, PayoutError> {
+        let mut router = PayoutRouter::new();
+        for TenantConfig {
+            name,
+            prefix,
+            mut config,
+        } in configs
+        {
+            config.tenant = Some(name.clone());
+            let service = EthereumPayoutService::new(config)
+                .map_err(|err| PayoutError::Config(format!("Tenant {}: {}", name, err)))?;
+            router = router.with_tenant(name, prefix, Arc::new(service));
+        }
+        Ok(router)
+    }
+
+    /// Pay destinations under `prefix` with `service`
+    pub fn with_tenant(
+        mut self,
+        name: impl Into<String>,
+        prefix: impl Into<String>,
+        service: Arc<EthereumPayoutService>,
+    ) -> Self {
+        let name = name.into();
+        let prefix = prefix.into().trim_end_matches('.').to_string();
+        let position = self
+            .tenants
+            .iter()
+            .position(|tenant| tenant.prefix.len() < prefix.len())
+            .unwrap_or(self.tenants.len());
+        self.tenants.insert(
+            position,
+            Tenant {
+                skips: SkipCounters::for_tenant(&name),
+                name,
+                prefix,
+                service,
+            },
+        );
+        self
+    }
+
+    pub fn tenants(&self) -> &[Tenant] {
+        &self.tenants
+    }
+
+    pub fn tenant(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.name == name)
+    }
+
+    /// Tenant owning `destination`, `None` if no prefix matches
+    pub fn route(&self, destination: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.owns(destination))
+    }
+
+    /// Health of every tenant's service, in routing order
+    pub async fn health(&self) -> Vec<(String, HealthReport)> {
+        let mut reports = Vec::with_capacity(self.tenants.len());
+        for tenant in &self.tenants {
+            reports.push((tenant.name.clone(), tenant.health().await));
+        }
+        reports
+    }
+
+    /// Hand the request to its tenant's service; destinations of no tenant
+    /// are counted as skipped for want of a service
+    pub(super) async fn dispatch(&self, request: PayoutRequest) {
+        match self.route(&request.destination) {
+            Some(tenant) => route_payout(Some(&tenant.service), &tenant.skips, request).await,
+            None => route_payout(None, &SKIPPED_PAYOUTS, request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, FakeChain, TEST_OPERATOR};
+    use super::*;
+    use serde_json::json;
+
+    const SHOP2_TREASURY: &str = "0xe7f1725E7734CE288F8367e1Bb143E90bb3F0512";
+    const SHOP2_OPERATOR: &str = "0x70997970C51812dc3A010C7d01b50e0d17dc79C8";
+    const SHOP2_OPERATOR_KEY: &str =
+        "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+    const RECIPIENT: &str = "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC";
+
+    /// Chain whose `operator` has sent `nonce` transactions before the test
+    fn chain(operator: &str, nonce: u64) -> Arc<FakeChain> {
+        let chain = FakeChain::new(31337);
+        chain.set_nonce(operator, nonce);
+        chain
+    }
+
+    fn destination(shop: &str) -> String {
+        format!("test.{}.eth.31337.EURC.{}.t", shop, RECIPIENT)
+    }
+
+    fn tenant_service(
+        name: &str,
+        treasury: &str,
+        key: &str,
+        transport: Arc<FakeChain>,
+    ) -> Arc<EthereumPayoutService> {
+        let mut config = test_config();
+        config.treasury_address = treasury.to_string();
+        config.operator_private_key = key.to_string();
+        config.tenant = Some(name.to_string());
+        Arc::new(test_service(config, transport))
+    }
+
+    fn two_shops() -> (PayoutRouter, Arc<FakeChain>, Arc<FakeChain>) {
+        let (shop1, shop2) = (chain(TEST_OPERATOR, 5), chain(SHOP2_OPERATOR, 40));
+        let default = test_config();
+        let router = PayoutRouter::new()
+            .with_tenant(
+                "shop1",
+                "test.shop1",
+                tenant_service(
+                    "shop1",
+                    &default.treasury_address,
+                    &default.operator_private_key,
+                    shop1.clone(),
+                ),
+            )
+            .with_tenant(
+                "shop2",
+                "test.shop2.",
+                tenant_service("shop2", SHOP2_TREASURY, SHOP2_OPERATOR_KEY, shop2.clone()),
+            );
+        (router, shop1, shop2)
+    }
+
+    #[test]
+    fn routes_by_longest_prefix() {
+        let (router, ..) = two_shops();
+        let vip = router.tenant("shop1").unwrap().service.clone();
+        let router = router.with_tenant("vip", "test.shop1.vip", vip);
+
+        let route = |destination: &str| router.route(destination).map(|t| t.name.as_str());
+        assert_eq!(route(&destination("shop1")), Some("shop1"));
+        assert_eq!(route(&destination("shop2")), Some("shop2"));
+        assert_eq!(route(&destination("shop1.vip")), Some("vip"));
+        // Prefixes only match whole segments
+        assert_eq!(route(&destination("shop10")), None);
+        assert_eq!(route(&destination("shop3")), None);
+    }
+
+    #[tokio::test]
+    async fn tenants_keep_their_own_treasury_and_nonces() {
+        let (router, shop1, shop2) = two_shops();
+        for sequence in 1..=2 {
+            router
+                .dispatch(PayoutRequest::new(destination("shop1"), 100, sequence))
+                .await;
+        }
+        router
+            .dispatch(PayoutRequest::new(destination("shop2"), 100, 1))
+            .await;
+        router
+            .dispatch(PayoutRequest::new(destination("shop3"), 100, 1))
+            .await;
+
+        let sent = |transport: &FakeChain| -> Vec<(String, String, String)> {
+            transport
+                .sent_transactions()
+                .iter()
+                .map(|call| {
+                    let tx = call;
+                    let field = |key: &str| tx[key].as_str().unwrap().to_string();
+                    (field("from"), field("to"), field("nonce"))
+                })
+                .collect()
+        };
+        let treasury = test_config().treasury_address;
+        assert_eq!(
+            sent(&shop1),
+            vec![
+                (
+                    TEST_OPERATOR.to_string(),
+                    treasury.clone(),
+                    "0x5".to_string()
+                ),
+                (TEST_OPERATOR.to_string(), treasury, "0x6".to_string()),
+            ]
+        );
+        assert_eq!(
+            sent(&shop2),
+            vec![(
+                SHOP2_OPERATOR.to_string(),
+                SHOP2_TREASURY.to_string(),
+                "0x28".to_string()
+            )]
+        );
+
+        // Records stay in the tenant's store
+        let shop1_service = &router.tenant("shop1").unwrap().service;
+        let shop2_service = &router.tenant("shop2").unwrap().service;
+        assert!(shop1_service
+            .store()
+            .highest_sequence(&destination("shop2"))
+            .is_none());
+        assert_eq!(
+            shop2_service
+                .store()
+                .highest_sequence(&destination("shop2")),
+            Some(1)
+        );
+        assert_eq!(
+            router.tenant("shop1").unwrap().skipped_payouts(),
+            SkipStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_are_counted_per_tenant() {
+        let (router, _, shop2) = two_shops();
+        router
+            .dispatch(PayoutRequest::new("test.shop2.alice", 30, 1))
+            .await;
+        let burn = "test.shop2.eth.31337.EURC.0x000000000000000000000000000000000000dEaD.t";
+        router.dispatch(PayoutRequest::new(burn, 40, 1)).await;
+
+        let shop2_skips = router.tenant("shop2").unwrap().skipped_payouts();
+        assert_eq!(shop2_skips.not_ethereum.amount, 30);
+        assert_eq!(shop2_skips.blocked.amount, 40);
+        assert_eq!(
+            router.tenant("shop1").unwrap().skipped_payouts(),
+            SkipStats::default()
+        );
+        assert_eq!(shop2.call_count("eth_sendRawTransaction"), 0);
+    }
+
+    #[test]
+    fn parses_tenants_over_the_environment() {
+        let env = |name: &str| match name {
+            "ETHEREUM_RPC_URL" => Some("http://127.0.0.1:8545".to_string()),
+            "CHAIN_ID" => Some("31337".to_string()),
+            "TREASURY_ADDRESS" => Some(test_config().treasury_address),
+            "OPERATOR_PRIVATE_KEY" => Some(test_config().operator_private_key),
+            _ => None,
+        };
+        let file = json!({"tenants": [
+            {"name": "shop1", "prefix": "test.shop1"},
+            {"name": "shop2", "prefix": "test.shop2.", "settings": {
+                "TREASURY_ADDRESS": SHOP2_TREASURY,
+                "OPERATOR_PRIVATE_KEY": SHOP2_OPERATOR_KEY,
+                "SEQUENCE_MAX_LAG": 10,
+            }},
+        ]});
+        let tenants = parse_tenants(&file.to_string(), env).unwrap();
+        assert_eq!(
+            tenants[0].config.treasury_address,
+            test_config().treasury_address
+        );
+        assert_eq!(tenants[1].prefix, "test.shop2");
+        assert_eq!(tenants[1].config.treasury_address, SHOP2_TREASURY);
+        assert_eq!(tenants[1].config.sequence_max_lag, Some(10));
+        assert_eq!(tenants[1].config.tenant.as_deref(), Some("shop2"));
+        let router = PayoutRouter::from_configs(tenants).unwrap();
+        assert_eq!(router.tenants().len(), 2);
+
+        let fails = |file: Value| parse_tenants(&file.to_string(), env).is_err();
+        // Both tenants would use the operator key from the environment
+        assert!(fails(json!({"tenants": [
+            {"name": "shop1", "prefix": "test.shop1"},
+            {"name": "shop2", "prefix": "test.shop2"},
+        ]})));
+        assert!(fails(json!({"tenants": [
+            {"name": "shop1", "prefix": "test.shop1"},
+            {"name": "shop2", "prefix": "test.shop1.", "settings": {
+                "OPERATOR_PRIVATE_KEY": SHOP2_OPERATOR_KEY,
+            }},
+        ]})));
+        assert!(fails(json!({"tenants": [{"name": "shop1"}]})));
+        assert!(fails(json!({"tenants": [
+            {"name": "shop1", "prefix": "test.shop1", "settings": {"CHAIN_ID": "one"}},
+        ]})));
+        assert!(fails(json!([{"name": "shop1", "prefix": "test.shop1"}])));
+    }
+}
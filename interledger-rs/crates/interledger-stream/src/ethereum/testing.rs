@@ -0,0 +1,613 @@
+//! Test support for the Ethereum payout module
+//!
+//! Available to other crates with the `testing` feature.
+//!
+//! Golden files live in `testdata/ethereum` at the crate root. To regenerate
+//! them after an intentional payload change, run the tests with
+//! `UPDATE_GOLDEN=1`, e.g.
+//!
+//! ```text
+//! UPDATE_GOLDEN=1 cargo test -p interledger-stream --features ethereum-payout golden
+//! ```
+//!
+//! and review the resulting diff before committing it.
+
+use super::abi::to_checksum_address;
+pub use super::fake_chain::{FakeChain, FAKE_CHAIN_BALANCE};
+use super::rpc::rpc_request;
+use super::{
+    legacy_signing_hash, plan_payout, send_transaction_request, signed_legacy_transaction, Clock,
+    EthereumPayoutConfig, EthereumPayoutService, LocalSigner, PayoutError, PayoutPlan,
+    RpcTransport, Signature, Timestamp, TxParams,
+};
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Environment variable that makes [`assert_golden`] rewrite golden files instead of comparing
+pub const UPDATE_GOLDEN_ENV: &str = "UPDATE_GOLDEN";
+
+pub const TEST_TREASURY: &str = "0x5FbDB2315678afecb367f032d93F642f64180aa3";
+pub const TEST_OPERATOR: &str = "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266";
+pub const TEST_OPERATOR_KEY: &str =
+    "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+pub const TEST_DESTINATION: &str =
+    "test.receiver.eth.31337.EURC.0x70997970C51812dc3A010C7d01b50e0d17dc79C8.abc123";
+
+/// Fixed configuration pointing at the default Anvil deployment
+pub fn test_config() -> EthereumPayoutConfig {
+    EthereumPayoutConfig::new(
+        "http://127.0.0.1:8545",
+        TEST_TREASURY,
+        TEST_OPERATOR_KEY,
+        31337,
+    )
+}
+
+/// Service wired to the given mock transport or fake chain
+pub fn test_service(
+    config: EthereumPayoutConfig,
+    transport: Arc<dyn RpcTransport>,
+) -> EthereumPayoutService {
+    EthereumPayoutService::new(config)
+        .expect("test config must be valid")
+        .with_transport(transport)
+}
+
+/// Node accepting every payout at nonce 0 and a gas price of 1 wei, under
+/// the hash `0xabc`, its simulation returning nothing
+pub fn mock_chain() -> Arc<MockTransport> {
+    let transport = MockTransport::new();
+    transport.on_result("eth_getTransactionCount", json!("0x0"));
+    transport.on_result("eth_gasPrice", json!("0x1"));
+    transport.on_result("eth_call", json!("0x"));
+    transport.on_result("eth_sendRawTransaction", json!("0xabc"));
+    transport
+}
+
+/// Fake chain with the Treasury of [`test_config`] deployed on it
+pub fn deployed_chain() -> Arc<FakeChain> {
+    let chain = FakeChain::new(31337);
+    chain.set_code(TEST_TREASURY, "0x6080");
+    chain
+}
+
+/// Clock that only moves when advanced, or when something sleeps on it
+pub struct FakeClock {
+    now: Mutex<Timestamp>,
+}
+
+impl FakeClock {
+    pub fn new() -> Arc<Self> {
+        Arc::new(FakeClock {
+            now: Mutex::new(chrono::Utc::now()),
+        })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += chrono::Duration::from_std(duration).unwrap();
+    }
+
+    /// Step the clock back, as NTP may do to the wall clock
+    pub fn rewind(&self, duration: Duration) {
+        *self.now.lock().unwrap() -= chrono::Duration::from_std(duration).unwrap();
+    }
+}
+
+#[async_trait]
+impl Clock for FakeClock {
+    fn now(&self) -> Timestamp {
+        *self.now.lock().unwrap()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+type Handler = Box<dyn Fn(&Value) -> Result<Value, Value> + Send + Sync>;
+
+/// Scriptable in-memory JSON-RPC endpoint recording every request it receives
+///
+/// Handlers are registered per method and return either a `result` value or a
+/// JSON-RPC `error` object. Methods without a handler answer with -32601.
+#[derive(Default)]
+pub struct MockTransport {
+    handlers: Mutex<HashMap<String, Handler>>,
+    delays: Mutex<HashMap<String, Duration>>,
+    calls: Mutex<Vec<Value>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Arc<Self> {
+        Arc::new(MockTransport::default())
+    }
+
+    /// Answer `method` using `handler`, which receives the request params
+    pub fn on<F>(&self, method: &str, handler: F)
+    where
+        F: Fn(&Value) -> Result<Value, Value> + Send + Sync + 'static,
+    {
+        self.handlers
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), Box::new(handler));
+    }
+
+    /// Always answer `method` with the given result
+    pub fn on_result(&self, method: &str, result: Value) {
+        self.on(method, move |_| Ok(result.clone()));
+    }
+
+    /// Always answer `method` with a JSON-RPC error
+    pub fn on_error(&self, method: &str, code: i64, message: &str) {
+        let error = json!({ "code": code, "message": message });
+        self.on(method, move |_| Err(error.clone()));
+    }
+
+    /// Wait `delay` before answering `method`, as a slow node would
+    pub fn delay(&self, method: &str, delay: Duration) {
+        self.delays
+            .lock()
+            .unwrap()
+            .insert(method.to_string(), delay);
+    }
+
+    /// All request bodies received for `method`, in order
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call["method"] == method)
+            .cloned()
+            .collect()
+    }
+
+    pub fn call_count(&self, method: &str) -> usize {
+        self.calls(method).len()
+    }
+
+    /// Number of requests received for any method
+    pub fn request_count(&self) -> usize {
+        self.calls.lock().unwrap().len()
+    }
+
+    /// Transactions received with `eth_sendRawTransaction`, in order, see
+    /// [`decode_raw_transaction`]
+    pub fn sent_transactions(&self) -> Vec<Value> {
+        sent_transactions(&self.calls("eth_sendRawTransaction"))
+    }
+}
+
+/// Decoded transactions of `eth_sendRawTransaction` request bodies
+pub(super) fn sent_transactions(calls: &[Value]) -> Vec<Value> {
+    calls
+        .iter()
+        .map(|call| {
+            call["params"][0]
+                .as_str()
+                .and_then(decode_raw_transaction)
+                .expect("sent transactions must be signed EIP-155 legacy transactions")
+        })
+        .collect()
+}
+
+/// Fields of a signed EIP-155 legacy transaction as `eth_sendTransaction`
+/// would carry them, with `from` recovered from its signature
+pub fn decode_raw_transaction(raw: &str) -> Option<Value> {
+    let bytes = hex::decode(raw.strip_prefix("0x")?).ok()?;
+    let mut payload = match rlp_item(&bytes)? {
+        (true, payload, []) => payload,
+        _ => return None,
+    };
+    let mut fields = Vec::new();
+    while !payload.is_empty() {
+        let (list, item, rest) = rlp_item(payload)?;
+        if list {
+            return None;
+        }
+        fields.push(item);
+        payload = rest;
+    }
+    if fields.len() != 9 {
+        return None;
+    }
+    let uint = |bytes: &[u8]| -> Option<u64> {
+        if bytes.len() > 8 {
+            return None;
+        }
+        Some(bytes.iter().fold(0, |n, byte| n << 8 | u64::from(*byte)))
+    };
+    let word = |bytes: &[u8]| -> Option<[u8; 32]> {
+        let mut word = [0u8; 32];
+        word.get_mut(32usize.checked_sub(bytes.len())?..)?
+            .copy_from_slice(bytes);
+        Some(word)
+    };
+    let params = TxParams {
+        nonce: uint(fields[0])?,
+        gas_price: uint(fields[1])?,
+        gas_limit: uint(fields[2])?,
+    };
+    let to = to_checksum_address(fields[3].try_into().ok()?);
+    let value = uint(fields[4])?;
+    let data = format!("0x{}", hex::encode(fields[5]));
+    let v = uint(fields[6])?.checked_sub(35)?;
+    let chain_id = v / 2;
+    let signature = Signature {
+        r: word(fields[7])?,
+        s: word(fields[8])?,
+        v: 27 + (v % 2) as u8,
+    };
+    let hash = legacy_signing_hash(&to, &data, value, params, chain_id)?;
+    let from = signature.recover(&hash)?;
+    Some(send_transaction_request(&from, &to, &data, value, params)["params"][0].clone())
+}
+
+/// The RLP item at the front of `bytes`: whether it is a list, its payload
+/// and what follows it
+fn rlp_item(bytes: &[u8]) -> Option<(bool, &[u8], &[u8])> {
+    let (&first, rest) = bytes.split_first()?;
+    let long_length = |size: u8, rest: &[u8]| -> Option<(usize, usize)> {
+        let size = usize::from(size);
+        let length = rest
+            .get(..size)?
+            .iter()
+            .fold(0usize, |n, byte| n << 8 | usize::from(*byte));
+        Some((size, length))
+    };
+    let (list, skip, length) = match first {
+        0x00..=0x7f => return Some((false, &bytes[..1], rest)),
+        0x80..=0xb7 => (false, 0, usize::from(first - 0x80)),
+        0xb8..=0xbf => {
+            let (size, length) = long_length(first - 0xb7, rest)?;
+            (false, size, length)
+        }
+        0xc0..=0xf7 => (true, 0, usize::from(first - 0xc0)),
+        _ => {
+            let (size, length) = long_length(first - 0xf7, rest)?;
+            (true, size, length)
+        }
+    };
+    let rest = &rest[skip..];
+    if rest.len() < length {
+        return None;
+    }
+    Some((list, &rest[..length], &rest[length..]))
+}
+
+#[async_trait]
+impl RpcTransport for MockTransport {
+    async fn send(&self, request: Value) -> Result<Value, PayoutError> {
+        let requests = match &request {
+            Value::Array(batch) => batch.iter().collect(),
+            request => vec![request],
+        };
+        let delay = requests
+            .iter()
+            .filter_map(|request| request["method"].as_str())
+            .filter_map(|method| self.delays.lock().unwrap().get(method).copied())
+            .max();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        match request {
+            Value::Array(batch) => Ok(batch.iter().map(|member| self.answer(member)).collect()),
+            request => Ok(self.answer(&request)),
+        }
+    }
+}
+
+impl MockTransport {
+    fn answer(&self, request: &Value) -> Value {
+        self.calls.lock().unwrap().push(request.clone());
+        let method = request["method"].as_str().unwrap_or_default();
+        let outcome = match self.handlers.lock().unwrap().get(method) {
+            Some(handler) => handler(&request["params"]),
+            None => Err(json!({ "code": -32601, "message": "Method not found" })),
+        };
+        match outcome {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }),
+            Err(error) => json!({ "jsonrpc": "2.0", "id": request["id"], "error": error }),
+        }
+    }
+}
+
+/// Build the exact JSON-RPC request body the service would send for a
+/// payout: the `eth_sendRawTransaction` of its transaction signed with the
+/// configured operator key
+pub fn payout_request_body(
+    config: &EthereumPayoutConfig,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+    params: TxParams,
+) -> Value {
+    let plan = plan_payout(config, destination, amount, sequence)
+        .expect("golden inputs must form a valid payout");
+    raw_transaction_request(config, &plan, params)
+}
+
+/// Request body a payout is sent with under `node_signing`, the
+/// `eth_sendTransaction` the node signs for `operator_address`
+pub fn node_signed_payout_request_body(
+    config: &EthereumPayoutConfig,
+    operator_address: &str,
+    destination: &str,
+    amount: u64,
+    sequence: u64,
+    params: TxParams,
+) -> Value {
+    let plan = plan_payout(config, destination, amount, sequence)
+        .expect("golden inputs must form a valid payout");
+    send_transaction_request(operator_address, &plan.to, &plan.data, plan.value, params)
+}
+
+/// `eth_sendRawTransaction` of `plan` signed with the configured operator key
+pub(super) fn raw_transaction_request(
+    config: &EthereumPayoutConfig,
+    plan: &PayoutPlan,
+    params: TxParams,
+) -> Value {
+    let chain_id = config.expected_chain_id;
+    let signer = LocalSigner::from_hex(&config.operator_private_key)
+        .expect("golden inputs must have a valid operator key");
+    let hash = legacy_signing_hash(&plan.to, &plan.data, plan.value, params, chain_id)
+        .expect("golden inputs must form an encodable transaction");
+    let signature = signer.sign_hash(&hash).unwrap();
+    let raw = signed_legacy_transaction(
+        &plan.to, &plan.data, plan.value, params, chain_id, &signature,
+    )
+    .expect("golden inputs must form an encodable transaction");
+    rpc_request(
+        "eth_sendRawTransaction",
+        json!([format!("0x{}", hex::encode(raw))]),
+    )
+}
+
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("testdata")
+        .join("ethereum")
+        .join(format!("{}.json", name))
+}
+
+/// Compare `actual` against the named golden file, or rewrite it when `UPDATE_GOLDEN` is set
+pub fn assert_golden(name: &str, actual: &Value) {
+    let path = golden_path(name);
+    if std::env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        let mut contents = serde_json::to_string_pretty(actual).unwrap();
+        contents.push('\n');
+        std::fs::write(&path, contents).unwrap();
+        return;
+    }
+    let contents = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "missing golden file {} ({}); rerun with {}=1 to create it",
+            path.display(),
+            err,
+            UPDATE_GOLDEN_ENV
+        )
+    });
+    let expected: Value = serde_json::from_str(&contents).unwrap();
+    assert_eq!(
+        actual,
+        &expected,
+        "payload differs from golden file {}; rerun with {}=1 if the change is intended",
+        path.display(),
+        UPDATE_GOLDEN_ENV
+    );
+}
+
+#[cfg(test)]
+mod golden_tests {
+    use super::super::{plan_payout, PayoutCall, DEFAULT_GAS_LIMIT};
+    use super::*;
+
+    const GOLDEN_PARAMS: TxParams = TxParams {
+        nonce: 7,
+        gas_limit: 100_000,
+        gas_price: 2_000_000_000,
+    };
+
+    /// The signed request with its decoded fields, which show what changed
+    /// when the raw bytes do
+    fn golden(body: Value) -> Value {
+        let raw = body["params"][0].as_str().unwrap();
+        json!({ "request": body, "decoded": decode_raw_transaction(raw).unwrap() })
+    }
+
+    #[test]
+    fn golden_legacy_payout() {
+        let body = payout_request_body(
+            &test_config(),
+            TEST_DESTINATION,
+            1_500_000,
+            42,
+            GOLDEN_PARAMS,
+        );
+        assert_golden("payout_legacy", &golden(body));
+    }
+
+    #[tokio::test]
+    async fn the_service_sends_the_golden_request() {
+        let transport = mock_chain();
+        transport.on_result("eth_getTransactionCount", json!("0x7"));
+        transport.on_result("eth_gasPrice", json!("0x77359400"));
+        let service = test_service(test_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 1_500_000, 42)
+            .await
+            .unwrap();
+        assert_eq!(GOLDEN_PARAMS.gas_limit, DEFAULT_GAS_LIMIT);
+        let expected = payout_request_body(
+            &test_config(),
+            TEST_DESTINATION,
+            1_500_000,
+            42,
+            GOLDEN_PARAMS,
+        );
+        let sent = transport.calls("eth_sendRawTransaction").remove(0);
+        assert_eq!(sent["method"], expected["method"]);
+        assert_eq!(sent["params"], expected["params"]);
+    }
+
+    #[test]
+    fn golden_node_signed_payout() {
+        let body = node_signed_payout_request_body(
+            &test_config(),
+            TEST_OPERATOR,
+            TEST_DESTINATION,
+            1_500_000,
+            42,
+            GOLDEN_PARAMS,
+        );
+        assert_golden("payout_node_signed", &body);
+    }
+
+    #[test]
+    fn golden_legacy_payout_large_amount() {
+        let body =
+            payout_request_body(&test_config(), TEST_DESTINATION, u64::MAX, 0, GOLDEN_PARAMS);
+        assert_golden("payout_legacy_max_amount", &golden(body));
+    }
+
+    fn call_request_body(signature: &str, arguments: &str) -> Value {
+        let mut config = test_config();
+        config.payout_call = Some(PayoutCall::parse(signature, arguments).unwrap());
+        let body = payout_request_body(&config, TEST_DESTINATION, 1_500_000, 42, GOLDEN_PARAMS);
+        golden(body)
+    }
+
+    #[test]
+    fn golden_default_call_layout() {
+        let body = call_request_body(
+            "payoutToUser(bytes32,address,uint256)",
+            "payment_id,recipient,amount",
+        );
+        assert_golden("payout_legacy", &body);
+    }
+
+    #[test]
+    fn golden_reordered_call_layout() {
+        let body = call_request_body(
+            "payout(address,uint256,bytes32,uint16)",
+            "recipient,amount,payment_id,uint:7",
+        );
+        assert_golden("payout_reordered_call", &body);
+    }
+
+    fn memo_request_body(memo: &[u8]) -> Value {
+        let config = test_config();
+        let mut plan = plan_payout(&config, TEST_DESTINATION, 1_500_000, 42).unwrap();
+        plan.attach_memo(memo).unwrap();
+        golden(raw_transaction_request(&config, &plan, GOLDEN_PARAMS))
+    }
+
+    #[test]
+    fn golden_memo_payout_empty() {
+        assert_golden("payout_memo_empty", &memo_request_body(b""));
+    }
+
+    #[test]
+    fn golden_memo_payout_short() {
+        assert_golden("payout_memo_short", &memo_request_body(b"INV-2024-0042"));
+    }
+
+    #[test]
+    fn golden_memo_payout_word_boundary() {
+        let memo: Vec<u8> = (0..32).collect();
+        assert_golden("payout_memo_32_bytes", &memo_request_body(&memo));
+    }
+}
+
+/// Proptest generators for destination strings, shared by every test that
+/// exercises parsing so format extensions inherit the same coverage
+#[cfg(test)]
+pub mod strategies {
+    use super::super::abi::to_checksum_address;
+    use super::super::EthereumDestination;
+    use proptest::prelude::*;
+
+    /// A single address segment, biased towards values that are meaningful to the parser
+    pub fn segment() -> impl Strategy<Value = String> {
+        prop_oneof![
+            Just("eth".to_string()),
+            Just(String::new()),
+            "[0-9]{1,30}",
+            "[+-][0-9]{1,5}",
+            "0x[0-9a-fA-F]{0,44}",
+            "0x[^.]{38,42}",
+            "[A-Za-z0-9_~-]{1,12}",
+            "[^.]{0,12}",
+            "\\PC*".prop_map(|s| s.replace('.', "")),
+            "[\0a-z]{1,6}",
+        ]
+    }
+
+    /// Arbitrary, mostly malformed destination strings
+    pub fn arbitrary_destination() -> impl Strategy<Value = String> {
+        prop_oneof![
+            any::<String>(),
+            prop::collection::vec(segment(), 0..16).prop_map(|parts| parts.join(".")),
+            // Well-formed prefix followed by garbage after the marker
+            (
+                prop::collection::vec(segment(), 0..6),
+                prop::collection::vec(segment(), 0..6)
+            )
+                .prop_map(|(tail, head)| {
+                    let mut parts = head;
+                    parts.push("eth".to_string());
+                    parts.extend(tail);
+                    parts.join(".")
+                }),
+        ]
+    }
+
+    /// Recipient address in lower case, upper case or EIP-55 checksummed
+    pub fn recipient() -> impl Strategy<Value = String> {
+        (any::<[u8; 20]>(), 0..3u8).prop_map(|(address, casing)| match casing {
+            0 => format!("0x{}", hex::encode(address)),
+            1 => format!("0x{}", hex::encode_upper(address)),
+            _ => to_checksum_address(&address),
+        })
+    }
+
+    /// A valid destination string together with the value it must parse to
+    pub fn valid_destination() -> impl Strategy<Value = (String, EthereumDestination)> {
+        (
+            prop::collection::vec("[a-z0-9]{1,10}", 2..5),
+            any::<u64>(),
+            "[A-Z][A-Z0-9]{0,7}",
+            recipient(),
+            "[A-Za-z0-9_-]{1,43}",
+        )
+            .prop_filter("prefix must not contain the marker", |(prefix, ..)| {
+                !prefix.iter().any(|p| p == "eth")
+            })
+            .prop_map(|(prefix, chain_id, asset_code, recipient, token)| {
+                let destination = format!(
+                    "{}.eth.{}.{}.{}.{}",
+                    prefix.join("."),
+                    chain_id,
+                    asset_code,
+                    recipient,
+                    token
+                );
+                let expected = EthereumDestination {
+                    chain_id,
+                    chain_segment: chain_id.to_string(),
+                    asset_segment: asset_code.clone(),
+                    asset_code,
+                    recipient,
+                    token,
+                };
+                (destination, expected)
+            })
+    }
+}
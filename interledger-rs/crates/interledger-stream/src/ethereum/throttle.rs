@@ -0,0 +1,281 @@
+//! Backing off when the RPC provider rate-limits us
+//!
+//! A throttled request (HTTP 429, or a JSON-RPC "limit exceeded" error) sets
+//! a service-wide pause until the provider's `Retry-After`, or for
+//! `retry_interval` without one. Every RPC request waits out that pause
+//! first, so concurrent payouts back off together instead of each hitting
+//! the limit again.
+
+use super::{metrics, EthereumPayoutService, PayoutError, Timestamp};
+use std::time::Duration;
+use tracing::warn;
+
+/// Parse a `Retry-After` value, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str, now: Timestamp) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&chrono::Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Host of an endpoint URL, or `ipc` for a socket, used to label throttling
+/// without leaking API keys in paths
+pub(super) fn endpoint_label(url: &str) -> String {
+    if super::is_ipc_endpoint(url) {
+        return "ipc".to_string();
+    }
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl PayoutError {
+    /// Whether the provider refused the request for exceeding its rate limit
+    pub fn is_throttle(&self) -> bool {
+        match self {
+            PayoutError::Throttled { .. } => true,
+            PayoutError::Rpc { code, message, .. } => {
+                let message = message.to_ascii_lowercase();
+                *code == -32005 || message.contains("exceeded") || message.contains("rate limit")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl EthereumPayoutService {
+    /// Time until which RPC requests are held back after being throttled
+    pub fn throttled_until(&self) -> Option<Timestamp> {
+        let until = (*self.throttled_until.lock().unwrap())?;
+        Some(until).filter(|until| *until > self.clock.now())
+    }
+
+    /// Wait out a throttle set by this or any concurrent request
+    pub(super) async fn wait_for_throttle(&self) -> Result<(), PayoutError> {
+        while let Some(until) = self.throttled_until() {
+            let wait = (until - self.clock.now()).to_std().unwrap_or_default();
+            if !self.sleep_unless_cancelled(wait).await {
+                return Err(PayoutError::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Hold back all RPC requests for as long as the provider asked
+    pub(super) fn note_throttle(&self, err: &PayoutError) {
+        let (endpoint, retry_after) = match err {
+            PayoutError::Throttled {
+                endpoint,
+                retry_after,
+            } => (endpoint.clone(), *retry_after),
+            _ => (endpoint_label(&self.config.rpc_url), None),
+        };
+        let delay = retry_after.unwrap_or(self.config.retry_interval);
+        let until = self.clock.now()
+            + chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+        let mut throttled_until = self.throttled_until.lock().unwrap();
+        if throttled_until.is_none_or(|current| current < until) {
+            *throttled_until = Some(until);
+        }
+        metrics::throttled(self.config.tenant.as_deref(), &endpoint);
+        warn!(
+            "RPC provider {} is throttling requests, backing off for {:?}: {}",
+            endpoint, delay, err
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rpc::{rpc_request, HttpTransport, RpcTransport};
+    use super::super::testing::{test_config, test_service, FakeClock, MockTransport};
+    use super::super::Clock;
+    use super::*;
+    use async_trait::async_trait;
+    use chrono::TimeZone;
+    use mockito::{mock, Mock};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = chrono::Utc.timestamp_opt(784_111_657, 0).unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn recognises_throttling_errors() {
+        let rpc = |code, message: &str| PayoutError::Rpc {
+            code,
+            message: message.to_string(),
+            data: None,
+        };
+        assert!(rpc(-32005, "limit").is_throttle());
+        assert!(rpc(
+            429,
+            "Your app has exceeded its compute units per second capacity"
+        )
+        .is_throttle());
+        assert!(!rpc(-32000, "nonce too low").is_throttle());
+        assert!(PayoutError::Throttled {
+            endpoint: "node".to_string(),
+            retry_after: None
+        }
+        .is_throttle());
+    }
+
+    /// Fake clock that removes a mock the first time something sleeps on it,
+    /// standing in for the provider lifting its limit
+    struct LiftingClock {
+        clock: Arc<FakeClock>,
+        limit: Mutex<Option<Mock>>,
+    }
+
+    #[async_trait]
+    impl Clock for LiftingClock {
+        fn now(&self) -> Timestamp {
+            self.clock.now()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.limit.lock().unwrap().take();
+            self.clock.sleep(duration).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_retry_after_then_succeeds() {
+        // Answers the service's second request, the first being throttled
+        let success = mock("POST", "/throttled")
+            .with_body(json!({"jsonrpc": "2.0", "id": 2, "result": "0x2a"}).to_string())
+            .expect(1)
+            .create();
+        let limit = mock("POST", "/throttled")
+            .with_status(429)
+            .with_header("retry-after", "7")
+            .expect(1)
+            .create();
+
+        let url = format!("{}/throttled", mockito::server_url());
+        let err = HttpTransport::new(reqwest::Client::new(), url.clone())
+            .send(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            PayoutError::Throttled { ref endpoint, retry_after: Some(delay) }
+                if endpoint == "127.0.0.1" && delay == Duration::from_secs(7)
+        ));
+
+        let clock = Arc::new(LiftingClock {
+            clock: FakeClock::new(),
+            limit: Mutex::new(Some(limit)),
+        });
+        let start = clock.now();
+        let mut config = test_config();
+        config.rpc_url = url;
+        let service = EthereumPayoutService::new(config)
+            .unwrap()
+            .with_clock(clock.clone());
+        let result = service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap();
+        assert_eq!(result, "0x2a");
+        assert_eq!(clock.now() - start, chrono::Duration::seconds(7));
+        success.assert();
+    }
+
+    /// Transport refusing the first request as throttled, then delegating
+    struct ThrottleOnce {
+        inner: Arc<MockTransport>,
+        refused: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl RpcTransport for ThrottleOnce {
+        async fn send(&self, request: serde_json::Value) -> Result<serde_json::Value, PayoutError> {
+            if self.refused.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Err(PayoutError::Throttled {
+                    endpoint: "node".to_string(),
+                    retry_after: Some(Duration::from_secs(30)),
+                });
+            }
+            self.inner.send(request).await
+        }
+    }
+
+    #[tokio::test]
+    async fn throttle_holds_back_every_request() {
+        let inner = MockTransport::new();
+        inner.on_result("eth_blockNumber", json!("0x1"));
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let service = test_service(test_config(), inner.clone())
+            .with_transport(Arc::new(ThrottleOnce {
+                inner: inner.clone(),
+                refused: AtomicUsize::new(0),
+            }))
+            .with_clock(clock.clone());
+
+        service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap();
+        assert_eq!(clock.now() - start, chrono::Duration::seconds(30));
+
+        // Another request made while the throttle is active waits for it too
+        *service.throttled_until.lock().unwrap() = Some(clock.now() + chrono::Duration::seconds(5));
+        service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap();
+        assert_eq!(clock.now() - start, chrono::Duration::seconds(35));
+        assert_eq!(service.throttled_until(), None);
+    }
+
+    #[tokio::test]
+    async fn json_rpc_limit_errors_back_off_and_give_up() {
+        let transport = MockTransport::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        transport.on("eth_blockNumber", move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Err(json!({"code": -32005, "message": "request limit exceeded"}))
+        });
+        let mut config = test_config();
+        config.throttle_retries = 2;
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let service = test_service(config, transport).with_clock(clock.clone());
+
+        let err = service
+            .rpc(rpc_request("eth_blockNumber", json!([])))
+            .await
+            .unwrap_err();
+        assert!(err.is_throttle());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // Two waits of retry_interval between the three attempts
+        assert_eq!(clock.now() - start, chrono::Duration::seconds(10));
+    }
+}
@@ -0,0 +1,280 @@
+//! Time spent in each phase of a payout
+//!
+//! Sending a payout measures how long fetching the nonce, pricing gas and
+//! broadcasting took, and looking up its receipt adds the wait until it was
+//! mined. Transactions are signed by the node, so signing is part of the
+//! broadcast. Each phase is recorded with the payout and reported to the
+//! `payouts.ethereum.phase_duration_us` histogram, and payouts slower than
+//! `slow_payout_threshold` are logged with the phase they spent most time in.
+
+use super::store::PayoutRecord;
+use super::{metrics, EthereumPayoutService, PayoutStatus};
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Part of a payout's latency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutPhase {
+    Nonce,
+    Gas,
+    Broadcast,
+    Receipt,
+}
+
+impl PayoutPhase {
+    pub const ALL: [PayoutPhase; 4] = [
+        PayoutPhase::Nonce,
+        PayoutPhase::Gas,
+        PayoutPhase::Broadcast,
+        PayoutPhase::Receipt,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PayoutPhase::Nonce => "nonce",
+            PayoutPhase::Gas => "gas",
+            PayoutPhase::Broadcast => "broadcast",
+            PayoutPhase::Receipt => "receipt",
+        }
+    }
+}
+
+/// Time a payout spent per phase, summed over its attempts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    pub nonce: Duration,
+    pub gas: Duration,
+    pub broadcast: Duration,
+    /// From submission until the receipt was seen, `None` until then
+    pub receipt: Option<Duration>,
+    /// How much sooner the pre-flight reads answered together than they
+    /// would have one after another; not a phase of its own
+    pub preflight_saved: Duration,
+}
+
+impl PhaseTimings {
+    pub fn get(&self, phase: PayoutPhase) -> Duration {
+        match phase {
+            PayoutPhase::Nonce => self.nonce,
+            PayoutPhase::Gas => self.gas,
+            PayoutPhase::Broadcast => self.broadcast,
+            PayoutPhase::Receipt => self.receipt.unwrap_or_default(),
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        PayoutPhase::ALL.iter().map(|&phase| self.get(phase)).sum()
+    }
+
+    /// Phase that took longest, `None` if nothing was timed
+    pub fn dominant(&self) -> Option<PayoutPhase> {
+        PayoutPhase::ALL
+            .iter()
+            .copied()
+            .filter(|&phase| self.get(phase) > Duration::ZERO)
+            .max_by_key(|&phase| self.get(phase))
+    }
+
+    pub(super) fn add(&mut self, phase: PayoutPhase, elapsed: Duration) {
+        match phase {
+            PayoutPhase::Nonce => self.nonce += elapsed,
+            PayoutPhase::Gas => self.gas += elapsed,
+            PayoutPhase::Broadcast => self.broadcast += elapsed,
+            PayoutPhase::Receipt => self.receipt = Some(self.get(phase) + elapsed),
+        }
+    }
+
+    pub(super) fn to_json(self) -> Value {
+        json!({
+            "nonce_us": self.nonce.as_micros() as u64,
+            "gas_us": self.gas.as_micros() as u64,
+            "broadcast_us": self.broadcast.as_micros() as u64,
+            "receipt_us": self.receipt.map(|receipt| receipt.as_micros() as u64),
+            "preflight_saved_us": self.preflight_saved.as_micros() as u64,
+        })
+    }
+
+    /// Timings stored by [`PhaseTimings::to_json`]; records from before
+    /// timings were kept have none
+    pub(super) fn from_json(value: &Value) -> Self {
+        let micros = |name: &str| value[name].as_u64().map(Duration::from_micros);
+        PhaseTimings {
+            nonce: micros("nonce_us").unwrap_or_default(),
+            gas: micros("gas_us").unwrap_or_default(),
+            broadcast: micros("broadcast_us").unwrap_or_default(),
+            receipt: micros("receipt_us"),
+            preflight_saved: micros("preflight_saved_us").unwrap_or_default(),
+        }
+    }
+}
+
+/// Run `future`, adding the time it took to `phase`
+pub(super) async fn timed<F: Future>(
+    timings: &mut PhaseTimings,
+    phase: PayoutPhase,
+    future: F,
+) -> F::Output {
+    let started = Instant::now();
+    let output = future.await;
+    timings.add(phase, started.elapsed());
+    output
+}
+
+impl EthereumPayoutService {
+    /// Report the submission phases of a sent payout
+    pub(super) fn report_submission(&self, record: &PayoutRecord) {
+        for phase in [PayoutPhase::Nonce, PayoutPhase::Gas, PayoutPhase::Broadcast] {
+            metrics::phase_duration(
+                self.config.tenant.as_deref(),
+                phase.as_str(),
+                record.timings.get(phase),
+            );
+        }
+        metrics::preflight_saved(
+            self.config.tenant.as_deref(),
+            record.timings.preflight_saved,
+        );
+        self.counters
+            .submitted(&record.asset_code, record.amount, record.timings.total());
+        self.warn_if_slow(record);
+    }
+
+    /// Record how long a payout waited to be mined, once its receipt is seen
+    pub(super) fn report_receipt(&self, record: &mut PayoutRecord) {
+        if record.timings.receipt.is_some() {
+            return;
+        }
+        let submitted_before = record.timings.total();
+        let waited = (self.clock.now() - record.timestamp)
+            .to_std()
+            .unwrap_or_default();
+        record.timings.add(PayoutPhase::Receipt, waited);
+        metrics::phase_duration(self.config.tenant.as_deref(), "receipt", waited);
+        self.counters
+            .mined(&record.asset_code, record.status == PayoutStatus::Confirmed);
+        // Payouts already slow to submit were warned about then
+        if submitted_before <= self.config.slow_payout_threshold {
+            self.warn_if_slow(record);
+        }
+    }
+
+    fn warn_if_slow(&self, record: &PayoutRecord) {
+        let timings = &record.timings;
+        if timings.total() <= self.config.slow_payout_threshold {
+            return;
+        }
+        let millis = |phase| timings.get(phase).as_millis() as u64;
+        warn!(
+            payout.payment_id = %record.payment_id_hex(),
+            payout.duration_ms = timings.total().as_millis() as u64,
+            payout.dominant_phase = timings.dominant().map_or("none", PayoutPhase::as_str),
+            payout.nonce_ms = millis(PayoutPhase::Nonce),
+            payout.gas_ms = millis(PayoutPhase::Gas),
+            payout.broadcast_ms = millis(PayoutPhase::Broadcast),
+            payout.receipt_ms = millis(PayoutPhase::Receipt),
+            "Slow payout"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::super::{ExecutionMode, PayoutRequest, PayoutStatus};
+    use super::*;
+    use std::sync::Arc;
+
+    fn slow_transport() -> Arc<MockTransport> {
+        let transport = mock_chain();
+        transport.delay("eth_getTransactionCount", Duration::from_millis(20));
+        transport.delay("eth_sendRawTransaction", Duration::from_millis(120));
+        transport
+    }
+
+    #[tokio::test]
+    async fn attributes_time_to_the_phase_spent_in() {
+        let transport = slow_transport();
+        let service = test_service(test_config(), transport.clone());
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+
+        let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+        let timings = service.store().get(&payment_id).unwrap().timings;
+        assert!(timings.nonce >= Duration::from_millis(20));
+        assert!(timings.nonce < Duration::from_millis(120));
+        assert!(timings.broadcast >= Duration::from_millis(120));
+        assert!(timings.gas < Duration::from_millis(20));
+        assert_eq!(timings.receipt, None);
+        assert_eq!(timings.dominant(), Some(PayoutPhase::Broadcast));
+
+        // The receipt wait runs from submission until the receipt is seen
+        transport.on_result(
+            "eth_getTransactionReceipt",
+            json!({"blockNumber": "0x1", "gasUsed": "0x5208", "status": "0x1"}),
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let record = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(record.status, PayoutStatus::Confirmed);
+        let receipt = record.timings.receipt.unwrap();
+        assert!(receipt >= Duration::from_millis(200));
+        assert_eq!(record.timings.dominant(), Some(PayoutPhase::Receipt));
+        // Later lookups keep the first observation
+        let again = service.refresh_receipt(&payment_id).await.unwrap().unwrap();
+        assert_eq!(again.timings.receipt, Some(receipt));
+    }
+
+    #[tokio::test]
+    async fn every_execution_mode_records_timings() {
+        for mode in [
+            ExecutionMode::Inline,
+            ExecutionMode::Spawned,
+            ExecutionMode::Queued,
+        ] {
+            let mut config = test_config();
+            config.execution_mode = mode;
+            let service = Arc::new(test_service(config, slow_transport()));
+            let _worker = service.spawn_payout_worker();
+            service
+                .dispatch_payout(PayoutRequest::new(TEST_DESTINATION, 100, 1))
+                .await;
+
+            let payment_id = service.config().payment_id(TEST_DESTINATION, 1);
+            let record = loop {
+                match service.store().get(&payment_id) {
+                    Some(record) => break record,
+                    None => tokio::time::sleep(Duration::from_millis(10)).await,
+                }
+            };
+            assert!(
+                record.timings.broadcast >= Duration::from_millis(120),
+                "{:?}",
+                mode
+            );
+            assert_eq!(record.timings.dominant(), Some(PayoutPhase::Broadcast));
+        }
+    }
+
+    #[test]
+    fn timings_round_trip_and_default_when_absent() {
+        let timings = PhaseTimings {
+            nonce: Duration::from_micros(1500),
+            gas: Duration::from_micros(20),
+            broadcast: Duration::from_millis(7),
+            receipt: Some(Duration::from_secs(8)),
+            preflight_saved: Duration::from_millis(3),
+        };
+        assert_eq!(PhaseTimings::from_json(&timings.to_json()), timings);
+        assert_eq!(
+            PhaseTimings::from_json(&Value::Null),
+            PhaseTimings::default()
+        );
+        assert_eq!(PhaseTimings::default().dominant(), None);
+        assert_eq!(timings.total(), Duration::from_micros(8_008_520));
+    }
+}
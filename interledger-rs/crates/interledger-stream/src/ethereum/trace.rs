@@ -0,0 +1,121 @@
+//! W3C trace context propagation to the RPC provider
+//!
+//! The crate does not depend on OpenTelemetry itself. The host registers a
+//! [`TraceContextSource`] reading the context of the current `tracing` span,
+//! e.g. through `tracing-opentelemetry`, and every HTTP JSON-RPC request made
+//! while that span is active carries it as `traceparent` and `tracestate`.
+
+use std::sync::{Arc, OnceLock};
+
+/// Trace and parent span a request belongs to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    /// Vendor-specific `tracestate` list, passed through unchanged
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// The `traceparent` header value, version 00
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id),
+            self.sampled as u8
+        )
+    }
+
+    /// All-zero IDs are invalid and must not be propagated
+    pub fn is_valid(&self) -> bool {
+        self.trace_id != [0; 16] && self.span_id != [0; 8]
+    }
+}
+
+/// Provides the trace context of the caller, if any
+pub trait TraceContextSource: Send + Sync {
+    fn current(&self) -> Option<TraceContext>;
+}
+
+static SOURCE: OnceLock<Arc<dyn TraceContextSource>> = OnceLock::new();
+
+/// Register the process-wide trace context source. Returns `false` if one was already set.
+pub fn set_trace_context_source(source: Arc<dyn TraceContextSource>) -> bool {
+    SOURCE.set(source).is_ok()
+}
+
+/// Add the current trace context, if valid, to an outgoing request
+pub(super) fn inject(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    let context = match SOURCE.get().and_then(|source| source.current()) {
+        Some(context) if context.is_valid() => context,
+        _ => return request,
+    };
+    let request = request.header("traceparent", context.traceparent());
+    match &context.tracestate {
+        Some(tracestate) if !tracestate.is_empty() => request.header("tracestate", tracestate),
+        _ => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::rpc::{rpc_request, HttpTransport, RpcTransport};
+    use super::*;
+    use mockito::{mock, Matcher};
+    use serde_json::json;
+
+    struct FixedSource;
+
+    impl TraceContextSource for FixedSource {
+        fn current(&self) -> Option<TraceContext> {
+            Some(TraceContext {
+                trace_id: [0x4b; 16],
+                span_id: [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7],
+                sampled: true,
+                tracestate: Some("congo=t61rcWkgMzE".to_string()),
+            })
+        }
+    }
+
+    #[test]
+    fn formats_traceparent() {
+        let context = FixedSource.current().unwrap();
+        assert_eq!(
+            context.traceparent(),
+            "00-4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b-00f067aa0ba902b7-01"
+        );
+        assert!(context.is_valid());
+        assert!(!TraceContext {
+            trace_id: [0; 16],
+            ..context
+        }
+        .is_valid());
+    }
+
+    #[tokio::test]
+    async fn rpc_requests_carry_trace_headers() {
+        set_trace_context_source(Arc::new(FixedSource));
+        let mock = mock("POST", "/traced")
+            .match_header(
+                "traceparent",
+                Matcher::Regex("^00-[0-9a-f]{32}-[0-9a-f]{16}-0[01]$".to_string()),
+            )
+            .match_header("tracestate", "congo=t61rcWkgMzE")
+            .with_body(json!({"jsonrpc": "2.0", "id": 1, "result": "31337"}).to_string())
+            .expect(1)
+            .create();
+
+        let transport = HttpTransport::new(
+            reqwest::Client::new(),
+            format!("{}/traced", mockito::server_url()),
+        );
+        let response = transport
+            .send(rpc_request("net_version", json!([])))
+            .await
+            .unwrap();
+        assert_eq!(response["result"], "31337");
+        mock.assert();
+    }
+}
@@ -0,0 +1,452 @@
+//! ERC-4337 UserOperation submission (EntryPoint v0.6)
+//!
+//! With a smart account as operator, payouts are not sent as transactions but
+//! as UserOperations calling `execute(treasury, 0, payoutCalldata)` on the
+//! account. Gas is estimated and the operation submitted through a bundler,
+//! which is then polled for the receipt.
+
+use super::abi::{decode_words, encode_address, encode_call, encode_uint, selector};
+use super::hash::keccak256;
+use super::rpc::{parse_quantity, rpc_request, rpc_response};
+use super::shutdown::unless_cancelled;
+use super::{
+    EthereumPayoutService, PayoutError, PayoutOutcome, PayoutPlan, PayoutRequest, PayoutStatus,
+    RpcTransport,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Canonical address of the v0.6 EntryPoint
+pub const ENTRY_POINT_V06: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+
+/// Placeholder signature accepted by `SimpleAccount` during gas estimation
+const DUMMY_SIGNATURE: &str = "0xfffffffffffffffffffffffffffffff0000000000000000000000000000000007aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa1c";
+
+/// Smart account and bundler used to submit payouts as UserOperations
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserOpConfig {
+    /// Smart account that holds the Treasury's operator role
+    pub smart_account: String,
+    pub entry_point: String,
+    pub bundler_url: String,
+    /// Delay between `eth_getUserOperationReceipt` polls
+    pub receipt_poll_interval: Duration,
+    /// Give up waiting for a receipt after this many polls
+    pub receipt_poll_attempts: u32,
+}
+
+impl UserOpConfig {
+    pub fn new(smart_account: impl Into<String>, bundler_url: impl Into<String>) -> Self {
+        UserOpConfig {
+            smart_account: smart_account.into(),
+            entry_point: ENTRY_POINT_V06.to_string(),
+            bundler_url: bundler_url.into(),
+            receipt_poll_interval: Duration::from_secs(2),
+            receipt_poll_attempts: 60,
+        }
+    }
+}
+
+/// A v0.6 UserOperation
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserOperation {
+    pub sender: String,
+    pub nonce: u64,
+    pub init_code: Vec<u8>,
+    pub call_data: Vec<u8>,
+    pub call_gas_limit: u64,
+    pub verification_gas_limit: u64,
+    pub pre_verification_gas: u64,
+    pub max_fee_per_gas: u64,
+    pub max_priority_fee_per_gas: u64,
+    pub paymaster_and_data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl UserOperation {
+    /// The v0.6 `pack`: every field but the signature, with dynamic fields hashed
+    pub fn pack(&self) -> Result<Vec<u8>, PayoutError> {
+        let sender = encode_address(&self.sender)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid smart account {}", self.sender)))?;
+        let words = [
+            sender,
+            encode_uint(self.nonce.into()),
+            keccak256(&self.init_code),
+            keccak256(&self.call_data),
+            encode_uint(self.call_gas_limit.into()),
+            encode_uint(self.verification_gas_limit.into()),
+            encode_uint(self.pre_verification_gas.into()),
+            encode_uint(self.max_fee_per_gas.into()),
+            encode_uint(self.max_priority_fee_per_gas.into()),
+            keccak256(&self.paymaster_and_data),
+        ];
+        Ok(words.concat())
+    }
+
+    /// `EntryPoint.getUserOpHash`: keccak256(abi.encode(keccak256(pack), entryPoint, chainId))
+    pub fn hash(&self, entry_point: &str, chain_id: u64) -> Result<[u8; 32], PayoutError> {
+        let entry_point = encode_address(entry_point)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid entry point {}", entry_point)))?;
+        let packed = [
+            keccak256(&self.pack()?),
+            entry_point,
+            encode_uint(chain_id.into()),
+        ]
+        .concat();
+        Ok(keccak256(&packed))
+    }
+
+    /// JSON form used by the bundler RPC methods
+    pub fn to_json(&self) -> Value {
+        let bytes = |data: &[u8]| format!("0x{}", hex::encode(data));
+        let quantity = |value: u64| format!("0x{:x}", value);
+        json!({
+            "sender": self.sender,
+            "nonce": quantity(self.nonce),
+            "initCode": bytes(&self.init_code),
+            "callData": bytes(&self.call_data),
+            "callGasLimit": quantity(self.call_gas_limit),
+            "verificationGasLimit": quantity(self.verification_gas_limit),
+            "preVerificationGas": quantity(self.pre_verification_gas),
+            "maxFeePerGas": quantity(self.max_fee_per_gas),
+            "maxPriorityFeePerGas": quantity(self.max_priority_fee_per_gas),
+            "paymasterAndData": bytes(&self.paymaster_and_data),
+            "signature": bytes(&self.signature),
+        })
+    }
+}
+
+/// Calldata for the account's `execute(address dest, uint256 value, bytes func)`
+pub fn execute_calldata(dest: &str, value: u64, func: &[u8]) -> Option<Vec<u8>> {
+    let mut data = selector("execute(address,uint256,bytes)").to_vec();
+    data.extend_from_slice(&encode_address(dest)?);
+    data.extend_from_slice(&encode_uint(value.into()));
+    // Offset of the dynamic `func` argument, after the three head words
+    data.extend_from_slice(&encode_uint(0x60));
+    data.extend_from_slice(&encode_uint(func.len() as u128));
+    data.extend_from_slice(func);
+    data.resize(data.len() + (32 - func.len() % 32) % 32, 0);
+    Some(data)
+}
+
+/// The EIP-191 personal-message digest `SimpleAccount` verifies signatures against
+pub fn eth_signed_message_hash(hash: &[u8; 32]) -> [u8; 32] {
+    let mut data = b"\x19Ethereum Signed Message:\n32".to_vec();
+    data.extend_from_slice(hash);
+    keccak256(&data)
+}
+
+impl EthereumPayoutService {
+    /// Submit `plan` as a UserOperation and wait for the bundler's receipt
+    pub(super) async fn submit_user_operation(
+        &self,
+        request: &PayoutRequest,
+        plan: &PayoutPlan,
+        config: &UserOpConfig,
+    ) -> Result<PayoutOutcome, PayoutError> {
+        let func = hex::decode(plan.data.trim_start_matches("0x"))
+            .map_err(|_| PayoutError::Config("Payout call data is not hex".to_string()))?;
+        let call_data = execute_calldata(&plan.to, 0, &func)
+            .ok_or_else(|| PayoutError::Config(format!("Invalid payout target {}", plan.to)))?;
+        let gas_price = self.get_gas_price().await?;
+        let priority_fee = self.priority_fee().await?.min(gas_price);
+        let mut op = UserOperation {
+            sender: config.smart_account.clone(),
+            nonce: self.entry_point_nonce(config).await?,
+            call_data,
+            max_fee_per_gas: gas_price,
+            max_priority_fee_per_gas: priority_fee,
+            signature: hex::decode(&DUMMY_SIGNATURE[2..]).expect("valid dummy signature"),
+            ..UserOperation::default()
+        };
+
+        let estimate = self
+            .bundler_rpc(rpc_request(
+                "eth_estimateUserOperationGas",
+                json!([op.to_json(), &config.entry_point]),
+            ))
+            .await?;
+        op.call_gas_limit = parse_quantity(&estimate["callGasLimit"])?;
+        op.verification_gas_limit = parse_quantity(&estimate["verificationGasLimit"])?;
+        op.pre_verification_gas = parse_quantity(&estimate["preVerificationGas"])?;
+
+        let hash = op.hash(&config.entry_point, self.config.expected_chain_id)?;
+        let signer = self.operator_signer()?;
+        op.signature = signer
+            .sign_normalized(&eth_signed_message_hash(&hash))
+            .await?
+            .to_bytes()
+            .to_vec();
+
+        let user_op_hash = self
+            .bundler_rpc(rpc_request(
+                "eth_sendUserOperation",
+                json!([op.to_json(), &config.entry_point]),
+            ))
+            .await?;
+        let user_op_hash = user_op_hash.as_str().map(str::to_string).ok_or_else(|| {
+            PayoutError::InvalidResponse(format!("eth_sendUserOperation returned {}", user_op_hash))
+        })?;
+        info!("UserOperation submitted: {}", user_op_hash);
+
+        for _ in 0..config.receipt_poll_attempts {
+            let receipt = self
+                .bundler_rpc(rpc_request(
+                    "eth_getUserOperationReceipt",
+                    json!([&user_op_hash]),
+                ))
+                .await?;
+            if receipt.is_null() {
+                if unless_cancelled(
+                    &self.cancellation,
+                    tokio::time::sleep(config.receipt_poll_interval),
+                )
+                .await
+                .is_none()
+                {
+                    break;
+                }
+                continue;
+            }
+            if receipt["success"] == json!(false) {
+                let err = PayoutError::InvalidResponse(format!(
+                    "UserOperation {} failed: {}",
+                    user_op_hash, receipt["reason"]
+                ));
+                self.store.save(self.failed_record(request, plan, &err));
+                return Err(err);
+            }
+            let tx_hash = receipt["receipt"]["transactionHash"]
+                .as_str()
+                .ok_or_else(|| {
+                    PayoutError::InvalidResponse(format!(
+                        "Receipt without transaction: {}",
+                        receipt
+                    ))
+                })?
+                .to_string();
+            self.store.save(self.payout_record(
+                request,
+                plan,
+                Some(tx_hash.clone()),
+                PayoutStatus::Submitted,
+            ));
+            return Ok(PayoutOutcome::Submitted { tx_hash });
+        }
+
+        warn!("No receipt for UserOperation {} yet", user_op_hash);
+        let record = self.payout_record(request, plan, None, PayoutStatus::Submitted);
+        if self.cancellation.is_cancelled() {
+            let payment_id = record.payment_id_hex();
+            self.store.save(record);
+            return Ok(PayoutOutcome::Cancelled { payment_id });
+        }
+        self.store.save(record);
+        Err(PayoutError::Transport(format!(
+            "Timed out waiting for the receipt of UserOperation {}",
+            user_op_hash
+        )))
+    }
+
+    /// `EntryPoint.getNonce(sender, 0)`
+    async fn entry_point_nonce(&self, config: &UserOpConfig) -> Result<u64, PayoutError> {
+        let sender = encode_address(&config.smart_account).ok_or_else(|| {
+            PayoutError::Config(format!("Invalid smart account {}", config.smart_account))
+        })?;
+        let result = self
+            .read_at_block(
+                "eth_call",
+                vec![json!({
+                    "to": &config.entry_point,
+                    "data": encode_call(selector("getNonce(address,uint192)"), &[sender, [0u8; 32]]),
+                })],
+            )
+            .await?;
+        let word = result
+            .as_str()
+            .and_then(decode_words)
+            .and_then(|words| words.first().copied())
+            .filter(|word| word[..24].iter().all(|b| *b == 0))
+            .ok_or_else(|| PayoutError::InvalidResponse(format!("getNonce returned {}", result)))?;
+        let mut low = [0u8; 8];
+        low.copy_from_slice(&word[24..]);
+        Ok(u64::from_be_bytes(low))
+    }
+
+    async fn bundler_rpc(&self, mut request: Value) -> Result<Value, PayoutError> {
+        let bundler: &dyn RpcTransport = self
+            .bundler
+            .as_deref()
+            .ok_or_else(|| PayoutError::Config("No bundler configured".to_string()))?;
+        let id = self.next_rpc_ids(1);
+        request["id"] = json!(id);
+        rpc_response(bundler.send(request).await?, id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        test_config, test_service, MockTransport, TEST_DESTINATION, TEST_TREASURY,
+    };
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const ACCOUNT: &str = "0x2222222222222222222222222222222222222222";
+
+    fn sample() -> UserOperation {
+        UserOperation {
+            sender: ACCOUNT.to_string(),
+            nonce: 7,
+            init_code: Vec::new(),
+            call_data: vec![0xb6, 0x1d, 0x27, 0xf6],
+            call_gas_limit: 100_000,
+            verification_gas_limit: 150_000,
+            pre_verification_gas: 21_000,
+            max_fee_per_gas: 2_000_000_000,
+            max_priority_fee_per_gas: 1_000_000_000,
+            paymaster_and_data: Vec::new(),
+            signature: vec![0xaa; 65],
+        }
+    }
+
+    #[test]
+    fn packs_per_v06_entry_point() {
+        let op = sample();
+        let packed = op.pack().unwrap();
+        assert_eq!(packed.len(), 10 * 32);
+        assert_eq!(
+            hex::encode(&packed[..32]),
+            "0000000000000000000000002222222222222222222222222222222222222222"
+        );
+        // Empty initCode and paymasterAndData hash to keccak256("")
+        let empty = "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470";
+        assert_eq!(hex::encode(&packed[64..96]), empty);
+        assert_eq!(hex::encode(&packed[288..]), empty);
+        assert_eq!(
+            hex::encode(&packed[96..128]),
+            hex::encode(keccak256(&op.call_data))
+        );
+
+        // The signature is not part of the hash, everything else is
+        let hash = op.hash(ENTRY_POINT_V06, 1).unwrap();
+        let resigned = UserOperation {
+            signature: Vec::new(),
+            ..sample()
+        };
+        assert_eq!(resigned.hash(ENTRY_POINT_V06, 1).unwrap(), hash);
+        assert_ne!(op.hash(ENTRY_POINT_V06, 31337).unwrap(), hash);
+        let bumped = UserOperation {
+            nonce: 8,
+            ..sample()
+        };
+        assert_ne!(bumped.hash(ENTRY_POINT_V06, 1).unwrap(), hash);
+    }
+
+    #[test]
+    fn encodes_execute_call() {
+        assert_eq!(
+            hex::encode(selector("execute(address,uint256,bytes)")),
+            "b61d27f6"
+        );
+        let data = execute_calldata(TEST_TREASURY, 0, &[0xb7, 0x72, 0x76, 0xd8, 0x01]).unwrap();
+        assert_eq!(data.len(), 4 + 5 * 32);
+        assert_eq!(
+            hex::encode(&data[100..132]),
+            format!("{:064x}", 5),
+            "length of the inner call"
+        );
+        assert_eq!(hex::encode(&data[132..137]), "b77276d801");
+        assert!(data[137..].iter().all(|b| *b == 0));
+    }
+
+    #[tokio::test]
+    async fn submits_through_bundler_and_polls_receipt() {
+        let node = MockTransport::new();
+        node.on_result("eth_gasPrice", json!("0x3b9aca00"));
+        node.on_result("eth_call", json!(format!("0x{:064x}", 3)));
+        let bundler = MockTransport::new();
+        bundler.on_result(
+            "eth_estimateUserOperationGas",
+            json!({
+                "preVerificationGas": "0xb708",
+                "verificationGasLimit": "0x186a0",
+                "callGasLimit": "0x11170"
+            }),
+        );
+        bundler.on_result("eth_sendUserOperation", json!("0x0b0b"));
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        bundler.on("eth_getUserOperationReceipt", move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) < 2 {
+                Ok(Value::Null)
+            } else {
+                Ok(json!({"success": true, "receipt": {"transactionHash": "0xfeed"}}))
+            }
+        });
+
+        let mut config = test_config();
+        let mut user_op = UserOpConfig::new(ACCOUNT, "http://bundler.invalid");
+        user_op.receipt_poll_interval = Duration::from_millis(1);
+        config.user_op = Some(user_op);
+        let service = test_service(config, node.clone()).with_bundler_transport(bundler.clone());
+
+        let outcome = service
+            .execute_payout(TEST_DESTINATION, 1_000, 1)
+            .await
+            .unwrap();
+        assert_eq!(outcome.tx_hash(), Some("0xfeed"));
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+        assert_eq!(node.call_count("eth_sendRawTransaction"), 0);
+
+        let nonce_call = &node.calls("eth_call")[0]["params"][0];
+        assert_eq!(nonce_call["to"], ENTRY_POINT_V06);
+        assert!(nonce_call["data"]
+            .as_str()
+            .unwrap()
+            .starts_with("0x35567e1a"));
+
+        let sent = &bundler.calls("eth_sendUserOperation")[0]["params"];
+        assert_eq!(sent[1], ENTRY_POINT_V06);
+        let op = &sent[0];
+        assert_eq!(op["sender"], ACCOUNT);
+        assert_eq!(op["nonce"], "0x3");
+        assert_eq!(op["callGasLimit"], "0x11170");
+        assert_eq!(op["preVerificationGas"], "0xb708");
+        assert!(op["callData"].as_str().unwrap().starts_with("0xb61d27f6"));
+        assert_eq!(op["signature"].as_str().unwrap().len(), 2 + 130);
+        assert_ne!(op["signature"], DUMMY_SIGNATURE);
+    }
+
+    #[tokio::test]
+    async fn failed_user_operation_is_recorded() {
+        let node = MockTransport::new();
+        node.on_result("eth_gasPrice", json!("0x1"));
+        node.on_result("eth_call", json!(format!("0x{:064x}", 0)));
+        let bundler = MockTransport::new();
+        bundler.on_result(
+            "eth_estimateUserOperationGas",
+            json!({"preVerificationGas": "0x1", "verificationGasLimit": "0x1", "callGasLimit": "0x1"}),
+        );
+        bundler.on_result("eth_sendUserOperation", json!("0x0b0b"));
+        bundler.on_result(
+            "eth_getUserOperationReceipt",
+            json!({"success": false, "reason": "AA23 reverted"}),
+        );
+        let mut config = test_config();
+        config.user_op = Some(UserOpConfig::new(ACCOUNT, "http://bundler.invalid"));
+        let service = test_service(config, node).with_bundler_transport(bundler);
+
+        assert!(service
+            .execute_payout(TEST_DESTINATION, 1_000, 1)
+            .await
+            .is_err());
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        assert_eq!(
+            service.store().get(&payment_id).unwrap().status,
+            PayoutStatus::Failed
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Re-verification of past payouts against the chain as it is now
+//!
+//! Support may need to confirm long after the fact that a payout's transaction
+//! is still canonical and paid what its record says. [`verify_onchain`]
+//! fetches the receipt again, checks that its block is still the canonical one
+//! at its height, and decodes its logs: the Treasury's `PayoutExecuted` event
+//! for Treasury payouts, the token's `Transfer` events for direct transfers
+//! and authorizations. Native payouts emit no logs, so only their inclusion
+//! and status are checked.
+//!
+//! Nodes that prune old receipts or blocks cannot answer for old payouts;
+//! those are reported as [`OnchainVerification::Unverifiable`] rather than
+//! missing, to be checked against an archive node.
+//!
+//! [`verify_onchain`]: EthereumPayoutService::verify_onchain
+
+use super::abi::{decode_words, encode_address};
+use super::hash::keccak256;
+use super::rpc::rpc_request;
+use super::{
+    received_amount, EthereumPayoutService, PayoutError, PayoutMode, PayoutRecord,
+    TransactionReceipt,
+};
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+/// Event the Treasury emits for every payout
+pub const PAYOUT_EXECUTED_EVENT_SIGNATURE: &str = "PayoutExecuted(bytes32,address,uint256,address)";
+
+/// What the chain says about a stored payout today
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnchainVerification {
+    /// Mined in a canonical block, paying what the record says
+    Match {
+        block_number: u64,
+        block_hash: String,
+    },
+    /// Mined in a canonical block, but not paying what the record says
+    Mismatch { details: Vec<String> },
+    /// The node knows no transaction for the payout
+    NotFound,
+    /// The block the transaction was mined in is no longer canonical
+    Reorged {
+        block_number: u64,
+        block_hash: String,
+    },
+    /// The node no longer serves the history needed; check against an archive node
+    Unverifiable { reason: String },
+}
+
+/// Whether `err` is a node declining to serve history it pruned
+pub fn is_pruned_history(err: &PayoutError) -> bool {
+    match err {
+        PayoutError::Rpc { message, .. } => {
+            let message = message.to_ascii_lowercase();
+            [
+                "pruned",
+                "missing trie node",
+                "header not found",
+                "history",
+                "not available",
+                "indexing is in progress",
+            ]
+            .iter()
+            .any(|pattern| message.contains(pattern))
+        }
+        _ => false,
+    }
+}
+
+fn topic(word: &[u8; 32]) -> String {
+    format!("0x{}", hex::encode(word))
+}
+
+/// Ways the logs of a Treasury payout's receipt disagree with its record
+fn treasury_mismatches(logs: &Value, treasury: &str, record: &PayoutRecord) -> Vec<String> {
+    let event = topic(&keccak256(PAYOUT_EXECUTED_EVENT_SIGNATURE.as_bytes()));
+    let payment_id = topic(&record.payment_id);
+    let executed = logs.as_array().and_then(|logs| {
+        logs.iter().find(|log| {
+            let topics = &log["topics"];
+            log["address"]
+                .as_str()
+                .is_some_and(|address| address.eq_ignore_ascii_case(treasury))
+                && topics[0]
+                    .as_str()
+                    .is_some_and(|first| first.eq_ignore_ascii_case(&event))
+                && topics[1]
+                    .as_str()
+                    .is_some_and(|id| id.eq_ignore_ascii_case(&payment_id))
+        })
+    });
+    let executed = match executed {
+        Some(executed) => executed,
+        None => return vec!["no PayoutExecuted event for the payment ID".to_string()],
+    };
+
+    let mut details = Vec::new();
+    let recipient = encode_address(&record.recipient);
+    let paid_to = executed["topics"][2]
+        .as_str()
+        .and_then(decode_words)
+        .and_then(|words| words.first().copied());
+    if recipient.is_none() || paid_to != recipient {
+        details.push(format!(
+            "PayoutExecuted paid {} instead of {}",
+            executed["topics"][2], record.recipient
+        ));
+    }
+    let amount = executed["data"]
+        .as_str()
+        .and_then(decode_words)
+        .and_then(|words| words.first().copied());
+    let expected = {
+        let mut word = [0u8; 32];
+        word[24..].copy_from_slice(&record.amount.to_be_bytes());
+        word
+    };
+    if amount != Some(expected) {
+        details.push(format!(
+            "PayoutExecuted paid {} instead of {}",
+            executed["data"], record.amount
+        ));
+    }
+    details
+}
+
+impl EthereumPayoutService {
+    /// Check the stored payout `payment_id` against the chain as it is now
+    pub async fn verify_onchain(
+        &self,
+        payment_id: &[u8; 32],
+    ) -> Result<OnchainVerification, PayoutError> {
+        let record = self.store.get(payment_id).ok_or_else(|| {
+            PayoutError::Config(format!("No payout 0x{}", hex::encode(payment_id)))
+        })?;
+        let tx_hash = match &record.tx_hash {
+            Some(tx_hash) if tx_hash.starts_with("0x") => tx_hash.clone(),
+            _ => return Ok(OnchainVerification::NotFound),
+        };
+        let verification = match self.verify_transaction(&record, &tx_hash).await {
+            Err(err) if is_pruned_history(&err) => Ok(OnchainVerification::Unverifiable {
+                reason: err.to_string(),
+            }),
+            verification => verification,
+        }?;
+        match &verification {
+            OnchainVerification::Match { block_number, .. } => info!(
+                "Payout {} verified in block {}",
+                record.payment_id_hex(),
+                block_number
+            ),
+            OnchainVerification::Mismatch { details } => error!(
+                "ALERT: Payout {} does not match transaction {}: {}",
+                record.payment_id_hex(),
+                tx_hash,
+                details.join("; ")
+            ),
+            other => info!(
+                "Payout {} could not be verified: {:?}",
+                record.payment_id_hex(),
+                other
+            ),
+        }
+        Ok(verification)
+    }
+
+    pub(super) async fn verify_transaction(
+        &self,
+        record: &PayoutRecord,
+        tx_hash: &str,
+    ) -> Result<OnchainVerification, PayoutError> {
+        let raw = self
+            .rpc(rpc_request("eth_getTransactionReceipt", json!([tx_hash])))
+            .await?;
+        if raw.is_null() {
+            return self.missing_receipt(record, tx_hash).await;
+        }
+        let receipt = TransactionReceipt::parse(&raw)?;
+        let block_hash = raw["blockHash"].as_str().unwrap_or_default().to_string();
+        let canonical = self.block_hash(receipt.block_number).await?;
+        if !canonical.is_some_and(|canonical| canonical.eq_ignore_ascii_case(&block_hash)) {
+            return Ok(OnchainVerification::Reorged {
+                block_number: receipt.block_number,
+                block_hash,
+            });
+        }
+
+        let mut details = Vec::new();
+        if !receipt.success {
+            details.push("transaction reverted".to_string());
+        }
+        let asset = self.config.assets.get(&record.asset_code);
+        match asset.map_or(PayoutMode::Treasury, |asset| asset.mode) {
+            PayoutMode::Treasury => details.extend(treasury_mismatches(
+                &raw["logs"],
+                &self.config.treasury_address,
+                record,
+            )),
+            PayoutMode::DirectTransfer | PayoutMode::Authorization => {
+                let token = asset.and_then(|asset| asset.token_address.as_deref());
+                match received_amount(&raw["logs"], token, &record.recipient) {
+                    Some(received) if received == u128::from(record.amount) => {}
+                    Some(received) => details.push(format!(
+                        "recipient was credited {} instead of {}",
+                        received, record.amount
+                    )),
+                    None => details.push("receipt has no logs".to_string()),
+                }
+            }
+            PayoutMode::Native => {}
+        }
+        if !details.is_empty() {
+            return Ok(OnchainVerification::Mismatch { details });
+        }
+        Ok(OnchainVerification::Match {
+            block_number: receipt.block_number,
+            block_hash,
+        })
+    }
+
+    /// Tell a transaction the node never saw, or lost to a reorg, from one
+    /// whose receipt it pruned
+    async fn missing_receipt(
+        &self,
+        record: &PayoutRecord,
+        tx_hash: &str,
+    ) -> Result<OnchainVerification, PayoutError> {
+        let block_number = match record.block_number {
+            Some(block_number) => block_number,
+            None => return Ok(OnchainVerification::NotFound),
+        };
+        let block = self
+            .rpc(rpc_request(
+                "eth_getBlockByNumber",
+                json!([format!("0x{:x}", block_number), false]),
+            ))
+            .await?;
+        if block.is_null() {
+            return Ok(OnchainVerification::Unverifiable {
+                reason: format!("node does not serve block {}", block_number),
+            });
+        }
+        let included = block["transactions"].as_array().is_some_and(|txs| {
+            txs.iter().any(|tx| {
+                tx.as_str()
+                    .is_some_and(|tx| tx.eq_ignore_ascii_case(tx_hash))
+            })
+        });
+        if included {
+            return Ok(OnchainVerification::Unverifiable {
+                reason: format!(
+                    "node does not serve the receipt of {} in block {}",
+                    tx_hash, block_number
+                ),
+            });
+        }
+        Ok(OnchainVerification::Reorged {
+            block_number,
+            block_hash: block["hash"].as_str().unwrap_or_default().to_string(),
+        })
+    }
+
+    /// Hash of the canonical block at `number`, `None` if there is none yet
+    async fn block_hash(&self, number: u64) -> Result<Option<String>, PayoutError> {
+        let block = self
+            .rpc(rpc_request(
+                "eth_getBlockByNumber",
+                json!([format!("0x{:x}", number), false]),
+            ))
+            .await?;
+        Ok(block["hash"].as_str().map(str::to_string))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{
+        mock_chain, test_config, test_service, MockTransport, TEST_DESTINATION,
+    };
+    use super::*;
+    use std::sync::Arc;
+
+    const BLOCK_HASH: &str = "0x1111111111111111111111111111111111111111111111111111111111111111";
+
+    /// Receipt of `record`'s payout as the Treasury would emit it, paying `amount`
+    fn receipt(service: &EthereumPayoutService, record: &PayoutRecord, amount: u64) -> Value {
+        let recipient = encode_address(&record.recipient).unwrap();
+        json!({
+            "transactionHash": "0xabc",
+            "blockNumber": "0x7",
+            "blockHash": BLOCK_HASH,
+            "gasUsed": "0x5208",
+            "status": "0x1",
+            "logs": [{
+                "address": service.config.treasury_address,
+                "topics": [
+                    topic(&keccak256(PAYOUT_EXECUTED_EVENT_SIGNATURE.as_bytes())),
+                    topic(&record.payment_id),
+                    topic(&recipient),
+                    topic(&[0u8; 32]),
+                ],
+                "data": format!("0x{:064x}", amount),
+            }],
+        })
+    }
+
+    fn block(hash: &str, transactions: &[&str]) -> Value {
+        json!({"number": "0x7", "hash": hash, "transactions": transactions})
+    }
+
+    async fn paid_service(transport: Arc<MockTransport>) -> (EthereumPayoutService, PayoutRecord) {
+        let service = test_service(test_config(), transport);
+        service
+            .execute_payout(TEST_DESTINATION, 100, 1)
+            .await
+            .unwrap();
+        let payment_id = EthereumPayoutService::generate_payment_id(TEST_DESTINATION, 1);
+        let mut record = service.store().get(&payment_id).unwrap();
+        record.block_number = Some(7);
+        service.store().save(record.clone());
+        (service, record)
+    }
+
+    #[tokio::test]
+    async fn matches_and_mismatches_the_payout_executed_event() {
+        let transport = mock_chain();
+        let (service, record) = paid_service(transport.clone()).await;
+        transport.on_result("eth_getBlockByNumber", block(BLOCK_HASH, &["0xabc"]));
+
+        transport.on_result("eth_getTransactionReceipt", receipt(&service, &record, 100));
+        assert_eq!(
+            service.verify_onchain(&record.payment_id).await.unwrap(),
+            OnchainVerification::Match {
+                block_number: 7,
+                block_hash: BLOCK_HASH.to_string(),
+            }
+        );
+
+        transport.on_result("eth_getTransactionReceipt", receipt(&service, &record, 90));
+        match service.verify_onchain(&record.payment_id).await.unwrap() {
+            OnchainVerification::Mismatch { details } => {
+                assert_eq!(details.len(), 1);
+                assert!(details[0].contains("instead of 100"));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_reorged_and_unknown_transactions() {
+        let transport = mock_chain();
+        let (service, record) = paid_service(transport.clone()).await;
+
+        // Still mined, but in a block that is no longer canonical
+        transport.on_result("eth_getTransactionReceipt", receipt(&service, &record, 100));
+        let other = "0x2222222222222222222222222222222222222222222222222222222222222222";
+        transport.on_result("eth_getBlockByNumber", block(other, &[]));
+        assert!(matches!(
+            service.verify_onchain(&record.payment_id).await.unwrap(),
+            OnchainVerification::Reorged {
+                block_number: 7,
+                ..
+            }
+        ));
+
+        // Gone, and its recorded block holds other transactions now
+        transport.on_result("eth_getTransactionReceipt", Value::Null);
+        assert!(matches!(
+            service.verify_onchain(&record.payment_id).await.unwrap(),
+            OnchainVerification::Reorged { .. }
+        ));
+
+        let mut unmined = record.clone();
+        unmined.block_number = None;
+        service.store().save(unmined);
+        assert_eq!(
+            service.verify_onchain(&record.payment_id).await.unwrap(),
+            OnchainVerification::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn pruned_history_is_unverifiable() {
+        let transport = mock_chain();
+        let (service, record) = paid_service(transport.clone()).await;
+
+        // The receipt was pruned while the block still lists the transaction
+        transport.on_result("eth_getTransactionReceipt", Value::Null);
+        transport.on_result("eth_getBlockByNumber", block(BLOCK_HASH, &["0xabc"]));
+        assert!(matches!(
+            service.verify_onchain(&record.payment_id).await.unwrap(),
+            OnchainVerification::Unverifiable { .. }
+        ));
+
+        // Nodes refusing old history outright
+        transport.on_error(
+            "eth_getTransactionReceipt",
+            -32000,
+            "missing trie node 5e1f (path ) state is not available",
+        );
+        match service.verify_onchain(&record.payment_id).await.unwrap() {
+            OnchainVerification::Unverifiable { reason } => {
+                assert!(reason.contains("missing trie node"))
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! What build and configuration shape a service runs with
+//!
+//! [`EthereumPayoutService::version_info`] names the crate version, the cargo
+//! features compiled in and the kinds of transaction, signer and store the
+//! service uses, so a log or status report can be matched to the code that
+//! produced it. It only names kinds, never keys, addresses or endpoints. Its
+//! compact form ends the startup log line and is kept in
+//! [`ServiceStatus::Ready`](super::ServiceStatus::Ready).
+
+use super::EthereumPayoutService;
+use serde_json::{json, Value};
+
+/// Version of this crate
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Cargo features of this crate compiled in, in manifest order
+pub fn compiled_features() -> Vec<&'static str> {
+    [
+        ("strict", cfg!(feature = "strict")),
+        ("roundtrip-only", cfg!(feature = "roundtrip-only")),
+        ("ethereum-payout", cfg!(feature = "ethereum-payout")),
+        ("erc4337", cfg!(feature = "erc4337")),
+        ("otel", cfg!(feature = "otel")),
+        ("blocking", cfg!(feature = "blocking")),
+        ("redis-nonce-store", cfg!(feature = "redis-nonce-store")),
+        ("testing", cfg!(feature = "testing")),
+    ]
+    .iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| *feature)
+    .collect()
+}
+
+/// Build and configuration shape of a service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub crate_version: &'static str,
+    pub features: Vec<&'static str>,
+    /// `user_operation` when payouts go through a bundler, `legacy` otherwise
+    pub tx_type: &'static str,
+    /// `custom` for a signer set through `with_signer`, `node` when the
+    /// node's account signs transactions and `local` for the operator key
+    pub signer: &'static str,
+    /// See [`PayoutStore::backend`](super::PayoutStore::backend)
+    pub store: &'static str,
+    pub schema_version: u32,
+}
+
+impl VersionInfo {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "crate_version": self.crate_version,
+            "features": self.features,
+            "tx_type": self.tx_type,
+            "signer": self.signer,
+            "store": self.store,
+            "schema_version": self.schema_version,
+        })
+    }
+
+    /// One line for logs, e.g. `1.0.0 [ethereum-payout] tx=legacy signer=local store=file/v2`
+    pub fn compact(&self) -> String {
+        format!(
+            "{} [{}] tx={} signer={} store={}/v{}",
+            self.crate_version,
+            self.features.join(","),
+            self.tx_type,
+            self.signer,
+            self.store,
+            self.schema_version
+        )
+    }
+}
+
+impl EthereumPayoutService {
+    /// Build and configuration shape of this service
+    pub fn version_info(&self) -> VersionInfo {
+        #[cfg(feature = "erc4337")]
+        let user_op = self.config.user_op.is_some();
+        #[cfg(not(feature = "erc4337"))]
+        let user_op = false;
+        VersionInfo {
+            crate_version: CRATE_VERSION,
+            features: compiled_features(),
+            tx_type: if user_op { "user_operation" } else { "legacy" },
+            signer: match (&self.signer, self.config.node_signing) {
+                (Some(_), _) => "custom",
+                (None, true) => "node",
+                (None, false) => "local",
+            },
+            store: self.store.backend(),
+            schema_version: self.store.schema_version(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{test_config, test_service, MockTransport};
+    use super::super::LocalSigner;
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn names_the_build_and_configuration() {
+        let service = test_service(test_config(), MockTransport::new());
+        let info = service.version_info();
+        assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            info.features.contains(&"erc4337"),
+            cfg!(feature = "erc4337")
+        );
+        assert_eq!(info.features.contains(&"otel"), cfg!(feature = "otel"));
+        assert_eq!(
+            info.features.contains(&"blocking"),
+            cfg!(feature = "blocking")
+        );
+        assert_eq!(
+            info.features.contains(&"testing"),
+            cfg!(feature = "testing")
+        );
+        assert_eq!(
+            info.to_json(),
+            json!({
+                "crate_version": CRATE_VERSION,
+                "features": info.features,
+                "tx_type": "legacy",
+                "signer": "local",
+                "store": "memory",
+                "schema_version": 2,
+            })
+        );
+        assert!(info
+            .compact()
+            .ends_with("tx=legacy signer=local store=memory/v2"));
+
+        let signer = LocalSigner::from_hex(&service.config().operator_private_key).unwrap();
+        let service = service.with_signer(Arc::new(signer));
+        assert_eq!(service.version_info().signer, "custom");
+    }
+}
@@ -212,10 +212,42 @@ where
                     // Execute Ethereum payout if configured (POC feature)
                     #[cfg(feature = "ethereum-payout")]
                     {
-                        use crate::ethereum::maybe_execute_payout;
+                        use crate::ethereum::{
+                            maybe_execute_stream_payout, would_accept_stream_payout,
+                        };
                         let dest_str = destination.to_string();
+                        let asset_code = request.to.asset_code().to_string();
+                        let asset_scale = request.to.asset_scale();
+                        // Reject rather than take money for a payout certain to fail
+                        if let Err(reason) = would_accept_stream_payout(
+                            &asset_code,
+                            asset_scale,
+                            &dest_str,
+                            amount,
+                            sequence,
+                        ) {
+                            debug!(
+                                "Rejecting packet whose payout would fail: {}",
+                                reason.message
+                            );
+                            let report = reason.to_json().to_string();
+                            return Err(RejectBuilder {
+                                code: reason.ilp_error_code(),
+                                message: report.as_bytes(),
+                                triggered_by: Some(to_address),
+                                data: &[],
+                            }
+                            .build());
+                        }
                         tokio::spawn(async move {
-                            maybe_execute_payout(&dest_str, amount, sequence).await;
+                            maybe_execute_stream_payout(
+                                &asset_code,
+                                asset_scale,
+                                &dest_str,
+                                amount,
+                                sequence,
+                            )
+                            .await;
                         });
                     }
 